@@ -15,11 +15,13 @@ use directory::{
 };
 use jmap_proto::{
     request::RequestMethod,
-    types::{acl::Acl, collection::Collection, id::Id},
+    types::{acl::Acl, collection::Collection, id::Id, value::AclGrant},
 };
 use std::{
     hash::{DefaultHasher, Hash, Hasher},
+    net::IpAddr,
     sync::Arc,
+    time::Duration,
 };
 use store::{dispatch::lookup::KeyValue, query::acl::AclQuery};
 use trc::AddContext;
@@ -35,6 +37,11 @@ use crate::{
 
 use super::{roles::RolePermissions, AccessToken, ResourceToken, TenantInfo};
 
+// FoundationDB allows 10,000 outstanding watches per database by default
+const MAX_TOKEN_WATCHES: usize = 8192;
+// How often watches check whether their token is still cached
+const TOKEN_WATCH_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
 pub enum PrincipalOrId {
     Principal(Principal),
     Id(u32),
@@ -110,12 +117,18 @@ impl Server {
         // SPDX-SnippetEnd
 
         // Build access token
+        let member_of = principal
+            .iter_int(PrincipalField::MemberOf)
+            .map(|v| v as u32)
+            .collect::<Vec<_>>();
+        let inherited_member_of = self
+            .inherited_member_of(principal.id(), &member_of)
+            .await
+            .caused_by(trc::location!())?;
         let mut access_token = AccessToken {
             primary_id: principal.id(),
-            member_of: principal
-                .iter_int(PrincipalField::MemberOf)
-                .map(|v| v as u32)
-                .collect(),
+            member_of,
+            inherited_member_of,
             access_to: VecMap::new(),
             tenant,
             name: principal.take_str(PrincipalField::Name).unwrap_or_default(),
@@ -138,15 +151,13 @@ impl Server {
                 .map(ConcurrencyLimiter::new),
             obj_size: 0,
             revision,
+            remote_ip: None,
         };
 
-        for grant_account_id in [access_token.primary_id]
-            .into_iter()
-            .chain(access_token.member_of.iter().copied())
-        {
+        for grant_account_id in access_token.grantee_ids() {
             for acl_item in self
                 .store()
-                .acl_query(AclQuery::HasAccess { grant_account_id })
+                .acl_query(AclQuery::GrantedTo { grant_account_id })
                 .await
                 .caused_by(trc::location!())?
             {
@@ -162,7 +173,10 @@ impl Server {
                     }
 
                     let mut collections: Bitmap<Collection> = Bitmap::new();
-                    if acl.contains(Acl::Read) || acl.contains(Acl::Administer) {
+                    if acl.contains(Acl::Read)
+                        || acl.contains(Acl::Lookup)
+                        || acl.contains(Acl::Administer)
+                    {
                         collections.insert(collection);
                     }
                     if collection == Collection::Mailbox
@@ -184,6 +198,53 @@ impl Server {
         Ok(access_token.update_size())
     }
 
+    /// Returns the groups that the groups a principal is a direct member of
+    /// belong to, recursively. Grants given to a parent group apply to the
+    /// members of its subgroups, which are not members of the parent group.
+    async fn inherited_member_of(
+        &self,
+        principal_id: u32,
+        member_of: &[u32],
+    ) -> trc::Result<Vec<u32>> {
+        let mut inherited = Vec::new();
+        let mut fetched_ids = AHashSet::from_iter([principal_id]);
+        let mut ids = member_of.to_vec().into_iter();
+        let mut ids_stack = vec![];
+
+        loop {
+            if let Some(id) = ids.next() {
+                // Skip if already fetched
+                if !fetched_ids.insert(id) {
+                    continue;
+                }
+                if !member_of.contains(&id) {
+                    inherited.push(id);
+                }
+
+                // Obtain the groups of the group
+                if let Some(group) = self
+                    .directory()
+                    .query(QueryBy::Id(id), true)
+                    .await
+                    .caused_by(trc::location!())?
+                {
+                    ids_stack.push(ids);
+                    ids = group
+                        .iter_int(PrincipalField::MemberOf)
+                        .map(|v| v as u32)
+                        .collect::<Vec<_>>()
+                        .into_iter();
+                }
+            } else if let Some(prev_ids) = ids_stack.pop() {
+                ids = prev_ids;
+            } else {
+                break;
+            }
+        }
+
+        Ok(inherited)
+    }
+
     async fn build_access_token(&self, account_id: u32, revision: u64) -> trc::Result<AccessToken> {
         let err = match self.directory().query(QueryBy::Id(account_id), true).await {
             Ok(Some(principal)) => {
@@ -212,12 +273,18 @@ impl Server {
         principal: impl Into<PrincipalOrId>,
     ) -> trc::Result<Arc<AccessToken>> {
         let principal = principal.into();
+        let principal_id = principal.id();
+
+        // Watched tokens are evicted as soon as any node increments their
+        // revision, so it does not have to be fetched
+        if let Some(token) = self.watched_access_token(principal_id) {
+            return Ok(token);
+        }
 
         // Obtain current revision
-        let principal_id = principal.id();
         let revision = self.fetch_token_revision(principal_id).await;
 
-        match self
+        let result = match self
             .inner
             .cache
             .access_tokens
@@ -263,12 +330,93 @@ impl Server {
                 let _ = guard.insert(token.clone());
                 Ok(token)
             }
+        };
+
+        if let (Ok(_), Some(revision)) = (&result, revision) {
+            self.watch_token_revision(principal_id, revision);
         }
+
+        result
+    }
+
+    fn watched_access_token(&self, id: u32) -> Option<Arc<AccessToken>> {
+        let revision = (*self.inner.data.token_watches.lock().get(&id)?)?;
+        self.inner
+            .cache
+            .access_tokens
+            .get(&id)
+            .filter(|token| token.revision == revision)
+    }
+
+    /// Evicts the cached access token of a principal as soon as any node
+    /// increments its revision, when the in-memory store can watch keys. The
+    /// watch is cancelled once the token leaves the cache, so evicted tokens
+    /// do not hold on to watches.
+    fn watch_token_revision(&self, id: u32, revision: u64) {
+        let store = self.in_memory_store();
+        if !store.supports_watch() {
+            return;
+        }
+        {
+            let mut watches = self.inner.data.token_watches.lock();
+            if watches.len() >= MAX_TOKEN_WATCHES || watches.contains_key(&id) {
+                return;
+            }
+            watches.insert(id, None);
+        }
+
+        let store = store.clone();
+        let inner = self.inner.clone();
+        tokio::spawn(async move {
+            let result = match store
+                .counter_watch(
+                    KeyValue::<()>::build_key(KV_TOKEN_REVISION, id.to_be_bytes()),
+                    revision as i64,
+                )
+                .await
+            {
+                Ok(mut changed) => {
+                    if let Some(watch) = inner.data.token_watches.lock().get_mut(&id) {
+                        *watch = Some(revision);
+                    }
+                    loop {
+                        tokio::select! {
+                            result = &mut changed => break result,
+                            _ = tokio::time::sleep(TOKEN_WATCH_CHECK_INTERVAL) => {
+                                if inner
+                                    .cache
+                                    .access_tokens
+                                    .peek(&id)
+                                    .is_none_or(|token| token.revision != revision)
+                                {
+                                    inner.data.token_watches.lock().remove(&id);
+                                    return;
+                                }
+                            }
+                        }
+                    }
+                }
+                Err(err) => Err(err),
+            };
+            if let Err(err) = result {
+                trc::error!(err
+                    .details("Failed to watch principal revision")
+                    .account_id(id));
+            }
+
+            inner.data.token_watches.lock().remove(&id);
+            inner.cache.access_tokens.remove(&id);
+        });
     }
 
     pub async fn increment_token_revision(&self, changed_principals: ChangedPrincipals) {
         let mut nested_principals = Vec::new();
 
+        // Shared document sets might include grants to any of the changed principals
+        if !changed_principals.is_empty() {
+            self.inner.cache.shared_acls.clear();
+        }
+
         for (id, changed_principal) in changed_principals.iter() {
             self.increment_revision(*id).await;
 
@@ -378,6 +526,41 @@ impl Server {
             }
         }
     }
+
+    /// Returns whether a principal, or one of the groups it belongs to, was
+    /// granted `Acl::Submit` on any mailbox of an account, which allows it to
+    /// send mail using the addresses of that account. Grants that are expired,
+    /// out of schedule or restricted to other networks than `remote_ip` are
+    /// not taken into account.
+    pub async fn has_submit_access(
+        &self,
+        access_token: &AccessToken,
+        to_account_id: u32,
+        remote_ip: Option<&IpAddr>,
+    ) -> trc::Result<bool> {
+        for grant_account_id in access_token.grantee_ids() {
+            if self
+                .store()
+                .acl_query(AclQuery::SharedWith {
+                    grant_account_id,
+                    to_account_id,
+                    to_collection: Collection::Mailbox.into(),
+                })
+                .await
+                .caused_by(trc::location!())?
+                .into_iter()
+                .any(|acl_item| {
+                    Bitmap::<Acl>::from(acl_item.permissions).contains(Acl::Submit)
+                        && AclGrant::from_extensions(&acl_item.extensions)
+                            .is_some_and(|grant| grant.is_active_from(remote_ip))
+                })
+            {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
 }
 
 impl From<u32> for PrincipalOrId {
@@ -418,10 +601,20 @@ impl AccessToken {
         self
     }
 
+    /// Returns a copy of a cached token bound to the address of the client
+    /// using it, as tokens are otherwise shared between connections.
+    pub fn with_remote_ip(self: Arc<Self>, remote_ip: IpAddr) -> Arc<Self> {
+        Arc::new(AccessToken {
+            remote_ip: Some(remote_ip),
+            ..self.as_ref().clone()
+        })
+    }
+
     pub fn state(&self) -> u32 {
         // Hash state
         let mut s = DefaultHasher::new();
         self.member_of.hash(&mut s);
+        self.inherited_member_of.hash(&mut s);
         self.access_to.hash(&mut s);
         s.finish() as u32
     }
@@ -436,6 +629,15 @@ impl AccessToken {
             .chain(self.access_to.iter().map(|(id, _)| id))
     }
 
+    /// Returns the ids ACL grants are matched against: the principal, the
+    /// groups it belongs to and the groups these belong to in turn.
+    pub fn grantee_ids(&self) -> impl Iterator<Item = u32> + '_ {
+        [self.primary_id]
+            .into_iter()
+            .chain(self.member_of.iter().copied())
+            .chain(self.inherited_member_of.iter().copied())
+    }
+
     pub fn is_member(&self, account_id: u32) -> bool {
         self.primary_id == account_id
             || self.member_of.contains(&account_id)
@@ -681,7 +883,8 @@ impl AccessToken {
 
     pub fn update_size(mut self) -> Self {
         self.obj_size = (std::mem::size_of::<AccessToken>()
-            + (self.member_of.len() * std::mem::size_of::<u32>())
+            + ((self.member_of.len() + self.inherited_member_of.len())
+                * std::mem::size_of::<u32>())
             + (self.access_to.len() * (std::mem::size_of::<u32>() + std::mem::size_of::<u64>()))
             + self.name.len()
             + self.description.as_ref().map_or(0, |v| v.len())
@@ -24,10 +24,13 @@ pub mod oauth;
 pub mod roles;
 pub mod sasl;
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct AccessToken {
     pub primary_id: u32,
     pub member_of: Vec<u32>,
+    /// Groups the principal belongs to through nested group memberships, only
+    /// used to match ACL grants, see `AccessToken::grantee_ids`
+    pub inherited_member_of: Vec<u32>,
     pub access_to: VecMap<u32, Bitmap<Collection>>,
     pub name: String,
     pub description: Option<String>,
@@ -40,6 +43,8 @@ pub struct AccessToken {
     pub concurrent_uploads: Option<ConcurrencyLimiter>,
     pub revision: u64,
     pub obj_size: u64,
+    /// Address of the client using the token, checked by network-restricted grants
+    pub remote_ip: Option<IpAddr>,
 }
 
 #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
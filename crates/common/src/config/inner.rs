@@ -26,8 +26,8 @@ use crate::{
     config::smtp::resolver::{Policy, Tlsa},
     listener::blocked::BlockedIps,
     manager::webadmin::WebAdminManager,
-    Account, AccountId, Caches, Data, Mailbox, MailboxId, MailboxState, NextMailboxState, Threads,
-    TlsConnectors,
+    Account, AccountId, Caches, Data, Mailbox, MailboxId, MailboxState, NextMailboxState,
+    SharedAclId, SharedDocuments, Threads, TlsConnectors,
 };
 
 use super::server::tls::{build_self_signed_cert, parse_certificates};
@@ -71,6 +71,7 @@ impl Data {
                 .unwrap_or_default(),
             config_version: 0.into(),
             logos: Default::default(),
+            token_watches: Default::default(),
             smtp_connectors: TlsConnectors::default(),
             asn_geo_data: Default::default(),
         }
@@ -125,6 +126,13 @@ impl Caches {
                 MB_10,
                 (std::mem::size_of::<Threads>() + (500 * std::mem::size_of::<u64>())) as u64,
             ),
+            shared_acls: Cache::from_config(
+                config,
+                "shared-acl",
+                MB_5,
+                (std::mem::size_of::<SharedAclId>() + std::mem::size_of::<SharedDocuments>() + 255)
+                    as u64,
+            ),
             bayes: CacheWithTtl::from_config(
                 config,
                 "bayes",
@@ -221,6 +229,7 @@ impl Default for Data {
             webadmin: Default::default(),
             config_version: Default::default(),
             logos: Default::default(),
+            token_watches: Default::default(),
             smtp_connectors: Default::default(),
             asn_geo_data: Default::default(),
         }
@@ -8,6 +8,7 @@ use std::{str::FromStr, time::Duration};
 
 use jmap_proto::request::capability::BaseCapabilities;
 use nlp::language::Language;
+use store::BlobQuotaMode;
 use utils::config::{cron::SimpleCron, utils::ParseValue, Config, Rate};
 
 #[derive(Default, Clone)]
@@ -32,6 +33,7 @@ pub struct JmapConfig {
     pub upload_tmp_quota_size: usize,
     pub upload_tmp_quota_amount: usize,
     pub upload_tmp_ttl: u64,
+    pub upload_quota_mode: BlobQuotaMode,
 
     pub mailbox_max_depth: usize,
     pub mailbox_name_max_len: usize,
@@ -65,6 +67,11 @@ pub struct JmapConfig {
     pub default_folders: Vec<DefaultFolder>,
     pub shared_folder: String,
 
+    pub acl_duplicate_grantee: DuplicateGrantee,
+    pub acl_evaluation: AclEvaluation,
+    pub acl_pin_requests: bool,
+    pub acl_consistent_reads: bool,
+
     pub http_headers: Vec<(hyper::header::HeaderName, hyper::header::HeaderValue)>,
     pub http_use_forwarded: bool,
 
@@ -84,6 +91,27 @@ pub struct DefaultFolder {
     pub create: bool,
 }
 
+/// How an ACL set listing the same principal more than once is handled
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum DuplicateGrantee {
+    #[default]
+    Merge,
+    Reject,
+}
+
+/// How the grants held by a principal and the groups it belongs to are combined
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum AclEvaluation {
+    /// Rights granted to the principal or any of its groups are added up
+    #[default]
+    Union,
+    /// A grant to the principal itself takes precedence over its group grants,
+    /// which are only combined when there is no active individual grant. This
+    /// applies to shared document lists and access checks as well, whose
+    /// results are not cached in this mode.
+    Priority,
+}
+
 #[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
 pub enum SpecialUse {
     Inbox,
@@ -277,6 +305,9 @@ impl JmapConfig {
                 .property_or_default::<Duration>("jmap.protocol.upload.ttl", "1h")
                 .unwrap_or_else(|| Duration::from_secs(3600))
                 .as_secs(),
+            upload_quota_mode: config
+                .property_or_default::<BlobQuotaMode>("jmap.protocol.upload.quota.mode", "logical")
+                .unwrap_or_default(),
             mailbox_max_depth: config.property("jmap.mailbox.max-depth").unwrap_or(10),
             mailbox_name_max_len: config
                 .property("jmap.mailbox.max-name-length")
@@ -362,6 +393,18 @@ impl JmapConfig {
             }),
             default_folders,
             shared_folder,
+            acl_duplicate_grantee: config
+                .property_or_default::<DuplicateGrantee>("jmap.acl.duplicate-grantee", "merge")
+                .unwrap_or_default(),
+            acl_evaluation: config
+                .property_or_default::<AclEvaluation>("jmap.acl.evaluation", "union")
+                .unwrap_or_default(),
+            acl_pin_requests: config
+                .property_or_default("jmap.acl.pin-requests", "false")
+                .unwrap_or(false),
+            acl_consistent_reads: config
+                .property_or_default("jmap.acl.consistent-reads", "false")
+                .unwrap_or(false),
         };
 
         // Add capabilities
@@ -385,3 +428,23 @@ impl ParseValue for SpecialUse {
         }
     }
 }
+
+impl ParseValue for DuplicateGrantee {
+    fn parse_value(value: &str) -> Result<Self, String> {
+        match value {
+            "merge" => Ok(DuplicateGrantee::Merge),
+            "reject" => Ok(DuplicateGrantee::Reject),
+            other => Err(format!("Unknown duplicate grantee policy {other:?}")),
+        }
+    }
+}
+
+impl ParseValue for AclEvaluation {
+    fn parse_value(value: &str) -> Result<Self, String> {
+        match value {
+            "union" => Ok(AclEvaluation::Union),
+            "priority" => Ok(AclEvaluation::Priority),
+            other => Err(format!("Unknown ACL evaluation mode {other:?}")),
+        }
+    }
+}
@@ -13,6 +13,7 @@ use hyper::{
     header::{HeaderName, HeaderValue, AUTHORIZATION},
     HeaderMap,
 };
+use jmap_proto::types::collection::Collection;
 use ring::signature::{EcdsaKeyPair, RsaKeyPair};
 use spamfilter::SpamFilterConfig;
 use store::{BlobBackend, BlobStore, FtsStore, InMemoryStore, Store, Stores};
@@ -158,6 +159,24 @@ impl Core {
             .directories
             .insert("*".to_string(), directory.clone());
 
+        // Collections can widen or narrow the document id assignment window of
+        // the data store
+        for collection in [
+            Collection::Email,
+            Collection::Mailbox,
+            Collection::Thread,
+            Collection::Identity,
+            Collection::EmailSubmission,
+            Collection::SieveScript,
+            Collection::PushSubscription,
+            Collection::Principal,
+        ] {
+            data.settings().set_collection_id_assignment_window(
+                collection.into(),
+                config.property::<usize>(("storage.id-assignment", collection.as_str(), "window")),
+            );
+        }
+
         // If any of the stores are missing, disable all stores to avoid data loss
         if matches!(data, Store::None)
             || matches!(&blob.backend, BlobBackend::Store(Store::None))
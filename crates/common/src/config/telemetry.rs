@@ -107,6 +107,7 @@ pub enum RotationStrategy {
 pub struct Telemetry {
     pub tracers: Tracers,
     pub metrics: Interests,
+    pub collection_metrics: bool,
 }
 
 #[derive(Debug)]
@@ -133,6 +134,9 @@ impl Telemetry {
         let mut telemetry = Telemetry {
             tracers: Tracers::parse(config, stores),
             metrics: Interests::default(),
+            collection_metrics: config
+                .property_or_default("metrics.store.per-collection", "false")
+                .unwrap_or(false),
         };
 
         // Parse metrics
@@ -8,13 +8,14 @@ use std::{sync::Arc, time::Duration};
 
 use directory::{Directory, QueryBy, Type, backend::internal::manage::ManageDirectory};
 use jmap_proto::types::{
-    blob::BlobId, collection::Collection, property::Property, state::StateChange,
+    acl::acl_changes_collection, blob::BlobId, collection::Collection, property::Property,
+    state::StateChange, type_state::DataType,
 };
 use sieve::Sieve;
 use store::{
-    BitmapKey, BlobClass, BlobStore, Deserialize, FtsStore, InMemoryStore, IndexKey, IterateParams,
-    LogKey, Serialize, Store, U32_LEN, ValueKey,
-    dispatch::DocumentSet,
+    BitmapKey, BlobClass, BlobQuotaMode, BlobStore, Deserialize, FtsStore, InMemoryStore, IndexKey,
+    IterateParams, LogKey, Serialize, Store, U32_LEN, ValueKey,
+    dispatch::{DocumentSet, blob::BlobHint},
     roaring::RoaringBitmap,
     write::{
         BatchBuilder, BitmapClass, BlobOp, DirectoryClass, QueueClass, TagValue, ValueClass,
@@ -509,6 +510,9 @@ impl Server {
             .map(ChangeLogBuilder::with_change_id)
     }
 
+    /// Change ids are snowflake ids generated locally rather than values of a
+    /// per-account counter, so assigning one never touches the store and
+    /// concurrent changes to the same account do not conflict on commit.
     #[inline(always)]
     pub fn assign_change_id(&self, _: u32) -> trc::Result<u64> {
         self.generate_snowflake_id()
@@ -552,11 +556,12 @@ impl Server {
         })?;
 
         for collection in [
-            Collection::Email,
-            Collection::Mailbox,
-            Collection::Thread,
-            Collection::Identity,
-            Collection::EmailSubmission,
+            Collection::Email.into(),
+            Collection::Mailbox.into(),
+            Collection::Thread.into(),
+            Collection::Identity.into(),
+            Collection::EmailSubmission.into(),
+            acl_changes_collection(Collection::Mailbox),
         ] {
             self.core
                 .storage
@@ -564,12 +569,12 @@ impl Server {
                 .delete_range(
                     LogKey {
                         account_id,
-                        collection: collection.into(),
+                        collection,
                         change_id: 0,
                     },
                     LogKey {
                         account_id,
-                        collection: collection.into(),
+                        collection,
                         change_id: reference_cid,
                     },
                 )
@@ -601,24 +606,79 @@ impl Server {
         }
     }
 
-    #[allow(clippy::blocks_in_conditions)]
+    /// Applies a change committed by another node, dropping the cached data
+    /// derived from the changed collection and publishing the state change to
+    /// the subscribers connected to this node.
+    pub async fn apply_remote_change(&self, change: LogKey) -> bool {
+        let collection = Collection::from(change.collection);
+        if matches!(collection, Collection::Email | Collection::Thread) {
+            self.inner.cache.threads.remove(&change.account_id);
+        }
+
+        match DataType::try_from(collection) {
+            Ok(data_type) => {
+                self.broadcast_state_change(
+                    StateChange::new(change.account_id).with_change(data_type, change.change_id),
+                )
+                .await
+            }
+            Err(_) => true,
+        }
+    }
+
     pub async fn put_blob(
         &self,
         account_id: u32,
         data: &[u8],
         set_quota: bool,
+    ) -> trc::Result<BlobId> {
+        self.put_blob_with_hint(account_id, data, set_quota, BlobHint::Other)
+            .await
+    }
+
+    /// Same as `put_blob`, passing a description of the contents to the blob
+    /// store so that already compressed data is not compressed again. Blobs are
+    /// compressed with the dictionary of the account when it has one.
+    #[allow(clippy::blocks_in_conditions)]
+    pub async fn put_blob_with_hint(
+        &self,
+        account_id: u32,
+        data: &[u8],
+        set_quota: bool,
+        hint: BlobHint<'_>,
     ) -> trc::Result<BlobId> {
         // First reserve the hash
         let hash = BlobHash::from(data);
         let mut batch = BatchBuilder::new();
         let until = now() + self.core.jmap.upload_tmp_ttl;
 
+        // Physical quota charges the encoded size, the blob is encoded once
+        // here and the same bytes are written below
+        let mut prepared = None;
+        let quota = if set_quota {
+            match self.core.jmap.upload_quota_mode {
+                BlobQuotaMode::Logical => data.len() as u32,
+                mode @ BlobQuotaMode::Physical => prepared
+                    .insert(
+                        self.core
+                            .storage
+                            .blob
+                            .prepare_account_blob(account_id, data, hint)
+                            .await
+                            .caused_by(trc::location!())?,
+                    )
+                    .quota_size(mode) as u32,
+            }
+        } else {
+            0u32
+        };
+
         batch.with_account_id(account_id).set(
             BlobOp::Reserve {
                 hash: hash.clone(),
                 until,
             },
-            (if set_quota { data.len() as u32 } else { 0u32 }).serialize(),
+            quota.serialize(),
         );
         self.core
             .storage
@@ -636,10 +696,20 @@ impl Server {
             .caused_by(trc::location!())?
         {
             // Upload blob to store
+            let blob = match prepared {
+                Some(blob) => blob,
+                None => self
+                    .core
+                    .storage
+                    .blob
+                    .prepare_account_blob(account_id, data, hint)
+                    .await
+                    .caused_by(trc::location!())?,
+            };
             self.core
                 .storage
                 .blob
-                .put_blob(hash.as_ref(), data)
+                .put_prepared_blob(hash.as_ref(), blob)
                 .await
                 .caused_by(trc::location!())?;
 
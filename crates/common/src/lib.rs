@@ -40,6 +40,7 @@ use manager::webadmin::{Resource, WebAdminManager};
 use nlp::bayes::{TokenHash, Weights};
 use parking_lot::{Mutex, RwLock};
 use rustls::sign::CertifiedKey;
+use store::roaring::RoaringBitmap;
 use tokio::sync::{mpsc, Notify, Semaphore};
 use tokio_rustls::TlsConnector;
 use utils::{
@@ -125,6 +126,10 @@ pub struct Data {
     pub logos: Mutex<AHashMap<String, Option<Resource<Vec<u8>>>>>,
     pub config_version: AtomicU8,
 
+    // Principals whose token revision is watched, along with the revision once
+    // the watch is registered
+    pub token_watches: Mutex<AHashMap<u32, Option<u64>>>,
+
     pub smtp_connectors: TlsConnectors,
 }
 
@@ -136,6 +141,7 @@ pub struct Caches {
     pub account: Cache<AccountId, Arc<Account>>,
     pub mailbox: Cache<MailboxId, Arc<MailboxState>>,
     pub threads: Cache<u32, Arc<Threads>>,
+    pub shared_acls: Cache<SharedAclId, Arc<SharedDocuments>>,
 
     pub bayes: CacheWithTtl<TokenHash, Weights>,
 
@@ -237,6 +243,22 @@ pub struct Threads {
     pub modseq: Option<u64>,
 }
 
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct SharedAclId {
+    pub access_id: u32,
+    pub revision: u64,
+    pub account_id: u32,
+    pub collection: u8,
+    pub acls: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SharedDocuments {
+    pub document_ids: RoaringBitmap,
+    // Last change ids of the collections the shared set was derived from
+    pub change_ids: [Option<u64>; 2],
+}
+
 #[derive(Clone, Default)]
 pub struct Core {
     pub storage: Storage,
@@ -271,6 +293,18 @@ impl CacheItemWeight for Threads {
     }
 }
 
+impl CacheItemWeight for SharedAclId {
+    fn weight(&self) -> u64 {
+        std::mem::size_of::<SharedAclId>() as u64
+    }
+}
+
+impl CacheItemWeight for SharedDocuments {
+    fn weight(&self) -> u64 {
+        (std::mem::size_of::<SharedDocuments>() + self.document_ids.serialized_size()) as u64
+    }
+}
+
 impl CacheItemWeight for MailboxState {
     fn weight(&self) -> u64 {
         self.obj_size
@@ -404,6 +438,7 @@ impl Default for Caches {
             account: Cache::new(1024, 10 * 1024 * 1024),
             mailbox: Cache::new(1024, 10 * 1024 * 1024),
             threads: Cache::new(1024, 10 * 1024 * 1024),
+            shared_acls: Cache::new(1024, 10 * 1024 * 1024),
             bayes: CacheWithTtl::new(1024, 10 * 1024 * 1024),
             dns_rbl: CacheWithTtl::new(1024, 10 * 1024 * 1024),
             dns_txt: CacheWithTtl::new(1024, 10 * 1024 * 1024),
@@ -15,6 +15,7 @@ use std::{
 use ahash::{AHashMap, AHashSet};
 use jmap_proto::types::{collection::Collection, property::Property};
 use store::{
+    dispatch::snapshot::SnapshotHandle,
     write::{
         key::DeserializeBigEndian, AnyKey, BitmapClass, BitmapHash, BlobOp, DirectoryClass,
         InMemoryClass, QueueClass, QueueEvent, TagValue, ValueClass,
@@ -76,42 +77,54 @@ impl Core {
             std::process::exit(1);
         }
 
+        let snapshot = self
+            .storage
+            .data
+            .long_lived_snapshot()
+            .await
+            .failed("Failed to create snapshot");
+        if !snapshot.is_consistent() {
+            eprintln!(
+                "Warning: this store does not support snapshots, data written during the backup may be inconsistent."
+            );
+        }
+
         let mut sync_handles = Vec::new();
 
         for (async_handle, sync_handle) in [
             params
                 .has_family(Family::Property)
-                .then(|| self.backup_properties(&params.dest)),
+                .then(|| self.backup_properties(&snapshot, &params.dest)),
             params
                 .has_family(Family::FtsIndex)
-                .then(|| self.backup_fts_index(&params.dest)),
+                .then(|| self.backup_fts_index(&snapshot, &params.dest)),
             params
                 .has_family(Family::Acl)
-                .then(|| self.backup_acl(&params.dest)),
+                .then(|| self.backup_acl(&snapshot, &params.dest)),
             params
                 .has_family(Family::Blob)
-                .then(|| self.backup_blob(&params.dest)),
+                .then(|| self.backup_blob(&snapshot, &params.dest)),
             params
                 .has_family(Family::Config)
-                .then(|| self.backup_config(&params.dest)),
+                .then(|| self.backup_config(&snapshot, &params.dest)),
             params
                 .has_family(Family::LookupValue)
-                .then(|| self.backup_lookup(&params.dest)),
+                .then(|| self.backup_lookup(&snapshot, &params.dest)),
             params
                 .has_family(Family::Directory)
-                .then(|| self.backup_directory(&params.dest)),
+                .then(|| self.backup_directory(&snapshot, &params.dest)),
             params
                 .has_family(Family::Queue)
-                .then(|| self.backup_queue(&params.dest)),
+                .then(|| self.backup_queue(&snapshot, &params.dest)),
             params
                 .has_family(Family::Index)
-                .then(|| self.backup_index(&params.dest)),
+                .then(|| self.backup_index(&snapshot, &params.dest)),
             params
                 .has_family(Family::Bitmap)
-                .then(|| self.backup_bitmaps(&params.dest)),
+                .then(|| self.backup_bitmaps(&snapshot, &params.dest)),
             params
                 .has_family(Family::Log)
-                .then(|| self.backup_logs(&params.dest)),
+                .then(|| self.backup_logs(&snapshot, &params.dest)),
         ]
         .into_iter()
         .flatten()
@@ -125,8 +138,8 @@ impl Core {
         }
     }
 
-    fn backup_properties(&self, dest: &Path) -> TaskHandle {
-        let store = self.storage.data.clone();
+    fn backup_properties(&self, snapshot: &SnapshotHandle, dest: &Path) -> TaskHandle {
+        let store = snapshot.clone();
         let (handle, writer) = spawn_writer(dest.join("property"));
         (
             tokio::spawn(async move {
@@ -237,8 +250,8 @@ impl Core {
         )
     }
 
-    fn backup_fts_index(&self, dest: &Path) -> TaskHandle {
-        let store = self.storage.data.clone();
+    fn backup_fts_index(&self, snapshot: &SnapshotHandle, dest: &Path) -> TaskHandle {
+        let store = snapshot.clone();
         let (handle, writer) = spawn_writer(dest.join("fts_index"));
         (
             tokio::spawn(async move {
@@ -311,8 +324,8 @@ impl Core {
         )
     }
 
-    fn backup_acl(&self, dest: &Path) -> TaskHandle {
-        let store = self.storage.data.clone();
+    fn backup_acl(&self, snapshot: &SnapshotHandle, dest: &Path) -> TaskHandle {
+        let store = snapshot.clone();
         let (handle, writer) = spawn_writer(dest.join("acl"));
         (
             tokio::spawn(async move {
@@ -384,8 +397,8 @@ impl Core {
         )
     }
 
-    fn backup_blob(&self, dest: &Path) -> TaskHandle {
-        let store = self.storage.data.clone();
+    fn backup_blob(&self, snapshot: &SnapshotHandle, dest: &Path) -> TaskHandle {
+        let store = snapshot.clone();
         let blob_store = self.storage.blob.clone();
         let (handle, writer) = spawn_writer(dest.join("blob"));
         (
@@ -475,8 +488,8 @@ impl Core {
         )
     }
 
-    fn backup_config(&self, dest: &Path) -> TaskHandle {
-        let store = self.storage.data.clone();
+    fn backup_config(&self, snapshot: &SnapshotHandle, dest: &Path) -> TaskHandle {
+        let store = snapshot.clone();
         let (handle, writer) = spawn_writer(dest.join("config"));
         (
             tokio::spawn(async move {
@@ -522,8 +535,8 @@ impl Core {
         )
     }
 
-    fn backup_lookup(&self, dest: &Path) -> TaskHandle {
-        let store = self.storage.data.clone();
+    fn backup_lookup(&self, snapshot: &SnapshotHandle, dest: &Path) -> TaskHandle {
+        let store = snapshot.clone();
         let (handle, writer) = spawn_writer(dest.join("lookup"));
         (
             tokio::spawn(async move {
@@ -628,8 +641,8 @@ impl Core {
         )
     }
 
-    fn backup_directory(&self, dest: &Path) -> TaskHandle {
-        let store = self.storage.data.clone();
+    fn backup_directory(&self, snapshot: &SnapshotHandle, dest: &Path) -> TaskHandle {
+        let store = snapshot.clone();
         let (handle, writer) = spawn_writer(dest.join("directory"));
         (
             tokio::spawn(async move {
@@ -700,8 +713,8 @@ impl Core {
         )
     }
 
-    fn backup_queue(&self, dest: &Path) -> TaskHandle {
-        let store = self.storage.data.clone();
+    fn backup_queue(&self, snapshot: &SnapshotHandle, dest: &Path) -> TaskHandle {
+        let store = snapshot.clone();
         let (handle, writer) = spawn_writer(dest.join("queue"));
         (
             tokio::spawn(async move {
@@ -781,8 +794,8 @@ impl Core {
         )
     }
 
-    fn backup_index(&self, dest: &Path) -> TaskHandle {
-        let store = self.storage.data.clone();
+    fn backup_index(&self, snapshot: &SnapshotHandle, dest: &Path) -> TaskHandle {
+        let store = snapshot.clone();
         let (handle, writer) = spawn_writer(dest.join("index"));
         (
             tokio::spawn(async move {
@@ -851,8 +864,8 @@ impl Core {
         )
     }
 
-    fn backup_bitmaps(&self, dest: &Path) -> TaskHandle {
-        let store = self.storage.data.clone();
+    fn backup_bitmaps(&self, snapshot: &SnapshotHandle, dest: &Path) -> TaskHandle {
+        let store = snapshot.clone();
 
         let (handle, writer) = spawn_writer(dest.join("bitmap"));
         (
@@ -1023,8 +1036,8 @@ impl Core {
         )
     }
 
-    fn backup_logs(&self, dest: &Path) -> TaskHandle {
-        let store = self.storage.data.clone();
+    fn backup_logs(&self, snapshot: &SnapshotHandle, dest: &Path) -> TaskHandle {
+        let store = snapshot.clone();
         let (handle, writer) = spawn_writer(dest.join("log"));
         (
             tokio::spawn(async move {
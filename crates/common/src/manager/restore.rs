@@ -157,7 +157,7 @@ async fn restore_file(store: Store, blob_store: BlobStore, path: &Path) {
                         } else {
                             batch_size -= value.len();
                             blob_store
-                                .put_blob(&key, &value)
+                                .put_blob_buffered(&key, &value)
                                 .await
                                 .expect("Failed to write blob");
                             batch.set(ValueClass::Blob(BlobOp::Commit { hash }), vec![]);
@@ -360,6 +360,8 @@ async fn restore_file(store: Store, blob_store: BlobStore, path: &Path) {
         }
 
         if batch.ops.len() >= 1000 || batch_size >= 5_000_000 {
+            // Blobs have to be written before the batch committing them
+            blob_store.flush().await.failed("Failed to write blobs");
             store
                 .write(batch.build())
                 .await
@@ -374,6 +376,7 @@ async fn restore_file(store: Store, blob_store: BlobStore, path: &Path) {
     }
 
     if !batch.is_empty() {
+        blob_store.flush().await.failed("Failed to write blobs");
         store
             .write(batch.build())
             .await
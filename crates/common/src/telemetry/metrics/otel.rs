@@ -6,7 +6,8 @@
 
 use std::time::SystemTime;
 
-use opentelemetry::global::set_error_handler;
+use jmap_proto::types::collection::Collection;
+use opentelemetry::{KeyValue, global::set_error_handler};
 use opentelemetry_sdk::metrics::data::{
     DataPoint, Gauge, Histogram, HistogramDataPoint, Metric, ResourceMetrics, ScopeMetrics, Sum,
     Temporality,
@@ -82,6 +83,42 @@ impl OtelMetrics {
             });
         }
 
+        // Add per-collection histograms
+        let mut collection_metrics: Vec<(trc::MetricType, Vec<HistogramDataPoint<u64>>)> =
+            Vec::new();
+        for (collection, histogram) in Collector::collect_collection_histograms() {
+            let data_point = HistogramDataPoint {
+                attributes: vec![KeyValue::new(
+                    "collection",
+                    Collection::from(collection).as_str(),
+                )],
+                start_time,
+                time: now,
+                count: histogram.count(),
+                bounds: histogram.upper_bounds_vec(),
+                bucket_counts: histogram.buckets_vec(),
+                min: histogram.min(),
+                max: histogram.max(),
+                sum: histogram.sum(),
+                exemplars: vec![],
+            };
+            match collection_metrics.last_mut() {
+                Some((id, data_points)) if *id == histogram.id() => data_points.push(data_point),
+                _ => collection_metrics.push((histogram.id(), vec![data_point])),
+            }
+        }
+        for (id, data_points) in collection_metrics {
+            metrics.push(Metric {
+                name: id.name().into(),
+                description: id.description().into(),
+                unit: id.unit().into(),
+                data: Box::new(Histogram {
+                    data_points,
+                    temporality: Temporality::Cumulative,
+                }),
+            });
+        }
+
         // Export metrics
         if let Err(err) = self
             .exporter
@@ -4,8 +4,9 @@
  * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
  */
 
+use jmap_proto::types::collection::Collection;
 use prometheus::{
-    proto::{Bucket, Counter, Gauge, Histogram, Metric, MetricFamily, MetricType},
+    proto::{Bucket, Counter, Gauge, Histogram, LabelPair, Metric, MetricFamily, MetricType},
     TextEncoder,
 };
 use trc::{atomics::histogram::AtomicHistogram, Collector};
@@ -52,6 +53,26 @@ impl Server {
             metrics.push(metric);
         }
 
+        // Add per-collection histograms
+        let mut last_id = None;
+        for (collection, histogram) in Collector::collect_collection_histograms() {
+            let mut label = LabelPair::default();
+            label.set_name("collection".to_string());
+            label.set_value(Collection::from(collection).to_string());
+            let mut m = new_histogram(histogram);
+            m.set_label(vec![label]);
+
+            if last_id != Some(histogram.id()) {
+                let mut metric = MetricFamily::default();
+                metric.set_name(metric_name(histogram.id().name()));
+                metric.set_help(histogram.id().description().into());
+                metric.set_field_type(MetricType::HISTOGRAM);
+                metrics.push(metric);
+                last_id = Some(histogram.id());
+            }
+            metrics.last_mut().unwrap().mut_metric().push(m);
+        }
+
         TextEncoder::new().encode_to_string(&metrics).map_err(|e| {
             trc::EventType::Telemetry(trc::TelemetryEvent::OtelExporterError).reason(e)
         })
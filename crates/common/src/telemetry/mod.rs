@@ -36,6 +36,7 @@ impl Telemetry {
         Collector::set_interests(self.tracers.interests);
         Collector::update_custom_levels(self.tracers.levels);
         Collector::set_metrics(self.metrics);
+        Collector::set_collection_metrics(self.collection_metrics);
         Collector::reload();
     }
 
@@ -71,6 +72,7 @@ impl Telemetry {
         Collector::set_interests(self.tracers.interests);
         Collector::update_custom_levels(self.tracers.levels);
         Collector::set_metrics(self.metrics);
+        Collector::set_collection_metrics(self.collection_metrics);
         Collector::reload();
     }
 
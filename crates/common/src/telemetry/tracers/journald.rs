@@ -29,7 +29,7 @@ impl Subscriber {
             &[match event.inner.level {
                 Level::Error => self.priority_mappings.error as u8,
                 Level::Warn => self.priority_mappings.warn as u8,
-                Level::Info => self.priority_mappings.info as u8,
+                Level::Info | Level::Audit => self.priority_mappings.info as u8,
                 Level::Debug => self.priority_mappings.debug as u8,
                 Level::Trace | Level::Disable => self.priority_mappings.trace as u8,
             }],
@@ -198,6 +198,7 @@ fn build_log_record(event: &Event<EventDetails>) -> opentelemetry_sdk::logs::Log
         Level::Info => Severity::Info,
         Level::Warn => Severity::Warn,
         Level::Error => Severity::Error,
+        Level::Audit => Severity::Info,
         Level::Disable => Severity::Error,
     }
     .into();
@@ -53,6 +53,8 @@ pub struct ChangedPrincipals(AHashMap<u32, ChangedPrincipal>);
 pub struct ChangedPrincipal {
     pub typ: Type,
     pub member_change: bool,
+    /// Id the principal was known by before the directory reassigned it
+    pub previous_id: Option<u32>,
 }
 
 #[derive(Debug, Default, PartialEq, Eq)]
@@ -440,6 +442,15 @@ impl ManageDirectory for Store {
             }
         }
 
+        // Principals recreated under the name of a deleted principal take over its grants
+        let deleted_name_key = ValueClass::Directory(DirectoryClass::NameToDeletedId(
+            principal.name().as_bytes().to_vec(),
+        ));
+        let previous_id = self
+            .get_value::<u32>(ValueKey::from(deleted_name_key.clone()))
+            .await
+            .caused_by(trc::location!())?;
+
         // Write principal
         let mut batch = BatchBuilder::new();
         let pinfo_name = DynamicPrincipalInfo::new(principal.typ, tenant_id);
@@ -467,6 +478,9 @@ impl ManageDirectory for Store {
                 )),
                 pinfo_name,
             );
+        if previous_id.is_some() {
+            batch.clear(deleted_name_key);
+        }
 
         // Write email to id mapping
         if let Some(emails) = principal
@@ -515,12 +529,18 @@ impl ManageDirectory for Store {
             );
         }
 
+        let principal_type = principal.typ;
         self.write(batch.build())
             .await
             .and_then(|r| r.last_document_id())
-            .map(|id| CreatedPrincipal {
-                id,
-                changed_principals,
+            .map(|id| {
+                if let Some(previous_id) = previous_id {
+                    changed_principals.add_id_change(previous_id, id, principal_type);
+                }
+                CreatedPrincipal {
+                    id,
+                    changed_principals,
+                }
             })
     }
 
@@ -683,20 +703,29 @@ impl ManageDirectory for Store {
             );
         }
 
+        // Remove the ACL keys of documents shared with the principal
+        self.acl_purge_grantee(principal_id)
+            .await
+            .caused_by(trc::location!())?;
+
         // Delete principal data
         self.purge_account(principal_id)
             .await
             .caused_by(trc::location!())?;
 
-        // Delete principal
+        // Delete principal, remembering its id so that the grants it still holds
+        // can be moved to a principal later created with the same name
+        let name = principal
+            .take_str(PrincipalField::Name)
+            .unwrap_or_default()
+            .into_bytes();
         batch
             .with_account_id(principal_id)
-            .clear(DirectoryClass::NameToId(
-                principal
-                    .take_str(PrincipalField::Name)
-                    .unwrap_or_default()
-                    .into_bytes(),
-            ))
+            .clear(DirectoryClass::NameToId(name.clone()))
+            .set(
+                DirectoryClass::NameToDeletedId(name),
+                principal_id.serialize(),
+            )
             .clear(DirectoryClass::Principal(MaybeDynamicId::Static(
                 principal_id,
             )))
@@ -765,6 +794,7 @@ impl ManageDirectory for Store {
         self.write(batch.build())
             .await
             .caused_by(trc::location!())?;
+        self.reclaim_space_background(vec![principal_id]);
 
         changed_principals.add_deletion(principal_id, principal.typ);
 
@@ -2320,17 +2350,21 @@ impl ChangedPrincipals {
                 Type::Group | Type::Tenant | Type::Role,
                 Type::Individual | Type::Group | Type::Tenant | Type::Role,
             ) => {
+                // Members of nested groups inherit the memberships of their parents
+                let is_nested_group = principal_type == Type::Group && member_type == Type::Group;
                 if principal_id < ROLE_USER {
                     self.0
                         .entry(principal_id)
                         .or_insert_with(|| ChangedPrincipal::new(principal_type))
-                        .update_member_change(matches!(member_type, Type::Role));
+                        .update_member_change(is_nested_group || matches!(member_type, Type::Role));
                 }
                 if member_id < ROLE_USER {
                     self.0
                         .entry(member_id)
                         .or_insert_with(|| ChangedPrincipal::new(member_type))
-                        .update_member_change(matches!(principal_type, Type::Role));
+                        .update_member_change(
+                            is_nested_group || matches!(principal_type, Type::Role),
+                        );
                 }
             }
             _ => {}
@@ -2353,6 +2387,31 @@ impl ChangedPrincipals {
         }
     }
 
+    /// Records that the directory now reports `previous_id` as `principal_id`.
+    /// Both ids are marked as changed so that tokens issued for either one are
+    /// refreshed, and the stored ACLs granting access to `previous_id` have to be
+    /// moved to the new id, see `id_changes`.
+    pub fn add_id_change(&mut self, previous_id: u32, principal_id: u32, principal_type: Type) {
+        if previous_id != principal_id {
+            self.add_deletion(previous_id, principal_type);
+            self.0
+                .entry(principal_id)
+                .or_insert_with(|| ChangedPrincipal::new(principal_type))
+                .previous_id = Some(previous_id);
+        }
+    }
+
+    /// Returns the previous and current ids of the principals whose id changed.
+    pub fn id_changes(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        self.0
+            .iter()
+            .filter_map(|(principal_id, changed_principal)| {
+                changed_principal
+                    .previous_id
+                    .map(|previous_id| (previous_id, *principal_id))
+            })
+    }
+
     pub fn contains(&self, principal_id: u32) -> bool {
         self.0.contains_key(&principal_id)
     }
@@ -2371,6 +2430,7 @@ impl ChangedPrincipal {
         Self {
             typ,
             member_change: false,
+            previous_id: None,
         }
     }
 
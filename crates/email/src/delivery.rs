@@ -153,6 +153,13 @@ impl MailDelivery for Server {
                 };
             }
             Err(err) => {
+                // Delivery is retried later whatever the error, but an unavailable
+                // blob store is expected to recover on its own
+                let reason = if err.is_blob_unavailable() {
+                    "Blob store temporarily unavailable."
+                } else {
+                    "Temporary I/O error."
+                };
                 trc::error!(err
                     .details("Failed to fetch message blob.")
                     .span_id(message.session_id)
@@ -161,7 +168,7 @@ impl MailDelivery for Server {
                 return LocalDeliveryResult {
                     status: (0..message.recipients.len())
                         .map(|_| LocalDeliveryStatus::TemporaryFailure {
-                            reason: "Temporary I/O error.".into(),
+                            reason: reason.into(),
                         })
                         .collect::<Vec<_>>(),
                     autogenerated: vec![],
@@ -34,6 +34,7 @@ use std::future::Future;
 use store::rand::Rng;
 use store::{
     ahash::AHashSet,
+    dispatch::blob::BlobHint,
     query::Filter,
     write::{
         log::{ChangeLogBuilder, Changes, LogInsert},
@@ -442,7 +443,7 @@ impl EmailIngest for Server {
 
         // Store blob
         let blob_id = self
-            .put_blob(account_id, raw_message.as_ref(), false)
+            .put_blob_with_hint(account_id, raw_message.as_ref(), false, BlobHint::Message)
             .await
             .caused_by(trc::location!())?;
 
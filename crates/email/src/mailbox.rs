@@ -20,8 +20,8 @@ use store::{
     rand,
     roaring::RoaringBitmap,
     write::{
-        BatchBuilder, BitmapClass, DeserializeFrom, MaybeDynamicId, Operation, SerializeInto,
-        TagValue, ToBitmaps,
+        account::AccountInit, BatchBuilder, BitmapClass, DeserializeFrom, MaybeDynamicId,
+        Operation, SerializeInto, TagValue, ToBitmaps,
     },
     Serialize, U32_LEN,
 };
@@ -128,10 +128,7 @@ impl MailboxFnc for Server {
             return Ok(mailbox_ids);
         }
 
-        let mut batch = BatchBuilder::new();
-        batch
-            .with_account_id(account_id)
-            .with_collection(Collection::Mailbox);
+        let mut init = AccountInit::new();
 
         // Create mailboxes
         let mut last_document_id = ARCHIVE_ID;
@@ -166,16 +163,18 @@ impl MailboxFnc for Server {
                     Value::List(vec![Value::Id(account_id.into())]),
                 );
             }
-            batch
-                .create_document_with_id(document_id)
-                .custom(ObjectIndexBuilder::new(SCHEMA).with_changes(object));
+            init = init.with_document(
+                Collection::Mailbox,
+                document_id,
+                ObjectIndexBuilder::new(SCHEMA).with_changes(object),
+            );
             mailbox_ids.insert(document_id);
         }
 
         self.core
             .storage
             .data
-            .write(batch.build())
+            .initialize_account(account_id, init)
             .await
             .caused_by(trc::location!())
             .map(|_| mailbox_ids)
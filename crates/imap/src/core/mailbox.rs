@@ -138,7 +138,7 @@ impl<T: SessionStream> SessionData<T> {
                 .caused_by(trc::location!())?
         } else {
             self.server
-                .shared_documents(access_token, account_id, Collection::Mailbox, Acl::Read)
+                .visible_shared_mailboxes(access_token, account_id)
                 .await
                 .caused_by(trc::location!())?
         };
@@ -641,7 +641,15 @@ impl<T: SessionStream> SessionData<T> {
                     Property::Value,
                 )
                 .await?
-                .map(|mailbox| mailbox.effective_acl(&access_token).contains(item))
+                .map(|mailbox| {
+                    mailbox
+                        .effective_acl(
+                            &access_token,
+                            account_id,
+                            self.server.core.jmap.acl_evaluation,
+                        )
+                        .contains(item)
+                })
                 .ok_or_else(|| {
                     trc::ImapEvent::Error
                         .caused_by(trc::location!())
@@ -10,12 +10,14 @@ use ahash::AHashMap;
 use common::{NextMailboxState, listener::SessionStream};
 use email::mailbox::UidMailbox;
 use imap_proto::protocol::{Sequence, expunge, select::Exists};
+use jmap::auth::acl::AclMethods;
 use jmap_proto::{
     object::Object,
-    types::{collection::Collection, property::Property, value::Value},
+    types::{acl::Acl, collection::Collection, property::Property, value::Value},
 };
 use store::{
     ValueKey,
+    roaring::RoaringBitmap,
     write::{ValueClass, assert::HashedValue},
 };
 use trc::AddContext;
@@ -27,9 +29,10 @@ use super::{ImapUidToId, MailboxId, MailboxState, SelectedMailbox, SessionData};
 pub(crate) const MAX_RETRIES: usize = 10;
 
 impl<T: SessionStream> SessionData<T> {
-    pub async fn fetch_messages(&self, mailbox: &MailboxId) -> trc::Result<MailboxState> {
-        // Obtain message ids
-        let message_ids = self
+    /// Returns the ids of the messages in a mailbox visible to the session.
+    /// Grants restricted by criteria only expose the messages matching them.
+    pub async fn mailbox_message_ids(&self, mailbox: &MailboxId) -> trc::Result<RoaringBitmap> {
+        let mut message_ids = self
             .server
             .get_tag(
                 mailbox.account_id,
@@ -39,6 +42,23 @@ impl<T: SessionStream> SessionData<T> {
             )
             .await?
             .unwrap_or_default();
+        if !message_ids.is_empty() {
+            let access_token = self.get_access_token().await?;
+            if !access_token.is_member(mailbox.account_id) {
+                message_ids &= self
+                    .server
+                    .shared_messages(&access_token, mailbox.account_id, Acl::Read)
+                    .await
+                    .caused_by(trc::location!())?;
+            }
+        }
+
+        Ok(message_ids)
+    }
+
+    pub async fn fetch_messages(&self, mailbox: &MailboxId) -> trc::Result<MailboxState> {
+        // Obtain message ids
+        let message_ids = self.mailbox_message_ids(mailbox).await?;
 
         // Obtain UID validity and UID next
         let uid_validity = self.get_uid_validity(mailbox).await?;
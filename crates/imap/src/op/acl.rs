@@ -20,11 +20,14 @@ use imap_proto::{
     Command, ResponseCode, StatusResponse,
 };
 
-use jmap::auth::acl::EffectiveAcl;
+use jmap::auth::acl::{check_delegated_acl, EffectiveAcl};
 use jmap_proto::{
     object::{index::ObjectIndexBuilder, Object},
     types::{
-        acl::Acl,
+        acl::{
+            acl_diff, audit_changed_grants, cascade_revocations, log_acl_changes,
+            log_grantee_acl_changes, track_grantors, Acl,
+        },
         collection::Collection,
         property::Property,
         state::StateChange,
@@ -111,7 +114,10 @@ impl<T: SessionStream> Session<T> {
                                 Acl::Submit => {
                                     rights.push(Rights::Post);
                                 }
-                                Acl::None => (),
+                                Acl::Lookup => {
+                                    rights.push(Rights::Lookup);
+                                }
+                                Acl::ManageShares | Acl::Inherit | Acl::Owner | Acl::None => (),
                             }
                         }
 
@@ -160,11 +166,17 @@ impl<T: SessionStream> Session<T> {
                 .await
                 .imap_ctx(&arguments.tag, trc::location!())?;
             let rights = if access_token.is_shared(mailbox.account_id) {
-                let acl = values.inner.effective_acl(&access_token);
+                let acl = values.inner.effective_acl(
+                    &access_token,
+                    mailbox.account_id,
+                    data.server.core.jmap.acl_evaluation,
+                );
                 let mut rights = Vec::with_capacity(5);
                 if acl.contains(Acl::ReadItems) {
                     rights.push(Rights::Read);
                     rights.push(Rights::Lookup);
+                } else if acl.contains(Acl::Lookup) {
+                    rights.push(Rights::Lookup);
                 }
                 if acl.contains(Acl::AddItems) {
                     rights.push(Rights::Insert);
@@ -241,8 +253,8 @@ impl<T: SessionStream> Session<T> {
 
         spawn_op!(data, {
             // Validate mailbox
-            let (mailbox, values, _) = data
-                .get_acl_mailbox(&arguments, false)
+            let (mailbox, values, access_token) = data
+                .get_acl_mailbox(&arguments, true)
                 .await
                 .imap_ctx(&arguments.tag, trc::location!())?;
 
@@ -322,15 +334,46 @@ impl<T: SessionStream> Session<T> {
             } else if !rights.is_empty() {
                 match op {
                     ModRightsOp::Add | ModRightsOp::Replace => {
-                        acl.push(AclGrant {
-                            account_id: acl_account_id,
-                            grants: rights,
-                        });
+                        acl.push(AclGrant::new(acl_account_id, rights));
                     }
                     ModRightsOp::Remove => (),
                 }
             }
 
+            // Delegates holding ManageShares cannot grant or revoke administer rights
+            // nor change their own rights
+            let current_acl = match values.inner.properties.get(&Property::Acl) {
+                Some(Value::Acl(acl)) => acl.as_slice(),
+                _ => &[],
+            };
+            if let Err(reason) = check_delegated_acl(
+                &access_token,
+                mailbox.account_id,
+                values.inner.effective_acl(
+                    &access_token,
+                    mailbox.account_id,
+                    data.server.core.jmap.acl_evaluation,
+                ),
+                current_acl,
+                acl,
+            ) {
+                return Err(trc::ImapEvent::Error
+                    .into_err()
+                    .details(reason)
+                    .code(ResponseCode::NoPerm)
+                    .id(arguments.tag));
+            }
+
+            // Record who created each grant and drop the grants derived from revoked principals
+            track_grantors(
+                acl,
+                current_acl,
+                (!access_token.is_member(mailbox.account_id)).then_some(access_token.primary_id),
+            );
+            let revoked_ids = cascade_revocations(acl, current_acl);
+            let diff = acl_diff(current_acl, acl);
+            let audit = (!diff.is_empty()).then(|| audit_changed_grants(&diff));
+
             let grants = acl
                 .iter()
                 .map(|r| trc::Value::from(r.account_id))
@@ -338,16 +381,28 @@ impl<T: SessionStream> Session<T> {
 
             // Write changes
             let mailbox_id = mailbox.mailbox_id;
+            let builder = ObjectIndexBuilder::new(SCHEMA)
+                .with_changes(changes)
+                .with_current(values);
+            let acl_changes = builder.acl_changes();
             let mut batch = BatchBuilder::new();
             batch
                 .with_account_id(mailbox.account_id)
                 .with_collection(Collection::Mailbox)
                 .update_document(mailbox_id)
-                .custom(
-                    ObjectIndexBuilder::new(SCHEMA)
-                        .with_changes(changes)
-                        .with_current(values),
+                .custom(builder);
+            if !acl_changes.is_empty() {
+                log_grantee_acl_changes(
+                    &mut batch,
+                    data.server
+                        .generate_snowflake_id()
+                        .imap_ctx(&arguments.tag, trc::location!())?,
+                    mailbox.account_id,
+                    Collection::Mailbox,
+                    mailbox_id,
+                    &acl_changes,
                 );
+            }
             if !batch.is_empty() {
                 data.server
                     .store()
@@ -356,6 +411,7 @@ impl<T: SessionStream> Session<T> {
                     .imap_ctx(&arguments.tag, trc::location!())?;
                 let mut changes = ChangeLogBuilder::new();
                 changes.log_update(Collection::Mailbox, mailbox_id);
+                log_acl_changes(&mut changes, Collection::Mailbox, mailbox_id, &acl_changes);
                 let change_id = data
                     .server
                     .commit_changes(mailbox.account_id, changes)
@@ -370,14 +426,34 @@ impl<T: SessionStream> Session<T> {
             }
 
             // Invalidate ACLs
-            data.server
-                .increment_token_revision(ChangedPrincipals::from_change(
-                    acl_account_id,
+            let mut changed_principals = ChangedPrincipals::from_change(
+                acl_account_id,
+                Type::Individual,
+                PrincipalField::EnabledPermissions,
+            );
+            for account_id in revoked_ids {
+                changed_principals.add_change(
+                    account_id,
                     Type::Individual,
                     PrincipalField::EnabledPermissions,
-                ))
+                );
+            }
+            data.server
+                .increment_token_revision(changed_principals)
                 .await;
 
+            if let Some(audit) = audit {
+                trc::event!(
+                    Security(trc::SecurityEvent::AclChanged),
+                    SpanId = data.session_id,
+                    Id = access_token.primary_id,
+                    AccountId = mailbox.account_id,
+                    Collection = Collection::Mailbox,
+                    DocumentId = mailbox.mailbox_id,
+                    Details = audit
+                );
+            }
+
             trc::event!(
                 Imap(trc::ImapEvent::SetAcl),
                 SpanId = data.session_id,
@@ -466,11 +542,14 @@ impl<T: SessionStream> SessionData<T> {
             {
                 let access_token = self.get_access_token().await.caused_by(trc::location!())?;
                 if !validate
-                    || access_token.is_member(mailbox.account_id)
                     || values
                         .inner
-                        .effective_acl(&access_token)
-                        .contains(Acl::Administer)
+                        .effective_acl(
+                            &access_token,
+                            mailbox.account_id,
+                            self.server.core.jmap.acl_evaluation,
+                        )
+                        .contains_any([Acl::Administer, Acl::ManageShares].into_iter())
                 {
                     Ok((mailbox, values, access_token))
                 } else {
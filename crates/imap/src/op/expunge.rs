@@ -119,16 +119,9 @@ impl<T: SessionStream> SessionData<T> {
         // Obtain message ids
         let account_id = mailbox.id.account_id;
         let mut deleted_ids = self
-            .server
-            .get_tag(
-                account_id,
-                Collection::Email,
-                Property::MailboxIds,
-                mailbox.id.mailbox_id,
-            )
+            .mailbox_message_ids(&mailbox.id)
             .await
             .caused_by(trc::location!())?
-            .unwrap_or_default()
             & self
                 .server
                 .get_tag(
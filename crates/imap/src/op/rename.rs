@@ -119,7 +119,11 @@ impl<T: SessionStream> SessionData<T> {
         if access_token.is_shared(params.account_id)
             && !mailbox
                 .inner
-                .effective_acl(&access_token)
+                .effective_acl(
+                    &access_token,
+                    params.account_id,
+                    self.server.core.jmap.acl_evaluation,
+                )
                 .contains(Acl::Modify)
         {
             return Err(trc::ImapEvent::Error
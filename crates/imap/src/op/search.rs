@@ -260,16 +260,7 @@ impl<T: SessionStream> SessionData<T> {
     ) -> trc::Result<(ResultSet, bool)> {
         // Obtain message ids
         let mut filters = Vec::with_capacity(imap_filter.len() + 1);
-        let message_ids = self
-            .server
-            .get_tag(
-                mailbox.id.account_id,
-                Collection::Email,
-                Property::MailboxIds,
-                mailbox.id.mailbox_id,
-            )
-            .await?
-            .unwrap_or_default();
+        let message_ids = self.mailbox_message_ids(&mailbox.id).await?;
         filters.push(query::Filter::is_in_set(message_ids.clone()));
 
         // Convert query
@@ -229,17 +229,13 @@ impl<T: SessionStream> SessionData<T> {
         if !items_update.is_empty() {
             // Retrieve latest values
             let mut values_update = Vec::with_capacity(items_update.len());
-            let mailbox_message_ids = self
-                .server
-                .get_tag(
-                    mailbox.account_id,
-                    Collection::Email,
-                    Property::MailboxIds,
-                    mailbox.mailbox_id,
-                )
-                .await
-                .caused_by(trc::location!())?
-                .map(Arc::new);
+            let mailbox_message_ids = Some(
+                self.mailbox_message_ids(&mailbox)
+                    .await
+                    .caused_by(trc::location!())?,
+            )
+            .filter(|message_ids| !message_ids.is_empty())
+            .map(Arc::new);
             let message_ids = self
                 .server
                 .get_document_ids(mailbox.account_id, Collection::Email)
@@ -5,7 +5,7 @@
  */
 
 use ahash::AHashMap;
-use utils::map::{bitmap::Bitmap, vec_map::VecMap};
+use utils::map::vec_map::VecMap;
 
 use crate::{
     error::set::{InvalidProperty, SetError},
@@ -18,7 +18,7 @@ use crate::{
     },
     response::Response,
     types::{
-        acl::Acl,
+        acl::AclRights,
         any_id::AnyId,
         blob::BlobId,
         date::UTCDate,
@@ -287,13 +287,12 @@ impl JsonObjectParser for Object<SetValue> {
                             let mut acls = Vec::new();
                             while let Some(account) = parser.next_dict_key::<String>()? {
                                 acls.push(Value::Text(account));
-                                acls.push(Value::UnsignedInt(<Bitmap<Acl>>::parse(parser)?.into()));
+                                acls.push(AclRights::parse(parser)?.into_value());
                             }
                             SetValue::Value(Value::List(acls))
                         }
                         1 => {
-                            key.patch
-                                .push(Value::UnsignedInt(<Bitmap<Acl>>::parse(parser)?.into()));
+                            key.patch.push(AclRights::parse(parser)?.into_value());
                             SetValue::Patch(key.patch)
                         }
                         2 => {
@@ -16,7 +16,12 @@ use store::{
 
 use crate::{
     error::set::SetError,
-    types::{id::Id, property::Property, value::Value},
+    types::{
+        acl::{acl_diff, AclChange},
+        id::Id,
+        property::Property,
+        value::Value,
+    },
 };
 
 use super::Object;
@@ -134,6 +139,44 @@ impl ObjectIndexBuilder {
     pub fn current(&self) -> Option<&HashedValue<Object<Value>>> {
         self.current.as_ref()
     }
+
+    /// Principals whose access to the object is granted, modified or revoked by
+    /// this change. Deleting the object revokes all its grants.
+    pub fn acl_changes(&self) -> Vec<(u32, AclChange)> {
+        let current = match self
+            .current
+            .as_ref()
+            .map(|current| current.inner.get(&Property::Acl))
+        {
+            Some(Value::Acl(acl)) => acl.as_slice(),
+            _ => &[],
+        };
+        let changes = match self
+            .changes
+            .as_ref()
+            .map(|changes| changes.properties.get(&Property::Acl))
+        {
+            Some(Some(Value::Acl(acl))) => acl.as_slice(),
+            Some(None) => return Vec::new(),
+            _ => &[],
+        };
+
+        let diff = acl_diff(current, changes);
+        diff.removed
+            .iter()
+            .map(|grant| (grant.account_id, AclChange::Revoked))
+            .chain(
+                diff.changed
+                    .iter()
+                    .map(|(grant, _)| (grant.account_id, AclChange::Modified)),
+            )
+            .chain(
+                diff.added
+                    .iter()
+                    .map(|grant| (grant.account_id, AclChange::Granted)),
+            )
+            .collect()
+    }
 }
 
 impl IntoOperations for ObjectIndexBuilder {
@@ -377,7 +420,7 @@ fn merge_batch(
                                 let mut add_item = true;
                                 for current_item in current_value {
                                     if item.account_id == current_item.account_id {
-                                        if item.grants == current_item.grants {
+                                        if item == current_item {
                                             add_item = false;
                                         }
                                         break;
@@ -386,7 +429,7 @@ fn merge_batch(
                                 if add_item {
                                     batch.ops.push(Operation::acl(
                                         item.account_id,
-                                        item.grants.bitmap.serialize().into(),
+                                        item.index_value().into(),
                                     ));
                                 }
                             }
@@ -396,7 +439,7 @@ fn merge_batch(
                             for item in values {
                                 batch.ops.push(Operation::acl(
                                     item.account_id,
-                                    item.grants.bitmap.serialize().into(),
+                                    item.index_value().into(),
                                 ));
                             }
                         }
@@ -530,11 +573,7 @@ fn build_batch(
                 for item in values {
                     batch.ops.push(Operation::acl(
                         item.account_id,
-                        if set {
-                            item.grants.bitmap.serialize().into()
-                        } else {
-                            None
-                        },
+                        if set { item.index_value().into() } else { None },
                     ));
                 }
             }
@@ -15,11 +15,11 @@ use std::slice::Iter;
 
 use store::{
     write::{DeserializeFrom, SerializeInto, ToBitmaps},
-    Deserialize, Serialize, U64_LEN,
+    Deserialize, Serialize,
 };
 use utils::{
     codec::leb128::{Leb128Iterator, Leb128Vec},
-    map::{bitmap::Bitmap, vec_map::VecMap},
+    map::vec_map::VecMap,
 };
 
 use crate::types::{
@@ -165,28 +165,6 @@ impl SerializeInto for Object<Value> {
     }
 }
 
-impl SerializeInto for AclGrant {
-    fn serialize_into(&self, buf: &mut Vec<u8>) {
-        buf.push_leb128(self.account_id);
-        buf.extend_from_slice(self.grants.bitmap.to_be_bytes().as_slice());
-    }
-}
-
-impl DeserializeFrom for AclGrant {
-    fn deserialize_from(bytes: &mut Iter<'_, u8>) -> Option<Self> {
-        let account_id = bytes.next_leb128()?;
-        let mut grants = [0u8; U64_LEN];
-        for byte in grants.iter_mut() {
-            *byte = *bytes.next()?;
-        }
-
-        Some(Self {
-            account_id,
-            grants: Bitmap::from(u64::from_be_bytes(grants)),
-        })
-    }
-}
-
 impl DeserializeFrom for Object<Value> {
     fn deserialize_from(bytes: &mut Iter<'_, u8>) -> Option<Object<Value>> {
         let len = bytes.next_leb128()?;
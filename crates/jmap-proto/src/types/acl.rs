@@ -4,11 +4,28 @@
  * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
  */
 
-use std::fmt::{self, Display};
+use std::{
+    fmt::{self, Display},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
 
-use utils::map::bitmap::BitmapItem;
+use ahash::AHashMap;
+use store::{
+    write::{log::ChangeLogBuilder, now, BatchBuilder, DeserializeFrom, SerializeInto},
+    Deserialize, U32_LEN, U64_LEN,
+};
+use utils::{
+    codec::leb128::{Leb128Iterator, Leb128Vec},
+    map::bitmap::{Bitmap, BitmapItem},
+};
 
-use crate::parser::{json::Parser, JsonObjectParser};
+use crate::parser::{json::Parser, JsonObjectParser, Token};
+
+use super::{
+    date::UTCDate,
+    value::{AclGrant, Value},
+};
 
 #[derive(Debug, Eq, PartialEq, PartialOrd, Ord, Hash, Clone, Copy)]
 #[repr(u8)]
@@ -23,7 +40,15 @@ pub enum Acl {
     CreateChild = 7,
     Administer = 8,
     Submit = 9,
-    None = 10,
+    Lookup = 10,
+    ManageShares = 11,
+    /// Synthesized by `effective_acl` for members of the owning account, it is
+    /// not accepted from clients and never stored in a grant.
+    Owner = 12,
+    /// Marks a mailbox grant as inherited by the child mailboxes that have no
+    /// grant of their own to the same principal.
+    Inherit = 13,
+    None = 14,
 }
 
 impl JsonObjectParser for Acl {
@@ -54,12 +79,34 @@ impl JsonObjectParser for Acl {
             0x0064_6c69_6843_6574_6165_7263 => Ok(Acl::CreateChild),
             0x7265_7473_696e_696d_6461 => Ok(Acl::Administer),
             0x7469_6d62_7573 => Ok(Acl::Submit),
+            0x7075_6b6f_6f6c => Ok(Acl::Lookup),
+            0x7365_7261_6853_6567_616e_616d => Ok(Acl::ManageShares),
+            0x0074_6972_6568_6e69 => Ok(Acl::Inherit),
             _ => Err(parser.error_value()),
         }
     }
 }
 
 impl Acl {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "read" => Some(Acl::Read),
+            "modify" => Some(Acl::Modify),
+            "delete" => Some(Acl::Delete),
+            "readItems" => Some(Acl::ReadItems),
+            "addItems" => Some(Acl::AddItems),
+            "modifyItems" => Some(Acl::ModifyItems),
+            "removeItems" => Some(Acl::RemoveItems),
+            "createChild" => Some(Acl::CreateChild),
+            "administer" => Some(Acl::Administer),
+            "submit" => Some(Acl::Submit),
+            "lookup" => Some(Acl::Lookup),
+            "manageShares" => Some(Acl::ManageShares),
+            "inherit" => Some(Acl::Inherit),
+            _ => None,
+        }
+    }
+
     fn as_str(&self) -> &'static str {
         match self {
             Acl::Read => "read",
@@ -72,6 +119,10 @@ impl Acl {
             Acl::CreateChild => "createChild",
             Acl::Administer => "administer",
             Acl::Submit => "submit",
+            Acl::Lookup => "lookup",
+            Acl::ManageShares => "manageShares",
+            Acl::Owner => "owner",
+            Acl::Inherit => "inherit",
             Acl::None => "",
         }
     }
@@ -121,11 +172,918 @@ impl From<u64> for Acl {
             7 => Acl::CreateChild,
             8 => Acl::Administer,
             9 => Acl::Submit,
+            10 => Acl::Lookup,
+            11 => Acl::ManageShares,
+            12 => Acl::Owner,
+            13 => Acl::Inherit,
             _ => Acl::None,
         }
     }
 }
 
+// Set on the serialized grants bitmap when grant extensions follow
+const GRANT_EXTENDED: u64 = 1 << 63;
+const GRANT_EXT_SCHEDULE: u8 = 1;
+const GRANT_EXT_CRITERIA: u8 = 2;
+const GRANT_EXT_GRANTED_BY: u8 = 3;
+const GRANT_EXT_EXPIRES: u8 = 4;
+const GRANT_EXT_NETWORKS: u8 = 5;
+// Rights that are never written to the store
+const GRANT_SYNTHESIZED: u64 = 1 << Acl::Owner as u64;
+
+/// Rights and grant modifiers (such as `schedule:mon-fri/09:00-17:00`) as
+/// received in an ACL set request.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct AclRights {
+    pub grants: Bitmap<Acl>,
+    pub modifiers: Vec<String>,
+}
+
+/// Weekly time window during which a grant is active.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize)]
+pub struct AclSchedule {
+    /// Bit 0 is Monday, bit 6 is Sunday
+    pub weekdays: u8,
+    /// Minutes after midnight, local time
+    pub start: u16,
+    pub end: u16,
+    /// Offset from UTC in minutes
+    pub utc_offset: i16,
+}
+
+/// Restricts a mailbox grant to the messages matching all of the criteria.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Hash, serde::Serialize)]
+pub struct AclCriteria {
+    /// Lowercase sender address
+    pub from: Option<String>,
+    /// Messages received after this timestamp
+    pub after: Option<u64>,
+    /// Messages received before this timestamp
+    pub before: Option<u64>,
+}
+
+/// Network in CIDR notation a grant is restricted to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize)]
+pub struct AclNetwork {
+    pub addr: IpAddr,
+    pub prefix: u8,
+}
+
+const WEEKDAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+impl AclSchedule {
+    pub fn is_active(&self, timestamp: u64) -> bool {
+        let local = timestamp as i64 + self.utc_offset as i64 * 60;
+        let minute = (local.rem_euclid(86400) / 60) as u16;
+        // 1970-01-01 was a Thursday
+        let weekday = (local.div_euclid(86400) + 3).rem_euclid(7) as u8;
+        let has_day = |day: u8| self.weekdays & (1 << day) != 0;
+
+        if self.start <= self.end {
+            has_day(weekday) && minute >= self.start && minute < self.end
+        } else {
+            // Overnight windows belong to the day they start on
+            (has_day(weekday) && minute >= self.start)
+                || (has_day((weekday + 6) % 7) && minute < self.end)
+        }
+    }
+}
+
+impl AclGrant {
+    pub fn new(account_id: u32, grants: impl Into<Bitmap<Acl>>) -> Self {
+        AclGrant {
+            account_id,
+            grants: grants.into(),
+            schedule: None,
+            criteria: None,
+            granted_by: None,
+            expires: None,
+            networks: Vec::new(),
+        }
+    }
+
+    /// Returns whether the grant is currently in effect, grants without
+    /// restrictions are always active.
+    pub fn is_active(&self) -> bool {
+        let now = now();
+        self.expires.is_none_or(|expires| expires > now)
+            && self.schedule.is_none_or(|schedule| schedule.is_active(now))
+    }
+
+    /// Returns whether the grant is in effect for a client connecting from
+    /// `remote_ip`. Grants restricted to certain networks never apply when the
+    /// address of the client is unknown.
+    pub fn is_active_from(&self, remote_ip: Option<&IpAddr>) -> bool {
+        self.is_active()
+            && (self.networks.is_empty()
+                || remote_ip.is_some_and(|remote_ip| {
+                    self.networks
+                        .iter()
+                        .any(|network| network.contains(remote_ip))
+                }))
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires.is_some_and(|expires| expires <= now())
+    }
+
+    pub fn has_extensions(&self) -> bool {
+        self.schedule.is_some()
+            || self.criteria.is_some()
+            || self.granted_by.is_some()
+            || self.expires.is_some()
+            || !self.networks.is_empty()
+    }
+
+    /// Adds the rights of another grant to the same principal. Grants with
+    /// different schedules, criteria, expiry or networks can't be merged,
+    /// `false` is returned and the grant is left unchanged.
+    pub fn merge(&mut self, other: &AclGrant) -> bool {
+        if self.schedule == other.schedule
+            && self.criteria == other.criteria
+            && self.expires == other.expires
+            && self.networks == other.networks
+        {
+            self.grants.union(&other.grants);
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn set_modifier(&mut self, modifier: &str) -> Result<(), String> {
+        match modifier.split_once(':') {
+            Some(("schedule", schedule)) => {
+                self.schedule = Some(schedule.parse()?);
+                Ok(())
+            }
+            Some(("criteria", criteria)) => {
+                self.criteria = Some(criteria.parse()?);
+                Ok(())
+            }
+            Some(("expires", expires)) => {
+                self.expires = Some(
+                    parse_date(expires)
+                        .ok_or_else(|| format!("Invalid ACL expiry {expires:?}."))?,
+                );
+                Ok(())
+            }
+            Some(("network", network)) => {
+                let network = network.parse()?;
+                if !self.networks.contains(&network) {
+                    self.networks.push(network);
+                }
+                Ok(())
+            }
+            _ => Err(format!("Invalid ACL modifier {modifier:?}.")),
+        }
+    }
+
+    pub fn modifiers(&self) -> impl Iterator<Item = String> + '_ {
+        self.schedule
+            .iter()
+            .map(|schedule| format!("schedule:{schedule}"))
+            .chain(
+                self.criteria
+                    .iter()
+                    .map(|criteria| format!("criteria:{criteria}")),
+            )
+            .chain(
+                self.expires
+                    .map(|expires| format!("expires:{}", UTCDate::from_timestamp(expires as i64))),
+            )
+            .chain(
+                self.networks
+                    .iter()
+                    .map(|network| format!("network:{network}")),
+            )
+    }
+
+    fn stored_grants(&self) -> u64 {
+        self.grants.bitmap & !GRANT_SYNTHESIZED
+    }
+
+    /// Value stored in the ACL index, the grants bitmap followed by any extensions.
+    pub fn index_value(&self) -> Vec<u8> {
+        let mut value = Vec::with_capacity(U64_LEN);
+        value.extend_from_slice(&self.stored_grants().to_be_bytes());
+        self.serialize_extensions(&mut value);
+        value
+    }
+
+    /// Evaluates the extensions of an ACL index entry, returns `false` if
+    /// they could not be decoded.
+    pub fn extensions_active(extensions: &[u8]) -> bool {
+        extensions.is_empty()
+            || AclGrant::from_extensions(extensions).is_some_and(|grant| grant.is_active())
+    }
+
+    /// Decodes the extensions of an ACL index entry into an otherwise empty grant.
+    pub fn from_extensions(extensions: &[u8]) -> Option<Self> {
+        AclGrant::default().deserialize_extensions(extensions)
+    }
+
+    fn serialize_extensions(&self, buf: &mut Vec<u8>) {
+        if let Some(schedule) = &self.schedule {
+            buf.push(GRANT_EXT_SCHEDULE);
+            buf.push(schedule.weekdays);
+            buf.extend_from_slice(&schedule.start.to_be_bytes());
+            buf.extend_from_slice(&schedule.end.to_be_bytes());
+            buf.extend_from_slice(&schedule.utc_offset.to_be_bytes());
+        }
+        if let Some(criteria) = &self.criteria {
+            let from = criteria.from.as_deref().unwrap_or_default();
+            buf.push(GRANT_EXT_CRITERIA);
+            buf.push_leb128(from.len());
+            buf.extend_from_slice(from.as_bytes());
+            buf.extend_from_slice(&criteria.after.unwrap_or_default().to_be_bytes());
+            buf.extend_from_slice(&criteria.before.unwrap_or_default().to_be_bytes());
+        }
+        if let Some(granted_by) = self.granted_by {
+            buf.push(GRANT_EXT_GRANTED_BY);
+            buf.extend_from_slice(&granted_by.to_be_bytes());
+        }
+        if let Some(expires) = self.expires {
+            buf.push(GRANT_EXT_EXPIRES);
+            buf.extend_from_slice(&expires.to_be_bytes());
+        }
+        if !self.networks.is_empty() {
+            buf.push(GRANT_EXT_NETWORKS);
+            buf.push_leb128(self.networks.len());
+            for network in &self.networks {
+                buf.push(network.prefix);
+                match network.addr {
+                    IpAddr::V4(addr) => {
+                        buf.push(4);
+                        buf.extend_from_slice(&addr.octets());
+                    }
+                    IpAddr::V6(addr) => {
+                        buf.push(6);
+                        buf.extend_from_slice(&addr.octets());
+                    }
+                }
+            }
+        }
+    }
+
+    fn deserialize_extensions(mut self, bytes: &[u8]) -> Option<Self> {
+        let mut bytes = bytes.iter();
+        let next_u16 = |bytes: &mut std::slice::Iter<'_, u8>| {
+            Some(u16::from_be_bytes([*bytes.next()?, *bytes.next()?]))
+        };
+        let next_u64 = |bytes: &mut std::slice::Iter<'_, u8>| {
+            let mut value = [0u8; U64_LEN];
+            for byte in value.iter_mut() {
+                *byte = *bytes.next()?;
+            }
+            Some(u64::from_be_bytes(value))
+        };
+
+        while let Some(tag) = bytes.next() {
+            match *tag {
+                GRANT_EXT_SCHEDULE => {
+                    self.schedule = Some(AclSchedule {
+                        weekdays: *bytes.next()?,
+                        start: next_u16(&mut bytes)?,
+                        end: next_u16(&mut bytes)?,
+                        utc_offset: next_u16(&mut bytes)? as i16,
+                    });
+                }
+                GRANT_EXT_CRITERIA => {
+                    let len: usize = bytes.next_leb128()?;
+                    let from = std::str::from_utf8(bytes.as_slice().get(..len)?)
+                        .ok()?
+                        .to_string();
+                    if len > 0 {
+                        bytes.nth(len - 1)?;
+                    }
+                    let (after, before) = (next_u64(&mut bytes)?, next_u64(&mut bytes)?);
+                    self.criteria = Some(AclCriteria {
+                        from: (!from.is_empty()).then_some(from),
+                        after: (after != 0).then_some(after),
+                        before: (before != 0).then_some(before),
+                    });
+                }
+                GRANT_EXT_GRANTED_BY => {
+                    let mut granted_by = [0u8; U32_LEN];
+                    for byte in granted_by.iter_mut() {
+                        *byte = *bytes.next()?;
+                    }
+                    self.granted_by = Some(u32::from_be_bytes(granted_by));
+                }
+                GRANT_EXT_EXPIRES => {
+                    self.expires = Some(next_u64(&mut bytes)?);
+                }
+                GRANT_EXT_NETWORKS => {
+                    let count: usize = bytes.next_leb128()?;
+                    self.networks = Vec::with_capacity(count);
+                    for _ in 0..count {
+                        let prefix = *bytes.next()?;
+                        let addr = match *bytes.next()? {
+                            4 => {
+                                let mut addr = [0u8; 4];
+                                for byte in addr.iter_mut() {
+                                    *byte = *bytes.next()?;
+                                }
+                                IpAddr::V4(Ipv4Addr::from(addr))
+                            }
+                            6 => {
+                                let mut addr = [0u8; 16];
+                                for byte in addr.iter_mut() {
+                                    *byte = *bytes.next()?;
+                                }
+                                IpAddr::V6(Ipv6Addr::from(addr))
+                            }
+                            _ => return None,
+                        };
+                        self.networks.push(AclNetwork { addr, prefix });
+                    }
+                }
+                _ => return None,
+            }
+        }
+
+        Some(self)
+    }
+}
+
+/// Records `grantor` as the creator of the grants that were added or modified,
+/// unchanged grants keep their original grantor.
+pub fn track_grantors(changes: &mut [AclGrant], current: &[AclGrant], grantor: Option<u32>) {
+    for grant in changes {
+        grant.granted_by = match current
+            .iter()
+            .find(|item| item.account_id == grant.account_id)
+        {
+            Some(item)
+                if item.grants == grant.grants
+                    && item.schedule == grant.schedule
+                    && item.criteria == grant.criteria
+                    && item.expires == grant.expires
+                    && item.networks == grant.networks =>
+            {
+                item.granted_by
+            }
+            _ => grantor,
+        };
+    }
+}
+
+/// Removes the grants created by principals that were removed from the ACL or
+/// lost the right to share the object, along with any grants derived from those.
+/// Returns the principals whose grants were removed this way.
+pub fn cascade_revocations(changes: &mut Vec<AclGrant>, current: &[AclGrant]) -> Vec<u32> {
+    let can_share = |grant: &AclGrant| {
+        grant
+            .grants
+            .contains_any([Acl::Administer, Acl::ManageShares].into_iter())
+    };
+    let mut revoked = current
+        .iter()
+        .filter(|item| {
+            can_share(item)
+                && !changes
+                    .iter()
+                    .any(|change| change.account_id == item.account_id && can_share(change))
+        })
+        .map(|item| item.account_id)
+        .collect::<Vec<_>>();
+    let mut cascaded = Vec::new();
+
+    while let Some(grantor) = revoked.pop() {
+        changes.retain(|item| {
+            if item.granted_by == Some(grantor) {
+                cascaded.push(item.account_id);
+                revoked.push(item.account_id);
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    cascaded
+}
+
+/// Flag set on the collection under which the ACL changes of a collection's
+/// documents are recorded in the change log of the account owning them.
+pub const ACL_CHANGES_FLAG: u8 = 0x80;
+
+/// How the access of a principal to a document changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AclChange {
+    Granted,
+    Modified,
+    Revoked,
+}
+
+/// Collection of the change log recording the ACL changes of `collection`.
+pub fn acl_changes_collection(collection: impl Into<u8>) -> u8 {
+    collection.into() | ACL_CHANGES_FLAG
+}
+
+/// Identifies the grant of a principal on a document in the ACL change log.
+pub fn acl_change_id(account_id: u32, document_id: u32) -> u64 {
+    ((account_id as u64) << 32) | document_id as u64
+}
+
+/// Records ACL changes in the change log, so that they can be followed through
+/// `Store::changes` on the collection returned by `acl_changes_collection`.
+/// Granted access is logged as an insert, modified rights as an update and
+/// revoked access as a delete of the id returned by `acl_change_id`.
+pub fn log_acl_changes(
+    log: &mut ChangeLogBuilder,
+    collection: impl Into<u8>,
+    document_id: u32,
+    changes: &[(u32, AclChange)],
+) {
+    let collection = acl_changes_collection(collection);
+    for (account_id, change) in changes {
+        let id = acl_change_id(*account_id, document_id);
+        match change {
+            AclChange::Granted => log.log_insert(collection, id),
+            AclChange::Modified => log.log_update(collection, id),
+            AclChange::Revoked => log.log_delete(collection, id),
+        }
+    }
+}
+
+/// Records ACL changes in the change log of each grantee under `change_id`, so
+/// that principals can follow the documents shared with them. In a grantee's log
+/// the id returned by `acl_change_id` pairs the account owning the document with
+/// the document id. The entries are appended to `batch`, which is left pointing
+/// at the account of the last grantee.
+pub fn log_grantee_acl_changes(
+    batch: &mut BatchBuilder,
+    change_id: u64,
+    account_id: u32,
+    collection: impl Into<u8>,
+    document_id: u32,
+    changes: &[(u32, AclChange)],
+) {
+    let collection = acl_changes_collection(collection);
+    let id = acl_change_id(account_id, document_id);
+    for (grantee_id, change) in changes {
+        let mut log = ChangeLogBuilder::with_change_id(change_id);
+        match change {
+            AclChange::Granted => log.log_insert(collection, id),
+            AclChange::Modified => log.log_update(collection, id),
+            AclChange::Revoked => log.log_delete(collection, id),
+        }
+        batch.with_account_id(*grantee_id).custom(log);
+    }
+}
+
+/// Grants that differ between two versions of an ACL, see `acl_diff`.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct AclDiff<'x> {
+    /// Grants to principals that had no grant
+    pub added: Vec<&'x AclGrant>,
+    /// Grants of principals that no longer have one
+    pub removed: Vec<&'x AclGrant>,
+    /// Grants before and after the change of principals whose grant was modified
+    pub changed: Vec<(&'x AclGrant, &'x AclGrant)>,
+}
+
+/// Compares the grants of each principal in `current` and `new`. Principals
+/// listed more than once only have their first grant compared.
+pub fn acl_diff<'x>(current: &'x [AclGrant], new: &'x [AclGrant]) -> AclDiff<'x> {
+    let mut new_grants = AHashMap::with_capacity(new.len());
+    for grant in new {
+        new_grants.entry(grant.account_id).or_insert(grant);
+    }
+
+    let mut diff = AclDiff::default();
+    for current_grant in current {
+        match new_grants.remove(&current_grant.account_id) {
+            Some(new_grant) if new_grant == current_grant => (),
+            Some(new_grant) => diff.changed.push((current_grant, new_grant)),
+            None => diff.removed.push(current_grant),
+        }
+    }
+    diff.added = new
+        .iter()
+        .filter(|grant| new_grants.remove(&grant.account_id).is_some())
+        .collect();
+
+    diff
+}
+
+impl AclDiff<'_> {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+
+    /// Principals whose grant was added, removed or modified.
+    pub fn account_ids(&self) -> impl Iterator<Item = u32> + '_ {
+        self.added
+            .iter()
+            .chain(self.removed.iter())
+            .chain(self.changed.iter().map(|(current, _)| current))
+            .map(|grant| grant.account_id)
+    }
+}
+
+/// Describes an `AclDiff` for audit events, one entry per principal holding
+/// its id followed by the rights and modifiers it held before and after the
+/// change. Removed grants are listed first, then modified and added ones.
+pub fn audit_changed_grants(diff: &AclDiff<'_>) -> trc::Value {
+    let describe = |grant: Option<&AclGrant>| {
+        trc::Value::Array(
+            grant
+                .map(|grant| {
+                    grant
+                        .grants
+                        .map(|acl| trc::Value::Static(acl.as_str()))
+                        .chain(grant.modifiers().map(trc::Value::String))
+                        .collect()
+                })
+                .unwrap_or_default(),
+        )
+    };
+
+    trc::Value::Array(
+        diff.removed
+            .iter()
+            .map(|grant| (*grant, None))
+            .chain(
+                diff.changed
+                    .iter()
+                    .map(|(current, new)| (*current, Some(*new))),
+            )
+            .map(|(current, new)| {
+                trc::Value::Array(vec![
+                    current.account_id.into(),
+                    describe(Some(current)),
+                    describe(new),
+                ])
+            })
+            .chain(diff.added.iter().map(|grant| {
+                trc::Value::Array(vec![
+                    grant.account_id.into(),
+                    describe(None),
+                    describe(Some(*grant)),
+                ])
+            }))
+            .collect(),
+    )
+}
+
+impl SerializeInto for AclGrant {
+    fn serialize_into(&self, buf: &mut Vec<u8>) {
+        buf.push_leb128(self.account_id);
+        if self.has_extensions() {
+            let mut extensions = Vec::new();
+            self.serialize_extensions(&mut extensions);
+            buf.extend_from_slice(
+                (self.stored_grants() | GRANT_EXTENDED)
+                    .to_be_bytes()
+                    .as_slice(),
+            );
+            buf.push_leb128(extensions.len());
+            buf.extend_from_slice(&extensions);
+        } else {
+            buf.extend_from_slice(self.stored_grants().to_be_bytes().as_slice());
+        }
+    }
+}
+
+impl DeserializeFrom for AclGrant {
+    fn deserialize_from(bytes: &mut std::slice::Iter<'_, u8>) -> Option<Self> {
+        let account_id = bytes.next_leb128()?;
+        let mut grants = [0u8; U64_LEN];
+        for byte in grants.iter_mut() {
+            *byte = *bytes.next()?;
+        }
+        let grants = u64::from_be_bytes(grants);
+        let grant = AclGrant::new(
+            account_id,
+            Bitmap::from(grants & !(GRANT_EXTENDED | GRANT_SYNTHESIZED)),
+        );
+
+        if grants & GRANT_EXTENDED != 0 {
+            let len: usize = bytes.next_leb128()?;
+            let extensions = bytes.as_slice().get(..len)?;
+            let grant = grant.deserialize_extensions(extensions)?;
+            if len > 0 {
+                bytes.nth(len - 1)?;
+            }
+            Some(grant)
+        } else {
+            Some(grant)
+        }
+    }
+}
+
+impl Deserialize for AclGrant {
+    fn deserialize(bytes: &[u8]) -> trc::Result<Self> {
+        let grants = bytes
+            .get(..U64_LEN)
+            .and_then(|bytes| bytes.try_into().ok())
+            .map(u64::from_be_bytes)
+            .ok_or_else(|| trc::StoreEvent::DataCorruption.caused_by(trc::location!()))?;
+
+        AclGrant::new(0, Bitmap::from(grants))
+            .deserialize_extensions(&bytes[U64_LEN..])
+            .ok_or_else(|| trc::StoreEvent::DataCorruption.caused_by(trc::location!()))
+    }
+}
+
+impl AclRights {
+    /// Parses rights given either as a bitmap, optionally followed by modifiers,
+    /// or as a list of permission names and modifiers as returned by `acl_get`.
+    /// Returns `None` when a permission name is not known.
+    pub fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::UnsignedInt(grants) => Some(AclRights {
+                grants: Bitmap::from(*grants),
+                modifiers: Vec::new(),
+            }),
+            Value::List(values) => {
+                let mut values = values.iter().peekable();
+                let mut rights = AclRights::default();
+                if let Some(Value::UnsignedInt(grants)) = values.peek() {
+                    rights.grants = Bitmap::from(*grants);
+                    values.next();
+                }
+                for value in values {
+                    match value {
+                        Value::Text(item) => {
+                            if let Some(acl) = Acl::from_name(item) {
+                                rights.grants.insert(acl);
+                            } else if item.contains(':') {
+                                rights.modifiers.push(item.clone());
+                            } else {
+                                return None;
+                            }
+                        }
+                        _ => return None,
+                    }
+                }
+                Some(rights)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn into_value(self) -> Value {
+        if self.modifiers.is_empty() {
+            Value::UnsignedInt(self.grants.into())
+        } else {
+            let mut values = Vec::with_capacity(self.modifiers.len() + 1);
+            values.push(Value::UnsignedInt(self.grants.into()));
+            values.extend(self.modifiers.into_iter().map(Value::Text));
+            Value::List(values)
+        }
+    }
+}
+
+impl JsonObjectParser for AclRights {
+    fn parse(parser: &mut Parser<'_>) -> trc::Result<Self>
+    where
+        Self: Sized,
+    {
+        let mut rights = AclRights::default();
+        match parser.next_token::<String>()? {
+            Token::ArrayStart => loop {
+                match parser.next_token::<String>()? {
+                    Token::String(item) => {
+                        if let Some(acl) = Acl::from_name(&item) {
+                            rights.grants.insert(acl);
+                        } else if item.contains(':') {
+                            rights.modifiers.push(item);
+                        } else {
+                            return Err(parser.error_value());
+                        }
+                    }
+                    Token::Comma => (),
+                    Token::ArrayEnd => break Ok(rights),
+                    token => return Err(token.error("", "string")),
+                }
+            },
+            Token::Null => Ok(rights),
+            token => Err(token.error("", "array or null")),
+        }
+    }
+}
+
+impl FromStr for AclSchedule {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let err = || format!("Invalid ACL schedule {value:?}.");
+        let mut parts = value.split('/');
+        let (days, hours, offset) = (
+            parts.next().ok_or_else(err)?,
+            parts.next().ok_or_else(err)?,
+            parts.next(),
+        );
+        if parts.next().is_some() {
+            return Err(err());
+        }
+
+        let day = |name: &str| {
+            WEEKDAYS
+                .iter()
+                .position(|day| day.eq_ignore_ascii_case(name.trim()))
+                .ok_or_else(err)
+        };
+        let mut weekdays = 0u8;
+        for range in days.split(',') {
+            if let Some((from, to)) = range.split_once('-') {
+                let (from, to) = (day(from)?, day(to)?);
+                let mut day = from;
+                loop {
+                    weekdays |= 1 << day;
+                    if day == to {
+                        break;
+                    }
+                    day = (day + 1) % 7;
+                }
+            } else {
+                weekdays |= 1 << day(range)?;
+            }
+        }
+
+        let time = |time: &str| {
+            time.split_once(':')
+                .and_then(|(h, m)| Some((h.parse::<u16>().ok()?, m.parse::<u16>().ok()?)))
+                .filter(|(h, m)| *h <= 24 && *m < 60 && (*h < 24 || *m == 0))
+                .map(|(h, m)| h * 60 + m)
+                .ok_or_else(err)
+        };
+        let (start, end) = hours.split_once('-').ok_or_else(err)?;
+        let utc_offset = if let Some(offset) = offset {
+            let (sign, offset) = match offset.as_bytes().first() {
+                Some(b'+') => (1, &offset[1..]),
+                Some(b'-') => (-1, &offset[1..]),
+                _ => return Err(err()),
+            };
+            let offset = time(offset)?;
+            if offset > 14 * 60 {
+                return Err(err());
+            }
+            sign * offset as i16
+        } else {
+            0
+        };
+
+        Ok(AclSchedule {
+            weekdays,
+            start: time(start)?,
+            end: time(end)?,
+            utc_offset,
+        })
+    }
+}
+
+impl Display for AclSchedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut is_first = true;
+        let mut day = 0;
+        while day < 7 {
+            if self.weekdays & (1 << day) != 0 {
+                let mut last_day = day;
+                while last_day < 6 && self.weekdays & (1 << (last_day + 1)) != 0 {
+                    last_day += 1;
+                }
+                if !is_first {
+                    f.write_str(",")?;
+                }
+                is_first = false;
+                if last_day > day {
+                    write!(f, "{}-{}", WEEKDAYS[day], WEEKDAYS[last_day])?;
+                } else {
+                    f.write_str(WEEKDAYS[day])?;
+                }
+                day = last_day + 1;
+            } else {
+                day += 1;
+            }
+        }
+
+        write!(
+            f,
+            "/{:02}:{:02}-{:02}:{:02}",
+            self.start / 60,
+            self.start % 60,
+            self.end / 60,
+            self.end % 60
+        )?;
+        if self.utc_offset != 0 {
+            let offset = self.utc_offset.unsigned_abs();
+            write!(
+                f,
+                "/{}{:02}:{:02}",
+                if self.utc_offset < 0 { '-' } else { '+' },
+                offset / 60,
+                offset % 60
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for AclCriteria {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let err = || format!("Invalid ACL criteria {value:?}.");
+        let date = |date: &str| parse_date(date).ok_or_else(err);
+
+        let mut criteria = AclCriteria::default();
+        for item in value.split(';') {
+            match item.split_once('=').ok_or_else(err)? {
+                ("from", from) if from.contains('@') && criteria.from.is_none() => {
+                    criteria.from = Some(from.trim().to_lowercase());
+                }
+                ("after", after) if criteria.after.is_none() => {
+                    criteria.after = Some(date(after)?);
+                }
+                ("before", before) if criteria.before.is_none() => {
+                    criteria.before = Some(date(before)?);
+                }
+                _ => return Err(err()),
+            }
+        }
+
+        Ok(criteria)
+    }
+}
+
+impl Display for AclCriteria {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut items = Vec::with_capacity(3);
+        if let Some(from) = &self.from {
+            items.push(format!("from={from}"));
+        }
+        if let Some(after) = self.after {
+            items.push(format!("after={}", UTCDate::from_timestamp(after as i64)));
+        }
+        if let Some(before) = self.before {
+            items.push(format!("before={}", UTCDate::from_timestamp(before as i64)));
+        }
+        f.write_str(&items.join(";"))
+    }
+}
+
+impl AclNetwork {
+    pub fn contains(&self, remote_ip: &IpAddr) -> bool {
+        match (self.addr, remote_ip.to_canonical()) {
+            (IpAddr::V4(addr), IpAddr::V4(remote_ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.prefix as u32)
+                    .unwrap_or_default();
+                u32::from(addr) & mask == u32::from(remote_ip) & mask
+            }
+            (IpAddr::V6(addr), IpAddr::V6(remote_ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.prefix as u32)
+                    .unwrap_or_default();
+                u128::from(addr) & mask == u128::from(remote_ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for AclNetwork {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let err = || format!("Invalid ACL network {value:?}.");
+        let (addr, prefix) = value.split_once('/').unwrap_or((value, ""));
+        let addr = addr.parse::<IpAddr>().map_err(|_| err())?.to_canonical();
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = if prefix.is_empty() {
+            max_prefix
+        } else {
+            prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix| *prefix <= max_prefix)
+                .ok_or_else(err)?
+        };
+
+        Ok(AclNetwork { addr, prefix })
+    }
+}
+
+impl Display for AclNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+fn parse_date(date: &str) -> Option<u64> {
+    Parser::new(format!("\"{date}\"").as_bytes())
+        .next_token::<UTCDate>()
+        .ok()
+        .and_then(|token| token.unwrap_string("").ok())
+        .filter(|date| date.is_valid() && date.timestamp() > 0)
+        .map(|date| date.timestamp() as u64)
+}
+
 /*impl SerializeInto for Acl {
     fn serialize_into(&self, buf: &mut Vec<u8>) {
         buf.push(*self as u8);
@@ -145,7 +1103,433 @@ impl DeserializeFrom for Acl {
             7 => Some(Acl::CreateChild),
             8 => Some(Acl::Administer),
             9 => Some(Acl::Submit),
+            10 => Some(Acl::Lookup),
+            11 => Some(Acl::ManageShares),
             _ => None,
         }
     }
 }*/
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        parser::json::Parser,
+        types::{
+            acl::{
+                acl_diff, audit_changed_grants, cascade_revocations, track_grantors, Acl,
+                AclCriteria, AclNetwork, AclRights, AclSchedule,
+            },
+            value::{AclGrant, Value},
+        },
+    };
+    use store::{
+        write::{now, DeserializeFrom, SerializeInto},
+        Deserialize, U64_LEN,
+    };
+
+    #[test]
+    fn acl_schedule() {
+        // Business hours in UTC+02:00
+        let schedule: AclSchedule = "mon-fri/09:00-17:00/+02:00".parse().unwrap();
+        assert_eq!(schedule.weekdays, 0b0001_1111);
+        assert_eq!(schedule.to_string(), "mon-fri/09:00-17:00/+02:00");
+
+        // 2024-01-03 (Wednesday) 08:30 UTC = 10:30 local
+        assert!(schedule.is_active(1704270600));
+        // 2024-01-03 (Wednesday) 15:30 UTC = 17:30 local
+        assert!(!schedule.is_active(1704295800));
+        // 2024-01-03 (Wednesday) 06:30 UTC = 08:30 local
+        assert!(!schedule.is_active(1704263400));
+        // 2024-01-06 (Saturday) 08:30 UTC = 10:30 local
+        assert!(!schedule.is_active(1704529800));
+
+        // Overnight window starting on Friday
+        let schedule: AclSchedule = "fri/22:00-06:00".parse().unwrap();
+        // 2024-01-05 (Friday) 23:00 UTC
+        assert!(schedule.is_active(1704495600));
+        // 2024-01-06 (Saturday) 05:00 UTC
+        assert!(schedule.is_active(1704517200));
+        // 2024-01-06 (Saturday) 23:00 UTC
+        assert!(!schedule.is_active(1704582000));
+
+        for invalid in [
+            "",
+            "mon",
+            "xyz/09:00-17:00",
+            "mon/25:00-17:00",
+            "mon/09:00-17:00/01:00",
+            "mon/09:00-17:00/+01:00/extra",
+        ] {
+            assert!(invalid.parse::<AclSchedule>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn acl_names() {
+        for id in 0..Acl::None as u64 {
+            let acl = Acl::from(id);
+            let name = acl.to_string();
+            if acl == Acl::Owner {
+                assert_eq!(Acl::from_name(&name), None);
+                assert!(Parser::new(format!("\"{name}\"").as_bytes())
+                    .next_token::<Acl>()
+                    .is_err());
+                continue;
+            }
+            assert_eq!(Acl::from_name(&name), Some(acl));
+            assert_eq!(
+                Parser::new(format!("\"{name}\"").as_bytes())
+                    .next_token::<Acl>()
+                    .unwrap()
+                    .unwrap_string("")
+                    .unwrap(),
+                acl
+            );
+        }
+        assert_eq!(Acl::from(11), Acl::ManageShares);
+        assert_eq!(Acl::from_name("manageShares"), Some(Acl::ManageShares));
+        assert_eq!(Acl::from(13), Acl::Inherit);
+        assert_eq!(Acl::from_name("inherit"), Some(Acl::Inherit));
+
+        let grant = AclGrant::new(5, vec![Acl::Read, Acl::ManageShares]);
+        assert!(grant.grants.contains(Acl::ManageShares));
+        assert!(!grant.grants.contains(Acl::Administer));
+        let mut buf = Vec::new();
+        grant.serialize_into(&mut buf);
+        assert_eq!(AclGrant::deserialize_from(&mut buf.iter()), Some(grant));
+
+        // Owner rights are dropped when serializing
+        let grant = AclGrant::new(5, vec![Acl::Read, Acl::Owner]);
+        let mut buf = Vec::new();
+        grant.serialize_into(&mut buf);
+        assert_eq!(
+            AclGrant::deserialize_from(&mut buf.iter()),
+            Some(AclGrant::new(5, vec![Acl::Read]))
+        );
+        assert!(!AclGrant::deserialize(&grant.index_value())
+            .unwrap()
+            .grants
+            .contains(Acl::Owner));
+    }
+
+    #[test]
+    fn acl_cascade_revocations() {
+        let grant = |account_id: u32, grants: Vec<Acl>, granted_by: Option<u32>| AclGrant {
+            granted_by,
+            ..AclGrant::new(account_id, grants)
+        };
+        let current = vec![
+            grant(1, vec![Acl::Read, Acl::ManageShares], None),
+            grant(2, vec![Acl::Read, Acl::ManageShares], Some(1)),
+            grant(3, vec![Acl::Read], Some(2)),
+            grant(4, vec![Acl::Read], None),
+        ];
+
+        // Grantors are kept for unchanged grants
+        let mut changes = vec![
+            grant(2, vec![Acl::Read, Acl::ManageShares], None),
+            grant(3, vec![Acl::Read, Acl::ReadItems], None),
+            grant(5, vec![Acl::Read], None),
+        ];
+        track_grantors(&mut changes, &current, Some(2));
+        assert_eq!(
+            changes
+                .iter()
+                .map(|item| item.granted_by)
+                .collect::<Vec<_>>(),
+            vec![Some(1), Some(2), Some(2)]
+        );
+        let mut buf = Vec::new();
+        changes[0].serialize_into(&mut buf);
+        assert_eq!(
+            AclGrant::deserialize_from(&mut buf.iter()),
+            Some(changes[0].clone())
+        );
+
+        // Revoking a principal removes the grants derived from it
+        let mut changes = current.clone();
+        changes.remove(0);
+        assert_eq!(cascade_revocations(&mut changes, &current), vec![2, 3]);
+        assert_eq!(changes, vec![grant(4, vec![Acl::Read], None)]);
+
+        // As does removing the right to share
+        let mut changes = current.clone();
+        changes[1].grants.remove(Acl::ManageShares);
+        assert_eq!(cascade_revocations(&mut changes, &current), vec![3]);
+        assert_eq!(changes.len(), 3);
+
+        let mut changes = current.clone();
+        assert!(cascade_revocations(&mut changes, &current).is_empty());
+        assert_eq!(changes, current);
+    }
+
+    #[test]
+    fn acl_diff_grants() {
+        let current = vec![
+            AclGrant::new(1, vec![Acl::Read]),
+            AclGrant::new(2, vec![Acl::Read, Acl::ReadItems]),
+            AclGrant::new(3, vec![Acl::Read]),
+        ];
+        let mut expiring = AclGrant::new(4, vec![Acl::Read]);
+        expiring
+            .set_modifier("expires:2030-01-01T00:00:00Z")
+            .unwrap();
+        let changes = vec![
+            AclGrant::new(2, vec![Acl::Read]),
+            AclGrant::new(3, vec![Acl::Read]),
+            expiring,
+        ];
+
+        let diff = acl_diff(&current, &changes);
+        assert_eq!(diff.added, vec![&changes[2]]);
+        assert_eq!(diff.removed, vec![&current[0]]);
+        assert_eq!(diff.changed, vec![(&current[1], &changes[0])]);
+        assert_eq!(diff.account_ids().collect::<Vec<_>>(), vec![4, 1, 2]);
+        assert_eq!(
+            audit_changed_grants(&diff).to_string(),
+            concat!(
+                "[[1, [read], []], [2, [read, readItems], [read]], ",
+                "[4, [], [read, expires:2030-01-01T00:00:00Z]]]"
+            )
+        );
+        assert!(acl_diff(&current, &current).is_empty());
+
+        // Only the first grant of a principal listed twice is compared
+        let duplicated = vec![
+            AclGrant::new(1, vec![Acl::Read]),
+            AclGrant::new(1, vec![Acl::Read, Acl::ReadItems]),
+            AclGrant::new(5, vec![Acl::Read]),
+            AclGrant::new(5, vec![Acl::ReadItems]),
+        ];
+        let diff = acl_diff(&current[..1], &duplicated);
+        assert!(diff.removed.is_empty() && diff.changed.is_empty());
+        assert_eq!(diff.added, vec![&duplicated[2]]);
+
+        // Large ACLs are compared in linear time
+        let current = (0..10_000)
+            .map(|account_id| AclGrant::new(account_id, vec![Acl::Read]))
+            .collect::<Vec<_>>();
+        let mut changes = current.iter().rev().cloned().collect::<Vec<_>>();
+        changes[0].grants.insert(Acl::ReadItems);
+        let diff = acl_diff(&current, &changes);
+        assert!(diff.added.is_empty() && diff.removed.is_empty());
+        assert_eq!(diff.changed, vec![(&current[9_999], &changes[0])]);
+    }
+
+    #[test]
+    fn acl_grant_serialize() {
+        let mut grant = AclGrant::new(123, vec![Acl::Read, Acl::ReadItems]);
+        for has_schedule in [false, true] {
+            if has_schedule {
+                grant
+                    .set_modifier("schedule:sat,sun/10:00-12:00/-05:30")
+                    .unwrap();
+            }
+
+            let mut buf = Vec::new();
+            grant.serialize_into(&mut buf);
+            buf.push(0xff);
+            let mut iter = buf.iter();
+            assert_eq!(AclGrant::deserialize_from(&mut iter), Some(grant.clone()));
+            assert_eq!(iter.next(), Some(&0xff));
+
+            let mut indexed = AclGrant::deserialize(&grant.index_value()).unwrap();
+            indexed.account_id = grant.account_id;
+            assert_eq!(indexed, grant);
+        }
+        assert_eq!(
+            grant.modifiers().collect::<Vec<_>>(),
+            vec!["schedule:sat-sun/10:00-12:00/-05:30".to_string()]
+        );
+    }
+
+    #[test]
+    fn acl_criteria() {
+        let criteria: AclCriteria = "from=Jane@Example.org;after=2024-01-01T00:00:00Z"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            criteria,
+            AclCriteria {
+                from: Some("jane@example.org".to_string()),
+                after: Some(1704067200),
+                before: None,
+            }
+        );
+        assert_eq!(
+            criteria.to_string(),
+            "from=jane@example.org;after=2024-01-01T00:00:00Z"
+        );
+
+        for invalid in [
+            "",
+            "from=jane",
+            "sender=jane@example.org",
+            "after=yesterday",
+            "from=jane@example.org;from=john@example.org",
+        ] {
+            assert!(invalid.parse::<AclCriteria>().is_err(), "{invalid}");
+        }
+
+        let mut grant = AclGrant::new(7, vec![Acl::Read, Acl::ReadItems]);
+        for modifier in [
+            "criteria:before=2024-06-30T12:00:00Z",
+            "criteria:from=jane@example.org;after=2024-01-01T00:00:00Z;before=2024-06-30T12:00:00Z",
+            "schedule:mon-fri/09:00-17:00",
+        ] {
+            grant.set_modifier(modifier).unwrap();
+
+            let mut buf = Vec::new();
+            grant.serialize_into(&mut buf);
+            assert_eq!(
+                AclGrant::deserialize_from(&mut buf.iter()),
+                Some(grant.clone())
+            );
+
+            let mut indexed = AclGrant::deserialize(&grant.index_value()).unwrap();
+            indexed.account_id = grant.account_id;
+            assert_eq!(indexed, grant);
+        }
+        assert_eq!(
+            grant.modifiers().collect::<Vec<_>>(),
+            vec![
+                "schedule:mon-fri/09:00-17:00".to_string(),
+                concat!(
+                    "criteria:from=jane@example.org;after=2024-01-01T00:00:00Z;",
+                    "before=2024-06-30T12:00:00Z"
+                )
+                .to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn acl_merge() {
+        let mut grant = AclGrant::new(7, vec![Acl::Read]);
+        assert!(grant.merge(&AclGrant::new(7, vec![Acl::ReadItems, Acl::Read])));
+        assert_eq!(grant, AclGrant::new(7, vec![Acl::Read, Acl::ReadItems]));
+
+        let mut scheduled = AclGrant::new(7, vec![Acl::AddItems]);
+        scheduled
+            .set_modifier("schedule:mon-fri/09:00-17:00")
+            .unwrap();
+        assert!(!grant.merge(&scheduled));
+        assert_eq!(grant, AclGrant::new(7, vec![Acl::Read, Acl::ReadItems]));
+    }
+
+    #[test]
+    fn acl_expiry() {
+        let mut grant = AclGrant::new(7, vec![Acl::Read, Acl::ReadItems]);
+        assert!(grant.set_modifier("expires:tomorrow").is_err());
+
+        grant.set_modifier("expires:2024-01-31T00:00:00Z").unwrap();
+        assert_eq!(grant.expires, Some(1706659200));
+        assert!(grant.is_expired());
+        assert!(!grant.is_active());
+        assert_eq!(
+            grant.modifiers().collect::<Vec<_>>(),
+            vec!["expires:2024-01-31T00:00:00Z".to_string()]
+        );
+
+        let mut buf = Vec::new();
+        grant.serialize_into(&mut buf);
+        assert_eq!(
+            AclGrant::deserialize_from(&mut buf.iter()),
+            Some(grant.clone())
+        );
+        assert!(!AclGrant::extensions_active(
+            &grant.index_value()[U64_LEN..]
+        ));
+
+        grant.expires = Some(now() + 3600);
+        assert!(!grant.is_expired());
+        assert!(grant.is_active());
+        assert!(AclGrant::extensions_active(&grant.index_value()[U64_LEN..]));
+        assert!(!grant.merge(&AclGrant::new(7, vec![Acl::AddItems])));
+    }
+
+    #[test]
+    fn acl_networks() {
+        let office: AclNetwork = "192.168.10.0/24".parse().unwrap();
+        assert!(office.contains(&"192.168.10.25".parse().unwrap()));
+        assert!(office.contains(&"::ffff:192.168.10.25".parse().unwrap()));
+        assert!(!office.contains(&"192.168.11.25".parse().unwrap()));
+        assert!(!office.contains(&"2001:db8::1".parse().unwrap()));
+
+        let vpn: AclNetwork = "2001:db8::/32".parse().unwrap();
+        assert!(vpn.contains(&"2001:db8:1::1".parse().unwrap()));
+        assert!(!vpn.contains(&"2001:db9::1".parse().unwrap()));
+
+        let host: AclNetwork = "10.0.0.1".parse().unwrap();
+        assert_eq!(host.to_string(), "10.0.0.1/32");
+        assert!(host.contains(&"10.0.0.1".parse().unwrap()));
+        assert!(!host.contains(&"10.0.0.2".parse().unwrap()));
+
+        for invalid in ["", "10.0.0.0/33", "2001:db8::/129", "office", "10.0.0.0/x"] {
+            assert!(invalid.parse::<AclNetwork>().is_err(), "{invalid}");
+        }
+
+        let mut grant = AclGrant::new(7, vec![Acl::Read, Acl::ReadItems]);
+        grant.set_modifier("network:192.168.10.0/24").unwrap();
+        grant.set_modifier("network:2001:db8::/32").unwrap();
+        assert_eq!(grant.networks, vec![office, vpn]);
+        assert!(grant.is_active_from(Some(&"2001:db8::1".parse().unwrap())));
+        assert!(!grant.is_active_from(Some(&"10.0.0.1".parse().unwrap())));
+        assert!(!grant.is_active_from(None));
+        assert!(AclGrant::new(7, vec![Acl::Read]).is_active_from(None));
+        assert_eq!(
+            grant.modifiers().collect::<Vec<_>>(),
+            vec![
+                "network:192.168.10.0/24".to_string(),
+                "network:2001:db8::/32".to_string()
+            ]
+        );
+
+        let mut buf = Vec::new();
+        grant.serialize_into(&mut buf);
+        assert_eq!(
+            AclGrant::deserialize_from(&mut buf.iter()),
+            Some(grant.clone())
+        );
+        let mut indexed = AclGrant::deserialize(&grant.index_value()).unwrap();
+        indexed.account_id = grant.account_id;
+        assert_eq!(indexed, grant);
+    }
+
+    #[test]
+    fn acl_rights_from_names() {
+        let grant = AclGrant::new(7, vec![Acl::Read, Acl::ReadItems, Acl::AddItems]);
+        let names = grant
+            .grants
+            .map(|acl| Value::Text(acl.to_string()))
+            .chain([Value::Text("schedule:mon-fri/09:00-17:00".to_string())])
+            .collect::<Vec<_>>();
+        let expected = AclRights {
+            grants: grant.grants,
+            modifiers: vec!["schedule:mon-fri/09:00-17:00".to_string()],
+        };
+        assert_eq!(
+            AclRights::from_value(&Value::List(names)),
+            Some(expected.clone())
+        );
+        assert_eq!(
+            AclRights::from_value(&expected.clone().into_value()),
+            Some(expected)
+        );
+        assert_eq!(
+            AclRights::from_value(&Value::List(vec![])),
+            Some(AclRights::default())
+        );
+
+        for invalid in [
+            vec![
+                Value::Text("read".to_string()),
+                Value::Text("write".to_string()),
+            ],
+            vec![Value::Text("owner".to_string())],
+            vec![Value::Text("read".to_string()), Value::UnsignedInt(1)],
+        ] {
+            assert_eq!(AclRights::from_value(&Value::List(invalid)), None);
+        }
+    }
+}
@@ -17,7 +17,7 @@ use crate::{
 };
 
 use super::{
-    acl::Acl,
+    acl::{Acl, AclCriteria, AclNetwork, AclSchedule},
     any_id::AnyId,
     blob::BlobId,
     date::UTCDate,
@@ -48,6 +48,14 @@ pub enum Value {
 pub struct AclGrant {
     pub account_id: u32,
     pub grants: Bitmap<Acl>,
+    pub schedule: Option<AclSchedule>,
+    pub criteria: Option<AclCriteria>,
+    /// Principal that created the grant on behalf of the owner
+    pub granted_by: Option<u32>,
+    /// Unix timestamp after which the grant no longer applies
+    pub expires: Option<u64>,
+    /// Networks the grant can be used from, any network when empty
+    pub networks: Vec<AclNetwork>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -1108,7 +1108,13 @@ impl ToRequestError for trc::Error {
                 | trc::SecurityEvent::LoiterBan
                 | trc::SecurityEvent::IpBlocked => RequestError::too_many_auth_attempts(),
                 trc::SecurityEvent::Unauthorized => RequestError::forbidden(),
+                trc::SecurityEvent::AclChanged => RequestError::internal_server_error(),
             },
+            trc::EventType::Store(trc::StoreEvent::BlobUnavailable) => RequestError::blank(
+                StatusCode::SERVICE_UNAVAILABLE.as_u16(),
+                "Service unavailable",
+                "The blob store is temporarily unavailable, please try again later.",
+            ),
             trc::EventType::Resource(cause) => match cause {
                 trc::ResourceEvent::NotFound => RequestError::not_found(),
                 trc::ResourceEvent::BadParameters => RequestError::blank(
@@ -21,7 +21,10 @@ use serde_json::json;
 use trc::AddContext;
 use utils::url_params::UrlParams;
 
-use crate::api::{http::ToHttpResponse, HttpRequest, HttpResponse, JsonResponse};
+use crate::{
+    api::{http::ToHttpResponse, HttpRequest, HttpResponse, JsonResponse},
+    auth::acl::AclMethods,
+};
 
 use super::decode_path_element;
 use std::future::Future;
@@ -183,7 +186,7 @@ impl PrincipalManager for Server {
                 }
 
                 // Increment revision
-                self.increment_token_revision(result.changed_principals)
+                self.apply_principal_changes(result.changed_principals)
                     .await;
 
                 Ok(JsonResponse::new(json!({
@@ -651,7 +654,7 @@ impl PrincipalManager for Server {
                             .await?;
 
                         // Increment revision
-                        self.increment_token_revision(changed_principals).await;
+                        self.apply_principal_changes(changed_principals).await;
 
                         Ok(JsonResponse::new(json!({
                             "data": (),
@@ -822,7 +825,7 @@ impl PrincipalManager for Server {
             .await?;
 
         // Increment revision
-        self.increment_token_revision(changed_principals).await;
+        self.apply_principal_changes(changed_principals).await;
 
         Ok(JsonResponse::new(json!({
             "data": (),
@@ -19,6 +19,7 @@ use jmap_proto::{
 use trc::JmapEvent;
 
 use crate::{
+    auth::acl::{with_shared_grants_memo, SharedGrantsMemo},
     blob::{copy::BlobCopy, get::BlobOperations, upload::BlobUpload},
     changes::{get::ChangesLookup, query::QueryChanges},
     email::{
@@ -74,6 +75,14 @@ impl RequestHandler for Server {
         );
         let add_created_ids = !response.created_ids.is_empty();
 
+        // Pinned grants are shared by all method calls so that the request sees a
+        // consistent ACL view, otherwise each call memoizes its own
+        let pinned_grants = self
+            .core
+            .jmap
+            .acl_pin_requests
+            .then(SharedGrantsMemo::pinned);
+
         for mut call in request.method_calls {
             // Resolve result and id references
             if let Err(error) = response.resolve_references(&mut call.method) {
@@ -90,15 +99,36 @@ impl RequestHandler for Server {
 
                 // Add response
                 let method_name = call.name.as_str();
-                match self
-                    .handle_method_call(
+
+                // ACLs are read from a snapshot taken for each call when consistent
+                // reads are enabled and the data store supports snapshots
+                let snapshot = if self.core.jmap.acl_consistent_reads {
+                    match self.core.storage.data.snapshot().await {
+                        Ok(snapshot) => Some(snapshot).filter(|snapshot| snapshot.is_consistent()),
+                        Err(err) => {
+                            trc::error!(err
+                                .span_id(session.session_id)
+                                .details("Failed to take ACL snapshot"));
+                            None
+                        }
+                    }
+                } else {
+                    None
+                };
+                match with_shared_grants_memo(
+                    pinned_grants
+                        .clone()
+                        .unwrap_or_default()
+                        .with_snapshot(snapshot),
+                    self.handle_method_call(
                         call.method,
                         method_name,
                         &access_token,
                         &mut next_call,
                         session,
-                    )
-                    .await
+                    ),
+                )
+                .await
                 {
                     Ok(mut method_response) => {
                         match &mut method_response {
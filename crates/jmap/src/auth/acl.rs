@@ -4,33 +4,229 @@
  * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
  */
 
-use std::future::Future;
+use std::{future::Future, net::IpAddr, sync::Arc};
 
-use common::{auth::AccessToken, Server};
+use common::{
+    auth::AccessToken,
+    config::jmap::settings::{AclEvaluation, DuplicateGrantee},
+    Server, SharedAclId, SharedDocuments,
+};
 use directory::{
     backend::internal::{manage::ChangedPrincipals, PrincipalField},
     QueryBy, Type,
 };
+use email::mailbox::SCHEMA as MAILBOX_SCHEMA;
+use futures_util::{StreamExt, TryStreamExt};
 use jmap_proto::{
     error::set::SetError,
-    object::Object,
+    object::{index::ObjectIndexBuilder, Object},
     types::{
-        acl::Acl,
+        acl::{
+            acl_diff, audit_changed_grants, log_acl_changes, log_grantee_acl_changes, Acl,
+            AclCriteria, AclRights,
+        },
         collection::Collection,
         property::Property,
+        state::StateChange,
+        type_state::DataType,
         value::{AclGrant, MaybePatchValue, Value},
     },
 };
 use store::{
-    query::acl::AclQuery,
+    ahash::{AHashMap, AHashSet},
+    dispatch::snapshot::SnapshotHandle,
+    parking_lot::Mutex,
+    query::{
+        self,
+        acl::{AclItem, AclQuery, DocumentAcl},
+    },
     roaring::RoaringBitmap,
-    write::{assert::HashedValue, ValueClass},
-    ValueKey,
+    write::{
+        assert::{HashedValue, ToAssertValue},
+        log::ChangeLogBuilder,
+        BatchBuilder, ValueClass,
+    },
+    BitmapKey, ValueKey,
 };
 use trc::AddContext;
-use utils::map::bitmap::Bitmap;
+use utils::map::bitmap::{Bitmap, BitmapItem};
+
+const GRANT_BATCH_SIZE: usize = 100;
+/// Maximum number of shared mailboxes whose messages are fetched concurrently
+const SHARED_MAILBOX_CONCURRENCY: usize = 8;
+/// Prefix of the names under which grants to deleted principals are listed
+const DELETED_PRINCIPAL_PREFIX: &str = "deleted:";
+
+type SharedGrants = Arc<Vec<(u32, AclGrant)>>;
+/// Principal id and token revision of the caller, followed by the account,
+/// collection and document id of the object
+type EffectiveAclKey = (u32, u64, u32, u8, u32);
+
+tokio::task_local! {
+    static SHARED_GRANTS: SharedGrantsMemo;
+}
+
+/// Grants shared with the caller, memoized so that repeated ACL checks query
+/// each principal's grants only once, along with the rights computed by
+/// `document_effective_acl`. ACLs updated through `refresh_acls` invalidate the
+/// memoized grants and rights of the affected account and collection.
+#[derive(Clone, Default)]
+pub struct SharedGrantsMemo {
+    grants: Arc<Mutex<AHashMap<(u32, u8), SharedGrants>>>,
+    effective: Arc<Mutex<AHashMap<EffectiveAclKey, Bitmap<Acl>>>>,
+    pinned: bool,
+    snapshot: Option<SnapshotHandle>,
+}
+
+impl SharedGrantsMemo {
+    /// Memo shared by all the method calls of a request, pinning the ACL view of
+    /// the request to the grants as they were when first read. Access checks and
+    /// shared document sets are then derived from the memoized grants only, so
+    /// changes made by concurrent requests are not seen halfway through.
+    pub fn pinned() -> Self {
+        SharedGrantsMemo {
+            grants: Default::default(),
+            effective: Default::default(),
+            pinned: true,
+            snapshot: None,
+        }
+    }
+
+    /// Reads the grants and the messages of shared mailboxes from a snapshot, so
+    /// that ACLs changed while they are evaluated cannot produce a torn view,
+    /// such as a mailbox listed as shared while its messages are not. Results
+    /// read from a snapshot bypass the shared documents cache.
+    pub fn with_snapshot(mut self, snapshot: Option<SnapshotHandle>) -> Self {
+        self.snapshot = snapshot;
+        self
+    }
+}
+
+/// Runs a JMAP method call with a memo of the grants shared with the caller.
+pub async fn with_shared_grants_memo<F: Future>(memo: SharedGrantsMemo, f: F) -> F::Output {
+    SHARED_GRANTS.scope(memo, f).await
+}
+
+fn acls_pinned() -> bool {
+    SHARED_GRANTS.try_with(|memo| memo.pinned).unwrap_or(false)
+}
+
+fn acl_snapshot() -> Option<SnapshotHandle> {
+    SHARED_GRANTS
+        .try_with(|memo| memo.snapshot.clone())
+        .ok()
+        .flatten()
+}
+
+async fn acl_query(server: &Server, query: AclQuery) -> trc::Result<Vec<AclItem>> {
+    match acl_snapshot() {
+        Some(snapshot) => snapshot.acl_query(query).await,
+        None => server.core.storage.data.acl_query(query).await,
+    }
+}
+
+async fn acl_iterate(
+    server: &Server,
+    query: AclQuery,
+    cb: impl FnMut(AclItem) -> trc::Result<bool> + Sync + Send,
+) -> trc::Result<()> {
+    match acl_snapshot() {
+        Some(snapshot) => snapshot.acl_iterate(query, cb).await,
+        None => server.core.storage.data.acl_iterate(query, cb).await,
+    }
+}
+
+/// Folds the documents shared with the token into a bitmap as the grants are
+/// read, returning whether any of them depends on the time or the client's
+/// network. Returns `None` when the grants have to go through `shared_grants`
+/// instead: when they are already memoized or pinned, when mailbox grants are
+/// inherited, which requires every grant of the collection, and with priority
+/// evaluation, where a grant can withhold the rights of another.
+async fn fold_shared_documents(
+    server: &Server,
+    access_token: &AccessToken,
+    to_account_id: u32,
+    to_collection: Collection,
+    check_acls: &Bitmap<Acl>,
+) -> trc::Result<Option<(RoaringBitmap, bool)>> {
+    let to_collection = u8::from(to_collection);
+    if acls_pinned()
+        || server.core.jmap.acl_evaluation == AclEvaluation::Priority
+        || SHARED_GRANTS
+            .try_with(|memo| {
+                memo.grants
+                    .lock()
+                    .contains_key(&(to_account_id, to_collection))
+            })
+            .unwrap_or(false)
+    {
+        return Ok(None);
+    }
+
+    let mut document_ids = RoaringBitmap::new();
+    let mut is_conditional = false;
+    let mut is_inherited = false;
+    for grant_account_id in access_token.grantee_ids() {
+        acl_iterate(
+            server,
+            AclQuery::SharedWith {
+                grant_account_id,
+                to_account_id,
+                to_collection,
+            },
+            |acl_item| {
+                let Some(grant) = AclGrant::from_extensions(&acl_item.extensions) else {
+                    return Ok(true);
+                };
+                let mut acls = Bitmap::<Acl>::from(acl_item.permissions);
+                if acls.contains(Acl::Inherit) && to_collection == u8::from(Collection::Mailbox) {
+                    is_inherited = true;
+                    return Ok(false);
+                }
+                acls.intersection(check_acls);
+                if !acls.is_empty() {
+                    is_conditional |= grant.schedule.is_some()
+                        || grant.expires.is_some()
+                        || !grant.networks.is_empty();
+                    if grant.is_active_from(access_token.remote_ip.as_ref()) {
+                        document_ids.insert(acl_item.to_document_id);
+                    }
+                }
+
+                Ok(true)
+            },
+        )
+        .await
+        .caused_by(trc::location!())?;
+
+        if is_inherited {
+            return Ok(None);
+        }
+    }
+
+    Ok(Some((document_ids, is_conditional)))
+}
+
+fn invalidate_effective_acls(account_id: u32, collection: Collection) {
+    let collection = u8::from(collection);
+    let _ = SHARED_GRANTS.try_with(|memo| {
+        memo.effective
+            .lock()
+            .retain(|&(_, _, memo_account_id, memo_collection, _), _| {
+                memo_account_id != account_id || memo_collection != collection
+            })
+    });
+}
 
 pub trait AclMethods: Sync + Send {
+    fn shared_grants(
+        &self,
+        access_token: &AccessToken,
+        to_account_id: u32,
+        to_collection: Collection,
+        check_acls: impl Into<Bitmap<Acl>> + Send,
+    ) -> impl Future<Output = trc::Result<Vec<(u32, AclGrant)>>> + Send;
+
     fn shared_documents(
         &self,
         access_token: &AccessToken,
@@ -39,6 +235,24 @@ pub trait AclMethods: Sync + Send {
         check_acls: impl Into<Bitmap<Acl>> + Send,
     ) -> impl Future<Output = trc::Result<RoaringBitmap>> + Send;
 
+    /// Shared mailboxes that appear in folder listings, which are those granted
+    /// with either the read or the lookup right. Their messages still require
+    /// the rights checked by `shared_messages`.
+    fn visible_shared_mailboxes(
+        &self,
+        access_token: &AccessToken,
+        to_account_id: u32,
+    ) -> impl Future<Output = trc::Result<RoaringBitmap>> + Send;
+
+    /// Documents in other accounts shared with the token's principal or any of
+    /// its groups, as `(to_account_id, to_collection, to_document_id, grant)`.
+    /// Grants held through several groups on the same document are merged, and
+    /// scheduled grants are returned even when inactive.
+    fn granted_documents(
+        &self,
+        access_token: &AccessToken,
+    ) -> impl Future<Output = trc::Result<Vec<(u32, Collection, u32, AclGrant)>>> + Send;
+
     fn shared_messages(
         &self,
         access_token: &AccessToken,
@@ -61,6 +275,9 @@ pub trait AclMethods: Sync + Send {
         check_acls: impl Into<Bitmap<Acl>> + Send,
     ) -> impl Future<Output = trc::Result<RoaringBitmap>> + Send;
 
+    /// Whether the token holds any of `check_acls` over a document. Members of
+    /// the account that owns the document have every right, so no grants are
+    /// read for them.
     fn has_access_to_document(
         &self,
         access_token: &AccessToken,
@@ -70,8 +287,91 @@ pub trait AclMethods: Sync + Send {
         check_acls: impl Into<Bitmap<Acl>> + Send,
     ) -> impl Future<Output = trc::Result<bool>> + Send;
 
+    fn has_access_to_documents(
+        &self,
+        access_token: &AccessToken,
+        to_account_id: u32,
+        to_collection: impl Into<u8> + Send,
+        document_ids: &RoaringBitmap,
+        check_acls: impl Into<Bitmap<Acl>> + Send,
+    ) -> impl Future<Output = trc::Result<RoaringBitmap>> + Send;
+
+    /// Rights held by the token over a loaded object, as returned by
+    /// `EffectiveAcl::effective_acl`. Requests with pinned ACLs evaluate the
+    /// grants pinned for the document instead of those of the object. Results
+    /// are memoized for the token revision until the ACLs of the collection
+    /// are refreshed. Mailboxes also hold the grants inherited from their
+    /// parents, see `Acl::Inherit`.
+    fn document_effective_acl(
+        &self,
+        access_token: &AccessToken,
+        account_id: u32,
+        collection: Collection,
+        document_id: u32,
+        object: &Object<Value>,
+    ) -> impl Future<Output = trc::Result<Bitmap<Acl>>> + Send;
+
+    fn explain_access_to_document(
+        &self,
+        access_token: &AccessToken,
+        to_account_id: u32,
+        to_collection: impl Into<u8> + Send,
+        to_document_id: u32,
+        check_acls: impl Into<Bitmap<Acl>> + Send,
+    ) -> impl Future<Output = trc::Result<AclExplanation>> + Send;
+
+    fn grant_to_documents(
+        &self,
+        actor_token: &AccessToken,
+        account_id: u32,
+        collection: Collection,
+        document_ids: &RoaringBitmap,
+        grantee_id: u32,
+        grants: Bitmap<Acl>,
+    ) -> impl Future<Output = trc::Result<usize>> + Send;
+
+    /// Moves the grants held by `previous_id` to `principal_id` on every
+    /// document shared with `previous_id`. When `principal_id` already holds
+    /// a grant on a document the rights of both grants are merged. Returns the
+    /// number of documents updated.
+    fn reassign_grants(
+        &self,
+        previous_id: u32,
+        principal_id: u32,
+    ) -> impl Future<Output = trc::Result<usize>> + Send;
+
+    /// Applies the principal changes reported by the directory: grants to
+    /// principals whose id changed are moved to the new id before the tokens
+    /// of the changed principals are invalidated.
+    fn apply_principal_changes(
+        &self,
+        changed_principals: ChangedPrincipals,
+    ) -> impl Future<Output = ()> + Send;
+
+    fn verify_acl_index(
+        &self,
+        account_id: u32,
+        collection: Collection,
+        repair: bool,
+    ) -> impl Future<Output = trc::Result<Vec<u32>>> + Send;
+
+    /// Every grant stored on a document, including inactive ones, without the
+    /// membership checks applied by `acl_get`. Callers have to make sure the
+    /// requester is allowed to administer the account. Documents that do not
+    /// exist have no grants.
+    fn list_document_acls(
+        &self,
+        account_id: u32,
+        collection: Collection,
+        document_id: u32,
+    ) -> impl Future<Output = trc::Result<Vec<AclGrant>>> + Send;
+
+    /// Applies a set or patch of the ACL property. Only the owner may remove the
+    /// last administer grant, otherwise nobody else could manage the object.
     fn acl_set(
         &self,
+        access_token: &AccessToken,
+        account_id: u32,
         changes: &mut Object<Value>,
         current: Option<&HashedValue<Object<Value>>>,
         acl_changes: MaybePatchValue,
@@ -84,15 +384,28 @@ pub trait AclMethods: Sync + Send {
         account_id: u32,
     ) -> impl Future<Output = Value> + Send;
 
+    /// Invalidates the tokens of the principals whose grants changed and emits
+    /// an audit event describing the change made by `access_token`.
     fn refresh_acls(
         &self,
-        changes: &Object<Value>,
+        access_token: &AccessToken,
+        account_id: u32,
+        collection: Collection,
+        document_id: Option<u32>,
+        changes: &mut Object<Value>,
         current: &Option<HashedValue<Object<Value>>>,
     ) -> impl Future<Output = ()> + Send;
 
+    /// Maps a JMAP ACL to grants. Grantees can be individuals or groups, in
+    /// which case the grant also applies to the members of nested groups.
+    /// Rights are given as a bitmap or as permission names, see
+    /// `AclRights::from_value`.
+    /// Maps a full ACL value. Grants to deleted principals, listed under
+    /// `deleted:<id>`, can only be kept as they are in `current` or dropped.
     fn map_acl_set(
         &self,
         acl_set: Vec<Value>,
+        current: &[AclGrant],
     ) -> impl Future<Output = Result<Vec<AclGrant>, SetError>> + Send;
 
     fn map_acl_patch(
@@ -102,72 +415,386 @@ pub trait AclMethods: Sync + Send {
 }
 
 impl AclMethods for Server {
-    async fn shared_documents(
+    async fn shared_grants(
         &self,
         access_token: &AccessToken,
         to_account_id: u32,
         to_collection: Collection,
         check_acls: impl Into<Bitmap<Acl>>,
-    ) -> trc::Result<RoaringBitmap> {
+    ) -> trc::Result<Vec<(u32, AclGrant)>> {
         let check_acls = check_acls.into();
-        let mut document_ids = RoaringBitmap::new();
         let to_collection = u8::from(to_collection);
-        for &grant_account_id in [access_token.primary_id]
-            .iter()
-            .chain(access_token.member_of.clone().iter())
+        let memo_key = (to_account_id, to_collection);
+        let shared_grants = if let Some(shared_grants) = SHARED_GRANTS
+            .try_with(|memo| memo.grants.lock().get(&memo_key).cloned())
+            .ok()
+            .flatten()
         {
-            for acl_item in self
-                .core
-                .storage
-                .data
-                .acl_query(AclQuery::SharedWith {
-                    grant_account_id,
-                    to_account_id,
-                    to_collection,
-                })
+            shared_grants
+        } else {
+            let mut shared_grants = Vec::new();
+            for grant_account_id in access_token.grantee_ids() {
+                for acl_item in acl_query(
+                    self,
+                    AclQuery::SharedWith {
+                        grant_account_id,
+                        to_account_id,
+                        to_collection,
+                    },
+                )
                 .await
                 .caused_by(trc::location!())?
+                {
+                    if let Some(mut grant) = AclGrant::from_extensions(&acl_item.extensions) {
+                        grant.account_id = grant_account_id;
+                        grant.grants = Bitmap::from(acl_item.permissions);
+                        shared_grants.push((acl_item.to_document_id, grant));
+                    }
+                }
+            }
+
+            // The mailbox tree is only read when a grant is inherited by child mailboxes
+            if to_collection == u8::from(Collection::Mailbox)
+                && shared_grants
+                    .iter()
+                    .any(|(_, grant)| grant.grants.contains(Acl::Inherit))
             {
-                let mut acls = Bitmap::<Acl>::from(acl_item.permissions);
+                let parents = mailbox_parents(self, to_account_id)
+                    .await
+                    .caused_by(trc::location!())?;
+                let inherited =
+                    inherited_grants(&shared_grants, &parents, self.core.jmap.mailbox_max_depth);
+                shared_grants.extend(inherited);
+            }
+
+            let shared_grants = Arc::new(shared_grants);
+            let _ = SHARED_GRANTS
+                .try_with(|memo| memo.grants.lock().insert(memo_key, shared_grants.clone()));
+            shared_grants
+        };
+
+        // With priority evaluation an active grant to the principal itself
+        // replaces the grants to its groups on the same document
+        let overridden = if self.core.jmap.acl_evaluation == AclEvaluation::Priority {
+            shared_grants
+                .iter()
+                .filter(|(_, grant)| {
+                    grant.account_id == access_token.primary_id
+                        && grant.is_active_from(access_token.remote_ip.as_ref())
+                })
+                .map(|(document_id, _)| *document_id)
+                .collect::<RoaringBitmap>()
+        } else {
+            RoaringBitmap::new()
+        };
 
+        // Scheduled grants are returned even when inactive, callers check `is_active_from`
+        Ok(shared_grants
+            .iter()
+            .filter(|(document_id, grant)| {
+                grant.account_id == access_token.primary_id || !overridden.contains(*document_id)
+            })
+            .filter_map(|(document_id, grant)| {
+                let mut acls = grant.grants;
                 acls.intersection(&check_acls);
-                if !acls.is_empty() {
-                    document_ids.insert(acl_item.to_document_id);
+                (!acls.is_empty()).then(|| {
+                    (
+                        *document_id,
+                        AclGrant {
+                            grants: acls,
+                            ..grant.clone()
+                        },
+                    )
+                })
+            })
+            .collect())
+    }
+
+    async fn granted_documents(
+        &self,
+        access_token: &AccessToken,
+    ) -> trc::Result<Vec<(u32, Collection, u32, AclGrant)>> {
+        let mut granted: AHashMap<(u32, u8, u32), AclGrant> = AHashMap::new();
+        for grant_account_id in access_token.grantee_ids() {
+            for acl_item in acl_query(self, AclQuery::GrantedTo { grant_account_id })
+                .await
+                .caused_by(trc::location!())?
+            {
+                if access_token.is_member(acl_item.to_account_id)
+                    || !Collection::from(acl_item.to_collection).is_valid()
+                {
+                    continue;
+                }
+                if let Some(mut grant) = AclGrant::from_extensions(&acl_item.extensions) {
+                    grant.account_id = grant_account_id;
+                    grant.grants = Bitmap::from(acl_item.permissions);
+                    granted
+                        .entry((
+                            acl_item.to_account_id,
+                            acl_item.to_collection,
+                            acl_item.to_document_id,
+                        ))
+                        .and_modify(|existing| existing.grants.union(&grant.grants))
+                        .or_insert(grant);
                 }
             }
         }
 
+        let mut granted = granted
+            .into_iter()
+            .map(|((account_id, collection, document_id), grant)| {
+                (account_id, Collection::from(collection), document_id, grant)
+            })
+            .collect::<Vec<_>>();
+        granted.sort_unstable_by_key(|(account_id, collection, document_id, _)| {
+            (*account_id, u8::from(*collection), *document_id)
+        });
+
+        Ok(granted)
+    }
+
+    async fn shared_documents(
+        &self,
+        access_token: &AccessToken,
+        to_account_id: u32,
+        to_collection: Collection,
+        check_acls: impl Into<Bitmap<Acl>>,
+    ) -> trc::Result<RoaringBitmap> {
+        let check_acls = check_acls.into();
+        let cache_id = SharedAclId {
+            access_id: access_token.primary_id,
+            revision: access_token.revision,
+            account_id: to_account_id,
+            collection: to_collection.into(),
+            acls: check_acls.bitmap,
+        };
+        let change_ids = [
+            self.core
+                .storage
+                .data
+                .get_last_change_id(to_account_id, to_collection)
+                .await
+                .caused_by(trc::location!())?,
+            None,
+        ];
+        // Requests with pinned ACLs derive shared documents from their own grants,
+        // as do reads from a snapshot. With priority evaluation, an individual
+        // grant withholding every requested right is not returned by
+        // `shared_grants`, so whether the results depend on its schedule is unknown.
+        let pinned = acls_pinned() || acl_snapshot().is_some();
+        let uncached = pinned || self.core.jmap.acl_evaluation == AclEvaluation::Priority;
+        if let Some(shared) = self
+            .inner
+            .cache
+            .shared_acls
+            .get(&cache_id)
+            .filter(|shared| !uncached && shared.change_ids == change_ids)
+        {
+            return Ok(shared.document_ids.clone());
+        }
+
+        let (document_ids, skip_cache) = if let Some((document_ids, is_conditional)) =
+            fold_shared_documents(
+                self,
+                access_token,
+                to_account_id,
+                to_collection,
+                &check_acls,
+            )
+            .await?
+        {
+            (document_ids, uncached || is_conditional)
+        } else {
+            let grants = self
+                .shared_grants(access_token, to_account_id, to_collection, check_acls)
+                .await?;
+            let skip_cache = uncached
+                || grants.iter().any(|(_, grant)| {
+                    grant.schedule.is_some()
+                        || grant.expires.is_some()
+                        || !grant.networks.is_empty()
+                });
+            let document_ids = grants
+                .into_iter()
+                .filter(|(_, grant)| grant.is_active_from(access_token.remote_ip.as_ref()))
+                .map(|(document_id, _)| document_id)
+                .collect::<RoaringBitmap>();
+            (document_ids, skip_cache)
+        };
+
+        // Results of grants depending on the time or the client's network are not
+        // cached, nor are those of pinned grants which might be outdated
+        if !skip_cache {
+            self.inner.cache.shared_acls.insert(
+                cache_id,
+                Arc::new(SharedDocuments {
+                    document_ids: document_ids.clone(),
+                    change_ids,
+                }),
+            );
+        }
+
         Ok(document_ids)
     }
 
+    async fn visible_shared_mailboxes(
+        &self,
+        access_token: &AccessToken,
+        to_account_id: u32,
+    ) -> trc::Result<RoaringBitmap> {
+        self.shared_documents(
+            access_token,
+            to_account_id,
+            Collection::Mailbox,
+            vec![Acl::Read, Acl::Lookup],
+        )
+        .await
+    }
+
     async fn shared_messages(
         &self,
         access_token: &AccessToken,
         to_account_id: u32,
         check_acls: impl Into<Bitmap<Acl>>,
     ) -> trc::Result<RoaringBitmap> {
+        // Messages move between mailboxes, so the Email changes are tracked as well
         let check_acls = check_acls.into();
-        let shared_mailboxes = self
-            .shared_documents(access_token, to_account_id, Collection::Mailbox, check_acls)
+        let cache_id = SharedAclId {
+            access_id: access_token.primary_id,
+            revision: access_token.revision,
+            account_id: to_account_id,
+            collection: Collection::Email.into(),
+            acls: check_acls.bitmap,
+        };
+        let mut change_ids = [None; 2];
+        for (change_id, collection) in change_ids
+            .iter_mut()
+            .zip([Collection::Mailbox, Collection::Email])
+        {
+            *change_id = self
+                .core
+                .storage
+                .data
+                .get_last_change_id(to_account_id, collection)
+                .await
+                .caused_by(trc::location!())?;
+        }
+        // Requests with pinned ACLs derive shared documents from their own grants,
+        // as do reads from a snapshot. With priority evaluation, an individual
+        // grant withholding every requested right is not returned by
+        // `shared_grants`, so whether the results depend on its schedule is unknown.
+        let pinned = acls_pinned() || acl_snapshot().is_some();
+        let uncached = pinned || self.core.jmap.acl_evaluation == AclEvaluation::Priority;
+        if let Some(shared) = self
+            .inner
+            .cache
+            .shared_acls
+            .get(&cache_id)
+            .filter(|shared| !uncached && shared.change_ids == change_ids)
+        {
+            return Ok(shared.document_ids.clone());
+        }
+
+        // Mailboxes shared without criteria expose all their messages
+        let grants = self
+            .shared_grants(access_token, to_account_id, Collection::Mailbox, check_acls)
             .await?;
-        if shared_mailboxes.is_empty() {
-            return Ok(shared_mailboxes);
+        let skip_cache = uncached
+            || grants.iter().any(|(_, grant)| {
+                grant.schedule.is_some() || grant.expires.is_some() || !grant.networks.is_empty()
+            });
+        let mut shared_mailboxes: AHashMap<u32, Option<Vec<AclCriteria>>> = AHashMap::new();
+        for (mailbox_id, grant) in grants
+            .into_iter()
+            .filter(|(_, grant)| grant.is_active_from(access_token.remote_ip.as_ref()))
+        {
+            match (
+                shared_mailboxes
+                    .entry(mailbox_id)
+                    .or_insert_with(|| Some(Vec::new())),
+                grant.criteria,
+            ) {
+                (Some(mailbox_criteria), Some(criteria)) => mailbox_criteria.push(criteria),
+                (mailbox_criteria, None) => *mailbox_criteria = None,
+                _ => (),
+            }
         }
+
+        // Fetch the messages of each mailbox concurrently, the union of the
+        // results does not depend on the order in which they complete
+        let snapshot = acl_snapshot();
+        let snapshot = snapshot.as_ref();
+        let mailbox_messages = futures_util::stream::iter(shared_mailboxes)
+            .map(|(mailbox_id, mailbox_criteria)| async move {
+                match snapshot {
+                    Some(snapshot) => snapshot
+                        .get_bitmap(BitmapKey::tag(
+                            to_account_id,
+                            Collection::Email,
+                            Property::MailboxIds,
+                            mailbox_id,
+                        ))
+                        .await
+                        .caused_by(trc::location!()),
+                    None => {
+                        self.get_tag(
+                            to_account_id,
+                            Collection::Email,
+                            Property::MailboxIds,
+                            mailbox_id,
+                        )
+                        .await
+                    }
+                }
+                .map(|messages| (messages, mailbox_criteria))
+            })
+            .buffer_unordered(SHARED_MAILBOX_CONCURRENCY)
+            .try_collect::<Vec<_>>()
+            .await?;
+
         let mut shared_messages = RoaringBitmap::new();
-        for mailbox_id in shared_mailboxes {
-            if let Some(messages_in_mailbox) = self
-                .get_tag(
-                    to_account_id,
-                    Collection::Email,
-                    Property::MailboxIds,
-                    mailbox_id,
-                )
-                .await?
-            {
+        let mut matching_messages: AHashMap<AclCriteria, RoaringBitmap> = AHashMap::new();
+        for (messages_in_mailbox, mailbox_criteria) in mailbox_messages {
+            if let Some(mut messages_in_mailbox) = messages_in_mailbox {
+                if let Some(mailbox_criteria) = mailbox_criteria {
+                    let mut matches = RoaringBitmap::new();
+                    for criteria in mailbox_criteria {
+                        if let Some(messages) = matching_messages.get(&criteria) {
+                            matches |= messages;
+                        } else {
+                            let messages = self
+                                .core
+                                .storage
+                                .data
+                                .filter(
+                                    to_account_id,
+                                    Collection::Email,
+                                    criteria_filters(&criteria),
+                                )
+                                .await
+                                .caused_by(trc::location!())?
+                                .results;
+                            matches |= &messages;
+                            matching_messages.insert(criteria, messages);
+                        }
+                    }
+                    messages_in_mailbox &= matches;
+                }
+
                 shared_messages |= messages_in_mailbox;
             }
         }
 
+        if !skip_cache {
+            self.inner.cache.shared_acls.insert(
+                cache_id,
+                Arc::new(SharedDocuments {
+                    document_ids: shared_messages.clone(),
+                    change_ids,
+                }),
+            );
+        }
+
         Ok(shared_messages)
     }
 
@@ -218,17 +845,36 @@ impl AclMethods for Server {
         to_document_id: u32,
         check_acls: impl Into<Bitmap<Acl>>,
     ) -> trc::Result<bool> {
+        if access_token.is_member(to_account_id) {
+            return Ok(true);
+        }
+
         let to_collection = to_collection.into();
         let check_acls = check_acls.into();
-        for &grant_account_id in [access_token.primary_id]
-            .iter()
-            .chain(access_token.member_of.clone().iter())
-        {
+        if acls_pinned() || self.core.jmap.acl_evaluation == AclEvaluation::Priority {
+            // Priority evaluation needs every grant held by the token on the document
+            return Ok(self
+                .shared_grants(
+                    access_token,
+                    to_account_id,
+                    Collection::from(to_collection),
+                    check_acls,
+                )
+                .await
+                .caused_by(trc::location!())?
+                .iter()
+                .any(|(document_id, grant)| {
+                    *document_id == to_document_id
+                        && grant.is_active_from(access_token.remote_ip.as_ref())
+                }));
+        }
+
+        for grant_account_id in access_token.grantee_ids() {
             match self
                 .core
                 .storage
                 .data
-                .get_value::<u64>(ValueKey {
+                .get_value::<AclGrant>(ValueKey {
                     account_id: to_account_id,
                     collection: to_collection,
                     document_id: to_document_id,
@@ -236,11 +882,11 @@ impl AclMethods for Server {
                 })
                 .await
             {
-                Ok(Some(acls)) => {
-                    let mut acls = Bitmap::<Acl>::from(acls);
+                Ok(Some(grant)) => {
+                    let mut acls = grant.grants;
 
                     acls.intersection(&check_acls);
-                    if !acls.is_empty() {
+                    if !acls.is_empty() && grant.is_active_from(access_token.remote_ip.as_ref()) {
                         return Ok(true);
                     }
                 }
@@ -250,20 +896,600 @@ impl AclMethods for Server {
                 }
             }
         }
+
+        // Mailboxes without a grant of their own can inherit one from a parent
+        if to_collection == u8::from(Collection::Mailbox) {
+            return Ok(self
+                .shared_grants(access_token, to_account_id, Collection::Mailbox, check_acls)
+                .await
+                .caused_by(trc::location!())?
+                .iter()
+                .any(|(document_id, grant)| {
+                    *document_id == to_document_id
+                        && grant.is_active_from(access_token.remote_ip.as_ref())
+                }));
+        }
+
         Ok(false)
     }
 
+    async fn has_access_to_documents(
+        &self,
+        access_token: &AccessToken,
+        to_account_id: u32,
+        to_collection: impl Into<u8>,
+        document_ids: &RoaringBitmap,
+        check_acls: impl Into<Bitmap<Acl>>,
+    ) -> trc::Result<RoaringBitmap> {
+        // Members of the account hold every right, as in `effective_acl`
+        if access_token.is_member(to_account_id) {
+            return Ok(document_ids.clone());
+        }
+
+        // Grants are read with a single range query per grantee rather than a
+        // point read per document and grantee
+        let to_collection = Collection::from(to_collection.into());
+        let check_acls = check_acls.into();
+        let mut allowed = RoaringBitmap::new();
+        if !document_ids.is_empty() {
+            for (document_id, grant) in self
+                .shared_grants(access_token, to_account_id, to_collection, check_acls)
+                .await
+                .caused_by(trc::location!())?
+            {
+                if document_ids.contains(document_id)
+                    && grant.is_active_from(access_token.remote_ip.as_ref())
+                {
+                    allowed.insert(document_id);
+                }
+            }
+        }
+        Ok(allowed)
+    }
+
+    async fn document_effective_acl(
+        &self,
+        access_token: &AccessToken,
+        account_id: u32,
+        collection: Collection,
+        document_id: u32,
+        object: &Object<Value>,
+    ) -> trc::Result<Bitmap<Acl>> {
+        let evaluation = self.core.jmap.acl_evaluation;
+        if access_token.is_member(account_id) {
+            return Ok(Bitmap::all());
+        }
+
+        let memo_key = (
+            access_token.primary_id,
+            access_token.revision,
+            account_id,
+            u8::from(collection),
+            document_id,
+        );
+        if let Some(acl) = SHARED_GRANTS
+            .try_with(|memo| memo.effective.lock().get(&memo_key).copied())
+            .ok()
+            .flatten()
+        {
+            return Ok(acl);
+        }
+
+        let acl = if acls_pinned() {
+            let grants = self
+                .shared_grants(access_token, account_id, collection, Bitmap::all())
+                .await
+                .caused_by(trc::location!())?
+                .into_iter()
+                .filter(|(grant_document_id, _)| *grant_document_id == document_id)
+                .map(|(_, grant)| grant)
+                .collect::<Vec<_>>();
+            Object::with_capacity(1)
+                .with_property(Property::Acl, Value::Acl(grants))
+                .effective_acl(access_token, account_id, evaluation)
+        } else if collection == Collection::Mailbox {
+            // Add the grants inherited from parent mailboxes by the principals
+            // without a grant of their own
+            let mut grants = match object.properties.get(&Property::Acl) {
+                Some(Value::Acl(grants)) => grants.clone(),
+                _ => Vec::new(),
+            };
+            for (grant_document_id, grant) in self
+                .shared_grants(access_token, account_id, collection, Bitmap::all())
+                .await
+                .caused_by(trc::location!())?
+            {
+                if grant_document_id == document_id
+                    && !grants
+                        .iter()
+                        .any(|item| item.account_id == grant.account_id)
+                {
+                    grants.push(grant);
+                }
+            }
+            Object::with_capacity(1)
+                .with_property(Property::Acl, Value::Acl(grants))
+                .effective_acl(access_token, account_id, evaluation)
+        } else {
+            object.effective_acl(access_token, account_id, evaluation)
+        };
+        let _ = SHARED_GRANTS.try_with(|memo| memo.effective.lock().insert(memo_key, acl));
+
+        Ok(acl)
+    }
+
+    async fn explain_access_to_document(
+        &self,
+        access_token: &AccessToken,
+        to_account_id: u32,
+        to_collection: impl Into<u8>,
+        to_document_id: u32,
+        check_acls: impl Into<Bitmap<Acl>>,
+    ) -> trc::Result<AclExplanation> {
+        let check_acls = check_acls.into();
+        if access_token.is_member(to_account_id) {
+            return Ok(AclExplanation::member(to_account_id, check_acls));
+        }
+
+        // Grants are resolved as in `has_access_to_document`, including the ones
+        // inherited from parent mailboxes
+        let grants = self
+            .shared_grants(
+                access_token,
+                to_account_id,
+                Collection::from(to_collection.into()),
+                Bitmap::all(),
+            )
+            .await
+            .caused_by(trc::location!())?
+            .into_iter()
+            .filter(|(document_id, _)| *document_id == to_document_id)
+            .map(|(_, grant)| grant)
+            .collect::<Vec<_>>();
+        let grantees = access_token
+            .grantee_ids()
+            .map(|account_id| {
+                (
+                    account_id,
+                    grants
+                        .iter()
+                        .find(|grant| grant.account_id == account_id)
+                        .cloned(),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        Ok(AclExplanation::new(
+            check_acls,
+            grantees,
+            access_token.remote_ip.as_ref(),
+        ))
+    }
+
+    async fn grant_to_documents(
+        &self,
+        actor_token: &AccessToken,
+        account_id: u32,
+        collection: Collection,
+        document_ids: &RoaringBitmap,
+        grantee_id: u32,
+        grants: Bitmap<Acl>,
+    ) -> trc::Result<usize> {
+        let schema = match collection {
+            Collection::Mailbox => MAILBOX_SCHEMA,
+            _ => {
+                return Err(trc::StoreEvent::NotSupported
+                    .into_err()
+                    .details("Collection does not support ACLs")
+                    .collection(collection)
+                    .caused_by(trc::location!()))
+            }
+        };
+
+        // Make sure the actor can manage the shares of all documents before applying any changes
+        if !actor_token.is_member(account_id) {
+            let allowed = self
+                .has_access_to_documents(
+                    actor_token,
+                    account_id,
+                    collection,
+                    document_ids,
+                    vec![Acl::Administer, Acl::ManageShares],
+                )
+                .await
+                .caused_by(trc::location!())?;
+            if let Some(document_id) = (document_ids - &allowed).min() {
+                return Err(trc::JmapEvent::Forbidden
+                    .into_err()
+                    .details("You are not allowed to change the permissions of this document.")
+                    .account_id(account_id)
+                    .document_id(document_id));
+            }
+        }
+
+        let grantor = (!actor_token.is_member(account_id)).then_some(actor_token.primary_id);
+        let document_ids = document_ids.iter().collect::<Vec<_>>();
+        let mut last_change_id = None;
+        let mut updated = 0;
+        for chunk in document_ids.chunks(GRANT_BATCH_SIZE) {
+            let change_id = self.generate_snowflake_id()?;
+            let mut changes = ChangeLogBuilder::with_change_id(change_id);
+            let mut batch = BatchBuilder::new();
+            batch
+                .with_account_id(account_id)
+                .with_collection(collection);
+
+            let mut audit = Vec::with_capacity(chunk.len());
+            for &document_id in chunk {
+                let current = if let Some(current) = self
+                    .get_property::<HashedValue<Object<Value>>>(
+                        account_id,
+                        collection,
+                        document_id,
+                        Property::Value,
+                    )
+                    .await?
+                {
+                    current
+                } else {
+                    continue;
+                };
+
+                let current_acl = match current.inner.properties.get(&Property::Acl) {
+                    Some(Value::Acl(acl)) => acl.as_slice(),
+                    _ => &[],
+                };
+                let mut acl = current_acl.to_vec();
+                if let Some(item) = acl.iter_mut().find(|item| item.account_id == grantee_id) {
+                    let mut new_grants = item.grants;
+                    new_grants.union(&grants);
+                    if new_grants == item.grants {
+                        continue;
+                    }
+                    item.grants = new_grants;
+                    item.granted_by = grantor;
+                } else {
+                    acl.push(AclGrant {
+                        granted_by: grantor,
+                        ..AclGrant::new(grantee_id, grants)
+                    });
+                }
+
+                audit.push((
+                    document_id,
+                    audit_changed_grants(&acl_diff(current_acl, &acl)),
+                ));
+
+                let mut object = Object::with_capacity(1);
+                object.set(Property::Acl, Value::Acl(acl));
+                let builder = ObjectIndexBuilder::new(schema)
+                    .with_changes(object)
+                    .with_current(current);
+                let acl_changes = builder.acl_changes();
+                batch.update_document(document_id).custom(builder);
+                log_grantee_acl_changes(
+                    &mut batch,
+                    change_id,
+                    account_id,
+                    collection,
+                    document_id,
+                    &acl_changes,
+                );
+                batch
+                    .with_account_id(account_id)
+                    .with_collection(collection);
+                changes.log_update(collection, document_id);
+                log_acl_changes(&mut changes, collection, document_id, &acl_changes);
+                updated += 1;
+            }
+
+            if !batch.is_empty() {
+                // Log the changes in the same batch so they are never lost
+                batch.custom(changes);
+                self.core
+                    .storage
+                    .data
+                    .write(batch.build())
+                    .await
+                    .caused_by(trc::location!())?;
+                last_change_id = Some(change_id);
+            }
+
+            for (document_id, details) in audit {
+                trc::event!(
+                    Security(trc::SecurityEvent::AclChanged),
+                    Id = actor_token.primary_id,
+                    AccountId = account_id,
+                    Collection = collection,
+                    DocumentId = document_id,
+                    Details = details
+                );
+            }
+        }
+
+        if let Some(change_id) = last_change_id {
+            // Memoized grants are kept so that pinned requests are not affected
+            invalidate_effective_acls(account_id, collection);
+            self.broadcast_state_change(
+                StateChange::new(account_id).with_change(DataType::Mailbox, change_id),
+            )
+            .await;
+            self.increment_token_revision(ChangedPrincipals::from_change(
+                grantee_id,
+                Type::Individual,
+                PrincipalField::EnabledPermissions,
+            ))
+            .await;
+        }
+
+        Ok(updated)
+    }
+
+    async fn reassign_grants(&self, previous_id: u32, principal_id: u32) -> trc::Result<usize> {
+        let mut shared_documents: AHashMap<(u32, u8), Vec<u32>> = AHashMap::new();
+        for item in acl_query(
+            self,
+            AclQuery::GrantedTo {
+                grant_account_id: previous_id,
+            },
+        )
+        .await
+        .caused_by(trc::location!())?
+        {
+            shared_documents
+                .entry((item.to_account_id, item.to_collection))
+                .or_default()
+                .push(item.to_document_id);
+        }
+
+        let mut updated = 0;
+        for ((account_id, collection), document_ids) in shared_documents {
+            let collection = Collection::from(collection);
+            let schema = match collection {
+                Collection::Mailbox => MAILBOX_SCHEMA,
+                _ => {
+                    trc::error!(trc::StoreEvent::NotSupported
+                        .into_err()
+                        .details("Collection does not support ACLs")
+                        .account_id(account_id)
+                        .collection(collection)
+                        .caused_by(trc::location!()));
+                    continue;
+                }
+            };
+
+            let mut last_change_id = None;
+            for chunk in document_ids.chunks(GRANT_BATCH_SIZE) {
+                let change_id = self.generate_snowflake_id()?;
+                let mut changes = ChangeLogBuilder::with_change_id(change_id);
+                let mut batch = BatchBuilder::new();
+                batch
+                    .with_account_id(account_id)
+                    .with_collection(collection);
+
+                let mut audit = Vec::with_capacity(chunk.len());
+                for &document_id in chunk {
+                    let current = if let Some(current) = self
+                        .get_property::<HashedValue<Object<Value>>>(
+                            account_id,
+                            collection,
+                            document_id,
+                            Property::Value,
+                        )
+                        .await?
+                    {
+                        current
+                    } else {
+                        continue;
+                    };
+
+                    let current_acl = match current.inner.properties.get(&Property::Acl) {
+                        Some(Value::Acl(acl)) => acl.as_slice(),
+                        _ => &[],
+                    };
+                    let Some(previous_grant) = current_acl
+                        .iter()
+                        .find(|item| item.account_id == previous_id)
+                    else {
+                        continue;
+                    };
+                    let mut acl = current_acl
+                        .iter()
+                        .filter(|item| item.account_id != previous_id)
+                        .cloned()
+                        .collect::<Vec<_>>();
+                    if let Some(item) = acl.iter_mut().find(|item| item.account_id == principal_id)
+                    {
+                        item.grants.union(&previous_grant.grants);
+                    } else {
+                        acl.push(AclGrant {
+                            account_id: principal_id,
+                            ..previous_grant.clone()
+                        });
+                    }
+
+                    audit.push((
+                        document_id,
+                        audit_changed_grants(&acl_diff(current_acl, &acl)),
+                    ));
+
+                    let mut object = Object::with_capacity(1);
+                    object.set(Property::Acl, Value::Acl(acl));
+                    let builder = ObjectIndexBuilder::new(schema)
+                        .with_changes(object)
+                        .with_current(current);
+                    let acl_changes = builder.acl_changes();
+                    batch.update_document(document_id).custom(builder);
+                    log_grantee_acl_changes(
+                        &mut batch,
+                        change_id,
+                        account_id,
+                        collection,
+                        document_id,
+                        &acl_changes,
+                    );
+                    batch
+                        .with_account_id(account_id)
+                        .with_collection(collection);
+                    changes.log_update(collection, document_id);
+                    log_acl_changes(&mut changes, collection, document_id, &acl_changes);
+                    updated += 1;
+                }
+
+                if !batch.is_empty() {
+                    batch.custom(changes);
+                    self.core
+                        .storage
+                        .data
+                        .write(batch.build())
+                        .await
+                        .caused_by(trc::location!())?;
+                    last_change_id = Some(change_id);
+                }
+
+                for (document_id, details) in audit {
+                    trc::event!(
+                        Security(trc::SecurityEvent::AclChanged),
+                        Id = principal_id,
+                        AccountId = account_id,
+                        Collection = collection,
+                        DocumentId = document_id,
+                        Details = details
+                    );
+                }
+            }
+
+            if let Some(change_id) = last_change_id {
+                invalidate_effective_acls(account_id, collection);
+                self.broadcast_state_change(
+                    StateChange::new(account_id).with_change(DataType::Mailbox, change_id),
+                )
+                .await;
+            }
+        }
+
+        Ok(updated)
+    }
+
+    async fn apply_principal_changes(&self, changed_principals: ChangedPrincipals) {
+        for (previous_id, principal_id) in changed_principals.id_changes() {
+            if let Err(err) = self.reassign_grants(previous_id, principal_id).await {
+                trc::error!(err
+                    .details("Failed to reassign grants")
+                    .account_id(principal_id)
+                    .caused_by(trc::location!()));
+            }
+        }
+
+        self.increment_token_revision(changed_principals).await;
+    }
+
+    async fn verify_acl_index(
+        &self,
+        account_id: u32,
+        collection: Collection,
+        repair: bool,
+    ) -> trc::Result<Vec<u32>> {
+        let mut expected = AHashMap::new();
+        let mut grantees: AHashMap<u32, bool> = AHashMap::new();
+        for document_id in self
+            .get_document_ids(account_id, collection)
+            .await?
+            .unwrap_or_default()
+        {
+            let Some(mut object) = self
+                .get_property::<HashedValue<Object<Value>>>(
+                    account_id,
+                    collection,
+                    document_id,
+                    Property::Value,
+                )
+                .await?
+            else {
+                continue;
+            };
+            let mut grants = AHashMap::new();
+            if let Some(Value::Acl(acl)) = object.inner.remove(&Property::Acl) {
+                for item in acl {
+                    // The keys of grants to deleted principals are purged on deletion
+                    let exists = match grantees.get(&item.account_id) {
+                        Some(exists) => *exists,
+                        None => {
+                            let exists = self
+                                .core
+                                .storage
+                                .directory
+                                .query(QueryBy::Id(item.account_id), false)
+                                .await
+                                .caused_by(trc::location!())?
+                                .is_some();
+                            grantees.insert(item.account_id, exists);
+                            exists
+                        }
+                    };
+                    if exists {
+                        grants.insert(item.account_id, item.index_value());
+                    }
+                }
+            }
+            expected.insert(
+                document_id,
+                DocumentAcl {
+                    grants,
+                    assert_value: object.to_assert_value(),
+                },
+            );
+        }
+
+        self.core
+            .storage
+            .data
+            .verify_acl_index(
+                account_id,
+                collection,
+                Property::Value.into(),
+                expected,
+                repair,
+            )
+            .await
+            .caused_by(trc::location!())
+    }
+
+    async fn list_document_acls(
+        &self,
+        account_id: u32,
+        collection: Collection,
+        document_id: u32,
+    ) -> trc::Result<Vec<AclGrant>> {
+        self.get_property::<Object<Value>>(account_id, collection, document_id, Property::Value)
+            .await
+            .caused_by(trc::location!())
+            .map(
+                |object| match object.map(|mut object| object.remove(&Property::Acl)) {
+                    Some(Value::Acl(acl)) => acl,
+                    _ => Vec::new(),
+                },
+            )
+    }
+
     async fn acl_set(
         &self,
+        access_token: &AccessToken,
+        account_id: u32,
         changes: &mut Object<Value>,
         current: Option<&HashedValue<Object<Value>>>,
         acl_changes: MaybePatchValue,
     ) -> Result<(), SetError> {
         match acl_changes {
             MaybePatchValue::Value(Value::List(values)) => {
-                changes
-                    .properties
-                    .set(Property::Acl, Value::Acl(self.map_acl_set(values).await?));
+                let current_acl = match current
+                    .and_then(|current| current.inner.properties.get(&Property::Acl))
+                {
+                    Some(Value::Acl(acl)) => acl.as_slice(),
+                    _ => &[],
+                };
+                let acl = self.map_acl_set(values, current_acl).await?;
+                changes.properties.set(Property::Acl, Value::Acl(acl));
             }
             MaybePatchValue::Patch(patch) => {
                 let (mut patch, is_update) = self.map_acl_patch(patch).await?;
@@ -308,7 +1534,7 @@ impl AclMethods for Server {
                         .iter_mut()
                         .find(|item| item.account_id == patch.account_id)
                     {
-                        acl_item.grants = patch.grants;
+                        *acl_item = patch;
                     } else {
                         acl.push(patch);
                     }
@@ -322,6 +1548,25 @@ impl AclMethods for Server {
                     .with_description("Invalid ACL property."))
             }
         }
+
+        // Grants to groups count as well, any of their members can manage the object
+        let has_administrators = |acl: Option<&Value>| {
+            matches!(acl, Some(Value::Acl(acl))
+                if acl.iter().any(|item| item.grants.contains(Acl::Administer)))
+        };
+        if !access_token.is_member(account_id)
+            && has_administrators(
+                current.and_then(|current| current.inner.properties.get(&Property::Acl)),
+            )
+            && !has_administrators(changes.properties.get(&Property::Acl))
+        {
+            return Err(SetError::forbidden()
+                .with_property(Property::Acl)
+                .with_description(
+                    "Only the owner can remove the last administer right from this object.",
+                ));
+        }
+
         Ok(())
     }
 
@@ -333,26 +1578,41 @@ impl AclMethods for Server {
     ) -> Value {
         if access_token.is_member(account_id)
             || value.iter().any(|item| {
-                access_token.is_member(item.account_id) && item.grants.contains(Acl::Administer)
+                access_token.is_member(item.account_id)
+                    && item
+                        .grants
+                        .contains_any([Acl::Administer, Acl::ManageShares].into_iter())
             })
         {
             let mut acl_obj = Object::with_capacity(value.len() / 2);
             for item in value {
-                if let Some(mut principal) = self
+                // Grants to deleted principals are listed under a placeholder name,
+                // so that they can be seen and removed
+                let name = match self
                     .core
                     .storage
                     .directory
                     .query(QueryBy::Id(item.account_id), false)
                     .await
-                    .unwrap_or_default()
                 {
-                    acl_obj.append(
-                        Property::_T(principal.take_str(PrincipalField::Name).unwrap_or_default()),
-                        item.grants
-                            .map(|acl_item| Value::Text(acl_item.to_string()))
-                            .collect::<Vec<_>>(),
-                    );
-                }
+                    Ok(Some(mut principal)) => {
+                        principal.take_str(PrincipalField::Name).unwrap_or_default()
+                    }
+                    Ok(None) => format!("{DELETED_PRINCIPAL_PREFIX}{}", item.account_id),
+                    Err(err) => {
+                        trc::error!(err
+                            .caused_by(trc::location!())
+                            .details("Failed to look up ACL grantee"));
+                        continue;
+                    }
+                };
+                acl_obj.append(
+                    Property::_T(name),
+                    item.grants
+                        .map(|acl_item| Value::Text(acl_item.to_string()))
+                        .chain(item.modifiers().map(Value::Text))
+                        .collect::<Vec<_>>(),
+                );
             }
 
             Value::Object(acl_obj)
@@ -363,66 +1623,95 @@ impl AclMethods for Server {
 
     async fn refresh_acls(
         &self,
-        changes: &Object<Value>,
+        access_token: &AccessToken,
+        account_id: u32,
+        collection: Collection,
+        document_id: Option<u32>,
+        changes: &mut Object<Value>,
         current: &Option<HashedValue<Object<Value>>>,
     ) {
+        let _ = SHARED_GRANTS.try_with(|memo| {
+            memo.grants
+                .lock()
+                .remove(&(account_id, u8::from(collection)))
+        });
+        invalidate_effective_acls(account_id, collection);
+        if let Some(Value::Acl(acl_changes)) = changes.properties.get_mut(&Property::Acl) {
+            // Expired grants no longer apply, drop them while the ACL is being rewritten
+            acl_changes.retain(|item| !item.is_expired());
+        }
+
         if let Value::Acl(acl_changes) = changes.get(&Property::Acl) {
-            let mut changed_principals = ChangedPrincipals::new();
-            if let Some(Value::Acl(acl_current)) = current
+            let acl_current = match current
                 .as_ref()
-                .and_then(|current| current.inner.properties.get(&Property::Acl))
+                .map(|current| current.inner.get(&Property::Acl))
             {
-                for current_item in acl_current {
-                    let mut invalidate = true;
-                    for change_item in acl_changes {
-                        if change_item.account_id == current_item.account_id {
-                            invalidate = change_item.grants != current_item.grants;
-                            break;
-                        }
-                    }
-                    if invalidate {
-                        changed_principals.add_change(
-                            current_item.account_id,
-                            Type::Individual,
-                            PrincipalField::EnabledPermissions,
-                        );
-                    }
-                }
+                Some(Value::Acl(acl)) => acl.as_slice(),
+                _ => &[],
+            };
+            let diff = acl_diff(acl_current, acl_changes);
+            if diff.is_empty() {
+                return;
+            }
 
-                for change_item in acl_changes {
-                    let mut invalidate = true;
-                    for current_item in acl_current {
-                        if change_item.account_id == current_item.account_id {
-                            invalidate = change_item.grants != current_item.grants;
-                            break;
-                        }
-                    }
-                    if invalidate {
-                        changed_principals.add_change(
-                            change_item.account_id,
-                            Type::Individual,
-                            PrincipalField::EnabledPermissions,
-                        );
-                    }
-                }
-            } else {
-                for value in acl_changes {
-                    changed_principals.add_change(
-                        value.account_id,
-                        Type::Individual,
-                        PrincipalField::EnabledPermissions,
-                    );
-                }
+            trc::event!(
+                Security(trc::SecurityEvent::AclChanged),
+                Id = access_token.primary_id,
+                AccountId = account_id,
+                Collection = collection,
+                DocumentId = document_id,
+                Details = audit_changed_grants(&diff)
+            );
+
+            let mut changed_principals = ChangedPrincipals::new();
+            for account_id in diff.account_ids() {
+                changed_principals.add_change(
+                    account_id,
+                    Type::Individual,
+                    PrincipalField::EnabledPermissions,
+                );
             }
 
             self.increment_token_revision(changed_principals).await;
         }
     }
 
-    async fn map_acl_set(&self, acl_set: Vec<Value>) -> Result<Vec<AclGrant>, SetError> {
+    async fn map_acl_set(
+        &self,
+        acl_set: Vec<Value>,
+        current: &[AclGrant],
+    ) -> Result<Vec<AclGrant>, SetError> {
         let mut acls = Vec::with_capacity(acl_set.len() / 2);
         for item in acl_set.chunks_exact(2) {
-            if let (Value::Text(account_name), Value::UnsignedInt(grants)) = (&item[0], &item[1]) {
+            if let (Value::Text(account_name), Some(rights)) =
+                (&item[0], AclRights::from_value(&item[1]))
+            {
+                if let Some(account_id) = account_name
+                    .strip_prefix(DELETED_PRINCIPAL_PREFIX)
+                    .and_then(|id| id.parse::<u32>().ok())
+                {
+                    // Grants to deleted principals are kept unchanged, leaving
+                    // them out of the list removes them
+                    let grant = current
+                        .iter()
+                        .find(|grant| grant.account_id == account_id)
+                        .filter(|grant| grant.grants == rights.grants)
+                        .ok_or_else(|| {
+                            SetError::invalid_properties()
+                                .with_property(Property::Acl)
+                                .with_description(format!(
+                                    "Grants to {account_name} can only be kept or removed."
+                                ))
+                        })?;
+                    if !acls
+                        .iter()
+                        .any(|item: &AclGrant| item.account_id == account_id)
+                    {
+                        acls.push(grant.clone());
+                    }
+                    continue;
+                }
+
                 match self
                     .core
                     .storage
@@ -431,10 +1720,32 @@ impl AclMethods for Server {
                     .await
                 {
                     Ok(Some(principal)) => {
-                        acls.push(AclGrant {
-                            account_id: principal.id(),
-                            grants: Bitmap::from(*grants),
-                        });
+                        // Grants to a group apply to the members of its nested groups
+                        // as well, as these are included in `AccessToken::grantee_ids`
+                        if !matches!(principal.typ(), Type::Individual | Type::Group) {
+                            return Err(SetError::invalid_properties()
+                                .with_property(Property::Acl)
+                                .with_description(format!(
+                                    "Account {account_name} cannot be granted access."
+                                )));
+                        }
+                        let grant = map_acl_rights(principal.id(), rights)?;
+                        if let Some(current) = acls
+                            .iter_mut()
+                            .find(|item| item.account_id == grant.account_id)
+                        {
+                            if self.core.jmap.acl_duplicate_grantee == DuplicateGrantee::Reject
+                                || !current.merge(&grant)
+                            {
+                                return Err(SetError::invalid_properties()
+                                    .with_property(Property::Acl)
+                                    .with_description(format!(
+                                        "Account {account_name} is listed more than once."
+                                    )));
+                            }
+                        } else {
+                            acls.push(grant);
+                        }
                     }
                     Ok(None) => {
                         return Err(SetError::invalid_properties()
@@ -461,9 +1772,19 @@ impl AclMethods for Server {
         &self,
         acl_patch: Vec<Value>,
     ) -> Result<(AclGrant, Option<bool>), SetError> {
-        if let (Value::Text(account_name), Value::UnsignedInt(grants)) =
-            (&acl_patch[0], &acl_patch[1])
+        if let (Value::Text(account_name), Some(rights)) =
+            (&acl_patch[0], AclRights::from_value(&acl_patch[1]))
         {
+            // Grants to deleted principals can be revoked but not given
+            let is_update = acl_patch.get(2).map(|v| v.as_bool().unwrap_or(false));
+            if let Some(account_id) = account_name
+                .strip_prefix(DELETED_PRINCIPAL_PREFIX)
+                .and_then(|id| id.parse::<u32>().ok())
+                .filter(|_| rights.grants.is_empty() || is_update == Some(false))
+            {
+                return Ok((map_acl_rights(account_id, rights)?, is_update));
+            }
+
             match self
                 .core
                 .storage
@@ -471,13 +1792,17 @@ impl AclMethods for Server {
                 .query(QueryBy::Name(account_name), false)
                 .await
             {
-                Ok(Some(principal)) => Ok((
-                    AclGrant {
-                        account_id: principal.id(),
-                        grants: Bitmap::from(*grants),
-                    },
-                    acl_patch.get(2).map(|v| v.as_bool().unwrap_or(false)),
-                )),
+                Ok(Some(principal))
+                    if !rights.grants.is_empty()
+                        && !matches!(principal.typ(), Type::Individual | Type::Group) =>
+                {
+                    Err(SetError::invalid_properties()
+                        .with_property(Property::Acl)
+                        .with_description(format!(
+                            "Account {account_name} cannot be granted access."
+                        )))
+                }
+                Ok(Some(principal)) => Ok((map_acl_rights(principal.id(), rights)?, is_update)),
                 Ok(None) => Err(SetError::invalid_properties()
                     .with_property(Property::Acl)
                     .with_description(format!("Account {account_name} does not exist."))),
@@ -493,21 +1818,330 @@ impl AclMethods for Server {
     }
 }
 
+/// Explains the outcome of an access check. As with `has_access_to_document`,
+/// access is allowed when an active grant provides any of the requested
+/// permissions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AclExplanation {
+    pub requested: Bitmap<Acl>,
+    /// Permissions provided by all active grants, requested or not
+    pub held: Bitmap<Acl>,
+    /// Principals considered, the account itself followed by its groups. Members
+    /// of the owning account are only listed with the account, without a grant.
+    pub grantees: Vec<GranteeExplanation>,
+    pub allowed: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GranteeExplanation {
+    pub account_id: u32,
+    pub grant: Option<AclGrant>,
+    /// Requested permissions provided by the grant, empty if it is not active
+    pub matched: Bitmap<Acl>,
+}
+
 pub trait EffectiveAcl {
-    fn effective_acl(&self, access_token: &AccessToken) -> Bitmap<Acl>;
+    /// Rights held by the token over an object of `account_id`, in order of
+    /// precedence:
+    ///
+    /// 1. Members of the owning account hold every right, including `Acl::Owner`.
+    /// 2. With `AclEvaluation::Priority`, an active grant to the token's own
+    ///    principal determines its rights on its own, so it can withhold rights
+    ///    granted to its groups.
+    /// 3. Otherwise the active grants to the principal and its groups are added up.
+    ///
+    /// Grants that are inactive because of their schedule, expiry or networks
+    /// are skipped at every step.
+    fn effective_acl(
+        &self,
+        access_token: &AccessToken,
+        account_id: u32,
+        evaluation: AclEvaluation,
+    ) -> Bitmap<Acl>;
+
+    fn explain_effective_acl(
+        &self,
+        access_token: &AccessToken,
+        check_acls: impl Into<Bitmap<Acl>>,
+    ) -> AclExplanation;
 }
 
 impl EffectiveAcl for Object<Value> {
-    fn effective_acl(&self, access_token: &AccessToken) -> Bitmap<Acl> {
+    fn effective_acl(
+        &self,
+        access_token: &AccessToken,
+        account_id: u32,
+        evaluation: AclEvaluation,
+    ) -> Bitmap<Acl> {
+        if access_token.is_member(account_id) {
+            return Bitmap::all();
+        }
+
         let mut acl = Bitmap::<Acl>::new();
         if let Some(Value::Acl(permissions)) = self.properties.get(&Property::Acl) {
-            for item in permissions {
-                if access_token.is_member(item.account_id) {
-                    acl.union(&item.grants);
+            let active = permissions.iter().filter(|item| {
+                access_token.is_member(item.account_id)
+                    && item.is_active_from(access_token.remote_ip.as_ref())
+            });
+
+            if evaluation == AclEvaluation::Priority {
+                if let Some(item) = active
+                    .clone()
+                    .find(|item| item.account_id == access_token.primary_id)
+                {
+                    return item.grants;
                 }
             }
+
+            for item in active {
+                acl.union(&item.grants);
+            }
         }
 
         acl
     }
+
+    fn explain_effective_acl(
+        &self,
+        access_token: &AccessToken,
+        check_acls: impl Into<Bitmap<Acl>>,
+    ) -> AclExplanation {
+        let grants = if let Some(Value::Acl(grants)) = self.properties.get(&Property::Acl) {
+            grants.as_slice()
+        } else {
+            &[]
+        };
+        let mut grantees = access_token
+            .grantee_ids()
+            .map(|account_id| {
+                (
+                    account_id,
+                    grants
+                        .iter()
+                        .find(|grant| grant.account_id == account_id)
+                        .cloned(),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        // Principals allowed to impersonate hold every grant
+        let impersonated = grants
+            .iter()
+            .filter(|grant| {
+                access_token.is_member(grant.account_id)
+                    && !grantees
+                        .iter()
+                        .any(|(account_id, _)| *account_id == grant.account_id)
+            })
+            .map(|grant| (grant.account_id, Some(grant.clone())))
+            .collect::<Vec<_>>();
+        grantees.extend(impersonated);
+
+        AclExplanation::new(check_acls.into(), grantees, access_token.remote_ip.as_ref())
+    }
+}
+
+impl AclExplanation {
+    fn member(account_id: u32, requested: Bitmap<Acl>) -> Self {
+        AclExplanation {
+            requested,
+            held: Bitmap::all(),
+            grantees: vec![GranteeExplanation {
+                account_id,
+                grant: None,
+                matched: requested,
+            }],
+            allowed: true,
+        }
+    }
+
+    fn new(
+        requested: Bitmap<Acl>,
+        grantees: impl IntoIterator<Item = (u32, Option<AclGrant>)>,
+        remote_ip: Option<&IpAddr>,
+    ) -> Self {
+        let mut held = Bitmap::new();
+        let grantees = grantees
+            .into_iter()
+            .map(|(account_id, grant)| {
+                let mut matched = Bitmap::new();
+                if let Some(grant) = grant
+                    .as_ref()
+                    .filter(|grant| grant.is_active_from(remote_ip))
+                {
+                    held.union(&grant.grants);
+                    matched = grant.grants;
+                    matched.intersection(&requested);
+                }
+                GranteeExplanation {
+                    account_id,
+                    grant,
+                    matched,
+                }
+            })
+            .collect::<Vec<_>>();
+
+        AclExplanation {
+            requested,
+            held,
+            allowed: grantees.iter().any(|grantee| !grantee.matched.is_empty()),
+            grantees,
+        }
+    }
+
+    /// Principals whose grants allowed access.
+    pub fn allowed_by(&self) -> impl Iterator<Item = u32> + '_ {
+        self.grantees
+            .iter()
+            .filter(|grantee| !grantee.matched.is_empty())
+            .map(|grantee| grantee.account_id)
+    }
+}
+
+/// Parent of each mailbox of the account that is not at the root.
+async fn mailbox_parents(server: &Server, account_id: u32) -> trc::Result<AHashMap<u32, u32>> {
+    let mailbox_ids = server
+        .get_document_ids(account_id, Collection::Mailbox)
+        .await?
+        .unwrap_or_default();
+    Ok(server
+        .get_properties::<Object<Value>, _, _>(
+            account_id,
+            Collection::Mailbox,
+            &mailbox_ids,
+            Property::Value,
+        )
+        .await?
+        .into_iter()
+        .filter_map(|(mailbox_id, mailbox)| {
+            mailbox
+                .properties
+                .get(&Property::ParentId)
+                .and_then(|id| id.as_id())
+                .map(|id| id.document_id())
+                .filter(|&parent_id| parent_id > 0)
+                .map(|parent_id| (mailbox_id, parent_id - 1))
+        })
+        .collect())
+}
+
+/// Grants inherited by mailboxes without a grant of their own to a principal,
+/// taken from the nearest parent mailbox granting that principal access when
+/// the grant is marked with `Acl::Inherit`. Walks stop after `max_depth`
+/// parents or when a mailbox is visited twice, as the tree could be corrupt.
+fn inherited_grants(
+    grants: &[(u32, AclGrant)],
+    parents: &AHashMap<u32, u32>,
+    max_depth: usize,
+) -> Vec<(u32, AclGrant)> {
+    let granted = grants
+        .iter()
+        .map(|(mailbox_id, grant)| ((*mailbox_id, grant.account_id), grant))
+        .collect::<AHashMap<_, _>>();
+    let grantees = grants
+        .iter()
+        .filter(|(_, grant)| grant.grants.contains(Acl::Inherit))
+        .map(|(_, grant)| grant.account_id)
+        .collect::<AHashSet<_>>();
+
+    let mut inherited = Vec::new();
+    for &grantee in &grantees {
+        for (&mailbox_id, &parent_id) in parents {
+            if granted.contains_key(&(mailbox_id, grantee)) {
+                continue;
+            }
+
+            let mut visited = AHashSet::from_iter([mailbox_id]);
+            let mut parent_id = Some(parent_id);
+            while let Some(ancestor_id) =
+                parent_id.filter(|&id| visited.len() <= max_depth && visited.insert(id))
+            {
+                if let Some(&grant) = granted.get(&(ancestor_id, grantee)) {
+                    if grant.grants.contains(Acl::Inherit) {
+                        inherited.push((mailbox_id, grant.clone()));
+                    }
+                    break;
+                }
+                parent_id = parents.get(&ancestor_id).copied();
+            }
+        }
+    }
+
+    inherited
+}
+
+fn criteria_filters(criteria: &AclCriteria) -> Vec<query::Filter> {
+    let mut filters = Vec::with_capacity(6);
+    if let Some(from) = &criteria.from {
+        // Sender index entries are either the address or the name followed by the address
+        filters.push(query::Filter::Or);
+        filters.push(query::Filter::eq(Property::From, from));
+        filters.push(query::Filter::ends_with(Property::From, format!(" {from}")));
+        filters.push(query::Filter::End);
+    }
+    if let Some(after) = criteria.after {
+        filters.push(query::Filter::gt(Property::ReceivedAt, after));
+    }
+    if let Some(before) = criteria.before {
+        filters.push(query::Filter::lt(Property::ReceivedAt, before));
+    }
+    filters
+}
+
+/// Checks a change made to the ACL of an object by a principal that does not
+/// own it. Unless `rights`, the rights it holds over the object, include the
+/// administer right, it can neither grant or revoke the administer right nor
+/// change the grants held by itself or its groups.
+pub fn check_delegated_acl(
+    access_token: &AccessToken,
+    account_id: u32,
+    rights: Bitmap<Acl>,
+    current: &[AclGrant],
+    changes: &[AclGrant],
+) -> Result<(), &'static str> {
+    if access_token.is_member(account_id) || rights.contains(Acl::Administer) {
+        Ok(())
+    } else if administrators(current) != administrators(changes) {
+        Err("You are not allowed to grant or revoke the administer right.")
+    } else if own_grants(access_token, current) != own_grants(access_token, changes) {
+        Err("You are not allowed to change your own rights.")
+    } else {
+        Ok(())
+    }
+}
+
+fn administrators(acl: &[AclGrant]) -> Vec<u32> {
+    let mut account_ids = acl
+        .iter()
+        .filter(|item| item.grants.contains(Acl::Administer))
+        .map(|item| item.account_id)
+        .collect::<Vec<_>>();
+    account_ids.sort_unstable();
+    account_ids
+}
+
+fn own_grants(access_token: &AccessToken, acl: &[AclGrant]) -> Vec<AclGrant> {
+    // Grantors are recorded when the ACL is written, they are not part of the grant
+    let mut grants = acl
+        .iter()
+        .filter(|item| access_token.is_member(item.account_id))
+        .map(|item| AclGrant {
+            granted_by: None,
+            ..item.clone()
+        })
+        .collect::<Vec<_>>();
+    grants.sort_unstable_by_key(|item| item.account_id);
+    grants
+}
+
+fn map_acl_rights(account_id: u32, rights: AclRights) -> Result<AclGrant, SetError> {
+    let mut grant = AclGrant::new(account_id, rights.grants);
+    for modifier in &rights.modifiers {
+        grant.set_modifier(modifier).map_err(|err| {
+            SetError::invalid_properties()
+                .with_property(Property::Acl)
+                .with_description(err)
+        })?;
+    }
+    Ok(grant)
 }
@@ -45,7 +45,9 @@ impl Authenticator for Server {
                     return self
                         .is_http_authenticated_request_allowed(&access_token)
                         .await
-                        .map(|in_flight| (in_flight, access_token));
+                        .map(|in_flight| {
+                            (in_flight, access_token.with_remote_ip(session.remote_ip))
+                        });
                 }
             }
 
@@ -103,7 +105,7 @@ impl Authenticator for Server {
             // Enforce authenticated rate limit
             self.is_http_authenticated_request_allowed(&access_token)
                 .await
-                .map(|in_flight| (in_flight, access_token))
+                .map(|in_flight| (in_flight, access_token.with_remote_ip(session.remote_ip)))
         } else {
             // Enforce anonymous rate limit
             self.is_http_anonymous_request_allowed(&session.remote_ip)
@@ -17,6 +17,7 @@ use jmap_proto::{
     types::id::Id,
 };
 
+use store::dispatch::blob::BlobHint;
 use trc::AddContext;
 
 use crate::auth::rate_limit::RateLimiter;
@@ -181,7 +182,16 @@ impl BlobUpload for Server {
             response.created.insert(
                 create_id,
                 BlobUploadResponseObject {
-                    id: self.put_blob(account_id, &data, true).await?,
+                    id: self
+                        .put_blob_with_hint(
+                            account_id,
+                            &data,
+                            true,
+                            BlobHint::Attachment {
+                                content_type: upload_object.type_.as_deref().unwrap_or_default(),
+                            },
+                        )
+                        .await?,
                     type_: upload_object.type_,
                     size: data.len(),
                 },
@@ -243,7 +253,12 @@ impl BlobUpload for Server {
         Ok(UploadResponse {
             account_id,
             blob_id: self
-                .put_blob(account_id.document_id(), data, true)
+                .put_blob_with_hint(
+                    account_id.document_id(),
+                    data,
+                    true,
+                    BlobHint::Attachment { content_type },
+                )
                 .await
                 .caused_by(trc::location!())?,
             c_type: content_type.to_string(),
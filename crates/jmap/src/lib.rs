@@ -19,7 +19,9 @@ use jmap_proto::{
     types::collection::Collection,
 };
 use services::{
-    housekeeper::spawn_housekeeper, index::spawn_email_queue_task, state::spawn_state_manager,
+    housekeeper::spawn_housekeeper,
+    index::spawn_email_queue_task,
+    state::{spawn_change_listener, spawn_state_manager},
 };
 
 use store::{
@@ -82,6 +84,9 @@ impl SpawnServices for IpcReceivers {
         // Spawn state manager
         spawn_state_manager(inner.clone(), self.state_rx.take().unwrap());
 
+        // Spawn listener for changes made by other nodes
+        spawn_change_listener(inner.clone());
+
         // Spawn housekeeper
         spawn_housekeeper(inner.clone(), self.housekeeper_rx.take().unwrap());
 
@@ -12,10 +12,7 @@ use jmap_proto::{
     types::{acl::Acl, collection::Collection, property::Property, value::Value},
 };
 
-use crate::{
-    auth::acl::{AclMethods, EffectiveAcl},
-    changes::state::StateManager,
-};
+use crate::{auth::acl::AclMethods, changes::state::StateManager};
 
 use std::future::Future;
 
@@ -51,7 +48,7 @@ impl MailboxGet for Server {
         let mut mailbox_ids = self.mailbox_get_or_create(account_id).await?;
         if access_token.is_shared(account_id) {
             mailbox_ids &= self
-                .shared_documents(access_token, account_id, Collection::Mailbox, Acl::Read)
+                .visible_shared_mailboxes(access_token, account_id)
                 .await?;
         }
         let message_ids = self.get_document_ids(account_id, Collection::Email).await?;
@@ -173,7 +170,15 @@ impl MailboxGet for Server {
                     ),
                     Property::MyRights => {
                         if access_token.is_shared(account_id) {
-                            let acl = values.effective_acl(access_token);
+                            let acl = self
+                                .document_effective_acl(
+                                    access_token,
+                                    account_id,
+                                    Collection::Mailbox,
+                                    document_id,
+                                    &values,
+                                )
+                                .await?;
                             Object::with_capacity(9)
                                 .with_property(Property::MayReadItems, acl.contains(Acl::ReadItems))
                                 .with_property(Property::MayAddItems, acl.contains(Acl::AddItems))
@@ -9,7 +9,7 @@ use email::mailbox::MailboxFnc;
 use jmap_proto::{
     method::query::{Comparator, Filter, QueryRequest, QueryResponse, SortProperty},
     object::{mailbox::QueryArguments, Object},
-    types::{acl::Acl, collection::Collection, property::Property, value::Value},
+    types::{collection::Collection, property::Property, value::Value},
 };
 use store::{
     ahash::{AHashMap, AHashSet},
@@ -103,7 +103,7 @@ impl MailboxQuery for Server {
             .await?;
         if access_token.is_shared(account_id) {
             result_set.apply_mask(
-                self.shared_documents(access_token, account_id, Collection::Mailbox, Acl::Read)
+                self.visible_shared_mailboxes(access_token, account_id)
                     .await?,
             );
         }
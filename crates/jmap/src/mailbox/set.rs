@@ -13,7 +13,7 @@ use jmap_proto::{
     object::{index::ObjectIndexBuilder, mailbox::SetArguments, Object},
     response::references::EvalObjectReferences,
     types::{
-        acl::Acl,
+        acl::{cascade_revocations, log_acl_changes, log_grantee_acl_changes, track_grantors, Acl},
         collection::Collection,
         id::Id,
         property::Property,
@@ -32,8 +32,10 @@ use store::{
     },
 };
 
+use trc::AddContext;
+
 use crate::{
-    auth::acl::{AclMethods, EffectiveAcl},
+    auth::acl::{check_delegated_acl, AclMethods, EffectiveAcl},
     email::delete::EmailDeletion,
     JmapMethods,
 };
@@ -117,6 +119,7 @@ impl MailboxSet for Server {
                         }
                     }
 
+                    let acl_changes = builder.acl_changes();
                     batch.create_document().custom(builder);
 
                     match self
@@ -129,6 +132,32 @@ impl MailboxSet for Server {
                     {
                         Ok(document_id) => {
                             changes.log_insert(Collection::Mailbox, document_id);
+                            log_acl_changes(
+                                &mut changes,
+                                Collection::Mailbox,
+                                document_id,
+                                &acl_changes,
+                            );
+
+                            // The document id is only known once created, so the
+                            // grantees are notified in a separate batch
+                            if !acl_changes.is_empty() {
+                                let mut batch = BatchBuilder::new();
+                                log_grantee_acl_changes(
+                                    &mut batch,
+                                    self.generate_snowflake_id()?,
+                                    account_id,
+                                    Collection::Mailbox,
+                                    document_id,
+                                    &acl_changes,
+                                );
+                                self.core
+                                    .storage
+                                    .data
+                                    .write(batch.build())
+                                    .await
+                                    .caused_by(trc::location!())?;
+                            }
                             ctx.mailbox_ids.insert(document_id);
                             ctx.response.created(id, document_id);
                         }
@@ -176,17 +205,28 @@ impl MailboxSet for Server {
             {
                 // Validate ACL
                 if ctx.is_shared {
-                    let acl = mailbox.inner.effective_acl(access_token);
-                    if !acl.contains(Acl::Modify) {
+                    let acl = self
+                        .document_effective_acl(
+                            access_token,
+                            account_id,
+                            Collection::Mailbox,
+                            document_id,
+                            &mailbox.inner,
+                        )
+                        .await?;
+                    let changes_acl = object.properties.contains_key(&Property::Acl);
+                    let can_share =
+                        acl.contains_any([Acl::Administer, Acl::ManageShares].into_iter());
+                    if !acl.contains(Acl::Modify)
+                        && !(changes_acl && can_share && object.properties.len() == 1)
+                    {
                         ctx.response.not_updated.append(
                             id,
                             SetError::forbidden()
                                 .with_description("You are not allowed to modify this mailbox."),
                         );
                         continue 'update;
-                    } else if object.properties.contains_key(&Property::Acl)
-                        && !acl.contains(Acl::Administer)
-                    {
+                    } else if changes_acl && !can_share {
                         ctx.response.not_updated.append(
                             id,
                             SetError::forbidden().with_description(
@@ -218,12 +258,29 @@ impl MailboxSet for Server {
                             }
                         }
 
+                        let acl_changes = builder.acl_changes();
                         batch.update_document(document_id).custom(builder);
+                        if !acl_changes.is_empty() {
+                            log_grantee_acl_changes(
+                                &mut batch,
+                                self.generate_snowflake_id()?,
+                                account_id,
+                                Collection::Mailbox,
+                                document_id,
+                                &acl_changes,
+                            );
+                        }
 
                         if !batch.is_empty() {
                             match self.core.storage.data.write(batch.build()).await {
                                 Ok(_) => {
                                     changes.log_update(Collection::Mailbox, document_id);
+                                    log_acl_changes(
+                                        &mut changes,
+                                        Collection::Mailbox,
+                                        document_id,
+                                        &acl_changes,
+                                    );
                                 }
                                 Err(err) if err.is_assertion_failure() => {
                                     ctx.response.not_updated.append(id, SetError::forbidden().with_description(
@@ -442,7 +499,15 @@ impl MailboxSet for Server {
         {
             // Validate ACLs
             if access_token.is_shared(account_id) {
-                let acl = mailbox.inner.effective_acl(access_token);
+                let acl = self
+                    .document_effective_acl(
+                        access_token,
+                        account_id,
+                        Collection::Mailbox,
+                        document_id,
+                        &mailbox.inner,
+                    )
+                    .await?;
                 if !acl.contains(Acl::Administer) {
                     if !acl.contains(Acl::Delete) {
                         return Ok(Err(SetError::forbidden()
@@ -455,17 +520,30 @@ impl MailboxSet for Server {
                 }
             }
 
+            let builder = ObjectIndexBuilder::new(SCHEMA).with_current(mailbox);
+            let acl_changes = builder.acl_changes();
             let mut batch = BatchBuilder::new();
             batch
                 .with_account_id(account_id)
                 .with_collection(Collection::Mailbox)
                 .delete_document(document_id)
                 .value(Property::EmailIds, (), F_VALUE | F_CLEAR)
-                .custom(ObjectIndexBuilder::new(SCHEMA).with_current(mailbox));
+                .custom(builder);
+            if !acl_changes.is_empty() {
+                log_grantee_acl_changes(
+                    &mut batch,
+                    self.generate_snowflake_id()?,
+                    account_id,
+                    Collection::Mailbox,
+                    document_id,
+                    &acl_changes,
+                );
+            }
 
             match self.core.storage.data.write(batch.build()).await {
                 Ok(_) => {
                     changes.log_delete(Collection::Mailbox, document_id);
+                    log_acl_changes(changes, Collection::Mailbox, document_id, &acl_changes);
                     Ok(Ok(did_remove_emails))
                 }
                 Err(err) if err.is_assertion_failure() => Ok(Err(SetError::forbidden()
@@ -563,7 +641,13 @@ impl MailboxSet for Server {
                 }
                 (Property::Acl, value) => {
                     match self
-                        .acl_set(&mut changes, update.as_ref().map(|(_, obj)| obj), value)
+                        .acl_set(
+                            ctx.access_token,
+                            ctx.account_id,
+                            &mut changes,
+                            update.as_ref().map(|(_, obj)| obj),
+                            value,
+                        )
                         .await
                     {
                         Ok(_) => continue,
@@ -616,8 +700,15 @@ impl MailboxSet for Server {
                 {
                     if depth == 0
                         && ctx.is_shared
-                        && !fields
-                            .effective_acl(ctx.access_token)
+                        && !self
+                            .document_effective_acl(
+                                ctx.access_token,
+                                ctx.account_id,
+                                Collection::Mailbox,
+                                parent_document_id,
+                                &fields,
+                            )
+                            .await?
                             .contains_any([Acl::CreateChild, Acl::Administer].into_iter())
                     {
                         return Ok(Err(SetError::forbidden().with_description(
@@ -755,9 +846,64 @@ impl MailboxSet for Server {
         }
 
         // Refresh ACLs
+        let document_id = update.as_ref().map(|(document_id, _)| *document_id);
         let current = update.map(|(_, current)| current);
         if changes.properties.contains_key(&Property::Acl) {
-            self.refresh_acls(&changes, &current).await;
+            // Record who created each grant and drop the grants derived from revoked principals
+            let current_acl = match current
+                .as_ref()
+                .map(|current| current.inner.get(&Property::Acl))
+            {
+                Some(Value::Acl(acl)) => acl.as_slice(),
+                _ => &[],
+            };
+            if let Some(Value::Acl(acl)) = changes.properties.get_mut(&Property::Acl) {
+                track_grantors(
+                    acl,
+                    current_acl,
+                    ctx.is_shared.then_some(ctx.access_token.primary_id),
+                );
+                cascade_revocations(acl, current_acl);
+            }
+
+            // Delegates holding ManageShares cannot grant or revoke administer rights
+            // nor change their own rights
+            if ctx.is_shared {
+                let rights = current
+                    .as_ref()
+                    .map(|current| {
+                        current.inner.effective_acl(
+                            ctx.access_token,
+                            ctx.account_id,
+                            self.core.jmap.acl_evaluation,
+                        )
+                    })
+                    .unwrap_or_default();
+                let new_acl = match changes.get(&Property::Acl) {
+                    Value::Acl(acl) => acl.as_slice(),
+                    _ => &[],
+                };
+                if let Err(reason) = check_delegated_acl(
+                    ctx.access_token,
+                    ctx.account_id,
+                    rights,
+                    current_acl,
+                    new_acl,
+                ) {
+                    return Ok(Err(SetError::forbidden()
+                        .with_property(Property::Acl)
+                        .with_description(reason)));
+                }
+            }
+            self.refresh_acls(
+                ctx.access_token,
+                ctx.account_id,
+                Collection::Mailbox,
+                document_id,
+                &mut changes,
+                &current,
+            )
+            .await;
         }
 
         // Validate
@@ -688,8 +688,12 @@ impl Purge for Server {
                 // SPDX-SnippetEnd
             }
             PurgeType::Blobs { store, blob_store } => {
-                if let Err(err) = store.purge_blobs(blob_store).await {
+                if let Err(err) = store.purge_blobs(blob_store.clone()).await {
                     trc::error!(err.details("Failed to purge blob store"));
+                } else if let Some(grace_period) = blob_store.orphan_grace_period {
+                    if let Err(err) = store.purge_orphaned_blobs(&blob_store, grace_period).await {
+                        trc::error!(err.details("Failed to purge orphaned blobs"));
+                    }
                 }
             }
             PurgeType::Lookup { store, prefix } => {
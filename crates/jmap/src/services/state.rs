@@ -53,6 +53,22 @@ enum SubscriberId {
     Push(u32),
 }
 
+/// Forwards the changes committed by other nodes, when the data store notifies
+/// them, to the cache invalidation and state change path.
+pub fn spawn_change_listener(inner: Arc<Inner>) {
+    let Some(mut change_rx) = inner.shared_core.load().storage.data.listen_changes() else {
+        return;
+    };
+
+    tokio::spawn(async move {
+        while let Some(change) = change_rx.recv().await {
+            if !inner.build_server().apply_remote_change(change).await {
+                break;
+            }
+        }
+    });
+}
+
 #[allow(clippy::unwrap_or_default)]
 pub fn spawn_state_manager(inner: Arc<Inner>, mut change_rx: mpsc::Receiver<StateEvent>) {
     let push_tx = spawn_push_manager(inner.clone());
@@ -231,4 +231,36 @@ impl<T: SessionStream> Session<T> {
             .map(|token| token.emails.as_slice())
             .unwrap_or_default()
     }
+
+    /// Returns whether the authenticated principal may send as an address of
+    /// another account, which requires being granted `Acl::Submit` on one of
+    /// the mailboxes of that account.
+    pub async fn can_submit_as(&self, address: &str) -> bool {
+        let Some(access_token) = &self.data.authenticated_as else {
+            return false;
+        };
+        let directory = self
+            .params
+            .auth_directory
+            .as_ref()
+            .unwrap_or(&self.server.core.storage.directory);
+
+        let result = match directory.email_to_id(address).await {
+            Ok(Some(account_id)) if !access_token.is_member(account_id) => {
+                self.server
+                    .has_submit_access(access_token, account_id, Some(&self.data.remote_ip))
+                    .await
+            }
+            Ok(_) => Ok(false),
+            Err(err) => Err(err),
+        };
+
+        result.unwrap_or_else(|err| {
+            trc::error!(err
+                .span_id(self.data.session_id)
+                .details("Failed to verify submit access")
+                .caused_by(trc::location!()));
+            false
+        })
+    }
 }
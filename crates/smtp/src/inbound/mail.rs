@@ -247,6 +247,7 @@ impl<T: SessionStream> Session<T> {
                     && !self.authenticated_emails().iter().any(|e| {
                         e == address_lcase || (e.starts_with('@') && address_lcase.ends_with(e))
                     })
+                    && !self.can_submit_as(address_lcase).await
                 {
                     trc::event!(
                         Smtp(SmtpEvent::MailFromUnauthorized),
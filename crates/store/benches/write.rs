@@ -0,0 +1,214 @@
+/*
+ * SPDX-FileCopyrightText: 2020 Stalwart Labs Ltd <hello@stalw.art>
+ *
+ * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
+ */
+
+// Write path benchmarks for every compiled backend, run with:
+//
+//   cargo bench -p store --features bench,sqlite,rocks,foundation
+//
+// BENCH_STORES restricts the backends (e.g. "sqlite,rocksdb") and
+// BENCH_CONCURRENCY sets the number of concurrent writers (default "1,8").
+// `assign_ids_1m` assigns ids in a collection holding a million documents.
+
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc,
+};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use store::{
+    write::{BatchBuilder, MaybeDynamicId, TagValue, ValueClass},
+    Store, Stores,
+};
+use utils::config::Config;
+
+const ACCOUNT_ID: u32 = 0;
+const COLLECTION: u8 = 0;
+const VALUE_SIZE: usize = 64;
+
+// Collection holding a million documents, one in a thousand deleted
+const LARGE_COLLECTION: u8 = 1;
+const LARGE_COLLECTION_SIZE: u32 = 1_000_000;
+
+// Operations per batch
+const SCENARIOS: [(&str, usize); 2] = [("small", 10), ("large", 1000)];
+
+#[derive(Clone, Copy)]
+enum Workload {
+    Values,
+    AssignIds,
+    AssignIdsLarge,
+    Bitmaps,
+}
+
+fn write_benchmarks(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let temp_dir = std::env::temp_dir().join(format!("store_bench_{}", std::process::id()));
+    let stores = runtime.block_on(open_stores(temp_dir.to_str().unwrap()));
+    let concurrency = std::env::var("BENCH_CONCURRENCY")
+        .unwrap_or_else(|_| "1,8".to_string())
+        .split(',')
+        .map(|value| {
+            value
+                .trim()
+                .parse::<usize>()
+                .expect("invalid BENCH_CONCURRENCY")
+        })
+        .collect::<Vec<_>>();
+
+    for (workload, name) in [
+        (Workload::Values, "write_values"),
+        (Workload::AssignIds, "assign_ids"),
+        (Workload::AssignIdsLarge, "assign_ids_1m"),
+        (Workload::Bitmaps, "update_bitmaps"),
+    ] {
+        if matches!(workload, Workload::AssignIdsLarge) {
+            for (_, store) in &stores {
+                runtime.block_on(fill_large_collection(store));
+            }
+        }
+
+        let mut group = c.benchmark_group(name);
+        group.sample_size(10);
+
+        for (store_id, store) in &stores {
+            for (scenario, batch_size) in SCENARIOS {
+                for &writers in &concurrency {
+                    let next_id = Arc::new(AtomicU32::new(0));
+                    group.throughput(Throughput::Elements((batch_size * writers) as u64));
+                    group.bench_with_input(
+                        BenchmarkId::new(format!("{store_id}/{scenario}"), writers),
+                        &writers,
+                        |b, &writers| {
+                            b.to_async(&runtime).iter(|| {
+                                run(
+                                    store.clone(),
+                                    workload,
+                                    batch_size,
+                                    writers,
+                                    next_id.clone(),
+                                )
+                            })
+                        },
+                    );
+                }
+            }
+        }
+
+        group.finish();
+    }
+
+    for (_, store) in stores {
+        runtime.block_on(store.destroy());
+    }
+    let _ = std::fs::remove_dir_all(&temp_dir);
+}
+
+async fn open_stores(path: &str) -> Vec<(String, Store)> {
+    let mut config = String::new();
+    if cfg!(feature = "sqlite") {
+        config.push_str("[store.\"sqlite\"]\ntype = \"sqlite\"\npath = \"{TMP}/sqlite.db\"\n");
+    }
+    if cfg!(feature = "rocks") {
+        config.push_str("[store.\"rocksdb\"]\ntype = \"rocksdb\"\npath = \"{TMP}/rocksdb\"\n");
+    }
+    if cfg!(feature = "foundation") {
+        config.push_str("[store.\"foundationdb\"]\ntype = \"foundationdb\"\n");
+    }
+    let mut config = Config::new(config.replace("{TMP}", path)).unwrap();
+    let filter = std::env::var("BENCH_STORES").ok();
+    let mut stores = Stores::parse(&mut config)
+        .await
+        .stores
+        .into_iter()
+        .filter(|(id, _)| {
+            filter
+                .as_ref()
+                .is_none_or(|filter| filter.split(',').any(|item| item.trim() == id))
+        })
+        .collect::<Vec<_>>();
+    assert!(!stores.is_empty(), "no store backends enabled");
+    stores.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+
+    for (_, store) in &stores {
+        store.destroy().await;
+    }
+
+    stores
+}
+
+async fn run(
+    store: Store,
+    workload: Workload,
+    batch_size: usize,
+    writers: usize,
+    next_id: Arc<AtomicU32>,
+) {
+    let mut handles = Vec::with_capacity(writers);
+
+    for _ in 0..writers {
+        let store = store.clone();
+        let first_id = next_id.fetch_add(batch_size as u32, Ordering::Relaxed);
+
+        handles.push(tokio::spawn(async move {
+            let mut batch = BatchBuilder::new();
+            batch
+                .with_account_id(ACCOUNT_ID)
+                .with_collection(match workload {
+                    Workload::AssignIdsLarge => LARGE_COLLECTION,
+                    _ => COLLECTION,
+                });
+
+            for document_id in first_id..first_id + batch_size as u32 {
+                match workload {
+                    Workload::Values => {
+                        batch
+                            .update_document(document_id)
+                            .set(ValueClass::Property(0), vec![0u8; VALUE_SIZE]);
+                    }
+                    Workload::AssignIds | Workload::AssignIdsLarge => {
+                        batch.create_document();
+                    }
+                    Workload::Bitmaps => {
+                        batch.update_document(document_id).tag(
+                            0u8,
+                            TagValue::Id(MaybeDynamicId::Static(document_id % 16)),
+                            0,
+                        );
+                    }
+                }
+            }
+
+            store.write(batch.build_batch()).await.unwrap();
+        }));
+    }
+
+    for handle in handles {
+        handle.await.unwrap();
+    }
+}
+
+async fn fill_large_collection(store: &Store) {
+    let document_ids = (0..LARGE_COLLECTION_SIZE)
+        .filter(|document_id| document_id % 1000 != 0)
+        .collect::<Vec<_>>();
+
+    for chunk in document_ids.chunks(10_000) {
+        let mut batch = BatchBuilder::new();
+        batch
+            .with_account_id(ACCOUNT_ID)
+            .with_collection(LARGE_COLLECTION);
+        for &document_id in chunk {
+            batch.create_document_with_id(document_id);
+        }
+        store.write(batch.build_batch()).await.unwrap();
+    }
+}
+
+criterion_group!(benches, write_benchmarks);
+criterion_main!(benches);
@@ -4,19 +4,22 @@
  * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
  */
 
-use std::{fmt::Display, io::Write, ops::Range, time::Duration};
+use std::{io::Write, ops::Range, time::Duration};
 
 use azure_core::error::ErrorKind;
+use azure_core::request_options::IfMatchCondition;
 use azure_core::{ExponentialRetryOptions, RetryOptions, StatusCode, TransportOptions};
 use azure_storage::StorageCredentials;
 use azure_storage_blobs::prelude::{ClientBuilder, ContainerClient};
 use futures::stream::StreamExt;
 use std::sync::Arc;
 use utils::{
-    codec::base32_custom::Base32Writer,
+    codec::base32_custom::{Base32Reader, Base32Writer},
     config::{utils::AsKey, Config},
 };
 
+use crate::dispatch::manifest::KeyPages;
+
 pub struct AzureStore {
     client: ContainerClient,
     prefix: Option<String>,
@@ -145,7 +148,7 @@ impl AzureStore {
                 ) {
                     Ok(None)
                 } else {
-                    Err(trc::StoreEvent::AzureError.reason(e))
+                    Err(into_error(e))
                 };
             }
         }
@@ -153,6 +156,37 @@ impl AzureStore {
         Ok(Some(buf))
     }
 
+    pub(crate) async fn blob_exists(&self, key: &[u8]) -> trc::Result<bool> {
+        self.client
+            .blob_client(self.build_key(key))
+            .exists()
+            .await
+            .map_err(into_error)
+    }
+
+    pub(crate) async fn blob_size(&self, key: &[u8]) -> trc::Result<Option<u64>> {
+        match self
+            .client
+            .blob_client(self.build_key(key))
+            .get_properties()
+            .await
+        {
+            Ok(properties) => Ok(Some(properties.blob.properties.content_length)),
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    ErrorKind::HttpResponse {
+                        status: StatusCode::NotFound,
+                        ..
+                    }
+                ) =>
+            {
+                Ok(None)
+            }
+            Err(e) => Err(into_error(e)),
+        }
+    }
+
     pub(crate) async fn put_blob(&self, key: &[u8], data: &[u8]) -> trc::Result<()> {
         let blob_client = self.client.blob_client(self.build_key(key));
 
@@ -170,6 +204,31 @@ impl AzureStore {
         Ok(())
     }
 
+    pub(crate) async fn put_blob_if_absent(&self, key: &[u8], data: &[u8]) -> trc::Result<bool> {
+        let blob_client = self.client.blob_client(self.build_key(key));
+
+        match blob_client
+            .put_block_blob(data.to_vec())
+            .if_match(IfMatchCondition::NotMatch("*".into()))
+            .into_future()
+            .await
+        {
+            Ok(_) => Ok(true),
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    ErrorKind::HttpResponse {
+                        status: StatusCode::Conflict | StatusCode::PreconditionFailed,
+                        ..
+                    }
+                ) =>
+            {
+                Ok(false)
+            }
+            Err(e) => Err(into_error(e)),
+        }
+    }
+
     pub(crate) async fn delete_blob(&self, key: &[u8]) -> trc::Result<bool> {
         let blob_client = self.client.blob_client(self.build_key(key));
 
@@ -183,13 +242,36 @@ impl AzureStore {
             ) {
                 Ok(false)
             } else {
-                Err(trc::StoreEvent::AzureError.reason(e))
+                Err(into_error(e))
             }
         } else {
             Ok(true)
         }
     }
 
+    /// Lists the blobs starting with `prefix`, one page per listing request.
+    pub(crate) fn list_blob_pages(&self, prefix: Vec<u8>) -> KeyPages<'_> {
+        let key_prefix = self.prefix.clone().unwrap_or_default();
+        self.client
+            .list_blobs()
+            .prefix(key_prefix.clone())
+            .into_stream()
+            .map(move |page| {
+                let mut keys = Vec::new();
+                for blob in page.map_err(into_error)?.blobs.blobs() {
+                    if let Some(name) = blob.name.strip_prefix(key_prefix.as_str()) {
+                        let key = Base32Reader::new(name.as_bytes()).collect::<Vec<_>>();
+                        if key.starts_with(&prefix) {
+                            keys.push(key);
+                        }
+                    }
+                }
+
+                Ok(keys)
+            })
+            .boxed()
+    }
+
     fn build_key(&self, key: &[u8]) -> String {
         if let Some(prefix) = &self.prefix {
             let mut writer =
@@ -203,7 +285,22 @@ impl AzureStore {
     }
 }
 
-#[inline(always)]
-fn into_error(err: impl Display) -> trc::Error {
-    trc::StoreEvent::AzureError.reason(err)
+// Server errors are only returned once the client retries are exhausted, and
+// like I/O errors reaching the service they can be retried later
+fn into_error(err: azure_core::Error) -> trc::Error {
+    if matches!(
+        err.kind(),
+        ErrorKind::Io
+            | ErrorKind::HttpResponse {
+                status: StatusCode::InternalServerError
+                    | StatusCode::BadGateway
+                    | StatusCode::ServiceUnavailable
+                    | StatusCode::GatewayTimeout,
+                ..
+            }
+    ) {
+        trc::StoreEvent::BlobUnavailable.reason(err)
+    } else {
+        trc::StoreEvent::AzureError.reason(err)
+    }
 }
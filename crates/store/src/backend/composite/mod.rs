@@ -12,3 +12,34 @@
 pub mod read_replica;
 pub mod sharded_blob;
 pub mod sharded_lookup;
+pub mod tiered_blob;
+
+use std::sync::Arc;
+
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+use crate::{dispatch::blob::acquire_permit, BlobBackend, BlobStore};
+
+/// Blob store a composite blob store is built on. Composite stores are not
+/// limited themselves, each operation takes a permit from the store it is
+/// dispatched to instead, see `storage.blob-concurrency`.
+#[derive(Clone)]
+pub struct BlobMember {
+    pub backend: BlobBackend,
+    pub concurrency: Option<Arc<Semaphore>>,
+}
+
+impl BlobMember {
+    pub(crate) async fn acquire_permit(&self) -> trc::Result<Option<SemaphorePermit<'_>>> {
+        acquire_permit(self.concurrency.as_deref()).await
+    }
+}
+
+impl From<&BlobStore> for BlobMember {
+    fn from(store: &BlobStore) -> Self {
+        Self {
+            backend: store.backend.clone(),
+            concurrency: store.concurrency.clone(),
+        }
+    }
+}
@@ -18,6 +18,7 @@ use roaring::RoaringBitmap;
 use utils::config::{utils::AsKey, Config};
 
 use crate::{
+    dispatch::settings::StoreSettings,
     write::{AssignedIds, Batch, BitmapClass, ValueClass},
     BitmapKey, Deserialize, IterateParams, Key, Store, Stores, ValueKey,
 };
@@ -26,6 +27,7 @@ pub struct SQLReadReplica {
     primary: Store,
     replicas: Vec<Store>,
     last_used_replica: AtomicUsize,
+    pub(crate) settings: Arc<StoreSettings>,
 }
 
 impl SQLReadReplica {
@@ -101,6 +103,7 @@ impl SQLReadReplica {
                 primary,
                 replicas,
                 last_used_replica: AtomicUsize::new(0),
+                settings: Default::default(),
             })
         } else {
             config.new_build_error((&prefix, "replicas"), "No replica stores specified");
@@ -152,6 +155,32 @@ impl SQLReadReplica {
         .await
     }
 
+    pub async fn blob_exists(&self, key: &[u8]) -> trc::Result<bool> {
+        self.run_op(move |store| async move {
+            match store {
+                #[cfg(feature = "postgres")]
+                Store::PostgreSQL(store) => store.blob_exists(key).await,
+                #[cfg(feature = "mysql")]
+                Store::MySQL(store) => store.blob_exists(key).await,
+                _ => panic!("Invalid store type"),
+            }
+        })
+        .await
+    }
+
+    pub async fn blob_size(&self, key: &[u8]) -> trc::Result<Option<u64>> {
+        self.run_op(move |store| async move {
+            match store {
+                #[cfg(feature = "postgres")]
+                Store::PostgreSQL(store) => store.blob_size(key).await,
+                #[cfg(feature = "mysql")]
+                Store::MySQL(store) => store.blob_size(key).await,
+                _ => panic!("Invalid store type"),
+            }
+        })
+        .await
+    }
+
     pub async fn put_blob(&self, key: &[u8], data: &[u8]) -> trc::Result<()> {
         match &self.primary {
             #[cfg(feature = "postgres")]
@@ -162,6 +191,16 @@ impl SQLReadReplica {
         }
     }
 
+    pub async fn put_blob_if_absent(&self, key: &[u8], data: &[u8]) -> trc::Result<bool> {
+        match &self.primary {
+            #[cfg(feature = "postgres")]
+            Store::PostgreSQL(store) => store.put_blob_if_absent(key, data).await,
+            #[cfg(feature = "mysql")]
+            Store::MySQL(store) => store.put_blob_if_absent(key, data).await,
+            _ => panic!("Invalid store type"),
+        }
+    }
+
     pub async fn delete_blob(&self, key: &[u8]) -> trc::Result<bool> {
         match &self.primary {
             #[cfg(feature = "postgres")]
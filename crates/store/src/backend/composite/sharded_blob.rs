@@ -14,8 +14,12 @@ use utils::config::{utils::AsKey, Config};
 
 use crate::{BlobBackend, Store, Stores};
 
+use super::BlobMember;
+
+/// Blob store spreading blobs across several stores by the hash of their key.
+/// Each store keeps its own concurrency limit.
 pub struct ShardedBlob {
-    pub stores: Vec<BlobBackend>,
+    pub stores: Vec<BlobMember>,
 }
 
 impl ShardedBlob {
@@ -29,7 +33,7 @@ impl ShardedBlob {
         let mut blob_stores = Vec::with_capacity(store_ids.len());
         for store_id in store_ids {
             if let Some(store) = stores.blob_stores.get(&store_id) {
-                blob_stores.push(store.backend.clone());
+                blob_stores.push(BlobMember::from(store));
             } else {
                 config.new_build_error(
                     (&prefix, "stores"),
@@ -49,7 +53,7 @@ impl ShardedBlob {
     }
 
     #[inline(always)]
-    fn get_store(&self, key: &[u8]) -> &BlobBackend {
+    fn get_store(&self, key: &[u8]) -> &BlobMember {
         &self.stores[xxhash_rust::xxh3::xxh3_64(key) as usize % self.stores.len()]
     }
 
@@ -59,7 +63,9 @@ impl ShardedBlob {
         read_range: Range<usize>,
     ) -> trc::Result<Option<Vec<u8>>> {
         Box::pin(async move {
-            match self.get_store(key) {
+            let store = self.get_store(key);
+            let _permit = store.acquire_permit().await?;
+            match &store.backend {
                 BlobBackend::Store(store) => match store {
                     #[cfg(feature = "sqlite")]
                     Store::SQLite(store) => store.get_blob(key, read_range).await,
@@ -83,7 +89,75 @@ impl ShardedBlob {
                 BlobBackend::S3(store) => store.get_blob(key, read_range).await,
                 #[cfg(feature = "azure")]
                 BlobBackend::Azure(store) => store.get_blob(key, read_range).await,
-                BlobBackend::Sharded(_) => unimplemented!(),
+                BlobBackend::Sharded(_) | BlobBackend::Tiered(_) => unimplemented!(),
+            }
+        })
+        .await
+    }
+
+    pub async fn blob_exists(&self, key: &[u8]) -> trc::Result<bool> {
+        Box::pin(async move {
+            let store = self.get_store(key);
+            let _permit = store.acquire_permit().await?;
+            match &store.backend {
+                BlobBackend::Store(store) => match store {
+                    #[cfg(feature = "sqlite")]
+                    Store::SQLite(store) => store.blob_exists(key).await,
+                    #[cfg(feature = "foundation")]
+                    Store::FoundationDb(store) => store.blob_exists(key).await,
+                    #[cfg(feature = "postgres")]
+                    Store::PostgreSQL(store) => store.blob_exists(key).await,
+                    #[cfg(feature = "mysql")]
+                    Store::MySQL(store) => store.blob_exists(key).await,
+                    #[cfg(feature = "rocks")]
+                    Store::RocksDb(store) => store.blob_exists(key).await,
+                    #[cfg(all(
+                        feature = "enterprise",
+                        any(feature = "postgres", feature = "mysql")
+                    ))]
+                    Store::SQLReadReplica(store) => store.blob_exists(key).await,
+                    Store::None => Err(trc::StoreEvent::NotConfigured.into()),
+                },
+                BlobBackend::Fs(store) => store.blob_exists(key).await,
+                #[cfg(feature = "s3")]
+                BlobBackend::S3(store) => store.blob_exists(key).await,
+                #[cfg(feature = "azure")]
+                BlobBackend::Azure(store) => store.blob_exists(key).await,
+                BlobBackend::Sharded(_) | BlobBackend::Tiered(_) => unimplemented!(),
+            }
+        })
+        .await
+    }
+
+    pub async fn blob_size(&self, key: &[u8]) -> trc::Result<Option<u64>> {
+        Box::pin(async move {
+            let store = self.get_store(key);
+            let _permit = store.acquire_permit().await?;
+            match &store.backend {
+                BlobBackend::Store(store) => match store {
+                    #[cfg(feature = "sqlite")]
+                    Store::SQLite(store) => store.blob_size(key).await,
+                    #[cfg(feature = "foundation")]
+                    Store::FoundationDb(store) => store.blob_size(key).await,
+                    #[cfg(feature = "postgres")]
+                    Store::PostgreSQL(store) => store.blob_size(key).await,
+                    #[cfg(feature = "mysql")]
+                    Store::MySQL(store) => store.blob_size(key).await,
+                    #[cfg(feature = "rocks")]
+                    Store::RocksDb(store) => store.blob_size(key).await,
+                    #[cfg(all(
+                        feature = "enterprise",
+                        any(feature = "postgres", feature = "mysql")
+                    ))]
+                    Store::SQLReadReplica(store) => store.blob_size(key).await,
+                    Store::None => Err(trc::StoreEvent::NotConfigured.into()),
+                },
+                BlobBackend::Fs(store) => store.blob_size(key).await,
+                #[cfg(feature = "s3")]
+                BlobBackend::S3(store) => store.blob_size(key).await,
+                #[cfg(feature = "azure")]
+                BlobBackend::Azure(store) => store.blob_size(key).await,
+                BlobBackend::Sharded(_) | BlobBackend::Tiered(_) => unimplemented!(),
             }
         })
         .await
@@ -91,7 +165,9 @@ impl ShardedBlob {
 
     pub async fn put_blob(&self, key: &[u8], data: &[u8]) -> trc::Result<()> {
         Box::pin(async move {
-            match self.get_store(key) {
+            let store = self.get_store(key);
+            let _permit = store.acquire_permit().await?;
+            match &store.backend {
                 BlobBackend::Store(store) => match store {
                     #[cfg(feature = "sqlite")]
                     Store::SQLite(store) => store.put_blob(key, data).await,
@@ -115,7 +191,41 @@ impl ShardedBlob {
                 BlobBackend::S3(store) => store.put_blob(key, data).await,
                 #[cfg(feature = "azure")]
                 BlobBackend::Azure(store) => store.put_blob(key, data).await,
-                BlobBackend::Sharded(_) => unimplemented!(),
+                BlobBackend::Sharded(_) | BlobBackend::Tiered(_) => unimplemented!(),
+            }
+        })
+        .await
+    }
+
+    pub async fn put_blob_if_absent(&self, key: &[u8], data: &[u8]) -> trc::Result<bool> {
+        Box::pin(async move {
+            let store = self.get_store(key);
+            let _permit = store.acquire_permit().await?;
+            match &store.backend {
+                BlobBackend::Store(store) => match store {
+                    #[cfg(feature = "sqlite")]
+                    Store::SQLite(store) => store.put_blob_if_absent(key, data).await,
+                    #[cfg(feature = "foundation")]
+                    Store::FoundationDb(store) => store.put_blob_if_absent(key, data).await,
+                    #[cfg(feature = "postgres")]
+                    Store::PostgreSQL(store) => store.put_blob_if_absent(key, data).await,
+                    #[cfg(feature = "mysql")]
+                    Store::MySQL(store) => store.put_blob_if_absent(key, data).await,
+                    #[cfg(feature = "rocks")]
+                    Store::RocksDb(store) => store.put_blob_if_absent(key, data).await,
+                    #[cfg(all(
+                        feature = "enterprise",
+                        any(feature = "postgres", feature = "mysql")
+                    ))]
+                    Store::SQLReadReplica(store) => store.put_blob_if_absent(key, data).await,
+                    Store::None => Err(trc::StoreEvent::NotConfigured.into()),
+                },
+                BlobBackend::Fs(store) => store.put_blob_if_absent(key, data).await,
+                #[cfg(feature = "s3")]
+                BlobBackend::S3(store) => store.put_blob_if_absent(key, data).await,
+                #[cfg(feature = "azure")]
+                BlobBackend::Azure(store) => store.put_blob_if_absent(key, data).await,
+                BlobBackend::Sharded(_) | BlobBackend::Tiered(_) => unimplemented!(),
             }
         })
         .await
@@ -123,7 +233,9 @@ impl ShardedBlob {
 
     pub async fn delete_blob(&self, key: &[u8]) -> trc::Result<bool> {
         Box::pin(async move {
-            match self.get_store(key) {
+            let store = self.get_store(key);
+            let _permit = store.acquire_permit().await?;
+            match &store.backend {
                 BlobBackend::Store(store) => match store {
                     #[cfg(feature = "sqlite")]
                     Store::SQLite(store) => store.delete_blob(key).await,
@@ -147,7 +259,7 @@ impl ShardedBlob {
                 BlobBackend::S3(store) => store.delete_blob(key).await,
                 #[cfg(feature = "azure")]
                 BlobBackend::Azure(store) => store.delete_blob(key).await,
-                BlobBackend::Sharded(_) => unimplemented!(),
+                BlobBackend::Sharded(_) | BlobBackend::Tiered(_) => unimplemented!(),
             }
         })
         .await
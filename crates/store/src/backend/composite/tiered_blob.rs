@@ -0,0 +1,348 @@
+/*
+ * SPDX-FileCopyrightText: 2020 Stalwart Labs Ltd <hello@stalw.art>
+ *
+ * SPDX-License-Identifier: LicenseRef-SEL
+ *
+ * This file is subject to the Stalwart Enterprise License Agreement (SEL) and
+ * is NOT open source software.
+ *
+ */
+
+use std::ops::Range;
+
+use trc::AddContext;
+use utils::config::{utils::AsKey, Config};
+
+use crate::{BlobBackend, Store, Stores};
+
+use super::BlobMember;
+
+/// Blob store made of a fast tier, such as a local filesystem cache, in front of
+/// a slow tier holding every blob. Reads try the fast tier first and fall back to
+/// the slow tier on a miss or an error, writes and deletions go to both tiers.
+///
+/// Blobs are encoded once by the `BlobStore` wrapping this backend, so both tiers
+/// hold identical bytes. Each tier keeps the concurrency limit of its store.
+pub struct TieredBlob {
+    pub fast: BlobMember,
+    pub slow: BlobMember,
+    /// Copy blobs read in full from the slow tier into the fast tier
+    pub populate: bool,
+}
+
+impl TieredBlob {
+    pub fn open(config: &mut Config, prefix: impl AsKey, stores: &Stores) -> Option<Self> {
+        let prefix = prefix.as_key();
+        let mut tiers = Vec::with_capacity(2);
+        for tier in ["fast", "slow"] {
+            let store_id = config.value_require((&prefix, tier))?.to_string();
+            match stores.blob_stores.get(&store_id) {
+                Some(store)
+                    if !matches!(
+                        store.backend,
+                        BlobBackend::Tiered(_) | BlobBackend::Sharded(_)
+                    ) =>
+                {
+                    tiers.push(BlobMember::from(store));
+                }
+                Some(_) => {
+                    config.new_build_error(
+                        (&prefix, tier),
+                        format!("Blob store {store_id} cannot be used as a tier"),
+                    );
+                    return None;
+                }
+                None => {
+                    config.new_build_error(
+                        (&prefix, tier),
+                        format!("Blob store {store_id} not found"),
+                    );
+                    return None;
+                }
+            }
+        }
+        let slow = tiers.pop()?;
+        let fast = tiers.pop()?;
+
+        Some(Self {
+            fast,
+            slow,
+            populate: config
+                .property_or_default((&prefix, "populate"), "true")
+                .unwrap_or(true),
+        })
+    }
+
+    pub async fn get_blob(
+        &self,
+        key: &[u8],
+        read_range: Range<usize>,
+    ) -> trc::Result<Option<Vec<u8>>> {
+        Box::pin(async move {
+            match get_blob(&self.fast, key, read_range.clone()).await {
+                Ok(Some(data)) => return Ok(Some(data)),
+                Ok(None) => {}
+                Err(err) => {
+                    trc::error!(err
+                        .caused_by(trc::location!())
+                        .details("Failed to read blob from fast tier"));
+                }
+            }
+
+            let is_full_read = read_range.start == 0 && read_range.end == usize::MAX;
+            let result = get_blob(&self.slow, key, read_range).await?;
+            if let Some(data) = result.as_ref().filter(|_| self.populate && is_full_read) {
+                if let Err(err) = put_blob(&self.fast, key, data).await {
+                    trc::error!(err
+                        .caused_by(trc::location!())
+                        .details("Failed to populate fast tier"));
+                }
+            }
+
+            Ok(result)
+        })
+        .await
+    }
+
+    pub async fn blob_exists(&self, key: &[u8]) -> trc::Result<bool> {
+        Box::pin(async move {
+            if matches!(blob_exists(&self.fast, key).await, Ok(true)) {
+                Ok(true)
+            } else {
+                blob_exists(&self.slow, key).await
+            }
+        })
+        .await
+    }
+
+    pub async fn blob_size(&self, key: &[u8]) -> trc::Result<Option<u64>> {
+        Box::pin(async move {
+            match blob_size(&self.fast, key).await {
+                Ok(Some(size)) => Ok(Some(size)),
+                _ => blob_size(&self.slow, key).await,
+            }
+        })
+        .await
+    }
+
+    pub async fn put_blob(&self, key: &[u8], data: &[u8]) -> trc::Result<()> {
+        // Write to the slow tier first so that the fast tier never holds a blob
+        // missing from the slow tier
+        Box::pin(async move {
+            put_blob(&self.slow, key, data)
+                .await
+                .caused_by(trc::location!())?;
+            put_blob(&self.fast, key, data)
+                .await
+                .caused_by(trc::location!())
+        })
+        .await
+    }
+
+    pub async fn put_blob_if_absent(&self, key: &[u8], data: &[u8]) -> trc::Result<bool> {
+        // The slow tier holds every blob, so it decides whether the blob exists
+        Box::pin(async move {
+            if put_blob_if_absent(&self.slow, key, data)
+                .await
+                .caused_by(trc::location!())?
+            {
+                put_blob(&self.fast, key, data)
+                    .await
+                    .caused_by(trc::location!())
+                    .map(|_| true)
+            } else {
+                Ok(false)
+            }
+        })
+        .await
+    }
+
+    pub async fn delete_blob(&self, key: &[u8]) -> trc::Result<bool> {
+        // Delete from the fast tier first so that it never serves a blob that
+        // was removed from the slow tier
+        Box::pin(async move {
+            let fast_deleted = delete_blob(&self.fast, key)
+                .await
+                .caused_by(trc::location!())?;
+            let slow_deleted = delete_blob(&self.slow, key)
+                .await
+                .caused_by(trc::location!())?;
+            Ok(fast_deleted || slow_deleted)
+        })
+        .await
+    }
+}
+
+async fn get_blob(
+    member: &BlobMember,
+    key: &[u8],
+    read_range: Range<usize>,
+) -> trc::Result<Option<Vec<u8>>> {
+    let _permit = member.acquire_permit().await?;
+    match &member.backend {
+        BlobBackend::Store(store) => match store {
+            #[cfg(feature = "sqlite")]
+            Store::SQLite(store) => store.get_blob(key, read_range).await,
+            #[cfg(feature = "foundation")]
+            Store::FoundationDb(store) => store.get_blob(key, read_range).await,
+            #[cfg(feature = "postgres")]
+            Store::PostgreSQL(store) => store.get_blob(key, read_range).await,
+            #[cfg(feature = "mysql")]
+            Store::MySQL(store) => store.get_blob(key, read_range).await,
+            #[cfg(feature = "rocks")]
+            Store::RocksDb(store) => store.get_blob(key, read_range).await,
+            #[cfg(all(feature = "enterprise", any(feature = "postgres", feature = "mysql")))]
+            Store::SQLReadReplica(store) => store.get_blob(key, read_range).await,
+            Store::None => Err(trc::StoreEvent::NotConfigured.into()),
+        },
+        BlobBackend::Fs(store) => store.get_blob(key, read_range).await,
+        #[cfg(feature = "s3")]
+        BlobBackend::S3(store) => store.get_blob(key, read_range).await,
+        #[cfg(feature = "azure")]
+        BlobBackend::Azure(store) => store.get_blob(key, read_range).await,
+        BlobBackend::Sharded(_) | BlobBackend::Tiered(_) => Err(composite_tier()),
+    }
+}
+
+async fn blob_exists(member: &BlobMember, key: &[u8]) -> trc::Result<bool> {
+    let _permit = member.acquire_permit().await?;
+    match &member.backend {
+        BlobBackend::Store(store) => match store {
+            #[cfg(feature = "sqlite")]
+            Store::SQLite(store) => store.blob_exists(key).await,
+            #[cfg(feature = "foundation")]
+            Store::FoundationDb(store) => store.blob_exists(key).await,
+            #[cfg(feature = "postgres")]
+            Store::PostgreSQL(store) => store.blob_exists(key).await,
+            #[cfg(feature = "mysql")]
+            Store::MySQL(store) => store.blob_exists(key).await,
+            #[cfg(feature = "rocks")]
+            Store::RocksDb(store) => store.blob_exists(key).await,
+            #[cfg(all(feature = "enterprise", any(feature = "postgres", feature = "mysql")))]
+            Store::SQLReadReplica(store) => store.blob_exists(key).await,
+            Store::None => Err(trc::StoreEvent::NotConfigured.into()),
+        },
+        BlobBackend::Fs(store) => store.blob_exists(key).await,
+        #[cfg(feature = "s3")]
+        BlobBackend::S3(store) => store.blob_exists(key).await,
+        #[cfg(feature = "azure")]
+        BlobBackend::Azure(store) => store.blob_exists(key).await,
+        BlobBackend::Sharded(_) | BlobBackend::Tiered(_) => Err(composite_tier()),
+    }
+}
+
+async fn blob_size(member: &BlobMember, key: &[u8]) -> trc::Result<Option<u64>> {
+    let _permit = member.acquire_permit().await?;
+    match &member.backend {
+        BlobBackend::Store(store) => match store {
+            #[cfg(feature = "sqlite")]
+            Store::SQLite(store) => store.blob_size(key).await,
+            #[cfg(feature = "foundation")]
+            Store::FoundationDb(store) => store.blob_size(key).await,
+            #[cfg(feature = "postgres")]
+            Store::PostgreSQL(store) => store.blob_size(key).await,
+            #[cfg(feature = "mysql")]
+            Store::MySQL(store) => store.blob_size(key).await,
+            #[cfg(feature = "rocks")]
+            Store::RocksDb(store) => store.blob_size(key).await,
+            #[cfg(all(feature = "enterprise", any(feature = "postgres", feature = "mysql")))]
+            Store::SQLReadReplica(store) => store.blob_size(key).await,
+            Store::None => Err(trc::StoreEvent::NotConfigured.into()),
+        },
+        BlobBackend::Fs(store) => store.blob_size(key).await,
+        #[cfg(feature = "s3")]
+        BlobBackend::S3(store) => store.blob_size(key).await,
+        #[cfg(feature = "azure")]
+        BlobBackend::Azure(store) => store.blob_size(key).await,
+        BlobBackend::Sharded(_) | BlobBackend::Tiered(_) => Err(composite_tier()),
+    }
+}
+
+async fn put_blob(member: &BlobMember, key: &[u8], data: &[u8]) -> trc::Result<()> {
+    let _permit = member.acquire_permit().await?;
+    match &member.backend {
+        BlobBackend::Store(store) => match store {
+            #[cfg(feature = "sqlite")]
+            Store::SQLite(store) => store.put_blob(key, data).await,
+            #[cfg(feature = "foundation")]
+            Store::FoundationDb(store) => store.put_blob(key, data).await,
+            #[cfg(feature = "postgres")]
+            Store::PostgreSQL(store) => store.put_blob(key, data).await,
+            #[cfg(feature = "mysql")]
+            Store::MySQL(store) => store.put_blob(key, data).await,
+            #[cfg(feature = "rocks")]
+            Store::RocksDb(store) => store.put_blob(key, data).await,
+            #[cfg(all(feature = "enterprise", any(feature = "postgres", feature = "mysql")))]
+            Store::SQLReadReplica(store) => store.put_blob(key, data).await,
+            Store::None => Err(trc::StoreEvent::NotConfigured.into()),
+        },
+        BlobBackend::Fs(store) => store.put_blob(key, data).await,
+        #[cfg(feature = "s3")]
+        BlobBackend::S3(store) => store.put_blob(key, data).await,
+        #[cfg(feature = "azure")]
+        BlobBackend::Azure(store) => store.put_blob(key, data).await,
+        BlobBackend::Sharded(_) | BlobBackend::Tiered(_) => Err(composite_tier()),
+    }
+}
+
+async fn put_blob_if_absent(member: &BlobMember, key: &[u8], data: &[u8]) -> trc::Result<bool> {
+    let _permit = member.acquire_permit().await?;
+    match &member.backend {
+        BlobBackend::Store(store) => match store {
+            #[cfg(feature = "sqlite")]
+            Store::SQLite(store) => store.put_blob_if_absent(key, data).await,
+            #[cfg(feature = "foundation")]
+            Store::FoundationDb(store) => store.put_blob_if_absent(key, data).await,
+            #[cfg(feature = "postgres")]
+            Store::PostgreSQL(store) => store.put_blob_if_absent(key, data).await,
+            #[cfg(feature = "mysql")]
+            Store::MySQL(store) => store.put_blob_if_absent(key, data).await,
+            #[cfg(feature = "rocks")]
+            Store::RocksDb(store) => store.put_blob_if_absent(key, data).await,
+            #[cfg(all(feature = "enterprise", any(feature = "postgres", feature = "mysql")))]
+            Store::SQLReadReplica(store) => store.put_blob_if_absent(key, data).await,
+            Store::None => Err(trc::StoreEvent::NotConfigured.into()),
+        },
+        BlobBackend::Fs(store) => store.put_blob_if_absent(key, data).await,
+        #[cfg(feature = "s3")]
+        BlobBackend::S3(store) => store.put_blob_if_absent(key, data).await,
+        #[cfg(feature = "azure")]
+        BlobBackend::Azure(store) => store.put_blob_if_absent(key, data).await,
+        BlobBackend::Sharded(_) | BlobBackend::Tiered(_) => Err(composite_tier()),
+    }
+}
+
+async fn delete_blob(member: &BlobMember, key: &[u8]) -> trc::Result<bool> {
+    let _permit = member.acquire_permit().await?;
+    match &member.backend {
+        BlobBackend::Store(store) => match store {
+            #[cfg(feature = "sqlite")]
+            Store::SQLite(store) => store.delete_blob(key).await,
+            #[cfg(feature = "foundation")]
+            Store::FoundationDb(store) => store.delete_blob(key).await,
+            #[cfg(feature = "postgres")]
+            Store::PostgreSQL(store) => store.delete_blob(key).await,
+            #[cfg(feature = "mysql")]
+            Store::MySQL(store) => store.delete_blob(key).await,
+            #[cfg(feature = "rocks")]
+            Store::RocksDb(store) => store.delete_blob(key).await,
+            #[cfg(all(feature = "enterprise", any(feature = "postgres", feature = "mysql")))]
+            Store::SQLReadReplica(store) => store.delete_blob(key).await,
+            Store::None => Err(trc::StoreEvent::NotConfigured.into()),
+        },
+        BlobBackend::Fs(store) => store.delete_blob(key).await,
+        #[cfg(feature = "s3")]
+        BlobBackend::S3(store) => store.delete_blob(key).await,
+        #[cfg(feature = "azure")]
+        BlobBackend::Azure(store) => store.delete_blob(key).await,
+        BlobBackend::Sharded(_) | BlobBackend::Tiered(_) => Err(composite_tier()),
+    }
+}
+
+// Rejected by `TieredBlob::open`
+fn composite_tier() -> trc::Error {
+    trc::StoreEvent::NotSupported
+        .into_err()
+        .details("Composite blob stores cannot be used as a tier")
+        .caused_by(trc::location!())
+}
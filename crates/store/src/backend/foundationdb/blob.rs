@@ -6,7 +6,7 @@
 
 use std::ops::Range;
 
-use foundationdb::{options::StreamingMode, KeySelector, RangeOption};
+use foundationdb::{options::StreamingMode, KeySelector, RangeOption, Transaction};
 use futures::TryStreamExt;
 use utils::BLOB_HASH_LEN;
 
@@ -24,21 +24,16 @@ impl FdbStore {
         let bytes_start = range.start % MAX_VALUE_SIZE;
         let block_end = (range.end / MAX_VALUE_SIZE) + 1;
 
-        let begin = KeySerializer::new(key.len() + 3)
-            .write(SUBSPACE_BLOBS)
-            .write(key)
-            .write(block_start as u16)
-            .finalize();
-        let end = KeySerializer::new(key.len() + 3)
-            .write(SUBSPACE_BLOBS)
-            .write(key)
-            .write(block_end as u16)
-            .finalize();
+        let begin = self.blob_key(key, block_start as u16);
+        let end = self.blob_key(key, block_end as u16);
         let key_len = begin.len();
         let trx = self.read_trx().await?;
+        if block_start > 0 && !self.has_first_chunk(&trx, key).await? {
+            return Ok(None);
+        }
         let mut values = trx.get_ranges_keyvalues(
             RangeOption {
-                begin: KeySelector::first_greater_or_equal(begin),
+                begin: KeySelector::first_greater_or_equal(&begin),
                 end: KeySelector::first_greater_or_equal(end),
                 mode: StreamingMode::WantAll,
                 reverse: false,
@@ -68,6 +63,10 @@ impl FdbStore {
                         break 'outer;
                     }
                 } else {
+                    // Blobs without a first chunk were not fully written
+                    if block_start == 0 && key != begin.as_slice() {
+                        return Ok(None);
+                    }
                     let blob_size = if blob_range <= (5 * (1 << 20)) {
                         blob_range
                     } else if value.len() == MAX_VALUE_SIZE {
@@ -92,39 +91,110 @@ impl FdbStore {
         Ok(blob_data)
     }
 
+    pub(crate) async fn blob_exists(&self, key: &[u8]) -> trc::Result<bool> {
+        self.has_first_chunk(&self.read_trx().await?, key).await
+    }
+
+    /// Blobs exist once their first chunk does, which `write_blob` writes last.
+    async fn has_first_chunk(&self, trx: &Transaction, key: &[u8]) -> trc::Result<bool> {
+        let begin = self.blob_key(key, 0);
+
+        // Only the key of the first chunk is fetched
+        let first_key = trx
+            .get_key(&KeySelector::first_greater_or_equal(&begin), true)
+            .await
+            .map_err(into_error)?;
+
+        Ok(first_key.len() == begin.len() && first_key.starts_with(&begin))
+    }
+
+    pub(crate) async fn blob_size(&self, key: &[u8]) -> trc::Result<Option<u64>> {
+        let end = self.blob_key(key, u16::MAX);
+
+        // All chunks but the last one are full, so only the last one is read
+        let trx = self.read_trx().await?;
+        let last_key = trx
+            .get_key(&KeySelector::last_less_than(&end), true)
+            .await
+            .map_err(into_error)?;
+        if last_key.len() != end.len()
+            || !last_key.starts_with(&end[..end.len() - 2])
+            || !self.has_first_chunk(&trx, key).await?
+        {
+            return Ok(None);
+        }
+        let last_chunk = u16::from_be_bytes([last_key[end.len() - 2], last_key[end.len() - 1]]);
+        let last_len = trx
+            .get(&last_key, true)
+            .await
+            .map_err(into_error)?
+            .map_or(0, |value| value.len());
+
+        Ok(Some(
+            (last_chunk as usize * MAX_VALUE_SIZE + last_len) as u64,
+        ))
+    }
+
     pub(crate) async fn put_blob(&self, key: &[u8], data: &[u8]) -> trc::Result<()> {
+        self.write_blob(key, data, false).await.map(|_| ())
+    }
+
+    pub(crate) async fn put_blob_if_absent(&self, key: &[u8], data: &[u8]) -> trc::Result<bool> {
+        self.write_blob(key, data, true).await
+    }
+
+    /// Blobs larger than a transaction are written in several, so the first
+    /// chunk, which marks the blob as existing, is committed last on its own.
+    /// Interrupted writes leave no readable blob behind and conditional writes
+    /// only skip blobs that were fully written. Concurrent conditional writes
+    /// of a key are expected to carry the same contents, as blob keys are
+    /// hashes of them, since chunks other than the first are not conditional.
+    async fn write_blob(&self, key: &[u8], data: &[u8], if_absent: bool) -> trc::Result<bool> {
         const N_CHUNKS: usize = (1 << 5) - 1;
-        let last_chunk = std::cmp::max(
-            (data.len() / MAX_VALUE_SIZE)
-                + if data.len() % MAX_VALUE_SIZE > 0 {
-                    1
-                } else {
-                    0
-                },
-            1,
-        ) - 1;
-        let mut trx = self.db.create_trx().map_err(into_error)?;
-
-        for (chunk_pos, chunk_bytes) in data.chunks(MAX_VALUE_SIZE).enumerate() {
-            trx.set(
-                &KeySerializer::new(key.len() + 3)
-                    .write(SUBSPACE_BLOBS)
-                    .write(key)
-                    .write(chunk_pos as u16)
-                    .finalize(),
-                chunk_bytes,
-            );
-            if chunk_pos == last_chunk || (chunk_pos > 0 && chunk_pos % N_CHUNKS == 0) {
-                self.commit(trx, false).await?;
-                if chunk_pos < last_chunk {
-                    trx = self.db.create_trx().map_err(into_error)?;
-                } else {
+        let (first_chunk, other_chunks) = data.split_at(data.len().min(MAX_VALUE_SIZE));
+
+        if if_absent
+            && !other_chunks.is_empty()
+            && self.has_first_chunk(&self.read_trx().await?, key).await?
+        {
+            return Ok(false);
+        }
+
+        let other_chunks = other_chunks.chunks(MAX_VALUE_SIZE).collect::<Vec<_>>();
+        for (batch_num, batch) in other_chunks.chunks(N_CHUNKS).enumerate() {
+            loop {
+                let trx = self.db.create_trx().map_err(into_error)?;
+                for (pos, chunk_bytes) in batch.iter().enumerate() {
+                    let chunk_pos = 1 + (batch_num * N_CHUNKS) + pos;
+                    trx.set(&self.blob_key(key, chunk_pos as u16), chunk_bytes);
+                }
+                if self.commit(trx, true, true).await? {
                     break;
                 }
             }
         }
 
-        Ok(())
+        loop {
+            let trx = self.db.create_trx().map_err(into_error)?;
+
+            // Reading the first chunk in the transaction that writes it makes
+            // concurrent writers of the same blob conflict, the retry then
+            // finds the chunk written by the winner
+            if if_absent
+                && trx
+                    .get(&self.blob_key(key, 0), false)
+                    .await
+                    .map_err(into_error)?
+                    .is_some()
+            {
+                return Ok(false);
+            }
+
+            trx.set(&self.blob_key(key, 0), first_chunk);
+            if self.commit(trx, if_absent, true).await? {
+                return Ok(true);
+            }
+        }
     }
 
     pub(crate) async fn delete_blob(&self, key: &[u8]) -> trc::Result<bool> {
@@ -133,19 +203,18 @@ impl FdbStore {
         }
 
         let trx = self.db.create_trx().map_err(into_error)?;
-        trx.clear_range(
-            &KeySerializer::new(key.len() + 3)
-                .write(SUBSPACE_BLOBS)
-                .write(key)
-                .write(0u16)
-                .finalize(),
-            &KeySerializer::new(key.len() + 3)
+        trx.clear_range(&self.blob_key(key, 0), &self.blob_key(key, u16::MAX));
+
+        self.commit(trx, false, true).await
+    }
+
+    fn blob_key(&self, key: &[u8], chunk: u16) -> Vec<u8> {
+        self.prefix_key(
+            KeySerializer::new(key.len() + 3)
                 .write(SUBSPACE_BLOBS)
                 .write(key)
-                .write(u16::MAX)
+                .write(chunk)
                 .finalize(),
-        );
-
-        self.commit(trx, false).await
+        )
     }
 }
@@ -9,7 +9,7 @@ use std::time::Duration;
 use foundationdb::{api, options::DatabaseOption, Database};
 use utils::config::{utils::AsKey, Config};
 
-use super::FdbStore;
+use super::{retry::FdbRetryPolicy, FdbStore};
 
 impl FdbStore {
     pub async fn open(config: &mut Config, prefix: impl AsKey) -> Option<Self> {
@@ -102,10 +102,36 @@ impl FdbStore {
                 .ok()?;
         }
 
+        // Prefixes are terminated so that no prefix is the start of another one
+        let key_prefix = match config.value((&prefix, "key-prefix")) {
+            Some(key_prefix) if key_prefix.contains('\0') => {
+                config.new_build_error(
+                    (&prefix, "key-prefix"),
+                    "Key prefix cannot contain NUL characters",
+                );
+                return None;
+            }
+            Some(key_prefix) if !key_prefix.is_empty() => {
+                let mut key_prefix = key_prefix.as_bytes().to_vec();
+                key_prefix.push(0);
+                key_prefix
+            }
+            _ => Vec::new(),
+        };
+
         Some(Self {
             guard,
             db,
             version: Default::default(),
+            retry_unknown_result: config
+                .property_or_default((&prefix, "transaction.retry-unknown-result"), "idempotent")
+                .unwrap_or_default(),
+            retry_policy: FdbRetryPolicy::parse(config, &prefix),
+            split_batches: config
+                .property_or_default((&prefix, "transaction.split-large-batches"), "false")
+                .unwrap_or_default(),
+            key_prefix,
+            settings: Default::default(),
         })
     }
 }
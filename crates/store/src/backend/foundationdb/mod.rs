@@ -4,16 +4,25 @@
  * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
  */
 
-use std::time::{Duration, Instant};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use foundationdb::{api::NetworkAutoStop, Database, FdbError, Transaction};
 
+use crate::{Key, WITH_SUBSPACE, dispatch::settings::StoreSettings};
+
 pub mod blob;
 pub mod main;
 pub mod read;
+pub mod retry;
 pub mod write;
 
 const MAX_VALUE_SIZE: usize = 100000;
+// Batches estimated above this size are split when `split_batches` is enabled,
+// leaving headroom below FoundationDB's 10MB transaction limit
+const MAX_TRANSACTION_SIZE: usize = 5_000_000;
 pub const TRANSACTION_EXPIRY: Duration = Duration::from_secs(1);
 pub const TRANSACTION_TIMEOUT: Duration = Duration::from_secs(4);
 
@@ -22,6 +31,13 @@ pub struct FdbStore {
     db: Database,
     guard: NetworkAutoStop,
     version: parking_lot::Mutex<ReadVersion>,
+    retry_unknown_result: retry::UnknownResultPolicy,
+    retry_policy: retry::FdbRetryPolicy,
+    // Commit large batches as several transactions, see `write::split_batch`
+    split_batches: bool,
+    // Prepended to every key so that several deployments can share a cluster
+    key_prefix: Vec<u8>,
+    pub(crate) settings: Arc<StoreSettings>,
 }
 
 pub(crate) struct TimedTransaction {
@@ -75,6 +91,30 @@ impl TimedTransaction {
     }
 }
 
+impl FdbStore {
+    /// Serializes a key with its subspace, within the key prefix of the store.
+    pub(crate) fn key(&self, key: &impl Key) -> Vec<u8> {
+        self.prefix_key(key.serialize(WITH_SUBSPACE))
+    }
+
+    /// Prepends the key prefix to a key starting with its subspace.
+    pub(crate) fn prefix_key(&self, key: Vec<u8>) -> Vec<u8> {
+        if self.key_prefix.is_empty() {
+            key
+        } else {
+            let mut prefixed = Vec::with_capacity(self.key_prefix.len() + key.len());
+            prefixed.extend_from_slice(&self.key_prefix);
+            prefixed.extend_from_slice(&key);
+            prefixed
+        }
+    }
+
+    /// Strips the key prefix and the subspace from a key read from the database.
+    pub(crate) fn unprefix_key<'x>(&self, key: &'x [u8]) -> &'x [u8] {
+        key.get(self.key_prefix.len() + 1..).unwrap_or_default()
+    }
+}
+
 #[inline(always)]
 fn into_error(error: FdbError) -> trc::Error {
     trc::StoreEvent::FoundationdbError
@@ -4,12 +4,17 @@
  * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
  */
 
+use std::sync::Arc;
+
 use foundationdb::{
     future::FdbSlice,
     options::{self, StreamingMode},
-    KeySelector, RangeOption, Transaction,
+    FdbError, KeySelector, RangeOption, Transaction,
+};
+use futures::{
+    future::{self, BoxFuture},
+    FutureExt, TryStreamExt,
 };
-use futures::TryStreamExt;
 use roaring::RoaringBitmap;
 
 use crate::{
@@ -18,11 +23,20 @@ use crate::{
         key::{DeserializeBigEndian, KeySerializer},
         BitmapClass, ValueClass,
     },
-    BitmapKey, Deserialize, IterateParams, Key, ValueKey, U32_LEN, WITH_SUBSPACE,
+    BitmapKey, Deserialize, IterateParams, Key, ValueKey, U32_LEN,
 };
 
 use super::{into_error, FdbStore, ReadVersion, TimedTransaction, MAX_VALUE_SIZE};
 
+/// Reads pinned to the version the snapshot was taken at. FoundationDB only
+/// keeps versions for a few seconds, reads issued after that fail with
+/// `transaction_too_old` rather than returning newer data. Backups can't be
+/// taken from these, see `Store::long_lived_snapshot`.
+pub struct FdbSnapshot {
+    store: Arc<FdbStore>,
+    version: i64,
+}
+
 #[allow(dead_code)]
 pub(crate) enum ChunkedValue {
     Single(FdbSlice),
@@ -35,7 +49,7 @@ impl FdbStore {
     where
         U: Deserialize,
     {
-        let key = key.serialize(WITH_SUBSPACE);
+        let key = self.key(&key);
         let trx = self.read_trx().await?;
 
         match read_chunked_value(&key, &trx, true).await? {
@@ -47,33 +61,9 @@ impl FdbStore {
 
     pub(crate) async fn get_bitmap(
         &self,
-        mut key: BitmapKey<BitmapClass<u32>>,
+        key: BitmapKey<BitmapClass<u32>>,
     ) -> trc::Result<Option<RoaringBitmap>> {
-        let mut bm = RoaringBitmap::new();
-        let begin = key.serialize(WITH_SUBSPACE);
-        key.document_id = u32::MAX;
-        let end = key.serialize(WITH_SUBSPACE);
-        let key_len = begin.len();
-        let trx = self.read_trx().await?;
-        let mut values = trx.get_ranges_keyvalues(
-            RangeOption {
-                begin: KeySelector::first_greater_or_equal(begin),
-                end: KeySelector::first_greater_or_equal(end),
-                mode: StreamingMode::WantAll,
-                reverse: false,
-                ..RangeOption::default()
-            },
-            true,
-        );
-
-        while let Some(value) = values.try_next().await.map_err(into_error)? {
-            let key = value.key();
-            if key.len() == key_len {
-                bm.insert(key.deserialize_be_u32(key.len() - U32_LEN)?);
-            }
-        }
-
-        Ok(if !bm.is_empty() { Some(bm) } else { None })
+        read_bitmap(self, key, &self.read_trx().await?).await
     }
 
     pub(crate) async fn iterate<T: Key>(
@@ -81,8 +71,8 @@ impl FdbStore {
         params: IterateParams<T>,
         mut cb: impl for<'x> FnMut(&'x [u8], &'x [u8]) -> trc::Result<bool> + Sync + Send,
     ) -> trc::Result<()> {
-        let mut begin = params.begin.serialize(WITH_SUBSPACE);
-        let end = params.end.serialize(WITH_SUBSPACE);
+        let mut begin = self.key(&params.begin);
+        let end = self.key(&params.end);
 
         if !params.first {
             let mut begin_selector = KeySelector::first_greater_or_equal(&begin);
@@ -108,7 +98,7 @@ impl FdbStore {
 
                         for value in values.iter() {
                             last_key = value.key();
-                            if !cb(last_key.get(1..).unwrap_or_default(), value.value())? {
+                            if !cb(self.unprefix_key(last_key), value.value())? {
                                 return Ok(());
                             }
                         }
@@ -141,7 +131,7 @@ impl FdbStore {
             );
 
             if let Some(value) = values.try_next().await.map_err(into_error)? {
-                cb(value.key().get(1..).unwrap_or_default(), value.value())?;
+                cb(self.unprefix_key(value.key()), value.value())?;
             }
         }
 
@@ -152,7 +142,7 @@ impl FdbStore {
         &self,
         key: impl Into<ValueKey<ValueClass<u32>>> + Sync + Send,
     ) -> trc::Result<i64> {
-        let key = key.into().serialize(WITH_SUBSPACE);
+        let key = self.key(&key.into());
         if let Some(bytes) = self
             .read_trx()
             .await?
@@ -166,6 +156,33 @@ impl FdbStore {
         }
     }
 
+    /// Registers a watch on a counter, returning a future that resolves once it
+    /// no longer holds `value`. The future resolves right away when the counter
+    /// changed before the watch was registered.
+    pub(crate) async fn watch_counter(
+        &self,
+        key: impl Into<ValueKey<ValueClass<u32>>> + Sync + Send,
+        value: i64,
+    ) -> trc::Result<BoxFuture<'static, trc::Result<()>>> {
+        let key = self.key(&key.into());
+        let trx = self.db.create_trx().map_err(into_error)?;
+        let current = match trx.get(&key, false).await.map_err(into_error)? {
+            Some(bytes) => deserialize_i64_le(&key, &bytes)?,
+            None => 0,
+        };
+        if current != value {
+            return Ok(future::ready(Ok(())).boxed());
+        }
+
+        // Watches are only registered once the transaction setting them commits
+        let watch = trx.watch(&key);
+        trx.commit()
+            .await
+            .map_err(|err| into_error(FdbError::from(err)))?;
+
+        Ok(watch.map(|result| result.map_err(into_error)).boxed())
+    }
+
     pub(crate) async fn read_trx(&self) -> trc::Result<Transaction> {
         let (is_expired, mut read_version) = {
             let version = self.version.lock();
@@ -189,6 +206,130 @@ impl FdbStore {
             .map_err(into_error)
             .map(TimedTransaction::new)
     }
+
+    pub(crate) async fn snapshot(self: &Arc<Self>) -> trc::Result<FdbSnapshot> {
+        let version = self
+            .db
+            .create_trx()
+            .map_err(into_error)?
+            .get_read_version()
+            .await
+            .map_err(into_error)?;
+
+        Ok(FdbSnapshot {
+            store: self.clone(),
+            version,
+        })
+    }
+}
+
+impl FdbSnapshot {
+    pub(crate) async fn get_value<U>(&self, key: impl Key) -> trc::Result<Option<U>>
+    where
+        U: Deserialize,
+    {
+        let key = self.store.key(&key);
+        let trx = self.read_trx()?;
+
+        match read_chunked_value(&key, &trx, true).await? {
+            ChunkedValue::Single(bytes) => U::deserialize(&bytes).map(Some),
+            ChunkedValue::Chunked { bytes, .. } => U::deserialize(&bytes).map(Some),
+            ChunkedValue::None => Ok(None),
+        }
+    }
+
+    pub(crate) async fn get_bitmap(
+        &self,
+        key: BitmapKey<BitmapClass<u32>>,
+    ) -> trc::Result<Option<RoaringBitmap>> {
+        read_bitmap(&self.store, key, &self.read_trx()?).await
+    }
+
+    pub(crate) async fn iterate<T: Key>(
+        &self,
+        params: IterateParams<T>,
+        mut cb: impl for<'x> FnMut(&'x [u8], &'x [u8]) -> trc::Result<bool> + Sync + Send,
+    ) -> trc::Result<()> {
+        // Ranges are read in a single transaction, as starting a new one
+        // would not move the read version forward anyway
+        let begin = self.store.key(&params.begin);
+        let end = self.store.key(&params.end);
+        let trx = self.read_trx()?;
+        let mut values = trx.get_ranges_keyvalues(
+            RangeOption {
+                begin: KeySelector::first_greater_or_equal(&begin),
+                end: KeySelector::first_greater_than(&end),
+                mode: if params.first {
+                    StreamingMode::Small
+                } else {
+                    StreamingMode::WantAll
+                },
+                reverse: !params.ascending,
+                ..Default::default()
+            },
+            true,
+        );
+
+        while let Some(value) = values.try_next().await.map_err(into_error)? {
+            if !cb(self.store.unprefix_key(value.key()), value.value())? || params.first {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub(crate) async fn get_counter(
+        &self,
+        key: impl Into<ValueKey<ValueClass<u32>>> + Sync + Send,
+    ) -> trc::Result<i64> {
+        let key = self.store.key(&key.into());
+        if let Some(bytes) = self.read_trx()?.get(&key, true).await.map_err(into_error)? {
+            deserialize_i64_le(&key, &bytes)
+        } else {
+            Ok(0)
+        }
+    }
+
+    fn read_trx(&self) -> trc::Result<Transaction> {
+        let trx = self.store.db.create_trx().map_err(into_error)?;
+        trx.set_read_version(self.version);
+        // Snapshots never write, so there are no writes for reads to observe
+        trx.set_option(options::TransactionOption::ReadYourWritesDisable)
+            .map_err(into_error)?;
+        Ok(trx)
+    }
+}
+
+async fn read_bitmap(
+    store: &FdbStore,
+    mut key: BitmapKey<BitmapClass<u32>>,
+    trx: &Transaction,
+) -> trc::Result<Option<RoaringBitmap>> {
+    let mut bm = RoaringBitmap::new();
+    let begin = store.key(&key);
+    key.document_id = u32::MAX;
+    let end = store.key(&key);
+    let key_len = begin.len();
+    let mut values = trx.get_ranges_keyvalues(
+        RangeOption {
+            begin: KeySelector::first_greater_or_equal(begin),
+            end: KeySelector::first_greater_or_equal(end),
+            mode: StreamingMode::WantAll,
+            reverse: false,
+            ..RangeOption::default()
+        },
+        true,
+    );
+
+    while let Some(value) = values.try_next().await.map_err(into_error)? {
+        let key = value.key();
+        if key.len() == key_len {
+            bm.insert(key.deserialize_be_u32(key.len() - U32_LEN)?);
+        }
+    }
+
+    Ok(if !bm.is_empty() { Some(bm) } else { None })
 }
 
 pub(crate) async fn read_chunked_value(
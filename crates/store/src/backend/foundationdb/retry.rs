@@ -0,0 +1,248 @@
+/*
+ * SPDX-FileCopyrightText: 2020 Stalwart Labs Ltd <hello@stalw.art>
+ *
+ * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
+ */
+
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+use utils::config::{
+    utils::{AsKey, ParseValue},
+    Config,
+};
+
+use crate::write::{MAX_COMMIT_ATTEMPTS, MAX_COMMIT_TIME};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// The transaction was not committed and can be safely retried
+    Retryable,
+    /// The transaction may or may not have been committed
+    MaybeCommitted,
+    /// Retrying would fail again
+    Fatal,
+}
+
+/// Whether to retry transactions that failed with `commit_unknown_result`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UnknownResultPolicy {
+    Always,
+    #[default]
+    Idempotent,
+    Never,
+}
+
+/// Limits on retrying transactions that failed with a retryable error
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FdbRetryPolicy {
+    pub max_attempts: u32,
+    pub max_time: Duration,
+    /// Shortest wait between attempts, waits are randomized between this
+    /// value and six times it
+    pub base_backoff: Duration,
+}
+
+impl Default for FdbRetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: MAX_COMMIT_ATTEMPTS,
+            max_time: MAX_COMMIT_TIME,
+            base_backoff: Duration::from_millis(50),
+        }
+    }
+}
+
+impl FdbRetryPolicy {
+    pub fn parse(config: &mut Config, prefix: impl AsKey) -> Self {
+        let prefix = prefix.as_key();
+        let default = Self::default();
+
+        Self {
+            max_attempts: config
+                .property((&prefix, "transaction.retry.max-attempts"))
+                .unwrap_or(default.max_attempts),
+            max_time: config
+                .property((&prefix, "transaction.retry.max-time"))
+                .unwrap_or(default.max_time),
+            base_backoff: config
+                .property((&prefix, "transaction.retry.backoff"))
+                .unwrap_or(default.base_backoff),
+        }
+    }
+
+    pub fn can_retry(&self, attempts: u32, start: Instant) -> bool {
+        attempts < self.max_attempts && start.elapsed() < self.max_time
+    }
+
+    pub fn backoff(&self) -> Duration {
+        let base_backoff = self.base_backoff.as_millis() as u64;
+        Duration::from_millis(rand::rng().random_range(base_backoff..=base_backoff * 6))
+    }
+}
+
+impl ErrorClass {
+    pub fn from_code(code: i32) -> Self {
+        match code {
+            // transaction_too_old, future_version, not_committed, process_behind,
+            // database_locked, commit_proxy_memory_limit_exceeded,
+            // batch_transaction_throttled, grv_proxy_memory_limit_exceeded, tag_throttled
+            1007 | 1009 | 1020 | 1037 | 1038 | 1042 | 1051 | 1078 | 1213 => ErrorClass::Retryable,
+            // commit_unknown_result, cluster_version_changed
+            1021 | 1039 => ErrorClass::MaybeCommitted,
+            // transaction_too_large, key_too_large, value_too_large, timeouts,
+            // cancellations and any other error
+            _ => ErrorClass::Fatal,
+        }
+    }
+
+    pub fn should_retry(self, policy: UnknownResultPolicy, is_idempotent: bool) -> bool {
+        match self {
+            ErrorClass::Retryable => true,
+            ErrorClass::MaybeCommitted => match policy {
+                UnknownResultPolicy::Always => true,
+                UnknownResultPolicy::Idempotent => is_idempotent,
+                UnknownResultPolicy::Never => false,
+            },
+            ErrorClass::Fatal => false,
+        }
+    }
+}
+
+impl ParseValue for UnknownResultPolicy {
+    fn parse_value(value: &str) -> Result<Self, String> {
+        match value {
+            "always" | "true" => Ok(UnknownResultPolicy::Always),
+            "idempotent" => Ok(UnknownResultPolicy::Idempotent),
+            "never" | "false" => Ok(UnknownResultPolicy::Never),
+            _ => Err(format!("Invalid unknown result retry policy: {value}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::write::{BatchBuilder, ValueClass};
+
+    use super::*;
+
+    const RETRYABLE: &[i32] = &[1007, 1020];
+    const MAYBE_COMMITTED: &[i32] = &[1021];
+    const FATAL: &[i32] = &[2101, 2102, 2103, 1031, 1025];
+
+    #[test]
+    fn classify_errors() {
+        for (codes, class) in [
+            (RETRYABLE, ErrorClass::Retryable),
+            (MAYBE_COMMITTED, ErrorClass::MaybeCommitted),
+            (FATAL, ErrorClass::Fatal),
+        ] {
+            for code in codes {
+                assert_eq!(ErrorClass::from_code(*code), class, "code {code}");
+            }
+        }
+    }
+
+    #[test]
+    fn retry_decision() {
+        for policy in [
+            UnknownResultPolicy::Always,
+            UnknownResultPolicy::Idempotent,
+            UnknownResultPolicy::Never,
+        ] {
+            for is_idempotent in [true, false] {
+                for code in RETRYABLE {
+                    assert!(ErrorClass::from_code(*code).should_retry(policy, is_idempotent));
+                }
+                for code in FATAL {
+                    assert!(!ErrorClass::from_code(*code).should_retry(policy, is_idempotent));
+                }
+                for code in MAYBE_COMMITTED {
+                    assert_eq!(
+                        ErrorClass::from_code(*code).should_retry(policy, is_idempotent),
+                        match policy {
+                            UnknownResultPolicy::Always => true,
+                            UnknownResultPolicy::Idempotent => is_idempotent,
+                            UnknownResultPolicy::Never => false,
+                        },
+                        "{policy:?} idempotent={is_idempotent}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn retry_policy() {
+        let mut config = Config::new(
+            r#"
+[store.fdb.transaction.retry]
+max-attempts = 3
+max-time = "1m"
+backoff = "200ms"
+"#,
+        )
+        .unwrap();
+        let policy = FdbRetryPolicy::parse(&mut config, "store.fdb");
+        assert_eq!(
+            policy,
+            FdbRetryPolicy {
+                max_attempts: 3,
+                max_time: Duration::from_secs(60),
+                base_backoff: Duration::from_millis(200),
+            }
+        );
+        assert_eq!(
+            FdbRetryPolicy::parse(&mut Config::default(), "store.fdb"),
+            FdbRetryPolicy::default()
+        );
+
+        let start = Instant::now();
+        assert!(policy.can_retry(2, start));
+        assert!(!policy.can_retry(3, start));
+        for _ in 0..100 {
+            let backoff = policy.backoff();
+            assert!(
+                (Duration::from_millis(200)..=Duration::from_millis(1200)).contains(&backoff),
+                "{backoff:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn batch_idempotency() {
+        let mut batch = BatchBuilder::new();
+        batch
+            .with_account_id(1)
+            .with_collection(0u8)
+            .create_document_with_id(2)
+            .set(ValueClass::Property(0), b"value".to_vec())
+            .clear(ValueClass::Property(1));
+        assert!(batch.build_batch().is_idempotent());
+
+        // Assigning a new document id twice would create a second document
+        let mut batch = BatchBuilder::new();
+        batch
+            .with_account_id(1)
+            .with_collection(0u8)
+            .create_document();
+        assert!(!batch.build_batch().is_idempotent());
+
+        // Counters and appends would be applied twice
+        let mut batch = BatchBuilder::new();
+        batch
+            .with_account_id(1)
+            .with_collection(0u8)
+            .update_document(2)
+            .add(ValueClass::Property(0), 1);
+        assert!(!batch.build_batch().is_idempotent());
+
+        let mut batch = BatchBuilder::new();
+        batch
+            .with_account_id(1)
+            .with_collection(0u8)
+            .update_document(2)
+            .append(ValueClass::Property(0), b"data".to_vec());
+        assert!(!batch.build_batch().is_idempotent());
+    }
+}
@@ -4,50 +4,93 @@
  * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
  */
 
-use std::{
-    cmp::Ordering,
-    time::{Duration, Instant},
-};
+use std::{cmp::Ordering, time::Instant};
 
+use ahash::AHashSet;
 use foundationdb::{
     FdbError, KeySelector, RangeOption, Transaction,
     options::{self, MutationType, StreamingMode},
 };
-use futures::TryStreamExt;
-use rand::Rng;
+use futures::{TryStreamExt, future::join_all};
 use roaring::RoaringBitmap;
 
 use crate::{
-    BitmapKey, IndexKey, Key, LogKey, SUBSPACE_COUNTER, SUBSPACE_IN_MEMORY_COUNTER, SUBSPACE_QUOTA,
-    U32_LEN, WITH_SUBSPACE,
+    BitmapKey, IndexKey, Key, LogKey, SUBSPACE_COUNTER, SUBSPACE_IN_MEMORY_COUNTER,
+    SUBSPACE_INDEXES, SUBSPACE_LOGS, SUBSPACE_QUOTA, U32_LEN, WITH_SUBSPACE,
     backend::deserialize_i64_le,
     write::{
-        AssignedIds, Batch, BitmapClass, MAX_COMMIT_ATTEMPTS, MAX_COMMIT_TIME, Operation,
-        RandomAvailableId, ValueOp,
+        AssignedIds, Batch, BitmapClass, MaybeDynamicValue, Operation, RandomAvailableId, ValueOp,
+        assert::AssertValue,
+        clear_prefix_range,
         key::{DeserializeBigEndian, KeySerializer},
     },
 };
 
 use super::{
-    FdbStore, MAX_VALUE_SIZE, ReadVersion, into_error,
+    FdbStore, MAX_TRANSACTION_SIZE, MAX_VALUE_SIZE, ReadVersion, into_error,
     read::{ChunkedValue, read_chunked_value},
+    retry::ErrorClass,
 };
 
 impl FdbStore {
     pub(crate) async fn write(&self, batch: Batch) -> trc::Result<AssignedIds> {
+        if !self.split_batches || estimated_size(&batch) <= MAX_TRANSACTION_SIZE {
+            return self.write_batch(&batch, AssignedIds::default()).await;
+        }
+
+        // Ids assigned by earlier transactions are carried over, as later
+        // operations can refer to them
+        let mut result = AssignedIds::default();
+        for batch in split_batch(batch, MAX_TRANSACTION_SIZE) {
+            result = self.write_batch(&batch, result).await?;
+        }
+        Ok(result)
+    }
+
+    async fn write_batch(
+        &self,
+        batch: &Batch,
+        assigned_ids: AssignedIds,
+    ) -> trc::Result<AssignedIds> {
         let start = Instant::now();
         let mut retry_count = 0;
 
         loop {
+            let attempt_start = Instant::now();
             let mut account_id = u32::MAX;
             let mut collection = u8::MAX;
             let mut document_id = u32::MAX;
             let mut change_id = u64::MAX;
-            let mut result = AssignedIds::default();
+            let mut result = assigned_ids.clone();
 
             let trx = self.db.create_trx().map_err(into_error)?;
 
-            for op in &batch.ops {
+            // Evaluate independent assertions concurrently
+            let mut assertions = batch.independent_assertions(WITH_SUBSPACE);
+            for assertion in &mut assertions {
+                assertion.key = self.prefix_key(std::mem::take(&mut assertion.key));
+            }
+            let mut verified_asserts = AHashSet::new();
+            if assertions.len() > 1 {
+                let values = join_all(
+                    assertions
+                        .iter()
+                        .map(|assertion| read_chunked_value(&assertion.key, &trx, false)),
+                )
+                .await;
+
+                for (assertion, value) in assertions.iter().zip(values) {
+                    if !assertion_matches(assertion.assert_value, value) {
+                        trx.cancel();
+                        return Err(trc::StoreEvent::AssertValueFailed
+                            .into_err()
+                            .ctx(trc::Key::Key, assertion.key.as_slice()));
+                    }
+                    verified_asserts.insert(assertion.op_idx);
+                }
+            }
+
+            for (op_idx, op) in batch.ops.iter().enumerate() {
                 match op {
                     Operation::AccountId {
                         account_id: account_id_,
@@ -70,13 +113,13 @@ impl FdbStore {
                         change_id = *change_id_;
                     }
                     Operation::Value { class, op } => {
-                        let mut key = class.serialize(
+                        let mut key = self.prefix_key(class.serialize(
                             account_id,
                             collection,
                             document_id,
                             WITH_SUBSPACE,
                             (&result).into(),
-                        );
+                        ));
                         let do_chunk = !class.is_counter(collection);
 
                         match op {
@@ -122,6 +165,26 @@ impl FdbStore {
                                 trx.set(&key, &num.to_le_bytes()[..]);
                                 result.push_counter_id(num);
                             }
+                            ValueOp::Append(data) => {
+                                // AppendIfFits drops the data when the value would grow past
+                                // the limit, so the size is read without a snapshot: appends
+                                // racing with this one conflict and are retried instead of
+                                // being lost. Values above the limit are chunked and can't be
+                                // appended to.
+                                let size = trx
+                                    .get(&key, false)
+                                    .await
+                                    .map_err(into_error)?
+                                    .map_or(0, |bytes| bytes.len())
+                                    + data.len();
+                                if size > MAX_VALUE_SIZE {
+                                    trx.cancel();
+                                    return Err(
+                                        trc::StoreEvent::ValueTooLarge.ctx(trc::Key::Size, size)
+                                    );
+                                }
+                                trx.atomic_op(&key, data, MutationType::AppendIfFits);
+                            }
                             ValueOp::Clear => {
                                 if do_chunk {
                                     trx.clear_range(
@@ -138,14 +201,13 @@ impl FdbStore {
                         }
                     }
                     Operation::Index { field, key, set } => {
-                        let key = IndexKey {
+                        let key = self.key(&IndexKey {
                             account_id,
                             collection,
                             document_id,
                             field: *field,
                             key,
-                        }
-                        .serialize(WITH_SUBSPACE);
+                        });
 
                         if *set {
                             trx.set(&key, &[]);
@@ -159,20 +221,18 @@ impl FdbStore {
                             && matches!(class, BitmapClass::DocumentIds)
                             && document_id == u32::MAX;
                         if assign_id {
-                            let begin = BitmapKey {
+                            let begin = self.key(&BitmapKey {
                                 account_id,
                                 collection,
                                 class: BitmapClass::DocumentIds,
                                 document_id: 0,
-                            }
-                            .serialize(WITH_SUBSPACE);
-                            let end = BitmapKey {
+                            });
+                            let end = self.key(&BitmapKey {
                                 account_id,
                                 collection,
                                 class: BitmapClass::DocumentIds,
                                 document_id: u32::MAX,
-                            }
-                            .serialize(WITH_SUBSPACE);
+                            });
                             let key_len = begin.len();
                             let mut values = trx.get_ranges_keyvalues(
                                 RangeOption {
@@ -193,29 +253,33 @@ impl FdbStore {
                                     break;
                                 }
                             }
-                            document_id = found_ids.random_available_id();
+                            document_id = found_ids.random_available_id(
+                                account_id,
+                                collection,
+                                self.settings.id_assignment_window(collection),
+                            );
                             result.push_document_id(document_id);
                         }
 
-                        let key = class.serialize(
+                        let key = self.prefix_key(class.serialize(
                             account_id,
                             collection,
                             document_id,
                             WITH_SUBSPACE,
                             (&result).into(),
-                        );
+                        ));
 
                         if *set {
                             if assign_id {
                                 trx.add_conflict_range(
                                     &key,
-                                    &class.serialize(
+                                    &self.prefix_key(class.serialize(
                                         account_id,
                                         collection,
                                         document_id + 1,
                                         WITH_SUBSPACE,
                                         (&result).into(),
-                                    ),
+                                    )),
                                     options::ConflictRangeType::Read,
                                 )
                                 .map_err(into_error)?;
@@ -227,60 +291,85 @@ impl FdbStore {
                         }
                     }
                     Operation::Log { set } => {
-                        let key = LogKey {
+                        let key = self.key(&LogKey {
                             account_id,
                             collection,
                             change_id,
-                        }
-                        .serialize(WITH_SUBSPACE);
+                        });
                         trx.set(&key, set.resolve(&result)?.as_ref());
                     }
+                    Operation::ClearPrefix {
+                        subspace,
+                        account_id: account_id_,
+                        collection: collection_,
+                    } => {
+                        let (from, to) = clear_prefix_range(
+                            *subspace,
+                            *account_id_,
+                            *collection_,
+                            WITH_SUBSPACE,
+                        )?;
+                        trx.clear_range(&self.prefix_key(from), &self.prefix_key(to));
+                    }
                     Operation::AssertValue {
                         class,
                         assert_value,
-                    } => {
-                        let key = class.serialize(
+                    } if !verified_asserts.contains(&op_idx) => {
+                        let key = self.prefix_key(class.serialize(
                             account_id,
                             collection,
                             document_id,
                             WITH_SUBSPACE,
                             (&result).into(),
-                        );
-
-                        let matches = match read_chunked_value(&key, &trx, false).await {
-                            Ok(ChunkedValue::Single(bytes)) => assert_value.matches(bytes.as_ref()),
-                            Ok(ChunkedValue::Chunked { bytes, .. }) => {
-                                assert_value.matches(bytes.as_ref())
-                            }
-                            Ok(ChunkedValue::None) => assert_value.is_none(),
-                            Err(_) => false,
-                        };
+                        ));
 
-                        if !matches {
+                        if !assertion_matches(
+                            assert_value,
+                            read_chunked_value(&key, &trx, false).await,
+                        ) {
                             trx.cancel();
-                            return Err(trc::StoreEvent::AssertValueFailed.into());
+                            return Err(trc::StoreEvent::AssertValueFailed
+                                .into_err()
+                                .ctx(trc::Key::Key, key));
                         }
                     }
+                    Operation::AssertValue { .. } => {}
                 }
             }
 
             if self
                 .commit(
                     trx,
-                    retry_count < MAX_COMMIT_ATTEMPTS && start.elapsed() < MAX_COMMIT_TIME,
+                    self.retry_policy.can_retry(retry_count, start),
+                    batch.is_idempotent(),
                 )
-                .await?
+                .await
+                .map_err(|err| {
+                    err.ctx(trc::Key::Total, retry_count)
+                        .ctx(trc::Key::Elapsed, start.elapsed())
+                })?
             {
                 return Ok(result);
             } else {
-                let backoff = rand::rng().random_range(50..=300);
-                tokio::time::sleep(Duration::from_millis(backoff)).await;
+                trc::event!(
+                    Store(trc::StoreEvent::FoundationdbCommitRetry),
+                    Details = batch_subspaces(batch),
+                    Total = retry_count + 1,
+                    Elapsed = attempt_start.elapsed()
+                );
+
+                tokio::time::sleep(self.retry_policy.backoff()).await;
                 retry_count += 1;
             }
         }
     }
 
-    pub(crate) async fn commit(&self, trx: Transaction, will_retry: bool) -> trc::Result<bool> {
+    pub(crate) async fn commit(
+        &self,
+        trx: Transaction,
+        will_retry: bool,
+        is_idempotent: bool,
+    ) -> trc::Result<bool> {
         match trx.commit().await {
             Ok(result) => {
                 let commit_version = result.committed_version().map_err(into_error)?;
@@ -291,7 +380,12 @@ impl FdbStore {
                 Ok(true)
             }
             Err(err) => {
-                if will_retry {
+                // A commit_unknown_result may have been applied, so only batches
+                // that can be replayed safely are retried by default
+                if will_retry
+                    && ErrorClass::from_code(err.code())
+                        .should_retry(self.retry_unknown_result, is_idempotent)
+                {
                     err.on_error().await.map_err(into_error)?;
                     Ok(false)
                 } else {
@@ -306,13 +400,14 @@ impl FdbStore {
         let mut delete_keys = Vec::new();
         for subspace in [SUBSPACE_COUNTER, SUBSPACE_QUOTA, SUBSPACE_IN_MEMORY_COUNTER] {
             let trx = self.db.create_trx().map_err(into_error)?;
-            let from_key = [subspace, 0u8];
-            let to_key = [subspace, u8::MAX, u8::MAX, u8::MAX, u8::MAX, u8::MAX];
+            let from_key = self.prefix_key(vec![subspace, 0u8]);
+            let to_key =
+                self.prefix_key(vec![subspace, u8::MAX, u8::MAX, u8::MAX, u8::MAX, u8::MAX]);
 
             let mut values = trx.get_ranges_keyvalues(
                 RangeOption {
-                    begin: KeySelector::first_greater_or_equal(&from_key[..]),
-                    end: KeySelector::first_greater_or_equal(&to_key[..]),
+                    begin: KeySelector::first_greater_or_equal(&from_key),
+                    end: KeySelector::first_greater_or_equal(&to_key),
                     mode: options::StreamingMode::WantAll,
                     reverse: false,
                     ..Default::default()
@@ -341,7 +436,10 @@ impl FdbStore {
                     trx.atomic_op(key, &integer, MutationType::CompareAndClear);
                 }
 
-                if self.commit(trx, retry_count < MAX_COMMIT_ATTEMPTS).await? {
+                if self
+                    .commit(trx, retry_count < self.retry_policy.max_attempts, true)
+                    .await?
+                {
                     break;
                 } else {
                     retry_count += 1;
@@ -353,11 +451,231 @@ impl FdbStore {
     }
 
     pub(crate) async fn delete_range(&self, from: impl Key, to: impl Key) -> trc::Result<()> {
-        let from = from.serialize(WITH_SUBSPACE);
-        let to = to.serialize(WITH_SUBSPACE);
+        let from = self.key(&from);
+        let to = self.key(&to);
 
         let trx = self.db.create_trx().map_err(into_error)?;
         trx.clear_range(&from, &to);
-        self.commit(trx, false).await.map(|_| ())
+        self.commit(trx, false, true).await.map(|_| ())
+    }
+}
+
+/// Lists the subspaces written or asserted by a batch, reported when its commit
+/// is retried to locate contention hotspots.
+fn batch_subspaces(batch: &Batch) -> trc::Value {
+    let mut collection = u8::MAX;
+    let mut subspaces = Vec::new();
+
+    for op in &batch.ops {
+        let subspace = match op {
+            Operation::Collection {
+                collection: collection_,
+            } => {
+                collection = *collection_;
+                continue;
+            }
+            Operation::Value { class, .. } | Operation::AssertValue { class, .. } => {
+                class.subspace(collection)
+            }
+            Operation::Index { .. } => SUBSPACE_INDEXES,
+            Operation::Bitmap { class, .. } => class.subspace(),
+            Operation::Log { .. } => SUBSPACE_LOGS,
+            Operation::ClearPrefix { subspace, .. } => *subspace,
+            Operation::AccountId { .. }
+            | Operation::DocumentId { .. }
+            | Operation::ChangeId { .. } => continue,
+        };
+        if !subspaces.contains(&subspace) {
+            subspaces.push(subspace);
+        }
+    }
+
+    trc::Value::Array(
+        subspaces
+            .into_iter()
+            .map(|subspace| trc::Value::from(char::from(subspace).to_string()))
+            .collect(),
+    )
+}
+
+/// Splits a batch into several batches of roughly `max_size` bytes, which are
+/// committed as separate transactions. Batches are only split where a new
+/// document starts, so that all changes to a document, including its bitmap
+/// sets and clears, are applied together. The account, collection and change
+/// id in effect are repeated at the start of each batch.
+///
+/// Atomicity only holds within each of the resulting transactions: when one
+/// of them fails, the ones committed before it are not rolled back. Batches
+/// with assertions are returned as is, as the assertions guard all of the
+/// changes that follow them.
+fn split_batch(batch: Batch, max_size: usize) -> Vec<Batch> {
+    if batch
+        .ops
+        .iter()
+        .any(|op| matches!(op, Operation::AssertValue { .. }))
+    {
+        return vec![batch];
+    }
+
+    let mut batches = Vec::new();
+    let mut ops = Vec::new();
+    let mut size = 0;
+    let mut account_id = None;
+    let mut collection = None;
+    let mut change_id = None;
+
+    for op in batch.ops {
+        match &op {
+            Operation::AccountId {
+                account_id: account_id_,
+            } => {
+                account_id = Some(*account_id_);
+            }
+            Operation::Collection {
+                collection: collection_,
+            } => {
+                collection = Some(*collection_);
+            }
+            Operation::ChangeId {
+                change_id: change_id_,
+            } => {
+                change_id = Some(*change_id_);
+            }
+            Operation::DocumentId { .. } if size >= max_size => {
+                batches.push(Batch {
+                    ops: std::mem::take(&mut ops),
+                });
+                size = 0;
+                ops.extend(account_id.map(|account_id| Operation::AccountId { account_id }));
+                ops.extend(collection.map(|collection| Operation::Collection { collection }));
+                ops.extend(change_id.map(|change_id| Operation::ChangeId { change_id }));
+            }
+            _ => {}
+        }
+
+        size += estimated_op_size(&op);
+        ops.push(op);
+    }
+
+    if !ops.is_empty() {
+        batches.push(Batch { ops });
+    }
+
+    batches
+}
+
+fn estimated_size(batch: &Batch) -> usize {
+    batch.ops.iter().map(estimated_op_size).sum()
+}
+
+/// Approximates the number of bytes an operation adds to a transaction. The
+/// size of dynamic values is not known until they are resolved, so only their
+/// key is counted.
+fn estimated_op_size(op: &Operation) -> usize {
+    const KEY_LEN: usize = 32;
+
+    match op {
+        Operation::Value {
+            op: ValueOp::Set(MaybeDynamicValue::Static(value)) | ValueOp::Append(value),
+            ..
+        }
+        | Operation::Log {
+            set: MaybeDynamicValue::Static(value),
+        }
+        | Operation::Index { key: value, .. } => KEY_LEN + value.len(),
+        Operation::Value { .. }
+        | Operation::AssertValue { .. }
+        | Operation::Bitmap { .. }
+        | Operation::Log { .. }
+        | Operation::ClearPrefix { .. } => KEY_LEN,
+        Operation::AccountId { .. }
+        | Operation::Collection { .. }
+        | Operation::DocumentId { .. }
+        | Operation::ChangeId { .. } => 0,
+    }
+}
+
+fn assertion_matches(assert_value: &AssertValue, value: trc::Result<ChunkedValue>) -> bool {
+    match value {
+        Ok(ChunkedValue::Single(bytes)) => assert_value.matches(bytes.as_ref()),
+        Ok(ChunkedValue::Chunked { bytes, .. }) => assert_value.matches(bytes.as_ref()),
+        Ok(ChunkedValue::None) => assert_value.is_none(),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::write::ValueClass;
+
+    use super::*;
+
+    fn document_batch(document_ids: &[u32]) -> Batch {
+        let mut ops = vec![
+            Operation::AccountId { account_id: 1 },
+            Operation::Collection { collection: 2 },
+            Operation::ChangeId { change_id: 3 },
+        ];
+        for document_id in document_ids {
+            ops.extend([
+                Operation::DocumentId {
+                    document_id: *document_id,
+                },
+                Operation::Value {
+                    class: ValueClass::Property(0),
+                    op: ValueOp::Set(MaybeDynamicValue::Static(vec![0u8; 100])),
+                },
+                Operation::Bitmap {
+                    class: BitmapClass::DocumentIds,
+                    set: true,
+                },
+            ]);
+        }
+        ops.push(Operation::Log {
+            set: MaybeDynamicValue::Static(vec![0u8; 10]),
+        });
+        Batch { ops }
+    }
+
+    #[test]
+    fn split_large_batches() {
+        let batch = document_batch(&[10, 20, 30]);
+        assert_eq!(estimated_size(&batch), 3 * (132 + 32) + 42);
+
+        // Small batches are not split
+        let batches = split_batch(document_batch(&[10, 20, 30]), 1000);
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].ops.len(), batch.ops.len());
+
+        // Each document is committed separately along with its context
+        let batches = split_batch(batch, 100);
+        assert_eq!(batches.len(), 3);
+        for (batch, document_id) in batches.iter().zip([10, 20, 30]) {
+            assert!(matches!(
+                batch.ops[..6],
+                [
+                    Operation::AccountId { account_id: 1 },
+                    Operation::Collection { collection: 2 },
+                    Operation::ChangeId { change_id: 3 },
+                    Operation::DocumentId { document_id: id },
+                    Operation::Value { .. },
+                    Operation::Bitmap { set: true, .. },
+                ] if id == document_id
+            ));
+        }
+        assert_eq!(batches[0].ops.len(), 6);
+        assert_eq!(batches[1].ops.len(), 6);
+        assert!(matches!(batches[2].ops[6], Operation::Log { .. }));
+
+        // Batches with assertions are never split
+        let mut batch = document_batch(&[10, 20, 30]);
+        batch.ops.insert(
+            4,
+            Operation::AssertValue {
+                class: ValueClass::Property(0),
+                assert_value: AssertValue::None,
+            },
+        );
+        assert_eq!(split_batch(batch, 100).len(), 1);
     }
 }
@@ -4,20 +4,36 @@
  * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
  */
 
-use std::{io::SeekFrom, ops::Range, path::PathBuf};
+use std::{
+    io::SeekFrom,
+    ops::{Deref, Range},
+    path::{Path, PathBuf},
+};
+
+use futures::{StreamExt, stream};
+use memmap2::Mmap;
 
 use tokio::{
     fs::{self, File},
-    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, Take},
 };
 use utils::{
-    codec::base32_custom::Base32Writer,
+    codec::base32_custom::{Base32Reader, Base32Writer},
     config::{Config, utils::AsKey},
 };
 
+use crate::dispatch::manifest::KeyPages;
+
 pub struct FsStore {
     path: PathBuf,
     hash_levels: usize,
+    /// Length of the key prefix of the blob store, see `BlobStore::with_key_prefix`
+    key_prefix_len: usize,
+}
+
+pub struct MappedBlob {
+    map: Option<Mmap>,
+    range: Range<usize>,
 }
 
 impl FsStore {
@@ -44,9 +60,21 @@ impl FsStore {
                     .unwrap_or(2),
                 5,
             ),
+            key_prefix_len: 0,
         })
     }
 
+    /// Returns a store for keys starting with a key prefix of `key_prefix_len`
+    /// bytes. Blobs sharing a prefix are kept in a directory of their own and
+    /// fanned out by the bytes that follow it.
+    pub(crate) fn with_key_prefix_len(&self, key_prefix_len: usize) -> Self {
+        FsStore {
+            path: self.path.clone(),
+            hash_levels: self.hash_levels,
+            key_prefix_len,
+        }
+    }
+
     pub(crate) async fn get_blob(
         &self,
         key: &[u8],
@@ -55,7 +83,8 @@ impl FsStore {
         let blob_path = self.build_path(key);
         let blob_size = match fs::metadata(&blob_path).await {
             Ok(m) => m.len() as usize,
-            Err(_) => return Ok(None),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(into_error(err)),
         };
         let mut blob = File::open(&blob_path).await.map_err(into_error)?;
 
@@ -81,6 +110,83 @@ impl FsStore {
         }))
     }
 
+    pub(crate) async fn get_blob_reader(
+        &self,
+        key: &[u8],
+        range: Range<usize>,
+    ) -> trc::Result<Option<Take<File>>> {
+        let mut blob = match File::open(self.build_path(key)).await {
+            Ok(blob) => blob,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(into_error(err)),
+        };
+        let blob_size = blob.metadata().await.map_err(into_error)?.len() as usize;
+        let from_offset = if range.start < blob_size {
+            range.start
+        } else {
+            0
+        };
+        if from_offset > 0 {
+            blob.seek(SeekFrom::Start(from_offset as u64))
+                .await
+                .map_err(into_error)?;
+        }
+
+        Ok(Some(blob.take(
+            range.end.min(blob_size).saturating_sub(from_offset) as u64,
+        )))
+    }
+
+    pub(crate) async fn get_blob_mapped(
+        &self,
+        key: &[u8],
+        range: Range<usize>,
+    ) -> trc::Result<Option<MappedBlob>> {
+        let blob = match File::open(self.build_path(key)).await {
+            Ok(blob) => blob.into_std().await,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(into_error(err)),
+        };
+        let blob_size = blob.metadata().map_err(into_error)?.len() as usize;
+        if blob_size == 0 {
+            return Ok(Some(MappedBlob {
+                map: None,
+                range: 0..0,
+            }));
+        }
+
+        // SAFETY: Blob files are never modified in place. Writers create a temporary
+        // file and atomically rename it over the destination, and deleting a file only
+        // unlinks it, so the mapped inode is not truncated while the map is alive.
+        let map = unsafe { Mmap::map(&blob) }.map_err(into_error)?;
+        let from_offset = if range.start < blob_size {
+            range.start
+        } else {
+            0
+        };
+
+        Ok(Some(MappedBlob {
+            map: Some(map),
+            range: from_offset..std::cmp::min(range.end, blob_size),
+        }))
+    }
+
+    pub(crate) async fn blob_exists(&self, key: &[u8]) -> trc::Result<bool> {
+        match fs::metadata(self.build_path(key)).await {
+            Ok(_) => Ok(true),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(into_error(err)),
+        }
+    }
+
+    pub(crate) async fn blob_size(&self, key: &[u8]) -> trc::Result<Option<u64>> {
+        match fs::metadata(self.build_path(key)).await {
+            Ok(metadata) => Ok(Some(metadata.len())),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(into_error(err)),
+        }
+    }
+
     pub(crate) async fn put_blob(&self, key: &[u8], data: &[u8]) -> trc::Result<()> {
         let blob_path = self.build_path(key);
 
@@ -88,17 +194,55 @@ impl FsStore {
             .await
             .map_or(true, |m| m.len() as usize != data.len())
         {
-            fs::create_dir_all(blob_path.parent().unwrap())
-                .await
-                .map_err(into_error)?;
-            let mut blob_file = File::create(&blob_path).await.map_err(into_error)?;
-            blob_file.write_all(data).await.map_err(into_error)?;
-            blob_file.flush().await.map_err(into_error)?;
+            // Write to a temporary file first so readers holding a memory map of
+            // the previous contents never observe a truncated file.
+            let tmp_path = write_tmp_file(&blob_path, data).await?;
+            if let Err(err) = fs::rename(&tmp_path, &blob_path).await {
+                let _ = fs::remove_file(&tmp_path).await;
+                return Err(into_error(err));
+            }
         }
 
         Ok(())
     }
 
+    pub(crate) async fn put_blob_if_absent(&self, key: &[u8], data: &[u8]) -> trc::Result<bool> {
+        let blob_path = self.build_path(key);
+        if fs::metadata(&blob_path).await.is_ok() {
+            return Ok(false);
+        }
+
+        // Linking fails when the path exists, like an O_EXCL create, but the
+        // blob only becomes visible once it has been written in full
+        let tmp_path = write_tmp_file(&blob_path, data).await?;
+        let result = match fs::hard_link(&tmp_path, &blob_path).await {
+            Ok(_) => Ok(true),
+            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => Ok(false),
+            Err(err) => Err(into_error(err)),
+        };
+        let _ = fs::remove_file(&tmp_path).await;
+
+        result
+    }
+
+    /// Starts writing a blob in chunks. Chunks are appended to a temporary file
+    /// that is moved in place once the upload is finished.
+    pub(crate) async fn begin_upload(&self, key: &[u8]) -> trc::Result<FsUpload> {
+        let blob_path = self.build_path(key);
+        let tmp_path = write_tmp_file(&blob_path, &[]).await?;
+        match fs::OpenOptions::new().append(true).open(&tmp_path).await {
+            Ok(file) => Ok(FsUpload {
+                file,
+                tmp_path,
+                blob_path,
+            }),
+            Err(err) => {
+                let _ = fs::remove_file(&tmp_path).await;
+                Err(into_error(err))
+            }
+        }
+    }
+
     pub(crate) async fn delete_blob(&self, key: &[u8]) -> trc::Result<bool> {
         let blob_path = self.build_path(key);
         if fs::metadata(&blob_path).await.is_ok() {
@@ -109,17 +253,237 @@ impl FsStore {
         }
     }
 
+    /// Lists the blobs starting with `prefix`, one page per directory.
+    pub(crate) fn list_blob_pages(&self, prefix: Vec<u8>) -> KeyPages<'_> {
+        let (key_prefix, _) = self.split_key(&prefix);
+        let dirs = vec![if key_prefix.len() == self.key_prefix_len {
+            self.prefix_path(key_prefix)
+        } else {
+            self.path.clone()
+        }];
+
+        stream::try_unfold(dirs, move |mut dirs| {
+            let prefix = prefix.clone();
+            async move {
+                let Some(dir) = dirs.pop() else {
+                    return Ok(None);
+                };
+                let mut keys = Vec::new();
+                let mut entries = match fs::read_dir(&dir).await {
+                    Ok(entries) => entries,
+                    Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                        return Ok(Some((keys, dirs)));
+                    }
+                    Err(err) => return Err(into_error(err)),
+                };
+                while let Some(entry) = entries.next_entry().await.map_err(into_error)? {
+                    if entry.file_type().await.map_err(into_error)?.is_dir() {
+                        dirs.push(entry.path());
+                    } else if let Some(name) = entry.file_name().to_str() {
+                        // Skip temporary files left behind by interrupted writes
+                        if !name.contains('.') {
+                            let key = Base32Reader::new(name.as_bytes()).collect::<Vec<_>>();
+                            if key.starts_with(&prefix) {
+                                keys.push(key);
+                            }
+                        }
+                    }
+                }
+
+                Ok(Some((keys, dirs)))
+            }
+        })
+        .boxed()
+    }
+
+    /// Removes the directory holding the blobs whose key starts with `prefix`,
+    /// returning the number of blobs it contained. Returns `None` when the
+    /// prefix does not map to a directory, as is the case of prefixes longer
+    /// than the directory depth, so the blobs have to be deleted one by one.
+    pub(crate) async fn delete_blob_dir(&self, prefix: &[u8]) -> trc::Result<Option<usize>> {
+        let (key_prefix, prefix) = self.split_key(prefix);
+        if key_prefix.len() < self.key_prefix_len
+            || prefix.is_empty()
+            || prefix.len() > self.hash_levels
+        {
+            return Ok(None);
+        }
+
+        let mut path = self.prefix_path(key_prefix);
+        for byte in prefix {
+            path.push(format!("{:x}", byte));
+        }
+
+        let mut deleted = 0;
+        let mut dirs = vec![path.clone()];
+        while let Some(dir) = dirs.pop() {
+            let mut entries = match fs::read_dir(&dir).await {
+                Ok(entries) => entries,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(into_error(err)),
+            };
+            while let Some(entry) = entries.next_entry().await.map_err(into_error)? {
+                if entry.file_type().await.map_err(into_error)?.is_dir() {
+                    dirs.push(entry.path());
+                } else if entry
+                    .file_name()
+                    .to_str()
+                    .is_some_and(|name| !name.contains('.'))
+                {
+                    deleted += 1;
+                }
+            }
+        }
+
+        match fs::remove_dir_all(&path).await {
+            Ok(_) => Ok(Some(deleted)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Some(0)),
+            Err(err) => Err(into_error(err)),
+        }
+    }
+
     fn build_path(&self, key: &[u8]) -> PathBuf {
-        let mut path = self.path.clone();
+        let (key_prefix, store_key) = self.split_key(key);
+        let mut path = self.prefix_path(key_prefix);
 
-        for byte in key.iter().take(self.hash_levels) {
+        for byte in store_key.iter().take(self.hash_levels) {
             path.push(format!("{:x}", byte));
         }
         path.push(Base32Writer::from_bytes(key).finalize());
         path
     }
+
+    fn split_key<'x>(&self, key: &'x [u8]) -> (&'x [u8], &'x [u8]) {
+        key.split_at(std::cmp::min(self.key_prefix_len, key.len()))
+    }
+
+    fn prefix_path(&self, key_prefix: &[u8]) -> PathBuf {
+        if !key_prefix.is_empty() {
+            self.path
+                .join(Base32Writer::from_bytes(key_prefix).finalize())
+        } else {
+            self.path.clone()
+        }
+    }
+}
+
+impl Deref for MappedBlob {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        self.map
+            .as_ref()
+            .map_or(&[][..], |map| &map[self.range.clone()])
+    }
+}
+
+impl AsRef<[u8]> for MappedBlob {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+pub(crate) struct FsUpload {
+    file: File,
+    tmp_path: PathBuf,
+    blob_path: PathBuf,
 }
 
+impl FsUpload {
+    pub(crate) async fn write(&mut self, data: &[u8]) -> trc::Result<()> {
+        self.file.write_all(data).await.map_err(into_error)
+    }
+
+    pub(crate) async fn finish(self) -> trc::Result<()> {
+        let FsUpload {
+            mut file,
+            tmp_path,
+            blob_path,
+        } = self;
+        let result = match file.flush().await {
+            Ok(_) => {
+                drop(file);
+                fs::rename(&tmp_path, &blob_path).await
+            }
+            Err(err) => Err(err),
+        };
+
+        if let Err(err) = result {
+            let _ = fs::remove_file(&tmp_path).await;
+            Err(into_error(err))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub(crate) async fn abort(self) {
+        drop(self.file);
+        let _ = fs::remove_file(&self.tmp_path).await;
+    }
+}
+
+async fn write_tmp_file(blob_path: &Path, data: &[u8]) -> trc::Result<PathBuf> {
+    fs::create_dir_all(blob_path.parent().unwrap())
+        .await
+        .map_err(into_error)?;
+
+    let tmp_path = blob_path.with_extension(format!("tmp{}", rand::random::<u32>()));
+    let mut blob_file = File::create(&tmp_path).await.map_err(into_error)?;
+    blob_file.write_all(data).await.map_err(into_error)?;
+    blob_file.flush().await.map_err(into_error)?;
+
+    Ok(tmp_path)
+}
+
+// Timeouts are reported by network filesystems that can no longer reach the server
 fn into_error(err: std::io::Error) -> trc::Error {
-    trc::StoreEvent::FilesystemError.reason(err)
+    match err.kind() {
+        std::io::ErrorKind::TimedOut
+        | std::io::ErrorKind::WouldBlock
+        | std::io::ErrorKind::Interrupted => trc::StoreEvent::BlobUnavailable.reason(err),
+        _ => trc::StoreEvent::FilesystemError.reason(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify_errors() {
+        for kind in [
+            std::io::ErrorKind::TimedOut,
+            std::io::ErrorKind::WouldBlock,
+            std::io::ErrorKind::Interrupted,
+        ] {
+            assert!(into_error(kind.into()).is_blob_unavailable(), "{kind:?}");
+        }
+        for kind in [
+            std::io::ErrorKind::PermissionDenied,
+            std::io::ErrorKind::InvalidData,
+        ] {
+            assert!(!into_error(kind.into()).is_blob_unavailable(), "{kind:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn missing_blobs() {
+        let path = std::env::temp_dir().join(format!("fs_store_{}", rand::random::<u32>()));
+        fs::create_dir_all(&path).await.unwrap();
+        let store = FsStore {
+            path: path.clone(),
+            hash_levels: 1,
+            key_prefix_len: 0,
+        };
+
+        // Only blobs that do not exist are missing
+        assert_eq!(store.get_blob(b"abc", 0..usize::MAX).await.unwrap(), None);
+        fs::write(path.join(format!("{:x}", b'x')), b"not a directory")
+            .await
+            .unwrap();
+        assert!(store.get_blob(b"xyz", 0..usize::MAX).await.is_err());
+        assert!(store.get_blob_reader(b"xyz", 0..usize::MAX).await.is_err());
+
+        fs::remove_dir_all(&path).await.unwrap();
+    }
 }
@@ -4,6 +4,8 @@
  * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
  */
 
+use std::ops::Range;
+
 #[cfg(feature = "azure")]
 pub mod azure;
 #[cfg(feature = "enterprise")]
@@ -30,6 +32,18 @@ pub mod sqlite;
 
 pub const MAX_TOKEN_LENGTH: usize = (u8::MAX >> 1) as usize;
 pub const MAX_TOKEN_MASK: usize = MAX_TOKEN_LENGTH - 1;
+/// Maximum number of blobs removed by a single bulk delete statement
+pub const MAX_DELETE_BLOBS: usize = 1000;
+
+/// Converts a byte range into the 1-based start position and the length taken
+/// by SQL substring functions, both capped to `max`.
+#[allow(dead_code)]
+fn sql_range(range: &Range<usize>, max: usize) -> (usize, usize) {
+    (
+        range.start.min(max - 1) + 1,
+        range.end.saturating_sub(range.start).min(max),
+    )
+}
 
 #[allow(dead_code)]
 fn deserialize_i64_le(key: &[u8], bytes: &[u8]) -> trc::Result<i64> {
@@ -8,6 +8,8 @@ use std::ops::Range;
 
 use mysql_async::prelude::Queryable;
 
+use crate::backend::{sql_range, MAX_DELETE_BLOBS};
+
 use super::{into_error, MysqlStore};
 
 impl MysqlStore {
@@ -16,25 +18,46 @@ impl MysqlStore {
         key: &[u8],
         range: Range<usize>,
     ) -> trc::Result<Option<Vec<u8>>> {
+        let mut conn = self.conn_pool.get_conn().await.map_err(into_error)?;
+        if range.start == 0 && range.end == usize::MAX {
+            let s = conn
+                .prep("SELECT v FROM t WHERE k = ?")
+                .await
+                .map_err(into_error)?;
+            conn.exec_first::<Vec<u8>, _, _>(&s, (key,)).await
+        } else {
+            // Only the requested range is read from the row, SUBSTRING is 1-based
+            let (start, len) = sql_range(&range, i64::MAX as usize);
+            let s = conn
+                .prep("SELECT SUBSTRING(v, ?, ?) FROM t WHERE k = ?")
+                .await
+                .map_err(into_error)?;
+            conn.exec_first::<Vec<u8>, _, _>(&s, (start as u64, len as u64, key))
+                .await
+        }
+        .map_err(into_error)
+    }
+
+    pub(crate) async fn blob_exists(&self, key: &[u8]) -> trc::Result<bool> {
+        let mut conn = self.conn_pool.get_conn().await.map_err(into_error)?;
+        let s = conn
+            .prep("SELECT 1 FROM t WHERE k = ? LIMIT 1")
+            .await
+            .map_err(into_error)?;
+        conn.exec_first::<u8, _, _>(&s, (key,))
+            .await
+            .map(|row| row.is_some())
+            .map_err(into_error)
+    }
+
+    pub(crate) async fn blob_size(&self, key: &[u8]) -> trc::Result<Option<u64>> {
         let mut conn = self.conn_pool.get_conn().await.map_err(into_error)?;
         let s = conn
-            .prep("SELECT v FROM t WHERE k = ?")
+            .prep("SELECT LENGTH(v) FROM t WHERE k = ?")
             .await
             .map_err(into_error)?;
-        conn.exec_first::<Vec<u8>, _, _>(&s, (key,))
+        conn.exec_first::<u64, _, _>(&s, (key,))
             .await
-            .map(|bytes| {
-                if range.start == 0 && range.end == usize::MAX {
-                    bytes
-                } else {
-                    bytes.map(|bytes| {
-                        bytes
-                            .get(range.start..std::cmp::min(bytes.len(), range.end))
-                            .unwrap_or_default()
-                            .to_vec()
-                    })
-                }
-            })
             .map_err(into_error)
     }
 
@@ -50,6 +73,20 @@ impl MysqlStore {
             .map(|_| ())
     }
 
+    pub(crate) async fn put_blob_if_absent(&self, key: &[u8], data: &[u8]) -> trc::Result<bool> {
+        // MySQL has no ON CONFLICT clause, an insert that matches an existing
+        // primary key is ignored instead
+        let mut conn = self.conn_pool.get_conn().await.map_err(into_error)?;
+        let s = conn
+            .prep("INSERT IGNORE INTO t (k, v) VALUES (?, ?)")
+            .await
+            .map_err(into_error)?;
+        conn.exec_iter(&s, (key, data))
+            .await
+            .map_err(into_error)
+            .map(|hits| hits.affected_rows() > 0)
+    }
+
     pub(crate) async fn delete_blob(&self, key: &[u8]) -> trc::Result<bool> {
         let mut conn = self.conn_pool.get_conn().await.map_err(into_error)?;
         let s = conn
@@ -61,4 +98,24 @@ impl MysqlStore {
             .map_err(into_error)
             .map(|hits| hits.affected_rows() > 0)
     }
+
+    pub(crate) async fn delete_blobs(&self, keys: &[Vec<u8>]) -> trc::Result<usize> {
+        let mut conn = self.conn_pool.get_conn().await.map_err(into_error)?;
+        let mut deleted = 0;
+        for keys in keys.chunks(MAX_DELETE_BLOBS) {
+            let s = conn
+                .prep(format!(
+                    "DELETE FROM t WHERE k IN ({})",
+                    vec!["?"; keys.len()].join(", ")
+                ))
+                .await
+                .map_err(into_error)?;
+            deleted += conn
+                .exec_iter(&s, keys.to_vec())
+                .await
+                .map_err(into_error)?
+                .affected_rows() as usize;
+        }
+        Ok(deleted)
+    }
 }
@@ -76,6 +76,7 @@ impl MysqlStore {
 
         let db = Self {
             conn_pool: Pool::new(opts),
+            settings: Default::default(),
         };
 
         if create_tables {
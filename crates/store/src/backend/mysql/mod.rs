@@ -4,10 +4,12 @@
  * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
  */
 
-use std::fmt::Display;
+use std::{fmt::Display, sync::Arc};
 
 use mysql_async::Pool;
 
+use crate::dispatch::settings::StoreSettings;
+
 pub mod blob;
 pub mod lookup;
 pub mod main;
@@ -16,6 +18,7 @@ pub mod write;
 
 pub struct MysqlStore {
     pub(crate) conn_pool: Pool,
+    pub(crate) settings: Arc<StoreSettings>,
 }
 
 #[inline(always)]
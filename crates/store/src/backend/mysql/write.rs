@@ -16,8 +16,8 @@ use crate::{
     BitmapKey, IndexKey, Key, LogKey, SUBSPACE_COUNTER, SUBSPACE_IN_MEMORY_COUNTER, SUBSPACE_QUOTA,
     U32_LEN,
     write::{
-        AssignedIds, Batch, BitmapClass, MAX_COMMIT_ATTEMPTS, MAX_COMMIT_TIME, Operation,
-        RandomAvailableId, ValueOp, key::DeserializeBigEndian,
+        AssignedIds, Batch, BitmapClass, MAX_APPEND_SIZE, MAX_COMMIT_ATTEMPTS, MAX_COMMIT_TIME,
+        Operation, RandomAvailableId, ValueOp, clear_prefix_range, key::DeserializeBigEndian,
     },
 };
 
@@ -195,6 +195,31 @@ impl MysqlStore {
                                 })?,
                             );
                         }
+                        ValueOp::Append(data) => {
+                            let s = trx
+                                .prep(format!(
+                                    concat!(
+                                        "INSERT INTO {} (k, v) VALUES (?, ?) ",
+                                        "ON DUPLICATE KEY UPDATE v = CONCAT(v, VALUES(v))"
+                                    ),
+                                    table
+                                ))
+                                .await?;
+                            trx.exec_drop(&s, (&key, data)).await?;
+                            let s = trx
+                                .prep(format!("SELECT LENGTH(v) FROM {} WHERE k = ?", table))
+                                .await?;
+                            let size =
+                                trx.exec_first::<u64, _, _>(&s, (key,))
+                                    .await?
+                                    .unwrap_or_default() as usize;
+                            if size > MAX_APPEND_SIZE {
+                                trx.rollback().await?;
+                                return Err(CommitError::Internal(
+                                    trc::StoreEvent::ValueTooLarge.ctx(trc::Key::Size, size),
+                                ));
+                            }
+                        }
                         ValueOp::Clear => {
                             let s = trx
                                 .prep(format!("DELETE FROM {} WHERE k = ?", table))
@@ -252,7 +277,11 @@ impl MysqlStore {
                             }
                         }
 
-                        document_id = found_ids.random_available_id();
+                        document_id = found_ids.random_available_id(
+                            account_id,
+                            collection,
+                            self.settings.id_assignment_window(collection),
+                        );
                         result.push_document_id(document_id);
                     }
                     let key =
@@ -299,6 +328,21 @@ impl MysqlStore {
                     trx.exec_drop(&s, (key, set.resolve(&result)?.as_ref()))
                         .await?;
                 }
+                Operation::ClearPrefix {
+                    subspace,
+                    account_id: account_id_,
+                    collection: collection_,
+                } => {
+                    let (from, to) = clear_prefix_range(*subspace, *account_id_, *collection_, 0)?;
+
+                    let s = trx
+                        .prep(format!(
+                            "DELETE FROM {} WHERE k >= ? AND k < ?",
+                            char::from(*subspace)
+                        ))
+                        .await?;
+                    trx.exec_drop(&s, (from, to)).await?;
+                }
                 Operation::AssertValue {
                     class,
                     assert_value,
@@ -6,6 +6,8 @@
 
 use std::ops::Range;
 
+use crate::backend::sql_range;
+
 use super::{into_error, PostgresStore};
 
 impl PostgresStore {
@@ -14,27 +16,50 @@ impl PostgresStore {
         key: &[u8],
         range: Range<usize>,
     ) -> trc::Result<Option<Vec<u8>>> {
+        let conn = self.conn_pool.get().await.map_err(into_error)?;
+        if range.start == 0 && range.end == usize::MAX {
+            let s = conn
+                .prepare_cached("SELECT v FROM t WHERE k = $1")
+                .await
+                .map_err(into_error)?;
+            conn.query_opt(&s, &[&key]).await
+        } else {
+            // Only the requested range is read from the row, substring is 1-based
+            let (start, len) = sql_range(&range, i32::MAX as usize);
+            let s = conn
+                .prepare_cached("SELECT substring(v FROM $2 FOR $3) FROM t WHERE k = $1")
+                .await
+                .map_err(into_error)?;
+            conn.query_opt(&s, &[&key, &(start as i32), &(len as i32)])
+                .await
+        }
+        .and_then(|row| row.map(|row| row.try_get::<_, Vec<u8>>(0)).transpose())
+        .map_err(into_error)
+    }
+
+    pub(crate) async fn blob_exists(&self, key: &[u8]) -> trc::Result<bool> {
+        let conn = self.conn_pool.get().await.map_err(into_error)?;
+        let s = conn
+            .prepare_cached("SELECT 1 FROM t WHERE k = $1 LIMIT 1")
+            .await
+            .map_err(into_error)?;
+        conn.query_opt(&s, &[&key])
+            .await
+            .map(|row| row.is_some())
+            .map_err(into_error)
+    }
+
+    pub(crate) async fn blob_size(&self, key: &[u8]) -> trc::Result<Option<u64>> {
         let conn = self.conn_pool.get().await.map_err(into_error)?;
         let s = conn
-            .prepare_cached("SELECT v FROM t WHERE k = $1")
+            .prepare_cached("SELECT octet_length(v) FROM t WHERE k = $1")
             .await
             .map_err(into_error)?;
         conn.query_opt(&s, &[&key])
             .await
             .and_then(|row| {
-                if let Some(row) = row {
-                    Ok(Some(if range.start == 0 && range.end == usize::MAX {
-                        row.try_get::<_, Vec<u8>>(0)?
-                    } else {
-                        let bytes = row.try_get::<_, &[u8]>(0)?;
-                        bytes
-                            .get(range.start..std::cmp::min(bytes.len(), range.end))
-                            .unwrap_or_default()
-                            .to_vec()
-                    }))
-                } else {
-                    Ok(None)
-                }
+                row.map(|row| row.try_get::<_, i32>(0).map(|size| size as u64))
+                    .transpose()
             })
             .map_err(into_error)
     }
@@ -53,6 +78,18 @@ impl PostgresStore {
             .map(|_| ())
     }
 
+    pub(crate) async fn put_blob_if_absent(&self, key: &[u8], data: &[u8]) -> trc::Result<bool> {
+        let conn = self.conn_pool.get().await.map_err(into_error)?;
+        let s = conn
+            .prepare_cached("INSERT INTO t (k, v) VALUES ($1, $2) ON CONFLICT (k) DO NOTHING")
+            .await
+            .map_err(into_error)?;
+        conn.execute(&s, &[&key, &data])
+            .await
+            .map_err(into_error)
+            .map(|hits| hits > 0)
+    }
+
     pub(crate) async fn delete_blob(&self, key: &[u8]) -> trc::Result<bool> {
         let conn = self.conn_pool.get().await.map_err(into_error)?;
         let s = conn
@@ -64,4 +101,16 @@ impl PostgresStore {
             .map_err(into_error)
             .map(|hits| hits > 0)
     }
+
+    pub(crate) async fn delete_blobs(&self, keys: &[Vec<u8>]) -> trc::Result<usize> {
+        let conn = self.conn_pool.get().await.map_err(into_error)?;
+        let s = conn
+            .prepare_cached("DELETE FROM t WHERE k = ANY($1)")
+            .await
+            .map_err(into_error)?;
+        conn.execute(&s, &[&keys])
+            .await
+            .map_err(into_error)
+            .map(|hits| hits as usize)
+    }
 }
@@ -8,7 +8,7 @@ use std::time::Duration;
 
 use crate::{backend::postgres::tls::MakeRustlsConnect, *};
 
-use super::{into_error, PostgresStore};
+use super::{into_error, notify::ChangeNotifier, PostgresStore};
 
 use deadpool_postgres::{Config, ManagerConfig, PoolConfig, RecyclingMethod, Runtime};
 use tokio_postgres::NoTls;
@@ -40,19 +40,47 @@ impl PostgresStore {
         if let Some(max_conn) = config.property::<usize>((&prefix, "pool.max-connections")) {
             cfg.pool = PoolConfig::new(max_conn).into();
         }
+        let tls = config
+            .property_or_default::<bool>((&prefix, "tls.enable"), "false")
+            .unwrap_or_default()
+            .then(|| {
+                MakeRustlsConnect::new(rustls_client_config(
+                    config
+                        .property_or_default((&prefix, "tls.allow-invalid-certs"), "false")
+                        .unwrap_or_default(),
+                ))
+            });
+
+        // Changes are notified to other nodes over a dedicated connection
+        let notifier = if config
+            .property_or_default::<bool>((&prefix, "notify.enable"), "false")
+            .unwrap_or_default()
+        {
+            ChangeNotifier {
+                channel: config
+                    .value((&prefix, "notify.channel"))
+                    .unwrap_or("stalwart_changes")
+                    .to_string(),
+                node_id: rand::random(),
+                config: cfg
+                    .get_pg_config()
+                    .map_err(|e| {
+                        config.new_build_error(
+                            prefix.as_str(),
+                            format!("Failed to build listener configuration: {e}"),
+                        )
+                    })
+                    .ok()?,
+                tls: tls.clone(),
+            }
+            .into()
+        } else {
+            None
+        };
+
         let db = Self {
-            conn_pool: if config
-                .property_or_default::<bool>((&prefix, "tls.enable"), "false")
-                .unwrap_or_default()
-            {
-                cfg.create_pool(
-                    Some(Runtime::Tokio1),
-                    MakeRustlsConnect::new(rustls_client_config(
-                        config
-                            .property_or_default((&prefix, "tls.allow-invalid-certs"), "false")
-                            .unwrap_or_default(),
-                    )),
-                )
+            conn_pool: if let Some(tls) = tls {
+                cfg.create_pool(Some(Runtime::Tokio1), tls)
             } else {
                 cfg.create_pool(Some(Runtime::Tokio1), NoTls)
             }
@@ -63,6 +91,8 @@ impl PostgresStore {
                 )
             })
             .ok()?,
+            notifier,
+            settings: Default::default(),
         };
 
         if create_tables {
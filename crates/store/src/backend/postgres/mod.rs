@@ -4,19 +4,24 @@
  * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
  */
 
-use std::fmt::Display;
+use std::{fmt::Display, sync::Arc};
 
 use deadpool_postgres::Pool;
 
+use crate::dispatch::settings::StoreSettings;
+
 pub mod blob;
 pub mod lookup;
 pub mod main;
+pub mod notify;
 pub mod read;
 pub mod tls;
 pub mod write;
 
 pub struct PostgresStore {
     pub(crate) conn_pool: Pool,
+    pub(crate) notifier: Option<notify::ChangeNotifier>,
+    pub(crate) settings: Arc<StoreSettings>,
 }
 
 #[inline(always)]
@@ -0,0 +1,129 @@
+/*
+ * SPDX-FileCopyrightText: 2020 Stalwart Labs Ltd <hello@stalw.art>
+ *
+ * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
+ */
+
+use std::time::Duration;
+
+use futures::{future, stream, StreamExt};
+use tokio::sync::mpsc;
+use tokio_postgres::{
+    tls::{MakeTlsConnect, TlsConnect},
+    AsyncMessage, NoTls, Socket, Transaction,
+};
+
+use crate::LogKey;
+
+use super::{into_error, tls::MakeRustlsConnect, PostgresStore};
+
+const LISTENER_CHANNEL_SIZE: usize = 1024;
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+// Changes are sent as `pg_notify` payloads made of the id of the node that
+// committed them followed by the log key of the change:
+//
+// node id:account id:collection:change id
+#[derive(Clone)]
+pub(crate) struct ChangeNotifier {
+    pub(crate) channel: String,
+    pub(crate) node_id: u64,
+    pub(crate) config: tokio_postgres::Config,
+    pub(crate) tls: Option<MakeRustlsConnect>,
+}
+
+impl PostgresStore {
+    /// Spawns a task listening for the changes committed by other nodes, which
+    /// reconnects whenever the listening connection is lost. Returns `None`
+    /// when change notifications are disabled.
+    pub(crate) fn listen_changes(&self) -> Option<mpsc::Receiver<LogKey>> {
+        let notifier = self.notifier.clone()?;
+        let (tx, rx) = mpsc::channel(LISTENER_CHANNEL_SIZE);
+
+        tokio::spawn(async move {
+            loop {
+                let result = match notifier.tls.clone() {
+                    Some(tls) => notifier.listen(tls, &tx).await,
+                    None => notifier.listen(NoTls, &tx).await,
+                };
+                if tx.is_closed() {
+                    break;
+                }
+                if let Err(err) = result {
+                    trc::error!(err
+                        .details("PostgreSQL change listener disconnected")
+                        .caused_by(trc::location!()));
+                }
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
+        });
+
+        Some(rx)
+    }
+}
+
+impl ChangeNotifier {
+    /// Queues a notification for a change, which PostgreSQL delivers to the
+    /// listeners once the transaction commits.
+    pub(crate) async fn notify(
+        &self,
+        trx: &Transaction<'_>,
+        change: &LogKey,
+    ) -> Result<(), tokio_postgres::Error> {
+        let payload = format!(
+            "{}:{}:{}:{}",
+            self.node_id, change.account_id, change.collection, change.change_id
+        );
+        let s = trx.prepare_cached("SELECT pg_notify($1, $2)").await?;
+        trx.execute(&s, &[&self.channel, &payload])
+            .await
+            .map(|_| ())
+    }
+
+    async fn listen<T>(&self, tls: T, tx: &mpsc::Sender<LogKey>) -> trc::Result<()>
+    where
+        T: MakeTlsConnect<Socket>,
+        T::Stream: Send + 'static,
+        T::TlsConnect: Send,
+        <T::TlsConnect as TlsConnect<Socket>>::Future: Send,
+    {
+        let (client, mut connection) = self.config.connect(tls).await.map_err(into_error)?;
+        let mut messages = stream::poll_fn(move |cx| connection.poll_message(cx));
+
+        // The connection has to be polled for the LISTEN command to complete
+        let listen = async {
+            client
+                .batch_execute(&format!("LISTEN \"{}\"", self.channel))
+                .await
+                .map_err(into_error)
+        };
+        let forward = async {
+            while let Some(message) = messages.next().await {
+                if let AsyncMessage::Notification(notification) = message.map_err(into_error)? {
+                    if let Some(change) = self.parse(notification.payload()) {
+                        if tx.send(change).await.is_err() {
+                            return Ok(());
+                        }
+                    }
+                }
+            }
+
+            Err(into_error("Connection closed"))
+        };
+
+        future::try_join(listen, forward).await.map(|_| ())
+    }
+
+    fn parse(&self, payload: &str) -> Option<LogKey> {
+        let mut parts = payload.split(':');
+        let node_id = parts.next()?.parse::<u64>().ok()?;
+        let change = LogKey {
+            account_id: parts.next()?.parse().ok()?,
+            collection: parts.next()?.parse().ok()?,
+            change_id: parts.next()?.parse().ok()?,
+        };
+
+        // Changes committed by this node are already known to it
+        (node_id != self.node_id && parts.next().is_none()).then_some(change)
+    }
+}
@@ -15,8 +15,8 @@ use tokio_postgres::{error::SqlState, IsolationLevel};
 
 use crate::{
     write::{
-        key::DeserializeBigEndian, AssignedIds, Batch, BitmapClass, Operation, RandomAvailableId,
-        ValueOp, MAX_COMMIT_ATTEMPTS, MAX_COMMIT_TIME,
+        clear_prefix_range, key::DeserializeBigEndian, AssignedIds, Batch, BitmapClass, Operation,
+        RandomAvailableId, ValueOp, MAX_APPEND_SIZE, MAX_COMMIT_ATTEMPTS, MAX_COMMIT_TIME,
     }, BitmapKey, IndexKey, Key, LogKey, SUBSPACE_COUNTER, SUBSPACE_IN_MEMORY_COUNTER, SUBSPACE_QUOTA, U32_LEN
 };
 
@@ -81,6 +81,7 @@ impl PostgresStore {
         let mut document_id = u32::MAX;
         let mut change_id = u64::MAX;
         let mut asserted_values = AHashMap::new();
+        let mut changes = Vec::new();
         let trx = conn
             .build_transaction()
             .isolation_level(IsolationLevel::ReadCommitted)
@@ -187,6 +188,28 @@ impl PostgresStore {
                                     .and_then(|row| row.try_get::<_, i64>(0))?,
                             );
                         }
+                        ValueOp::Append(data) => {
+                            let s = trx
+                                .prepare_cached(&format!(
+                                    concat!(
+                                        "INSERT INTO {} (k, v) VALUES ($1, $2) ",
+                                        "ON CONFLICT(k) DO UPDATE SET v = {}.v || EXCLUDED.v ",
+                                        "RETURNING octet_length(v)"
+                                    ),
+                                    table, table
+                                ))
+                                .await?;
+                            let size = trx
+                                .query_one(&s, &[&key, &data.as_slice()])
+                                .await
+                                .and_then(|row| row.try_get::<_, i32>(0))?
+                                as usize;
+                            if size > MAX_APPEND_SIZE {
+                                return Err(CommitError::Internal(
+                                    trc::StoreEvent::ValueTooLarge.ctx(trc::Key::Size, size),
+                                ));
+                            }
+                        }
                         ValueOp::Clear => {
                             let s = trx
                                 .prepare_cached(&format!("DELETE FROM {} WHERE k = $1", table))
@@ -251,7 +274,11 @@ impl PostgresStore {
                             }
                         }
 
-                        document_id = found_ids.random_available_id();
+                        document_id = found_ids.random_available_id(
+                            account_id,
+                            collection,
+                            self.settings.id_assignment_window(collection),
+                        );
                         result.push_document_id(document_id);
                     }
 
@@ -284,12 +311,15 @@ impl PostgresStore {
                     })?;
                 }
                 Operation::Log { set } => {
-                    let key = LogKey {
+                    let log_key = LogKey {
                         account_id,
                         collection,
                         change_id,
+                    };
+                    let key = log_key.serialize(0);
+                    if self.notifier.is_some() && !changes.contains(&log_key) {
+                        changes.push(log_key);
                     }
-                    .serialize(0);
 
                     let s = trx
                         .prepare_cached(concat!(
@@ -301,6 +331,21 @@ impl PostgresStore {
                     trx.execute(&s, &[&key, &set.resolve(&result)?.as_ref()])
                         .await?;
                 }
+                Operation::ClearPrefix {
+                    subspace,
+                    account_id: account_id_,
+                    collection: collection_,
+                } => {
+                    let (from, to) = clear_prefix_range(*subspace, *account_id_, *collection_, 0)?;
+
+                    let s = trx
+                        .prepare_cached(&format!(
+                            "DELETE FROM {} WHERE k >= $1 AND k < $2",
+                            char::from(*subspace)
+                        ))
+                        .await?;
+                    trx.execute(&s, &[&from, &to]).await?;
+                }
                 Operation::AssertValue {
                     class,
                     assert_value,
@@ -328,6 +373,12 @@ impl PostgresStore {
             }
         }
 
+        if let Some(notifier) = &self.notifier {
+            for change in &changes {
+                notifier.notify(&trx, change).await?;
+            }
+        }
+
         trx.commit().await.map(|_| result).map_err(Into::into)
     }
 
@@ -4,37 +4,116 @@
  * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
  */
 
-use std::ops::Range;
+use std::{
+    ops::Range,
+    sync::{Arc, Weak},
+    time::Duration,
+};
+
+use ahash::AHashMap;
+use parking_lot::Mutex;
+use rocksdb::{ErrorKind, MultiThreaded, OptimisticTransactionDB, WriteBatchWithTransaction};
+
+use crate::write::MAX_COMMIT_ATTEMPTS;
 
 use super::{into_error, RocksDbStore, CF_BLOBS};
 
+type BlobMap = AHashMap<Vec<u8>, Vec<u8>>;
+
+/// Blob writes kept in memory and written together as a single `WriteBatch`,
+/// which avoids the write amplification of many small writes during bulk
+/// imports. Only `put_blob_buffered` adds blobs to the batch. Pending blobs are
+/// written once they add up to `max_size` bytes, every `max_delay`, on
+/// `RocksDbStore::flush_blobs` and when the store is dropped, and are lost if
+/// the process dies before that.
+pub(crate) struct BlobBatch {
+    max_size: usize,
+    max_delay: Duration,
+    pending: Mutex<PendingBlobs>,
+    // Deletions wait for flushes in progress, otherwise a blob deleted while
+    // a batch is being written could be written back by it
+    flush_lock: tokio::sync::Mutex<()>,
+}
+
+#[derive(Default)]
+struct PendingBlobs {
+    blobs: BlobMap,
+    size: usize,
+    // Blobs being written, which remain visible to readers until the batch
+    // is committed
+    in_flight: Arc<BlobMap>,
+}
+
 impl RocksDbStore {
     pub(crate) async fn get_blob(
         &self,
         key: &[u8],
         range: Range<usize>,
     ) -> trc::Result<Option<Vec<u8>>> {
+        if let Some(data) = self
+            .blob_batch
+            .as_ref()
+            .and_then(|batch| batch.with_blob(key, |bytes| read_range(bytes, &range)))
+        {
+            return Ok(Some(data));
+        }
+
+        let db = self.db.clone();
+        self.spawn_worker(move || {
+            db.get_pinned_cf(&db.cf_handle(CF_BLOBS).unwrap(), key)
+                .map(|obj| obj.map(|bytes| read_range(&bytes, &range)))
+                .map_err(into_error)
+        })
+        .await
+    }
+
+    pub(crate) async fn blob_exists(&self, key: &[u8]) -> trc::Result<bool> {
+        if self
+            .blob_batch
+            .as_ref()
+            .is_some_and(|batch| batch.with_blob(key, |_| ()).is_some())
+        {
+            return Ok(true);
+        }
+
         let db = self.db.clone();
         self.spawn_worker(move || {
             db.get_pinned_cf(&db.cf_handle(CF_BLOBS).unwrap(), key)
-                .map(|obj| {
-                    obj.map(|bytes| {
-                        if range.start == 0 && range.end == usize::MAX {
-                            bytes.to_vec()
-                        } else {
-                            bytes
-                                .get(range.start..std::cmp::min(bytes.len(), range.end))
-                                .unwrap_or_default()
-                                .to_vec()
-                        }
-                    })
-                })
+                .map(|obj| obj.is_some())
+                .map_err(into_error)
+        })
+        .await
+    }
+
+    pub(crate) async fn blob_size(&self, key: &[u8]) -> trc::Result<Option<u64>> {
+        if let Some(size) = self
+            .blob_batch
+            .as_ref()
+            .and_then(|batch| batch.with_blob(key, |bytes| bytes.len() as u64))
+        {
+            return Ok(Some(size));
+        }
+
+        let db = self.db.clone();
+        self.spawn_worker(move || {
+            db.get_pinned_cf(&db.cf_handle(CF_BLOBS).unwrap(), key)
+                .map(|obj| obj.map(|bytes| bytes.len() as u64))
                 .map_err(into_error)
         })
         .await
     }
 
     pub(crate) async fn put_blob(&self, key: &[u8], data: &[u8]) -> trc::Result<()> {
+        // A buffered version of the blob would overwrite this one when flushed
+        let _flush_lock = match &self.blob_batch {
+            Some(batch) => {
+                let flush_lock = batch.flush_lock.lock().await;
+                batch.remove(key);
+                Some(flush_lock)
+            }
+            None => None,
+        };
+
         let db = self.db.clone();
         self.spawn_worker(move || {
             db.put_cf(&db.cf_handle(CF_BLOBS).unwrap(), key, data)
@@ -43,7 +122,67 @@ impl RocksDbStore {
         .await
     }
 
+    /// Buffers a blob when blob batching is enabled, otherwise writes it. The
+    /// blob is only durable after `flush_blobs`.
+    pub(crate) async fn put_blob_buffered(&self, key: &[u8], data: &[u8]) -> trc::Result<()> {
+        match &self.blob_batch {
+            Some(batch) if batch.insert(key, data) => batch.flush(&self.db).await,
+            Some(_) => Ok(()),
+            None => self.put_blob(key, data).await,
+        }
+    }
+
+    pub(crate) async fn put_blob_if_absent(&self, key: &[u8], data: &[u8]) -> trc::Result<bool> {
+        if self
+            .blob_batch
+            .as_ref()
+            .is_some_and(|batch| batch.with_blob(key, |_| ()).is_some())
+        {
+            return Ok(false);
+        }
+
+        let db = self.db.clone();
+        self.spawn_worker(move || {
+            let cf = db.cf_handle(CF_BLOBS).unwrap();
+            let mut retry_count = 0;
+
+            loop {
+                // Reading the key for update makes concurrent writers conflict on commit
+                let txn = db.transaction();
+                if txn
+                    .get_for_update_cf(&cf, key, true)
+                    .map_err(into_error)?
+                    .is_some()
+                {
+                    return Ok(false);
+                }
+                txn.put_cf(&cf, key, data).map_err(into_error)?;
+
+                match txn.commit() {
+                    Ok(_) => return Ok(true),
+                    Err(err)
+                        if matches!(err.kind(), ErrorKind::Busy | ErrorKind::TryAgain)
+                            && retry_count < MAX_COMMIT_ATTEMPTS =>
+                    {
+                        retry_count += 1;
+                    }
+                    Err(err) => return Err(into_error(err)),
+                }
+            }
+        })
+        .await
+    }
+
     pub(crate) async fn delete_blob(&self, key: &[u8]) -> trc::Result<bool> {
+        let _flush_lock = match &self.blob_batch {
+            Some(batch) => {
+                let flush_lock = batch.flush_lock.lock().await;
+                batch.remove(key);
+                Some(flush_lock)
+            }
+            None => None,
+        };
+
         let db = self.db.clone();
         self.spawn_worker(move || {
             db.delete_cf(&db.cf_handle(CF_BLOBS).unwrap(), key)
@@ -52,4 +191,141 @@ impl RocksDbStore {
         })
         .await
     }
+
+    /// Writes the blobs buffered when blob batching is enabled.
+    pub(crate) async fn flush_blobs(&self) -> trc::Result<()> {
+        match &self.blob_batch {
+            Some(batch) => batch.flush(&self.db).await,
+            None => Ok(()),
+        }
+    }
+}
+
+impl BlobBatch {
+    pub(crate) fn new(max_size: usize, max_delay: Duration) -> Self {
+        Self {
+            max_size,
+            max_delay,
+            pending: Mutex::new(PendingBlobs::default()),
+            flush_lock: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// Spawns a task writing the pending blobs every `max_delay`, which exits
+    /// once the store is dropped.
+    pub(crate) fn spawn_flusher(
+        self: &Arc<Self>,
+        db: &Arc<OptimisticTransactionDB<MultiThreaded>>,
+    ) {
+        let batch = Arc::downgrade(self);
+        let db = Arc::downgrade(db);
+        let max_delay = self.max_delay;
+
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(max_delay).await;
+                let (Some(batch), Some(db)) = (Weak::upgrade(&batch), Weak::upgrade(&db)) else {
+                    break;
+                };
+                if let Err(err) = batch.flush(&db).await {
+                    trc::error!(err
+                        .details("Failed to write blob batch")
+                        .caused_by(trc::location!()));
+                }
+            }
+        });
+    }
+
+    fn with_blob<T>(&self, key: &[u8], f: impl FnOnce(&[u8]) -> T) -> Option<T> {
+        let pending = self.pending.lock();
+        pending
+            .blobs
+            .get(key)
+            .or_else(|| pending.in_flight.get(key))
+            .map(|bytes| f(bytes))
+    }
+
+    /// Buffers a blob, returning whether the batch is due to be written.
+    fn insert(&self, key: &[u8], data: &[u8]) -> bool {
+        let mut pending = self.pending.lock();
+        if let Some(previous) = pending.blobs.insert(key.to_vec(), data.to_vec()) {
+            pending.size -= key.len() + previous.len();
+        }
+        pending.size += key.len() + data.len();
+        pending.size >= self.max_size
+    }
+
+    fn remove(&self, key: &[u8]) {
+        let mut pending = self.pending.lock();
+        if let Some(previous) = pending.blobs.remove(key) {
+            pending.size -= key.len() + previous.len();
+        }
+    }
+
+    async fn flush(&self, db: &Arc<OptimisticTransactionDB<MultiThreaded>>) -> trc::Result<()> {
+        let _flush_lock = self.flush_lock.lock().await;
+        let blobs = {
+            let mut pending = self.pending.lock();
+            if pending.blobs.is_empty() {
+                return Ok(());
+            }
+            pending.size = 0;
+            pending.in_flight = Arc::new(std::mem::take(&mut pending.blobs));
+            pending.in_flight.clone()
+        };
+
+        let db = db.clone();
+        let result = tokio::task::spawn_blocking(move || write_blobs(&db, &blobs))
+            .await
+            .map_err(|err| trc::EventType::Server(trc::ServerEvent::ThreadError).reason(err))
+            .and_then(|result| result);
+
+        // Blobs that could not be written are kept for the next flush, unless
+        // they were written again in the meantime
+        let mut pending = self.pending.lock();
+        let in_flight = std::mem::take(&mut pending.in_flight);
+        if result.is_err() {
+            for (key, data) in Arc::unwrap_or_clone(in_flight) {
+                if !pending.blobs.contains_key(&key) {
+                    pending.size += key.len() + data.len();
+                    pending.blobs.insert(key, data);
+                }
+            }
+        }
+
+        result
+    }
+
+    /// Writes the pending blobs from a synchronous context, used when the store
+    /// is dropped.
+    pub(crate) fn flush_blocking(&self, db: &OptimisticTransactionDB<MultiThreaded>) {
+        let blobs = std::mem::take(&mut self.pending.lock().blobs);
+        if !blobs.is_empty() {
+            if let Err(err) = write_blobs(db, &blobs) {
+                trc::error!(err
+                    .details("Failed to write blob batch")
+                    .caused_by(trc::location!()));
+            }
+        }
+    }
+}
+
+fn write_blobs(db: &OptimisticTransactionDB<MultiThreaded>, blobs: &BlobMap) -> trc::Result<()> {
+    let cf = db.cf_handle(CF_BLOBS).unwrap();
+    let mut batch = WriteBatchWithTransaction::<true>::default();
+    for (key, data) in blobs.iter() {
+        batch.put_cf(&cf, key, data);
+    }
+    db.write(batch).map_err(into_error)
+}
+
+fn read_range(bytes: &[u8], range: &Range<usize>) -> Vec<u8> {
+    if range.start == 0 && range.end == usize::MAX {
+        bytes.to_vec()
+    } else {
+        bytes
+            .get(range.start..std::cmp::min(bytes.len(), range.end))
+            .unwrap_or_default()
+            .to_vec()
+    }
 }
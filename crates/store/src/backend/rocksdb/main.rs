@@ -4,16 +4,18 @@
  * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
  */
 
-use std::path::PathBuf;
+use std::{path::PathBuf, sync::Arc, time::Duration};
 
-use rocksdb::{ColumnFamilyDescriptor, MergeOperands, OptimisticTransactionDB, Options};
+use rocksdb::{
+    ColumnFamilyDescriptor, DBCompressionType, MergeOperands, OptimisticTransactionDB, Options,
+};
 
 use tokio::sync::oneshot;
 use utils::config::{utils::AsKey, Config};
 
 use crate::*;
 
-use super::{RocksDbStore, CF_BLOBS};
+use super::{blob::BlobBatch, RocksDbStore, CF_BLOBS};
 
 impl RocksDbStore {
     pub async fn open(config: &mut Config, prefix: impl AsKey) -> Option<Self> {
@@ -59,7 +61,24 @@ impl RocksDbStore {
             ));
         }
 
-        // Blobs
+        // Blobs are kept in their own column family, with large values stored
+        // in blob files, so that their writes and compactions do not stall
+        // reads of the much smaller metadata values
+        let blob_compression = match config
+            .value((&prefix, "blob.compression"))
+            .unwrap_or("none")
+        {
+            "none" => DBCompressionType::None,
+            "lz4" => DBCompressionType::Lz4,
+            "zstd" => DBCompressionType::Zstd,
+            other => {
+                config.new_build_error(
+                    (&prefix, "blob.compression"),
+                    format!("Invalid blob compression type: {other:?}"),
+                );
+                return None;
+            }
+        };
         let mut cf_opts = Options::default();
         cf_opts.set_enable_blob_files(true);
         cf_opts.set_min_blob_size(
@@ -67,6 +86,18 @@ impl RocksDbStore {
                 .property_or_default((&prefix, "min-blob-size"), "16834")
                 .unwrap_or(16834),
         );
+        cf_opts.set_write_buffer_size(
+            config
+                .property_or_default((&prefix, "blob.write-buffer-size"), "268435456")
+                .unwrap_or(268435456),
+        );
+        cf_opts.set_max_write_buffer_number(
+            config
+                .property_or_default((&prefix, "blob.max-write-buffers"), "4")
+                .unwrap_or(4),
+        );
+        cf_opts.set_compression_type(blob_compression);
+        cf_opts.set_blob_compression_type(blob_compression);
         cfs.push(ColumnFamilyDescriptor::new(CF_BLOBS, cf_opts));
 
         // Other cfs
@@ -86,7 +117,6 @@ impl RocksDbStore {
             SUBSPACE_REPORT_IN,
             SUBSPACE_FTS_INDEX,
             SUBSPACE_LOGS,
-            SUBSPACE_BLOBS,
             SUBSPACE_TELEMETRY_SPAN,
             SUBSPACE_TELEMETRY_METRIC,
             SUBSPACE_TELEMETRY_INDEX,
@@ -113,16 +143,33 @@ impl RocksDbStore {
                 .unwrap_or(134217728),
         );
 
+        let db: Arc<_> = OptimisticTransactionDB::open_cf_descriptors(&db_opts, idx_path, cfs)
+            .map_err(|err| {
+                config.new_build_error(
+                    prefix.as_str(),
+                    format!("Failed to open database: {:?}", err),
+                )
+            })
+            .ok()?
+            .into();
+
+        // Blob writes are batched when a batch size is configured
+        let blob_batch = config
+            .property_or_default::<usize>((&prefix, "blob.batch.max-size"), "0")
+            .filter(|max_size| *max_size > 0)
+            .map(|max_size| {
+                let batch = Arc::new(BlobBatch::new(
+                    max_size,
+                    config
+                        .property_or_default::<Duration>((&prefix, "blob.batch.max-delay"), "1s")
+                        .unwrap_or(Duration::from_secs(1)),
+                ));
+                batch.spawn_flusher(&db);
+                batch
+            });
+
         Some(RocksDbStore {
-            db: OptimisticTransactionDB::open_cf_descriptors(&db_opts, idx_path, cfs)
-                .map_err(|err| {
-                    config.new_build_error(
-                        prefix.as_str(),
-                        format!("Failed to open database: {:?}", err),
-                    )
-                })
-                .ok()?
-                .into(),
+            db,
             worker_pool: rayon::ThreadPoolBuilder::new()
                 .num_threads(std::cmp::max(
                     config
@@ -139,6 +186,8 @@ impl RocksDbStore {
                     )
                 })
                 .ok()?,
+            blob_batch,
+            settings: Default::default(),
         })
     }
 
@@ -8,7 +8,9 @@ use std::sync::Arc;
 
 use rocksdb::{BoundColumnFamily, MultiThreaded, OptimisticTransactionDB};
 
-use crate::{SUBSPACE_BLOBS, SUBSPACE_INDEXES, SUBSPACE_LOGS};
+use crate::{
+    SUBSPACE_BLOBS, SUBSPACE_INDEXES, SUBSPACE_LOGS, dispatch::settings::StoreSettings,
+};
 
 pub mod blob;
 pub mod main;
@@ -35,6 +37,16 @@ impl CfHandle for OptimisticTransactionDB<MultiThreaded> {
 pub struct RocksDbStore {
     db: Arc<OptimisticTransactionDB<MultiThreaded>>,
     worker_pool: rayon::ThreadPool,
+    blob_batch: Option<Arc<blob::BlobBatch>>,
+    pub(crate) settings: Arc<StoreSettings>,
+}
+
+impl Drop for RocksDbStore {
+    fn drop(&mut self) {
+        if let Some(batch) = &self.blob_batch {
+            batch.flush_blocking(&self.db);
+        }
+    }
 }
 
 #[inline(always)]
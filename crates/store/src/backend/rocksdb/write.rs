@@ -22,19 +22,23 @@ use crate::{
     BitmapKey, Deserialize, IndexKey, Key, LogKey, SUBSPACE_COUNTER, SUBSPACE_IN_MEMORY_COUNTER,
     SUBSPACE_QUOTA, U32_LEN,
     backend::deserialize_i64_le,
+    dispatch::settings::StoreSettings,
     write::{
-        AssignedIds, Batch, BitmapClass, MAX_COMMIT_ATTEMPTS, MAX_COMMIT_TIME, Operation,
-        RandomAvailableId, ValueOp, key::DeserializeBigEndian,
+        AnyKey, AssignedIds, Batch, BitmapClass, MAX_APPEND_SIZE, MAX_COMMIT_ATTEMPTS,
+        MAX_COMMIT_TIME, Operation, RandomAvailableId, ValueOp, clear_prefix_range,
+        key::DeserializeBigEndian,
     },
 };
 
 impl RocksDbStore {
     pub(crate) async fn write(&self, batch: Batch) -> trc::Result<AssignedIds> {
         let db = self.db.clone();
+        let settings = self.settings.clone();
 
         self.spawn_worker(move || {
             let mut txn = RocksDBTransaction {
                 db: &db,
+                settings: &settings,
                 cf_indexes: db.cf_handle(CF_INDEXES).unwrap(),
                 cf_logs: db.cf_handle(CF_LOGS).unwrap(),
                 txn_opts: OptimisticTransactionOptions::default(),
@@ -82,6 +86,52 @@ impl RocksDbStore {
         .await
     }
 
+    /// Compacts each range, ranges without an end run to the end of their subspace.
+    pub(crate) async fn compact_ranges(
+        &self,
+        ranges: Vec<(AnyKey<Vec<u8>>, Option<AnyKey<Vec<u8>>>)>,
+    ) -> trc::Result<()> {
+        let db = self.db.clone();
+        self.spawn_worker(move || {
+            for (from, to) in &ranges {
+                db.compact_range_cf(
+                    &db.cf_handle(std::str::from_utf8(&[from.subspace]).unwrap())
+                        .unwrap(),
+                    Some(from.key.as_slice()),
+                    to.as_ref().map(|to| to.key.as_slice()),
+                );
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    pub(crate) async fn storage_size(&self) -> trc::Result<u64> {
+        let db = self.db.clone();
+        self.spawn_worker(move || {
+            let mut size = 0u64;
+            // Column families are named after their subspace, which is an ASCII character
+            for subspace in 0..0x80u8 {
+                let cf = match db.cf_handle(std::str::from_utf8(&[subspace]).unwrap()) {
+                    Some(cf) => cf,
+                    None => continue,
+                };
+                for property in [
+                    "rocksdb.total-sst-files-size",
+                    "rocksdb.size-all-mem-tables",
+                ] {
+                    size = db
+                        .property_int_value_cf(&cf, property)
+                        .map_err(into_error)?
+                        .unwrap_or_default()
+                        .saturating_add(size);
+                }
+            }
+            Ok(size)
+        })
+        .await
+    }
+
     pub(crate) async fn purge_store(&self) -> trc::Result<()> {
         let db = self.db.clone();
         self.spawn_worker(move || {
@@ -125,6 +175,7 @@ impl RocksDbStore {
 
 struct RocksDBTransaction<'x> {
     db: &'x OptimisticTransactionDB,
+    settings: &'x StoreSettings,
     cf_indexes: Arc<BoundColumnFamily<'x>>,
     cf_logs: Arc<BoundColumnFamily<'x>>,
     txn_opts: OptimisticTransactionOptions,
@@ -198,6 +249,18 @@ impl RocksDBTransaction<'_> {
                             txn.put_cf(&cf, &key, &num.to_le_bytes()[..])?;
                             result.push_counter_id(num);
                         }
+                        ValueOp::Append(data) => {
+                            let mut value =
+                                txn.get_for_update_cf(&cf, &key, true)?.unwrap_or_default();
+                            value.extend_from_slice(data);
+                            if value.len() > MAX_APPEND_SIZE {
+                                txn.rollback()?;
+                                return Err(CommitError::Internal(
+                                    trc::StoreEvent::ValueTooLarge.ctx(trc::Key::Size, value.len()),
+                                ));
+                            }
+                            txn.put_cf(&cf, &key, &value)?;
+                        }
                         ValueOp::Clear => {
                             txn.delete_cf(&cf, &key)?;
                         }
@@ -255,7 +318,11 @@ impl RocksDBTransaction<'_> {
                             }
                         }
 
-                        document_id = found_ids.random_available_id();
+                        document_id = found_ids.random_available_id(
+                            account_id,
+                            collection,
+                            self.settings.id_assignment_window(collection),
+                        );
                         result.push_document_id(document_id);
                     }
                     let key =
@@ -277,6 +344,28 @@ impl RocksDBTransaction<'_> {
 
                     txn.put_cf(&self.cf_logs, &key, set.resolve(&result)?.as_ref())?;
                 }
+                Operation::ClearPrefix {
+                    subspace,
+                    account_id: account_id_,
+                    collection: collection_,
+                } => {
+                    let (from, to) = clear_prefix_range(*subspace, *account_id_, *collection_, 0)?;
+                    let cf = self.db.subspace_handle(*subspace);
+
+                    // Optimistic transactions do not support range deletes
+                    let mut keys = Vec::new();
+                    for row in txn.iterator_cf(&cf, IteratorMode::From(&from, Direction::Forward)) {
+                        let (key, _) = row?;
+                        if key.as_ref() < to.as_slice() {
+                            keys.push(key);
+                        } else {
+                            break;
+                        }
+                    }
+                    for key in keys {
+                        txn.delete_cf(&cf, key)?;
+                    }
+                }
                 Operation::AssertValue {
                     class,
                     assert_value,
@@ -6,16 +6,41 @@
 
 use std::{fmt::Display, io::Write, ops::Range, time::Duration};
 
-use s3::{creds::Credentials, Bucket, Region};
+use futures::{stream, StreamExt};
+use s3::{creds::Credentials, serde_types::Part, Bucket, Region};
 use utils::{
-    codec::base32_custom::Base32Writer,
-    config::{utils::AsKey, Config},
+    codec::base32_custom::{Base32Reader, Base32Writer},
+    config::{
+        utils::{AsKey, ParseValue},
+        Config,
+    },
 };
 
+use crate::dispatch::manifest::KeyPages;
+
+const MIN_PART_SIZE: usize = 5 * 1024 * 1024;
+const CONTENT_TYPE: &str = "application/octet-stream";
+
 pub struct S3Store {
     bucket: Bucket,
+    // Same bucket with the server-side encryption headers, which S3 rejects
+    // on reads of KMS encrypted objects so they are only sent on uploads
+    upload_bucket: Bucket,
+    // Upload bucket sending `If-None-Match: *`, used by conditional writes
+    create_bucket: Bucket,
     prefix: Option<String>,
     max_retries: u32,
+    // Objects written before sharding was enabled keep their unsharded name,
+    // reads and deletes fall back to it so no migration is needed
+    key_shards: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ServerSideEncryption {
+    #[default]
+    None,
+    Aes256,
+    Kms,
 }
 
 impl S3Store {
@@ -48,27 +73,64 @@ impl S3Store {
         let timeout = config
             .property_or_default::<Duration>((&prefix, "timeout"), "30s")
             .unwrap_or_else(|| Duration::from_secs(30));
+        let sse = config
+            .property_or_default::<ServerSideEncryption>((&prefix, "sse.mode"), "none")
+            .unwrap_or_default();
+        let kms_key_id = config
+            .value((&prefix, "sse.kms-key-id"))
+            .map(|s| s.to_string());
+        if kms_key_id.is_some() && sse != ServerSideEncryption::Kms {
+            config.new_build_error(
+                (&prefix, "sse.kms-key-id"),
+                "A KMS key id can only be used with the aws:kms encryption mode",
+            );
+            return None;
+        }
+
+        let bucket = Bucket::new(
+            config.value_require((&prefix, "bucket"))?,
+            region,
+            credentials,
+        )
+        .map_err(|err| {
+            config.new_build_error(prefix.as_str(), format!("Failed to create bucket: {err:?}"))
+        })
+        .ok()?
+        .with_path_style()
+        .with_request_timeout(timeout)
+        .map_err(|err| {
+            config.new_build_error(prefix.as_str(), format!("Failed to create bucket: {err:?}"))
+        })
+        .ok()?;
+        let mut upload_bucket = bucket.clone();
+        match sse {
+            ServerSideEncryption::None => {}
+            ServerSideEncryption::Aes256 => {
+                upload_bucket.add_header("x-amz-server-side-encryption", "AES256");
+            }
+            ServerSideEncryption::Kms => {
+                upload_bucket.add_header("x-amz-server-side-encryption", "aws:kms");
+                if let Some(kms_key_id) = &kms_key_id {
+                    upload_bucket
+                        .add_header("x-amz-server-side-encryption-aws-kms-key-id", kms_key_id);
+                }
+            }
+        }
+
+        let mut create_bucket = upload_bucket.clone();
+        create_bucket.add_header("If-None-Match", "*");
 
         Some(S3Store {
-            bucket: Bucket::new(
-                config.value_require((&prefix, "bucket"))?,
-                region,
-                credentials,
-            )
-            .map_err(|err| {
-                config.new_build_error(prefix.as_str(), format!("Failed to create bucket: {err:?}"))
-            })
-            .ok()?
-            .with_path_style()
-            .with_request_timeout(timeout)
-            .map_err(|err| {
-                config.new_build_error(prefix.as_str(), format!("Failed to create bucket: {err:?}"))
-            })
-            .ok()?,
+            bucket,
+            upload_bucket,
+            create_bucket,
             max_retries: config
                 .property_or_default((&prefix, "max-retries"), "3")
                 .unwrap_or(3),
             prefix: config.value((&prefix, "key-prefix")).map(|s| s.to_string()),
+            key_shards: config
+                .property_or_default((&prefix, "key-shards"), "0")
+                .unwrap_or(0),
         })
     }
 
@@ -77,20 +139,29 @@ impl S3Store {
         key: &[u8],
         range: Range<usize>,
     ) -> trc::Result<Option<Vec<u8>>> {
-        let path = self.build_key(key);
+        match self.get_object(&self.build_key(key), range.clone()).await? {
+            None => match self.unsharded_key(key) {
+                Some(path) => self.get_object(&path, range).await,
+                None => Ok(None),
+            },
+            found => Ok(found),
+        }
+    }
+
+    async fn get_object(&self, path: &str, range: Range<usize>) -> trc::Result<Option<Vec<u8>>> {
         let mut retries_left = self.max_retries;
 
         loop {
             let response = if range.start != 0 || range.end != usize::MAX {
                 self.bucket
                     .get_object_range(
-                        &path,
+                        path,
                         range.start as u64,
                         Some(range.end.saturating_sub(1) as u64),
                     )
                     .await
             } else {
-                self.bucket.get_object(&path).await
+                self.bucket.get_object(path).await
             }
             .map_err(into_error)?;
 
@@ -107,10 +178,55 @@ impl S3Store {
                     retries_left -= 1;
                 }
                 code => {
-                    return Err(trc::StoreEvent::S3Error
-                        .reason(String::from_utf8_lossy(response.as_slice()))
-                        .ctx(trc::Key::Code, code))
+                    return Err(status_error(
+                        code,
+                        String::from_utf8_lossy(response.as_slice()),
+                    ))
+                }
+            }
+        }
+    }
+
+    pub(crate) async fn blob_exists(&self, key: &[u8]) -> trc::Result<bool> {
+        self.blob_size(key).await.map(|size| size.is_some())
+    }
+
+    pub(crate) async fn blob_size(&self, key: &[u8]) -> trc::Result<Option<u64>> {
+        match self.object_size(&self.build_key(key)).await? {
+            None => match self.unsharded_key(key) {
+                Some(path) => self.object_size(&path).await,
+                None => Ok(None),
+            },
+            found => Ok(found),
+        }
+    }
+
+    async fn object_size(&self, path: &str) -> trc::Result<Option<u64>> {
+        let mut retries_left = self.max_retries;
+
+        loop {
+            let (head, status_code) = self.bucket.head_object(path).await.map_err(into_error)?;
+
+            match status_code {
+                200..=299 => {
+                    return head
+                        .content_length
+                        .map(|size| Some(size as u64))
+                        .ok_or_else(|| {
+                            trc::StoreEvent::S3Error.reason("Missing HeadObject content length")
+                        })
+                }
+                404 => return Ok(None),
+                500..=599 if retries_left > 0 => {
+                    // wait backoff
+                    tokio::time::sleep(Duration::from_secs(
+                        1 << (self.max_retries - retries_left).min(6),
+                    ))
+                    .await;
+
+                    retries_left -= 1;
                 }
+                code => return Err(status_error(code, "Unexpected HeadObject response")),
             }
         }
     }
@@ -120,7 +236,7 @@ impl S3Store {
 
         loop {
             let response = self
-                .bucket
+                .upload_bucket
                 .put_object(self.build_key(key), data)
                 .await
                 .map_err(into_error)?;
@@ -137,24 +253,90 @@ impl S3Store {
                     retries_left -= 1;
                 }
                 code => {
-                    return Err(trc::StoreEvent::S3Error
-                        .reason(String::from_utf8_lossy(response.as_slice()))
-                        .ctx(trc::Key::Code, code))
+                    return Err(status_error(
+                        code,
+                        String::from_utf8_lossy(response.as_slice()),
+                    ))
                 }
             }
         }
     }
 
-    pub(crate) async fn delete_blob(&self, key: &[u8]) -> trc::Result<bool> {
+    pub(crate) async fn put_blob_if_absent(&self, key: &[u8], data: &[u8]) -> trc::Result<bool> {
+        // Unsharded objects are no longer created, so checking for them before
+        // the conditional write does not race with other writers
+        if let Some(path) = self.unsharded_key(key) {
+            if self.object_size(&path).await?.is_some() {
+                return Ok(false);
+            }
+        }
+
         let mut retries_left = self.max_retries;
 
         loop {
             let response = self
-                .bucket
-                .delete_object(self.build_key(key))
+                .create_bucket
+                .put_object(self.build_key(key), data)
                 .await
                 .map_err(into_error)?;
 
+            match response.status_code() {
+                200..=299 => return Ok(true),
+                412 => return Ok(false),
+                // 409 is returned while a conditional write to the same key is in
+                // progress, retrying tells whether it succeeded
+                409 | 500..=599 if retries_left > 0 => {
+                    // wait backoff
+                    tokio::time::sleep(Duration::from_secs(
+                        1 << (self.max_retries - retries_left).min(6),
+                    ))
+                    .await;
+
+                    retries_left -= 1;
+                }
+                code => {
+                    return Err(status_error(
+                        code,
+                        String::from_utf8_lossy(response.as_slice()),
+                    ))
+                }
+            }
+        }
+    }
+
+    /// Starts writing a blob in chunks as a multipart upload.
+    pub(crate) async fn begin_upload(&self, key: &[u8]) -> trc::Result<S3Upload<'_>> {
+        let path = self.build_key(key);
+        let upload_id = self
+            .upload_bucket
+            .initiate_multipart_upload(&path, CONTENT_TYPE)
+            .await
+            .map_err(into_error)?
+            .upload_id;
+
+        Ok(S3Upload {
+            store: self,
+            path,
+            upload_id,
+            parts: Vec::new(),
+            part: Vec::with_capacity(MIN_PART_SIZE),
+        })
+    }
+
+    pub(crate) async fn delete_blob(&self, key: &[u8]) -> trc::Result<bool> {
+        let deleted = self.delete_object(&self.build_key(key)).await?;
+        match self.unsharded_key(key) {
+            Some(path) => Ok(self.delete_object(&path).await? || deleted),
+            None => Ok(deleted),
+        }
+    }
+
+    async fn delete_object(&self, path: &str) -> trc::Result<bool> {
+        let mut retries_left = self.max_retries;
+
+        loop {
+            let response = self.bucket.delete_object(path).await.map_err(into_error)?;
+
             match response.status_code() {
                 200..=299 => return Ok(true),
                 404 => return Ok(false),
@@ -168,28 +350,234 @@ impl S3Store {
                     retries_left -= 1;
                 }
                 code => {
-                    return Err(trc::StoreEvent::S3Error
-                        .reason(String::from_utf8_lossy(response.as_slice()))
-                        .ctx(trc::Key::Code, code))
+                    return Err(status_error(
+                        code,
+                        String::from_utf8_lossy(response.as_slice()),
+                    ))
                 }
             }
         }
     }
 
-    fn build_key(&self, key: &[u8]) -> String {
-        if let Some(prefix) = &self.prefix {
-            let mut writer =
-                Base32Writer::with_raw_capacity(prefix.len() + ((key.len() + 3) / 4 * 5));
+    /// Lists the blobs starting with `prefix`, one page per listing request.
+    pub(crate) fn list_blob_pages(&self, prefix: Vec<u8>) -> KeyPages<'_> {
+        let key_prefix = self.prefix.as_deref().unwrap_or_default();
+
+        // Only the characters encoding whole bits of the prefix can be matched
+        // by the listing, the remaining bits are checked once decoded
+        let mut name_prefix = Base32Writer::from_bytes(&prefix).finalize();
+        name_prefix.truncate(prefix.len() * 8 / 5);
+        let mut list_prefixes = vec![format!("{key_prefix}{name_prefix}")];
+        if self.key_shards > 1 {
+            list_prefixes.extend(
+                (0..self.key_shards as u64)
+                    .map(|shard| format!("{key_prefix}{}{name_prefix}", self.shard_name(shard))),
+            );
+        }
+
+        stream::try_unfold(
+            (
+                list_prefixes.into_iter().enumerate(),
+                None::<(usize, String, Option<String>)>,
+            ),
+            move |(mut list_prefixes, listing)| {
+                let prefix = prefix.clone();
+                async move {
+                    let Some((pos, list_prefix, continuation_token)) = listing.or_else(|| {
+                        list_prefixes
+                            .next()
+                            .map(|(pos, list_prefix)| (pos, list_prefix, None))
+                    }) else {
+                        return Ok(None);
+                    };
+                    let (page, _) = self
+                        .bucket
+                        .list_page(list_prefix.clone(), None, continuation_token, None, None)
+                        .await
+                        .map_err(into_error)?;
+
+                    let mut keys = Vec::new();
+                    for object in page.contents {
+                        if let Some(name) = object.key.strip_prefix(key_prefix) {
+                            // Sharded objects are only taken from the listings of
+                            // their shard, unsharded ones from the first listing
+                            let name = match name.split_once('/') {
+                                Some((_, name)) if pos > 0 => name,
+                                None if pos == 0 => name,
+                                _ => continue,
+                            };
+                            let key = Base32Reader::new(name.as_bytes()).collect::<Vec<_>>();
+                            if key.starts_with(&prefix) {
+                                keys.push(key);
+                            }
+                        }
+                    }
+                    let listing = page
+                        .next_continuation_token
+                        .filter(|_| page.is_truncated)
+                        .map(|token| (pos, list_prefix, Some(token)));
+
+                    Ok(Some((keys, (list_prefixes, listing))))
+                }
+            },
+        )
+        .boxed()
+    }
+
+    /// Returns the object name of a blob key, including the shard prefix
+    /// when key hashing is enabled.
+    pub fn build_key(&self, key: &[u8]) -> String {
+        self.build_path(key, self.key_shard(key).as_deref())
+    }
+
+    /// Returns the object name a blob key had before key hashing was enabled,
+    /// or `None` if it is disabled.
+    pub fn unsharded_key(&self, key: &[u8]) -> Option<String> {
+        if self.key_shards > 1 {
+            Some(self.build_path(key, None))
+        } else {
+            None
+        }
+    }
+
+    fn build_path(&self, key: &[u8], shard: Option<&str>) -> String {
+        if self.prefix.is_some() || shard.is_some() {
+            let prefix = self.prefix.as_deref().unwrap_or_default();
+            let shard = shard.unwrap_or_default();
+            let mut writer = Base32Writer::with_raw_capacity(
+                prefix.len() + shard.len() + ((key.len() + 3) / 4 * 5),
+            );
             writer.push_string(prefix);
+            writer.push_string(shard);
             writer.write_all(key).unwrap();
             writer.finalize()
         } else {
             Base32Writer::from_bytes(key).finalize()
         }
     }
+
+    // Keys sharing a long common prefix (such as the account id) are spread
+    // across S3 partitions by prepending a shard derived from the whole key.
+    fn key_shard(&self, key: &[u8]) -> Option<String> {
+        if self.key_shards > 1 {
+            Some(self.shard_name(xxhash_rust::xxh3::xxh3_64(key) % self.key_shards as u64))
+        } else {
+            None
+        }
+    }
+
+    fn shard_name(&self, shard: u64) -> String {
+        let width = format!("{:x}", self.key_shards - 1).len();
+        format!("{shard:0width$x}/")
+    }
+}
+
+impl ParseValue for ServerSideEncryption {
+    fn parse_value(value: &str) -> Result<Self, String> {
+        match value {
+            "none" => Ok(ServerSideEncryption::None),
+            "aes256" => Ok(ServerSideEncryption::Aes256),
+            "aws:kms" => Ok(ServerSideEncryption::Kms),
+            mode => Err(format!("Invalid server-side encryption mode: {mode}")),
+        }
+    }
+}
+
+// Requests that timed out or could not reach the service can be retried
+/// Blob being written as a multipart upload. Chunks are merged into parts of at
+/// least `MIN_PART_SIZE` bytes, the smallest size S3 accepts for all parts but
+/// the last one, and each part is sent as soon as it is complete.
+pub(crate) struct S3Upload<'x> {
+    store: &'x S3Store,
+    path: String,
+    upload_id: String,
+    parts: Vec<Part>,
+    part: Vec<u8>,
 }
 
-#[inline(always)]
-fn into_error(err: impl Display) -> trc::Error {
-    trc::StoreEvent::S3Error.reason(err)
+impl S3Upload<'_> {
+    pub(crate) async fn write(&mut self, data: &[u8]) -> trc::Result<()> {
+        self.part.extend_from_slice(data);
+        if self.part.len() >= MIN_PART_SIZE {
+            self.send_part().await
+        } else {
+            Ok(())
+        }
+    }
+
+    pub(crate) async fn finish(mut self) -> trc::Result<()> {
+        // The last part can be smaller, and there has to be at least one
+        let result = if !self.part.is_empty() || self.parts.is_empty() {
+            self.send_part().await
+        } else {
+            Ok(())
+        };
+        let result = match result {
+            Ok(_) => self
+                .store
+                .bucket
+                .complete_multipart_upload(
+                    &self.path,
+                    &self.upload_id,
+                    std::mem::take(&mut self.parts),
+                )
+                .await
+                .map_err(into_error)
+                .map(|_| ()),
+            Err(err) => Err(err),
+        };
+
+        if result.is_err() {
+            self.abort().await;
+        }
+
+        result
+    }
+
+    pub(crate) async fn abort(self) {
+        let _ = self
+            .store
+            .bucket
+            .abort_upload(&self.path, &self.upload_id)
+            .await;
+    }
+
+    async fn send_part(&mut self) -> trc::Result<()> {
+        // Encryption headers are only accepted when initiating the upload
+        let part = self
+            .store
+            .bucket
+            .put_multipart_chunk(
+                std::mem::replace(&mut self.part, Vec::with_capacity(MIN_PART_SIZE)),
+                &self.path,
+                self.parts.len() as u32 + 1,
+                &self.upload_id,
+                CONTENT_TYPE,
+            )
+            .await
+            .map_err(into_error)?;
+        self.parts.push(part);
+        Ok(())
+    }
+}
+
+fn into_error(err: s3::error::S3Error) -> trc::Error {
+    match &err {
+        s3::error::S3Error::Reqwest(error) if error.is_timeout() || error.is_connect() => {
+            trc::StoreEvent::BlobUnavailable.reason(err)
+        }
+        _ => trc::StoreEvent::S3Error.reason(err),
+    }
+}
+
+// Server errors are only returned once retries are exhausted, so the service is
+// unavailable rather than the request invalid
+fn status_error(code: u16, reason: impl Display) -> trc::Error {
+    if (500..=599).contains(&code) {
+        trc::StoreEvent::BlobUnavailable
+    } else {
+        trc::StoreEvent::S3Error
+    }
+    .reason(reason)
+    .ctx(trc::Key::Code, code)
 }
@@ -8,6 +8,8 @@ use std::ops::Range;
 
 use rusqlite::OptionalExtension;
 
+use crate::backend::{sql_range, MAX_DELETE_BLOBS};
+
 use super::{into_error, SqliteStore};
 
 impl SqliteStore {
@@ -18,24 +20,48 @@ impl SqliteStore {
     ) -> trc::Result<Option<Vec<u8>>> {
         let conn = self.conn_pool.get().map_err(into_error)?;
         self.spawn_worker(move || {
-            let mut result = conn
-                .prepare_cached("SELECT v FROM t WHERE k = ?")
-                .map_err(into_error)?;
-            result
-                .query_row([&key], |row| {
-                    Ok({
-                        let bytes = row.get_ref(0)?.as_bytes()?;
-                        if range.start == 0 && range.end == usize::MAX {
-                            bytes.to_vec()
-                        } else {
-                            bytes
-                                .get(range.start..std::cmp::min(bytes.len(), range.end))
-                                .unwrap_or_default()
-                                .to_vec()
-                        }
+            if range.start == 0 && range.end == usize::MAX {
+                conn.prepare_cached("SELECT v FROM t WHERE k = ?")
+                    .map_err(into_error)?
+                    .query_row([&key], |row| Ok(row.get_ref(0)?.as_bytes()?.to_vec()))
+            } else {
+                // Only the requested range is read from the row, substr is 1-based
+                let (start, len) = sql_range(&range, i64::MAX as usize);
+                conn.prepare_cached("SELECT substr(v, ?, ?) FROM t WHERE k = ?")
+                    .map_err(into_error)?
+                    .query_row(rusqlite::params![start as i64, len as i64, key], |row| {
+                        Ok(row
+                            .get_ref(0)?
+                            .as_bytes_or_null()?
+                            .unwrap_or_default()
+                            .to_vec())
                     })
-                })
+            }
+            .optional()
+            .map_err(into_error)
+        })
+        .await
+    }
+
+    pub(crate) async fn blob_exists(&self, key: &[u8]) -> trc::Result<bool> {
+        let conn = self.conn_pool.get().map_err(into_error)?;
+        self.spawn_worker(move || {
+            conn.prepare_cached("SELECT 1 FROM t WHERE k = ? LIMIT 1")
+                .map_err(into_error)?
+                .exists([key])
+                .map_err(into_error)
+        })
+        .await
+    }
+
+    pub(crate) async fn blob_size(&self, key: &[u8]) -> trc::Result<Option<u64>> {
+        let conn = self.conn_pool.get().map_err(into_error)?;
+        self.spawn_worker(move || {
+            conn.prepare_cached("SELECT LENGTH(v) FROM t WHERE k = ?")
+                .map_err(into_error)?
+                .query_row([&key], |row| row.get::<_, i64>(0))
                 .optional()
+                .map(|size| size.map(|size| size as u64))
                 .map_err(into_error)
         })
         .await
@@ -53,6 +79,18 @@ impl SqliteStore {
         .await
     }
 
+    pub(crate) async fn put_blob_if_absent(&self, key: &[u8], data: &[u8]) -> trc::Result<bool> {
+        let conn = self.conn_pool.get().map_err(into_error)?;
+        self.spawn_worker(move || {
+            conn.prepare_cached("INSERT INTO t (k, v) VALUES (?, ?) ON CONFLICT (k) DO NOTHING")
+                .map_err(into_error)?
+                .execute([key, data])
+                .map_err(into_error)
+                .map(|rows| rows > 0)
+        })
+        .await
+    }
+
     pub(crate) async fn delete_blob(&self, key: &[u8]) -> trc::Result<bool> {
         let conn = self.conn_pool.get().map_err(into_error)?;
         self.spawn_worker(move || {
@@ -64,4 +102,23 @@ impl SqliteStore {
         })
         .await
     }
+
+    pub(crate) async fn delete_blobs(&self, keys: &[Vec<u8>]) -> trc::Result<usize> {
+        let conn = self.conn_pool.get().map_err(into_error)?;
+        self.spawn_worker(move || {
+            let mut deleted = 0;
+            for keys in keys.chunks(MAX_DELETE_BLOBS) {
+                deleted += conn
+                    .prepare(&format!(
+                        "DELETE FROM t WHERE k IN ({})",
+                        vec!["?"; keys.len()].join(", ")
+                    ))
+                    .map_err(into_error)?
+                    .execute(rusqlite::params_from_iter(keys))
+                    .map_err(into_error)?;
+            }
+            Ok(deleted)
+        })
+        .await
+    }
 }
@@ -26,6 +26,7 @@ impl SqliteStore {
                     SqliteConnectionManager::file(config.value_require((&prefix, "path"))?)
                         .with_init(|c| {
                             c.execute_batch(concat!(
+                                "PRAGMA auto_vacuum = INCREMENTAL; ",
                                 "PRAGMA journal_mode = WAL; ",
                                 "PRAGMA synchronous = NORMAL; ",
                                 "PRAGMA temp_store = memory;",
@@ -56,6 +57,7 @@ impl SqliteStore {
                     )
                 })
                 .ok()?,
+            settings: Default::default(),
         };
 
         if let Err(err) = db.create_tables() {
@@ -80,6 +82,7 @@ impl SqliteStore {
                 .map_err(|err| {
                     into_error(err).ctx(trc::Key::Reason, "Failed to build worker pool")
                 })?,
+            settings: Default::default(),
         };
         db.create_tables()?;
         Ok(db)
@@ -4,10 +4,12 @@
  * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
  */
 
-use std::fmt::Display;
+use std::{fmt::Display, sync::Arc};
 
 use r2d2::Pool;
 
+use crate::dispatch::settings::StoreSettings;
+
 use self::pool::SqliteConnectionManager;
 
 pub mod blob;
@@ -20,6 +22,7 @@ pub mod write;
 pub struct SqliteStore {
     pub(crate) conn_pool: Pool<SqliteConnectionManager>,
     pub(crate) worker_pool: rayon::ThreadPool,
+    pub(crate) settings: Arc<StoreSettings>,
 }
 
 #[inline(always)]
@@ -4,15 +4,26 @@
  * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
  */
 
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+use r2d2::PooledConnection;
 use roaring::RoaringBitmap;
-use rusqlite::OptionalExtension;
+use rusqlite::{Connection, OptionalExtension};
 
 use crate::{
     write::{key::DeserializeBigEndian, BitmapClass, ValueClass},
     BitmapKey, Deserialize, IterateParams, Key, ValueKey, U32_LEN,
 };
 
-use super::{into_error, SqliteStore};
+use super::{into_error, pool::SqliteConnectionManager, SqliteStore};
+
+/// Read transaction held open on a dedicated connection, so that all reads
+/// see the database as it was when the snapshot was taken.
+pub struct SqliteSnapshot {
+    store: Arc<SqliteStore>,
+    conn: Mutex<PooledConnection<SqliteConnectionManager>>,
+}
 
 impl SqliteStore {
     pub(crate) async fn get_value<U>(&self, key: impl Key) -> trc::Result<Option<U>>
@@ -20,56 +31,16 @@ impl SqliteStore {
         U: Deserialize + 'static,
     {
         let conn = self.conn_pool.get().map_err(into_error)?;
-        self.spawn_worker(move || {
-            let mut result = conn
-                .prepare_cached(&format!(
-                    "SELECT v FROM {} WHERE k = ?",
-                    char::from(key.subspace())
-                ))
-                .map_err(into_error)?;
-            let key = key.serialize(0);
-            result
-                .query_row([&key], |row| {
-                    U::deserialize(row.get_ref(0)?.as_bytes()?)
-                        .map_err(|err| rusqlite::Error::ToSqlConversionFailure(err.into()))
-                })
-                .optional()
-                .map_err(into_error)
-        })
-        .await
+        self.spawn_worker(move || get_value(&conn, &key)).await
     }
 
     pub(crate) async fn get_bitmap(
         &self,
-        mut key: BitmapKey<BitmapClass<u32>>,
+        key: BitmapKey<BitmapClass<u32>>,
     ) -> trc::Result<Option<RoaringBitmap>> {
-        let begin = key.serialize(0);
-        key.document_id = u32::MAX;
-        let key_len = begin.len();
-        let end = key.serialize(0);
         let conn = self.conn_pool.get().map_err(into_error)?;
-        let table = char::from(key.subspace());
-
-        self.spawn_worker(move || {
-            let mut bm = RoaringBitmap::new();
-            let mut query = conn
-                .prepare_cached(&format!("SELECT k FROM {table} WHERE k >= ? AND k <= ?"))
-                .map_err(into_error)?;
-            let mut rows = query.query([&begin, &end]).map_err(into_error)?;
-
-            while let Some(row) = rows.next().map_err(into_error)? {
-                let key = row
-                    .get_ref(0)
-                    .map_err(into_error)?
-                    .as_bytes()
-                    .map_err(into_error)?;
-                if key.len() == key_len {
-                    bm.insert(key.deserialize_be_u32(key.len() - U32_LEN)?);
-                }
-            }
-            Ok(if !bm.is_empty() { Some(bm) } else { None })
-        })
-        .await
+        self.spawn_worker(move || get_bitmap(&conn, key.clone()))
+            .await
     }
 
     pub(crate) async fn iterate<T: Key>(
@@ -78,71 +49,63 @@ impl SqliteStore {
         mut cb: impl for<'x> FnMut(&'x [u8], &'x [u8]) -> trc::Result<bool> + Sync + Send,
     ) -> trc::Result<()> {
         let conn = self.conn_pool.get().map_err(into_error)?;
+        self.spawn_worker(move || iterate(&conn, &params, &mut cb))
+            .await
+    }
 
-        self.spawn_worker(move || {
-            let table = char::from(params.begin.subspace());
-            let begin = params.begin.serialize(0);
-            let end = params.end.serialize(0);
-            let keys = if params.values { "k, v" } else { "k" };
-
-            let mut query = conn
-                .prepare_cached(&match (params.first, params.ascending) {
-                    (true, true) => {
-                        format!(
-                        "SELECT {keys} FROM {table} WHERE k >= ? AND k <= ? ORDER BY k ASC LIMIT 1"
-                    )
-                    }
-                    (true, false) => {
-                        format!(
-                        "SELECT {keys} FROM {table} WHERE k >= ? AND k <= ? ORDER BY k DESC LIMIT 1"
-                    )
-                    }
-                    (false, true) => {
-                        format!("SELECT {keys} FROM {table} WHERE k >= ? AND k <= ? ORDER BY k ASC")
-                    }
-                    (false, false) => {
-                        format!(
-                            "SELECT {keys} FROM {table} WHERE k >= ? AND k <= ? ORDER BY k DESC"
-                        )
-                    }
-                })
-                .map_err(into_error)?;
-            let mut rows = query.query([&begin, &end]).map_err(into_error)?;
-
-            if params.values {
-                while let Some(row) = rows.next().map_err(into_error)? {
-                    let key = row
-                        .get_ref(0)
-                        .map_err(into_error)?
-                        .as_bytes()
-                        .map_err(into_error)?;
-                    let value = row
-                        .get_ref(1)
-                        .map_err(into_error)?
-                        .as_bytes()
-                        .map_err(into_error)?;
-
-                    if !cb(key, value)? {
-                        break;
-                    }
-                }
-            } else {
-                while let Some(row) = rows.next().map_err(into_error)? {
-                    if !cb(
-                        row.get_ref(0)
-                            .map_err(into_error)?
-                            .as_bytes()
-                            .map_err(into_error)?,
-                        b"",
-                    )? {
-                        break;
-                    }
-                }
-            }
+    pub(crate) async fn get_counter(
+        &self,
+        key: impl Into<ValueKey<ValueClass<u32>>> + Sync + Send,
+    ) -> trc::Result<i64> {
+        let key = key.into();
+        let conn = self.conn_pool.get().map_err(into_error)?;
+        self.spawn_worker(move || get_counter(&conn, &key)).await
+    }
+
+    pub(crate) fn snapshot(self: &Arc<Self>) -> trc::Result<SqliteSnapshot> {
+        let conn = self.conn_pool.get().map_err(into_error)?;
+
+        // Deferred transactions only take their snapshot on the first read
+        conn.execute_batch("BEGIN DEFERRED").map_err(into_error)?;
+        if let Err(err) = conn.query_row("SELECT COUNT(*) FROM sqlite_master", [], |_| Ok(())) {
+            let _ = conn.execute_batch("ROLLBACK");
+            return Err(into_error(err));
+        }
 
-            Ok(())
+        Ok(SqliteSnapshot {
+            store: self.clone(),
+            conn: Mutex::new(conn),
         })
-        .await
+    }
+}
+
+impl SqliteSnapshot {
+    pub(crate) async fn get_value<U>(&self, key: impl Key) -> trc::Result<Option<U>>
+    where
+        U: Deserialize + 'static,
+    {
+        self.store
+            .spawn_worker(|| get_value(&self.conn.lock(), &key))
+            .await
+    }
+
+    pub(crate) async fn get_bitmap(
+        &self,
+        key: BitmapKey<BitmapClass<u32>>,
+    ) -> trc::Result<Option<RoaringBitmap>> {
+        self.store
+            .spawn_worker(|| get_bitmap(&self.conn.lock(), key.clone()))
+            .await
+    }
+
+    pub(crate) async fn iterate<T: Key>(
+        &self,
+        params: IterateParams<T>,
+        mut cb: impl for<'x> FnMut(&'x [u8], &'x [u8]) -> trc::Result<bool> + Sync + Send,
+    ) -> trc::Result<()> {
+        self.store
+            .spawn_worker(|| iterate(&self.conn.lock(), &params, &mut cb))
+            .await
     }
 
     pub(crate) async fn get_counter(
@@ -150,20 +113,143 @@ impl SqliteStore {
         key: impl Into<ValueKey<ValueClass<u32>>> + Sync + Send,
     ) -> trc::Result<i64> {
         let key = key.into();
-        let table = char::from(key.subspace());
-        let key = key.serialize(0);
-        let conn = self.conn_pool.get().map_err(into_error)?;
-        self.spawn_worker(move || {
-            match conn
-                .prepare_cached(&format!("SELECT v FROM {table} WHERE k = ?"))
-                .map_err(into_error)?
-                .query_row([&key], |row| row.get::<_, i64>(0))
-            {
-                Ok(value) => Ok(value),
-                Err(rusqlite::Error::QueryReturnedNoRows) => Ok(0),
-                Err(e) => Err(into_error(e)),
+        self.store
+            .spawn_worker(|| get_counter(&self.conn.lock(), &key))
+            .await
+    }
+}
+
+impl Drop for SqliteSnapshot {
+    fn drop(&mut self) {
+        // End the read transaction before the connection returns to the pool
+        let _ = self.conn.get_mut().execute_batch("ROLLBACK");
+    }
+}
+
+fn get_value<U>(conn: &Connection, key: &impl Key) -> trc::Result<Option<U>>
+where
+    U: Deserialize + 'static,
+{
+    let mut result = conn
+        .prepare_cached(&format!(
+            "SELECT v FROM {} WHERE k = ?",
+            char::from(key.subspace())
+        ))
+        .map_err(into_error)?;
+    let key = key.serialize(0);
+    result
+        .query_row([&key], |row| {
+            U::deserialize(row.get_ref(0)?.as_bytes()?)
+                .map_err(|err| rusqlite::Error::ToSqlConversionFailure(err.into()))
+        })
+        .optional()
+        .map_err(into_error)
+}
+
+fn get_bitmap(
+    conn: &Connection,
+    mut key: BitmapKey<BitmapClass<u32>>,
+) -> trc::Result<Option<RoaringBitmap>> {
+    let begin = key.serialize(0);
+    key.document_id = u32::MAX;
+    let key_len = begin.len();
+    let end = key.serialize(0);
+    let table = char::from(key.subspace());
+
+    let mut bm = RoaringBitmap::new();
+    let mut query = conn
+        .prepare_cached(&format!("SELECT k FROM {table} WHERE k >= ? AND k <= ?"))
+        .map_err(into_error)?;
+    let mut rows = query.query([&begin, &end]).map_err(into_error)?;
+
+    while let Some(row) = rows.next().map_err(into_error)? {
+        let key = row
+            .get_ref(0)
+            .map_err(into_error)?
+            .as_bytes()
+            .map_err(into_error)?;
+        if key.len() == key_len {
+            bm.insert(key.deserialize_be_u32(key.len() - U32_LEN)?);
+        }
+    }
+    Ok(if !bm.is_empty() { Some(bm) } else { None })
+}
+
+fn iterate<T: Key>(
+    conn: &Connection,
+    params: &IterateParams<T>,
+    cb: &mut impl for<'x> FnMut(&'x [u8], &'x [u8]) -> trc::Result<bool>,
+) -> trc::Result<()> {
+    let table = char::from(params.begin.subspace());
+    let begin = params.begin.serialize(0);
+    let end = params.end.serialize(0);
+    let keys = if params.values { "k, v" } else { "k" };
+
+    let mut query = conn
+        .prepare_cached(&match (params.first, params.ascending) {
+            (true, true) => {
+                format!("SELECT {keys} FROM {table} WHERE k >= ? AND k <= ? ORDER BY k ASC LIMIT 1")
+            }
+            (true, false) => {
+                format!(
+                    "SELECT {keys} FROM {table} WHERE k >= ? AND k <= ? ORDER BY k DESC LIMIT 1"
+                )
+            }
+            (false, true) => {
+                format!("SELECT {keys} FROM {table} WHERE k >= ? AND k <= ? ORDER BY k ASC")
+            }
+            (false, false) => {
+                format!("SELECT {keys} FROM {table} WHERE k >= ? AND k <= ? ORDER BY k DESC")
             }
         })
-        .await
+        .map_err(into_error)?;
+    let mut rows = query.query([&begin, &end]).map_err(into_error)?;
+
+    if params.values {
+        while let Some(row) = rows.next().map_err(into_error)? {
+            let key = row
+                .get_ref(0)
+                .map_err(into_error)?
+                .as_bytes()
+                .map_err(into_error)?;
+            let value = row
+                .get_ref(1)
+                .map_err(into_error)?
+                .as_bytes()
+                .map_err(into_error)?;
+
+            if !cb(key, value)? {
+                break;
+            }
+        }
+    } else {
+        while let Some(row) = rows.next().map_err(into_error)? {
+            if !cb(
+                row.get_ref(0)
+                    .map_err(into_error)?
+                    .as_bytes()
+                    .map_err(into_error)?,
+                b"",
+            )? {
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn get_counter(conn: &Connection, key: &ValueKey<ValueClass<u32>>) -> trc::Result<i64> {
+    let table = char::from(key.subspace());
+    let key = key.serialize(0);
+
+    match conn
+        .prepare_cached(&format!("SELECT v FROM {table} WHERE k = ?"))
+        .map_err(into_error)?
+        .query_row([&key], |row| row.get::<_, i64>(0))
+    {
+        Ok(value) => Ok(value),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(0),
+        Err(e) => Err(into_error(e)),
     }
 }
@@ -9,16 +9,19 @@ use rusqlite::{params, OptionalExtension, TransactionBehavior};
 
 use crate::{
     write::{
-        key::DeserializeBigEndian, AssignedIds, Batch, BitmapClass, Operation, RandomAvailableId,
-        ValueOp,
+        clear_prefix_range, key::DeserializeBigEndian, AssignedIds, Batch, BitmapClass, Operation,
+        RandomAvailableId, ValueOp, MAX_APPEND_SIZE,
     }, BitmapKey, IndexKey, Key, LogKey, SUBSPACE_COUNTER, SUBSPACE_IN_MEMORY_COUNTER, SUBSPACE_QUOTA, U32_LEN
 };
 
 use super::{into_error, SqliteStore};
 
+const AUTO_VACUUM_INCREMENTAL: i64 = 2;
+
 impl SqliteStore {
     pub(crate) async fn write(&self, batch: Batch) -> trc::Result<AssignedIds> {
         let mut conn = self.conn_pool.get().map_err(into_error)?;
+        let settings = self.settings.clone();
         self.spawn_worker(move || {
             let mut account_id = u32::MAX;
             let mut collection = u8::MAX;
@@ -107,6 +110,27 @@ impl SqliteStore {
                                     .map_err(into_error)?,
                                 );
                             }
+                            ValueOp::Append(data) => {
+                                let mut value = trx
+                                    .prepare_cached(&format!("SELECT v FROM {} WHERE k = ?", table))
+                                    .map_err(into_error)?
+                                    .query_row([&key], |row| row.get::<_, Vec<u8>>(0))
+                                    .optional()
+                                    .map_err(into_error)?
+                                    .unwrap_or_default();
+                                value.extend_from_slice(data);
+                                if value.len() > MAX_APPEND_SIZE {
+                                    return Err(trc::StoreEvent::ValueTooLarge
+                                        .ctx(trc::Key::Size, value.len()));
+                                }
+                                trx.prepare_cached(&format!(
+                                    "INSERT OR REPLACE INTO {} (k, v) VALUES (?, ?)",
+                                    table
+                                ))
+                                .map_err(into_error)?
+                                .execute([&key, &value])
+                                .map_err(into_error)?;
+                            }
                             ValueOp::Clear => {
                                 trx.prepare_cached(&format!("DELETE FROM {} WHERE k = ?", table))
                                     .map_err(into_error)?
@@ -173,7 +197,11 @@ impl SqliteStore {
                                 }
                             }
 
-                            document_id = found_ids.random_available_id();
+                            document_id = found_ids.random_available_id(
+                                account_id,
+                                collection,
+                                settings.id_assignment_window(collection),
+                            );
                             result.push_document_id(document_id);
                         }
                         let key = class.serialize(
@@ -220,6 +248,22 @@ impl SqliteStore {
                             .execute([&key, set.resolve(&result).map_err(into_error)?.as_ref()])
                             .map_err(into_error)?;
                     }
+                    Operation::ClearPrefix {
+                        subspace,
+                        account_id: account_id_,
+                        collection: collection_,
+                    } => {
+                        let (from, to) =
+                            clear_prefix_range(*subspace, *account_id_, *collection_, 0)?;
+
+                        trx.prepare_cached(&format!(
+                            "DELETE FROM {} WHERE k >= ? AND k < ?",
+                            char::from(*subspace)
+                        ))
+                        .map_err(into_error)?
+                        .execute([&from, &to])
+                        .map_err(into_error)?;
+                    }
                     Operation::AssertValue {
                         class,
                         assert_value,
@@ -270,6 +314,39 @@ impl SqliteStore {
         .await
     }
 
+    pub(crate) async fn reclaim_space(&self) -> trc::Result<()> {
+        let conn = self.conn_pool.get().map_err(into_error)?;
+        self.spawn_worker(move || {
+            // Databases created before incremental vacuuming was enabled have to be
+            // rebuilt once, after which free pages can be released incrementally
+            if conn
+                .query_row("PRAGMA auto_vacuum", [], |row| row.get::<_, i64>(0))
+                .map_err(into_error)?
+                == AUTO_VACUUM_INCREMENTAL
+            {
+                conn.execute_batch("PRAGMA incremental_vacuum")
+            } else {
+                conn.execute_batch("PRAGMA auto_vacuum = INCREMENTAL; VACUUM")
+            }
+            .map_err(into_error)
+        })
+        .await
+    }
+
+    pub(crate) async fn storage_size(&self) -> trc::Result<u64> {
+        let conn = self.conn_pool.get().map_err(into_error)?;
+        self.spawn_worker(move || {
+            conn.query_row(
+                "SELECT page_count * page_size FROM pragma_page_count(), pragma_page_size()",
+                [],
+                |row| row.get::<_, i64>(0),
+            )
+            .map(|size| size as u64)
+            .map_err(into_error)
+        })
+        .await
+    }
+
     pub(crate) async fn delete_range(&self, from: impl Key, to: impl Key) -> trc::Result<()> {
         let conn = self.conn_pool.get().map_err(into_error)?;
         self.spawn_worker(move || {
@@ -4,11 +4,18 @@
  * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
  */
 
+use std::{sync::Arc, time::Duration};
+
+use ahash::AHashMap;
+use tokio::sync::Semaphore;
 use utils::config::{cron::SimpleCron, utils::ParseValue, Config};
 
 use crate::{
-    backend::fs::FsStore, BlobStore, CompressionAlgo, InMemoryStore, PurgeSchedule, PurgeStore,
-    Store, Stores,
+    backend::fs::FsStore,
+    dispatch::pipeline::BlobChecksum,
+    write::{compress::ValueCompression, DEFAULT_ID_ASSIGNMENT_WINDOW},
+    BlobBackend, BlobStore, CompressionAlgo, InMemoryStore, PurgeSchedule, PurgeStore, Store,
+    Stores,
 };
 
 #[cfg(feature = "s3")]
@@ -44,6 +51,7 @@ enum CompositeStore {
     SQLReadReplica(String),
     ShardedBlob(String),
     ShardedInMemory(String),
+    TieredBlob(String),
 }
 
 impl Stores {
@@ -86,9 +94,7 @@ impl Stores {
                 continue;
             };
             let prefix = ("store", id);
-            let compression_algo = config
-                .property_or_default::<CompressionAlgo>(("store", id, "compression"), "none")
-                .unwrap_or(CompressionAlgo::None);
+            let compression_algo = parse_compression(config, id);
 
             match protocol.as_str() {
                 #[cfg(feature = "rocks")]
@@ -229,6 +235,10 @@ impl Stores {
                     composite_stores.push(CompositeStore::ShardedBlob(store_id));
                 }
                 #[cfg(feature = "enterprise")]
+                "tiered-blob" => {
+                    composite_stores.push(CompositeStore::TieredBlob(store_id));
+                }
+                #[cfg(feature = "enterprise")]
                 "sharded-in-memory" => {
                     composite_stores.push(CompositeStore::ShardedInMemory(store_id));
                 }
@@ -248,6 +258,32 @@ impl Stores {
             }
         }
 
+        // Limit concurrent blob operations per backend type. Composite stores,
+        // opened below, take their permits from the stores they are built on,
+        // see `BlobMember`.
+        let mut limits = AHashMap::new();
+        for blob_store in self.blob_stores.values_mut() {
+            let backend = match &blob_store.backend {
+                BlobBackend::Store(_) => "store",
+                BlobBackend::Fs(_) => "fs",
+                #[cfg(feature = "s3")]
+                BlobBackend::S3(_) => "s3",
+                #[cfg(feature = "azure")]
+                BlobBackend::Azure(_) => "azure",
+                #[cfg(feature = "enterprise")]
+                BlobBackend::Sharded(_) | BlobBackend::Tiered(_) => continue,
+            };
+            blob_store.concurrency = limits
+                .entry(backend)
+                .or_insert_with(|| {
+                    config
+                        .property::<usize>(("storage.blob-concurrency", backend))
+                        .filter(|limit| *limit > 0)
+                        .map(|limit| Arc::new(Semaphore::new(limit)))
+                })
+                .clone();
+        }
+
         #[cfg(feature = "enterprise")]
         for composite_store in composite_stores {
             match composite_store {
@@ -267,14 +303,8 @@ impl Stores {
                         self.fts_stores.insert(id.to_string(), db.clone().into());
                         self.blob_stores.insert(
                             id.to_string(),
-                            BlobStore::from(db.clone()).with_compression(
-                                config
-                                    .property_or_default::<CompressionAlgo>(
-                                        ("store", id.as_str(), "compression"),
-                                        "none",
-                                    )
-                                    .unwrap_or(CompressionAlgo::None),
-                            ),
+                            BlobStore::from(db.clone())
+                                .with_compression(parse_compression(config, id.as_str())),
                         );
                         self.in_memory_stores.insert(id, db.into());
                     }
@@ -286,13 +316,30 @@ impl Stores {
                     ) {
                         let store = BlobStore {
                             backend: crate::BlobBackend::Sharded(db.into()),
-                            compression: config
-                                .property_or_default::<CompressionAlgo>(
-                                    ("store", id.as_str(), "compression"),
-                                    "none",
-                                )
-                                .unwrap_or(CompressionAlgo::None),
-                        };
+                            pipeline: Default::default(),
+                            concurrency: None,
+                            deduplicate: false,
+                            key_prefix: None,
+                            orphan_grace_period: None,
+                        }
+                        .with_compression(parse_compression(config, id.as_str()));
+                        self.blob_stores.insert(id, store);
+                    }
+                }
+                CompositeStore::TieredBlob(id) => {
+                    let prefix = ("store", id.as_str());
+                    if let Some(db) = crate::backend::composite::tiered_blob::TieredBlob::open(
+                        config, prefix, self,
+                    ) {
+                        let store = BlobStore {
+                            backend: crate::BlobBackend::Tiered(db.into()),
+                            pipeline: Default::default(),
+                            concurrency: None,
+                            deduplicate: false,
+                            key_prefix: None,
+                            orphan_grace_period: None,
+                        }
+                        .with_compression(parse_compression(config, id.as_str()));
                         self.blob_stores.insert(id, store);
                     }
                 }
@@ -309,6 +356,71 @@ impl Stores {
                 }
             }
         }
+
+        for (store_id, blob_store) in self.blob_stores.iter_mut() {
+            blob_store.deduplicate = config
+                .property_or_default(("store", store_id.as_str(), "deduplicate"), "false")
+                .unwrap_or_default();
+            blob_store.orphan_grace_period = config.property::<Duration>((
+                "store",
+                store_id.as_str(),
+                "purge.orphans.grace-period",
+            ));
+            if let Some(key_prefix) = config
+                .value(("store", store_id.as_str(), "key-prefix"))
+                .map(|prefix| prefix.to_string())
+            {
+                *blob_store = blob_store.clone().with_key_prefix(key_prefix);
+            }
+            blob_store.pipeline = std::mem::take(&mut blob_store.pipeline).with_legacy_compression(
+                config
+                    .property_or_default(("store", store_id.as_str(), "compression-legacy"), "true")
+                    .unwrap_or(true),
+            );
+            if config
+                .property_or_default(("store", store_id.as_str(), "checksum"), "false")
+                .unwrap_or_default()
+            {
+                blob_store.pipeline =
+                    std::mem::take(&mut blob_store.pipeline).with_stage(BlobChecksum);
+            }
+        }
+
+        for (store_id, store) in &self.stores {
+            let settings = store.settings();
+            settings.set_read_repair(
+                config
+                    .property_or_default::<bool>(
+                        ("store", store_id.as_str(), "read-repair"),
+                        "false",
+                    )
+                    .unwrap_or_default(),
+            );
+            settings.set_id_assignment_window(
+                config
+                    .property_or_default::<usize>(
+                        ("store", store_id.as_str(), "id-assignment.window"),
+                        "100",
+                    )
+                    .unwrap_or(DEFAULT_ID_ASSIGNMENT_WINDOW),
+            );
+            settings.set_value_compression(
+                config
+                    .property_or_default::<CompressionAlgo>(
+                        ("store", store_id.as_str(), "value-compression.algorithm"),
+                        "none",
+                    )
+                    .map(|algorithm| ValueCompression {
+                        algorithm,
+                        threshold: config
+                            .property_or_default::<usize>(
+                                ("store", store_id.as_str(), "value-compression.threshold"),
+                                "4096",
+                            )
+                            .unwrap_or(4096),
+                    }),
+            );
+        }
     }
 
     pub async fn parse_in_memory(&mut self, config: &mut Config, is_reload: bool) {
@@ -374,6 +486,29 @@ impl Stores {
     }
 }
 
+fn parse_compression(config: &mut Config, id: &str) -> CompressionAlgo {
+    match config
+        .property_or_default::<CompressionAlgo>(("store", id, "compression"), "none")
+        .unwrap_or(CompressionAlgo::None)
+    {
+        CompressionAlgo::Zstd(default_level) => {
+            let level = config
+                .property::<i32>(("store", id, "compression-level"))
+                .unwrap_or(default_level);
+            if zstd::compression_level_range().contains(&level) {
+                CompressionAlgo::Zstd(level)
+            } else {
+                config.new_parse_error(
+                    ("store", id, "compression-level"),
+                    format!("Invalid Zstandard compression level: {level}"),
+                );
+                CompressionAlgo::Zstd(default_level)
+            }
+        }
+        algo => algo,
+    }
+}
+
 #[allow(dead_code)]
 trait IsActiveStore {
     fn is_active_store(&self, id: &str) -> bool;
@@ -0,0 +1,166 @@
+/*
+ * SPDX-FileCopyrightText: 2020 Stalwart Labs Ltd <hello@stalw.art>
+ *
+ * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
+ */
+
+use ahash::{AHashMap, AHashSet};
+use trc::AddContext;
+use utils::{BLOB_HASH_LEN, BlobHash};
+
+use crate::{
+    BlobStore, CompressionAlgo, IterateParams, SUBSPACE_BLOB_LINK, Store, U32_LEN, write::AnyKey,
+};
+
+use super::pipeline::BlobTransform;
+
+/// LZ4 is preferred unless Zstandard saves this much more of the original size
+const ZSTD_MIN_GAIN: f64 = 0.05;
+/// Compression is not recommended when it saves less than this
+const MIN_SAVINGS: f64 = 0.05;
+
+/// Compression ratios sampled from the blobs linked to one collection.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompressionReport {
+    /// Collection the blobs are linked to, `u8::MAX` for blobs linked by id
+    pub collection: u8,
+    pub samples: usize,
+    /// Size of the sampled blobs once decoded
+    pub original_size: usize,
+    /// Size of the sampled blobs as stored by the current pipeline
+    pub stored_size: usize,
+    /// Size of the sampled blobs compressed with each available algorithm
+    pub estimates: Vec<(CompressionAlgo, usize)>,
+    pub recommendation: CompressionAlgo,
+}
+
+impl BlobStore {
+    /// Samples up to `max_samples` blobs of each collection linked in `store`
+    /// and compares the ratio achieved by the current settings against the
+    /// ratio every available algorithm would achieve.
+    pub async fn compression_report(
+        &self,
+        store: &Store,
+        max_samples: usize,
+    ) -> trc::Result<Vec<CompressionReport>> {
+        let mut samples: AHashMap<u8, AHashSet<BlobHash>> = AHashMap::new();
+        store
+            .iterate(
+                IterateParams::new(
+                    AnyKey {
+                        subspace: SUBSPACE_BLOB_LINK,
+                        key: vec![0u8],
+                    },
+                    AnyKey {
+                        subspace: SUBSPACE_BLOB_LINK,
+                        key: vec![u8::MAX; BLOB_HASH_LEN + U32_LEN * 2 + 2],
+                    },
+                )
+                .no_values(),
+                |key, _| {
+                    // Skip commit markers, which are not linked to any collection
+                    if key.len() == BLOB_HASH_LEN + U32_LEN * 2 + 1
+                        && key[BLOB_HASH_LEN..BLOB_HASH_LEN + U32_LEN] != [u8::MAX; U32_LEN]
+                    {
+                        let class = samples.entry(key[BLOB_HASH_LEN + U32_LEN]).or_default();
+                        if class.len() < max_samples {
+                            class.insert(
+                                BlobHash::try_from_hash_slice(&key[..BLOB_HASH_LEN]).map_err(
+                                    |_| trc::Error::corrupted_key(key, None, trc::location!()),
+                                )?,
+                            );
+                        }
+                    }
+
+                    Ok(true)
+                },
+            )
+            .await
+            .caused_by(trc::location!())?;
+
+        let mut reports = Vec::with_capacity(samples.len());
+        for (collection, hashes) in samples {
+            let mut report = CompressionReport {
+                collection,
+                samples: 0,
+                original_size: 0,
+                stored_size: 0,
+                estimates: [
+                    CompressionAlgo::None,
+                    CompressionAlgo::Lz4,
+                    CompressionAlgo::zstd(),
+                ]
+                .into_iter()
+                .map(|algorithm| (algorithm, 0))
+                .collect(),
+                recommendation: CompressionAlgo::None,
+            };
+
+            for hash in hashes {
+                let stored = if let Some(stored) = self
+                    .read_blob(hash.as_slice(), 0..usize::MAX)
+                    .await
+                    .caused_by(trc::location!())?
+                {
+                    stored
+                } else {
+                    continue;
+                };
+                report.stored_size += stored.len();
+                let data = self
+                    .decode_blob(hash.as_slice(), stored)
+                    .await
+                    .caused_by(trc::location!())?;
+                report.original_size += data.len();
+                report.samples += 1;
+
+                for (algorithm, size) in &mut report.estimates {
+                    *size += algorithm.encode(&data).caused_by(trc::location!())?.len();
+                }
+            }
+
+            report.recommendation = report.recommend();
+            reports.push(report);
+        }
+        reports.sort_unstable_by_key(|report| report.collection);
+
+        Ok(reports)
+    }
+}
+
+impl CompressionReport {
+    /// Stored size relative to the decoded size, lower is better.
+    pub fn ratio(&self) -> f64 {
+        ratio(self.stored_size, self.original_size)
+    }
+
+    pub fn estimated_ratio(&self, algorithm: CompressionAlgo) -> Option<f64> {
+        self.estimates
+            .iter()
+            .find(|(estimate, _)| *estimate == algorithm)
+            .map(|(_, size)| ratio(*size, self.original_size))
+    }
+
+    // Zstandard usually compresses better but LZ4 is considerably faster, so
+    // it is only recommended when the difference is significant
+    fn recommend(&self) -> CompressionAlgo {
+        let lz4 = self.estimated_ratio(CompressionAlgo::Lz4).unwrap_or(1.0);
+        let zstd = self.estimated_ratio(CompressionAlgo::zstd()).unwrap_or(1.0);
+
+        if lz4 - zstd >= ZSTD_MIN_GAIN && 1.0 - zstd >= MIN_SAVINGS {
+            CompressionAlgo::zstd()
+        } else if 1.0 - lz4 >= MIN_SAVINGS {
+            CompressionAlgo::Lz4
+        } else {
+            CompressionAlgo::None
+        }
+    }
+}
+
+fn ratio(size: usize, original_size: usize) -> f64 {
+    if original_size > 0 {
+        size as f64 / original_size as f64
+    } else {
+        1.0
+    }
+}
@@ -4,43 +4,297 @@
  * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
  */
 
-use std::{borrow::Cow, ops::Range, time::Instant};
+use std::{
+    borrow::Cow,
+    ops::{Deref, Range},
+    sync::Arc,
+    time::Instant,
+};
 
+use futures::{StreamExt, TryStreamExt};
+use tokio::sync::{Semaphore, SemaphorePermit};
 use trc::{AddContext, StoreEvent};
-use utils::config::utils::ParseValue;
+use utils::{BLOB_HASH_LEN, BlobHash, codec::leb128::Leb128Vec, config::utils::ParseValue};
 
-use crate::{BlobBackend, BlobStore, CompressionAlgo, Store};
+use crate::{
+    BlobBackend, BlobQuotaMode, BlobStore, CompressionAlgo, Deserialize, Store, U32_LEN,
+    backend::fs::MappedBlob, write::compress::decompress_value,
+};
+
+use super::{
+    dictionary::{AccountDictionary, DICTIONARY_MARKER, DICTIONARY_PREFIX},
+    frame,
+    gc::GC_MARKER_PREFIX,
+    pipeline::{self, BlobPipeline, BlobTransform},
+    stats::record_blob_write,
+    upload::UPLOAD_PREFIX,
+};
+
+pub enum BlobView {
+    Mapped(MappedBlob),
+    Owned(Vec<u8>),
+}
+
+/// Blob contents that may still be compressed, as returned by `get_blob_encoded`.
+pub enum EncodedBlob {
+    Compressed {
+        algorithm: CompressionAlgo,
+        data: Vec<u8>,
+    },
+    Decoded(Vec<u8>),
+}
+
+/// Blob encoded by the pipeline, as returned by `prepare_blob`.
+pub struct PreparedBlob<'x> {
+    encoded: Cow<'x, [u8]>,
+    algorithm: CompressionAlgo,
+    len: usize,
+}
+
+impl PreparedBlob<'_> {
+    /// Returns the number of bytes the blob is charged against the account quota.
+    ///
+    /// Logical accounting charges the uncompressed size, which matches what users
+    /// see when downloading the blob. Physical accounting charges the bytes written
+    /// to the backend instead, which tracks storage costs but makes usage depend on
+    /// how well each blob compresses.
+    pub fn quota_size(&self, mode: BlobQuotaMode) -> usize {
+        match mode {
+            BlobQuotaMode::Logical => self.len,
+            BlobQuotaMode::Physical => self.encoded.len(),
+        }
+    }
+}
+
+/// Describes the contents of a blob being written, see `put_blob_with_hint`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BlobHint<'x> {
+    #[default]
+    Other,
+    /// Message bodies, which are always compressed
+    Message,
+    /// Attachment of the given MIME type, stored uncompressed when the type is
+    /// already compressed, such as JPEG images or ZIP archives
+    Attachment { content_type: &'x str },
+}
 
 impl BlobStore {
+    /// Returns a range of a blob once decoded.
+    ///
+    /// Blobs are decompressed with the algorithm recorded in their marker rather
+    /// than the configured one, so blobs written with different algorithms can
+    /// be read from the same store.
     pub async fn get_blob(&self, key: &[u8], range: Range<usize>) -> trc::Result<Option<Vec<u8>>> {
-        let read_range = match self.compression {
-            CompressionAlgo::None => range.clone(),
-            CompressionAlgo::Lz4 => 0..usize::MAX,
+        let is_ranged = range.start != 0 || range.end != usize::MAX;
+        let compression = if self.pipeline.is_empty() && is_ranged {
+            self.trailing_compression(key)
+                .await
+                .caused_by(trc::location!())?
+        } else {
+            self.pipeline.compression()
         };
+
+        if is_ranged
+            && (self.pipeline.is_framed() || self.pipeline.is_empty())
+            && compression == Some(CompressionAlgo::Lz4Framed)
+        {
+            if let Some(data) = self
+                .get_blob_framed(key, range.clone())
+                .await
+                .caused_by(trc::location!())?
+            {
+                return Ok(Some(data));
+            }
+        }
+
+        let is_raw = self.pipeline.is_empty() && is_ranged && compression.is_none();
+        let read_range = if is_raw { range.clone() } else { 0..usize::MAX };
+        let result = self.read_blob(key, read_range).await;
+
+        if is_raw {
+            return result;
+        }
+        let decoded = match result.caused_by(trc::location!())? {
+            Some(data) => self
+                .decode_blob(key, data)
+                .await
+                .caused_by(trc::location!())?,
+            None => return Ok(None),
+        };
+
+        if range.end > decoded.len() {
+            Ok(Some(decoded))
+        } else {
+            Ok(Some(
+                decoded
+                    .get(range.start..range.end)
+                    .unwrap_or_default()
+                    .to_vec(),
+            ))
+        }
+    }
+
+    /// Returns several ranges of a blob, fetching and decoding it only once.
+    ///
+    /// Without a pipeline a single ranged read spanning all requested ranges is
+    /// issued, otherwise the whole blob is read and decoded. Ranges extending
+    /// past the end of the blob are truncated.
+    pub async fn get_blob_ranges(
+        &self,
+        key: &[u8],
+        ranges: &[Range<usize>],
+    ) -> trc::Result<Option<Vec<Vec<u8>>>> {
+        let is_raw = self.pipeline.is_empty()
+            && self
+                .trailing_compression(key)
+                .await
+                .caused_by(trc::location!())?
+                .is_none();
+        let read_range = if is_raw {
+            ranges
+                .iter()
+                .map(|range| range.start)
+                .min()
+                .unwrap_or_default()
+                ..ranges
+                    .iter()
+                    .map(|range| range.end)
+                    .max()
+                    .unwrap_or_default()
+        } else {
+            0..usize::MAX
+        };
+        let data = match self
+            .read_blob(key, read_range.clone())
+            .await
+            .caused_by(trc::location!())?
+        {
+            Some(data) if is_raw => data,
+            Some(data) => self
+                .decode_blob(key, data)
+                .await
+                .caused_by(trc::location!())?,
+            None => return Ok(None),
+        };
+
+        Ok(Some(
+            ranges
+                .iter()
+                .map(|range| {
+                    let start = (range.start - read_range.start).min(data.len());
+                    let end = range
+                        .end
+                        .saturating_sub(read_range.start)
+                        .clamp(start, data.len());
+                    data[start..end].to_vec()
+                })
+                .collect(),
+        ))
+    }
+
+    /// Returns a blob without decompressing it when the algorithm it was
+    /// compressed with has a content coding (see `CompressionAlgo::content_encoding`)
+    /// and `accepts` returns true for it, so that it can be forwarded as-is to
+    /// clients supporting the same encoding. Otherwise the blob is decoded.
+    pub async fn get_blob_encoded(
+        &self,
+        key: &[u8],
+        accepts: impl Fn(CompressionAlgo) -> bool,
+    ) -> trc::Result<Option<EncodedBlob>> {
+        let data = match self
+            .read_blob(key, 0..usize::MAX)
+            .await
+            .caused_by(trc::location!())?
+        {
+            Some(data) => self
+                .pipeline
+                .decode_transforms(key, data)
+                .caused_by(trc::location!())?,
+            None => return Ok(None),
+        };
+        self.load_blob_dictionary(&data)
+            .await
+            .caused_by(trc::location!())?;
+
+        match self
+            .pipeline
+            .split_compression(key, data)
+            .caused_by(trc::location!())?
+        {
+            (data, Some(algorithm))
+                if algorithm.content_encoding().is_some() && accepts(algorithm) =>
+            {
+                Ok(Some(EncodedBlob::Compressed { algorithm, data }))
+            }
+            (data, Some(algorithm)) => algorithm
+                .decode(&data)
+                .map(|data| Some(EncodedBlob::Decoded(data)))
+                .map_err(|err| err.ctx(trc::Key::Key, key)),
+            (data, None) => Ok(Some(EncodedBlob::Decoded(data))),
+        }
+    }
+
+    /// Returns the algorithm a blob was compressed with when the pipeline has no
+    /// stages, which only happens for blobs written before compression was
+    /// disabled. Returns `None` for blobs stored as they are, and without a
+    /// request when legacy compression was turned off for the store, see
+    /// `BlobPipeline::with_legacy_compression`.
+    pub(crate) async fn trailing_compression(
+        &self,
+        key: &[u8],
+    ) -> trc::Result<Option<CompressionAlgo>> {
+        if !self.pipeline.has_legacy_compression() {
+            return Ok(None);
+        }
+
+        let size = match self.blob_size(key).await.caused_by(trc::location!())? {
+            Some(size) if size > 0 => size as usize,
+            _ => return Ok(None),
+        };
+        self.read_blob(key, size - 1..size)
+            .await
+            .caused_by(trc::location!())
+            .map(|marker| {
+                marker
+                    .and_then(|marker| marker.first().copied())
+                    .and_then(pipeline::trailing_compression)
+            })
+    }
+
+    /// Returns a range of a blob as it is stored, without reversing the pipeline.
+    pub async fn read_blob(
+        &self,
+        key: &[u8],
+        read_range: Range<usize>,
+    ) -> trc::Result<Option<Vec<u8>>> {
+        let _permit = self.acquire_permit().await?;
         let start_time = Instant::now();
+        let backend_key = self.backend_key(key);
         let result = match &self.backend {
             BlobBackend::Store(store) => match store {
                 #[cfg(feature = "sqlite")]
-                Store::SQLite(store) => store.get_blob(key, read_range).await,
+                Store::SQLite(store) => store.get_blob(&backend_key, read_range).await,
                 #[cfg(feature = "foundation")]
-                Store::FoundationDb(store) => store.get_blob(key, read_range).await,
+                Store::FoundationDb(store) => store.get_blob(&backend_key, read_range).await,
                 #[cfg(feature = "postgres")]
-                Store::PostgreSQL(store) => store.get_blob(key, read_range).await,
+                Store::PostgreSQL(store) => store.get_blob(&backend_key, read_range).await,
                 #[cfg(feature = "mysql")]
-                Store::MySQL(store) => store.get_blob(key, read_range).await,
+                Store::MySQL(store) => store.get_blob(&backend_key, read_range).await,
                 #[cfg(feature = "rocks")]
-                Store::RocksDb(store) => store.get_blob(key, read_range).await,
+                Store::RocksDb(store) => store.get_blob(&backend_key, read_range).await,
                 #[cfg(all(feature = "enterprise", any(feature = "postgres", feature = "mysql")))]
-                Store::SQLReadReplica(store) => store.get_blob(key, read_range).await,
+                Store::SQLReadReplica(store) => store.get_blob(&backend_key, read_range).await,
                 Store::None => Err(trc::StoreEvent::NotConfigured.into()),
             },
-            BlobBackend::Fs(store) => store.get_blob(key, read_range).await,
+            BlobBackend::Fs(store) => store.get_blob(&backend_key, read_range).await,
             #[cfg(feature = "s3")]
-            BlobBackend::S3(store) => store.get_blob(key, read_range).await,
+            BlobBackend::S3(store) => store.get_blob(&backend_key, read_range).await,
             #[cfg(feature = "azure")]
-            BlobBackend::Azure(store) => store.get_blob(key, read_range).await,
+            BlobBackend::Azure(store) => store.get_blob(&backend_key, read_range).await,
             #[cfg(feature = "enterprise")]
-            BlobBackend::Sharded(store) => store.get_blob(key, read_range).await,
+            BlobBackend::Sharded(store) => store.get_blob(&backend_key, read_range).await,
+            #[cfg(feature = "enterprise")]
+            BlobBackend::Tiered(store) => store.get_blob(&backend_key, read_range).await,
         };
 
         trc::event!(
@@ -52,77 +306,344 @@ impl BlobStore {
                 .map_or(0, |data| data.as_ref().map_or(0, |data| data.len())),
         );
 
-        let decompressed = match self.compression {
-            CompressionAlgo::Lz4 => match result.caused_by(trc::location!())? {
-                Some(data)
-                    if data.last().copied().unwrap_or_default()
-                        == CompressionAlgo::Lz4.marker() =>
-                {
-                    lz4_flex::decompress_size_prepended(
-                        data.get(..data.len() - 1).unwrap_or_default(),
-                    )
-                    .map_err(|err| {
-                        trc::StoreEvent::DecompressError
-                            .reason(err)
-                            .ctx(trc::Key::Key, key)
-                            .ctx(trc::Key::CausedBy, trc::location!())
-                    })?
-                }
-                Some(data) => {
-                    trc::event!(Store(StoreEvent::BlobMissingMarker), Key = key,);
-                    data
-                }
-                None => return Ok(None),
-            },
-            _ => return result,
-        };
+        result
+    }
 
-        if range.end > decompressed.len() {
-            Ok(Some(decompressed))
-        } else {
-            Ok(Some(
-                decompressed
-                    .get(range.start..range.end)
-                    .unwrap_or_default()
-                    .to_vec(),
-            ))
+    /// Returns a view of the blob that avoids copying the data into memory when possible.
+    ///
+    /// Blobs on the filesystem backend are memory-mapped when no transformations are
+    /// configured, other backends and transformed blobs fall back to a regular read.
+    pub async fn get_blob_view(
+        &self,
+        key: &[u8],
+        range: Range<usize>,
+    ) -> trc::Result<Option<BlobView>> {
+        // Blobs compressed before compression was disabled have to be decoded
+        let is_mapped = matches!(&self.backend, BlobBackend::Fs(_))
+            && self.pipeline.is_empty()
+            && self
+                .trailing_compression(key)
+                .await
+                .caused_by(trc::location!())?
+                .is_none();
+
+        match &self.backend {
+            BlobBackend::Fs(store) if is_mapped => {
+                let _permit = self.acquire_permit().await?;
+                let start_time = Instant::now();
+                let result = store
+                    .get_blob_mapped(&self.backend_key(key), range)
+                    .await
+                    .caused_by(trc::location!());
+
+                trc::event!(
+                    Store(StoreEvent::BlobRead),
+                    Key = key,
+                    Elapsed = start_time.elapsed(),
+                    Size = result
+                        .as_ref()
+                        .map_or(0, |data| data.as_ref().map_or(0, |data| data.len())),
+                );
+
+                result.map(|data| data.map(BlobView::Mapped))
+            }
+            _ => self
+                .get_blob(key, range)
+                .await
+                .map(|data| data.map(BlobView::Owned)),
         }
     }
 
-    pub async fn put_blob(&self, key: &[u8], data: &[u8]) -> trc::Result<()> {
-        let data: Cow<[u8]> = match self.compression {
-            CompressionAlgo::None => data.into(),
-            CompressionAlgo::Lz4 => {
-                let mut compressed = lz4_flex::compress_prepend_size(data);
-                compressed.push(CompressionAlgo::Lz4.marker());
-                compressed.into()
-            }
-        };
+    /// Returns whether a blob exists without transferring its contents. Object
+    /// stores issue a metadata request, SQL backends select a constant instead of
+    /// the value.
+    pub async fn blob_exists(&self, key: &[u8]) -> trc::Result<bool> {
+        let _permit = self.acquire_permit().await?;
+        let key = self.backend_key(key);
+        match &self.backend {
+            BlobBackend::Store(store) => match store {
+                #[cfg(feature = "sqlite")]
+                Store::SQLite(store) => store.blob_exists(&key).await,
+                #[cfg(feature = "foundation")]
+                Store::FoundationDb(store) => store.blob_exists(&key).await,
+                #[cfg(feature = "postgres")]
+                Store::PostgreSQL(store) => store.blob_exists(&key).await,
+                #[cfg(feature = "mysql")]
+                Store::MySQL(store) => store.blob_exists(&key).await,
+                #[cfg(feature = "rocks")]
+                Store::RocksDb(store) => store.blob_exists(&key).await,
+                #[cfg(all(feature = "enterprise", any(feature = "postgres", feature = "mysql")))]
+                Store::SQLReadReplica(store) => store.blob_exists(&key).await,
+                Store::None => Err(trc::StoreEvent::NotConfigured.into()),
+            },
+            BlobBackend::Fs(store) => store.blob_exists(&key).await,
+            #[cfg(feature = "s3")]
+            BlobBackend::S3(store) => store.blob_exists(&key).await,
+            #[cfg(feature = "azure")]
+            BlobBackend::Azure(store) => store.blob_exists(&key).await,
+            #[cfg(feature = "enterprise")]
+            BlobBackend::Sharded(store) => store.blob_exists(&key).await,
+            #[cfg(feature = "enterprise")]
+            BlobBackend::Tiered(store) => store.blob_exists(&key).await,
+        }
+        .caused_by(trc::location!())
+    }
 
-        let start_time = Instant::now();
-        let result = match &self.backend {
+    /// Returns the number of bytes a blob takes up in the backend, which is its
+    /// compressed size when compression is enabled. Object stores and the
+    /// filesystem read it from the object metadata.
+    pub async fn blob_size(&self, key: &[u8]) -> trc::Result<Option<u64>> {
+        let _permit = self.acquire_permit().await?;
+        let key = self.backend_key(key);
+        match &self.backend {
             BlobBackend::Store(store) => match store {
                 #[cfg(feature = "sqlite")]
-                Store::SQLite(store) => store.put_blob(key, data.as_ref()).await,
+                Store::SQLite(store) => store.blob_size(&key).await,
                 #[cfg(feature = "foundation")]
-                Store::FoundationDb(store) => store.put_blob(key, data.as_ref()).await,
+                Store::FoundationDb(store) => store.blob_size(&key).await,
                 #[cfg(feature = "postgres")]
-                Store::PostgreSQL(store) => store.put_blob(key, data.as_ref()).await,
+                Store::PostgreSQL(store) => store.blob_size(&key).await,
                 #[cfg(feature = "mysql")]
-                Store::MySQL(store) => store.put_blob(key, data.as_ref()).await,
+                Store::MySQL(store) => store.blob_size(&key).await,
                 #[cfg(feature = "rocks")]
-                Store::RocksDb(store) => store.put_blob(key, data.as_ref()).await,
+                Store::RocksDb(store) => store.blob_size(&key).await,
                 #[cfg(all(feature = "enterprise", any(feature = "postgres", feature = "mysql")))]
-                Store::SQLReadReplica(store) => store.put_blob(key, data.as_ref()).await,
+                Store::SQLReadReplica(store) => store.blob_size(&key).await,
                 Store::None => Err(trc::StoreEvent::NotConfigured.into()),
             },
-            BlobBackend::Fs(store) => store.put_blob(key, data.as_ref()).await,
+            BlobBackend::Fs(store) => store.blob_size(&key).await,
             #[cfg(feature = "s3")]
-            BlobBackend::S3(store) => store.put_blob(key, data.as_ref()).await,
+            BlobBackend::S3(store) => store.blob_size(&key).await,
             #[cfg(feature = "azure")]
-            BlobBackend::Azure(store) => store.put_blob(key, data.as_ref()).await,
+            BlobBackend::Azure(store) => store.blob_size(&key).await,
+            #[cfg(feature = "enterprise")]
+            BlobBackend::Sharded(store) => store.blob_size(&key).await,
             #[cfg(feature = "enterprise")]
-            BlobBackend::Sharded(store) => store.put_blob(key, data.as_ref()).await,
+            BlobBackend::Tiered(store) => store.blob_size(&key).await,
+        }
+        .caused_by(trc::location!())
+    }
+
+    /// Returns the size of a blob once decoded. Blobs compressed with LZ4 or
+    /// framed LZ4 record their uncompressed size, which is read without
+    /// decompressing them, other encoded blobs are read and decoded in full.
+    /// A single marker byte can also end a blob stored as it is, so blobs are
+    /// decoded in full as well when the recorded size is not plausible.
+    pub async fn uncompressed_size(&self, key: &[u8]) -> trc::Result<Option<u64>> {
+        if self.pipeline.is_empty()
+            && self
+                .trailing_compression(key)
+                .await
+                .caused_by(trc::location!())?
+                .is_none()
+        {
+            return self.blob_size(key).await;
+        } else if !self.pipeline.is_compression_only() {
+            return self
+                .get_blob(key, 0..usize::MAX)
+                .await
+                .map(|data| data.map(|data| data.len() as u64));
+        }
+
+        let size = match self.blob_size(key).await.caused_by(trc::location!())? {
+            Some(size) if size > 0 => size as usize,
+            size => return Ok(size),
+        };
+        let marker = self
+            .read_blob(key, size - 1..size)
+            .await
+            .caused_by(trc::location!())?
+            .and_then(|marker| marker.first().copied());
+        let decoded_size = match marker.and_then(CompressionAlgo::from_marker) {
+            // Blobs compressed with an account dictionary are decoded in full
+            None if marker == Some(DICTIONARY_MARKER) => None,
+            // LZ4 does not expand data more than 255 times
+            Some(CompressionAlgo::Lz4) => self
+                .read_blob(key, 0..U32_LEN)
+                .await
+                .caused_by(trc::location!())?
+                .and_then(|prefix| prefix.try_into().ok())
+                .map(u32::from_le_bytes)
+                .map(|decoded| decoded as usize)
+                .filter(|decoded| *decoded <= size.saturating_sub(U32_LEN + 1) * 255),
+            Some(CompressionAlgo::Lz4Framed) => self
+                .read_blob(key, 0..frame::FRAME_HEADER_LEN)
+                .await
+                .caused_by(trc::location!())?
+                .and_then(|header| frame::decoded_len(&header)),
+            // Blobs stored before compression was enabled are not decoded
+            None => Some(size),
+            // The checksum following uncompressed blobs requires the whole blob
+            Some(_) => None,
+        };
+
+        match decoded_size {
+            Some(size) => Ok(Some(size as u64)),
+            None => self
+                .get_blob(key, 0..usize::MAX)
+                .await
+                .map(|data| data.map(|data| data.len() as u64)),
+        }
+    }
+
+    /// Writes a blob under the given key.
+    ///
+    /// With deduplication enabled, writes to a key of `BLOB_HASH_LEN` bytes are
+    /// skipped when a blob already exists under it. These keys are hashes of the
+    /// uncompressed contents, so the stored blob already holds the same data.
+    /// Blobs remain shared through their `BlobOp::Link` entries, which the blob
+    /// purge checks before deleting them.
+    pub async fn put_blob(&self, key: &[u8], data: &[u8]) -> trc::Result<()> {
+        self.put_blob_with_hint(key, data, BlobHint::Other).await
+    }
+
+    /// Writes a blob, skipping compression when the hint describes contents that
+    /// are already compressed. The blob is then stored with the trailer of
+    /// `CompressionAlgo::None`, so reads can tell it apart from compressed blobs.
+    pub async fn put_blob_with_hint(
+        &self,
+        key: &[u8],
+        data: &[u8],
+        hint: BlobHint<'_>,
+    ) -> trc::Result<()> {
+        self.store_blob(key, data.len(), || self.prepare_blob(data, hint))
+            .await
+    }
+
+    /// Encodes a blob with the pipeline so that its stored size is known before
+    /// it is written with `put_prepared_blob`, as physical quota accounting
+    /// requires.
+    pub fn prepare_blob<'x>(
+        &self,
+        data: &'x [u8],
+        hint: BlobHint<'_>,
+    ) -> trc::Result<PreparedBlob<'x>> {
+        let compress = hint.is_compressible();
+        if compress {
+            self.pipeline.encode(data)
+        } else {
+            self.pipeline.encode_uncompressed(data)
+        }
+        .caused_by(trc::location!())
+        .map(|encoded| PreparedBlob {
+            algorithm: self
+                .pipeline
+                .compression()
+                .filter(|_| compress)
+                .unwrap_or(CompressionAlgo::None),
+            len: data.len(),
+            encoded,
+        })
+    }
+
+    /// Same as `prepare_blob` for compressible contents, compressing them with
+    /// the dictionary of an account, see `prepare_account_blob`.
+    pub(crate) fn prepare_blob_with_dictionary<'x>(
+        &self,
+        data: &'x [u8],
+        dictionary: &Arc<AccountDictionary>,
+    ) -> trc::Result<PreparedBlob<'x>> {
+        self.pipeline
+            .encode_with_dictionary(data, dictionary)
+            .caused_by(trc::location!())
+            .map(|encoded| PreparedBlob {
+                algorithm: self.pipeline.compression().unwrap_or(CompressionAlgo::None),
+                len: data.len(),
+                encoded,
+            })
+    }
+
+    /// Same as `put_blob_with_hint`, for a blob encoded by `prepare_blob`.
+    pub async fn put_prepared_blob(&self, key: &[u8], blob: PreparedBlob<'_>) -> trc::Result<()> {
+        self.store_blob(key, blob.len, || Ok(blob)).await
+    }
+
+    async fn store_blob<'x>(
+        &self,
+        key: &[u8],
+        len: usize,
+        prepare: impl FnOnce() -> trc::Result<PreparedBlob<'x>>,
+    ) -> trc::Result<()> {
+        if is_hold_key(key) {
+            return Err(hold_modified(key));
+        }
+
+        // Concurrent writes of the same blob can both miss the existence check,
+        // the conditional write makes sure only one of them stores it
+        let deduplicate = self.deduplicate && key.len() == BLOB_HASH_LEN;
+        if (deduplicate && self.blob_exists(key).await.caused_by(trc::location!())?)
+            || !self.write_blob(key, &prepare()?, deduplicate).await?
+        {
+            trc::event!(Store(StoreEvent::BlobDeduplicated), Key = key, Size = len);
+
+            self.clear_gc_marker(key)
+                .await
+                .caused_by(trc::location!())?;
+        }
+
+        Ok(())
+    }
+
+    /// Same as `put_blob`, for bulk imports. RocksDB keeps the blob in memory
+    /// and writes it together with other blobs when `blob.batch.max-size` is
+    /// configured, other backends write it right away. Buffered blobs can be
+    /// read, but are only durable once `flush` returns, which has to happen
+    /// before committing anything that references them.
+    pub async fn put_blob_buffered(&self, key: &[u8], data: &[u8]) -> trc::Result<()> {
+        match &self.backend {
+            #[cfg(feature = "rocks")]
+            BlobBackend::Store(Store::RocksDb(store)) => {
+                if is_hold_key(key) {
+                    return Err(hold_modified(key));
+                }
+
+                let encoded = self.pipeline.encode(data).caused_by(trc::location!())?;
+                let _permit = self.acquire_permit().await?;
+                store
+                    .put_blob_buffered(&self.backend_key(key), encoded.as_ref())
+                    .await
+                    .caused_by(trc::location!())?;
+                record_blob_write(
+                    self.pipeline.compression().unwrap_or(CompressionAlgo::None),
+                    data.len(),
+                    encoded.len(),
+                );
+                Ok(())
+            }
+            _ => self.put_blob(key, data).await,
+        }
+    }
+
+    /// Writes a blob unless one already exists under the given key, returning
+    /// whether it was written. The check and the write are a single operation
+    /// on the backend, so concurrent writers of the same key never both write
+    /// it: object stores send `If-None-Match: *`, the filesystem links the
+    /// blob in place only if no file exists at its path and SQL backends skip
+    /// the insert on a key conflict.
+    pub async fn put_blob_if_absent(&self, key: &[u8], data: &[u8]) -> trc::Result<bool> {
+        if is_hold_key(key) {
+            return Err(hold_modified(key));
+        }
+
+        self.write_blob(key, &self.prepare_blob(data, BlobHint::Other)?, true)
+            .await
+    }
+
+    async fn write_blob(
+        &self,
+        key: &[u8],
+        blob: &PreparedBlob<'_>,
+        if_absent: bool,
+    ) -> trc::Result<bool> {
+        let data = &blob.encoded;
+        let _permit = self.acquire_permit().await?;
+        let start_time = Instant::now();
+        let result = if if_absent {
+            self.backend_put_blob_if_absent(key, data.as_ref()).await
+        } else {
+            self.backend_put_blob(key, data.as_ref())
+                .await
+                .map(|_| true)
         }
         .caused_by(trc::location!());
 
@@ -133,34 +654,110 @@ impl BlobStore {
             Size = data.len(),
         );
 
+        if matches!(result, Ok(true)) {
+            record_blob_write(blob.algorithm, blob.len, data.len());
+        }
+
         result
     }
 
+    pub(crate) async fn backend_put_blob(&self, key: &[u8], data: &[u8]) -> trc::Result<()> {
+        let key = self.backend_key(key);
+        match &self.backend {
+            BlobBackend::Store(store) => match store {
+                #[cfg(feature = "sqlite")]
+                Store::SQLite(store) => store.put_blob(&key, data).await,
+                #[cfg(feature = "foundation")]
+                Store::FoundationDb(store) => store.put_blob(&key, data).await,
+                #[cfg(feature = "postgres")]
+                Store::PostgreSQL(store) => store.put_blob(&key, data).await,
+                #[cfg(feature = "mysql")]
+                Store::MySQL(store) => store.put_blob(&key, data).await,
+                #[cfg(feature = "rocks")]
+                Store::RocksDb(store) => store.put_blob(&key, data).await,
+                #[cfg(all(feature = "enterprise", any(feature = "postgres", feature = "mysql")))]
+                Store::SQLReadReplica(store) => store.put_blob(&key, data).await,
+                Store::None => Err(trc::StoreEvent::NotConfigured.into()),
+            },
+            BlobBackend::Fs(store) => store.put_blob(&key, data).await,
+            #[cfg(feature = "s3")]
+            BlobBackend::S3(store) => store.put_blob(&key, data).await,
+            #[cfg(feature = "azure")]
+            BlobBackend::Azure(store) => store.put_blob(&key, data).await,
+            #[cfg(feature = "enterprise")]
+            BlobBackend::Sharded(store) => store.put_blob(&key, data).await,
+            #[cfg(feature = "enterprise")]
+            BlobBackend::Tiered(store) => store.put_blob(&key, data).await,
+        }
+    }
+
+    pub(crate) async fn backend_put_blob_if_absent(
+        &self,
+        key: &[u8],
+        data: &[u8],
+    ) -> trc::Result<bool> {
+        let key = self.backend_key(key);
+        match &self.backend {
+            BlobBackend::Store(store) => match store {
+                #[cfg(feature = "sqlite")]
+                Store::SQLite(store) => store.put_blob_if_absent(&key, data).await,
+                #[cfg(feature = "foundation")]
+                Store::FoundationDb(store) => store.put_blob_if_absent(&key, data).await,
+                #[cfg(feature = "postgres")]
+                Store::PostgreSQL(store) => store.put_blob_if_absent(&key, data).await,
+                #[cfg(feature = "mysql")]
+                Store::MySQL(store) => store.put_blob_if_absent(&key, data).await,
+                #[cfg(feature = "rocks")]
+                Store::RocksDb(store) => store.put_blob_if_absent(&key, data).await,
+                #[cfg(all(feature = "enterprise", any(feature = "postgres", feature = "mysql")))]
+                Store::SQLReadReplica(store) => store.put_blob_if_absent(&key, data).await,
+                Store::None => Err(trc::StoreEvent::NotConfigured.into()),
+            },
+            BlobBackend::Fs(store) => store.put_blob_if_absent(&key, data).await,
+            #[cfg(feature = "s3")]
+            BlobBackend::S3(store) => store.put_blob_if_absent(&key, data).await,
+            #[cfg(feature = "azure")]
+            BlobBackend::Azure(store) => store.put_blob_if_absent(&key, data).await,
+            #[cfg(feature = "enterprise")]
+            BlobBackend::Sharded(store) => store.put_blob_if_absent(&key, data).await,
+            #[cfg(feature = "enterprise")]
+            BlobBackend::Tiered(store) => store.put_blob_if_absent(&key, data).await,
+        }
+    }
+
     pub async fn delete_blob(&self, key: &[u8]) -> trc::Result<bool> {
+        if is_hold_key(key) {
+            return Err(hold_modified(key));
+        }
+
+        let _permit = self.acquire_permit().await?;
         let start_time = Instant::now();
+        let backend_key = self.backend_key(key);
         let result = match &self.backend {
             BlobBackend::Store(store) => match store {
                 #[cfg(feature = "sqlite")]
-                Store::SQLite(store) => store.delete_blob(key).await,
+                Store::SQLite(store) => store.delete_blob(&backend_key).await,
                 #[cfg(feature = "foundation")]
-                Store::FoundationDb(store) => store.delete_blob(key).await,
+                Store::FoundationDb(store) => store.delete_blob(&backend_key).await,
                 #[cfg(feature = "postgres")]
-                Store::PostgreSQL(store) => store.delete_blob(key).await,
+                Store::PostgreSQL(store) => store.delete_blob(&backend_key).await,
                 #[cfg(feature = "mysql")]
-                Store::MySQL(store) => store.delete_blob(key).await,
+                Store::MySQL(store) => store.delete_blob(&backend_key).await,
                 #[cfg(feature = "rocks")]
-                Store::RocksDb(store) => store.delete_blob(key).await,
+                Store::RocksDb(store) => store.delete_blob(&backend_key).await,
                 #[cfg(all(feature = "enterprise", any(feature = "postgres", feature = "mysql")))]
-                Store::SQLReadReplica(store) => store.delete_blob(key).await,
+                Store::SQLReadReplica(store) => store.delete_blob(&backend_key).await,
                 Store::None => Err(trc::StoreEvent::NotConfigured.into()),
             },
-            BlobBackend::Fs(store) => store.delete_blob(key).await,
+            BlobBackend::Fs(store) => store.delete_blob(&backend_key).await,
             #[cfg(feature = "s3")]
-            BlobBackend::S3(store) => store.delete_blob(key).await,
+            BlobBackend::S3(store) => store.delete_blob(&backend_key).await,
             #[cfg(feature = "azure")]
-            BlobBackend::Azure(store) => store.delete_blob(key).await,
+            BlobBackend::Azure(store) => store.delete_blob(&backend_key).await,
             #[cfg(feature = "enterprise")]
-            BlobBackend::Sharded(store) => store.delete_blob(key).await,
+            BlobBackend::Sharded(store) => store.delete_blob(&backend_key).await,
+            #[cfg(feature = "enterprise")]
+            BlobBackend::Tiered(store) => store.delete_blob(&backend_key).await,
         }
         .caused_by(trc::location!());
 
@@ -173,22 +770,544 @@ impl BlobStore {
         result
     }
 
+    /// Deletes several blobs at once, returning the number of blobs removed.
+    ///
+    /// SQL backends remove them with a single statement per `MAX_DELETE_BLOBS`
+    /// keys, other backends delete them concurrently, limited by the configured
+    /// concurrency and `DELETE_CONCURRENCY`.
+    pub async fn delete_blobs(&self, keys: &[Vec<u8>]) -> trc::Result<usize> {
+        if let Some(key) = keys.iter().find(|key| is_hold_key(key)) {
+            return Err(hold_modified(key));
+        } else if keys.is_empty() {
+            return Ok(0);
+        }
+
+        let start_time = Instant::now();
+        let result = match &self.backend {
+            #[cfg(feature = "sqlite")]
+            BlobBackend::Store(Store::SQLite(store)) => {
+                let _permit = self.acquire_permit().await?;
+                store.delete_blobs(&self.backend_keys(keys)).await
+            }
+            #[cfg(feature = "postgres")]
+            BlobBackend::Store(Store::PostgreSQL(store)) => {
+                let _permit = self.acquire_permit().await?;
+                store.delete_blobs(&self.backend_keys(keys)).await
+            }
+            #[cfg(feature = "mysql")]
+            BlobBackend::Store(Store::MySQL(store)) => {
+                let _permit = self.acquire_permit().await?;
+                store.delete_blobs(&self.backend_keys(keys)).await
+            }
+            _ => {
+                futures::stream::iter(keys)
+                    .map(|key| self.delete_blob(key))
+                    .buffer_unordered(DELETE_CONCURRENCY)
+                    .try_fold(0, |deleted, is_deleted| async move {
+                        Ok(deleted + is_deleted as usize)
+                    })
+                    .await
+            }
+        }
+        .caused_by(trc::location!());
+
+        trc::event!(
+            Store(StoreEvent::BlobWrite),
+            Total = keys.len(),
+            Elapsed = start_time.elapsed(),
+        );
+
+        result
+    }
+
+    /// Deletes every blob whose key starts with `prefix`, returning the number of
+    /// blobs removed.
+    ///
+    /// The filesystem removes the directory holding them when the prefix is not
+    /// longer than the directory depth. Other backends list the matching keys,
+    /// which S3 does with a prefixed `ListObjectsV2` per key shard, and delete
+    /// them `PREFIX_DELETE_BATCH` keys at a time through `delete_blobs`. Prefixes
+    /// covering held blobs are rejected.
+    pub async fn delete_blob_prefix(&self, prefix: &[u8]) -> trc::Result<usize> {
+        if is_hold_key(prefix)
+            || HOLD_DATA_PREFIX.starts_with(prefix)
+            || HOLD_MARKER_PREFIX.starts_with(prefix)
+        {
+            return Err(hold_modified(prefix));
+        }
+
+        if let BlobBackend::Fs(store) = &self.backend {
+            let _permit = self.acquire_permit().await?;
+            if let Some(deleted) = store
+                .delete_blob_dir(&self.backend_key(prefix))
+                .await
+                .caused_by(trc::location!())?
+            {
+                return Ok(deleted);
+            }
+        }
+
+        let mut deleted = 0;
+        for keys in self
+            .list_blobs(prefix)
+            .await
+            .caused_by(trc::location!())?
+            .chunks(PREFIX_DELETE_BATCH)
+        {
+            deleted += self.delete_blobs(keys).await.caused_by(trc::location!())?;
+        }
+
+        Ok(deleted)
+    }
+
+    /// Copies a blob into the write-once legal hold namespace.
+    ///
+    /// The copy is stored under `hold_key` together with a marker holding the hash of
+    /// its contents, which is used to detect tampering when reading it back. Held blobs
+    /// live outside the hash-addressed key space, so purging the original blob never
+    /// removes them, and `put_blob` or `delete_blob` calls targeting them are rejected.
+    ///
+    /// The copy is keyed by its hash and the marker is written last with a conditional
+    /// write, so the hold only exists once the marker does. Concurrent holds under the
+    /// same key can't overwrite each other's copy, only the first marker written counts.
+    pub async fn hold_blob(&self, key: &[u8], hold_key: &[u8]) -> trc::Result<()> {
+        let marker_key = hold_marker_key(hold_key);
+        if self
+            .get_blob(&marker_key, 0..usize::MAX)
+            .await
+            .caused_by(trc::location!())?
+            .is_some()
+        {
+            return Err(hold_modified(hold_key));
+        }
+
+        let data = self
+            .get_blob(key, 0..usize::MAX)
+            .await
+            .caused_by(trc::location!())?
+            .ok_or_else(|| {
+                trc::StoreEvent::NotFound
+                    .ctx(trc::Key::Key, key)
+                    .ctx(trc::Key::CausedBy, trc::location!())
+            })?;
+
+        // Copies left behind by failed holds are never read, a hold can be retried
+        let hash = BlobHash::from(data.as_slice());
+        self.write_blob(
+            &hold_data_key(hold_key, &hash),
+            &self.prepare_blob(&data, BlobHint::Other)?,
+            false,
+        )
+        .await
+        .caused_by(trc::location!())?;
+        if self
+            .write_blob(
+                &marker_key,
+                &self.prepare_blob(hash.as_slice(), BlobHint::Other)?,
+                true,
+            )
+            .await
+            .caused_by(trc::location!())?
+        {
+            Ok(())
+        } else {
+            Err(hold_modified(hold_key))
+        }
+    }
+
+    /// Returns the contents of a held blob after verifying them against the hold marker.
+    pub async fn get_held_blob(&self, hold_key: &[u8]) -> trc::Result<Option<Vec<u8>>> {
+        let marker = match self
+            .get_blob(&hold_marker_key(hold_key), 0..usize::MAX)
+            .await
+            .caused_by(trc::location!())?
+        {
+            Some(marker) => marker,
+            None => return Ok(None),
+        };
+
+        let data = match BlobHash::try_from_hash_slice(&marker) {
+            Ok(hash) => self
+                .get_blob(&hold_data_key(hold_key, &hash), 0..usize::MAX)
+                .await
+                .caused_by(trc::location!())?,
+            Err(_) => None,
+        };
+        match data {
+            Some(data) if BlobHash::from(data.as_slice()).as_slice() == marker => Ok(Some(data)),
+            _ => Err(trc::StoreEvent::DataCorruption
+                .reason("Held blob does not match its hold marker")
+                .ctx(trc::Key::Key, hold_key)
+                .ctx(trc::Key::CausedBy, trc::location!())),
+        }
+    }
+
     pub fn with_compression(self, compression: CompressionAlgo) -> Self {
         Self {
             backend: self.backend,
-            compression,
+            pipeline: self.pipeline.with_compression(compression),
+            concurrency: self.concurrency,
+            deduplicate: self.deduplicate,
+            key_prefix: self.key_prefix,
+            orphan_grace_period: self.orphan_grace_period,
+        }
+    }
+
+    pub fn with_pipeline(self, pipeline: BlobPipeline) -> Self {
+        Self {
+            backend: self.backend,
+            pipeline,
+            concurrency: self.concurrency,
+            deduplicate: self.deduplicate,
+            key_prefix: self.key_prefix,
+            orphan_grace_period: self.orphan_grace_period,
+        }
+    }
+
+    pub fn with_concurrency(self, concurrency: Option<Arc<Semaphore>>) -> Self {
+        Self {
+            backend: self.backend,
+            pipeline: self.pipeline,
+            concurrency,
+            deduplicate: self.deduplicate,
+            key_prefix: self.key_prefix,
+            orphan_grace_period: self.orphan_grace_period,
+        }
+    }
+
+    pub fn with_deduplication(self, deduplicate: bool) -> Self {
+        Self {
+            deduplicate,
+            ..self
+        }
+    }
+
+    /// Stores blobs under `prefix`, so that several tenants can share a backend
+    /// without their keys colliding. The prefix is opaque, keys given to and
+    /// returned by the store never include it. It is stored after its length,
+    /// so the keys of a tenant never start with those of a tenant whose prefix
+    /// begins with its own.
+    pub fn with_key_prefix(self, prefix: impl AsRef<[u8]>) -> Self {
+        let prefix = prefix.as_ref();
+        let key_prefix = (!prefix.is_empty()).then(|| {
+            let mut key_prefix = Vec::with_capacity(prefix.len() + 2);
+            key_prefix.push_leb128(prefix.len());
+            key_prefix.extend_from_slice(prefix);
+            key_prefix
+        });
+        let backend = match self.backend {
+            BlobBackend::Fs(store) => BlobBackend::Fs(
+                store
+                    .with_key_prefix_len(key_prefix.as_ref().map_or(0, |prefix| prefix.len()))
+                    .into(),
+            ),
+            backend => backend,
+        };
+        Self {
+            backend,
+            key_prefix: key_prefix.map(Into::into),
+            ..self
+        }
+    }
+
+    /// Returns the key a blob is stored under in the backend.
+    pub(crate) fn backend_key<'x>(&self, key: &'x [u8]) -> Cow<'x, [u8]> {
+        match &self.key_prefix {
+            Some(prefix) => Cow::Owned([prefix.as_ref(), key].concat()),
+            None => Cow::Borrowed(key),
+        }
+    }
+
+    pub(crate) fn backend_keys<'x>(&self, keys: &'x [Vec<u8>]) -> Cow<'x, [Vec<u8>]> {
+        match &self.key_prefix {
+            Some(_) => Cow::Owned(
+                keys.iter()
+                    .map(|key| self.backend_key(key).into_owned())
+                    .collect(),
+            ),
+            None => Cow::Borrowed(keys),
+        }
+    }
+
+    /// Writes the blobs buffered by `put_blob_buffered`. Flushing backends that
+    /// do not buffer writes does nothing.
+    pub async fn flush(&self) -> trc::Result<()> {
+        self.backend.flush().await.caused_by(trc::location!())
+    }
+
+    /// Returns the keys of all blobs starting with `prefix`, sorted and without
+    /// the key prefix of the store.
+    pub(crate) async fn list_blobs(&self, prefix: &[u8]) -> trc::Result<Vec<Vec<u8>>> {
+        let mut keys = self
+            .list_blob_pages(prefix)
+            .try_concat()
+            .await
+            .caused_by(trc::location!())?;
+        keys.sort_unstable();
+
+        Ok(keys)
+    }
+
+    pub(crate) async fn acquire_permit(&self) -> trc::Result<Option<SemaphorePermit<'_>>> {
+        acquire_permit(self.concurrency.as_deref()).await
+    }
+}
+
+pub(crate) async fn acquire_permit(
+    concurrency: Option<&Semaphore>,
+) -> trc::Result<Option<SemaphorePermit<'_>>> {
+    match concurrency {
+        Some(concurrency) => concurrency.acquire().await.map(Some).map_err(|err| {
+            trc::StoreEvent::UnexpectedError
+                .reason(err)
+                .ctx(trc::Key::CausedBy, trc::location!())
+        }),
+        None => Ok(None),
+    }
+}
+
+impl BlobBackend {
+    async fn flush(&self) -> trc::Result<()> {
+        match self {
+            #[cfg(feature = "rocks")]
+            BlobBackend::Store(Store::RocksDb(store)) => store.flush_blobs().await,
+            #[cfg(feature = "enterprise")]
+            BlobBackend::Sharded(store) => {
+                for store in &store.stores {
+                    Box::pin(store.backend.flush()).await?;
+                }
+                Ok(())
+            }
+            // The slow tier is written first, see `TieredBlob::put_blob`
+            #[cfg(feature = "enterprise")]
+            BlobBackend::Tiered(store) => {
+                Box::pin(store.slow.backend.flush()).await?;
+                Box::pin(store.fast.backend.flush()).await
+            }
+            _ => Ok(()),
         }
     }
 }
 
+impl Deref for BlobView {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            BlobView::Mapped(data) => data,
+            BlobView::Owned(data) => data,
+        }
+    }
+}
+
+impl AsRef<[u8]> for BlobView {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
 const MAGIC_MARKER: u8 = 0xa0;
 
+impl BlobHint<'_> {
+    pub fn is_compressible(&self) -> bool {
+        match self {
+            BlobHint::Attachment { content_type } => !is_compressed_type(content_type),
+            BlobHint::Message | BlobHint::Other => true,
+        }
+    }
+}
+
+/// Whether contents of this MIME type are already compressed, in which case
+/// compressing them again costs CPU for little to no gain.
+fn is_compressed_type(content_type: &str) -> bool {
+    let content_type = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    match content_type.split_once('/') {
+        Some(("image", subtype)) => !matches!(subtype, "svg+xml" | "bmp" | "x-ms-bmp" | "tiff"),
+        Some(("audio" | "video", subtype)) => !matches!(subtype, "wav" | "x-wav" | "vnd.wave"),
+        Some(("font", subtype)) => matches!(subtype, "woff" | "woff2"),
+        Some(("application", subtype)) => {
+            subtype.ends_with("+zip")
+                || subtype.starts_with("vnd.openxmlformats-")
+                || subtype.starts_with("vnd.oasis.opendocument.")
+                || matches!(
+                    subtype,
+                    "zip"
+                        | "gzip"
+                        | "x-gzip"
+                        | "x-bzip2"
+                        | "x-xz"
+                        | "zstd"
+                        | "x-7z-compressed"
+                        | "x-rar-compressed"
+                        | "vnd.rar"
+                        | "java-archive"
+                        | "pdf"
+                )
+        }
+        _ => false,
+    }
+}
+
+/// Maximum number of concurrent requests issued by `delete_blobs`
+const DELETE_CONCURRENCY: usize = 16;
+/// Number of listed keys deleted at a time by `delete_blob_prefix`
+const PREFIX_DELETE_BATCH: usize = 1000;
+
+const HOLD_DATA_PREFIX: &[u8] = b"\xffhold.data:";
+const HOLD_MARKER_PREFIX: &[u8] = b"\xffhold.marker:";
+
+fn hold_data_key(hold_key: &[u8], hash: &BlobHash) -> Vec<u8> {
+    [HOLD_DATA_PREFIX, hold_key, b":", hash.as_slice()].concat()
+}
+
+fn hold_marker_key(hold_key: &[u8]) -> Vec<u8> {
+    [HOLD_MARKER_PREFIX, hold_key].concat()
+}
+
+pub(crate) fn is_hold_key(key: &[u8]) -> bool {
+    key.starts_with(HOLD_DATA_PREFIX) || key.starts_with(HOLD_MARKER_PREFIX)
+}
+
+/// Returns whether a key belongs to one of the namespaces used internally by
+/// the blob store rather than to a blob.
+pub(crate) fn is_internal_key(key: &[u8]) -> bool {
+    is_hold_key(key)
+        || key.starts_with(UPLOAD_PREFIX)
+        || key.starts_with(GC_MARKER_PREFIX)
+        || key.starts_with(DICTIONARY_PREFIX)
+}
+
+pub(crate) fn hold_modified(key: &[u8]) -> trc::Error {
+    trc::StoreEvent::AssertValueFailed
+        .reason("Held blobs cannot be modified")
+        .ctx(trc::Key::Key, key)
+        .ctx(trc::Key::CausedBy, trc::location!())
+}
+
 impl CompressionAlgo {
     pub fn marker(&self) -> u8 {
         match self {
             CompressionAlgo::Lz4 => MAGIC_MARKER | 0x01,
-            //CompressionAlgo::Zstd => MAGIC_MARKER | 0x02,
-            CompressionAlgo::None => 0,
+            CompressionAlgo::Zstd(_) => MAGIC_MARKER | 0x02,
+            CompressionAlgo::Lz4Framed => MAGIC_MARKER | 0x04,
+            // Blobs stored uncompressed by a pipeline that compresses, the
+            // marker follows a checksum of the blob, see `pipeline::strip_uncompressed`
+            CompressionAlgo::None => MAGIC_MARKER | 0x08,
+        }
+    }
+
+    /// Returns the algorithm a marker belongs to. The level of Zstandard is not
+    /// recorded in blobs, so the default level is returned.
+    pub fn from_marker(marker: u8) -> Option<Self> {
+        [
+            CompressionAlgo::None,
+            CompressionAlgo::Lz4,
+            CompressionAlgo::Lz4Framed,
+            CompressionAlgo::zstd(),
+        ]
+        .into_iter()
+        .find(|algo| algo.marker() == marker)
+    }
+
+    pub fn zstd() -> Self {
+        CompressionAlgo::Zstd(zstd::DEFAULT_COMPRESSION_LEVEL)
+    }
+
+    /// Returns the HTTP content coding of the compressed data, if there is one.
+    /// LZ4 blobs are prefixed with their length or a block index of their own,
+    /// neither of which is the LZ4 frame format, so only Zstandard has one.
+    pub fn content_encoding(&self) -> Option<&'static str> {
+        match self {
+            CompressionAlgo::Zstd(_) => Some("zstd"),
+            CompressionAlgo::None | CompressionAlgo::Lz4 | CompressionAlgo::Lz4Framed => None,
+        }
+    }
+
+    pub fn compress<'x>(&self, data: &'x [u8]) -> Cow<'x, [u8]> {
+        match self {
+            CompressionAlgo::None => data.into(),
+            CompressionAlgo::Lz4 => {
+                let mut compressed = lz4_flex::compress_prepend_size(data);
+                compressed.push(CompressionAlgo::Lz4.marker());
+                compressed.into()
+            }
+            CompressionAlgo::Lz4Framed => {
+                let mut compressed = frame::encode(data);
+                compressed.push(CompressionAlgo::Lz4Framed.marker());
+                compressed.into()
+            }
+            CompressionAlgo::Zstd(level) => match zstd::encode_all(data, *level) {
+                Ok(mut compressed) => {
+                    compressed.push(self.marker());
+                    compressed.into()
+                }
+                Err(err) => {
+                    // Encoding into memory only fails on invalid parameters
+                    trc::event!(
+                        Store(StoreEvent::UnexpectedError),
+                        Details = "Failed to compress value",
+                        Reason = err.to_string(),
+                    );
+                    data.into()
+                }
+            },
+        }
+    }
+
+    pub fn decompress(data: &[u8]) -> trc::Result<Cow<'_, [u8]>> {
+        match data.split_last() {
+            Some((&marker, compressed)) if marker == CompressionAlgo::Lz4.marker() => {
+                lz4_flex::decompress_size_prepended(compressed)
+                    .map(Cow::Owned)
+                    .map_err(|err| {
+                        trc::StoreEvent::DecompressError
+                            .reason(err)
+                            .ctx(trc::Key::CausedBy, trc::location!())
+                    })
+            }
+            Some((&marker, compressed)) if marker == CompressionAlgo::Lz4Framed.marker() => {
+                frame::decode(compressed).map(Cow::Owned)
+            }
+            Some((&marker, compressed)) if marker == CompressionAlgo::zstd().marker() => {
+                zstd::decode_all(compressed).map(Cow::Owned).map_err(|err| {
+                    trc::StoreEvent::DecompressError
+                        .reason(err)
+                        .ctx(trc::Key::CausedBy, trc::location!())
+                })
+            }
+            _ => Ok(data.into()),
+        }
+    }
+
+    pub fn is_compressed(data: &[u8]) -> bool {
+        data.last().is_some_and(|marker| {
+            CompressionAlgo::from_marker(*marker)
+                .is_some_and(|algorithm| algorithm != CompressionAlgo::None)
+        })
+    }
+}
+
+/// Wrapper for values that may have been written with value-level compression,
+/// see `write::compress::encode_value`.
+pub struct Decompressed<T>(pub T);
+
+impl<T: Deserialize> Deserialize for Decompressed<T> {
+    fn deserialize(bytes: &[u8]) -> trc::Result<Self> {
+        T::deserialize(decompress_value(bytes)?.as_ref()).map(Decompressed)
+    }
+}
+
+impl ParseValue for BlobQuotaMode {
+    fn parse_value(value: &str) -> Result<Self, String> {
+        match value {
+            "logical" => Ok(BlobQuotaMode::Logical),
+            "physical" => Ok(BlobQuotaMode::Physical),
+            mode => Err(format!("Invalid quota accounting mode: {mode}",)),
         }
     }
 }
@@ -197,7 +1316,14 @@ impl ParseValue for CompressionAlgo {
     fn parse_value(value: &str) -> Result<Self, String> {
         match value {
             "lz4" => Ok(CompressionAlgo::Lz4),
-            //"zstd" => Ok(CompressionAlgo::Zstd),
+            "lz4-framed" => Ok(CompressionAlgo::Lz4Framed),
+            "zstd" => Ok(CompressionAlgo::zstd()),
+            // lz4_flex only implements the fast encoder
+            "lz4-hc" | "lz4hc" => Err(concat!(
+                "LZ4 high compression is not supported, ",
+                "use \"zstd\" for a better compression ratio"
+            )
+            .to_string()),
             "none" | "false" | "disable" | "disabled" => Ok(CompressionAlgo::None),
             algo => Err(format!("Invalid compression algorithm: {algo}",)),
         }
@@ -0,0 +1,377 @@
+/*
+ * SPDX-FileCopyrightText: 2020 Stalwart Labs Ltd <hello@stalw.art>
+ *
+ * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
+ */
+
+use std::{
+    io::{Read, Write},
+    sync::Arc,
+};
+
+use ahash::AHashMap;
+use parking_lot::RwLock;
+use trc::{AddContext, StoreEvent};
+
+use crate::{BlobStore, U32_LEN};
+
+use super::{
+    blob::{BlobHint, PreparedBlob},
+    pipeline::BlobTransform,
+};
+
+// Dictionaries are stored as they are, without going through the blob
+// pipeline, under keys made of the account id followed by the dictionary id:
+//
+// prefix | account id (u32, big-endian) | dictionary id (u32, big-endian)
+//
+// Blobs are shared between accounts through deduplication and keep the
+// dictionary they were compressed with, so dictionaries are never deleted.
+pub(crate) const DICTIONARY_PREFIX: &[u8] = b"\xffdict:";
+
+// Blobs compressed with an account dictionary end with the ids of the
+// dictionary followed by this marker:
+//
+// zstd frame | account id (u32, big-endian) | dictionary id (u32, big-endian) | marker
+pub(crate) const DICTIONARY_MARKER: u8 = 0xa0 | 0x06;
+const DICTIONARY_TRAILER_LEN: usize = U32_LEN * 2;
+
+/// Zstandard dictionary trained on the blobs of an account, see
+/// `BlobStore::train_account_dictionary`.
+#[derive(Debug)]
+pub struct AccountDictionary {
+    pub account_id: u32,
+    /// Increases every time a dictionary is trained for the account
+    pub dictionary_id: u32,
+    pub data: Vec<u8>,
+}
+
+/// Account dictionaries read by a blob store, shared by its clones.
+#[derive(Debug, Default)]
+pub struct BlobDictionaries {
+    // Every dictionary read so far, indexed by account and dictionary id
+    loaded: RwLock<AHashMap<(u32, u32), Arc<AccountDictionary>>>,
+    // Dictionary new blobs of an account are compressed with, `None` for
+    // accounts without one
+    current: RwLock<AHashMap<u32, Option<Arc<AccountDictionary>>>>,
+}
+
+/// Zstandard compression with an account dictionary, which replaces the
+/// compression stage of the pipeline for the blobs of the account.
+pub(crate) struct DictionaryCompression {
+    pub dictionary: Arc<AccountDictionary>,
+    pub level: i32,
+}
+
+impl BlobStore {
+    /// Trains a Zstandard dictionary of at most `max_size` bytes on samples of
+    /// the blobs of an account and stores it, returning its id. Blobs the
+    /// account writes through `prepare_account_blob` are then compressed with
+    /// it, blobs compressed with a previous dictionary remain readable.
+    ///
+    /// Other nodes keep compressing with the dictionary they found until they
+    /// are restarted, but read blobs compressed with any dictionary.
+    pub async fn train_account_dictionary(
+        &self,
+        account_id: u32,
+        samples: &[impl AsRef<[u8]>],
+        max_size: usize,
+    ) -> trc::Result<u32> {
+        let data = zstd::dict::from_samples(samples, max_size).map_err(|err| {
+            StoreEvent::UnexpectedError
+                .reason(err)
+                .ctx(trc::Key::AccountId, account_id)
+                .ctx(trc::Key::CausedBy, trc::location!())
+        })?;
+
+        // Nodes training a dictionary at the same time can pick the same id,
+        // the conditional write makes sure neither replaces the other
+        let mut dictionary_id = self
+            .account_dictionary_ids(account_id)
+            .await?
+            .last()
+            .map_or(0, |id| id + 1);
+        loop {
+            let _permit = self.acquire_permit().await?;
+            if self
+                .backend_put_blob_if_absent(&dictionary_key(account_id, dictionary_id), &data)
+                .await
+                .caused_by(trc::location!())?
+            {
+                break;
+            }
+            dictionary_id += 1;
+        }
+
+        let dictionary = Arc::new(AccountDictionary {
+            account_id,
+            dictionary_id,
+            data,
+        });
+        self.pipeline
+            .dictionaries()
+            .loaded
+            .write()
+            .insert((account_id, dictionary_id), dictionary.clone());
+        self.pipeline
+            .dictionaries()
+            .current
+            .write()
+            .insert(account_id, Some(dictionary));
+
+        Ok(dictionary_id)
+    }
+
+    /// Returns the dictionary the blobs of an account are compressed with, if
+    /// the account has one.
+    pub async fn account_dictionary(
+        &self,
+        account_id: u32,
+    ) -> trc::Result<Option<Arc<AccountDictionary>>> {
+        if let Some(dictionary) = self.pipeline.dictionaries().current.read().get(&account_id) {
+            return Ok(dictionary.clone());
+        }
+
+        let dictionary = match self.account_dictionary_ids(account_id).await?.last() {
+            Some(dictionary_id) => self
+                .load_dictionary(account_id, *dictionary_id)
+                .await
+                .caused_by(trc::location!())?,
+            None => None,
+        };
+        self.pipeline
+            .dictionaries()
+            .current
+            .write()
+            .insert(account_id, dictionary.clone());
+
+        Ok(dictionary)
+    }
+
+    /// Same as `prepare_blob` for a blob written by an account, which is
+    /// compressed with the dictionary of the account when it has one and the
+    /// pipeline compresses with Zstandard.
+    pub async fn prepare_account_blob<'x>(
+        &self,
+        account_id: u32,
+        data: &'x [u8],
+        hint: BlobHint<'_>,
+    ) -> trc::Result<PreparedBlob<'x>> {
+        match self.account_dictionary(account_id).await? {
+            Some(dictionary) if hint.is_compressible() => {
+                self.prepare_blob_with_dictionary(data, &dictionary)
+            }
+            _ => self.prepare_blob(data, hint),
+        }
+    }
+
+    /// Same as `put_blob_with_hint` for a blob written by an account, see
+    /// `prepare_account_blob`.
+    pub async fn put_account_blob(
+        &self,
+        account_id: u32,
+        key: &[u8],
+        data: &[u8],
+        hint: BlobHint<'_>,
+    ) -> trc::Result<()> {
+        let blob = self.prepare_account_blob(account_id, data, hint).await?;
+        self.put_prepared_blob(key, blob).await
+    }
+
+    /// Reverses the pipeline, reading the account dictionary the blob was
+    /// compressed with first if it was not read before.
+    pub(crate) async fn decode_blob(&self, key: &[u8], data: Vec<u8>) -> trc::Result<Vec<u8>> {
+        let data = self.pipeline.decode_transforms(key, data)?;
+        self.load_blob_dictionary(&data).await?;
+        self.pipeline.decompress(key, data)
+    }
+
+    /// Loads the dictionary named by the trailer of a blob whose stages other
+    /// than compression were reversed. Blobs stored as they are can end with
+    /// the same byte as the marker, so missing dictionaries are not an error
+    /// here, decoding a blob that needs one fails instead.
+    pub(crate) async fn load_blob_dictionary(&self, data: &[u8]) -> trc::Result<()> {
+        if let Some((account_id, dictionary_id)) = dictionary_ids(data) {
+            self.load_dictionary(account_id, dictionary_id)
+                .await
+                .caused_by(trc::location!())?;
+        }
+
+        Ok(())
+    }
+
+    async fn load_dictionary(
+        &self,
+        account_id: u32,
+        dictionary_id: u32,
+    ) -> trc::Result<Option<Arc<AccountDictionary>>> {
+        if let Some(dictionary) = self
+            .pipeline
+            .dictionaries()
+            .loaded
+            .read()
+            .get(&(account_id, dictionary_id))
+        {
+            return Ok(Some(dictionary.clone()));
+        }
+
+        let dictionary = self
+            .read_blob(&dictionary_key(account_id, dictionary_id), 0..usize::MAX)
+            .await
+            .caused_by(trc::location!())?
+            .map(|data| {
+                Arc::new(AccountDictionary {
+                    account_id,
+                    dictionary_id,
+                    data,
+                })
+            });
+        if let Some(dictionary) = &dictionary {
+            self.pipeline
+                .dictionaries()
+                .loaded
+                .write()
+                .insert((account_id, dictionary_id), dictionary.clone());
+        }
+
+        Ok(dictionary)
+    }
+
+    /// Returns the ids of the dictionaries trained for an account, sorted.
+    async fn account_dictionary_ids(&self, account_id: u32) -> trc::Result<Vec<u32>> {
+        let prefix = [DICTIONARY_PREFIX, &account_id.to_be_bytes()].concat();
+        let mut ids = self
+            .list_blobs(&prefix)
+            .await
+            .caused_by(trc::location!())?
+            .into_iter()
+            .filter_map(|key| {
+                key.strip_prefix(prefix.as_slice())
+                    .and_then(|id| id.try_into().ok())
+                    .map(u32::from_be_bytes)
+            })
+            .collect::<Vec<_>>();
+        ids.sort_unstable();
+
+        Ok(ids)
+    }
+}
+
+impl BlobDictionaries {
+    /// Decompresses a blob compressed with an account dictionary, `data` being
+    /// the blob without its marker. The dictionary has to be loaded already.
+    pub(crate) fn decode(&self, data: &[u8]) -> trc::Result<Vec<u8>> {
+        let (frame, trailer) = data
+            .len()
+            .checked_sub(DICTIONARY_TRAILER_LEN)
+            .map(|pos| data.split_at(pos))
+            .ok_or_else(|| {
+                StoreEvent::DecompressError
+                    .reason("Missing account dictionary trailer")
+                    .ctx(trc::Key::CausedBy, trc::location!())
+            })?;
+        let (account_id, dictionary_id) = trailer_ids(trailer);
+        self.loaded
+            .read()
+            .get(&(account_id, dictionary_id))
+            .cloned()
+            .ok_or_else(|| {
+                StoreEvent::DecompressError
+                    .reason("Unknown account dictionary")
+                    .ctx(trc::Key::AccountId, account_id)
+                    .ctx(trc::Key::Id, dictionary_id)
+                    .ctx(trc::Key::CausedBy, trc::location!())
+            })?
+            .decompress(frame)
+    }
+}
+
+impl AccountDictionary {
+    fn decompress(&self, frame: &[u8]) -> trc::Result<Vec<u8>> {
+        let mut decoded = Vec::new();
+        zstd::stream::read::Decoder::with_dictionary(frame, &self.data)
+            .and_then(|mut decoder| decoder.read_to_end(&mut decoded))
+            .map_err(|err| {
+                StoreEvent::DecompressError
+                    .reason(err)
+                    .ctx(trc::Key::AccountId, self.account_id)
+                    .ctx(trc::Key::CausedBy, trc::location!())
+            })?;
+
+        Ok(decoded)
+    }
+}
+
+impl BlobTransform for DictionaryCompression {
+    fn marker(&self) -> u8 {
+        DICTIONARY_MARKER
+    }
+
+    fn encode(&self, data: &[u8]) -> trc::Result<Vec<u8>> {
+        let mut encoded = zstd::stream::write::Encoder::with_dictionary(
+            Vec::new(),
+            self.level,
+            &self.dictionary.data,
+        )
+        .and_then(|mut encoder| {
+            encoder.write_all(data)?;
+            encoder.finish()
+        })
+        .map_err(|err| {
+            StoreEvent::UnexpectedError
+                .reason(err)
+                .ctx(trc::Key::CausedBy, trc::location!())
+        })?;
+        encoded.extend_from_slice(&self.dictionary.account_id.to_be_bytes());
+        encoded.extend_from_slice(&self.dictionary.dictionary_id.to_be_bytes());
+
+        Ok(encoded)
+    }
+
+    fn decode(&self, data: &[u8]) -> trc::Result<Vec<u8>> {
+        match data
+            .len()
+            .checked_sub(DICTIONARY_TRAILER_LEN)
+            .map(|pos| data.split_at(pos))
+        {
+            Some((frame, trailer))
+                if trailer_ids(trailer)
+                    == (self.dictionary.account_id, self.dictionary.dictionary_id) =>
+            {
+                self.dictionary.decompress(frame)
+            }
+            _ => Err(StoreEvent::DecompressError
+                .reason("Blob was compressed with another account dictionary")
+                .ctx(trc::Key::CausedBy, trc::location!())),
+        }
+    }
+}
+
+/// Returns the account and dictionary ids of a blob compressed with an account
+/// dictionary, `data` ending with the marker.
+pub(crate) fn dictionary_ids(data: &[u8]) -> Option<(u32, u32)> {
+    match data.split_last() {
+        Some((&DICTIONARY_MARKER, encoded)) => encoded
+            .len()
+            .checked_sub(DICTIONARY_TRAILER_LEN)
+            .map(|pos| trailer_ids(&encoded[pos..])),
+        _ => None,
+    }
+}
+
+fn trailer_ids(trailer: &[u8]) -> (u32, u32) {
+    let (account_id, dictionary_id) = trailer.split_at(U32_LEN);
+    (
+        u32::from_be_bytes(account_id.try_into().unwrap()),
+        u32::from_be_bytes(dictionary_id.try_into().unwrap()),
+    )
+}
+
+fn dictionary_key(account_id: u32, dictionary_id: u32) -> Vec<u8> {
+    [
+        DICTIONARY_PREFIX,
+        &account_id.to_be_bytes(),
+        &dictionary_id.to_be_bytes(),
+    ]
+    .concat()
+}
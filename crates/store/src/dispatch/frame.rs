@@ -0,0 +1,287 @@
+/*
+ * SPDX-FileCopyrightText: 2020 Stalwart Labs Ltd <hello@stalw.art>
+ *
+ * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
+ */
+
+use std::ops::Range;
+
+use futures::{StreamExt, stream};
+use trc::{AddContext, StoreEvent};
+
+use crate::{BlobStore, CompressionAlgo, U32_LEN, U64_LEN};
+
+use super::stream::{BlobChunks, STREAM_WINDOW};
+
+// Framed LZ4 splits blobs into independently compressed blocks so that ranged
+// reads only have to fetch and decompress the blocks overlapping the range:
+//
+// magic | block size (u32) | length (u64) | block count (u32) |
+// compressed block lengths (u32 each) | compressed blocks | marker
+//
+// Integers are big-endian, the marker is appended by the blob pipeline.
+const FRAME_MAGIC: &[u8; 4] = b"LZ4B";
+const FRAME_BLOCK_SIZE: usize = 64 * 1024;
+pub(crate) const FRAME_HEADER_LEN: usize = FRAME_MAGIC.len() + U32_LEN + U64_LEN + U32_LEN;
+
+#[derive(Clone, Copy)]
+struct FrameHeader {
+    block_size: usize,
+    len: usize,
+    block_count: usize,
+}
+
+pub(crate) fn encode(data: &[u8]) -> Vec<u8> {
+    let blocks = data
+        .chunks(FRAME_BLOCK_SIZE)
+        .map(lz4_flex::block::compress)
+        .collect::<Vec<_>>();
+    let mut encoded = Vec::with_capacity(
+        FRAME_HEADER_LEN
+            + blocks.len() * U32_LEN
+            + blocks.iter().map(|block| block.len()).sum::<usize>()
+            + 1,
+    );
+    encoded.extend_from_slice(FRAME_MAGIC);
+    encoded.extend_from_slice(&(FRAME_BLOCK_SIZE as u32).to_be_bytes());
+    encoded.extend_from_slice(&(data.len() as u64).to_be_bytes());
+    encoded.extend_from_slice(&(blocks.len() as u32).to_be_bytes());
+    for block in &blocks {
+        encoded.extend_from_slice(&(block.len() as u32).to_be_bytes());
+    }
+    for block in &blocks {
+        encoded.extend_from_slice(block);
+    }
+    encoded
+}
+
+pub(crate) fn decode(data: &[u8]) -> trc::Result<Vec<u8>> {
+    let header = FrameHeader::parse(data).ok_or_else(frame_error)?;
+    let index_end = header.index_end();
+    let block_lens = data
+        .get(FRAME_HEADER_LEN..index_end)
+        .ok_or_else(frame_error)
+        .map(block_lens)?;
+    if index_end + block_lens.iter().sum::<usize>() != data.len() {
+        return Err(frame_error());
+    }
+
+    header.decode_blocks(0, &block_lens, &data[index_end..])
+}
+
+/// Returns the uncompressed length recorded in the header of a framed blob.
+pub(crate) fn decoded_len(header: &[u8]) -> Option<usize> {
+    FrameHeader::parse(header).map(|header| header.len)
+}
+
+impl BlobStore {
+    /// Reads a range of a blob compressed with framed LZ4 by fetching only the
+    /// blocks overlapping it. Returns `None` when the blob is not framed or the
+    /// range is not within the blob, in which case it has to be read in full.
+    pub(crate) async fn get_blob_framed(
+        &self,
+        key: &[u8],
+        range: Range<usize>,
+    ) -> trc::Result<Option<Vec<u8>>> {
+        let (header, block_lens) = match self
+            .read_frame_index(key)
+            .await
+            .caused_by(trc::location!())?
+        {
+            Some((header, block_lens)) if range.end <= header.len => (header, block_lens),
+            _ => return Ok(None),
+        };
+        if range.start >= range.end {
+            return Ok(Some(Vec::new()));
+        }
+
+        let first_block = range.start / header.block_size;
+        let last_block = (range.end - 1) / header.block_size;
+        let blocks_start = header.index_end() + block_lens[..first_block].iter().sum::<usize>();
+        let blocks_len = block_lens[first_block..=last_block].iter().sum::<usize>();
+        let blocks = match self
+            .read_blob(key, blocks_start..blocks_start + blocks_len)
+            .await
+            .caused_by(trc::location!())?
+        {
+            Some(blocks) if blocks.len() == blocks_len => blocks,
+            _ => return Ok(None),
+        };
+
+        let offset = first_block * header.block_size;
+        header
+            .decode_blocks(first_block, &block_lens[first_block..=last_block], &blocks)
+            .map(|data| Some(data[range.start - offset..range.end - offset].to_vec()))
+            .map_err(|err| err.ctx(trc::Key::Key, key))
+    }
+
+    /// Streams a range of a blob compressed with framed LZ4, fetching and
+    /// decoding a few blocks at a time. Returns `None` when the blob is not
+    /// framed. Ranges extending past the end of the blob are truncated.
+    pub(crate) async fn get_blob_framed_stream(
+        &self,
+        key: &[u8],
+        range: Range<usize>,
+    ) -> trc::Result<Option<BlobChunks>> {
+        let (header, block_lens) = match self
+            .read_frame_index(key)
+            .await
+            .caused_by(trc::location!())?
+        {
+            Some(frame) => frame,
+            None => return Ok(None),
+        };
+        let range = range.start.min(header.len)..range.end.min(header.len);
+        if range.is_empty() {
+            return Ok(Some(stream::empty().boxed()));
+        }
+
+        let store = self.clone();
+        let key = key.to_vec();
+        let blocks_per_window = (STREAM_WINDOW / header.block_size).max(1);
+        let first_block = range.start / header.block_size;
+        let last_block = (range.end - 1) / header.block_size;
+
+        Ok(Some(
+            stream::try_unfold(first_block, move |block| {
+                let store = store.clone();
+                let key = key.clone();
+                let block_lens = block_lens.clone();
+                let range = range.clone();
+
+                async move {
+                    if block > last_block {
+                        return Ok(None);
+                    }
+                    let next_block = (block + blocks_per_window).min(last_block + 1);
+                    let blocks_start =
+                        header.index_end() + block_lens[..block].iter().sum::<usize>();
+                    let blocks_len = block_lens[block..next_block].iter().sum::<usize>();
+                    let blocks = match store
+                        .read_blob(&key, blocks_start..blocks_start + blocks_len)
+                        .await
+                        .caused_by(trc::location!())?
+                    {
+                        Some(blocks) if blocks.len() == blocks_len => blocks,
+                        _ => return Err(frame_error().ctx(trc::Key::Key, key.as_slice())),
+                    };
+
+                    let offset = block * header.block_size;
+                    let data = header
+                        .decode_blocks(block, &block_lens[block..next_block], &blocks)
+                        .map_err(|err| err.ctx(trc::Key::Key, key.as_slice()))?;
+                    let start = range.start.saturating_sub(offset);
+                    let end = (range.end - offset).min(data.len());
+
+                    Ok(Some((data[start..end].to_vec(), next_block)))
+                }
+            })
+            .boxed(),
+        ))
+    }
+
+    /// Reads the header and block index of a framed blob, returning `None` when
+    /// the blob does not exist or is not framed.
+    async fn read_frame_index(&self, key: &[u8]) -> trc::Result<Option<(FrameHeader, Vec<usize>)>> {
+        let header = match self
+            .read_blob(key, 0..FRAME_HEADER_LEN)
+            .await
+            .caused_by(trc::location!())?
+            .and_then(|data| FrameHeader::parse(&data))
+        {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+
+        let index_end = header.index_end();
+        let block_lens = match self
+            .read_blob(key, FRAME_HEADER_LEN..index_end)
+            .await
+            .caused_by(trc::location!())?
+        {
+            Some(index) if index.len() == index_end - FRAME_HEADER_LEN => block_lens(&index),
+            _ => return Ok(None),
+        };
+
+        // Make sure the blob ends where the header says it does, as blobs stored
+        // uncompressed could start with the frame magic
+        let blob_len = index_end + block_lens.iter().sum::<usize>() + 1;
+        let marker = CompressionAlgo::Lz4Framed.marker();
+        if self
+            .read_blob(key, blob_len - 1..blob_len + 1)
+            .await
+            .caused_by(trc::location!())?
+            .is_none_or(|trailer| trailer != [marker])
+        {
+            return Ok(None);
+        }
+
+        Ok(Some((header, block_lens)))
+    }
+}
+
+impl FrameHeader {
+    fn parse(data: &[u8]) -> Option<Self> {
+        let header = data.get(..FRAME_HEADER_LEN)?;
+        if !header.starts_with(FRAME_MAGIC) {
+            return None;
+        }
+        let block_size = u32::from_be_bytes(header[4..8].try_into().ok()?) as usize;
+        let len = u64::from_be_bytes(header[8..16].try_into().ok()?) as usize;
+        let block_count = u32::from_be_bytes(header[16..20].try_into().ok()?) as usize;
+
+        (block_size > 0
+            && block_size <= FRAME_BLOCK_SIZE * 16
+            && block_count == len.div_ceil(block_size))
+        .then_some(FrameHeader {
+            block_size,
+            len,
+            block_count,
+        })
+    }
+
+    fn index_end(&self) -> usize {
+        FRAME_HEADER_LEN + self.block_count * U32_LEN
+    }
+
+    fn decode_blocks(
+        &self,
+        first_block: usize,
+        block_lens: &[usize],
+        mut blocks: &[u8],
+    ) -> trc::Result<Vec<u8>> {
+        let start = first_block * self.block_size;
+        let end = (start + block_lens.len() * self.block_size).min(self.len);
+        let mut decoded = vec![0u8; end - start];
+
+        for (output, block_len) in decoded.chunks_mut(self.block_size).zip(block_lens) {
+            let (block, rest) = blocks
+                .split_at_checked(*block_len)
+                .ok_or_else(frame_error)?;
+            if lz4_flex::block::decompress_into(block, output).map_err(|err| {
+                StoreEvent::DecompressError
+                    .reason(err)
+                    .ctx(trc::Key::CausedBy, trc::location!())
+            })? != output.len()
+            {
+                return Err(frame_error());
+            }
+            blocks = rest;
+        }
+
+        Ok(decoded)
+    }
+}
+
+fn block_lens(index: &[u8]) -> Vec<usize> {
+    index
+        .chunks_exact(U32_LEN)
+        .map(|len| u32::from_be_bytes(len.try_into().unwrap()) as usize)
+        .collect()
+}
+
+fn frame_error() -> trc::Error {
+    StoreEvent::DecompressError
+        .reason("Invalid LZ4 frame")
+        .ctx(trc::Key::CausedBy, trc::location!())
+}
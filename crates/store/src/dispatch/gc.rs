@@ -0,0 +1,278 @@
+/*
+ * SPDX-FileCopyrightText: 2020 Stalwart Labs Ltd <hello@stalw.art>
+ *
+ * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
+ */
+
+use std::time::Duration;
+
+use ahash::{AHashMap, AHashSet};
+use trc::AddContext;
+use utils::{BLOB_HASH_LEN, BlobHash};
+
+use crate::{
+    BlobStore, IterateParams, Store, U32_LEN, U64_LEN, ValueKey,
+    write::{BlobOp, ValueClass, now},
+};
+
+use super::blob::is_internal_key;
+
+// Blobs found unreferenced are marked with the time they were first seen,
+// stored as they are under the key of the blob:
+//
+// prefix | blob key
+//
+// The marker holds the timestamp as a big-endian u64.
+pub(crate) const GC_MARKER_PREFIX: &[u8] = b"\xffgc:";
+
+impl Store {
+    /// Deletes blobs that no reservation, commit or link references, which are
+    /// left behind when the process stops between writing a blob and the
+    /// metadata referencing it.
+    ///
+    /// Every blob in the backend is listed and checked against the blob hashes
+    /// referenced by the store. Unreferenced blobs are only deleted once they
+    /// have been found unreferenced for at least `grace_period`, so uploads
+    /// still in progress are not removed: the first scan finding a blob marks
+    /// it, and a later scan deletes it if it is still unreferenced by then.
+    /// The grace period has to be longer than the time taken to write a blob
+    /// and commit it. Returns the number of blobs deleted.
+    pub async fn purge_orphaned_blobs(
+        &self,
+        blob_store: &BlobStore,
+        grace_period: Duration,
+    ) -> trc::Result<usize> {
+        // Blobs are listed before the references are read, so blobs committed
+        // in between are seen as referenced
+        let mut blob_keys = Vec::new();
+        let mut markers = AHashMap::new();
+        for key in blob_store
+            .list_blobs(&[])
+            .await
+            .caused_by(trc::location!())?
+        {
+            if let Some(blob_key) = key.strip_prefix(GC_MARKER_PREFIX) {
+                markers.insert(blob_key.to_vec(), key);
+            } else if key.len() == BLOB_HASH_LEN && !is_internal_key(&key) {
+                blob_keys.push(key);
+            }
+        }
+        let referenced = self.referenced_blobs().await.caused_by(trc::location!())?;
+
+        let now = now();
+        let mut delete_keys = Vec::new();
+        let mut expired = Vec::new();
+        let mut new_markers = Vec::new();
+        for key in blob_keys {
+            let marker_key = markers.remove(&key);
+            if referenced.contains(&BlobHash::try_from_hash_slice(&key).unwrap()) {
+                // Committed after being marked
+                delete_keys.extend(marker_key);
+                continue;
+            }
+
+            let first_seen = match &marker_key {
+                Some(marker_key) => blob_store
+                    .read_blob(marker_key, 0..U64_LEN)
+                    .await
+                    .caused_by(trc::location!())?
+                    .and_then(|value| value.try_into().ok())
+                    .map(u64::from_be_bytes),
+                None => None,
+            };
+            match first_seen {
+                Some(first_seen) if first_seen + grace_period.as_secs() <= now => {
+                    expired.extend(marker_key.map(|marker_key| (key, marker_key)));
+                }
+                Some(_) => {}
+                None => {
+                    new_markers.push([GC_MARKER_PREFIX, key.as_slice()].concat());
+                }
+            }
+        }
+
+        // Markers of blobs that were deleted by other means
+        delete_keys.extend(markers.into_values());
+
+        let _permit = blob_store.acquire_permit().await?;
+        for marker_key in new_markers {
+            blob_store
+                .backend_put_blob(&marker_key, &now.to_be_bytes())
+                .await
+                .caused_by(trc::location!())?;
+        }
+        drop(_permit);
+
+        blob_store
+            .delete_blobs(&delete_keys)
+            .await
+            .caused_by(trc::location!())?;
+
+        // Blobs can be referenced again while the scan runs, as writes reserve a
+        // hash and then skip storing the blob when it exists, so references are
+        // checked again right before each blob is deleted. Deduplicated writes
+        // also remove the marker of the blob, which restarts its grace period.
+        let mut deleted = 0;
+        if !expired.is_empty() {
+            let reserved = self.reserved_blobs().await.caused_by(trc::location!())?;
+            for (key, marker_key) in expired {
+                let hash = BlobHash::try_from_hash_slice(&key).unwrap();
+                let delete_keys = if reserved.contains(&hash)
+                    || self
+                        .is_blob_linked(&hash)
+                        .await
+                        .caused_by(trc::location!())?
+                {
+                    vec![marker_key]
+                } else if blob_store
+                    .blob_exists(&marker_key)
+                    .await
+                    .caused_by(trc::location!())?
+                {
+                    deleted += 1;
+                    vec![key, marker_key]
+                } else {
+                    continue;
+                };
+                blob_store
+                    .delete_blobs(&delete_keys)
+                    .await
+                    .caused_by(trc::location!())?;
+            }
+        }
+
+        Ok(deleted)
+    }
+
+    /// Returns the hashes of all blobs that are reserved, committed or linked.
+    async fn referenced_blobs(&self) -> trc::Result<AHashSet<BlobHash>> {
+        let mut hashes = self.reserved_blobs().await?;
+
+        // Commits and links
+        self.iterate(
+            IterateParams::new(
+                ValueKey {
+                    account_id: 0,
+                    collection: 0,
+                    document_id: 0,
+                    class: ValueClass::Blob(BlobOp::Link {
+                        hash: BlobHash::default(),
+                    }),
+                },
+                ValueKey {
+                    account_id: u32::MAX,
+                    collection: u8::MAX,
+                    document_id: u32::MAX,
+                    class: ValueClass::Blob(BlobOp::Link {
+                        hash: BlobHash::new_max(),
+                    }),
+                },
+            )
+            .ascending()
+            .no_values(),
+            |key, _| {
+                hashes.insert(
+                    BlobHash::try_from_hash_slice(
+                        key.get(0..BLOB_HASH_LEN).ok_or_else(|| {
+                            trc::Error::corrupted_key(key, None, trc::location!())
+                        })?,
+                    )
+                    .unwrap(),
+                );
+                Ok(true)
+            },
+        )
+        .await
+        .caused_by(trc::location!())?;
+
+        Ok(hashes)
+    }
+
+    /// Returns whether a blob is committed or linked to a document.
+    async fn is_blob_linked(&self, hash: &BlobHash) -> trc::Result<bool> {
+        let mut is_linked = false;
+        self.iterate(
+            IterateParams::new(
+                ValueKey {
+                    account_id: 0,
+                    collection: 0,
+                    document_id: 0,
+                    class: ValueClass::Blob(BlobOp::Link { hash: hash.clone() }),
+                },
+                ValueKey {
+                    account_id: u32::MAX,
+                    collection: u8::MAX,
+                    document_id: u32::MAX,
+                    class: ValueClass::Blob(BlobOp::Link { hash: hash.clone() }),
+                },
+            )
+            .ascending()
+            .no_values(),
+            |_, _| {
+                is_linked = true;
+                Ok(false)
+            },
+        )
+        .await
+        .caused_by(trc::location!())
+        .map(|_| is_linked)
+    }
+
+    /// Returns the hashes of the blobs reserved by any account. Reservations
+    /// are temporary, so there are few of them.
+    async fn reserved_blobs(&self) -> trc::Result<AHashSet<BlobHash>> {
+        let mut hashes = AHashSet::new();
+
+        // Reservations, including expired ones, which `purge_blobs` deletes
+        // together with their blobs
+        self.iterate(
+            IterateParams::new(
+                ValueKey {
+                    account_id: 0,
+                    collection: 0,
+                    document_id: 0,
+                    class: ValueClass::Blob(BlobOp::Reserve {
+                        until: 0,
+                        hash: BlobHash::default(),
+                    }),
+                },
+                ValueKey {
+                    account_id: u32::MAX,
+                    collection: 0,
+                    document_id: 0,
+                    class: ValueClass::Blob(BlobOp::Reserve {
+                        until: 0,
+                        hash: BlobHash::default(),
+                    }),
+                },
+            )
+            .ascending()
+            .no_values(),
+            |key, _| {
+                hashes.insert(
+                    BlobHash::try_from_hash_slice(
+                        key.get(U32_LEN..U32_LEN + BLOB_HASH_LEN).ok_or_else(|| {
+                            trc::Error::corrupted_key(key, None, trc::location!())
+                        })?,
+                    )
+                    .unwrap(),
+                );
+                Ok(true)
+            },
+        )
+        .await
+        .caused_by(trc::location!())?;
+
+        Ok(hashes)
+    }
+}
+
+impl BlobStore {
+    /// Removes the marker left on a blob found unreferenced, called when a write
+    /// is deduplicated onto the blob, which is then about to be referenced again.
+    pub(crate) async fn clear_gc_marker(&self, key: &[u8]) -> trc::Result<()> {
+        self.delete_blob(&[GC_MARKER_PREFIX, key].concat())
+            .await
+            .map(|_| ())
+    }
+}
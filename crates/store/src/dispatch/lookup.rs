@@ -6,6 +6,7 @@
 
 use std::borrow::Cow;
 
+use futures::future::BoxFuture;
 use trc::AddContext;
 use utils::config::Rate;
 
@@ -233,6 +234,33 @@ impl InMemoryStore {
         .caused_by(trc::location!())
     }
 
+    /// Whether `counter_watch` is supported by the store.
+    pub fn supports_watch(&self) -> bool {
+        matches!(self, InMemoryStore::Store(store) if store.supports_watch())
+    }
+
+    /// Registers a watch on a counter, see `Store::watch_counter`.
+    pub async fn counter_watch(
+        &self,
+        key: impl Into<LookupKey<'_>>,
+        value: i64,
+    ) -> trc::Result<BoxFuture<'static, trc::Result<()>>> {
+        match self {
+            InMemoryStore::Store(store) => {
+                store
+                    .watch_counter(
+                        ValueKey::from(ValueClass::InMemory(InMemoryClass::Counter(
+                            key.into().into_bytes(),
+                        ))),
+                        value,
+                    )
+                    .await
+            }
+            _ => Err(trc::StoreEvent::NotSupported.into_err()),
+        }
+        .caused_by(trc::location!())
+    }
+
     pub async fn key_exists(&self, key: impl Into<LookupKey<'_>>) -> trc::Result<bool> {
         match self {
             InMemoryStore::Store(store) => store
@@ -0,0 +1,321 @@
+/*
+ * SPDX-FileCopyrightText: 2020 Stalwart Labs Ltd <hello@stalw.art>
+ *
+ * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
+ */
+
+use std::{fmt::Display, str::FromStr};
+
+use ahash::AHashMap;
+use futures::{Stream, StreamExt, TryStreamExt, stream, stream::BoxStream};
+use trc::AddContext;
+use utils::config::utils::ParseValue;
+
+use crate::{
+    BlobBackend, BlobStore, CompressionAlgo, IterateParams, SUBSPACE_BLOBS, Store, write::AnyKey,
+};
+
+use super::dictionary::dictionary_ids;
+
+/// Describes a blob as it is stored in the backend, used to compare a blob
+/// store against a backup.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestEntry {
+    pub key: Vec<u8>,
+    /// Stored size, after compression and any other pipeline stage
+    pub size: usize,
+    /// BLAKE3 hash of the stored bytes
+    pub checksum: [u8; 32],
+    pub algorithm: CompressionAlgo,
+}
+
+/// Pages of blob keys, in the order the backend lists them.
+pub(crate) type KeyPages<'x> = BoxStream<'x, trc::Result<Vec<Vec<u8>>>>;
+
+// Number of keys read from the data store per page
+const STORE_PAGE_SIZE: usize = 1024;
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ManifestReport {
+    /// Blobs listed in the manifest that are not in the store
+    pub missing: Vec<Vec<u8>>,
+    /// Blobs in the store that are not listed in the manifest
+    pub extra: Vec<Vec<u8>>,
+    /// Blobs whose stored contents do not match the manifest
+    pub corrupt: Vec<Vec<u8>>,
+}
+
+impl BlobStore {
+    /// Streams a manifest entry for every blob whose key starts with `prefix`.
+    /// Entries are produced while the backend is being listed, so they follow
+    /// its listing order, which is only the key order on data stores.
+    pub fn generate_manifest<'x>(
+        &'x self,
+        prefix: &'x [u8],
+    ) -> impl Stream<Item = trc::Result<ManifestEntry>> + Send + 'x {
+        self.list_blob_pages(prefix)
+            .map_ok(|keys| stream::iter(keys.into_iter().map(Ok)))
+            .try_flatten()
+            .try_filter_map(move |key| async move {
+                match self
+                    .read_blob(&key, 0..usize::MAX)
+                    .await
+                    .caused_by(trc::location!())?
+                {
+                    Some(data) => self.manifest_entry(key, data).map(Some),
+                    None => Ok(None),
+                }
+            })
+    }
+
+    /// Compares a manifest against the blobs currently stored under `prefix`.
+    pub async fn verify_manifest(
+        &self,
+        prefix: &[u8],
+        manifest: impl IntoIterator<Item = ManifestEntry>,
+    ) -> trc::Result<ManifestReport> {
+        let mut expected = manifest
+            .into_iter()
+            .filter(|entry| entry.key.starts_with(prefix))
+            .map(|entry| (entry.key.clone(), entry))
+            .collect::<AHashMap<_, _>>();
+        let mut report = ManifestReport::default();
+
+        let mut keys = self
+            .list_blob_pages(prefix)
+            .map_ok(|keys| stream::iter(keys.into_iter().map(Ok)))
+            .try_flatten();
+        while let Some(key) = keys.try_next().await.caused_by(trc::location!())? {
+            let entry = expected.remove(&key);
+            let data = self
+                .read_blob(&key, 0..usize::MAX)
+                .await
+                .caused_by(trc::location!())?;
+
+            match (entry, data) {
+                (Some(entry), Some(data)) => {
+                    // Blobs that can't be decoded are reported as corrupt
+                    if self
+                        .manifest_entry(key.clone(), data)
+                        .is_ok_and(|live| live == entry)
+                    {
+                        continue;
+                    }
+                    report.corrupt.push(key);
+                }
+                (Some(_), None) => report.missing.push(key),
+                (None, Some(_)) => report.extra.push(key),
+                (None, None) => (),
+            }
+        }
+        report.missing.extend(expected.into_keys());
+        report.missing.sort_unstable();
+        report.extra.sort_unstable();
+        report.corrupt.sort_unstable();
+
+        Ok(report)
+    }
+
+    /// Returns the keys of the blobs starting with `prefix` in pages, as the
+    /// backend lists them and without the key prefix of the store.
+    pub(crate) fn list_blob_pages<'x>(&'x self, prefix: &'x [u8]) -> KeyPages<'x> {
+        // Buffered blobs are only listed once written
+        stream::once(self.flush())
+            .map_ok(move |_| {
+                self.backend
+                    .list_blob_pages(self.backend_key(prefix).into_owned())
+            })
+            .try_flatten()
+            .map_ok(move |keys| match &self.key_prefix {
+                Some(key_prefix) => keys
+                    .into_iter()
+                    .map(|key| key[key_prefix.len()..].to_vec())
+                    .collect(),
+                None => keys,
+            })
+            .boxed()
+    }
+
+    fn manifest_entry(&self, key: Vec<u8>, data: Vec<u8>) -> trc::Result<ManifestEntry> {
+        let size = data.len();
+        let checksum = blake3::hash(&data).into();
+        let data = self
+            .pipeline
+            .decode_transforms(&key, data)
+            .caused_by(trc::location!())?;
+        // Account dictionaries are not needed to tell the algorithm
+        let algorithm = if self.pipeline.compression().is_some() && dictionary_ids(&data).is_some()
+        {
+            Some(CompressionAlgo::zstd())
+        } else {
+            self.pipeline
+                .split_compression(&key, data)
+                .caused_by(trc::location!())?
+                .1
+        };
+
+        Ok(ManifestEntry {
+            key,
+            size,
+            checksum,
+            algorithm: algorithm.unwrap_or(CompressionAlgo::None),
+        })
+    }
+}
+
+impl ManifestReport {
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty() && self.extra.is_empty() && self.corrupt.is_empty()
+    }
+}
+
+impl BlobBackend {
+    /// Returns the keys of all blobs starting with `prefix` in pages, without
+    /// waiting for the whole listing.
+    pub(crate) fn list_blob_pages(&self, prefix: Vec<u8>) -> KeyPages<'_> {
+        match self {
+            BlobBackend::Store(store) => list_store_blob_pages(store, prefix),
+            BlobBackend::Fs(store) => store.list_blob_pages(prefix),
+            #[cfg(feature = "s3")]
+            BlobBackend::S3(store) => store.list_blob_pages(prefix),
+            #[cfg(feature = "azure")]
+            BlobBackend::Azure(store) => store.list_blob_pages(prefix),
+            #[cfg(feature = "enterprise")]
+            BlobBackend::Sharded(store) => stream::iter(&store.stores)
+                .flat_map(move |store| store.backend.list_blob_pages(prefix.clone()))
+                .boxed(),
+            // The slow tier holds every blob, the fast tier only a subset
+            #[cfg(feature = "enterprise")]
+            BlobBackend::Tiered(store) => store.slow.backend.list_blob_pages(prefix),
+        }
+    }
+}
+
+fn list_store_blob_pages(store: &Store, prefix: Vec<u8>) -> KeyPages<'_> {
+    stream::try_unfold(Some(prefix.clone()), move |from| {
+        let prefix = prefix.clone();
+        async move {
+            match from {
+                Some(from) => list_store_blobs(store, &prefix, from).await.map(Some),
+                None => Ok(None),
+            }
+        }
+    })
+    .boxed()
+}
+
+/// Lists up to `STORE_PAGE_SIZE` blobs starting at key `from`, returning the
+/// key the next page starts at.
+async fn list_store_blobs(
+    store: &Store,
+    prefix: &[u8],
+    from: Vec<u8>,
+) -> trc::Result<(Vec<Vec<u8>>, Option<Vec<u8>>)> {
+    // FoundationDB splits blobs into chunks suffixed by a 16-bit index
+    #[cfg(feature = "foundation")]
+    let suffix_len = if matches!(store, Store::FoundationDb(_)) {
+        std::mem::size_of::<u16>()
+    } else {
+        0
+    };
+    #[cfg(not(feature = "foundation"))]
+    let suffix_len = 0;
+
+    let mut keys: Vec<Vec<u8>> = Vec::new();
+    let mut next = None;
+    store
+        .iterate(
+            IterateParams::new(
+                AnyKey {
+                    subspace: SUBSPACE_BLOBS,
+                    key: from,
+                },
+                AnyKey {
+                    subspace: SUBSPACE_BLOBS,
+                    key: [prefix, &[u8::MAX; 64]].concat(),
+                },
+            )
+            .no_values(),
+            |raw_key, _| {
+                let key = &raw_key[..raw_key.len().saturating_sub(suffix_len)];
+                if keys.last().is_none_or(|last| last != key) {
+                    // Pages end before the first chunk of a blob, so blobs are
+                    // never split across pages
+                    if keys.len() == STORE_PAGE_SIZE {
+                        next = Some(raw_key.to_vec());
+                        return Ok(false);
+                    }
+                    keys.push(key.to_vec());
+                }
+                Ok(true)
+            },
+        )
+        .await
+        .caused_by(trc::location!())?;
+
+    Ok((keys, next))
+}
+
+// Manifests are exported one entry per line as
+// `<hex key> <stored size> <hex checksum> <algorithm>`
+impl Display for ManifestEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} {} {}",
+            to_hex(&self.key),
+            self.size,
+            to_hex(&self.checksum),
+            match self.algorithm {
+                CompressionAlgo::Lz4 => "lz4",
+                CompressionAlgo::Lz4Framed => "lz4-framed",
+                CompressionAlgo::Zstd(_) => "zstd",
+                CompressionAlgo::None => "none",
+            }
+        )
+    }
+}
+
+impl FromStr for ManifestEntry {
+    type Err = String;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let mut fields = line.split_ascii_whitespace();
+        let mut next_field = || {
+            fields
+                .next()
+                .ok_or_else(|| format!("Incomplete manifest entry: {line:?}"))
+        };
+        let key = from_hex(next_field()?)?;
+        let size = next_field()?
+            .parse()
+            .map_err(|_| format!("Invalid blob size in manifest entry: {line:?}"))?;
+        let checksum = from_hex(next_field()?)?
+            .try_into()
+            .map_err(|_| format!("Invalid checksum in manifest entry: {line:?}"))?;
+        let algorithm = CompressionAlgo::parse_value(next_field()?)?;
+
+        Ok(ManifestEntry {
+            key,
+            size,
+            checksum,
+            algorithm,
+        })
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn from_hex(hex: &str) -> Result<Vec<u8>, String> {
+    if hex.len() % 2 == 0 {
+        (0..hex.len())
+            .step_by(2)
+            .map(|pos| u8::from_str_radix(&hex[pos..pos + 2], 16))
+            .collect::<Result<_, _>>()
+            .map_err(|_| format!("Invalid hex value: {hex:?}"))
+    } else {
+        Err(format!("Invalid hex value: {hex:?}"))
+    }
+}
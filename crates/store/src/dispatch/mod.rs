@@ -8,10 +8,21 @@ use roaring::RoaringBitmap;
 
 use crate::Store;
 
+pub mod advisor;
 pub mod blob;
+pub mod dictionary;
+pub mod frame;
 pub mod fts;
+pub mod gc;
 pub mod lookup;
+pub mod manifest;
+pub mod pipeline;
+pub mod settings;
+pub mod snapshot;
+pub mod stats;
 pub mod store;
+pub mod stream;
+pub mod upload;
 
 impl Store {
     pub fn id(&self) -> &'static str {
@@ -0,0 +1,604 @@
+/*
+ * SPDX-FileCopyrightText: 2020 Stalwart Labs Ltd <hello@stalw.art>
+ *
+ * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
+ */
+
+use std::{borrow::Cow, io::Write, sync::Arc};
+
+use trc::StoreEvent;
+use xxhash_rust::xxh3::Xxh3;
+
+use crate::{CompressionAlgo, U32_LEN};
+
+use super::dictionary::{
+    AccountDictionary, BlobDictionaries, DICTIONARY_MARKER, DictionaryCompression, dictionary_ids,
+};
+
+/// A reversible transformation applied to blobs before they are written.
+///
+/// Encoded data is followed by the marker of the stage, which is how reads
+/// detect whether the stage was applied to a blob.
+pub trait BlobTransform: Sync + Send {
+    fn marker(&self) -> u8;
+
+    fn encode(&self, data: &[u8]) -> trc::Result<Vec<u8>>;
+
+    fn decode(&self, data: &[u8]) -> trc::Result<Vec<u8>>;
+
+    /// Called for blobs lacking the marker of the stage, which are read as
+    /// written before the stage was configured unless an error is returned.
+    fn missing_marker(&self, _data: &[u8]) -> trc::Result<()> {
+        Ok(())
+    }
+
+    /// Returns an encoder producing the same output as `encode` from data
+    /// received in chunks, or `None` if the stage needs the whole blob.
+    fn encoder(&self) -> Option<Box<dyn BlobEncoder>> {
+        None
+    }
+
+    /// Returns a stage compressing with the dictionary of an account in place
+    /// of this one, or `None` if the stage does not support dictionaries.
+    fn with_dictionary(
+        &self,
+        _dictionary: &Arc<AccountDictionary>,
+    ) -> Option<Box<dyn BlobTransform>> {
+        None
+    }
+}
+
+/// Incremental form of `BlobTransform::encode`.
+pub trait BlobEncoder: Send {
+    /// Encodes the next chunk of the blob, returning the encoded bytes that are
+    /// ready so far.
+    fn update(&mut self, data: &[u8]) -> trc::Result<Vec<u8>>;
+
+    /// Returns the remaining encoded bytes, without the marker of the stage.
+    fn finish(self: Box<Self>) -> trc::Result<Vec<u8>>;
+}
+
+/// Encodes a blob received in chunks through every stage of a pipeline.
+pub struct PipelineEncoder {
+    stages: Vec<(Box<dyn BlobEncoder>, u8)>,
+}
+
+/// Ordered list of transformations, applied in order on write and in reverse
+/// order on read.
+#[derive(Clone)]
+pub struct BlobPipeline {
+    stages: Vec<Arc<dyn BlobTransform>>,
+    /// Whether blobs compressed before compression was disabled may exist,
+    /// see `with_legacy_compression`
+    legacy_compression: bool,
+    /// Account dictionaries read so far, see `BlobStore::account_dictionary`
+    dictionaries: Arc<BlobDictionaries>,
+}
+
+/// Appends an xxh3 checksum to blobs, reads fail with `BlobChecksumMismatch`
+/// if the blob does not match it. Blobs stored before checksums were enabled
+/// lack its marker and are returned unverified, unless they end with the
+/// checksum of their contents, in which case only the marker was removed.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BlobChecksum;
+
+const CHECKSUM_MARKER: u8 = 0xa0 | 0x03;
+const CHECKSUM_LEN: usize = std::mem::size_of::<u64>();
+
+// Blobs stored uncompressed by a pipeline that compresses end with the first
+// 32 bits of their xxh3 hash followed by the marker of `CompressionAlgo::None`.
+// Blobs written before compression was configured can end with the same byte,
+// the hash tells them apart.
+fn uncompressed_checksum(data: &[u8]) -> [u8; U32_LEN] {
+    (xxhash_rust::xxh3::xxh3_64(data) as u32).to_be_bytes()
+}
+
+impl Default for BlobPipeline {
+    fn default() -> Self {
+        Self {
+            stages: Vec::new(),
+            legacy_compression: true,
+            dictionaries: Default::default(),
+        }
+    }
+}
+
+impl BlobPipeline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_stage(mut self, stage: impl BlobTransform + 'static) -> Self {
+        debug_assert!(
+            !self.stages.iter().any(|s| s.marker() == stage.marker()),
+            "duplicate blob transform marker"
+        );
+        self.stages.push(Arc::new(stage));
+        self
+    }
+
+    /// Replaces the compression stage, which always runs first.
+    pub fn with_compression(mut self, compression: CompressionAlgo) -> Self {
+        self.stages
+            .retain(|stage| CompressionAlgo::from_marker(stage.marker()).is_none());
+        if !matches!(compression, CompressionAlgo::None) {
+            self.stages.insert(0, Arc::new(compression));
+        }
+        self
+    }
+
+    /// Whether blobs ending with a compression marker are decoded when there is
+    /// no compression stage, which is the default so that blobs compressed
+    /// before compression was disabled remain readable. Finding the marker
+    /// costs an extra request on ranged reads, and uncompressed blobs can end
+    /// with a byte that looks like one, so it can be turned off for stores that
+    /// never had compression enabled.
+    pub fn with_legacy_compression(mut self, legacy_compression: bool) -> Self {
+        self.legacy_compression = legacy_compression;
+        self
+    }
+
+    pub fn has_legacy_compression(&self) -> bool {
+        self.legacy_compression
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stages.is_empty()
+    }
+
+    /// Whether compression is the only stage.
+    pub fn is_compression_only(&self) -> bool {
+        matches!(
+            self.stages.as_slice(),
+            [stage] if CompressionAlgo::from_marker(stage.marker()).is_some()
+        )
+    }
+
+    /// Returns the algorithm of the compression stage, if there is one. Zstd is
+    /// returned with the default level as stages only expose their marker.
+    pub fn compression(&self) -> Option<CompressionAlgo> {
+        self.stages
+            .first()
+            .and_then(|stage| CompressionAlgo::from_marker(stage.marker()))
+    }
+
+    /// Whether framed LZ4 is the only stage, so ranges can be decoded without
+    /// reading the whole blob.
+    pub fn is_framed(&self) -> bool {
+        matches!(
+            self.stages.as_slice(),
+            [stage] if stage.marker() == CompressionAlgo::Lz4Framed.marker()
+        )
+    }
+
+    pub fn encode<'x>(&self, data: &'x [u8]) -> trc::Result<Cow<'x, [u8]>> {
+        self.encode_stages(data, None)
+    }
+
+    /// Same as `encode` with the compression stage replaced by one using the
+    /// dictionary of an account, which only Zstandard supports. Blobs are
+    /// encoded as `encode` does with other algorithms.
+    pub fn encode_with_dictionary<'x>(
+        &self,
+        data: &'x [u8],
+        dictionary: &Arc<AccountDictionary>,
+    ) -> trc::Result<Cow<'x, [u8]>> {
+        match self
+            .compression_stage()
+            .and_then(|stage| stage.with_dictionary(dictionary))
+        {
+            Some(compression) => self.encode_stages(data, Some(compression.as_ref())),
+            None => self.encode(data),
+        }
+    }
+
+    /// Returns an encoder producing the same output as `encode` from data
+    /// received in chunks, or `None` if a stage needs the whole blob.
+    pub fn encoder(&self) -> Option<PipelineEncoder> {
+        self.stages
+            .iter()
+            .map(|stage| stage.encoder().map(|encoder| (encoder, stage.marker())))
+            .collect::<Option<Vec<_>>>()
+            .map(|stages| PipelineEncoder { stages })
+    }
+
+    /// Same as `encode` with the compression stage replaced by
+    /// `CompressionAlgo::None`, which only appends a checksum and its marker.
+    pub fn encode_uncompressed<'x>(&self, data: &'x [u8]) -> trc::Result<Cow<'x, [u8]>> {
+        self.encode_stages(data, Some(&CompressionAlgo::None))
+    }
+
+    /// Runs every stage, using `compression` in place of the compression stage
+    /// when given.
+    fn encode_stages<'x>(
+        &self,
+        data: &'x [u8],
+        compression: Option<&dyn BlobTransform>,
+    ) -> trc::Result<Cow<'x, [u8]>> {
+        let mut data = Cow::Borrowed(data);
+        for stage in &self.stages {
+            let stage: &dyn BlobTransform = match compression {
+                Some(compression) if CompressionAlgo::from_marker(stage.marker()).is_some() => {
+                    compression
+                }
+                _ => stage.as_ref(),
+            };
+            let mut encoded = stage.encode(data.as_ref())?;
+            encoded.push(stage.marker());
+            data = Cow::Owned(encoded);
+        }
+        Ok(data)
+    }
+
+    /// Reverses the pipeline, stages whose marker is missing are skipped as the
+    /// blob was written before they were configured. Blobs are decompressed
+    /// with the algorithm of their marker, so those compressed before the
+    /// algorithm was changed or compression was disabled remain readable.
+    ///
+    /// Blobs compressed with an account dictionary can only be decoded once the
+    /// dictionary was read, which `BlobStore::decode_blob` takes care of.
+    pub fn decode(&self, key: &[u8], data: Vec<u8>) -> trc::Result<Vec<u8>> {
+        let data = self.decode_transforms(key, data)?;
+        self.decompress(key, data)
+    }
+
+    /// Reverses every stage except compression.
+    pub fn decode_transforms(&self, key: &[u8], data: Vec<u8>) -> trc::Result<Vec<u8>> {
+        let stages = match self.compression_stage() {
+            Some(_) => &self.stages[1..],
+            None => &self.stages[..],
+        };
+        decode_stages(stages, &self.dictionaries, key, data)
+    }
+
+    /// Reverses the compression stage of a blob returned by `decode_transforms`.
+    pub fn decompress(&self, key: &[u8], data: Vec<u8>) -> trc::Result<Vec<u8>> {
+        match self.compression_stage() {
+            Some(stage) => {
+                decode_stages(std::slice::from_ref(stage), &self.dictionaries, key, data)
+            }
+            None if self.legacy_compression => {
+                Ok(decode_trailing_compression(&self.dictionaries, data))
+            }
+            None => Ok(data),
+        }
+    }
+
+    /// Reverses every stage except compression, returning the compressed bytes
+    /// and the algorithm they were compressed with. Blobs that were stored
+    /// uncompressed are returned decoded with no algorithm.
+    pub fn decode_compressed(
+        &self,
+        key: &[u8],
+        data: Vec<u8>,
+    ) -> trc::Result<(Vec<u8>, Option<CompressionAlgo>)> {
+        let data = self.decode_transforms(key, data)?;
+        self.split_compression(key, data)
+    }
+
+    /// Same as `decode_compressed` for a blob returned by `decode_transforms`.
+    /// Blobs compressed with an account dictionary are returned decoded with no
+    /// algorithm, as their frames can't be decoded without the dictionary.
+    pub fn split_compression(
+        &self,
+        key: &[u8],
+        mut data: Vec<u8>,
+    ) -> trc::Result<(Vec<u8>, Option<CompressionAlgo>)> {
+        if self.compression_stage().is_none() {
+            return self.decompress(key, data).map(|data| (data, None));
+        }
+
+        // Blobs may have been compressed before the algorithm was changed
+        match data
+            .split_last()
+            .and_then(|(marker, encoded)| compression_of(*marker, encoded))
+        {
+            Some(CompressionAlgo::None) => {
+                data.truncate(data.len() - U32_LEN - 1);
+                Ok((data, None))
+            }
+            Some(algorithm) => {
+                data.pop();
+                Ok((data, Some(algorithm)))
+            }
+            None if dictionary_ids(&data).is_some() => {
+                self.decompress(key, data).map(|data| (data, None))
+            }
+            None => {
+                trc::event!(Store(StoreEvent::BlobMissingMarker), Key = key,);
+                Ok((data, None))
+            }
+        }
+    }
+
+    pub(crate) fn dictionaries(&self) -> &BlobDictionaries {
+        &self.dictionaries
+    }
+
+    fn compression_stage(&self) -> Option<&Arc<dyn BlobTransform>> {
+        self.stages
+            .first()
+            .filter(|stage| CompressionAlgo::from_marker(stage.marker()).is_some())
+    }
+}
+
+impl PipelineEncoder {
+    pub fn update(&mut self, data: &[u8]) -> trc::Result<Vec<u8>> {
+        let mut data = data.to_vec();
+        for (encoder, _) in &mut self.stages {
+            data = encoder.update(&data)?;
+        }
+        Ok(data)
+    }
+
+    pub fn finish(self) -> trc::Result<Vec<u8>> {
+        // The output of each stage is followed by its marker, which is then
+        // encoded by the next stages
+        let mut data = Vec::new();
+        for (mut encoder, marker) in self.stages {
+            let mut encoded = encoder.update(&data)?;
+            encoded.extend_from_slice(&encoder.finish()?);
+            encoded.push(marker);
+            data = encoded;
+        }
+        Ok(data)
+    }
+}
+
+/// Returns the algorithm of a compression marker found at the end of a blob
+/// read without a compression stage. Blobs stored as they are can end with a
+/// byte that looks like a marker, so the marker of `CompressionAlgo::None`,
+/// which can only be verified against the whole blob, is ignored. Blobs
+/// compressed with an account dictionary are reported as Zstandard.
+pub(crate) fn trailing_compression(marker: u8) -> Option<CompressionAlgo> {
+    if marker == DICTIONARY_MARKER {
+        return Some(CompressionAlgo::zstd());
+    }
+    CompressionAlgo::from_marker(marker).filter(|algorithm| *algorithm != CompressionAlgo::None)
+}
+
+/// Returns the algorithm of a compression marker, `encoded` being the blob
+/// without it. The marker of `CompressionAlgo::None` is only accepted when
+/// the blob ends with a valid checksum.
+fn compression_of(marker: u8, encoded: &[u8]) -> Option<CompressionAlgo> {
+    CompressionAlgo::from_marker(marker).filter(|algorithm| {
+        *algorithm != CompressionAlgo::None || strip_uncompressed(encoded).is_some()
+    })
+}
+
+/// Removes the checksum appended by `CompressionAlgo::None`, returning `None`
+/// if the blob does not end with a valid one.
+pub(crate) fn strip_uncompressed(encoded: &[u8]) -> Option<&[u8]> {
+    let (data, checksum) = encoded
+        .len()
+        .checked_sub(U32_LEN)
+        .map(|pos| encoded.split_at(pos))?;
+    (checksum == uncompressed_checksum(data)).then_some(data)
+}
+
+/// Decompresses a blob ending with a compression marker when no compression
+/// stage is configured. The blob is returned unchanged unless it decodes with
+/// the algorithm of the marker, as it may have been stored uncompressed.
+fn decode_trailing_compression(dictionaries: &BlobDictionaries, data: Vec<u8>) -> Vec<u8> {
+    let decoded = data.split_last().and_then(|(&marker, encoded)| {
+        if marker == CompressionAlgo::None.marker() {
+            return strip_uncompressed(encoded).map(|data| data.to_vec());
+        } else if marker == DICTIONARY_MARKER {
+            return dictionaries.decode(encoded).ok();
+        }
+        let algorithm = trailing_compression(marker)?;
+        // LZ4 does not expand data more than 255 times, which avoids allocating
+        // buffers for sizes read from arbitrary data
+        if algorithm == CompressionAlgo::Lz4
+            && encoded
+                .get(..U32_LEN)
+                .and_then(|size| size.try_into().ok())
+                .is_none_or(|size| {
+                    u32::from_le_bytes(size) as usize > (encoded.len() - U32_LEN) * 255
+                })
+        {
+            return None;
+        }
+        algorithm.decode(encoded).ok()
+    });
+
+    decoded.unwrap_or(data)
+}
+
+fn decode_stages(
+    stages: &[Arc<dyn BlobTransform>],
+    dictionaries: &BlobDictionaries,
+    key: &[u8],
+    mut data: Vec<u8>,
+) -> trc::Result<Vec<u8>> {
+    for stage in stages.iter().rev() {
+        match data.split_last() {
+            Some((&marker, encoded)) if marker == stage.marker() => {
+                data = stage
+                    .decode(encoded)
+                    .map_err(|err| err.ctx(trc::Key::Key, key))?;
+            }
+            // Compressed with the dictionary of an account
+            Some((&DICTIONARY_MARKER, encoded))
+                if CompressionAlgo::from_marker(stage.marker()).is_some() =>
+            {
+                data = dictionaries
+                    .decode(encoded)
+                    .map_err(|err| err.ctx(trc::Key::Key, key))?;
+            }
+            // Compressed with a previously configured algorithm
+            Some((&marker, encoded))
+                if CompressionAlgo::from_marker(stage.marker()).is_some()
+                    && compression_of(marker, encoded).is_some() =>
+            {
+                data = compression_of(marker, encoded)
+                    .unwrap_or(CompressionAlgo::None)
+                    .decode(encoded)
+                    .map_err(|err| err.ctx(trc::Key::Key, key))?;
+            }
+            _ => {
+                stage
+                    .missing_marker(&data)
+                    .map_err(|err| err.ctx(trc::Key::Key, key))?;
+                trc::event!(Store(StoreEvent::BlobMissingMarker), Key = key,);
+            }
+        }
+    }
+    Ok(data)
+}
+
+impl BlobTransform for CompressionAlgo {
+    fn marker(&self) -> u8 {
+        CompressionAlgo::marker(self)
+    }
+
+    fn encode(&self, data: &[u8]) -> trc::Result<Vec<u8>> {
+        match self {
+            CompressionAlgo::None => Ok([data, &uncompressed_checksum(data)].concat()),
+            CompressionAlgo::Lz4 => Ok(lz4_flex::compress_prepend_size(data)),
+            CompressionAlgo::Lz4Framed => Ok(super::frame::encode(data)),
+            CompressionAlgo::Zstd(level) => zstd::encode_all(data, *level).map_err(|err| {
+                StoreEvent::UnexpectedError
+                    .reason(err)
+                    .ctx(trc::Key::CausedBy, trc::location!())
+            }),
+        }
+    }
+
+    fn decode(&self, data: &[u8]) -> trc::Result<Vec<u8>> {
+        match self {
+            CompressionAlgo::None => strip_uncompressed(data)
+                .map(|data| data.to_vec())
+                .ok_or_else(|| {
+                    StoreEvent::DecompressError
+                        .reason("Invalid uncompressed blob checksum")
+                        .ctx(trc::Key::CausedBy, trc::location!())
+                }),
+            CompressionAlgo::Lz4 => lz4_flex::decompress_size_prepended(data).map_err(|err| {
+                StoreEvent::DecompressError
+                    .reason(err)
+                    .ctx(trc::Key::CausedBy, trc::location!())
+            }),
+            CompressionAlgo::Lz4Framed => super::frame::decode(data),
+            CompressionAlgo::Zstd(_) => zstd::decode_all(data).map_err(|err| {
+                StoreEvent::DecompressError
+                    .reason(err)
+                    .ctx(trc::Key::CausedBy, trc::location!())
+            }),
+        }
+    }
+
+    fn encoder(&self) -> Option<Box<dyn BlobEncoder>> {
+        match self {
+            CompressionAlgo::None => Some(Box::new(UncompressedEncoder(Xxh3::new()))),
+            CompressionAlgo::Zstd(level) => zstd::stream::write::Encoder::new(Vec::new(), *level)
+                .ok()
+                .map(|encoder| Box::new(ZstdEncoder(encoder)) as Box<dyn BlobEncoder>),
+            // Both store the length of the blob before its contents
+            CompressionAlgo::Lz4 | CompressionAlgo::Lz4Framed => None,
+        }
+    }
+
+    fn with_dictionary(
+        &self,
+        dictionary: &Arc<AccountDictionary>,
+    ) -> Option<Box<dyn BlobTransform>> {
+        match self {
+            CompressionAlgo::Zstd(level) => Some(Box::new(DictionaryCompression {
+                dictionary: dictionary.clone(),
+                level: *level,
+            })),
+            CompressionAlgo::None | CompressionAlgo::Lz4 | CompressionAlgo::Lz4Framed => None,
+        }
+    }
+}
+
+struct UncompressedEncoder(Xxh3);
+
+impl BlobEncoder for UncompressedEncoder {
+    fn update(&mut self, data: &[u8]) -> trc::Result<Vec<u8>> {
+        self.0.update(data);
+        Ok(data.to_vec())
+    }
+
+    fn finish(self: Box<Self>) -> trc::Result<Vec<u8>> {
+        Ok((self.0.digest() as u32).to_be_bytes().to_vec())
+    }
+}
+
+struct ZstdEncoder(zstd::stream::write::Encoder<'static, Vec<u8>>);
+
+impl BlobEncoder for ZstdEncoder {
+    fn update(&mut self, data: &[u8]) -> trc::Result<Vec<u8>> {
+        self.0.write_all(data).map_err(|err| {
+            StoreEvent::UnexpectedError
+                .reason(err)
+                .ctx(trc::Key::CausedBy, trc::location!())
+        })?;
+        Ok(std::mem::take(self.0.get_mut()))
+    }
+
+    fn finish(self: Box<Self>) -> trc::Result<Vec<u8>> {
+        self.0.finish().map_err(|err| {
+            StoreEvent::UnexpectedError
+                .reason(err)
+                .ctx(trc::Key::CausedBy, trc::location!())
+        })
+    }
+}
+
+impl BlobTransform for BlobChecksum {
+    fn marker(&self) -> u8 {
+        CHECKSUM_MARKER
+    }
+
+    fn encode(&self, data: &[u8]) -> trc::Result<Vec<u8>> {
+        let mut encoded = Vec::with_capacity(data.len() + CHECKSUM_LEN + 1);
+        encoded.extend_from_slice(data);
+        encoded.extend_from_slice(&xxhash_rust::xxh3::xxh3_64(data).to_be_bytes());
+        Ok(encoded)
+    }
+
+    fn decode(&self, data: &[u8]) -> trc::Result<Vec<u8>> {
+        if let Some((data, checksum)) = data
+            .len()
+            .checked_sub(CHECKSUM_LEN)
+            .map(|pos| data.split_at(pos))
+        {
+            if checksum == xxhash_rust::xxh3::xxh3_64(data).to_be_bytes() {
+                return Ok(data.to_vec());
+            }
+        }
+
+        Err(StoreEvent::BlobChecksumMismatch
+            .into_err()
+            .ctx(trc::Key::CausedBy, trc::location!()))
+    }
+
+    fn missing_marker(&self, data: &[u8]) -> trc::Result<()> {
+        if self.decode(data).is_err() {
+            Ok(())
+        } else {
+            Err(StoreEvent::BlobChecksumMismatch
+                .reason("Blob checksum has no marker")
+                .ctx(trc::Key::CausedBy, trc::location!()))
+        }
+    }
+
+    fn encoder(&self) -> Option<Box<dyn BlobEncoder>> {
+        Some(Box::new(ChecksumEncoder(Xxh3::new())))
+    }
+}
+
+struct ChecksumEncoder(Xxh3);
+
+impl BlobEncoder for ChecksumEncoder {
+    fn update(&mut self, data: &[u8]) -> trc::Result<Vec<u8>> {
+        self.0.update(data);
+        Ok(data.to_vec())
+    }
+
+    fn finish(self: Box<Self>) -> trc::Result<Vec<u8>> {
+        Ok(self.0.digest().to_be_bytes().to_vec())
+    }
+}
@@ -0,0 +1,118 @@
+/*
+ * SPDX-FileCopyrightText: 2020 Stalwart Labs Ltd <hello@stalw.art>
+ *
+ * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
+ */
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use parking_lot::RwLock;
+
+use crate::{
+    CompressionAlgo, Store,
+    write::{DEFAULT_ID_ASSIGNMENT_WINDOW, compress::ValueCompression},
+};
+
+/// Settings of a single data store, parsed from `store.<id>.*` by
+/// `Stores::parse_stores`. Stores are not reopened when the configuration is
+/// reloaded, so their settings can be changed while they are in use.
+#[derive(Debug)]
+pub struct StoreSettings {
+    value_compression: RwLock<Option<ValueCompression>>,
+    read_repair: AtomicBool,
+    id_assignment_window: AtomicUsize,
+    // Per-collection overrides of the id assignment window, zero when unset
+    collection_id_assignment_windows: [AtomicUsize; 256],
+}
+
+// Settings of `Store::None`, which never reads or writes
+static NO_SETTINGS: StoreSettings = StoreSettings::new();
+
+impl StoreSettings {
+    pub const fn new() -> Self {
+        Self {
+            value_compression: RwLock::new(None),
+            read_repair: AtomicBool::new(false),
+            id_assignment_window: AtomicUsize::new(DEFAULT_ID_ASSIGNMENT_WINDOW),
+            collection_id_assignment_windows: [const { AtomicUsize::new(0) }; 256],
+        }
+    }
+
+    /// Compression applied to property values written through `Store::write`.
+    pub fn value_compression(&self) -> Option<ValueCompression> {
+        *self.value_compression.read()
+    }
+
+    /// Sets the compression used for property values. Values written while it was
+    /// enabled are still decompressed on read after disabling it.
+    pub fn set_value_compression(&self, compression: Option<ValueCompression>) {
+        *self.value_compression.write() = compression
+            .filter(|compression| !matches!(compression.algorithm, CompressionAlgo::None));
+    }
+
+    pub fn read_repair(&self) -> bool {
+        self.read_repair.load(Ordering::Relaxed)
+    }
+
+    /// Restores document ids that are referenced by an index but missing from the
+    /// collection's document ids bitmap when they are found by a query. Queries
+    /// only detect the missing ids, the bitmap is repaired by a background task
+    /// that verifies the index entries again before writing. This hides the bug
+    /// that caused the corruption, so it should only be enabled while recovering
+    /// a damaged store.
+    pub fn set_read_repair(&self, enable: bool) {
+        self.read_repair.store(enable, Ordering::Relaxed);
+    }
+
+    /// Returns the window ids are assigned from in a collection, see
+    /// `set_id_assignment_window`.
+    pub fn id_assignment_window(&self, collection: u8) -> usize {
+        match self.collection_id_assignment_windows[collection as usize].load(Ordering::Relaxed) {
+            0 => self.id_assignment_window.load(Ordering::Relaxed),
+            window => window,
+        }
+    }
+
+    /// Sets the minimum number of available document ids a new id is randomly
+    /// picked from. Concurrent writers to the same collection pick the same id,
+    /// and one of them has to retry, with a probability close to `1 / window`,
+    /// so wider windows reduce commit conflicts during bulk imports at the cost
+    /// of sparser document ids.
+    pub fn set_id_assignment_window(&self, window: usize) {
+        self.id_assignment_window
+            .store(window.max(1), Ordering::Relaxed);
+    }
+
+    /// Overrides the id assignment window for a single collection, `None` reverts
+    /// it to the store-wide window.
+    pub fn set_collection_id_assignment_window(&self, collection: u8, window: Option<usize>) {
+        self.collection_id_assignment_windows[collection as usize]
+            .store(window.map_or(0, |window| window.max(1)), Ordering::Relaxed);
+    }
+}
+
+impl Default for StoreSettings {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Store {
+    pub fn settings(&self) -> &StoreSettings {
+        match self {
+            #[cfg(feature = "sqlite")]
+            Self::SQLite(store) => &store.settings,
+            #[cfg(feature = "foundation")]
+            Self::FoundationDb(store) => &store.settings,
+            #[cfg(feature = "postgres")]
+            Self::PostgreSQL(store) => &store.settings,
+            #[cfg(feature = "mysql")]
+            Self::MySQL(store) => &store.settings,
+            #[cfg(feature = "rocks")]
+            Self::RocksDb(store) => &store.settings,
+            #[cfg(all(feature = "enterprise", any(feature = "postgres", feature = "mysql")))]
+            Self::SQLReadReplica(store) => &store.settings,
+            Self::None => &NO_SETTINGS,
+        }
+    }
+}
@@ -0,0 +1,156 @@
+/*
+ * SPDX-FileCopyrightText: 2020 Stalwart Labs Ltd <hello@stalw.art>
+ *
+ * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
+ */
+
+#[cfg(any(feature = "sqlite", feature = "foundation"))]
+use std::sync::Arc;
+
+use roaring::RoaringBitmap;
+use trc::AddContext;
+
+use crate::{
+    BitmapKey, Deserialize, IterateParams, Key, SUBSPACE_PROPERTY, Store, ValueKey,
+    write::{
+        BitmapClass, ValueClass,
+        compress::{MaybeCompressed, decompress_values},
+    },
+};
+
+#[cfg(feature = "foundation")]
+use crate::backend::foundationdb::read::FdbSnapshot;
+#[cfg(feature = "sqlite")]
+use crate::backend::sqlite::read::SqliteSnapshot;
+
+/// Read-only view of a store as of the moment it was taken, used by online
+/// backups so that the exported data is consistent across subspaces.
+///
+/// Backends without snapshot support read the live store, which
+/// `is_consistent` reports so that callers can warn about it.
+#[derive(Clone)]
+pub enum SnapshotHandle {
+    #[cfg(feature = "sqlite")]
+    SQLite(Arc<SqliteSnapshot>),
+    #[cfg(feature = "foundation")]
+    FoundationDb(Arc<FdbSnapshot>),
+    Live(Store),
+}
+
+impl Store {
+    /// Takes a snapshot of the store. SQLite holds a read transaction open on a
+    /// dedicated connection until the last handle is dropped, FoundationDB pins
+    /// the current read version. Other backends return a handle to the live store.
+    #[allow(unreachable_patterns)]
+    pub async fn snapshot(&self) -> trc::Result<SnapshotHandle> {
+        match self {
+            #[cfg(feature = "sqlite")]
+            Self::SQLite(store) => store
+                .snapshot()
+                .map(|snapshot| SnapshotHandle::SQLite(Arc::new(snapshot))),
+            #[cfg(feature = "foundation")]
+            Self::FoundationDb(store) => store
+                .snapshot()
+                .await
+                .map(|snapshot| SnapshotHandle::FoundationDb(Arc::new(snapshot))),
+            Self::None => Err(trc::StoreEvent::NotConfigured.into()),
+            _ => Ok(SnapshotHandle::Live(self.clone())),
+        }
+        .caused_by(trc::location!())
+    }
+
+    /// Takes a snapshot for reads that can run for longer than a few seconds,
+    /// such as backups. FoundationDB discards versions older than about five
+    /// seconds, so it returns a handle to the live store instead of one that
+    /// would fail with `transaction_too_old` halfway through.
+    #[allow(unreachable_patterns)]
+    pub async fn long_lived_snapshot(&self) -> trc::Result<SnapshotHandle> {
+        match self {
+            #[cfg(feature = "foundation")]
+            Self::FoundationDb(_) => Ok(SnapshotHandle::Live(self.clone())),
+            _ => self.snapshot().await,
+        }
+    }
+}
+
+impl SnapshotHandle {
+    pub async fn get_value<U>(&self, key: impl Key) -> trc::Result<Option<U>>
+    where
+        U: Deserialize + 'static,
+    {
+        match self {
+            #[cfg(feature = "sqlite")]
+            Self::SQLite(snapshot) if key.subspace() == SUBSPACE_PROPERTY => snapshot
+                .get_value::<MaybeCompressed<U>>(key)
+                .await
+                .map(|value| value.map(|value| value.0)),
+            #[cfg(feature = "sqlite")]
+            Self::SQLite(snapshot) => snapshot.get_value(key).await,
+            #[cfg(feature = "foundation")]
+            Self::FoundationDb(snapshot) if key.subspace() == SUBSPACE_PROPERTY => snapshot
+                .get_value::<MaybeCompressed<U>>(key)
+                .await
+                .map(|value| value.map(|value| value.0)),
+            #[cfg(feature = "foundation")]
+            Self::FoundationDb(snapshot) => snapshot.get_value(key).await,
+            Self::Live(store) => store.get_value(key).await,
+        }
+        .caused_by(trc::location!())
+    }
+
+    pub async fn get_bitmap(
+        &self,
+        key: BitmapKey<BitmapClass<u32>>,
+    ) -> trc::Result<Option<RoaringBitmap>> {
+        match self {
+            #[cfg(feature = "sqlite")]
+            Self::SQLite(snapshot) => snapshot.get_bitmap(key).await,
+            #[cfg(feature = "foundation")]
+            Self::FoundationDb(snapshot) => snapshot.get_bitmap(key).await,
+            Self::Live(store) => store.get_bitmap(key).await,
+        }
+        .caused_by(trc::location!())
+    }
+
+    pub async fn iterate<T: Key>(
+        &self,
+        params: IterateParams<T>,
+        cb: impl for<'x> FnMut(&'x [u8], &'x [u8]) -> trc::Result<bool> + Sync + Send,
+    ) -> trc::Result<()> {
+        match self {
+            #[cfg(feature = "sqlite")]
+            Self::SQLite(snapshot) if params.begin.subspace() == SUBSPACE_PROPERTY => {
+                snapshot.iterate(params, decompress_values(cb)).await
+            }
+            #[cfg(feature = "sqlite")]
+            Self::SQLite(snapshot) => snapshot.iterate(params, cb).await,
+            #[cfg(feature = "foundation")]
+            Self::FoundationDb(snapshot) if params.begin.subspace() == SUBSPACE_PROPERTY => {
+                snapshot.iterate(params, decompress_values(cb)).await
+            }
+            #[cfg(feature = "foundation")]
+            Self::FoundationDb(snapshot) => snapshot.iterate(params, cb).await,
+            Self::Live(store) => store.iterate(params, cb).await,
+        }
+        .caused_by(trc::location!())
+    }
+
+    pub async fn get_counter(
+        &self,
+        key: impl Into<ValueKey<ValueClass<u32>>> + Sync + Send,
+    ) -> trc::Result<i64> {
+        match self {
+            #[cfg(feature = "sqlite")]
+            Self::SQLite(snapshot) => snapshot.get_counter(key).await,
+            #[cfg(feature = "foundation")]
+            Self::FoundationDb(snapshot) => snapshot.get_counter(key).await,
+            Self::Live(store) => store.get_counter(key).await,
+        }
+        .caused_by(trc::location!())
+    }
+
+    /// Whether reads are isolated from writes made after the snapshot was taken.
+    pub fn is_consistent(&self) -> bool {
+        !matches!(self, Self::Live(_))
+    }
+}
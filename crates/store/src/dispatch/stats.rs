@@ -0,0 +1,123 @@
+/*
+ * SPDX-FileCopyrightText: 2020 Stalwart Labs Ltd <hello@stalw.art>
+ *
+ * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
+ */
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use trc::{Collector, MetricType};
+
+use crate::CompressionAlgo;
+
+// Counters are shared by all blob stores and reset on restart. Totals are also
+// published as the `store.blob-uncompressed-size` and `store.blob-stored-size`
+// gauges.
+static COUNTERS: [AlgorithmCounters; ALGORITHMS.len()] = [COUNTER; ALGORITHMS.len()];
+
+const ALGORITHMS: [CompressionAlgo; 4] = [
+    CompressionAlgo::None,
+    CompressionAlgo::Lz4,
+    CompressionAlgo::Lz4Framed,
+    CompressionAlgo::Zstd(0),
+];
+#[allow(clippy::declare_interior_mutable_const)]
+const COUNTER: AlgorithmCounters = AlgorithmCounters {
+    blobs: AtomicU64::new(0),
+    uncompressed_bytes: AtomicU64::new(0),
+    stored_bytes: AtomicU64::new(0),
+};
+
+struct AlgorithmCounters {
+    blobs: AtomicU64,
+    uncompressed_bytes: AtomicU64,
+    stored_bytes: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AlgorithmStats {
+    pub blobs: u64,
+    pub uncompressed_bytes: u64,
+    /// Size as written to the backend, including the markers and checksums
+    /// appended by the blob pipeline
+    pub stored_bytes: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompressionStats {
+    pub total: AlgorithmStats,
+    /// Blobs grouped by the algorithm they were compressed with, blobs written
+    /// with compression disabled or skipped are counted under `None`
+    pub algorithms: Vec<(CompressionAlgo, AlgorithmStats)>,
+}
+
+/// Returns the sizes of the blobs written by all blob stores since startup,
+/// before and after going through the blob pipeline. Parts of multipart uploads
+/// and blobs copied without being encoded are not counted.
+pub fn compression_stats() -> CompressionStats {
+    let algorithms = ALGORITHMS
+        .iter()
+        .zip(COUNTERS.iter())
+        .map(|(algorithm, counters)| {
+            (
+                *algorithm,
+                AlgorithmStats {
+                    blobs: counters.blobs.load(Ordering::Relaxed),
+                    uncompressed_bytes: counters.uncompressed_bytes.load(Ordering::Relaxed),
+                    stored_bytes: counters.stored_bytes.load(Ordering::Relaxed),
+                },
+            )
+        })
+        .collect::<Vec<_>>();
+    let total = algorithms
+        .iter()
+        .fold(AlgorithmStats::default(), |total, (_, stats)| {
+            AlgorithmStats {
+                blobs: total.blobs + stats.blobs,
+                uncompressed_bytes: total.uncompressed_bytes + stats.uncompressed_bytes,
+                stored_bytes: total.stored_bytes + stats.stored_bytes,
+            }
+        });
+
+    CompressionStats { total, algorithms }
+}
+
+impl CompressionStats {
+    pub fn algorithm(&self, algorithm: CompressionAlgo) -> AlgorithmStats {
+        self.algorithms[algorithm_index(algorithm)].1
+    }
+}
+
+impl AlgorithmStats {
+    /// Fraction of the uncompressed size saved, negative when blobs grew.
+    pub fn savings(&self) -> f64 {
+        if self.uncompressed_bytes > 0 {
+            1.0 - (self.stored_bytes as f64 / self.uncompressed_bytes as f64)
+        } else {
+            0.0
+        }
+    }
+}
+
+pub(crate) fn record_blob_write(algorithm: CompressionAlgo, uncompressed: usize, stored: usize) {
+    let counters = &COUNTERS[algorithm_index(algorithm)];
+    counters.blobs.fetch_add(1, Ordering::Relaxed);
+    counters
+        .uncompressed_bytes
+        .fetch_add(uncompressed as u64, Ordering::Relaxed);
+    counters
+        .stored_bytes
+        .fetch_add(stored as u64, Ordering::Relaxed);
+
+    Collector::add_gauge(MetricType::BlobUncompressedSize, uncompressed as u64);
+    Collector::add_gauge(MetricType::BlobStoredSize, stored as u64);
+}
+
+fn algorithm_index(algorithm: CompressionAlgo) -> usize {
+    match algorithm {
+        CompressionAlgo::None => 0,
+        CompressionAlgo::Lz4 => 1,
+        CompressionAlgo::Lz4Framed => 2,
+        CompressionAlgo::Zstd(_) => 3,
+    }
+}
@@ -5,20 +5,26 @@
  */
 
 use std::{
+    collections::hash_map::Entry,
     ops::{BitAndAssign, Range},
     time::Instant,
 };
 
+use ahash::AHashMap;
+use futures::future::BoxFuture;
 use roaring::RoaringBitmap;
-use trc::{AddContext, StoreEvent};
+use tokio::sync::mpsc;
+use trc::{AddContext, Collector, MetricType, StoreEvent};
 
 use crate::{
-    BitmapKey, Deserialize, IterateParams, Key, QueryResult, SUBSPACE_BITMAP_ID,
-    SUBSPACE_BITMAP_TAG, SUBSPACE_BITMAP_TEXT, SUBSPACE_INDEXES, SUBSPACE_LOGS, Store, U32_LEN,
-    Value, ValueKey,
+    BitmapKey, Deserialize, IndexKeyPrefix, IterateParams, Key, LogKey, QueryResult, SUBSPACE_ACL,
+    SUBSPACE_BITMAP_ID, SUBSPACE_BITMAP_TAG, SUBSPACE_BITMAP_TEXT, SUBSPACE_COUNTER,
+    SUBSPACE_FTS_INDEX, SUBSPACE_INDEXES, SUBSPACE_LOGS, SUBSPACE_PROPERTY, Store, U32_LEN,
+    U64_LEN, Value, ValueKey,
     write::{
-        AnyClass, AnyKey, AssignedIds, Batch, BatchBuilder, BitmapClass, BitmapHash, Operation,
-        ReportClass, ValueClass, ValueOp,
+        AnyClass, AnyKey, AssignedIds, Batch, BatchBuilder, BitmapClass, BitmapHash,
+        DirectoryClass, Operation, ReportClass, ValueClass, ValueOp,
+        compress::{MaybeCompressed, decompress_values},
         key::{DeserializeBigEndian, KeySerializer},
         now,
     },
@@ -26,6 +32,20 @@ use crate::{
 
 use super::DocumentSet;
 
+/// Subspaces with keys prefixed by the account id, cleared by `purge_account`
+#[cfg(feature = "rocks")]
+const ACCOUNT_SUBSPACES: [u8; 9] = [
+    SUBSPACE_ACL,
+    SUBSPACE_BITMAP_ID,
+    SUBSPACE_BITMAP_TAG,
+    SUBSPACE_BITMAP_TEXT,
+    SUBSPACE_COUNTER,
+    SUBSPACE_FTS_INDEX,
+    SUBSPACE_INDEXES,
+    SUBSPACE_LOGS,
+    SUBSPACE_PROPERTY,
+];
+
 #[cfg(feature = "test_mode")]
 #[allow(clippy::type_complexity)]
 static BITMAPS: std::sync::LazyLock<
@@ -38,6 +58,36 @@ static BITMAPS: std::sync::LazyLock<
 
 impl Store {
     pub async fn get_value<U>(&self, key: impl Key) -> trc::Result<Option<U>>
+    where
+        U: Deserialize + 'static,
+    {
+        let metric = Collector::has_collection_metrics()
+            .then(|| key.collection())
+            .flatten()
+            .map(|collection| (collection, Instant::now()));
+
+        // Property values may have been compressed when written
+        let result = if key.subspace() == SUBSPACE_PROPERTY {
+            self.get_value_raw::<MaybeCompressed<U>>(key)
+                .await
+                .map(|value| value.map(|value| value.0))
+        } else {
+            self.get_value_raw(key).await
+        }
+        .caused_by(trc::location!());
+
+        if let Some((collection, start_time)) = metric {
+            Collector::observe_collection(
+                MetricType::StoreCollectionReadTime,
+                collection,
+                start_time.elapsed().as_millis() as u64,
+            );
+        }
+
+        result
+    }
+
+    async fn get_value_raw<U>(&self, key: impl Key) -> trc::Result<Option<U>>
     where
         U: Deserialize + 'static,
     {
@@ -56,14 +106,17 @@ impl Store {
             Self::SQLReadReplica(store) => store.get_value(key).await,
             Self::None => Err(trc::StoreEvent::NotConfigured.into()),
         }
-        .caused_by(trc::location!())
     }
 
     pub async fn get_bitmap(
         &self,
         key: BitmapKey<BitmapClass<u32>>,
     ) -> trc::Result<Option<RoaringBitmap>> {
-        match self {
+        let metric = (Collector::has_collection_metrics()
+            && matches!(key.class, BitmapClass::DocumentIds))
+        .then(|| (key.collection, Instant::now()));
+
+        let result = match self {
             #[cfg(feature = "sqlite")]
             Self::SQLite(store) => store.get_bitmap(key).await,
             #[cfg(feature = "foundation")]
@@ -78,7 +131,17 @@ impl Store {
             Self::SQLReadReplica(store) => store.get_bitmap(key).await,
             Self::None => Err(trc::StoreEvent::NotConfigured.into()),
         }
-        .caused_by(trc::location!())
+        .caused_by(trc::location!());
+
+        if let Some((collection, start_time)) = metric {
+            Collector::observe_collection(
+                MetricType::StoreCollectionDocumentIdsTime,
+                collection,
+                start_time.elapsed().as_millis() as u64,
+            );
+        }
+
+        result
     }
 
     pub async fn get_bitmaps_intersection(
@@ -109,7 +172,31 @@ impl Store {
         cb: impl for<'x> FnMut(&'x [u8], &'x [u8]) -> trc::Result<bool> + Sync + Send,
     ) -> trc::Result<()> {
         let start_time = Instant::now();
-        let result = match self {
+
+        // Property values may have been compressed when written
+        let result = if params.begin.subspace() == SUBSPACE_PROPERTY {
+            self.iterate_raw(params, decompress_values(cb)).await
+        } else {
+            self.iterate_raw(params, cb).await
+        }
+        .caused_by(trc::location!());
+
+        trc::event!(
+            Store(StoreEvent::DataIterate),
+            Elapsed = start_time.elapsed(),
+        );
+
+        result
+    }
+
+    /// Iterates over the values as they are stored, without decompressing
+    /// property values.
+    pub async fn iterate_raw<T: Key>(
+        &self,
+        params: IterateParams<T>,
+        cb: impl for<'x> FnMut(&'x [u8], &'x [u8]) -> trc::Result<bool> + Sync + Send,
+    ) -> trc::Result<()> {
+        match self {
             #[cfg(feature = "sqlite")]
             Self::SQLite(store) => store.iterate(params, cb).await,
             #[cfg(feature = "foundation")]
@@ -124,14 +211,6 @@ impl Store {
             Self::SQLReadReplica(store) => store.iterate(params, cb).await,
             Self::None => Err(trc::StoreEvent::NotConfigured.into()),
         }
-        .caused_by(trc::location!());
-
-        trc::event!(
-            Store(StoreEvent::DataIterate),
-            Elapsed = start_time.elapsed(),
-        );
-
-        result
     }
 
     pub async fn get_counter(
@@ -156,6 +235,33 @@ impl Store {
         .caused_by(trc::location!())
     }
 
+    /// Whether the backend can notify changes to counters, see `watch_counter`.
+    #[allow(unreachable_patterns)]
+    pub fn supports_watch(&self) -> bool {
+        match self {
+            #[cfg(feature = "foundation")]
+            Self::FoundationDb(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Registers a watch on a counter, returning a future that resolves once it
+    /// no longer holds `value`. Watches can fire without the counter changing.
+    #[allow(unreachable_patterns)]
+    #[allow(unused_variables)]
+    pub async fn watch_counter(
+        &self,
+        key: impl Into<ValueKey<ValueClass<u32>>> + Sync + Send,
+        value: i64,
+    ) -> trc::Result<BoxFuture<'static, trc::Result<()>>> {
+        match self {
+            #[cfg(feature = "foundation")]
+            Self::FoundationDb(store) => store.watch_counter(key, value).await,
+            _ => Err(trc::StoreEvent::NotSupported.into_err()),
+        }
+        .caused_by(trc::location!())
+    }
+
     #[allow(unreachable_patterns)]
     #[allow(unused_variables)]
     pub async fn sql_query<T: QueryResult + std::fmt::Debug>(
@@ -184,51 +290,43 @@ impl Store {
     }
 
     pub async fn write(&self, batch: impl Into<Batch>) -> trc::Result<AssignedIds> {
-        let batch = batch.into();
+        let mut batch = batch.into();
+        batch
+            .compress_values(self.settings().value_compression().as_ref())
+            .caused_by(trc::location!())?;
         #[cfg(feature = "test_mode")]
-        if std::env::var("PARANOID_WRITE").is_ok_and(|v| v == "1") {
-            let mut account_id = u32::MAX;
-            let mut collection = u8::MAX;
-            let mut document_id = u32::MAX;
-
-            let mut bitmaps = Vec::new();
-            let mut result = AssignedIds::default();
-
-            for op in &batch.ops {
-                match op {
-                    Operation::AccountId {
-                        account_id: account_id_,
-                    } => {
-                        account_id = *account_id_;
-                    }
-                    Operation::Collection {
-                        collection: collection_,
-                    } => {
-                        collection = *collection_;
-                    }
-                    Operation::DocumentId {
-                        document_id: document_id_,
-                    } => {
-                        document_id = *document_id_;
+        let paranoid = std::env::var("PARANOID_WRITE").is_ok_and(|v| v == "1");
+        #[cfg(feature = "test_mode")]
+        let uncancelled_bitmaps = paranoid.then(|| paranoid_bitmaps(&batch.ops));
+        batch.cancel_bitmap_pairs();
+        #[cfg(feature = "test_mode")]
+        if let Some(uncancelled_bitmaps) = uncancelled_bitmaps {
+            let bitmaps = paranoid_bitmaps(&batch.ops);
+
+            // Cancelling set and clear pairs must not change the resulting bitmaps
+            {
+                let current = BITMAPS.lock();
+                let apply = |bitmaps: &[ParanoidBitmap]| {
+                    let mut model = std::collections::HashMap::new();
+                    for (key, _, _, _, _, _) in &uncancelled_bitmaps {
+                        model.insert(key.clone(), current.get(key).cloned().unwrap_or_default());
                     }
-                    Operation::Bitmap { class, set } => {
-                        if *set && matches!(class, BitmapClass::DocumentIds) {
-                            let id = result.document_ids.len() as u32;
-                            result.document_ids.push(id);
+                    for (key, _, _, _, document_id, set) in bitmaps {
+                        let map = model.get_mut(key).unwrap();
+                        if *set {
+                            map.insert(*document_id);
+                        } else {
+                            map.remove(document_id);
                         }
-
-                        let key = class.serialize(
-                            account_id,
-                            collection,
-                            document_id,
-                            0,
-                            (&result).into(),
-                        );
-
-                        bitmaps.push((key, class.clone(), document_id, *set));
                     }
-                    _ => {}
-                }
+                    model
+                };
+                assert_eq!(
+                    apply(&uncancelled_bitmaps),
+                    apply(&bitmaps),
+                    "cancelling bitmap operations changed the result of {:?}",
+                    batch.ops
+                );
             }
 
             match self {
@@ -248,7 +346,7 @@ impl Store {
             }
             .caused_by(trc::location!())?;
 
-            for (key, class, document_id, set) in bitmaps {
+            for (key, class, account_id, collection, document_id, set) in bitmaps {
                 let mut bitmaps = BITMAPS.lock();
                 let map = bitmaps.entry(key).or_default();
                 if set {
@@ -277,6 +375,16 @@ impl Store {
 
         let start_time = Instant::now();
         let ops = batch.ops.len();
+        let mut collections = Vec::new();
+        if Collector::has_collection_metrics() {
+            for op in &batch.ops {
+                if let Operation::Collection { collection } = op {
+                    if !collections.contains(collection) {
+                        collections.push(*collection);
+                    }
+                }
+            }
+        }
 
         let result = match self {
             #[cfg(feature = "sqlite")]
@@ -294,11 +402,16 @@ impl Store {
             Self::None => Err(trc::StoreEvent::NotConfigured.into()),
         };
 
-        trc::event!(
-            Store(StoreEvent::DataWrite),
-            Elapsed = start_time.elapsed(),
-            Total = ops,
-        );
+        let elapsed = start_time.elapsed();
+        for collection in collections {
+            Collector::observe_collection(
+                MetricType::StoreCollectionWriteTime,
+                collection,
+                elapsed.as_millis() as u64,
+            );
+        }
+
+        trc::event!(Store(StoreEvent::DataWrite), Elapsed = elapsed, Total = ops,);
 
         result
     }
@@ -457,6 +570,93 @@ impl Store {
         Ok(())
     }
 
+    /// Removes duplicate index entries, returning the number of entries removed.
+    ///
+    /// An entry is a duplicate when the same document is indexed twice under the
+    /// field with the same value in different encodings. Numbers are indexed as
+    /// big-endian integers, and a document reindexed after the width of a field
+    /// changed is left with both a 32 and a 64-bit entry for the same number,
+    /// which queries then count twice. The widest encoding is kept. Several
+    /// distinct values indexed for one document are left untouched, as
+    /// multi-value fields index one entry per value.
+    pub async fn dedup_index(
+        &self,
+        account_id: u32,
+        collection: u8,
+        field: u8,
+    ) -> trc::Result<usize> {
+        let prefix = IndexKeyPrefix {
+            account_id,
+            collection,
+            field,
+        }
+        .serialize(0);
+        let mut entries: AHashMap<(u32, Vec<u8>), Vec<u8>> = AHashMap::new();
+        let mut delete_keys = Vec::new();
+        self.iterate(
+            IterateParams::new(
+                AnyKey {
+                    subspace: SUBSPACE_INDEXES,
+                    key: prefix.clone(),
+                },
+                AnyKey {
+                    subspace: SUBSPACE_INDEXES,
+                    key: [prefix.as_slice(), &[u8::MAX; 64]].concat(),
+                },
+            )
+            .no_values()
+            .ascending(),
+            |key, _| {
+                let Some(value_len) = key.len().checked_sub(IndexKeyPrefix::len() + U32_LEN) else {
+                    return Ok(true);
+                };
+                let (value, document_id) = key[IndexKeyPrefix::len()..].split_at(value_len);
+                let document_id = document_id.deserialize_be_u32(0)?;
+                match entries.entry((document_id, canonical_index_value(value).to_vec())) {
+                    Entry::Vacant(entry) => {
+                        entry.insert(key.to_vec());
+                    }
+                    Entry::Occupied(mut entry) => {
+                        delete_keys.push(if key.len() > entry.get().len() {
+                            std::mem::replace(entry.get_mut(), key.to_vec())
+                        } else {
+                            key.to_vec()
+                        });
+                    }
+                }
+
+                Ok(true)
+            },
+        )
+        .await
+        .caused_by(trc::location!())?;
+
+        let removed = delete_keys.len();
+        let mut batch = BatchBuilder::new();
+        for key in delete_keys {
+            if batch.ops.len() >= 1000 {
+                self.write(std::mem::take(&mut batch).build())
+                    .await
+                    .caused_by(trc::location!())?;
+            }
+            batch.ops.push(Operation::Value {
+                class: ValueClass::Any(AnyClass {
+                    subspace: SUBSPACE_INDEXES,
+                    key,
+                }),
+                op: ValueOp::Clear,
+            });
+        }
+
+        if !batch.is_empty() {
+            self.write(batch.build())
+                .await
+                .caused_by(trc::location!())?;
+        }
+
+        Ok(removed)
+    }
+
     pub async fn purge_account(&self, account_id: u32) -> trc::Result<()> {
         for subspace in [
             SUBSPACE_BITMAP_ID,
@@ -532,6 +732,191 @@ impl Store {
         Ok(())
     }
 
+    /// Releases the space left behind by `purge_account` for the given accounts.
+    ///
+    /// RocksDB compacts the purged ranges so that their tombstones are dropped and
+    /// SQLite returns free pages to the file system, other backends reclaim space
+    /// on their own.
+    #[allow(unreachable_patterns)]
+    #[allow(unused_variables)]
+    pub async fn reclaim_space(&self, account_ids: &[u32]) -> trc::Result<()> {
+        match self {
+            #[cfg(feature = "sqlite")]
+            Self::SQLite(store) => store.reclaim_space().await,
+            #[cfg(feature = "rocks")]
+            Self::RocksDb(store) => {
+                store
+                    .compact_ranges(
+                        account_ids
+                            .iter()
+                            .flat_map(|&account_id| {
+                                ACCOUNT_SUBSPACES.iter().map(move |&subspace| {
+                                    (
+                                        AnyKey {
+                                            subspace,
+                                            key: KeySerializer::new(U32_LEN)
+                                                .write(account_id)
+                                                .finalize(),
+                                        },
+                                        account_id.checked_add(1).map(|account_id| AnyKey {
+                                            subspace,
+                                            key: KeySerializer::new(U32_LEN)
+                                                .write(account_id)
+                                                .finalize(),
+                                        }),
+                                    )
+                                })
+                            })
+                            .collect(),
+                    )
+                    .await
+            }
+            _ => Ok(()),
+        }
+        .caused_by(trc::location!())
+    }
+
+    /// Reclaims space in the background once accounts have been purged.
+    pub fn reclaim_space_background(&self, account_ids: Vec<u32>) {
+        let store = self.clone();
+        tokio::spawn(async move {
+            if let Err(err) = store.reclaim_space(&account_ids).await {
+                trc::error!(err.details("Failed to reclaim space from purged accounts"));
+            }
+        });
+    }
+
+    /// Listens for the changes committed to the store by other nodes, returning
+    /// `None` for backends that do not notify changes or when notifications are
+    /// disabled.
+    #[allow(unreachable_patterns)]
+    pub fn listen_changes(&self) -> Option<mpsc::Receiver<LogKey>> {
+        match self {
+            #[cfg(feature = "postgres")]
+            Self::PostgreSQL(store) => store.listen_changes(),
+            _ => None,
+        }
+    }
+
+    /// Returns the size of the data stored on disk, or `None` for backends that
+    /// do not report it.
+    #[allow(unreachable_patterns)]
+    pub async fn storage_size(&self) -> trc::Result<Option<u64>> {
+        match self {
+            #[cfg(feature = "sqlite")]
+            Self::SQLite(store) => store.storage_size().await.map(Some),
+            #[cfg(feature = "rocks")]
+            Self::RocksDb(store) => store.storage_size().await.map(Some),
+            _ => Ok(None),
+        }
+        .caused_by(trc::location!())
+    }
+
+    /// Computes a digest of all the data stored for an account.
+    ///
+    /// Keys are hashed without their account id, so an account copied to a different
+    /// id or to another server produces the same digest. Entries are hashed in key
+    /// order, subspace by subspace, and counters are hashed by value since their
+    /// encoding is backend specific. Change logs are not part of the digest, they
+    /// record the history of the account rather than its data and are not carried
+    /// over by migrations.
+    pub async fn account_digest(&self, account_id: u32) -> trc::Result<[u8; 32]> {
+        let next_account_id = account_id.checked_add(1).ok_or_else(|| {
+            trc::StoreEvent::UnexpectedError
+                .into_err()
+                .details("Invalid account id")
+                .ctx(trc::Key::AccountId, account_id)
+        })?;
+        let mut hasher = blake3::Hasher::new();
+
+        for subspace in [
+            SUBSPACE_ACL,
+            SUBSPACE_BITMAP_ID,
+            SUBSPACE_BITMAP_TAG,
+            SUBSPACE_BITMAP_TEXT,
+            SUBSPACE_FTS_INDEX,
+            SUBSPACE_INDEXES,
+            SUBSPACE_PROPERTY,
+            SUBSPACE_COUNTER,
+        ] {
+            let is_counter = subspace == SUBSPACE_COUNTER;
+            let mut params = IterateParams::new(
+                AnyKey {
+                    subspace,
+                    key: KeySerializer::new(U32_LEN).write(account_id).finalize(),
+                },
+                AnyKey {
+                    subspace,
+                    key: KeySerializer::new(U32_LEN)
+                        .write(next_account_id)
+                        .finalize(),
+                },
+            )
+            .ascending();
+            if is_counter {
+                params = params.no_values();
+            }
+
+            let mut entries = 0u64;
+            let mut last_key = Vec::new();
+            let mut counter_keys = Vec::new();
+            hasher.update(&[subspace]);
+            self.iterate(params, |key, value| {
+                // Digests are only comparable if every backend returns keys in the same order
+                if entries > 0 && key <= last_key.as_slice() {
+                    return Err(trc::StoreEvent::UnexpectedError
+                        .into_err()
+                        .details("Keys returned out of order")
+                        .ctx(trc::Key::Key, key.to_vec()));
+                }
+                last_key.clear();
+                last_key.extend_from_slice(key);
+                entries += 1;
+
+                let key = key.get(U32_LEN..).unwrap_or_default();
+                if is_counter {
+                    counter_keys.push(key.to_vec());
+                } else {
+                    hasher.update(&(key.len() as u64).to_be_bytes());
+                    hasher.update(key);
+                    hasher.update(&(value.len() as u64).to_be_bytes());
+                    hasher.update(value);
+                }
+
+                Ok(true)
+            })
+            .await
+            .caused_by(trc::location!())?;
+
+            for key in counter_keys {
+                let value = self
+                    .get_counter(ValueKey {
+                        account_id,
+                        collection: key.first().copied().unwrap_or_default(),
+                        document_id: key
+                            .as_slice()
+                            .deserialize_be_u32(2)
+                            .caused_by(trc::location!())?,
+                        class: ValueClass::Property(key.get(1).copied().unwrap_or_default()),
+                    })
+                    .await
+                    .caused_by(trc::location!())?;
+                hasher.update(&(key.len() as u64).to_be_bytes());
+                hasher.update(&key);
+                hasher.update(&value.to_be_bytes());
+            }
+            hasher.update(&entries.to_be_bytes());
+        }
+
+        let quota = self
+            .get_counter(DirectoryClass::UsedQuota(account_id))
+            .await
+            .caused_by(trc::location!())?;
+        hasher.update(&quota.to_be_bytes());
+
+        Ok(*hasher.finalize().as_bytes())
+    }
+
     pub async fn get_blob(&self, key: &[u8], range: Range<usize>) -> trc::Result<Option<Vec<u8>>> {
         match self {
             #[cfg(feature = "sqlite")]
@@ -648,7 +1033,7 @@ impl Store {
     pub async fn blob_expire_all(&self) {
         use utils::{BLOB_HASH_LEN, BlobHash};
 
-        use crate::{U64_LEN, write::BlobOp};
+        use crate::write::BlobOp;
 
         // Delete all temporary hashes
         let from_key = ValueKey {
@@ -940,3 +1325,76 @@ impl From<BatchBuilder> for Batch {
         builder.build()
     }
 }
+
+#[cfg(feature = "test_mode")]
+type ParanoidBitmap = (
+    Vec<u8>,
+    BitmapClass<crate::write::MaybeDynamicId>,
+    u32,
+    u8,
+    u32,
+    bool,
+);
+
+// Bitmap keys and documents changed by a batch, along with the class, account
+// and collection reported when they disagree with the in-memory model
+#[cfg(feature = "test_mode")]
+fn paranoid_bitmaps(ops: &[Operation]) -> Vec<ParanoidBitmap> {
+    let mut account_id = u32::MAX;
+    let mut collection = u8::MAX;
+    let mut document_id = u32::MAX;
+
+    let mut bitmaps = Vec::new();
+    let mut result = AssignedIds::default();
+
+    for op in ops {
+        match op {
+            Operation::AccountId {
+                account_id: account_id_,
+            } => {
+                account_id = *account_id_;
+            }
+            Operation::Collection {
+                collection: collection_,
+            } => {
+                collection = *collection_;
+            }
+            Operation::DocumentId {
+                document_id: document_id_,
+            } => {
+                document_id = *document_id_;
+            }
+            Operation::Bitmap { class, set } => {
+                if *set && matches!(class, BitmapClass::DocumentIds) {
+                    let id = result.document_ids.len() as u32;
+                    result.document_ids.push(id);
+                }
+
+                let key = class.serialize(account_id, collection, document_id, 0, (&result).into());
+
+                bitmaps.push((
+                    key,
+                    class.clone(),
+                    account_id,
+                    collection,
+                    document_id,
+                    *set,
+                ));
+            }
+            _ => {}
+        }
+    }
+
+    bitmaps
+}
+
+// 32 and 64-bit big-endian encodings of the same number only differ in their
+// leading zeros. Text values never start with a zero byte.
+fn canonical_index_value(value: &[u8]) -> &[u8] {
+    if matches!(value.len(), U32_LEN | U64_LEN) {
+        let zeros = value.iter().take_while(|&&byte| byte == 0).count();
+        &value[zeros..]
+    } else {
+        value
+    }
+}
@@ -0,0 +1,191 @@
+/*
+ * SPDX-FileCopyrightText: 2020 Stalwart Labs Ltd <hello@stalw.art>
+ *
+ * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
+ */
+
+use std::{
+    io::Cursor,
+    ops::Range,
+    pin::Pin,
+    task::{Context, Poll, ready},
+    time::Instant,
+};
+
+use futures::{StreamExt, stream::BoxStream};
+use tokio::io::{AsyncRead, ReadBuf};
+use trc::{AddContext, StoreEvent};
+
+use crate::{BlobBackend, BlobStore, CompressionAlgo};
+
+/// Maximum number of bytes fetched from the backend at once while streaming.
+pub(crate) const STREAM_WINDOW: usize = 1024 * 1024;
+
+pub type BlobStream = Box<dyn AsyncRead + Send + Unpin>;
+
+pub(crate) type BlobChunks = BoxStream<'static, trc::Result<Vec<u8>>>;
+
+struct ChunkReader {
+    chunks: BlobChunks,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl BlobStore {
+    /// Returns a reader over a range of a blob that fetches and decodes it
+    /// incrementally rather than loading it into memory.
+    ///
+    /// Blobs without a pipeline are read straight from the file on the
+    /// filesystem backend and with ranged reads of `STREAM_WINDOW` bytes on
+    /// other backends. Framed LZ4 blobs are decoded a few blocks at a time.
+    /// Any other pipeline requires the whole blob, so it is read and decoded
+    /// in full before returning. Without a pipeline, blobs compressed before
+    /// compression was disabled are decoded as their marker indicates.
+    pub async fn get_blob_stream(
+        &self,
+        key: &[u8],
+        range: Range<usize>,
+    ) -> trc::Result<Option<BlobStream>> {
+        let compression = if self.pipeline.is_empty() {
+            self.trailing_compression(key)
+                .await
+                .caused_by(trc::location!())?
+        } else {
+            self.pipeline.compression()
+        };
+
+        if self.pipeline.is_empty() && compression.is_none() {
+            if let BlobBackend::Fs(store) = &self.backend {
+                let start_time = Instant::now();
+                let result = store
+                    .get_blob_reader(&self.backend_key(key), range)
+                    .await
+                    .caused_by(trc::location!());
+
+                trc::event!(
+                    Store(StoreEvent::BlobRead),
+                    Key = key,
+                    Elapsed = start_time.elapsed(),
+                    Size = result
+                        .as_ref()
+                        .map_or(0, |data| data.as_ref().map_or(0, |data| data.limit())),
+                );
+
+                return result.map(|reader| reader.map(|reader| Box::new(reader) as BlobStream));
+            }
+
+            return self
+                .get_blob_windows(key, range)
+                .await
+                .map(|chunks| chunks.map(ChunkReader::boxed));
+        } else if (self.pipeline.is_framed() || self.pipeline.is_empty())
+            && compression == Some(CompressionAlgo::Lz4Framed)
+        {
+            if let Some(chunks) = self
+                .get_blob_framed_stream(key, range.clone())
+                .await
+                .caused_by(trc::location!())?
+            {
+                return Ok(Some(ChunkReader::boxed(chunks)));
+            }
+        }
+
+        self.get_blob(key, range)
+            .await
+            .map(|data| data.map(|data| Box::new(Cursor::new(data)) as BlobStream))
+    }
+
+    async fn get_blob_windows(
+        &self,
+        key: &[u8],
+        range: Range<usize>,
+    ) -> trc::Result<Option<BlobChunks>> {
+        // The first window is fetched upfront to find out whether the blob exists
+        let window_end = range
+            .end
+            .min(range.start.saturating_add(STREAM_WINDOW))
+            .max(range.start);
+        let first = match self
+            .read_blob(key, range.start..window_end)
+            .await
+            .caused_by(trc::location!())?
+        {
+            Some(data) => data,
+            None => return Ok(None),
+        };
+        let next = (first.len() == window_end - range.start).then_some(window_end);
+
+        let store = self.clone();
+        let key = key.to_vec();
+        let end = range.end;
+
+        Ok(Some(
+            futures::stream::once(async move { Ok(first) })
+                .chain(futures::stream::try_unfold(next, move |next| {
+                    let store = store.clone();
+                    let key = key.clone();
+
+                    async move {
+                        let pos = match next {
+                            Some(pos) if pos < end => pos,
+                            _ => return Ok(None),
+                        };
+
+                        // The last byte of the previous window is read again so the
+                        // read never starts at the end of the blob, which some
+                        // backends reject when the blob size is a multiple of the window
+                        let window_end = end.min(pos.saturating_add(STREAM_WINDOW));
+                        let data = store
+                            .read_blob(&key, pos - 1..window_end)
+                            .await
+                            .caused_by(trc::location!())?
+                            .ok_or_else(|| {
+                                StoreEvent::NotFound
+                                    .into_err()
+                                    .ctx(trc::Key::Key, key.as_slice())
+                            })?;
+                        let chunk = data.get(1..).unwrap_or_default().to_vec();
+                        let next = (chunk.len() == window_end - pos).then_some(window_end);
+
+                        Ok(Some((chunk, next)))
+                    }
+                }))
+                .boxed(),
+        ))
+    }
+}
+
+impl ChunkReader {
+    fn boxed(chunks: BlobChunks) -> BlobStream {
+        Box::new(ChunkReader {
+            chunks,
+            chunk: Vec::new(),
+            pos: 0,
+        })
+    }
+}
+
+impl AsyncRead for ChunkReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        while self.pos >= self.chunk.len() {
+            match ready!(self.chunks.poll_next_unpin(cx)) {
+                Some(Ok(chunk)) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                }
+                Some(Err(err)) => return Poll::Ready(Err(std::io::Error::other(err))),
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+
+        let len = buf.remaining().min(self.chunk.len() - self.pos);
+        buf.put_slice(&self.chunk[self.pos..self.pos + len]);
+        self.pos += len;
+
+        Poll::Ready(Ok(()))
+    }
+}
@@ -0,0 +1,236 @@
+/*
+ * SPDX-FileCopyrightText: 2020 Stalwart Labs Ltd <hello@stalw.art>
+ *
+ * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
+ */
+
+use trc::AddContext;
+
+#[cfg(feature = "s3")]
+use crate::backend::s3::S3Upload;
+use crate::{BlobBackend, BlobStore, CompressionAlgo, U32_LEN, backend::fs::FsUpload};
+
+use super::{
+    blob::{hold_modified, is_hold_key},
+    pipeline::PipelineEncoder,
+    stats::record_blob_write,
+};
+
+// Parts are stored as they are received, without going through the blob
+// pipeline, under keys made of the upload id followed by the part number:
+//
+// prefix | upload id | ':' | part number (u32, big-endian)
+pub(crate) const UPLOAD_PREFIX: &[u8] = b"\xffupload:";
+
+impl BlobStore {
+    /// Stores one part of a blob uploaded over several requests. Parts can be
+    /// sent in any order, and sending a part again replaces it, so interrupted
+    /// uploads can be resumed by sending the missing parts.
+    pub async fn put_blob_part(
+        &self,
+        upload_id: &[u8],
+        part_number: u32,
+        data: &[u8],
+    ) -> trc::Result<()> {
+        let _permit = self.acquire_permit().await?;
+        self.backend_put_blob(&part_key(upload_id, part_number), data)
+            .await
+            .caused_by(trc::location!())
+    }
+
+    /// Writes the parts of an upload, in part number order, as the blob stored
+    /// under `key` and deletes them.
+    ///
+    /// Parts are read one at a time and passed through the pipeline as they are
+    /// read. The filesystem appends the encoded data to a temporary file that is
+    /// then moved in place and S3 sends it as a multipart upload, while other
+    /// backends receive the encoded blob in a single write. Pipelines with a
+    /// stage that needs the whole blob, such as LZ4 compression, assemble the
+    /// blob in memory instead.
+    pub async fn finalize_blob_upload(&self, upload_id: &[u8], key: &[u8]) -> trc::Result<()> {
+        if is_hold_key(key) {
+            return Err(hold_modified(key));
+        }
+
+        let part_keys = self.blob_part_keys(upload_id).await?;
+        if part_keys.is_empty() {
+            return Err(trc::StoreEvent::NotFound
+                .ctx(trc::Key::Key, upload_id)
+                .ctx(trc::Key::CausedBy, trc::location!()));
+        }
+
+        if let Some(encoder) = self.pipeline.encoder() {
+            let mut upload = self.begin_upload(key).await?;
+            match self.encode_parts(&part_keys, encoder, &mut upload).await {
+                Ok((uncompressed_len, stored_len)) => {
+                    self.finish_upload(upload).await?;
+                    record_blob_write(
+                        self.pipeline.compression().unwrap_or(CompressionAlgo::None),
+                        uncompressed_len,
+                        stored_len,
+                    );
+                }
+                Err(err) => {
+                    upload.abort().await;
+                    return Err(err);
+                }
+            }
+        } else {
+            let mut data = Vec::new();
+            for part_key in &part_keys {
+                data.extend_from_slice(&self.read_part(part_key).await?);
+            }
+            self.put_blob(key, &data).await?;
+        }
+
+        self.delete_blobs(&part_keys)
+            .await
+            .caused_by(trc::location!())
+            .map(|_| ())
+    }
+
+    /// Deletes the parts received for an upload.
+    pub async fn abort_blob_upload(&self, upload_id: &[u8]) -> trc::Result<()> {
+        let part_keys = self.blob_part_keys(upload_id).await?;
+        self.delete_blobs(&part_keys)
+            .await
+            .caused_by(trc::location!())
+            .map(|_| ())
+    }
+
+    /// Passes the parts through the pipeline into the upload, returning the
+    /// length of the blob before and after encoding it.
+    async fn encode_parts(
+        &self,
+        part_keys: &[Vec<u8>],
+        mut encoder: PipelineEncoder,
+        upload: &mut BlobUpload<'_>,
+    ) -> trc::Result<(usize, usize)> {
+        let mut uncompressed_len = 0;
+        let mut stored_len = 0;
+        for part_key in part_keys {
+            let data = self.read_part(part_key).await?;
+            uncompressed_len += data.len();
+            let encoded = encoder.update(&data).caused_by(trc::location!())?;
+            stored_len += encoded.len();
+            self.write_upload(upload, &encoded).await?;
+        }
+        let encoded = encoder.finish().caused_by(trc::location!())?;
+        stored_len += encoded.len();
+        self.write_upload(upload, &encoded).await?;
+
+        Ok((uncompressed_len, stored_len))
+    }
+
+    async fn begin_upload<'x>(&'x self, key: &'x [u8]) -> trc::Result<BlobUpload<'x>> {
+        match &self.backend {
+            BlobBackend::Fs(store) => {
+                let _permit = self.acquire_permit().await?;
+                store
+                    .begin_upload(&self.backend_key(key))
+                    .await
+                    .map(BlobUpload::Fs)
+            }
+            #[cfg(feature = "s3")]
+            BlobBackend::S3(store) => {
+                let _permit = self.acquire_permit().await?;
+                store
+                    .begin_upload(&self.backend_key(key))
+                    .await
+                    .map(BlobUpload::S3)
+            }
+            _ => Ok(BlobUpload::Buffer {
+                key,
+                data: Vec::new(),
+            }),
+        }
+        .caused_by(trc::location!())
+    }
+
+    async fn write_upload(&self, upload: &mut BlobUpload<'_>, data: &[u8]) -> trc::Result<()> {
+        match upload {
+            BlobUpload::Fs(upload) => {
+                let _permit = self.acquire_permit().await?;
+                upload.write(data).await
+            }
+            #[cfg(feature = "s3")]
+            BlobUpload::S3(upload) => {
+                let _permit = self.acquire_permit().await?;
+                upload.write(data).await
+            }
+            BlobUpload::Buffer { data: buffer, .. } => {
+                buffer.extend_from_slice(data);
+                Ok(())
+            }
+        }
+        .caused_by(trc::location!())
+    }
+
+    async fn finish_upload(&self, upload: BlobUpload<'_>) -> trc::Result<()> {
+        let _permit = self.acquire_permit().await?;
+        match upload {
+            BlobUpload::Fs(upload) => upload.finish().await,
+            #[cfg(feature = "s3")]
+            BlobUpload::S3(upload) => upload.finish().await,
+            BlobUpload::Buffer { key, data } => self.backend_put_blob(key, &data).await,
+        }
+        .caused_by(trc::location!())
+    }
+
+    /// Reads a part as it was received.
+    async fn read_part(&self, part_key: &[u8]) -> trc::Result<Vec<u8>> {
+        self.read_blob(part_key, 0..usize::MAX)
+            .await
+            .caused_by(trc::location!())?
+            .ok_or_else(|| {
+                trc::StoreEvent::NotFound
+                    .ctx(trc::Key::Key, part_key)
+                    .ctx(trc::Key::CausedBy, trc::location!())
+            })
+    }
+
+    /// Returns the keys of the parts received for an upload, sorted by part number.
+    async fn blob_part_keys(&self, upload_id: &[u8]) -> trc::Result<Vec<Vec<u8>>> {
+        let prefix = [UPLOAD_PREFIX, upload_id, b":"].concat();
+        self.list_blobs(&prefix)
+            .await
+            .caused_by(trc::location!())
+            .map(|mut keys| {
+                // Skip parts of uploads whose id starts with this one
+                keys.retain(|key| key.len() == prefix.len() + U32_LEN);
+                keys
+            })
+    }
+}
+
+/// Destination of a blob written in chunks.
+enum BlobUpload<'x> {
+    Fs(FsUpload),
+    #[cfg(feature = "s3")]
+    S3(S3Upload<'x>),
+    Buffer {
+        key: &'x [u8],
+        data: Vec<u8>,
+    },
+}
+
+impl BlobUpload<'_> {
+    async fn abort(self) {
+        match self {
+            BlobUpload::Fs(upload) => upload.abort().await,
+            #[cfg(feature = "s3")]
+            BlobUpload::S3(upload) => upload.abort().await,
+            BlobUpload::Buffer { .. } => {}
+        }
+    }
+}
+
+fn part_key(upload_id: &[u8], part_number: u32) -> Vec<u8> {
+    [
+        UPLOAD_PREFIX,
+        upload_id,
+        b":",
+        part_number.to_be_bytes().as_slice(),
+    ]
+    .concat()
+}
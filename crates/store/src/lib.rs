@@ -4,7 +4,7 @@
  * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
  */
 
-use std::{borrow::Cow, sync::Arc};
+use std::{borrow::Cow, sync::Arc, time::Duration};
 
 pub mod backend;
 pub mod config;
@@ -65,8 +65,16 @@ pub(crate) const WITH_SUBSPACE: u32 = 1;
 pub trait Key: Sync + Send + Clone {
     fn serialize(&self, flags: u32) -> Vec<u8>;
     fn subspace(&self) -> u8;
+
+    /// Collection the key belongs to, if it addresses a document.
+    fn collection(&self) -> Option<u8> {
+        None
+    }
 }
 
+/// Bitmaps are stored as one key per set document id, ending with the document
+/// id and holding no value. Clearing a bit deletes its key, so bitmaps never
+/// leave empty blocks behind and need no compaction.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct BitmapKey<T: AsRef<BitmapClass<u32>>> {
     pub account_id: u32,
@@ -198,13 +206,35 @@ pub enum Store {
 #[derive(Clone)]
 pub struct BlobStore {
     pub backend: BlobBackend,
-    pub compression: CompressionAlgo,
-}
-
-#[derive(Clone, Copy, Debug)]
+    pub pipeline: dispatch::pipeline::BlobPipeline,
+    pub concurrency: Option<Arc<tokio::sync::Semaphore>>,
+    /// Skip writing blobs whose key already exists, see `BlobStore::put_blob`
+    pub deduplicate: bool,
+    /// Prepended to every key before it reaches the backend, isolating the
+    /// blobs of tenants sharing a bucket or filesystem. Holds the length of the
+    /// configured prefix followed by the prefix itself.
+    pub key_prefix: Option<Arc<[u8]>>,
+    /// Time blobs have to remain unreferenced before the blob purge deletes
+    /// them, see `Store::purge_orphaned_blobs`
+    pub orphan_grace_period: Option<Duration>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum CompressionAlgo {
     None,
+    /// LZ4 using the fast encoder, there is no high compression mode
     Lz4,
+    /// LZ4 split into independently compressed blocks, allowing ranged reads
+    Lz4Framed,
+    /// Zstandard at the given compression level
+    Zstd(i32),
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BlobQuotaMode {
+    #[default]
+    Logical,
+    Physical,
 }
 
 #[derive(Clone)]
@@ -217,6 +247,8 @@ pub enum BlobBackend {
     Azure(Arc<AzureStore>),
     #[cfg(feature = "enterprise")]
     Sharded(Arc<backend::composite::sharded_blob::ShardedBlob>),
+    #[cfg(feature = "enterprise")]
+    Tiered(Arc<backend::composite::tiered_blob::TieredBlob>),
 }
 
 #[derive(Clone)]
@@ -276,7 +308,11 @@ impl From<FsStore> for BlobStore {
     fn from(store: FsStore) -> Self {
         BlobStore {
             backend: BlobBackend::Fs(Arc::new(store)),
-            compression: CompressionAlgo::None,
+            pipeline: Default::default(),
+            concurrency: None,
+            deduplicate: false,
+            key_prefix: None,
+            orphan_grace_period: None,
         }
     }
 }
@@ -286,7 +322,11 @@ impl From<S3Store> for BlobStore {
     fn from(store: S3Store) -> Self {
         BlobStore {
             backend: BlobBackend::S3(Arc::new(store)),
-            compression: CompressionAlgo::None,
+            pipeline: Default::default(),
+            concurrency: None,
+            deduplicate: false,
+            key_prefix: None,
+            orphan_grace_period: None,
         }
     }
 }
@@ -296,7 +336,11 @@ impl From<AzureStore> for BlobStore {
     fn from(store: AzureStore) -> Self {
         BlobStore {
             backend: BlobBackend::Azure(Arc::new(store)),
-            compression: CompressionAlgo::None,
+            pipeline: Default::default(),
+            concurrency: None,
+            deduplicate: false,
+            key_prefix: None,
+            orphan_grace_period: None,
         }
     }
 }
@@ -325,7 +369,11 @@ impl From<Store> for BlobStore {
     fn from(store: Store) -> Self {
         BlobStore {
             backend: BlobBackend::Store(store),
-            compression: CompressionAlgo::None,
+            pipeline: Default::default(),
+            concurrency: None,
+            deduplicate: false,
+            key_prefix: None,
+            orphan_grace_period: None,
         }
     }
 }
@@ -340,7 +388,11 @@ impl Default for BlobStore {
     fn default() -> Self {
         Self {
             backend: BlobBackend::Store(Store::None),
-            compression: CompressionAlgo::None,
+            pipeline: Default::default(),
+            concurrency: None,
+            deduplicate: false,
+            key_prefix: None,
+            orphan_grace_period: None,
         }
     }
 }
@@ -768,8 +820,12 @@ impl Stores {
             #[cfg(any(feature = "postgres", feature = "mysql"))]
             self.stores
                 .retain(|_, store| !matches!(store, Store::SQLReadReplica(_)));
-            self.blob_stores
-                .retain(|_, store| !matches!(store.backend, BlobBackend::Sharded(_)));
+            self.blob_stores.retain(|_, store| {
+                !matches!(
+                    store.backend,
+                    BlobBackend::Sharded(_) | BlobBackend::Tiered(_)
+                )
+            });
         }
     }
 }
@@ -4,12 +4,16 @@
  * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
  */
 
-use ahash::AHashSet;
+use ahash::{AHashMap, AHashSet};
 use trc::AddContext;
 
 use crate::{
-    write::{key::DeserializeBigEndian, BatchBuilder, Operation, ValueClass, ValueOp},
-    Deserialize, IterateParams, Store, ValueKey, U32_LEN,
+    dispatch::snapshot::SnapshotHandle,
+    write::{
+        assert::AssertValue, key::DeserializeBigEndian, BatchBuilder, Operation, ValueClass,
+        ValueOp,
+    },
+    Deserialize, IterateParams, Store, ValueKey, U32_LEN, U64_LEN,
 };
 
 pub enum AclQuery {
@@ -18,9 +22,17 @@ pub enum AclQuery {
         to_account_id: u32,
         to_collection: u8,
     },
-    HasAccess {
-        grant_account_id: u32,
-    },
+    /// Every document shared with `grant_account_id`, across all accounts. ACL
+    /// keys start with the grantee id, so this is a single range scan.
+    GrantedTo { grant_account_id: u32 },
+}
+
+/// Grants stored with a document, given as grantee id -> index value, along with
+/// an assertion on the value they were read from.
+#[derive(Debug)]
+pub struct DocumentAcl {
+    pub grants: AHashMap<u32, Vec<u8>>,
+    pub assert_value: AssertValue,
 }
 
 #[derive(Debug)]
@@ -29,12 +41,12 @@ pub struct AclItem {
     pub to_collection: u8,
     pub to_document_id: u32,
     pub permissions: u64,
+    pub extensions: Vec<u8>,
 }
 
-impl Store {
-    pub async fn acl_query(&self, query: AclQuery) -> trc::Result<Vec<AclItem>> {
-        let mut results = Vec::new();
-        let (from_key, to_key) = match query {
+impl AclQuery {
+    fn key_range(&self) -> (ValueKey<ValueClass<u32>>, ValueKey<ValueClass<u32>>) {
+        match *self {
             AclQuery::SharedWith {
                 grant_account_id,
                 to_account_id,
@@ -51,7 +63,7 @@ impl Store {
 
                 (from_key, to_key)
             }
-            AclQuery::HasAccess { grant_account_id } => (
+            AclQuery::GrantedTo { grant_account_id } => (
                 ValueKey {
                     account_id: 0,
                     collection: 0,
@@ -65,21 +77,68 @@ impl Store {
                     class: ValueClass::Acl(grant_account_id),
                 },
             ),
-        };
+        }
+    }
+}
+
+impl SnapshotHandle {
+    /// Same as `Store::acl_query`, reading the grants as they were when the
+    /// snapshot was taken so that several queries see the same point in time.
+    pub async fn acl_query(&self, query: AclQuery) -> trc::Result<Vec<AclItem>> {
+        let mut results = Vec::new();
+        self.acl_iterate(query, |item| {
+            results.push(item);
+            Ok(true)
+        })
+        .await
+        .map(|_| results)
+    }
+
+    /// Same as `Store::acl_iterate`, reading from the snapshot.
+    pub async fn acl_iterate(
+        &self,
+        query: AclQuery,
+        mut cb: impl FnMut(AclItem) -> trc::Result<bool> + Sync + Send,
+    ) -> trc::Result<()> {
+        let (from_key, to_key) = query.key_range();
 
         self.iterate(
             IterateParams::new(from_key, to_key).ascending(),
-            |key, value| {
-                results.push(AclItem::deserialize(key)?.with_permissions(u64::deserialize(value)?));
-
-                Ok(true)
-            },
+            |key, value| cb(AclItem::deserialize(key)?.with_value(value)?),
         )
         .await
         .caused_by(trc::location!())
+    }
+}
+
+impl Store {
+    pub async fn acl_query(&self, query: AclQuery) -> trc::Result<Vec<AclItem>> {
+        let mut results = Vec::new();
+        self.acl_iterate(query, |item| {
+            results.push(item);
+            Ok(true)
+        })
+        .await
         .map(|_| results)
     }
 
+    /// Passes the items matching an ACL query to `cb` as they are read, without
+    /// collecting them first. Iteration stops when `cb` returns `false`.
+    pub async fn acl_iterate(
+        &self,
+        query: AclQuery,
+        mut cb: impl FnMut(AclItem) -> trc::Result<bool> + Sync + Send,
+    ) -> trc::Result<()> {
+        let (from_key, to_key) = query.key_range();
+
+        self.iterate(
+            IterateParams::new(from_key, to_key).ascending(),
+            |key, value| cb(AclItem::deserialize(key)?.with_value(value)?),
+        )
+        .await
+        .caused_by(trc::location!())
+    }
+
     pub async fn acl_revoke_all(&self, account_id: u32) -> trc::Result<AHashSet<u32>> {
         let from_key = ValueKey {
             account_id: 0,
@@ -145,6 +204,192 @@ impl Store {
 
         Ok(revoked_accounts)
     }
+
+    /// Removes the ACL keys of every document shared with a deleted principal,
+    /// returning the ids of the accounts owning these documents. The grants stored
+    /// with the documents are left in place so that owners can still see them.
+    pub async fn acl_purge_grantee(&self, grant_account_id: u32) -> trc::Result<AHashSet<u32>> {
+        let mut items = self
+            .acl_query(AclQuery::GrantedTo { grant_account_id })
+            .await
+            .caused_by(trc::location!())?;
+        items.sort_unstable_by_key(|item| (item.to_account_id, item.to_collection));
+
+        let mut batch = BatchBuilder::new();
+        let mut purged_accounts = AHashSet::new();
+        let mut last_account_id = u32::MAX;
+        let mut last_collection = u8::MAX;
+        for item in items {
+            if batch.ops.len() >= 1000 {
+                self.write(batch.build())
+                    .await
+                    .caused_by(trc::location!())?;
+                batch = BatchBuilder::new();
+                last_account_id = u32::MAX;
+                last_collection = u8::MAX;
+            }
+            if item.to_account_id != last_account_id {
+                batch.with_account_id(item.to_account_id);
+                purged_accounts.insert(item.to_account_id);
+                last_account_id = item.to_account_id;
+                last_collection = u8::MAX;
+            }
+            if item.to_collection != last_collection {
+                batch.with_collection(item.to_collection);
+                last_collection = item.to_collection;
+            }
+            batch.update_document(item.to_document_id);
+            batch.ops.push(Operation::Value {
+                class: ValueClass::Acl(grant_account_id),
+                op: ValueOp::Clear,
+            })
+        }
+        if !batch.is_empty() {
+            self.write(batch.build())
+                .await
+                .caused_by(trc::location!())?;
+        }
+
+        Ok(purged_accounts)
+    }
+
+    /// Compares the ACL index of a collection against the grants stored with each
+    /// document in the `field` property, and returns the ids of the documents
+    /// where they differ. When `repair` is set, the index entries of these
+    /// documents are rewritten to match `expected`, asserting that the stored
+    /// grants did not change since they were read.
+    pub async fn verify_acl_index(
+        &self,
+        account_id: u32,
+        collection: impl Into<u8> + Sync + Send,
+        field: u8,
+        expected: AHashMap<u32, DocumentAcl>,
+        repair: bool,
+    ) -> trc::Result<Vec<u32>> {
+        let collection = collection.into();
+
+        // The index is keyed by grantee, so each grantee is located with a single
+        // key read and only its entries for this collection are scanned
+        let mut indexed: AHashMap<u32, AHashMap<u32, Vec<u8>>> = AHashMap::new();
+        let mut next_grant_account_id = Some(0);
+        while let Some(grant_account_id) = next_grant_account_id {
+            let mut found_id = None;
+            self.iterate(
+                IterateParams::new(
+                    ValueKey {
+                        account_id: 0,
+                        collection: 0,
+                        document_id: 0,
+                        class: ValueClass::Acl(grant_account_id),
+                    },
+                    ValueKey {
+                        account_id: u32::MAX,
+                        collection: u8::MAX,
+                        document_id: u32::MAX,
+                        class: ValueClass::Acl(u32::MAX),
+                    },
+                )
+                .ascending()
+                .no_values(),
+                |key, _| {
+                    found_id = Some(key.deserialize_be_u32(0)?);
+                    Ok(false)
+                },
+            )
+            .await
+            .caused_by(trc::location!())?;
+            let Some(grant_account_id) = found_id else {
+                break;
+            };
+
+            let (from_key, to_key) = AclQuery::SharedWith {
+                grant_account_id,
+                to_account_id: account_id,
+                to_collection: collection,
+            }
+            .key_range();
+            self.iterate(
+                IterateParams::new(from_key, to_key).ascending(),
+                |key, value| {
+                    indexed
+                        .entry(AclItem::deserialize(key)?.to_document_id)
+                        .or_default()
+                        .insert(grant_account_id, value.to_vec());
+                    Ok(true)
+                },
+            )
+            .await
+            .caused_by(trc::location!())?;
+
+            next_grant_account_id = grant_account_id.checked_add(1);
+        }
+
+        let mut mismatches = indexed
+            .keys()
+            .chain(expected.keys())
+            .copied()
+            .collect::<AHashSet<_>>()
+            .into_iter()
+            .filter(|document_id| {
+                indexed.get(document_id).filter(|grants| !grants.is_empty())
+                    != expected
+                        .get(document_id)
+                        .map(|acl| &acl.grants)
+                        .filter(|grants| !grants.is_empty())
+            })
+            .collect::<Vec<_>>();
+        mismatches.sort_unstable();
+
+        if repair && !mismatches.is_empty() {
+            let mut batch = BatchBuilder::new();
+            batch
+                .with_account_id(account_id)
+                .with_collection(collection);
+            for &document_id in &mismatches {
+                if batch.ops.len() >= 1000 {
+                    self.write(batch.build())
+                        .await
+                        .caused_by(trc::location!())?;
+                    batch = BatchBuilder::new();
+                    batch
+                        .with_account_id(account_id)
+                        .with_collection(collection);
+                }
+                batch.update_document(document_id);
+
+                // Documents deleted since they were read must still be missing
+                let acl = expected.get(&document_id);
+                batch.ops.push(Operation::AssertValue {
+                    class: ValueClass::Property(field),
+                    assert_value: acl.map_or(AssertValue::None, |acl| acl.assert_value),
+                });
+                let grants = acl.map(|acl| &acl.grants);
+                if let Some(stale) = indexed.get(&document_id) {
+                    for grant_account_id in stale.keys() {
+                        if grants.is_none_or(|grants| !grants.contains_key(grant_account_id)) {
+                            batch.ops.push(Operation::Value {
+                                class: ValueClass::Acl(*grant_account_id),
+                                op: ValueOp::Clear,
+                            });
+                        }
+                    }
+                }
+                for (grant_account_id, value) in grants.into_iter().flatten() {
+                    batch.ops.push(Operation::Value {
+                        class: ValueClass::Acl(*grant_account_id),
+                        op: ValueOp::Set(value.clone().into()),
+                    });
+                }
+            }
+            if !batch.is_empty() {
+                self.write(batch.build())
+                    .await
+                    .caused_by(trc::location!())?;
+            }
+        }
+
+        Ok(mismatches)
+    }
 }
 
 impl Deserialize for AclItem {
@@ -156,13 +401,18 @@ impl Deserialize for AclItem {
                 .ok_or_else(|| trc::StoreEvent::DataCorruption.caused_by(trc::location!()))?,
             to_document_id: bytes.deserialize_be_u32((U32_LEN * 2) + 1)?,
             permissions: 0,
+            extensions: Vec::new(),
         })
     }
 }
 
 impl AclItem {
-    fn with_permissions(mut self, permissions: u64) -> Self {
-        self.permissions = permissions;
-        self
+    fn with_value(mut self, value: &[u8]) -> trc::Result<Self> {
+        // Grants may be followed by extensions such as schedules
+        self.permissions = u64::deserialize(value.get(..U64_LEN).unwrap_or(value))?;
+        if let Some(extensions) = value.get(U64_LEN..).filter(|ext| !ext.is_empty()) {
+            self.extensions = extensions.to_vec();
+        }
+        Ok(self)
     }
 }
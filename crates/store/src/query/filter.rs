@@ -4,16 +4,20 @@
  * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
  */
 
-use std::ops::{BitAndAssign, BitOrAssign, BitXorAssign};
+use std::ops::{BitAndAssign, BitOrAssign, BitXorAssign, SubAssign};
 
 use ahash::HashSet;
 use nlp::tokenizers::word::WordTokenizer;
 use roaring::RoaringBitmap;
-use trc::AddContext;
+use trc::{AddContext, StoreEvent};
 
 use crate::{
-    backend::MAX_TOKEN_LENGTH, write::key::DeserializeBigEndian, BitmapKey, IndexKey,
-    IndexKeyPrefix, IterateParams, Key, Store, U32_LEN,
+    backend::MAX_TOKEN_LENGTH,
+    write::{
+        key::{DeserializeBigEndian, KeySerializer},
+        AnyKey, BatchBuilder,
+    },
+    BitmapKey, IndexKey, IndexKeyPrefix, IterateParams, Key, Store, SUBSPACE_INDEXES, U32_LEN,
 };
 
 use super::{Filter, Operator, ResultSet};
@@ -50,7 +54,11 @@ impl Store {
         let mut not_mask = RoaringBitmap::new();
         let mut not_fetch = false;
 
+        let read_repair = self.settings().read_repair();
+        let mut referenced_ids = RoaringBitmap::new();
+
         while let Some(filter) = filters.next() {
+            let is_indexed = matches!(filter, Filter::MatchValue { .. });
             let mut result = match filter {
                 Filter::MatchValue { field, op, value } => self
                     .range_to_bitmap(account_id, collection, field, &value, op)
@@ -106,6 +114,12 @@ impl Store {
                 }
             };
 
+            if read_repair && is_indexed {
+                if let Some(result) = &result {
+                    referenced_ids.bitor_assign(result);
+                }
+            }
+
             // Only fetch not mask if we need it
             if matches!(state.op, Filter::Not) && !not_fetch {
                 not_mask = self
@@ -162,6 +176,30 @@ impl Store {
             }
         }
 
+        if !referenced_ids.is_empty() {
+            if let Some(document_ids) = self
+                .get_bitmap(BitmapKey::document_ids(account_id, collection))
+                .await
+                .caused_by(trc::location!())?
+            {
+                referenced_ids.sub_assign(document_ids);
+            }
+            if !referenced_ids.is_empty() {
+                let store = self.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = store
+                        .repair_document_ids(account_id, collection, referenced_ids)
+                        .await
+                    {
+                        trc::error!(err
+                            .account_id(account_id)
+                            .collection(collection)
+                            .details("Failed to repair document ids"));
+                    }
+                });
+            }
+        }
+
         Ok(ResultSet {
             account_id,
             collection,
@@ -169,6 +207,83 @@ impl Store {
         })
     }
 
+    async fn repair_document_ids(
+        &self,
+        account_id: u32,
+        collection: u8,
+        candidate_ids: RoaringBitmap,
+    ) -> trc::Result<()> {
+        // The query results might be stale by now, only restore ids that are
+        // still referenced by an index and still missing from the bitmap
+        let mut prefix = KeySerializer::new(U32_LEN + 1)
+            .write(account_id)
+            .write(collection)
+            .finalize();
+        let mut referenced_ids = RoaringBitmap::new();
+        self.iterate(
+            IterateParams::new(
+                AnyKey {
+                    subspace: SUBSPACE_INDEXES,
+                    key: prefix.clone(),
+                },
+                AnyKey {
+                    subspace: SUBSPACE_INDEXES,
+                    key: {
+                        prefix.extend_from_slice(&[u8::MAX; 64]);
+                        prefix
+                    },
+                },
+            )
+            .no_values()
+            .ascending(),
+            |key, _| {
+                if key.len() >= IndexKeyPrefix::len() + U32_LEN {
+                    let document_id = key.deserialize_be_u32(key.len() - U32_LEN)?;
+                    if candidate_ids.contains(document_id) {
+                        referenced_ids.insert(document_id);
+                    }
+                }
+
+                Ok(true)
+            },
+        )
+        .await
+        .caused_by(trc::location!())?;
+        if let Some(document_ids) = self
+            .get_bitmap(BitmapKey::document_ids(account_id, collection))
+            .await
+            .caused_by(trc::location!())?
+        {
+            referenced_ids.sub_assign(document_ids);
+        }
+        if referenced_ids.is_empty() {
+            return Ok(());
+        }
+
+        let mut batch = BatchBuilder::new();
+        batch
+            .with_account_id(account_id)
+            .with_collection(collection);
+        for document_id in &referenced_ids {
+            batch.create_document_with_id(document_id);
+        }
+        self.write(batch.build())
+            .await
+            .caused_by(trc::location!())?;
+
+        trc::event!(
+            Store(StoreEvent::BitmapRepaired),
+            AccountId = account_id,
+            Collection = collection,
+            DocumentId = referenced_ids
+                .iter()
+                .map(trc::Value::from)
+                .collect::<Vec<_>>(),
+        );
+
+        Ok(())
+    }
+
     async fn range_to_bitmap(
         &self,
         account_id: u32,
@@ -242,6 +357,22 @@ impl Store {
                     key: &[][..],
                 },
             ),
+            Operator::EndsWith => (
+                IndexKey {
+                    account_id,
+                    collection,
+                    document_id: 0,
+                    field,
+                    key: &[][..],
+                },
+                IndexKey {
+                    account_id,
+                    collection,
+                    document_id: u32::MAX,
+                    field: field + 1,
+                    key: &[][..],
+                },
+            ),
             Operator::Equal => (
                 IndexKey {
                     account_id,
@@ -286,6 +417,7 @@ impl Store {
                     Operator::GreaterThan => value > match_value,
                     Operator::GreaterEqualThan => value >= match_value,
                     Operator::Equal => value == match_value,
+                    Operator::EndsWith => value.ends_with(match_value),
                 };
 
                 if matches {
@@ -23,6 +23,7 @@ pub enum Operator {
     GreaterThan,
     GreaterEqualThan,
     Equal,
+    EndsWith,
 }
 
 #[derive(Debug)]
@@ -127,6 +128,14 @@ impl Filter {
         }
     }
 
+    pub fn ends_with(field: impl Into<u8>, value: impl Serialize) -> Self {
+        Filter::MatchValue {
+            field: field.into(),
+            op: Operator::EndsWith,
+            value: value.serialize(),
+        }
+    }
+
     pub fn has_text(field: impl Into<u8>, text: impl Into<String>) -> Self {
         Filter::HasText {
             field: field.into(),
@@ -0,0 +1,91 @@
+/*
+ * SPDX-FileCopyrightText: 2020 Stalwart Labs Ltd <hello@stalw.art>
+ *
+ * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
+ */
+
+use trc::AddContext;
+
+use crate::Store;
+
+use super::{
+    BatchBuilder, IntoOperations, MaybeDynamicId, MaybeDynamicValue, ValueClass,
+    log::ChangeLogBuilder,
+};
+
+/// Initial contents of a new account, such as its default folders and settings.
+#[derive(Default)]
+pub struct AccountInit {
+    batch: BatchBuilder,
+    documents: Vec<(u8, u32)>,
+    change_id: Option<u64>,
+}
+
+impl AccountInit {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a document with a fixed id, `value` writes its contents.
+    pub fn with_document(
+        mut self,
+        collection: impl Into<u8>,
+        document_id: u32,
+        value: impl IntoOperations,
+    ) -> Self {
+        let collection = collection.into();
+        self.batch
+            .with_collection(collection)
+            .create_document_with_id(document_id)
+            .custom(value);
+        self.documents.push((collection, document_id));
+        self
+    }
+
+    /// Adds a value that does not belong to any document, it is written under
+    /// collection and document id 0.
+    pub fn with_value(
+        mut self,
+        class: impl Into<ValueClass<MaybeDynamicId>>,
+        value: impl Into<MaybeDynamicValue>,
+    ) -> Self {
+        self.batch
+            .with_collection(0u8)
+            .update_document(0)
+            .set(class, value);
+        self
+    }
+
+    /// Logs the creation of all documents under the given change id.
+    pub fn with_change_id(mut self, change_id: u64) -> Self {
+        self.change_id = Some(change_id);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.batch.is_empty()
+    }
+}
+
+impl Store {
+    /// Writes the initial contents of an account in a single transaction, so the
+    /// account is either fully initialized or not modified at all.
+    pub async fn initialize_account(&self, account_id: u32, init: AccountInit) -> trc::Result<()> {
+        let mut batch = BatchBuilder::new();
+        batch.with_account_id(account_id);
+        batch.ops.extend(init.batch.ops);
+
+        if let Some(change_id) = init.change_id {
+            let mut changes = ChangeLogBuilder::with_change_id(change_id);
+            for (collection, document_id) in init.documents {
+                changes.log_insert(collection, document_id);
+            }
+            batch.custom(changes);
+        }
+
+        self.write(batch.build())
+            .await
+            .caused_by(trc::location!())
+            .map(|_| ())
+    }
+}
@@ -4,7 +4,12 @@
  * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
  */
 
-use crate::{Deserialize, U32_LEN, U64_LEN};
+use ahash::AHashSet;
+use trc::AddContext;
+
+use crate::{Deserialize, Store, U32_LEN, U64_LEN, WITH_SUBSPACE};
+
+use super::{AnyKey, Batch, BitmapClass, Operation, compress::decompress_value, outcome::RawValue};
 
 #[derive(Debug, Clone)]
 pub struct HashedValue<T: Deserialize> {
@@ -12,6 +17,12 @@ pub struct HashedValue<T: Deserialize> {
     pub inner: T,
 }
 
+pub struct PendingAssertion<'x> {
+    pub op_idx: usize,
+    pub key: Vec<u8>,
+    pub assert_value: &'x AssertValue,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum AssertValue {
     U32(u32),
@@ -72,7 +83,7 @@ impl AssertValue {
         match self {
             AssertValue::U32(v) => bytes.len() == U32_LEN && u32::deserialize(bytes).unwrap() == *v,
             AssertValue::U64(v) => bytes.len() == U64_LEN && u64::deserialize(bytes).unwrap() == *v,
-            AssertValue::Hash(v) => xxhash_rust::xxh3::xxh3_64(bytes) == *v,
+            AssertValue::Hash(_) => *self == AssertValue::hash_of(bytes),
             AssertValue::None => false,
             AssertValue::Some => true,
         }
@@ -81,6 +92,15 @@ impl AssertValue {
     pub fn is_none(&self) -> bool {
         matches!(self, AssertValue::None)
     }
+
+    /// Asserts that a key holds the given raw value.
+    pub fn hash_of(bytes: &[u8]) -> Self {
+        // Hashes are taken from the decompressed form of compressed values
+        AssertValue::Hash(match decompress_value(bytes) {
+            Ok(value) => xxhash_rust::xxh3::xxh3_64(&value),
+            Err(_) => xxhash_rust::xxh3::xxh3_64(bytes),
+        })
+    }
 }
 
 impl<T: Deserialize> Deserialize for HashedValue<T> {
@@ -91,3 +111,119 @@ impl<T: Deserialize> Deserialize for HashedValue<T> {
         })
     }
 }
+
+impl Batch {
+    /// Returns the assertions in this batch that do not depend on any previous
+    /// operation, so they can be evaluated concurrently before the batch is applied.
+    ///
+    /// Scanning stops at the first document id assignment or prefix clear, as
+    /// keys after that point can only be resolved once the transaction is running.
+    /// Assertions on keys written earlier in the batch are skipped as well.
+    pub fn independent_assertions(&self, flags: u32) -> Vec<PendingAssertion<'_>> {
+        let mut account_id = u32::MAX;
+        let mut collection = u8::MAX;
+        let mut document_id = u32::MAX;
+        let mut written_keys = AHashSet::new();
+        let mut assertions = Vec::new();
+
+        for (op_idx, op) in self.ops.iter().enumerate() {
+            match op {
+                Operation::AccountId {
+                    account_id: account_id_,
+                } => {
+                    account_id = *account_id_;
+                }
+                Operation::Collection {
+                    collection: collection_,
+                } => {
+                    collection = *collection_;
+                }
+                Operation::DocumentId {
+                    document_id: document_id_,
+                } => {
+                    document_id = *document_id_;
+                }
+                Operation::Value { class, .. } => {
+                    written_keys.insert(class.serialize(
+                        account_id,
+                        collection,
+                        document_id,
+                        flags,
+                        None,
+                    ));
+                }
+                Operation::Bitmap {
+                    class: BitmapClass::DocumentIds,
+                    set: true,
+                } if document_id == u32::MAX => {
+                    break;
+                }
+                Operation::ClearPrefix { .. } => {
+                    break;
+                }
+                Operation::AssertValue {
+                    class,
+                    assert_value,
+                } => {
+                    let key = class.serialize(account_id, collection, document_id, flags, None);
+                    if !written_keys.contains(&key) {
+                        assertions.push(PendingAssertion {
+                            op_idx,
+                            key,
+                            assert_value,
+                        });
+                    }
+                }
+                Operation::ChangeId { .. }
+                | Operation::Index { .. }
+                | Operation::Bitmap { .. }
+                | Operation::Log { .. } => {}
+            }
+        }
+
+        assertions
+    }
+}
+
+impl Store {
+    /// Checks whether the assertions of a batch hold without writing anything,
+    /// so that conflicts can be detected before building a large write. On
+    /// SQLite and FoundationDB all values are read from the same snapshot of
+    /// the store, other backends read the live store so each assertion is only
+    /// known to have held when its value was read. In both cases the values can
+    /// change before the batch is written, which `write` checks again.
+    ///
+    /// Only the assertions that `independent_assertions` returns are checked,
+    /// the ones depending on document ids assigned by the batch or on keys it
+    /// writes can only be evaluated by `write`.
+    pub async fn validate(&self, batch: &Batch) -> trc::Result<()> {
+        let assertions = batch.independent_assertions(WITH_SUBSPACE);
+        if assertions.is_empty() {
+            return Ok(());
+        }
+
+        let snapshot = self.snapshot().await.caused_by(trc::location!())?;
+        for assertion in assertions {
+            let (subspace, key) = assertion.key.split_first().unwrap();
+            let matches = match snapshot
+                .get_value::<RawValue>(AnyKey {
+                    subspace: *subspace,
+                    key,
+                })
+                .await
+                .caused_by(trc::location!())?
+            {
+                Some(value) => assertion.assert_value.matches(&value.0),
+                None => assertion.assert_value.is_none(),
+            };
+
+            if !matches {
+                return Err(trc::StoreEvent::AssertValueFailed
+                    .into_err()
+                    .ctx(trc::Key::Key, assertion.key.as_slice()));
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -4,6 +4,8 @@
  * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
  */
 
+use ahash::AHashMap;
+
 use super::{
     assert::ToAssertValue, Batch, BatchBuilder, BitmapClass, HasFlag, IntoOperations,
     MaybeDynamicId, MaybeDynamicValue, Operation, Serialize, TagValue, ToBitmaps, ValueClass,
@@ -123,6 +125,53 @@ impl BatchBuilder {
         self
     }
 
+    /// Writes a value along with index entries derived from it by the given
+    /// functions, so the entries can't diverge from the stored value.
+    pub fn set_indexed_value<T: Serialize>(
+        &mut self,
+        field: impl Into<u8>,
+        value: T,
+        index_fields: &[(u8, fn(&T) -> Vec<u8>)],
+    ) -> &mut Self {
+        self.indexed_value(field.into(), value, index_fields, true)
+    }
+
+    /// Removes a value written with `set_indexed_value` along with the index
+    /// entries derived from it, `value` has to be the currently stored value.
+    pub fn clear_indexed_value<T: Serialize>(
+        &mut self,
+        field: impl Into<u8>,
+        value: T,
+        index_fields: &[(u8, fn(&T) -> Vec<u8>)],
+    ) -> &mut Self {
+        self.indexed_value(field.into(), value, index_fields, false)
+    }
+
+    fn indexed_value<T: Serialize>(
+        &mut self,
+        field: u8,
+        value: T,
+        index_fields: &[(u8, fn(&T) -> Vec<u8>)],
+        set: bool,
+    ) -> &mut Self {
+        for (index_field, index_key) in index_fields {
+            self.ops.push(Operation::Index {
+                field: *index_field,
+                key: index_key(&value),
+                set,
+            });
+        }
+        self.ops.push(Operation::Value {
+            class: ValueClass::Property(field),
+            op: if set {
+                ValueOp::Set(value.serialize().into())
+            } else {
+                ValueOp::Clear
+            },
+        });
+        self
+    }
+
     pub fn tag(
         &mut self,
         field: impl Into<u8>,
@@ -159,6 +208,18 @@ impl BatchBuilder {
         self
     }
 
+    pub fn append(
+        &mut self,
+        class: impl Into<ValueClass<MaybeDynamicId>>,
+        data: impl Into<Vec<u8>>,
+    ) -> &mut Self {
+        self.ops.push(Operation::Value {
+            class: class.into(),
+            op: ValueOp::Append(data.into()),
+        });
+        self
+    }
+
     pub fn set(
         &mut self,
         class: impl Into<ValueClass<MaybeDynamicId>>,
@@ -179,6 +240,22 @@ impl BatchBuilder {
         self
     }
 
+    /// Removes all keys of an account's collection from a subspace in the same
+    /// transaction as the rest of the batch, see `CLEAR_PREFIX_SUBSPACES`.
+    pub fn clear_prefix(
+        &mut self,
+        subspace: u8,
+        account_id: u32,
+        collection: impl Into<u8>,
+    ) -> &mut Self {
+        self.ops.push(Operation::ClearPrefix {
+            subspace,
+            account_id,
+            collection: collection.into(),
+        });
+        self
+    }
+
     pub fn log(&mut self, value: impl Into<MaybeDynamicValue>) -> &mut Self {
         self.ops.push(Operation::Log { set: value.into() });
         self
@@ -234,12 +311,88 @@ impl Batch {
         })
     }
 
+    pub fn is_idempotent(&self) -> bool {
+        let mut document_id = u32::MAX;
+        self.ops.iter().all(|op| match op {
+            Operation::DocumentId {
+                document_id: document_id_,
+            } => {
+                document_id = *document_id_;
+                true
+            }
+            Operation::Value {
+                op: ValueOp::AtomicAdd(_) | ValueOp::AddAndGet(_) | ValueOp::Append(_),
+                ..
+            } => false,
+            // Replaying a committed batch fails its assertions, as the values they
+            // check were changed by the batch itself
+            Operation::AssertValue { .. } => false,
+            Operation::Bitmap {
+                class: BitmapClass::DocumentIds,
+                set: true,
+            } => document_id != u32::MAX,
+            _ => true,
+        })
+    }
+
     pub fn first_account_id(&self) -> Option<u32> {
         self.ops.iter().find_map(|op| match op {
             Operation::AccountId { account_id } => Some(*account_id),
             _ => None,
         })
     }
+
+    /// Removes the operations on a bitmap entry of a document that are
+    /// superseded by a later operation on the same entry, as only the last
+    /// one determines whether the document ends up in the bitmap. Documents
+    /// whose id is assigned by the store are skipped. Returns the number of
+    /// operations removed.
+    pub(crate) fn cancel_bitmap_pairs(&mut self) -> usize {
+        let mut account_id = u32::MAX;
+        let mut collection = u8::MAX;
+        let mut document_id = u32::MAX;
+        let mut last_pos = AHashMap::new();
+        let mut cancelled = Vec::new();
+
+        for (pos, op) in self.ops.iter().enumerate() {
+            match op {
+                Operation::AccountId {
+                    account_id: account_id_,
+                } => {
+                    account_id = *account_id_;
+                }
+                Operation::Collection {
+                    collection: collection_,
+                } => {
+                    collection = *collection_;
+                }
+                Operation::DocumentId {
+                    document_id: document_id_,
+                } => {
+                    document_id = *document_id_;
+                }
+                Operation::Bitmap { class, .. } if document_id != u32::MAX => {
+                    if let Some(prev_pos) =
+                        last_pos.insert((account_id, collection, document_id, class), pos)
+                    {
+                        cancelled.push(prev_pos);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if !cancelled.is_empty() {
+            cancelled.sort_unstable();
+            let mut pos = 0;
+            self.ops.retain(|_| {
+                pos += 1;
+                cancelled.binary_search(&(pos - 1)).is_err()
+            });
+        }
+
+        cancelled.len()
+    }
 }
 
 impl Default for BatchBuilder {
@@ -202,14 +202,18 @@ impl Store {
         .caused_by(trc::location!())?;
 
         // Delete expired or unlinked blobs
-        for (_, op) in &delete_keys {
-            if let BlobOp::Commit { hash } = op {
-                blob_store
-                    .delete_blob(hash.as_ref())
-                    .await
-                    .caused_by(trc::location!())?;
-            }
-        }
+        blob_store
+            .delete_blobs(
+                &delete_keys
+                    .iter()
+                    .filter_map(|(_, op)| match op {
+                        BlobOp::Commit { hash } => Some(hash.as_slice().to_vec()),
+                        _ => None,
+                    })
+                    .collect::<Vec<_>>(),
+            )
+            .await
+            .caused_by(trc::location!())?;
 
         // Delete hashes
         let mut batch = BatchBuilder::new();
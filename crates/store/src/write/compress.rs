@@ -0,0 +1,293 @@
+/*
+ * SPDX-FileCopyrightText: 2020 Stalwart Labs Ltd <hello@stalw.art>
+ *
+ * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
+ */
+
+use std::borrow::Cow;
+
+use trc::{AddContext, StoreEvent};
+
+use crate::{CompressionAlgo, Deserialize, IterateParams, Store, U32_LEN};
+
+use super::{
+    AnyClass, AnyKey, Batch, BatchBuilder, MaybeDynamicValue, Operation, ValueClass, ValueOp,
+    assert::AssertValue,
+};
+
+// Compressed property values are stored as the output of `CompressionAlgo::compress`
+// followed by the first 32 bits of its xxh3 hash (big-endian) and this marker, so
+// that uncompressed values ending with a compression marker are not mistaken
+// for compressed ones. Uncompressed values that end with a valid trailer are
+// stored inside one, see `encode_value`.
+const VALUE_MARKER: u8 = 0xa8;
+const VALUE_TRAILER_LEN: usize = U32_LEN + 1;
+
+/// Compression applied to property values written through `Store::write`,
+/// configured per store in `StoreSettings`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ValueCompression {
+    pub algorithm: CompressionAlgo,
+    /// Values smaller than this are stored as they are
+    pub threshold: usize,
+}
+
+pub struct CompressionMigration {
+    pub subspace: u8,
+    pub compression: CompressionAlgo,
+    pub resume_from: Option<Vec<u8>>,
+    pub batch_size: usize,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct MigrationProgress {
+    pub last_key: Option<Vec<u8>>,
+    pub scanned: u64,
+    pub migrated: u64,
+}
+
+/// Compresses a value if it is above the threshold and compression saves space.
+pub fn compress_value(compression: &ValueCompression, data: &[u8]) -> Option<Vec<u8>> {
+    if data.len() < compression.threshold || is_compressed_value(data) {
+        return None;
+    }
+
+    let compressed = compression.algorithm.compress(data);
+    if compressed.len() + VALUE_TRAILER_LEN >= data.len() {
+        return None;
+    }
+    Some(wrap_value(&compressed))
+}
+
+/// Returns the form in which a value has to be stored, or `None` if it is
+/// stored as it is. Values that end with a valid trailer are wrapped in
+/// another one without being compressed, otherwise reads would decode them.
+pub fn encode_value(compression: Option<&ValueCompression>, data: &[u8]) -> Option<Vec<u8>> {
+    if is_compressed_value(data) {
+        Some(wrap_value(data))
+    } else {
+        compression.and_then(|compression| compress_value(compression, data))
+    }
+}
+
+fn wrap_value(payload: &[u8]) -> Vec<u8> {
+    let mut value = Vec::with_capacity(payload.len() + VALUE_TRAILER_LEN);
+    value.extend_from_slice(payload);
+    value.extend_from_slice(&(xxhash_rust::xxh3::xxh3_64(payload) as u32).to_be_bytes());
+    value.push(VALUE_MARKER);
+    value
+}
+
+/// Returns the original form of a value written with `encode_value`, other
+/// values are returned as they are.
+pub fn decompress_value(data: &[u8]) -> trc::Result<Cow<'_, [u8]>> {
+    if is_compressed_value(data) {
+        let payload = &data[..data.len() - VALUE_TRAILER_LEN];
+        if CompressionAlgo::is_compressed(payload) {
+            return CompressionAlgo::decompress(payload).caused_by(trc::location!());
+        } else if is_compressed_value(payload) {
+            return Ok(payload.into());
+        }
+    }
+
+    Ok(data.into())
+}
+
+pub fn is_compressed_value(data: &[u8]) -> bool {
+    data.len() > VALUE_TRAILER_LEN
+        && data.last() == Some(&VALUE_MARKER)
+        && data[data.len() - VALUE_TRAILER_LEN..data.len() - 1]
+            == (xxhash_rust::xxh3::xxh3_64(&data[..data.len() - VALUE_TRAILER_LEN]) as u32)
+                .to_be_bytes()
+}
+
+/// Deserializes a value that may have been written with `compress_value`.
+pub(crate) struct MaybeCompressed<T>(pub T);
+
+impl<T: Deserialize> Deserialize for MaybeCompressed<T> {
+    fn deserialize(bytes: &[u8]) -> trc::Result<Self> {
+        T::deserialize(decompress_value(bytes)?.as_ref()).map(MaybeCompressed)
+    }
+}
+
+/// Wraps an iterator callback so that it receives values written with
+/// `compress_value` in their original form.
+pub(crate) fn decompress_values<'x>(
+    mut cb: impl for<'y> FnMut(&'y [u8], &'y [u8]) -> trc::Result<bool> + Sync + Send + 'x,
+) -> impl for<'y> FnMut(&'y [u8], &'y [u8]) -> trc::Result<bool> + Sync + Send + 'x {
+    move |key, value| cb(key, decompress_value(value)?.as_ref())
+}
+
+impl Batch {
+    /// Encodes the static property values in the batch with `encode_value`,
+    /// which is needed even without compression. Dynamic values are only
+    /// serialized by the backend, so they are stored as they are.
+    ///
+    /// Property values can't be appended to: backends append raw bytes, which
+    /// would corrupt values stored compressed or wrapped in a trailer.
+    pub(crate) fn compress_values(
+        &mut self,
+        compression: Option<&ValueCompression>,
+    ) -> trc::Result<()> {
+        for op in &mut self.ops {
+            match op {
+                Operation::Value {
+                    class: ValueClass::Property(_),
+                    op: ValueOp::Set(MaybeDynamicValue::Static(value)),
+                } => {
+                    if let Some(encoded) = encode_value(compression, value) {
+                        *value = encoded;
+                    }
+                }
+                Operation::Value {
+                    class: ValueClass::Property(_),
+                    op: ValueOp::Append(_),
+                } => {
+                    return Err(StoreEvent::NotSupported
+                        .into_err()
+                        .details("Property values can't be appended to"));
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl CompressionMigration {
+    pub fn new(subspace: u8, compression: CompressionAlgo) -> Self {
+        Self {
+            subspace,
+            compression,
+            resume_from: None,
+            batch_size: 1000,
+        }
+    }
+
+    pub fn resume_from(mut self, key: impl Into<Vec<u8>>) -> Self {
+        self.resume_from = Some(key.into());
+        self
+    }
+
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+}
+
+impl Store {
+    /// Re-encodes all values in a subspace using the requested compression algorithm.
+    ///
+    /// Values are rewritten in batches of at most `batch_size` keys, each batch being
+    /// committed in its own transaction. After every batch `on_progress` is invoked with
+    /// the last processed key, which can be passed to `CompressionMigration::resume_from`
+    /// to continue an interrupted migration.
+    ///
+    /// Values are encoded as `encode_value` does, which reads only decode for the
+    /// property subspace, others have to be read through `Decompressed`. Each
+    /// batch asserts the values it read, batches with values changed in the
+    /// meantime are read again.
+    pub async fn migrate_value_compression(
+        &self,
+        migration: CompressionMigration,
+        mut on_progress: impl FnMut(&MigrationProgress) + Send,
+    ) -> trc::Result<MigrationProgress> {
+        let subspace = migration.subspace;
+        let compression =
+            (!matches!(migration.compression, CompressionAlgo::None)).then_some(ValueCompression {
+                algorithm: migration.compression,
+                threshold: 0,
+            });
+        let mut progress = MigrationProgress {
+            last_key: migration.resume_from,
+            ..Default::default()
+        };
+
+        loop {
+            let from_key = if let Some(last_key) = &progress.last_key {
+                let mut key = last_key.clone();
+                key.push(0);
+                key
+            } else {
+                vec![0u8]
+            };
+            let mut entries = Vec::with_capacity(migration.batch_size);
+            let mut last_key = None;
+            let mut scanned = 0;
+
+            self.iterate_raw(
+                IterateParams::new(
+                    AnyKey {
+                        subspace,
+                        key: from_key,
+                    },
+                    AnyKey {
+                        subspace,
+                        key: vec![u8::MAX; 32],
+                    },
+                ),
+                |key, value| {
+                    let decompressed = decompress_value(value)?;
+                    let encoded = encode_value(compression.as_ref(), &decompressed);
+                    if encoded.as_deref().unwrap_or(&decompressed) != value {
+                        entries.push((
+                            key.to_vec(),
+                            xxhash_rust::xxh3::xxh3_64(&decompressed),
+                            encoded.unwrap_or_else(|| decompressed.into_owned()),
+                        ));
+                    }
+                    last_key = Some(key.to_vec());
+                    scanned += 1;
+
+                    Ok(scanned < migration.batch_size)
+                },
+            )
+            .await
+            .caused_by(trc::location!())?;
+
+            if scanned == 0 {
+                break;
+            }
+
+            if !entries.is_empty() {
+                let mut batch = BatchBuilder::new();
+                let migrated = entries.len() as u64;
+                for (key, hash, value) in entries {
+                    let class = ValueClass::Any(AnyClass { subspace, key });
+                    batch.ops.push(Operation::AssertValue {
+                        class: class.clone(),
+                        assert_value: AssertValue::Hash(hash),
+                    });
+                    batch.ops.push(Operation::Value {
+                        class,
+                        op: ValueOp::Set(value.into()),
+                    });
+                }
+                match self.write(batch.build()).await {
+                    Ok(_) => {
+                        progress.migrated += migrated;
+                    }
+                    Err(err)
+                        if err.matches(trc::EventType::Store(StoreEvent::AssertValueFailed)) =>
+                    {
+                        continue;
+                    }
+                    Err(err) => {
+                        return Err(err.caused_by(trc::location!()));
+                    }
+                }
+            }
+
+            progress.scanned += scanned as u64;
+            progress.last_key = last_key;
+            on_progress(&progress);
+
+            if scanned < migration.batch_size {
+                break;
+            }
+        }
+
+        Ok(progress)
+    }
+}
@@ -224,6 +224,13 @@ impl<T: AsRef<ValueClass<u32>> + Sync + Send + Clone> Key for ValueKey<T> {
             None,
         )
     }
+
+    fn collection(&self) -> Option<u8> {
+        match self.class.as_ref() {
+            ValueClass::Property(_) | ValueClass::Acl(_) => Some(self.collection),
+            _ => None,
+        }
+    }
 }
 
 impl<T: ResolveId> ValueClass<T> {
@@ -332,6 +339,9 @@ impl<T: ResolveId> ValueClass<T> {
                     .write(6u8)
                     .write(principal_id.resolve_id(assigned_ids))
                     .write(has_member.resolve_id(assigned_ids)),
+                DirectoryClass::NameToDeletedId(name) => {
+                    serializer.write(7u8).write(name.as_slice())
+                }
             },
             ValueClass::Queue(queue) => match queue {
                 QueueClass::Message(queue_id) => serializer.write(*queue_id),
@@ -545,7 +555,9 @@ impl<T> ValueClass<T> {
             ValueClass::InMemory(InMemoryClass::Counter(v) | InMemoryClass::Key(v))
             | ValueClass::Config(v) => v.len(),
             ValueClass::Directory(d) => match d {
-                DirectoryClass::NameToId(v) | DirectoryClass::EmailToId(v) => v.len(),
+                DirectoryClass::NameToId(v)
+                | DirectoryClass::EmailToId(v)
+                | DirectoryClass::NameToDeletedId(v) => v.len(),
                 DirectoryClass::Principal(_) | DirectoryClass::UsedQuota(_) => U32_LEN,
                 DirectoryClass::Members { .. } | DirectoryClass::MemberOf { .. } => U32_LEN * 2,
             },
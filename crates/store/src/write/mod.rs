@@ -21,16 +21,24 @@ use utils::{
     codec::leb128::{Leb128Iterator, Leb128Vec},
 };
 
-use crate::{BlobClass, Deserialize, Serialize, Value, backend::MAX_TOKEN_LENGTH};
+use crate::{
+    BlobClass, Deserialize, SUBSPACE_BITMAP_ID, SUBSPACE_BITMAP_TAG, SUBSPACE_COUNTER,
+    SUBSPACE_INDEXES, SUBSPACE_LOGS, SUBSPACE_PROPERTY, Serialize, U32_LEN, Value, WITH_SUBSPACE,
+    backend::MAX_TOKEN_LENGTH,
+};
 
-use self::assert::AssertValue;
+use self::{assert::AssertValue, key::KeySerializer};
 
+pub mod account;
 pub mod assert;
 pub mod batch;
 pub mod blob;
+pub mod compress;
 pub mod hash;
 pub mod key;
 pub mod log;
+pub mod outcome;
+pub mod transfer;
 
 pub trait SerializeWithId: Send + Sync {
     fn serialize_with_id(&self, ids: &AssignedIds) -> trc::Result<Vec<u8>>;
@@ -54,7 +62,7 @@ pub enum MaybeDynamicId {
 #[derive(Debug, PartialEq, Clone, Eq, Hash)]
 pub struct DynamicDocumentId(pub usize);
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct AssignedIds {
     pub document_ids: Vec<u32>,
     pub counter_ids: Vec<i64>,
@@ -70,6 +78,19 @@ pub(crate) const MAX_COMMIT_ATTEMPTS: u32 = 1000;
 #[cfg(feature = "test_mode")]
 pub(crate) const MAX_COMMIT_TIME: Duration = Duration::from_secs(3600);
 
+// Matches the FoundationDB value size limit so appends behave alike on every backend
+pub const MAX_APPEND_SIZE: usize = 100_000;
+
+// Subspaces with keys starting with the account id and collection
+pub const CLEAR_PREFIX_SUBSPACES: [u8; 6] = [
+    SUBSPACE_BITMAP_ID,
+    SUBSPACE_BITMAP_TAG,
+    SUBSPACE_COUNTER,
+    SUBSPACE_INDEXES,
+    SUBSPACE_LOGS,
+    SUBSPACE_PROPERTY,
+];
+
 pub const F_VALUE: u32 = 1 << 0;
 pub const F_INDEX: u32 = 1 << 1;
 pub const F_BITMAP: u32 = 1 << 2;
@@ -119,6 +140,13 @@ pub enum Operation {
     Log {
         set: MaybeDynamicValue,
     },
+    /// Removes all keys of an account's collection from a subspace, which has
+    /// to be one of `CLEAR_PREFIX_SUBSPACES`.
+    ClearPrefix {
+        subspace: u8,
+        account_id: u32,
+        collection: u8,
+    },
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -189,6 +217,7 @@ pub enum DirectoryClass<T> {
     Members { principal_id: T, has_member: T },
     Principal(T),
     UsedQuota(u32),
+    NameToDeletedId(Vec<u8>),
 }
 
 #[derive(Debug, PartialEq, Clone, Eq, Hash)]
@@ -245,6 +274,7 @@ pub enum ValueOp {
     Set(MaybeDynamicValue),
     AtomicAdd(i64),
     AddAndGet(i64),
+    Append(Vec<u8>),
     #[default]
     Clear,
 }
@@ -876,27 +906,108 @@ impl SerializeWithId for DynamicDocumentId {
     }
 }
 
+/// Returns the key range, end excluded, removed by `Operation::ClearPrefix`.
+pub(crate) fn clear_prefix_range(
+    subspace: u8,
+    account_id: u32,
+    collection: u8,
+    flags: u32,
+) -> trc::Result<(Vec<u8>, Vec<u8>)> {
+    if !CLEAR_PREFIX_SUBSPACES.contains(&subspace) {
+        return Err(trc::StoreEvent::NotSupported
+            .ctx(trc::Key::Reason, "Subspace can't be cleared by prefix")
+            .ctx(trc::Key::Id, char::from(subspace).to_string()));
+    }
+
+    let prefix = |account_id: u32, collection: u8| {
+        if (flags & WITH_SUBSPACE) != 0 {
+            KeySerializer::new(U32_LEN + 2).write(subspace)
+        } else {
+            KeySerializer::new(U32_LEN + 1)
+        }
+        .write(account_id)
+        .write(collection)
+        .finalize()
+    };
+
+    Ok((
+        prefix(account_id, collection),
+        if collection < u8::MAX {
+            prefix(account_id, collection + 1)
+        } else {
+            prefix(account_id.saturating_add(1), 0)
+        },
+    ))
+}
+
+pub const DEFAULT_ID_ASSIGNMENT_WINDOW: usize = 100;
+
+/// Where a newly assigned document id came from, reported in
+/// `StoreEvent::DocumentIdAssigned` to diagnose document id fragmentation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdAssignmentSource {
+    /// An id freed by a deleted document, below the highest assigned id.
+    Reused,
+    /// An id above the highest assigned id.
+    NextAvailable,
+}
+
+impl IdAssignmentSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IdAssignmentSource::Reused => "reused",
+            IdAssignmentSource::NextAvailable => "next-available",
+        }
+    }
+}
+
 pub(crate) trait RandomAvailableId {
-    fn random_available_id(&self) -> u32;
+    fn random_available_id(&self, account_id: u32, collection: u8, window: usize) -> u32;
 }
 
 impl RandomAvailableId for RoaringBitmap {
-    fn random_available_id(&self) -> u32 {
-        let mut last_id = 0;
-        let mut available_ids = Vec::with_capacity(100);
-        for id in self.iter() {
-            for i in last_id..id {
-                available_ids.push(i);
-            }
-            last_id = id + 1;
-        }
+    fn random_available_id(&self, account_id: u32, collection: u8, window: usize) -> u32 {
+        let (document_id, source) = random_available_id(self, window);
 
-        while available_ids.len() < 100 {
-            available_ids.push(last_id);
-            last_id += 1;
-        }
+        trc::event!(
+            Store(trc::StoreEvent::DocumentIdAssigned),
+            AccountId = account_id,
+            Collection = collection,
+            DocumentId = document_id,
+            Type = source.as_str(),
+            Total = self.len()
+        );
+
+        document_id
+    }
+}
+
+fn random_available_id(assigned_ids: &RoaringBitmap, window: usize) -> (u32, IdAssignmentSource) {
+    let next_id = assigned_ids.max().map_or(0, |id| id + 1);
+    let mut available_ids = Vec::with_capacity(window);
+
+    // Ids freed by deleted documents are found by subtracting the assigned ids
+    // from the full range, which works on whole containers of 2^16 ids rather
+    // than id by id, so full containers cancel out without visiting their ids.
+    // Collections without gaps skip the subtraction altogether.
+    if u64::from(next_id) > assigned_ids.len() {
+        let mut free_ids = RoaringBitmap::new();
+        free_ids.insert_range(0..next_id);
+        free_ids -= assigned_ids;
+        available_ids.extend(free_ids);
+    }
 
-        available_ids[rand::rng().random_range(0..available_ids.len())]
+    let mut last_id = next_id;
+    while available_ids.len() < window {
+        available_ids.push(last_id);
+        last_id += 1;
+    }
+
+    let document_id = available_ids[rand::rng().random_range(0..available_ids.len())];
+    if document_id < next_id {
+        (document_id, IdAssignmentSource::Reused)
+    } else {
+        (document_id, IdAssignmentSource::NextAvailable)
     }
 }
 
@@ -909,3 +1020,162 @@ impl QueueClass {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use roaring::RoaringBitmap;
+
+    use super::{
+        BatchBuilder, BitmapClass, DEFAULT_ID_ASSIGNMENT_WINDOW, F_CLEAR, IdAssignmentSource,
+        MaybeDynamicId, Operation, TagValue, ValueClass, assert::AssertValue, random_available_id,
+    };
+    use crate::dispatch::settings::StoreSettings;
+
+    #[test]
+    fn id_assignment_conflicts() {
+        // Two writers assigning an id to the same collection at the same time
+        // conflict when both pick the same id
+        let assigned_ids = (0..1000)
+            .filter(|id| id % 10 != 0)
+            .collect::<RoaringBitmap>();
+        let conflicts = |window: usize| {
+            (0..10_000)
+                .filter(|_| {
+                    random_available_id(&assigned_ids, window).0
+                        == random_available_id(&assigned_ids, window).0
+                })
+                .count()
+        };
+
+        // The default window conflicts about 1% of the time, a window ten times
+        // wider about 0.1% of the time
+        let default_conflicts = conflicts(DEFAULT_ID_ASSIGNMENT_WINDOW);
+        let wide_conflicts = conflicts(DEFAULT_ID_ASSIGNMENT_WINDOW * 10);
+        assert!(default_conflicts < 300, "{default_conflicts} conflicts");
+        assert!(wide_conflicts < 60, "{wide_conflicts} conflicts");
+        assert!(wide_conflicts < default_conflicts);
+
+        // Ids freed by deleted documents are picked before new ones
+        let available_ids = (0..100).map(|id| id * 10).collect::<RoaringBitmap>();
+        for _ in 0..100 {
+            let (document_id, source) = random_available_id(&assigned_ids, 1);
+            assert!(available_ids.contains(document_id));
+            assert_eq!(source, IdAssignmentSource::Reused);
+        }
+
+        // Collections without gaps allocate ids above the highest one
+        let assigned_ids = (0..1000).collect::<RoaringBitmap>();
+        let (document_id, source) = random_available_id(&assigned_ids, 10);
+        assert!((1000..1010).contains(&document_id));
+        assert_eq!(source, IdAssignmentSource::NextAvailable);
+    }
+
+    #[test]
+    fn id_assignment_large_collections() {
+        // Gaps are found past blocks where every id is assigned, including at
+        // the boundaries of the blocks
+        let mut assigned_ids = (0..1_000_000).collect::<RoaringBitmap>();
+        let gaps = [65_535, 65_536, 500_000, 999_998];
+        for id in gaps {
+            assigned_ids.remove(id);
+        }
+        for _ in 0..100 {
+            let (document_id, source) = random_available_id(&assigned_ids, 1);
+            assert!(gaps.contains(&document_id), "{document_id}");
+            assert_eq!(source, IdAssignmentSource::Reused);
+        }
+
+        // Without gaps the next id is assigned
+        for id in gaps {
+            assigned_ids.insert(id);
+        }
+        assert_eq!(
+            random_available_id(&assigned_ids, 1),
+            (1_000_000, IdAssignmentSource::NextAvailable)
+        );
+    }
+
+    #[test]
+    fn collection_id_assignment_window() {
+        // Collections without an override use the store-wide window
+        let settings = StoreSettings::new();
+        settings.set_collection_id_assignment_window(200, Some(1000));
+        settings.set_collection_id_assignment_window(201, Some(0));
+        assert_eq!(settings.id_assignment_window(200), 1000);
+        assert_eq!(settings.id_assignment_window(201), 1);
+        assert_eq!(settings.id_assignment_window(202), DEFAULT_ID_ASSIGNMENT_WINDOW);
+        settings.set_id_assignment_window(500);
+        assert_eq!(settings.id_assignment_window(202), 500);
+        assert_eq!(StoreSettings::new().id_assignment_window(200), DEFAULT_ID_ASSIGNMENT_WINDOW);
+
+        settings.set_collection_id_assignment_window(200, None);
+        assert_eq!(settings.id_assignment_window(200), 500);
+    }
+
+    #[test]
+    fn cancel_bitmap_pairs() {
+        let mut batch = BatchBuilder::new();
+        batch
+            .with_account_id(1)
+            .with_collection(0u8)
+            .update_document(5)
+            .tag(0u8, 1u32, 0)
+            .tag(0u8, 1u32, F_CLEAR)
+            .tag(0u8, 2u32, F_CLEAR)
+            .tag(0u8, 3u32, 0)
+            .tag(0u8, 3u32, F_CLEAR)
+            .tag(0u8, 3u32, 0)
+            .update_document(6)
+            .tag(0u8, 1u32, F_CLEAR)
+            .create_document()
+            .tag(0u8, 1u32, 0)
+            .tag(0u8, 1u32, F_CLEAR);
+        let mut batch = batch.build();
+
+        // Only the last operation on an entry is kept, within the same document,
+        // and documents whose id is assigned on write are left as they are
+        assert_eq!(batch.cancel_bitmap_pairs(), 3);
+        let bitmaps = batch
+            .ops
+            .iter()
+            .filter_map(|op| match op {
+                Operation::Bitmap {
+                    class: BitmapClass::Tag { value, .. },
+                    set,
+                } => Some((value.clone(), *set)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            &bitmaps[..4],
+            [
+                (TagValue::Id(MaybeDynamicId::Static(1)), false),
+                (TagValue::Id(MaybeDynamicId::Static(2)), false),
+                (TagValue::Id(MaybeDynamicId::Static(3)), true),
+                (TagValue::Id(MaybeDynamicId::Static(1)), false),
+            ]
+        );
+        assert_eq!(batch.cancel_bitmap_pairs(), 0);
+    }
+
+    #[test]
+    fn idempotent_batches() {
+        let class = ValueClass::<MaybeDynamicId>::Property(0);
+        let mut batch = BatchBuilder::new();
+        batch
+            .with_account_id(1)
+            .with_collection(0u8)
+            .update_document(5)
+            .set(class.clone(), vec![1]);
+        assert!(batch.build_batch().is_idempotent());
+
+        // Batches asserting values cannot be replayed once committed
+        batch
+            .with_account_id(1)
+            .with_collection(0u8)
+            .update_document(5)
+            .assert_value(class.clone(), AssertValue::None)
+            .set(class, vec![1]);
+        assert!(!batch.build_batch().is_idempotent());
+    }
+}
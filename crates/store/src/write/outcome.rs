@@ -0,0 +1,415 @@
+/*
+ * SPDX-FileCopyrightText: 2020 Stalwart Labs Ltd <hello@stalw.art>
+ *
+ * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
+ */
+
+use std::collections::hash_map::Entry;
+
+use ahash::{AHashMap, AHashSet};
+use roaring::RoaringBitmap;
+use trc::AddContext;
+
+use crate::{BitmapKey, Deserialize, IndexKey, IterateParams, Key, Store};
+
+use super::{
+    AnyKey, AssignedIds, Batch, BitmapClass, MaybeDynamicId, MaybeDynamicValue, Operation,
+    TagValue, ValueClass, ValueOp, assert::AssertValue,
+};
+
+/// Outcome of a single batch operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OperationResult {
+    /// Selects the account, collection, document or change id of the operations that follow
+    Context,
+    /// The value, index or bitmap entry was created, modified or removed
+    Changed,
+    /// The entry already held the requested state
+    Unchanged,
+    /// Counter updates, change log entries and prefix clears, which always take effect
+    Applied,
+    AssertPassed,
+    AssertFailed,
+    /// The target key depends on a document id assigned during the write
+    Unknown,
+}
+
+#[derive(Debug, Default)]
+pub struct WriteResult {
+    pub assigned_ids: AssignedIds,
+    /// One entry for each operation in the batch, in the same order
+    pub results: Vec<OperationResult>,
+}
+
+pub(crate) struct RawValue(pub Vec<u8>);
+
+impl Deserialize for RawValue {
+    fn deserialize(bytes: &[u8]) -> trc::Result<Self> {
+        Ok(RawValue(bytes.to_vec()))
+    }
+}
+
+/// Value read from the store while evaluating a batch, along with the index of
+/// the operation that read it.
+struct ValueRead {
+    op_idx: usize,
+    class: ValueClass<MaybeDynamicId>,
+    value: Option<Vec<u8>>,
+}
+
+impl Store {
+    /// Writes a batch and reports the outcome of each of its operations.
+    ///
+    /// Affected keys are read before the batch is committed, which makes this
+    /// considerably slower than `write`. Every value read is asserted to be
+    /// unchanged when the batch is committed, so a concurrent change makes the
+    /// write fail with `AssertValueFailed`, as a failed assertion of the batch
+    /// does. Index and bitmap outcomes reflect the state read before the commit.
+    /// Operations on documents created by the batch are resolved once their ids
+    /// are assigned.
+    pub async fn write_with_results(&self, batch: impl Into<Batch>) -> trc::Result<WriteResult> {
+        let batch = batch.into();
+        let mut reads = Vec::new();
+        let mut results = self
+            .evaluate_ops(&batch, &mut reads)
+            .await
+            .caused_by(trc::location!())?;
+
+        // Assert the values read before each operation that read them
+        let mut ops = Vec::with_capacity(batch.ops.len() + reads.len());
+        let mut reads = reads.into_iter().peekable();
+        let mut created_ops = Vec::new();
+        let mut document_id = u32::MAX;
+        for (op_idx, op) in batch.ops.into_iter().enumerate() {
+            while let Some(read) = reads.next_if(|read| read.op_idx == op_idx) {
+                ops.push(Operation::AssertValue {
+                    class: read.class,
+                    assert_value: match read.value {
+                        Some(value) => AssertValue::hash_of(&value),
+                        None => AssertValue::None,
+                    },
+                });
+            }
+            match &op {
+                Operation::DocumentId {
+                    document_id: document_id_,
+                } => {
+                    document_id = *document_id_;
+                }
+                Operation::Value { class, op }
+                    if document_id == u32::MAX && is_document_scoped(class) =>
+                {
+                    created_ops.push((
+                        op_idx,
+                        match op {
+                            ValueOp::Set(MaybeDynamicValue::Static(_)) | ValueOp::Append(_) => {
+                                OperationResult::Changed
+                            }
+                            ValueOp::Clear => OperationResult::Unchanged,
+                            _ => OperationResult::Unknown,
+                        },
+                    ));
+                }
+                Operation::AssertValue { class, .. }
+                    if document_id == u32::MAX && is_document_scoped(class) =>
+                {
+                    created_ops.push((op_idx, OperationResult::AssertPassed));
+                }
+                _ => {}
+            }
+            ops.push(op);
+        }
+
+        let assigned_ids = self.write(Batch { ops }).await?;
+
+        // Documents created by the batch held no values before the write
+        if !assigned_ids.document_ids.is_empty() {
+            for (op_idx, result) in created_ops {
+                if results[op_idx] == OperationResult::Unknown {
+                    results[op_idx] = result;
+                }
+            }
+        }
+
+        Ok(WriteResult {
+            assigned_ids,
+            results,
+        })
+    }
+
+    /// Evaluates each operation of a batch against the current contents of the
+    /// store without writing anything.
+    pub async fn evaluate_batch(&self, batch: &Batch) -> trc::Result<Vec<OperationResult>> {
+        self.evaluate_ops(batch, &mut Vec::new()).await
+    }
+
+    async fn evaluate_ops(
+        &self,
+        batch: &Batch,
+        reads: &mut Vec<ValueRead>,
+    ) -> trc::Result<Vec<OperationResult>> {
+        let mut account_id = u32::MAX;
+        let mut collection = u8::MAX;
+        let mut document_id = u32::MAX;
+        let mut values: AHashMap<Vec<u8>, Option<Vec<u8>>> = AHashMap::new();
+        let mut unknown_values = AHashSet::new();
+        let mut indexes: AHashMap<Vec<u8>, bool> = AHashMap::new();
+        let mut bitmaps: AHashMap<BitmapKey<BitmapClass<u32>>, RoaringBitmap> = AHashMap::new();
+        let mut results = Vec::with_capacity(batch.ops.len());
+
+        for (op_idx, op) in batch.ops.iter().enumerate() {
+            let mut read = |class: &ValueClass<MaybeDynamicId>, value: &Option<Vec<u8>>| {
+                reads.push(ValueRead {
+                    op_idx,
+                    class: class.clone(),
+                    value: value.clone(),
+                })
+            };
+            let result = match op {
+                Operation::AccountId {
+                    account_id: account_id_,
+                } => {
+                    account_id = *account_id_;
+                    OperationResult::Context
+                }
+                Operation::Collection {
+                    collection: collection_,
+                } => {
+                    collection = *collection_;
+                    OperationResult::Context
+                }
+                Operation::DocumentId {
+                    document_id: document_id_,
+                } => {
+                    document_id = *document_id_;
+                    OperationResult::Context
+                }
+                Operation::ChangeId { .. } => OperationResult::Context,
+                Operation::Log { .. } | Operation::ClearPrefix { .. } => OperationResult::Applied,
+                Operation::Value { class, op } => {
+                    if class.is_counter(collection)
+                        || matches!(op, ValueOp::AtomicAdd(_) | ValueOp::AddAndGet(_))
+                    {
+                        OperationResult::Applied
+                    } else if document_id == u32::MAX && is_document_scoped(class) {
+                        OperationResult::Unknown
+                    } else {
+                        let key = class.serialize(account_id, collection, document_id, 0, None);
+                        let new_value = match op {
+                            ValueOp::Set(MaybeDynamicValue::Static(value)) => Some(value.clone()),
+                            ValueOp::Append(_) if unknown_values.contains(&key) => {
+                                results.push(OperationResult::Unknown);
+                                continue;
+                            }
+                            ValueOp::Append(data) => {
+                                let mut value = self
+                                    .current_value(
+                                        &mut values,
+                                        class,
+                                        collection,
+                                        key.clone(),
+                                        &mut read,
+                                    )
+                                    .await?
+                                    .unwrap_or_default();
+                                value.extend_from_slice(data);
+                                Some(value)
+                            }
+                            ValueOp::Set(MaybeDynamicValue::Dynamic(_)) => {
+                                values.remove(&key);
+                                unknown_values.insert(key);
+                                results.push(OperationResult::Unknown);
+                                continue;
+                            }
+                            _ => None,
+                        };
+
+                        if unknown_values.remove(&key) {
+                            values.insert(key, new_value);
+                            OperationResult::Unknown
+                        } else {
+                            let current = self
+                                .current_value(
+                                    &mut values,
+                                    class,
+                                    collection,
+                                    key.clone(),
+                                    &mut read,
+                                )
+                                .await?;
+                            let result = if current == new_value {
+                                OperationResult::Unchanged
+                            } else {
+                                OperationResult::Changed
+                            };
+                            values.insert(key, new_value);
+                            result
+                        }
+                    }
+                }
+                Operation::AssertValue {
+                    class,
+                    assert_value,
+                } => {
+                    let key = class.serialize(account_id, collection, document_id, 0, None);
+                    if (document_id == u32::MAX && is_document_scoped(class))
+                        || unknown_values.contains(&key)
+                    {
+                        OperationResult::Unknown
+                    } else {
+                        let current = self
+                            .current_value(&mut values, class, collection, key, &mut read)
+                            .await?;
+                        let matches = match current {
+                            Some(value) => assert_value.matches(&value),
+                            None => assert_value.is_none(),
+                        };
+                        if matches {
+                            OperationResult::AssertPassed
+                        } else {
+                            OperationResult::AssertFailed
+                        }
+                    }
+                }
+                Operation::Index { field, key, set } => {
+                    if document_id == u32::MAX {
+                        if *set {
+                            OperationResult::Changed
+                        } else {
+                            OperationResult::Unchanged
+                        }
+                    } else {
+                        let key = IndexKey {
+                            account_id,
+                            collection,
+                            document_id,
+                            field: *field,
+                            key: key.as_slice(),
+                        };
+                        let serialized_key = key.serialize(0);
+                        let exists = if let Some(exists) = indexes.get(&serialized_key) {
+                            *exists
+                        } else {
+                            let mut exists = false;
+                            self.iterate(
+                                IterateParams::new(key.clone(), key).no_values(),
+                                |key, _| {
+                                    exists = key == serialized_key;
+                                    Ok(false)
+                                },
+                            )
+                            .await
+                            .caused_by(trc::location!())?;
+                            exists
+                        };
+                        indexes.insert(serialized_key, *set);
+
+                        if exists != *set {
+                            OperationResult::Changed
+                        } else {
+                            OperationResult::Unchanged
+                        }
+                    }
+                }
+                Operation::Bitmap { class, set } => {
+                    if document_id == u32::MAX {
+                        // Newly assigned ids are not part of any bitmap yet
+                        if *set {
+                            OperationResult::Changed
+                        } else {
+                            OperationResult::Unchanged
+                        }
+                    } else if let Some(class) = resolve_bitmap(class) {
+                        let key = BitmapKey {
+                            account_id,
+                            collection,
+                            class,
+                            document_id: 0,
+                        };
+                        let bitmap = match bitmaps.entry(key) {
+                            Entry::Occupied(entry) => entry.into_mut(),
+                            Entry::Vacant(entry) => {
+                                let bitmap = self
+                                    .get_bitmap(entry.key().clone())
+                                    .await
+                                    .caused_by(trc::location!())?
+                                    .unwrap_or_default();
+                                entry.insert(bitmap)
+                            }
+                        };
+                        let changed = if *set {
+                            bitmap.insert(document_id)
+                        } else {
+                            bitmap.remove(document_id)
+                        };
+
+                        if changed {
+                            OperationResult::Changed
+                        } else {
+                            OperationResult::Unchanged
+                        }
+                    } else {
+                        OperationResult::Unknown
+                    }
+                }
+            };
+
+            results.push(result);
+        }
+
+        Ok(results)
+    }
+
+    async fn current_value(
+        &self,
+        values: &mut AHashMap<Vec<u8>, Option<Vec<u8>>>,
+        class: &ValueClass<MaybeDynamicId>,
+        collection: u8,
+        key: Vec<u8>,
+        read: &mut impl FnMut(&ValueClass<MaybeDynamicId>, &Option<Vec<u8>>),
+    ) -> trc::Result<Option<Vec<u8>>> {
+        if let Some(value) = values.get(&key) {
+            Ok(value.clone())
+        } else {
+            let value = self
+                .get_value::<RawValue>(AnyKey {
+                    subspace: class.subspace(collection),
+                    key: key.clone(),
+                })
+                .await
+                .caused_by(trc::location!())?
+                .map(|value| value.0);
+            read(class, &value);
+            values.insert(key, value.clone());
+            Ok(value)
+        }
+    }
+}
+
+fn is_document_scoped(class: &ValueClass<MaybeDynamicId>) -> bool {
+    matches!(
+        class,
+        ValueClass::Property(_)
+            | ValueClass::Acl(_)
+            | ValueClass::FtsIndex(_)
+            | ValueClass::TaskQueue(_)
+    )
+}
+
+fn resolve_bitmap(class: &BitmapClass<MaybeDynamicId>) -> Option<BitmapClass<u32>> {
+    match class {
+        BitmapClass::DocumentIds => Some(BitmapClass::DocumentIds),
+        BitmapClass::Tag { field, value } => Some(BitmapClass::Tag {
+            field: *field,
+            value: match value {
+                TagValue::Id(MaybeDynamicId::Static(id)) => TagValue::Id(*id),
+                TagValue::Id(MaybeDynamicId::Dynamic(_)) => return None,
+                TagValue::Text(text) => TagValue::Text(text.clone()),
+            },
+        }),
+        BitmapClass::Text { field, token } => Some(BitmapClass::Text {
+            field: *field,
+            token: *token,
+        }),
+    }
+}
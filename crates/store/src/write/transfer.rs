@@ -0,0 +1,345 @@
+/*
+ * SPDX-FileCopyrightText: 2020 Stalwart Labs Ltd <hello@stalw.art>
+ *
+ * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
+ */
+
+use trc::AddContext;
+use utils::{BLOB_HASH_LEN, BlobHash, codec::leb128::Leb128Reader};
+
+use crate::{
+    BitmapKey, IterateParams, SUBSPACE_ACL, SUBSPACE_BITMAP_TAG, SUBSPACE_BITMAP_TEXT,
+    SUBSPACE_BLOB_LINK, SUBSPACE_COUNTER, SUBSPACE_FTS_INDEX, SUBSPACE_INDEXES, SUBSPACE_PROPERTY,
+    Store, U32_LEN, ValueKey,
+};
+
+use super::{
+    AnyKey, BatchBuilder, BitmapClass, BitmapHash, BlobOp, MaybeDynamicId, Operation, TagValue,
+    ValueClass, ValueOp, assert::AssertValue, key::DeserializeBigEndian, outcome::RawValue,
+};
+
+const BM_MARKER: u8 = 1 << 7;
+
+impl Store {
+    /// Moves a document to another account, where it is assigned a new id.
+    ///
+    /// The document's values, indexes, bitmaps, full-text index entries, ACLs and
+    /// blob links are rewritten under the target account and removed from the
+    /// source account in a single batch, which asserts that the copied values were
+    /// not modified in the meantime and otherwise fails with `AssertValueFailed`.
+    /// Grants to the target account itself are dropped. Values referencing other
+    /// documents of the source account (such as thread or mailbox ids), change
+    /// logs and quotas are left to the caller.
+    ///
+    /// Locating the ACLs and blob links requires a scan of their subspaces, so this
+    /// is meant for administrative tasks rather than regular mail delivery.
+    pub async fn transfer_document(
+        &self,
+        from_account_id: u32,
+        to_account_id: u32,
+        collection: impl Into<u8> + Sync + Send,
+        document_id: u32,
+    ) -> trc::Result<u32> {
+        let collection = collection.into();
+
+        if !self
+            .get_bitmap(BitmapKey::document_ids(from_account_id, collection))
+            .await
+            .caused_by(trc::location!())?
+            .is_some_and(|document_ids| document_ids.contains(document_id))
+        {
+            return Err(trc::StoreEvent::NotFound
+                .into_err()
+                .ctx(trc::Key::AccountId, from_account_id)
+                .ctx(trc::Key::Collection, collection)
+                .ctx(trc::Key::DocumentId, document_id));
+        }
+
+        let mut copy_ops = Vec::new();
+        let mut assert_ops = Vec::new();
+        let mut clear_ops = Vec::new();
+        let document_suffix = document_id.to_be_bytes();
+        let account_prefix = from_account_id.to_be_bytes().to_vec();
+        let collection_prefix = [account_prefix.as_slice(), &[collection]].concat();
+
+        // Values and counters
+        let value_key_len = U32_LEN + 2 + U32_LEN;
+        for (subspace, is_counter) in [(SUBSPACE_PROPERTY, false), (SUBSPACE_COUNTER, true)] {
+            for key in self
+                .document_keys(subspace, &collection_prefix, |key| {
+                    key.len() == value_key_len && key.ends_with(&document_suffix)
+                })
+                .await?
+            {
+                let field = key[U32_LEN + 1];
+                let value_key = ValueKey {
+                    account_id: from_account_id,
+                    collection,
+                    document_id,
+                    class: ValueClass::Property(field),
+                };
+                let op = if is_counter {
+                    ValueOp::AtomicAdd(
+                        self.get_counter(value_key)
+                            .await
+                            .caused_by(trc::location!())?,
+                    )
+                } else if let Some(RawValue(value)) = self
+                    .get_value::<RawValue>(value_key)
+                    .await
+                    .caused_by(trc::location!())?
+                {
+                    assert_ops.push(Operation::AssertValue {
+                        class: ValueClass::Property(field),
+                        assert_value: AssertValue::hash_of(&value),
+                    });
+                    ValueOp::Set(value.into())
+                } else {
+                    continue;
+                };
+                copy_ops.push(Operation::Value {
+                    class: ValueClass::Property(field),
+                    op,
+                });
+                clear_ops.push(Operation::Value {
+                    class: ValueClass::Property(field),
+                    op: ValueOp::Clear,
+                });
+            }
+        }
+
+        // Indexes
+        for key in self
+            .document_keys(SUBSPACE_INDEXES, &collection_prefix, |key| {
+                key.len() >= value_key_len && key.ends_with(&document_suffix)
+            })
+            .await?
+        {
+            let field = key[U32_LEN + 1];
+            let index_key = key[U32_LEN + 2..key.len() - U32_LEN].to_vec();
+            copy_ops.push(Operation::Index {
+                field,
+                key: index_key.clone(),
+                set: true,
+            });
+            clear_ops.push(Operation::Index {
+                field,
+                key: index_key,
+                set: false,
+            });
+        }
+
+        // Tags
+        for key in self
+            .document_keys(SUBSPACE_BITMAP_TAG, &collection_prefix, |key| {
+                key.len() > value_key_len && key.ends_with(&document_suffix)
+            })
+            .await?
+        {
+            let value = &key[U32_LEN + 2..key.len() - U32_LEN];
+            let class = match key[U32_LEN + 1] {
+                field if field & BM_MARKER == 0 => BitmapClass::Tag {
+                    field,
+                    value: TagValue::Id(MaybeDynamicId::Static(
+                        value
+                            .read_leb128::<u32>()
+                            .map(|(id, _)| id)
+                            .ok_or_else(|| {
+                                trc::Error::corrupted_key(&key, None, trc::location!())
+                            })?,
+                    )),
+                },
+                field => BitmapClass::Tag {
+                    field: field & !BM_MARKER,
+                    value: TagValue::Text(value.to_vec()),
+                },
+            };
+            copy_ops.push(Operation::Bitmap {
+                class: class.clone(),
+                set: true,
+            });
+            clear_ops.push(Operation::Bitmap { class, set: false });
+        }
+
+        // Text bitmaps and full-text index entries are grouped by token rather than
+        // by collection, so the whole account has to be scanned
+        for (subspace, suffix_len) in [(SUBSPACE_BITMAP_TEXT, 2), (SUBSPACE_FTS_INDEX, 1)] {
+            for key in self
+                .document_keys(subspace, &account_prefix, |key| {
+                    key.len() > U32_LEN + suffix_len + U32_LEN
+                        && key.ends_with(&document_suffix)
+                        && key[key.len() - U32_LEN - suffix_len] == collection
+                })
+                .await?
+            {
+                let hash_end = key.len() - U32_LEN - suffix_len;
+                let token = token_hash(&key, U32_LEN..hash_end)?;
+
+                if subspace == SUBSPACE_BITMAP_TEXT {
+                    let class = BitmapClass::Text {
+                        field: key[hash_end + 1],
+                        token,
+                    };
+                    copy_ops.push(Operation::Bitmap {
+                        class: class.clone(),
+                        set: true,
+                    });
+                    clear_ops.push(Operation::Bitmap { class, set: false });
+                } else if let Some(RawValue(value)) = self
+                    .get_value::<RawValue>(ValueKey {
+                        account_id: from_account_id,
+                        collection,
+                        document_id,
+                        class: ValueClass::FtsIndex(token),
+                    })
+                    .await
+                    .caused_by(trc::location!())?
+                {
+                    assert_ops.push(Operation::AssertValue {
+                        class: ValueClass::FtsIndex(token),
+                        assert_value: AssertValue::hash_of(&value),
+                    });
+                    copy_ops.push(Operation::Value {
+                        class: ValueClass::FtsIndex(token),
+                        op: ValueOp::Set(value.into()),
+                    });
+                    clear_ops.push(Operation::Value {
+                        class: ValueClass::FtsIndex(token),
+                        op: ValueOp::Clear,
+                    });
+                }
+            }
+        }
+
+        // ACLs are keyed by grantee, followed by the shared document
+        let acl_suffix = [collection_prefix.as_slice(), &document_suffix].concat();
+        for key in self
+            .document_keys(SUBSPACE_ACL, &[], |key| {
+                key.len() == U32_LEN + acl_suffix.len() && key.ends_with(&acl_suffix)
+            })
+            .await?
+        {
+            let grant_account_id = key.deserialize_be_u32(0)?;
+            let value_key = ValueKey {
+                account_id: from_account_id,
+                collection,
+                document_id,
+                class: ValueClass::Acl(grant_account_id),
+            };
+            if grant_account_id != to_account_id {
+                if let Some(RawValue(value)) = self
+                    .get_value::<RawValue>(value_key)
+                    .await
+                    .caused_by(trc::location!())?
+                {
+                    assert_ops.push(Operation::AssertValue {
+                        class: ValueClass::Acl(grant_account_id),
+                        assert_value: AssertValue::hash_of(&value),
+                    });
+                    copy_ops.push(Operation::Value {
+                        class: ValueClass::Acl(grant_account_id),
+                        op: ValueOp::Set(value.into()),
+                    });
+                }
+            }
+            clear_ops.push(Operation::Value {
+                class: ValueClass::Acl(grant_account_id),
+                op: ValueOp::Clear,
+            });
+        }
+
+        // Blob links are keyed by hash, followed by the linked document
+        for key in self
+            .document_keys(SUBSPACE_BLOB_LINK, &[], |key| {
+                key.len() == BLOB_HASH_LEN + acl_suffix.len() && key.ends_with(&acl_suffix)
+            })
+            .await?
+        {
+            let hash = BlobHash::try_from_hash_slice(&key[..BLOB_HASH_LEN])
+                .map_err(|_| trc::Error::corrupted_key(&key, None, trc::location!()))?;
+            copy_ops.push(Operation::Value {
+                class: ValueClass::Blob(BlobOp::Link { hash: hash.clone() }),
+                op: ValueOp::Set(vec![].into()),
+            });
+            clear_ops.push(Operation::Value {
+                class: ValueClass::Blob(BlobOp::Link { hash }),
+                op: ValueOp::Clear,
+            });
+        }
+
+        let mut batch = BatchBuilder::new();
+        batch
+            .with_account_id(to_account_id)
+            .with_collection(collection)
+            .create_document();
+        batch.ops.extend(copy_ops);
+
+        // The copied values must still be current when the source is removed
+        batch
+            .with_account_id(from_account_id)
+            .with_collection(collection)
+            .update_document(document_id);
+        batch.ops.extend(assert_ops);
+        batch.delete_document(document_id);
+        batch.ops.extend(clear_ops);
+
+        self.write(batch.build())
+            .await
+            .caused_by(trc::location!())?
+            .last_document_id()
+    }
+
+    async fn document_keys(
+        &self,
+        subspace: u8,
+        prefix: &[u8],
+        filter: impl Fn(&[u8]) -> bool + Sync + Send,
+    ) -> trc::Result<Vec<Vec<u8>>> {
+        let mut keys = Vec::new();
+
+        self.iterate(
+            IterateParams::new(
+                AnyKey {
+                    subspace,
+                    key: prefix.to_vec(),
+                },
+                AnyKey {
+                    subspace,
+                    key: [prefix, &[u8::MAX; 64]].concat(),
+                },
+            )
+            .no_values(),
+            |key, _| {
+                if filter(key) {
+                    keys.push(key.to_vec());
+                }
+                Ok(true)
+            },
+        )
+        .await
+        .caused_by(trc::location!())?;
+
+        Ok(keys)
+    }
+}
+
+fn token_hash(key: &[u8], range: std::ops::Range<usize>) -> trc::Result<BitmapHash> {
+    let mut hash = [0u8; 8];
+    match range.len() {
+        9 => {
+            hash.copy_from_slice(&key[range.start..range.end - 1]);
+            Ok(BitmapHash {
+                hash,
+                len: key[range.end - 1],
+            })
+        }
+        len @ 1..=7 => {
+            hash[..len].copy_from_slice(&key[range]);
+            Ok(BitmapHash {
+                hash,
+                len: len as u8,
+            })
+        }
+        _ => Err(trc::Error::corrupted_key(key, None, trc::location!())),
+    }
+}
@@ -1545,7 +1545,11 @@ impl StoreEvent {
             StoreEvent::NotSupported => "Operation not supported by store",
             StoreEvent::UnexpectedError => "Unexpected store error",
             StoreEvent::CryptoError => "Store crypto error",
+            StoreEvent::ValueTooLarge => "Value too large",
+            StoreEvent::BlobChecksumMismatch => "Blob checksum mismatch",
+            StoreEvent::BlobUnavailable => "Blob store unavailable",
             StoreEvent::BlobMissingMarker => "Blob missing marker",
+            StoreEvent::BitmapRepaired => "Bitmap repaired",
             StoreEvent::SqlQuery => "SQL query executed",
             StoreEvent::LdapQuery => "LDAP query executed",
             StoreEvent::LdapBind => "LDAP bind operation",
@@ -1554,6 +1558,9 @@ impl StoreEvent {
             StoreEvent::BlobWrite => "Blob write operation",
             StoreEvent::BlobDelete => "Blob delete operation",
             StoreEvent::DataIterate => "Data store iteration operation",
+            StoreEvent::DocumentIdAssigned => "Document id assigned",
+            StoreEvent::FoundationdbCommitRetry => "FoundationDB commit retried",
+            StoreEvent::BlobDeduplicated => "Blob deduplicated",
             StoreEvent::HttpStoreFetch => "HTTP store updated",
             StoreEvent::HttpStoreError => "Error updating HTTP store",
         }
@@ -1582,7 +1589,15 @@ impl StoreEvent {
             StoreEvent::NotSupported => "The operation is not supported by the store",
             StoreEvent::UnexpectedError => "An unexpected store error occurred",
             StoreEvent::CryptoError => "A store crypto error occurred",
+            StoreEvent::ValueTooLarge => "The value exceeds the maximum supported size",
+            StoreEvent::BlobChecksumMismatch => {
+                "The blob does not match the checksum stored with it"
+            }
+            StoreEvent::BlobUnavailable => {
+                "The blob store is temporarily unavailable, the operation can be retried"
+            }
             StoreEvent::BlobMissingMarker => "The blob is missing a marker",
+            StoreEvent::BitmapRepaired => "Missing document ids were restored to a bitmap",
             StoreEvent::SqlQuery => "An SQL query was executed",
             StoreEvent::LdapQuery => "An LDAP query was executed",
             StoreEvent::LdapBind => "An LDAP bind operation was executed",
@@ -1591,6 +1606,15 @@ impl StoreEvent {
             StoreEvent::BlobWrite => "A blob write operation was executed",
             StoreEvent::BlobDelete => "A blob delete operation was executed",
             StoreEvent::DataIterate => "A data store iteration operation was executed",
+            StoreEvent::DocumentIdAssigned => {
+                "A document id was assigned, either reusing a freed id or allocating a new one"
+            }
+            StoreEvent::FoundationdbCommitRetry => {
+                "A FoundationDB transaction failed to commit and is being retried"
+            }
+            StoreEvent::BlobDeduplicated => {
+                "A blob write was skipped as the blob store already holds the same contents"
+            }
             StoreEvent::HttpStoreFetch => "The HTTP store was updated",
             StoreEvent::HttpStoreError => "An error occurred while updating the HTTP store",
         }
@@ -1792,6 +1816,7 @@ impl SecurityEvent {
             SecurityEvent::IpBlocked => "Blocked IP address",
             SecurityEvent::ScanBan => "Banned due to scan",
             SecurityEvent::Unauthorized => "Unauthorized access",
+            SecurityEvent::AclChanged => "ACL changed",
         }
     }
 
@@ -1807,6 +1832,9 @@ impl SecurityEvent {
             SecurityEvent::LoiterBan => "IP address was banned due to multiple loitering events",
             SecurityEvent::IpBlocked => "Rejected connection from blocked IP address",
             SecurityEvent::Unauthorized => "Account does not have permission to access resource",
+            SecurityEvent::AclChanged => {
+                "The permissions granted on a shared resource were changed"
+            }
         }
     }
 }
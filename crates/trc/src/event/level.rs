@@ -14,13 +14,17 @@ impl EventType {
             EventType::Store(event) => match event {
                 StoreEvent::DataWrite
                 | StoreEvent::DataIterate
+                | StoreEvent::DocumentIdAssigned
+                | StoreEvent::BlobDeduplicated
                 | StoreEvent::BlobRead
                 | StoreEvent::BlobWrite
                 | StoreEvent::BlobDelete
                 | StoreEvent::SqlQuery
                 | StoreEvent::LdapQuery
                 | StoreEvent::LdapBind => Level::Trace,
-                StoreEvent::NotFound | StoreEvent::HttpStoreFetch => Level::Debug,
+                StoreEvent::NotFound
+                | StoreEvent::HttpStoreFetch
+                | StoreEvent::FoundationdbCommitRetry => Level::Debug,
                 StoreEvent::AssertValueFailed
                 | StoreEvent::FoundationdbError
                 | StoreEvent::MysqlError
@@ -40,8 +44,13 @@ impl EventType {
                 | StoreEvent::NotConfigured
                 | StoreEvent::NotSupported
                 | StoreEvent::UnexpectedError
-                | StoreEvent::CryptoError => Level::Error,
-                StoreEvent::BlobMissingMarker | StoreEvent::HttpStoreError => Level::Warn,
+                | StoreEvent::CryptoError
+                | StoreEvent::ValueTooLarge
+                | StoreEvent::BlobChecksumMismatch
+                | StoreEvent::BlobUnavailable => Level::Error,
+                StoreEvent::BlobMissingMarker
+                | StoreEvent::BitmapRepaired
+                | StoreEvent::HttpStoreError => Level::Warn,
             },
             EventType::Jmap(_) => Level::Debug,
             EventType::Imap(event) => match event {
@@ -527,7 +536,15 @@ impl EventType {
                 | MessageIngestEvent::Duplicate => Level::Info,
                 MessageIngestEvent::Error => Level::Error,
             },
-            EventType::Security(_) => Level::Info,
+            EventType::Security(event) => match event {
+                SecurityEvent::AclChanged => Level::Audit,
+                SecurityEvent::AuthenticationBan
+                | SecurityEvent::AbuseBan
+                | SecurityEvent::ScanBan
+                | SecurityEvent::LoiterBan
+                | SecurityEvent::IpBlocked
+                | SecurityEvent::Unauthorized => Level::Info,
+            },
             EventType::Ai(event) => match event {
                 AiEvent::LlmResponse => Level::Trace,
                 AiEvent::ApiError => Level::Warn,
@@ -581,6 +598,7 @@ impl FromStr for Level {
             "info" => Ok(Self::Info),
             "warn" => Ok(Self::Warn),
             "error" => Ok(Self::Error),
+            "audit" => Ok(Self::Audit),
             _ => Err(s.to_string()),
         }
     }
@@ -595,6 +613,7 @@ impl Level {
             Self::Info => "INFO",
             Self::Warn => "WARN",
             Self::Error => "ERROR",
+            Self::Audit => "AUDIT",
         }
     }
 
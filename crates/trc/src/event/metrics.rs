@@ -36,6 +36,12 @@ impl MetricType {
             Self::QueueCount => "queue.count",
             Self::UserCount => "user.count",
             Self::DomainCount => "domain.count",
+            Self::StoreCollectionReadTime => "store.collection-read-time",
+            Self::StoreCollectionDocumentIdsTime => "store.collection-document-ids-time",
+            Self::StoreCollectionWriteTime => "store.collection-write-time",
+            Self::StoreCommitRetryTime => "store.commit-retry-time",
+            Self::BlobUncompressedSize => "store.blob-uncompressed-size",
+            Self::BlobStoredSize => "store.blob-stored-size",
         }
     }
 
@@ -68,6 +74,14 @@ impl MetricType {
             Self::QueueCount => "Total number of messages in the queue",
             Self::UserCount => "Total number of users",
             Self::DomainCount => "Total number of domains",
+            Self::StoreCollectionReadTime => "Data store read time per collection",
+            Self::StoreCollectionDocumentIdsTime => {
+                "Data store document id retrieval time per collection"
+            }
+            Self::StoreCollectionWriteTime => "Data store write time per collection",
+            Self::StoreCommitRetryTime => "Time spent on commit attempts that were retried",
+            Self::BlobUncompressedSize => "Total size of the blobs written, before compression",
+            Self::BlobStoredSize => "Total size of the blobs written, as stored",
         }
     }
 
@@ -86,11 +100,17 @@ impl MetricType {
             | Self::ImapRequestTime
             | Self::Pop3RequestTime
             | Self::SmtpRequestTime
-            | Self::SieveRequestTime => "milliseconds",
+            | Self::SieveRequestTime
+            | Self::StoreCollectionReadTime
+            | Self::StoreCollectionDocumentIdsTime
+            | Self::StoreCollectionWriteTime
+            | Self::StoreCommitRetryTime => "milliseconds",
             Self::MessageSize
             | Self::MessageAuthSize
             | Self::ReportOutgoingSize
-            | Self::ServerMemory => "bytes",
+            | Self::ServerMemory
+            | Self::BlobUncompressedSize
+            | Self::BlobStoredSize => "bytes",
             Self::HttpActiveConnections
             | Self::ImapActiveConnections
             | Self::Pop3ActiveConnections
@@ -132,6 +152,12 @@ impl MetricType {
             Self::QueueCount => 24,
             Self::UserCount => 25,
             Self::DomainCount => 26,
+            Self::StoreCollectionReadTime => 27,
+            Self::StoreCollectionDocumentIdsTime => 28,
+            Self::StoreCollectionWriteTime => 29,
+            Self::StoreCommitRetryTime => 30,
+            Self::BlobUncompressedSize => 31,
+            Self::BlobStoredSize => 32,
         }
     }
 
@@ -164,6 +190,12 @@ impl MetricType {
             24 => Some(Self::QueueCount),
             25 => Some(Self::UserCount),
             26 => Some(Self::DomainCount),
+            27 => Some(Self::StoreCollectionReadTime),
+            28 => Some(Self::StoreCollectionDocumentIdsTime),
+            29 => Some(Self::StoreCollectionWriteTime),
+            30 => Some(Self::StoreCommitRetryTime),
+            31 => Some(Self::BlobUncompressedSize),
+            32 => Some(Self::BlobStoredSize),
             _ => None,
         }
     }
@@ -197,6 +229,12 @@ impl MetricType {
             "queue.count" => Some(Self::QueueCount),
             "user.count" => Some(Self::UserCount),
             "domain.count" => Some(Self::DomainCount),
+            "store.collection-read-time" => Some(Self::StoreCollectionReadTime),
+            "store.collection-document-ids-time" => Some(Self::StoreCollectionDocumentIdsTime),
+            "store.collection-write-time" => Some(Self::StoreCollectionWriteTime),
+            "store.commit-retry-time" => Some(Self::StoreCommitRetryTime),
+            "store.blob-uncompressed-size" => Some(Self::BlobUncompressedSize),
+            "store.blob-stored-size" => Some(Self::BlobStoredSize),
             _ => None,
         }
     }
@@ -230,6 +268,12 @@ impl MetricType {
             Self::QueueCount,
             Self::UserCount,
             Self::DomainCount,
+            Self::StoreCollectionReadTime,
+            Self::StoreCollectionDocumentIdsTime,
+            Self::StoreCollectionWriteTime,
+            Self::StoreCommitRetryTime,
+            Self::BlobUncompressedSize,
+            Self::BlobStoredSize,
         ]
     }
 }
@@ -184,6 +184,13 @@ impl Error {
         self.0.inner == EventType::Store(StoreEvent::AssertValueFailed)
     }
 
+    /// Whether a blob backend failed temporarily, in which case the operation
+    /// can be retried later. Missing blobs are not errors, reads return `None`.
+    #[inline(always)]
+    pub fn is_blob_unavailable(&self) -> bool {
+        self.0.inner == EventType::Store(StoreEvent::BlobUnavailable)
+    }
+
     pub fn key(&self, key: Key) -> Option<&Value> {
         self.0
             .keys
@@ -343,6 +350,7 @@ impl StoreEvent {
         match self {
             Self::AssertValueFailed => "Another process has modified the value",
             Self::BlobMissingMarker => "Blob is missing marker",
+            Self::BitmapRepaired => "Bitmap was repaired",
             Self::FoundationdbError => "FoundationDB error",
             Self::MysqlError => "MySQL error",
             Self::PostgresqlError => "PostgreSQL error",
@@ -363,6 +371,9 @@ impl StoreEvent {
             Self::NotSupported => "Operation not supported",
             Self::UnexpectedError => "Unexpected error",
             Self::CryptoError => "Crypto error",
+            Self::ValueTooLarge => "Value is too large",
+            Self::BlobChecksumMismatch => "Blob checksum mismatch",
+            Self::BlobUnavailable => "Blob store unavailable",
             _ => "Store error",
         }
     }
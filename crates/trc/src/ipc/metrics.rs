@@ -4,7 +4,7 @@
  * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
  */
 
-use std::sync::atomic::Ordering;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use atomics::{array::AtomicU32Array, gauge::AtomicGauge, histogram::AtomicHistogram};
 use ipc::{
@@ -37,6 +37,12 @@ static STORE_DATA_READ_TIME: AtomicHistogram<12> =
     AtomicHistogram::<10>::new_short_durations(MetricType::StoreReadTime);
 static STORE_DATA_WRITE_TIME: AtomicHistogram<12> =
     AtomicHistogram::<10>::new_short_durations(MetricType::StoreWriteTime);
+static STORE_COMMIT_RETRY_TIME: AtomicHistogram<12> =
+    AtomicHistogram::<10>::new_short_durations(MetricType::StoreCommitRetryTime);
+static STORE_COLLECTION_METRICS: [CollectionMetrics; TOTAL_COLLECTION_SLOTS] =
+    init_collection_metrics();
+static STORE_COLLECTION_METRICS_ENABLED: AtomicBool = AtomicBool::new(false);
+
 static STORE_BLOB_READ_TIME: AtomicHistogram<12> =
     AtomicHistogram::<10>::new_short_durations(MetricType::BlobReadTime);
 static STORE_BLOB_WRITE_TIME: AtomicHistogram<12> =
@@ -49,6 +55,9 @@ static SERVER_MEMORY: AtomicGauge = AtomicGauge::new(MetricType::ServerMemory);
 static QUEUE_COUNT: AtomicGauge = AtomicGauge::new(MetricType::QueueCount);
 static USER_COUNT: AtomicGauge = AtomicGauge::new(MetricType::UserCount);
 static DOMAIN_COUNT: AtomicGauge = AtomicGauge::new(MetricType::DomainCount);
+static STORE_BLOB_UNCOMPRESSED_SIZE: AtomicGauge =
+    AtomicGauge::new(MetricType::BlobUncompressedSize);
+static STORE_BLOB_STORED_SIZE: AtomicGauge = AtomicGauge::new(MetricType::BlobStoredSize);
 
 const CONN_SMTP_IN: usize = 0;
 const CONN_SMTP_OUT: usize = 1;
@@ -63,6 +72,14 @@ pub struct ConnectionMetrics {
     pub elapsed: AtomicHistogram<12>,
 }
 
+const TOTAL_COLLECTION_SLOTS: usize = 16;
+
+pub struct CollectionMetrics {
+    pub read_time: AtomicHistogram<12>,
+    pub document_ids_time: AtomicHistogram<12>,
+    pub write_time: AtomicHistogram<12>,
+}
+
 pub struct EventCounter {
     id: EventType,
     value: u32,
@@ -193,6 +210,9 @@ impl Collector {
             EventType::Store(StoreEvent::DataIterate) => {
                 STORE_DATA_READ_TIME.observe(elapsed);
             }
+            EventType::Store(StoreEvent::FoundationdbCommitRetry) => {
+                STORE_COMMIT_RETRY_TIME.observe(elapsed);
+            }
 
             _ => {}
         }
@@ -226,9 +246,21 @@ impl Collector {
     }
 
     pub fn collect_gauges(is_enterprise: bool) -> impl Iterator<Item = &'static AtomicGauge> {
-        static E_GAUGES: &[&AtomicGauge] =
-            &[&SERVER_MEMORY, &QUEUE_COUNT, &USER_COUNT, &DOMAIN_COUNT];
-        static C_GAUGES: &[&AtomicGauge] = &[&SERVER_MEMORY, &USER_COUNT, &DOMAIN_COUNT];
+        static E_GAUGES: &[&AtomicGauge] = &[
+            &SERVER_MEMORY,
+            &QUEUE_COUNT,
+            &USER_COUNT,
+            &DOMAIN_COUNT,
+            &STORE_BLOB_UNCOMPRESSED_SIZE,
+            &STORE_BLOB_STORED_SIZE,
+        ];
+        static C_GAUGES: &[&AtomicGauge] = &[
+            &SERVER_MEMORY,
+            &USER_COUNT,
+            &DOMAIN_COUNT,
+            &STORE_BLOB_UNCOMPRESSED_SIZE,
+            &STORE_BLOB_STORED_SIZE,
+        ];
 
         if is_enterprise { E_GAUGES } else { C_GAUGES }
             .iter()
@@ -248,6 +280,7 @@ impl Collector {
             &MESSAGE_OUT_REPORT_SIZE,
             &STORE_DATA_READ_TIME,
             &STORE_DATA_WRITE_TIME,
+            &STORE_COMMIT_RETRY_TIME,
             &STORE_BLOB_READ_TIME,
             &STORE_BLOB_WRITE_TIME,
             &DNS_LOOKUP_TIME,
@@ -269,6 +302,54 @@ impl Collector {
         .filter(|h| h.is_active())
     }
 
+    /// Enables or disables the per-collection store latency histograms.
+    pub fn set_collection_metrics(enabled: bool) {
+        STORE_COLLECTION_METRICS_ENABLED.store(enabled, Ordering::Relaxed);
+    }
+
+    #[inline(always)]
+    pub fn has_collection_metrics() -> bool {
+        STORE_COLLECTION_METRICS_ENABLED.load(Ordering::Relaxed)
+    }
+
+    pub fn observe_collection(metric_type: MetricType, collection: u8, elapsed: u64) {
+        if let Some(histogram) = Self::collection_histogram(metric_type, collection) {
+            histogram.observe(elapsed);
+        }
+    }
+
+    pub fn collection_histogram(
+        metric_type: MetricType,
+        collection: u8,
+    ) -> Option<&'static AtomicHistogram<12>> {
+        let metrics = STORE_COLLECTION_METRICS.get(collection as usize)?;
+        match metric_type {
+            MetricType::StoreCollectionReadTime => Some(&metrics.read_time),
+            MetricType::StoreCollectionDocumentIdsTime => Some(&metrics.document_ids_time),
+            MetricType::StoreCollectionWriteTime => Some(&metrics.write_time),
+            _ => None,
+        }
+    }
+
+    /// Returns the active per-collection histograms along with the collection id
+    /// they were recorded for, grouped by metric type.
+    pub fn collect_collection_histograms(
+    ) -> impl Iterator<Item = (u8, &'static AtomicHistogram<12>)> {
+        [
+            MetricType::StoreCollectionReadTime,
+            MetricType::StoreCollectionDocumentIdsTime,
+            MetricType::StoreCollectionWriteTime,
+        ]
+        .into_iter()
+        .flat_map(|metric_type| {
+            (0..TOTAL_COLLECTION_SLOTS as u8).filter_map(move |collection| {
+                Self::collection_histogram(metric_type, collection)
+                    .filter(|h| h.is_active())
+                    .map(|h| (collection, h))
+            })
+        })
+    }
+
     #[inline(always)]
     pub fn read_event_metric(metric_id: usize) -> u32 {
         EVENT_COUNTERS.get(metric_id)
@@ -290,8 +371,11 @@ impl Collector {
             MetricType::ReportOutgoingSize => MESSAGE_OUT_REPORT_SIZE.average(),
             MetricType::StoreReadTime => STORE_DATA_READ_TIME.average(),
             MetricType::StoreWriteTime => STORE_DATA_WRITE_TIME.average(),
+            MetricType::StoreCommitRetryTime => STORE_COMMIT_RETRY_TIME.average(),
             MetricType::BlobReadTime => STORE_BLOB_READ_TIME.average(),
             MetricType::BlobWriteTime => STORE_BLOB_WRITE_TIME.average(),
+            MetricType::BlobUncompressedSize => STORE_BLOB_UNCOMPRESSED_SIZE.get() as f64,
+            MetricType::BlobStoredSize => STORE_BLOB_STORED_SIZE.get() as f64,
             MetricType::DnsLookupTime => DNS_LOOKUP_TIME.average(),
             MetricType::HttpActiveConnections => {
                 CONNECTION_METRICS[CONN_HTTP].active_connections.get() as f64
@@ -315,6 +399,18 @@ impl Collector {
             MetricType::SieveRequestTime => CONNECTION_METRICS[CONN_SIEVE].elapsed.average(),
             MetricType::UserCount => USER_COUNT.get() as f64,
             MetricType::DomainCount => DOMAIN_COUNT.get() as f64,
+            MetricType::StoreCollectionReadTime
+            | MetricType::StoreCollectionDocumentIdsTime
+            | MetricType::StoreCollectionWriteTime => {
+                let (sum, count) = (0..TOTAL_COLLECTION_SLOTS as u8)
+                    .filter_map(|collection| Self::collection_histogram(metric_type, collection))
+                    .fold((0, 0), |(sum, count), h| (sum + h.sum(), count + h.count()));
+                if count > 0 {
+                    sum as f64 / count as f64
+                } else {
+                    0.0
+                }
+            }
         }
     }
 
@@ -324,6 +420,17 @@ impl Collector {
             MetricType::QueueCount => QUEUE_COUNT.set(value),
             MetricType::UserCount => USER_COUNT.set(value),
             MetricType::DomainCount => DOMAIN_COUNT.set(value),
+            MetricType::BlobUncompressedSize => STORE_BLOB_UNCOMPRESSED_SIZE.set(value),
+            MetricType::BlobStoredSize => STORE_BLOB_STORED_SIZE.set(value),
+            _ => {}
+        }
+    }
+
+    /// Adds to a gauge tracking a running total.
+    pub fn add_gauge(metric_type: MetricType, value: u64) {
+        match metric_type {
+            MetricType::BlobUncompressedSize => STORE_BLOB_UNCOMPRESSED_SIZE.add(value),
+            MetricType::BlobStoredSize => STORE_BLOB_STORED_SIZE.add(value),
             _ => {}
         }
     }
@@ -407,6 +514,20 @@ const fn init_conn_metrics() -> [ConnectionMetrics; TOTAL_CONN_TYPES] {
     array
 }
 
+#[allow(clippy::declare_interior_mutable_const)]
+const fn init_collection_metrics() -> [CollectionMetrics; TOTAL_COLLECTION_SLOTS] {
+    const INIT: CollectionMetrics = CollectionMetrics {
+        read_time: AtomicHistogram::<12>::new_short_durations(MetricType::StoreCollectionReadTime),
+        document_ids_time: AtomicHistogram::<12>::new_short_durations(
+            MetricType::StoreCollectionDocumentIdsTime,
+        ),
+        write_time: AtomicHistogram::<12>::new_short_durations(
+            MetricType::StoreCollectionWriteTime,
+        ),
+    };
+    [INIT; TOTAL_COLLECTION_SLOTS]
+}
+
 impl EventType {
     pub fn is_metric(&self) -> bool {
         match self {
@@ -451,9 +572,15 @@ impl EventType {
                 | StoreEvent::NotSupported
                 | StoreEvent::UnexpectedError
                 | StoreEvent::CryptoError
+                | StoreEvent::ValueTooLarge
+                | StoreEvent::BlobChecksumMismatch
+                | StoreEvent::BlobUnavailable
                 | StoreEvent::BlobMissingMarker
+                | StoreEvent::BitmapRepaired
                 | StoreEvent::DataWrite
                 | StoreEvent::DataIterate
+                | StoreEvent::FoundationdbCommitRetry
+                | StoreEvent::BlobDeduplicated
                 | StoreEvent::BlobRead
                 | StoreEvent::BlobWrite
                 | StoreEvent::BlobDelete
@@ -48,7 +48,8 @@ pub enum Level {
     Info = 2,
     Warn = 3,
     Error = 4,
-    Disable = 5,
+    Audit = 5,
+    Disable = 6,
 }
 
 #[derive(Debug, Default, Clone)]
@@ -208,6 +209,7 @@ pub enum SecurityEvent {
     LoiterBan,
     IpBlocked,
     Unauthorized,
+    AclChanged,
 }
 
 #[event_type]
@@ -840,13 +842,20 @@ pub enum StoreEvent {
     UnexpectedError,
     CryptoError,
     HttpStoreError,
+    ValueTooLarge,
+    BlobChecksumMismatch,
+    BlobUnavailable,
 
     // Warnings
     BlobMissingMarker,
+    BitmapRepaired,
 
     // Traces
     DataWrite,
     DataIterate,
+    DocumentIdAssigned,
+    FoundationdbCommitRetry,
+    BlobDeduplicated,
     BlobRead,
     BlobWrite,
     BlobDelete,
@@ -979,6 +988,12 @@ pub enum MetricType {
     SieveRequestTime,
     UserCount,
     DomainCount,
+    StoreCollectionReadTime,
+    StoreCollectionDocumentIdsTime,
+    StoreCollectionWriteTime,
+    StoreCommitRetryTime,
+    BlobUncompressedSize,
+    BlobStoredSize,
 }
 
 pub const TOTAL_EVENT_COUNT: usize = total_event_count!();
@@ -864,6 +864,14 @@ impl EventType {
             EventType::Spam(SpamEvent::Dnsbl) => 562,
             EventType::Spam(SpamEvent::DnsblError) => 563,
             EventType::Spam(SpamEvent::Pyzor) => 564,
+            EventType::Store(StoreEvent::ValueTooLarge) => 565,
+            EventType::Store(StoreEvent::BitmapRepaired) => 566,
+            EventType::Security(SecurityEvent::AclChanged) => 567,
+            EventType::Store(StoreEvent::DocumentIdAssigned) => 568,
+            EventType::Store(StoreEvent::FoundationdbCommitRetry) => 569,
+            EventType::Store(StoreEvent::BlobDeduplicated) => 570,
+            EventType::Store(StoreEvent::BlobChecksumMismatch) => 571,
+            EventType::Store(StoreEvent::BlobUnavailable) => 572,
             EventType::Queue(QueueEvent::BackPressure) => 48,
             EventType::Imap(ImapEvent::GetQuota) => 57,
         }
@@ -1467,6 +1475,14 @@ impl EventType {
             562 => Some(EventType::Spam(SpamEvent::Dnsbl)),
             563 => Some(EventType::Spam(SpamEvent::DnsblError)),
             564 => Some(EventType::Spam(SpamEvent::Pyzor)),
+            565 => Some(EventType::Store(StoreEvent::ValueTooLarge)),
+            566 => Some(EventType::Store(StoreEvent::BitmapRepaired)),
+            567 => Some(EventType::Security(SecurityEvent::AclChanged)),
+            568 => Some(EventType::Store(StoreEvent::DocumentIdAssigned)),
+            569 => Some(EventType::Store(StoreEvent::FoundationdbCommitRetry)),
+            570 => Some(EventType::Store(StoreEvent::BlobDeduplicated)),
+            571 => Some(EventType::Store(StoreEvent::BlobChecksumMismatch)),
+            572 => Some(EventType::Store(StoreEvent::BlobUnavailable)),
             48 => Some(EventType::Queue(QueueEvent::BackPressure)),
             57 => Some(EventType::Imap(ImapEvent::GetQuota)),
             _ => None,
@@ -76,6 +76,7 @@ impl<T: AsyncWrite + Unpin> FmtWriter<T> {
                         Level::Info => Color::Green,
                         Level::Debug => Color::Blue,
                         Level::Trace => Color::Magenta,
+                        Level::Audit => Color::Cyan,
                         Level::Disable => return Ok(()),
                     }
                     .as_code_bold()
@@ -425,6 +426,8 @@ mod tests {
         assert!(Level::Trace.is_contained(Level::Debug));
         assert!(!Level::Error.is_contained(Level::Trace));
         assert!(!Level::Debug.is_contained(Level::Trace));
+        assert!(Level::Info.is_contained(Level::Audit));
+        assert!(!Level::Audit.is_contained(Level::Error));
 
         let mut names = Vec::with_capacity(100);
 
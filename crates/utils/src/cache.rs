@@ -67,6 +67,15 @@ impl<K: Eq + Hash + CacheItemWeight, V: Clone + CacheItemWeight> Cache<K, V> {
         self.0.get(key)
     }
 
+    /// Same as `get` without marking the entry as recently used.
+    #[inline(always)]
+    pub fn peek<Q>(&self, key: &Q) -> Option<V>
+    where
+        Q: Hash + Equivalent<K> + ?Sized,
+    {
+        self.0.peek(key)
+    }
+
     #[inline(always)]
     pub async fn get_value_or_guard_async<'a, Q>(
         &'a self,
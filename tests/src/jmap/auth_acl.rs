@@ -5,6 +5,16 @@
  */
 
 use ::email::mailbox::{INBOX_ID, TRASH_ID};
+use common::{
+    config::jmap::settings::{AclEvaluation, DuplicateGrantee},
+    core::BuildServer,
+    SharedAclId,
+};
+use directory::{
+    backend::internal::{manage::ManageDirectory, PrincipalField},
+    Principal, QueryBy, Type,
+};
+use jmap::auth::acl::{with_shared_grants_memo, AclMethods, EffectiveAcl, SharedGrantsMemo};
 use jmap_client::{
     core::{
         error::{MethodError, MethodErrorType},
@@ -14,13 +24,33 @@ use jmap_client::{
     mailbox::{self, Role},
     principal::ACL,
 };
-use jmap_proto::types::id::Id;
-use std::fmt::Debug;
-use store::ahash::AHashMap;
+use jmap_proto::{
+    object::Object,
+    types::{
+        acl::{acl_change_id, acl_changes_collection, Acl},
+        collection::Collection,
+        date::UTCDate,
+        id::Id,
+        value::Value,
+    },
+};
+use std::{fmt::Debug, sync::Arc};
+use store::{
+    ahash::AHashMap,
+    query::{
+        acl::AclQuery,
+        log::{Change, Query},
+    },
+    roaring::RoaringBitmap,
+    write::{BatchBuilder, ValueClass},
+};
+use utils::map::bitmap::Bitmap;
 
 use crate::{
     directory::internal::TestInternalDirectory,
-    jmap::{assert_is_empty, mailbox::destroy_all_mailboxes, test_account_login},
+    jmap::{
+        assert_is_empty, jmap_json_request, mailbox::destroy_all_mailboxes, test_account_login,
+    },
 };
 
 use super::JMAPTest;
@@ -171,6 +201,421 @@ pub async fn test(params: &mut JMAPTest) {
             .await,
     );
 
+    // Jane grants Inbox Lookup access to John
+    let response = jmap_json_request(
+        format!(
+            r#"[["Mailbox/set",{{"accountId":"{jane_id}","update":{{"{inbox_id}":{{"acl/jdoe@example.com":["lookup"]}}}}}},"0"]]"#
+        ),
+        "jane.smith@example.com",
+        "abcde",
+    )
+    .await;
+    assert!(
+        response["methodResponses"][0][1]["updated"]
+            .as_object()
+            .is_some_and(|updated| updated.contains_key(&inbox_id)),
+        "unexpected response: {response}"
+    );
+
+    // The grant is recorded in the ACL change log of Jane's account
+    let acl_changes = server
+        .core
+        .storage
+        .data
+        .changes(
+            jane_id.document_id(),
+            acl_changes_collection(Collection::Mailbox),
+            Query::All,
+        )
+        .await
+        .unwrap();
+    assert!(
+        acl_changes.changes.contains(&Change::Insert(acl_change_id(
+            john_id.document_id(),
+            INBOX_ID
+        ))),
+        "{acl_changes:?}"
+    );
+
+    // and in the ACL change log of John's account
+    let acl_changes = server
+        .core
+        .storage
+        .data
+        .changes(
+            john_id.document_id(),
+            acl_changes_collection(Collection::Mailbox),
+            Query::All,
+        )
+        .await
+        .unwrap();
+    assert!(
+        acl_changes.changes.contains(&Change::Insert(acl_change_id(
+            jane_id.document_id(),
+            INBOX_ID
+        ))),
+        "{acl_changes:?}"
+    );
+
+    // Jane's Inbox is listed among the documents shared with John
+    let granted = server
+        .granted_documents(
+            &server
+                .get_access_token(john_id.document_id())
+                .await
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert!(
+        granted
+            .iter()
+            .any(|(account_id, collection, document_id, grant)| {
+                *account_id == jane_id.document_id()
+                    && *collection == Collection::Mailbox
+                    && *document_id == INBOX_ID
+                    && grant.grants.contains(Acl::Lookup)
+            }),
+        "{granted:?}"
+    );
+
+    // The lookup right gives John access to Jane's mailboxes but not her emails
+    let john_token = server
+        .get_access_token(john_id.document_id())
+        .await
+        .unwrap();
+    assert!(john_token.has_access(jane_id.document_id(), Collection::Mailbox));
+    assert!(!john_token.has_access(jane_id.document_id(), Collection::Email));
+
+    // Only listings include mailboxes granted with the lookup right, queries for
+    // the read right do not
+    assert!(server
+        .visible_shared_mailboxes(&john_token, jane_id.document_id())
+        .await
+        .unwrap()
+        .contains(INBOX_ID));
+    assert!(!server
+        .shared_documents(
+            &john_token,
+            jane_id.document_id(),
+            Collection::Mailbox,
+            Acl::Read,
+        )
+        .await
+        .unwrap()
+        .contains(INBOX_ID));
+
+    // Sending as Jane requires the submit right on one of her mailboxes,
+    assert!(!server
+        .has_submit_access(&john_token, jane_id.document_id(), None)
+        .await
+        .unwrap());
+    // and grants restricted to other networks are not taken into account
+    let remote_ip = "127.0.0.1".parse::<std::net::IpAddr>().unwrap();
+    for (rights, can_submit) in [
+        (r#"["lookup","submit"]"#, true),
+        (r#"["lookup","submit","network:192.168.10.0/24"]"#, false),
+        (r#"["lookup","submit","network:127.0.0.0/8"]"#, true),
+        (r#"["lookup"]"#, false),
+    ] {
+        let response = jmap_json_request(
+            format!(
+                r#"[["Mailbox/set",{{"accountId":"{jane_id}","update":{{"{inbox_id}":{{"acl/jdoe@example.com":{rights}}}}}}},"0"]]"#
+            ),
+            "jane.smith@example.com",
+            "abcde",
+        )
+        .await;
+        assert!(
+            response["methodResponses"][0][1]["updated"]
+                .as_object()
+                .is_some_and(|updated| updated.contains_key(&inbox_id)),
+            "unexpected response: {response}"
+        );
+        let acl = jmap_json_request(
+            format!(
+                r#"[["Mailbox/get",{{"accountId":"{jane_id}","ids":["{inbox_id}"],"properties":["acl"]}},"0"]]"#
+            ),
+            "jane.smith@example.com",
+            "abcde",
+        )
+        .await;
+        assert_eq!(
+            acl["methodResponses"][0][1]["list"][0]["acl"]["jdoe@example.com"],
+            serde_json::from_str::<serde_json::Value>(rights).unwrap(),
+            "unexpected response: {acl}"
+        );
+        assert_eq!(
+            server
+                .has_submit_access(&john_token, jane_id.document_id(), Some(&remote_ip))
+                .await
+                .unwrap(),
+            can_submit,
+            "{rights}"
+        );
+    }
+
+    // John should see Jane's Inbox in listings but not its messages
+    assert_eq!(
+        john_client
+            .set_default_account_id(jane_id.to_string())
+            .mailbox_get(&inbox_id, [mailbox::Property::Name].into())
+            .await
+            .unwrap()
+            .unwrap()
+            .name()
+            .unwrap(),
+        "Inbox"
+    );
+    assert!(john_client
+        .set_default_account_id(jane_id.to_string())
+        .email_query(None::<Filter>, None::<Vec<_>>)
+        .await
+        .unwrap()
+        .ids()
+        .is_empty());
+    assert!(john_client
+        .set_default_account_id(jane_id.to_string())
+        .email_get(
+            email_ids.get("jane").unwrap().first().unwrap(),
+            [Property::Subject].into(),
+        )
+        .await
+        .unwrap()
+        .is_none());
+
+    // Jane grants Inbox access to John only during a time window
+    let minute = (store::write::now() % 86400) / 60;
+    for (schedule, is_active) in [
+        ("mon-sun/00:00-24:00".to_string(), true),
+        (
+            format!(
+                "mon-sun/{:02}:{:02}-{:02}:{:02}",
+                ((minute + 120) % 1440) / 60,
+                ((minute + 120) % 1440) % 60,
+                ((minute + 180) % 1440) / 60,
+                ((minute + 180) % 1440) % 60,
+            ),
+            false,
+        ),
+    ] {
+        let response = jmap_json_request(
+            format!(
+                r#"[["Mailbox/set",{{"accountId":"{jane_id}","update":{{"{inbox_id}":{{"acl/jdoe@example.com":["read","readItems","schedule:{schedule}"]}}}}}},"0"]]"#
+            ),
+            "jane.smith@example.com",
+            "abcde",
+        )
+        .await;
+        assert!(
+            response["methodResponses"][0][1]["updated"]
+                .as_object()
+                .is_some_and(|updated| updated.contains_key(&inbox_id)),
+            "unexpected response: {response}"
+        );
+        let acl = jmap_json_request(
+            format!(
+                r#"[["Mailbox/get",{{"accountId":"{jane_id}","ids":["{inbox_id}"],"properties":["acl"]}},"0"]]"#
+            ),
+            "jane.smith@example.com",
+            "abcde",
+        )
+        .await;
+        assert_eq!(
+            acl["methodResponses"][0][1]["list"][0]["acl"]["jdoe@example.com"],
+            serde_json::json!(["read", "readItems", format!("schedule:{schedule}")]),
+            "unexpected response: {acl}"
+        );
+
+        let ids = john_client
+            .set_default_account_id(jane_id.to_string())
+            .email_query(None::<Filter>, None::<Vec<_>>)
+            .await
+            .unwrap()
+            .take_ids();
+        if is_active {
+            assert_eq!(
+                ids,
+                [email_ids.get("jane").unwrap().first().unwrap().as_str()]
+            );
+        } else {
+            assert!(ids.is_empty(), "unexpected ids {ids:?}");
+        }
+    }
+
+    // Jane grants John access to a subset of the Inbox messages
+    let legal_id = jane_client
+        .set_default_account_id(jane_id.to_string())
+        .email_import(
+            concat!(
+                "From: Legal Review <legal@example.com>\r\n",
+                "To: jane.smith@example.com\r\n",
+                "Subject: Under review\r\n",
+                "\r\n",
+                "This message is part of the review.",
+            )
+            .as_bytes()
+            .to_vec(),
+            [&inbox_id],
+            None::<Vec<&str>>,
+            None,
+        )
+        .await
+        .unwrap()
+        .take_id();
+    let jane_inbox_id = email_ids.get("jane").unwrap().first().unwrap().as_str();
+    for (criteria, expected_ids) in [
+        ("from=legal@example.com", vec![legal_id.as_str()]),
+        ("from=acl_test@example.com", vec![jane_inbox_id]),
+        (
+            "from=legal@example.com;after=2000-01-01T00:00:00Z",
+            vec![legal_id.as_str()],
+        ),
+        (
+            "after=2000-01-01T00:00:00Z",
+            vec![jane_inbox_id, legal_id.as_str()],
+        ),
+        ("before=2000-01-01T00:00:00Z", vec![]),
+    ] {
+        let response = jmap_json_request(
+            format!(
+                r#"[["Mailbox/set",{{"accountId":"{jane_id}","update":{{"{inbox_id}":{{"acl/jdoe@example.com":["read","readItems","criteria:{criteria}"]}}}}}},"0"]]"#
+            ),
+            "jane.smith@example.com",
+            "abcde",
+        )
+        .await;
+        assert!(
+            response["methodResponses"][0][1]["updated"]
+                .as_object()
+                .is_some_and(|updated| updated.contains_key(&inbox_id)),
+            "unexpected response: {response}"
+        );
+        let acl = jmap_json_request(
+            format!(
+                r#"[["Mailbox/get",{{"accountId":"{jane_id}","ids":["{inbox_id}"],"properties":["acl"]}},"0"]]"#
+            ),
+            "jane.smith@example.com",
+            "abcde",
+        )
+        .await;
+        assert_eq!(
+            acl["methodResponses"][0][1]["list"][0]["acl"]["jdoe@example.com"],
+            serde_json::json!(["read", "readItems", format!("criteria:{criteria}")]),
+            "unexpected response: {acl}"
+        );
+
+        let mut ids = john_client
+            .set_default_account_id(jane_id.to_string())
+            .email_query(None::<Filter>, None::<Vec<_>>)
+            .await
+            .unwrap()
+            .take_ids();
+        ids.sort_unstable();
+        let mut expected_ids = expected_ids;
+        expected_ids.sort_unstable();
+        assert_eq!(ids, expected_ids, "criteria {criteria}");
+    }
+    jane_client.email_destroy(&legal_id).await.unwrap();
+
+    // Jane grants Inbox access to John until a given date
+    for (expires, is_active) in [
+        (
+            UTCDate::from_timestamp(store::write::now() as i64 + 86400).to_string(),
+            true,
+        ),
+        ("2000-01-01T00:00:00Z".to_string(), false),
+    ] {
+        let response = jmap_json_request(
+            format!(
+                r#"[["Mailbox/set",{{"accountId":"{jane_id}","update":{{"{inbox_id}":{{"acl/jdoe@example.com":["read","readItems","expires:{expires}"]}}}}}},"0"]]"#
+            ),
+            "jane.smith@example.com",
+            "abcde",
+        )
+        .await;
+        assert!(
+            response["methodResponses"][0][1]["updated"]
+                .as_object()
+                .is_some_and(|updated| updated.contains_key(&inbox_id)),
+            "unexpected response: {response}"
+        );
+        let acl = jmap_json_request(
+            format!(
+                r#"[["Mailbox/get",{{"accountId":"{jane_id}","ids":["{inbox_id}"],"properties":["acl"]}},"0"]]"#
+            ),
+            "jane.smith@example.com",
+            "abcde",
+        )
+        .await;
+
+        let ids = john_client
+            .set_default_account_id(jane_id.to_string())
+            .email_query(None::<Filter>, None::<Vec<_>>)
+            .await
+            .unwrap()
+            .take_ids();
+        if is_active {
+            assert_eq!(
+                acl["methodResponses"][0][1]["list"][0]["acl"]["jdoe@example.com"],
+                serde_json::json!(["read", "readItems", format!("expires:{expires}")]),
+                "unexpected response: {acl}"
+            );
+            assert_eq!(ids, [jane_inbox_id]);
+        } else {
+            // Expired grants are dropped when the ACL is updated
+            assert!(
+                acl["methodResponses"][0][1]["list"][0]["acl"]["jdoe@example.com"].is_null(),
+                "unexpected response: {acl}"
+            );
+            assert!(ids.is_empty(), "unexpected ids {ids:?}");
+        }
+    }
+
+    // Jane grants Inbox access to John only from certain networks, test clients
+    // connect from the loopback address
+    for (network, is_active) in [("127.0.0.0/8", true), ("192.168.10.0/24", false)] {
+        let response = jmap_json_request(
+            format!(
+                r#"[["Mailbox/set",{{"accountId":"{jane_id}","update":{{"{inbox_id}":{{"acl/jdoe@example.com":["read","readItems","network:{network}"]}}}}}},"0"]]"#
+            ),
+            "jane.smith@example.com",
+            "abcde",
+        )
+        .await;
+        assert!(
+            response["methodResponses"][0][1]["updated"]
+                .as_object()
+                .is_some_and(|updated| updated.contains_key(&inbox_id)),
+            "unexpected response: {response}"
+        );
+        let acl = jmap_json_request(
+            format!(
+                r#"[["Mailbox/get",{{"accountId":"{jane_id}","ids":["{inbox_id}"],"properties":["acl"]}},"0"]]"#
+            ),
+            "jane.smith@example.com",
+            "abcde",
+        )
+        .await;
+        assert_eq!(
+            acl["methodResponses"][0][1]["list"][0]["acl"]["jdoe@example.com"],
+            serde_json::json!(["read", "readItems", format!("network:{network}")]),
+            "unexpected response: {acl}"
+        );
+
+        let ids = john_client
+            .set_default_account_id(jane_id.to_string())
+            .email_query(None::<Filter>, None::<Vec<_>>)
+            .await
+            .unwrap()
+            .take_ids();
+        if is_active {
+            assert_eq!(ids, [jane_inbox_id]);
+        } else {
+            assert!(ids.is_empty(), "unexpected ids {ids:?}");
+        }
+    }
+
     // Jane grants Inbox ReadItems access to John
     jane_client
         .mailbox_update_acl(&inbox_id, "jdoe@example.com", [ACL::ReadItems])
@@ -784,6 +1229,1251 @@ pub async fn test(params: &mut JMAPTest) {
             .await,
     );
 
+    // Bill grants Jane access to several mailboxes at once
+    let mut legal_ids = RoaringBitmap::new();
+    for name in ["Legal Hold 1", "Legal Hold 2", "Legal Hold 3"] {
+        legal_ids.insert(
+            Id::from_bytes(
+                bill_client
+                    .set_default_account_id(bill_id.to_string())
+                    .mailbox_create(name, None::<&str>, Role::None)
+                    .await
+                    .unwrap()
+                    .take_id()
+                    .as_bytes(),
+            )
+            .unwrap()
+            .document_id(),
+        );
+    }
+    let grants = Bitmap::from_iter([Acl::Read, Acl::ReadItems]);
+    let john_token = server
+        .get_access_token(john_id.document_id())
+        .await
+        .unwrap();
+    assert!(server
+        .grant_to_documents(
+            &john_token,
+            bill_id.document_id(),
+            Collection::Mailbox,
+            &legal_ids,
+            jane_id.document_id(),
+            grants,
+        )
+        .await
+        .unwrap_err()
+        .matches(trc::EventType::Jmap(trc::JmapEvent::Forbidden)));
+    let bill_token = server
+        .get_access_token(bill_id.document_id())
+        .await
+        .unwrap();
+    let last_change_id = server
+        .core
+        .storage
+        .data
+        .get_last_change_id(bill_id.document_id(), Collection::Mailbox)
+        .await
+        .unwrap()
+        .unwrap();
+    for expected_count in [3, 0] {
+        assert_eq!(
+            server
+                .grant_to_documents(
+                    &bill_token,
+                    bill_id.document_id(),
+                    Collection::Mailbox,
+                    &legal_ids,
+                    jane_id.document_id(),
+                    grants,
+                )
+                .await
+                .unwrap(),
+            expected_count
+        );
+    }
+    let jane_token = server
+        .get_access_token(jane_id.document_id())
+        .await
+        .unwrap();
+    assert_eq!(
+        server
+            .has_access_to_documents(
+                &jane_token,
+                bill_id.document_id(),
+                Collection::Mailbox,
+                &legal_ids,
+                Acl::ReadItems,
+            )
+            .await
+            .unwrap(),
+        legal_ids
+    );
+
+    // The updates are recorded in Bill's change log
+    let changes = server
+        .core
+        .storage
+        .data
+        .changes(
+            bill_id.document_id(),
+            Collection::Mailbox,
+            Query::Since(last_change_id),
+        )
+        .await
+        .unwrap();
+    for document_id in &legal_ids {
+        assert!(
+            changes
+                .changes
+                .contains(&Change::Update(document_id as u64)),
+            "{changes:?}"
+        );
+    }
+    let mut requested_ids = legal_ids.clone();
+    requested_ids.insert(u32::MAX - 1);
+    assert_eq!(
+        server
+            .has_access_to_documents(
+                &jane_token,
+                bill_id.document_id(),
+                Collection::Mailbox,
+                &requested_ids,
+                Acl::ReadItems,
+            )
+            .await
+            .unwrap(),
+        legal_ids
+    );
+    assert!(server
+        .has_access_to_documents(
+            &john_token,
+            bill_id.document_id(),
+            Collection::Mailbox,
+            &requested_ids,
+            Acl::ReadItems,
+        )
+        .await
+        .unwrap()
+        .is_empty());
+
+    // The owner of the account has access to all its documents
+    assert_eq!(
+        server
+            .has_access_to_documents(
+                &bill_token,
+                bill_id.document_id(),
+                Collection::Mailbox,
+                &requested_ids,
+                Acl::Administer,
+            )
+            .await
+            .unwrap(),
+        requested_ids
+    );
+
+    // Requests with pinned ACLs keep their decisions when grants change halfway
+    let mut pinned_ids = legal_ids.iter();
+    let first_id = pinned_ids.next().unwrap();
+    let second_id = pinned_ids.next().unwrap();
+    let (decisions, rights) = with_shared_grants_memo(SharedGrantsMemo::pinned(), async {
+        let mut decisions = Vec::new();
+        for document_id in [first_id, second_id] {
+            decisions.push(
+                server
+                    .has_access_to_document(
+                        &john_token,
+                        bill_id.document_id(),
+                        Collection::Mailbox,
+                        document_id,
+                        Acl::ReadItems,
+                    )
+                    .await
+                    .unwrap(),
+            );
+            if document_id == first_id {
+                server
+                    .grant_to_documents(
+                        &bill_token,
+                        bill_id.document_id(),
+                        Collection::Mailbox,
+                        &legal_ids,
+                        john_id.document_id(),
+                        grants,
+                    )
+                    .await
+                    .unwrap();
+            }
+        }
+        let mailbox = server
+            .get_property::<Object<Value>>(
+                bill_id.document_id(),
+                Collection::Mailbox,
+                second_id,
+                jmap_proto::types::property::Property::Value,
+            )
+            .await
+            .unwrap()
+            .unwrap();
+        let rights = server
+            .document_effective_acl(
+                &john_token,
+                bill_id.document_id(),
+                Collection::Mailbox,
+                second_id,
+                &mailbox,
+            )
+            .await
+            .unwrap();
+        (decisions, rights)
+    })
+    .await;
+    assert_eq!(decisions, [false, false]);
+    assert!(!rights.contains(Acl::ReadItems));
+    assert!(server
+        .has_access_to_document(
+            &john_token,
+            bill_id.document_id(),
+            Collection::Mailbox,
+            second_id,
+            Acl::ReadItems,
+        )
+        .await
+        .unwrap());
+
+    // Effective rights are memoized until the ACLs of the collection are refreshed
+    let mailbox = server
+        .get_property::<Object<Value>>(
+            bill_id.document_id(),
+            Collection::Mailbox,
+            second_id,
+            jmap_proto::types::property::Property::Value,
+        )
+        .await
+        .unwrap()
+        .unwrap();
+    let (rights, memoized, refreshed) =
+        with_shared_grants_memo(SharedGrantsMemo::default(), async {
+            let mut rights = Vec::new();
+            for object in [&mailbox, &Object::with_capacity(0)] {
+                rights.push(
+                    server
+                        .document_effective_acl(
+                            &john_token,
+                            bill_id.document_id(),
+                            Collection::Mailbox,
+                            second_id,
+                            object,
+                        )
+                        .await
+                        .unwrap(),
+                );
+            }
+            server
+                .refresh_acls(
+                    &bill_token,
+                    bill_id.document_id(),
+                    Collection::Mailbox,
+                    Some(second_id),
+                    &mut Object::with_capacity(0),
+                    &None,
+                )
+                .await;
+            let refreshed = server
+                .document_effective_acl(
+                    &john_token,
+                    bill_id.document_id(),
+                    Collection::Mailbox,
+                    second_id,
+                    &Object::with_capacity(0),
+                )
+                .await
+                .unwrap();
+            (rights[0], rights[1], refreshed)
+        })
+        .await;
+    assert!(rights.contains(Acl::ReadItems));
+    assert_eq!(memoized, rights);
+    assert!(refreshed.is_empty());
+    for document_id in &legal_ids {
+        bill_client
+            .set_default_account_id(bill_id.to_string())
+            .mailbox_update_acl(&Id::from(document_id).to_string(), "jdoe@example.com", [])
+            .await
+            .unwrap();
+    }
+
+    // Explanations attribute access to the grant that allowed it
+    let group_shared_id = legal_ids.min().unwrap();
+    assert_eq!(
+        server
+            .grant_to_documents(
+                &bill_token,
+                bill_id.document_id(),
+                Collection::Mailbox,
+                &RoaringBitmap::from_iter([group_shared_id]),
+                sales_id.document_id(),
+                Bitmap::from_iter([Acl::Modify]),
+            )
+            .await
+            .unwrap(),
+        1
+    );
+    let explanation = server
+        .explain_access_to_document(
+            &jane_token,
+            bill_id.document_id(),
+            Collection::Mailbox,
+            group_shared_id,
+            Acl::Modify,
+        )
+        .await
+        .unwrap();
+    assert!(explanation.allowed);
+    assert_eq!(
+        explanation.allowed_by().collect::<Vec<_>>(),
+        vec![sales_id.document_id()]
+    );
+    assert_eq!(
+        explanation
+            .grantees
+            .iter()
+            .map(|grantee| grantee.account_id)
+            .collect::<Vec<_>>(),
+        vec![jane_id.document_id(), sales_id.document_id()]
+    );
+    assert_eq!(
+        explanation.grantees[0].grant.as_ref().unwrap().grants,
+        grants
+    );
+    assert!(explanation.grantees[0].matched.is_empty());
+    assert!(explanation.held.contains(Acl::ReadItems) && explanation.held.contains(Acl::Modify));
+    let explanation = server
+        .explain_access_to_document(
+            &john_token,
+            bill_id.document_id(),
+            Collection::Mailbox,
+            group_shared_id,
+            Acl::Modify,
+        )
+        .await
+        .unwrap();
+    assert!(!explanation.allowed);
+    assert!(explanation
+        .grantees
+        .iter()
+        .all(|grantee| grantee.grant.is_none()));
+
+    // Members of the account are allowed without a grant
+    let explanation = server
+        .explain_access_to_document(
+            &bill_token,
+            bill_id.document_id(),
+            Collection::Mailbox,
+            group_shared_id,
+            Acl::Modify,
+        )
+        .await
+        .unwrap();
+    assert!(explanation.allowed);
+    assert_eq!(
+        explanation.allowed_by().collect::<Vec<_>>(),
+        vec![bill_id.document_id()]
+    );
+
+    // Administrators can list every grant on a document
+    let listed = server
+        .list_document_acls(bill_id.document_id(), Collection::Mailbox, group_shared_id)
+        .await
+        .unwrap();
+    assert!(listed
+        .iter()
+        .any(|grant| grant.account_id == jane_id.document_id() && grant.grants == grants));
+    assert!(listed
+        .iter()
+        .any(|grant| grant.account_id == sales_id.document_id()
+            && grant.grants.contains(Acl::Modify)));
+    assert!(server
+        .list_document_acls(bill_id.document_id(), Collection::Mailbox, u32::MAX - 1)
+        .await
+        .unwrap()
+        .is_empty());
+
+    // Jane's own grant takes precedence over the grant to her group when
+    // evaluating by priority, while the rights are added up otherwise
+    let mailbox = server
+        .get_property::<Object<Value>>(
+            bill_id.document_id(),
+            Collection::Mailbox,
+            group_shared_id,
+            jmap_proto::types::property::Property::Value,
+        )
+        .await
+        .unwrap()
+        .unwrap();
+    let union_acl = mailbox.effective_acl(&jane_token, bill_id.document_id(), AclEvaluation::Union);
+    assert!(union_acl.contains(Acl::ReadItems) && union_acl.contains(Acl::Modify));
+    assert_eq!(
+        mailbox.effective_acl(&jane_token, bill_id.document_id(), AclEvaluation::Priority),
+        grants
+    );
+    let sales_token = server
+        .get_access_token(sales_id.document_id())
+        .await
+        .unwrap();
+    for evaluation in [AclEvaluation::Union, AclEvaluation::Priority] {
+        assert_eq!(
+            mailbox.effective_acl(&sales_token, bill_id.document_id(), evaluation),
+            Bitmap::from_iter([Acl::Modify])
+        );
+        assert_eq!(
+            mailbox.effective_acl(&john_token, bill_id.document_id(), evaluation),
+            Bitmap::new()
+        );
+        assert!(mailbox
+            .effective_acl(&bill_token, bill_id.document_id(), evaluation)
+            .contains(Acl::Owner));
+    }
+
+    // Document access checks and shared documents follow the same precedence
+    for evaluation in [AclEvaluation::Union, AclEvaluation::Priority] {
+        let mut core = server.inner.shared_core.load_full().as_ref().clone();
+        core.jmap.acl_evaluation = evaluation;
+        server.inner.shared_core.store(core.into());
+        let server = server.inner.build_server();
+        let is_union = evaluation == AclEvaluation::Union;
+
+        assert_eq!(
+            server
+                .has_access_to_document(
+                    &jane_token,
+                    bill_id.document_id(),
+                    Collection::Mailbox,
+                    group_shared_id,
+                    Acl::Modify,
+                )
+                .await
+                .unwrap(),
+            is_union
+        );
+        assert_eq!(
+            server
+                .shared_documents(
+                    &jane_token,
+                    bill_id.document_id(),
+                    Collection::Mailbox,
+                    Acl::Modify,
+                )
+                .await
+                .unwrap()
+                .contains(group_shared_id),
+            is_union
+        );
+        assert!(server
+            .has_access_to_document(
+                &jane_token,
+                bill_id.document_id(),
+                Collection::Mailbox,
+                group_shared_id,
+                Acl::ReadItems,
+            )
+            .await
+            .unwrap());
+    }
+    let mut core = server.inner.shared_core.load_full().as_ref().clone();
+    core.jmap.acl_evaluation = AclEvaluation::Union;
+    server.inner.shared_core.store(core.into());
+
+    // Shared mailboxes are cached until an ACL change invalidates them
+    let cache_id = SharedAclId {
+        access_id: jane_token.primary_id,
+        revision: jane_token.revision,
+        account_id: bill_id.document_id(),
+        collection: Collection::Mailbox.into(),
+        acls: Bitmap::from(Acl::Read).bitmap,
+    };
+    let shared_ids = server
+        .shared_documents(
+            &jane_token,
+            bill_id.document_id(),
+            Collection::Mailbox,
+            Acl::Read,
+        )
+        .await
+        .unwrap();
+    assert!(shared_ids.is_superset(&legal_ids));
+    let mut cached = server
+        .inner
+        .cache
+        .shared_acls
+        .get(&cache_id)
+        .expect("shared mailboxes were not cached")
+        .as_ref()
+        .clone();
+    assert_eq!(cached.document_ids, shared_ids);
+    cached.document_ids.insert(u32::MAX - 1);
+    server
+        .inner
+        .cache
+        .shared_acls
+        .insert(cache_id, Arc::new(cached));
+    assert!(server
+        .shared_documents(
+            &jane_token,
+            bill_id.document_id(),
+            Collection::Mailbox,
+            Acl::Read,
+        )
+        .await
+        .unwrap()
+        .contains(u32::MAX - 1));
+    let revoked_id = legal_ids.max().unwrap();
+    let response = jmap_json_request(
+        format!(
+            r#"[["Mailbox/set",{{"accountId":"{bill_id}","update":{{"{}":{{"acl/jane.smith@example.com":[]}}}}}},"0"]]"#,
+            Id::from(revoked_id)
+        ),
+        "bill@example.com",
+        "098765",
+    )
+    .await;
+    assert!(
+        response["methodResponses"][0][1]["updated"]
+            .as_object()
+            .is_some_and(|updated| updated.len() == 1),
+        "unexpected response: {response}"
+    );
+    assert!(server.inner.cache.shared_acls.get(&cache_id).is_none());
+    let jane_token = server
+        .get_access_token(jane_id.document_id())
+        .await
+        .unwrap();
+    let shared_ids = server
+        .shared_documents(
+            &jane_token,
+            bill_id.document_id(),
+            Collection::Mailbox,
+            Acl::Read,
+        )
+        .await
+        .unwrap();
+    assert!(!shared_ids.contains(u32::MAX - 1));
+    assert!(!shared_ids.contains(revoked_id));
+
+    // Grants marked as inherited apply to the child mailboxes without a grant
+    // of their own to the same principal
+    let mut tree_ids: Vec<String> = Vec::new();
+    for (name, parent) in [
+        ("Projects", None),
+        ("Current", Some(0)),
+        ("Archive", Some(0)),
+        ("2023", Some(2)),
+        ("Reports", None),
+        ("Weekly", Some(4)),
+    ] {
+        let parent_id = parent.map(|parent: usize| tree_ids[parent].clone());
+        tree_ids.push(
+            bill_client
+                .set_default_account_id(bill_id.to_string())
+                .mailbox_create(name, parent_id, Role::None)
+                .await
+                .unwrap()
+                .take_id(),
+        );
+    }
+    let tree_document_ids = tree_ids
+        .iter()
+        .map(|id| Id::from_bytes(id.as_bytes()).unwrap().document_id())
+        .collect::<Vec<_>>();
+    for (mailbox, grants) in [
+        (0, vec![Acl::Read, Acl::ReadItems, Acl::Inherit]),
+        (2, vec![Acl::Lookup]),
+        (4, vec![Acl::Read, Acl::ReadItems]),
+    ] {
+        server
+            .grant_to_documents(
+                &bill_token,
+                bill_id.document_id(),
+                Collection::Mailbox,
+                &RoaringBitmap::from_iter([tree_document_ids[mailbox]]),
+                jane_id.document_id(),
+                Bitmap::from_iter(grants),
+            )
+            .await
+            .unwrap();
+    }
+    let jane_token = server
+        .get_access_token(jane_id.document_id())
+        .await
+        .unwrap();
+    let shared_ids = server
+        .shared_documents(
+            &jane_token,
+            bill_id.document_id(),
+            Collection::Mailbox,
+            Acl::ReadItems,
+        )
+        .await
+        .unwrap();
+    for (mailbox, expected) in [
+        (0, true),
+        (1, true),
+        (2, false),
+        (3, false),
+        (4, true),
+        (5, false),
+    ] {
+        let document_id = tree_document_ids[mailbox];
+        assert_eq!(shared_ids.contains(document_id), expected, "{mailbox}");
+        assert_eq!(
+            server
+                .has_access_to_document(
+                    &jane_token,
+                    bill_id.document_id(),
+                    Collection::Mailbox,
+                    document_id,
+                    Acl::ReadItems,
+                )
+                .await
+                .unwrap(),
+            expected,
+            "{mailbox}"
+        );
+        let mailbox_object = server
+            .get_property::<Object<Value>>(
+                bill_id.document_id(),
+                Collection::Mailbox,
+                document_id,
+                jmap_proto::types::property::Property::Value,
+            )
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            server
+                .document_effective_acl(
+                    &jane_token,
+                    bill_id.document_id(),
+                    Collection::Mailbox,
+                    document_id,
+                    &mailbox_object,
+                )
+                .await
+                .unwrap()
+                .contains(Acl::ReadItems),
+            expected,
+            "{mailbox}"
+        );
+    }
+    for mailbox_id in tree_ids.iter().rev() {
+        bill_client.mailbox_destroy(mailbox_id, true).await.unwrap();
+    }
+
+    // Bill lets John manage the shares of one mailbox and fully administer another
+    let shares_document_id = legal_ids.min().unwrap();
+    let mut legal_ids = legal_ids.iter().map(Id::from);
+    let shares_id = legal_ids.next().unwrap().to_string();
+    let admin_id = legal_ids.next().unwrap().to_string();
+    for (mailbox_id, right) in [(&shares_id, "manageShares"), (&admin_id, "administer")] {
+        let response = jmap_json_request(
+            format!(
+                r#"[["Mailbox/set",{{"accountId":"{bill_id}","update":{{"{mailbox_id}":{{"acl/jdoe@example.com":["read","{right}"]}}}}}},"0"]]"#
+            ),
+            "bill@example.com",
+            "098765",
+        )
+        .await;
+        assert!(
+            response["methodResponses"][0][1]["updated"]
+                .as_object()
+                .is_some_and(|updated| updated.contains_key(mailbox_id)),
+            "unexpected response: {response}"
+        );
+    }
+
+    // Both rights allow John to read and edit the ACL, but only Administer
+    // allows granting administer rights
+    for (update, shares_allowed, admin_allowed) in [
+        (
+            r#""acl/jane.smith@example.com":["read","readItems","addItems"]"#,
+            true,
+            true,
+        ),
+        (
+            r#""acl/jane.smith@example.com":["read","administer"]"#,
+            false,
+            true,
+        ),
+        (r#""name":"Renamed by John""#, false, false),
+    ] {
+        for (mailbox_id, is_allowed) in [(&shares_id, shares_allowed), (&admin_id, admin_allowed)] {
+            let response = jmap_json_request(
+                format!(
+                    r#"[["Mailbox/set",{{"accountId":"{bill_id}","update":{{"{mailbox_id}":{{{update}}}}}}},"0"]]"#
+                ),
+                "jdoe@example.com",
+                "12345",
+            )
+            .await;
+            assert_eq!(
+                response["methodResponses"][0][1]["updated"]
+                    .as_object()
+                    .is_some_and(|updated| updated.contains_key(mailbox_id)),
+                is_allowed,
+                "unexpected response for {update}: {response}"
+            );
+        }
+    }
+
+    // Delegates cannot widen their own grant
+    let response = jmap_json_request(
+        format!(
+            r#"[["Mailbox/set",{{"accountId":"{bill_id}","update":{{"{shares_id}":{{"acl/jdoe@example.com":["read","manageShares","readItems"]}}}}}},"0"]]"#
+        ),
+        "jdoe@example.com",
+        "12345",
+    )
+    .await;
+    assert_eq!(
+        response["methodResponses"][0][1]["notUpdated"][&shares_id]["type"], "forbidden",
+        "unexpected response: {response}"
+    );
+
+    let acl = jmap_json_request(
+        format!(
+            r#"[["Mailbox/get",{{"accountId":"{bill_id}","ids":["{shares_id}"],"properties":["acl"]}},"0"]]"#
+        ),
+        "jdoe@example.com",
+        "12345",
+    )
+    .await;
+    assert_eq!(
+        acl["methodResponses"][0][1]["list"][0]["acl"]["jane.smith@example.com"],
+        serde_json::json!(["read", "readItems", "addItems"]),
+        "unexpected response: {acl}"
+    );
+
+    // Delegates cannot remove the last administer right, only the owner can
+    for (update, is_allowed) in [
+        (r#""acl/jane.smith@example.com":["read"]"#, true),
+        (r#""acl/jdoe@example.com":["read"]"#, false),
+        (r#""acl/jdoe@example.com":[]"#, false),
+    ] {
+        let response = jmap_json_request(
+            format!(
+                r#"[["Mailbox/set",{{"accountId":"{bill_id}","update":{{"{admin_id}":{{{update}}}}}}},"0"]]"#
+            ),
+            "jdoe@example.com",
+            "12345",
+        )
+        .await;
+        assert_eq!(
+            response["methodResponses"][0][1]["updated"]
+                .as_object()
+                .is_some_and(|updated| updated.contains_key(&admin_id)),
+            is_allowed,
+            "unexpected response for {update}: {response}"
+        );
+        if !is_allowed {
+            assert_eq!(
+                response["methodResponses"][0][1]["notUpdated"][&admin_id]["type"], "forbidden",
+                "unexpected response for {update}: {response}"
+            );
+        }
+    }
+
+    // Deleting a mailbox still requires full Administer rights
+    for (mailbox_id, is_allowed) in [(&shares_id, false), (&admin_id, true)] {
+        let response = jmap_json_request(
+            format!(
+                r#"[["Mailbox/set",{{"accountId":"{bill_id}","destroy":["{mailbox_id}"]}},"0"]]"#
+            ),
+            "jdoe@example.com",
+            "12345",
+        )
+        .await;
+        assert_eq!(
+            response["methodResponses"][0][1]["destroyed"]
+                .as_array()
+                .is_some_and(|destroyed| destroyed.iter().any(|id| id == mailbox_id.as_str())),
+            is_allowed,
+            "unexpected response: {response}"
+        );
+    }
+
+    // Removing John from the ACL also revokes the grants he created
+    let response = jmap_json_request(
+        format!(
+            r#"[["Mailbox/set",{{"accountId":"{bill_id}","update":{{"{shares_id}":{{"acl/jdoe@example.com":[]}}}}}},"0"]]"#
+        ),
+        "bill@example.com",
+        "098765",
+    )
+    .await;
+    assert!(
+        response["methodResponses"][0][1]["updated"]
+            .as_object()
+            .is_some_and(|updated| updated.contains_key(&shares_id)),
+        "unexpected response: {response}"
+    );
+    let acl = jmap_json_request(
+        format!(
+            r#"[["Mailbox/get",{{"accountId":"{bill_id}","ids":["{shares_id}"],"properties":["acl"]}},"0"]]"#
+        ),
+        "bill@example.com",
+        "098765",
+    )
+    .await;
+    assert_eq!(
+        acl["methodResponses"][0][1]["list"][0]["acl"],
+        serde_json::json!({}),
+        "unexpected response: {acl}"
+    );
+    let jane_token = server
+        .get_access_token(jane_id.document_id())
+        .await
+        .unwrap();
+    assert!(!server
+        .has_access_to_document(
+            &jane_token,
+            bill_id.document_id(),
+            Collection::Mailbox,
+            shares_document_id,
+            Acl::Read,
+        )
+        .await
+        .unwrap());
+
+    // Divergences between the stored grants and the ACL index are detected and repaired
+    assert_eq!(
+        server
+            .verify_acl_index(bill_id.document_id(), Collection::Mailbox, false)
+            .await
+            .unwrap(),
+        Vec::<u32>::new()
+    );
+    assert_eq!(
+        server
+            .grant_to_documents(
+                &bill_token,
+                bill_id.document_id(),
+                Collection::Mailbox,
+                &RoaringBitmap::from_iter([shares_document_id]),
+                jane_id.document_id(),
+                grants,
+            )
+            .await
+            .unwrap(),
+        1
+    );
+    let mut batch = BatchBuilder::new();
+    batch
+        .with_account_id(bill_id.document_id())
+        .with_collection(Collection::Mailbox)
+        .update_document(shares_document_id)
+        .clear(ValueClass::Acl(jane_id.document_id()))
+        .update_document(revoked_id)
+        .set(
+            ValueClass::Acl(john_id.document_id()),
+            grants.bitmap.to_be_bytes().to_vec(),
+        );
+    server.core.storage.data.write(batch.build()).await.unwrap();
+    assert!(!server
+        .has_access_to_document(
+            &jane_token,
+            bill_id.document_id(),
+            Collection::Mailbox,
+            shares_document_id,
+            Acl::Read,
+        )
+        .await
+        .unwrap());
+    for repair in [false, true] {
+        assert_eq!(
+            server
+                .verify_acl_index(bill_id.document_id(), Collection::Mailbox, repair)
+                .await
+                .unwrap(),
+            vec![shares_document_id, revoked_id]
+        );
+    }
+    assert_eq!(
+        server
+            .verify_acl_index(bill_id.document_id(), Collection::Mailbox, false)
+            .await
+            .unwrap(),
+        Vec::<u32>::new()
+    );
+    assert!(server
+        .has_access_to_document(
+            &jane_token,
+            bill_id.document_id(),
+            Collection::Mailbox,
+            shares_document_id,
+            Acl::Read,
+        )
+        .await
+        .unwrap());
+    assert!(!server
+        .has_access_to_document(
+            &john_token,
+            bill_id.document_id(),
+            Collection::Mailbox,
+            revoked_id,
+            Acl::Read,
+        )
+        .await
+        .unwrap());
+
+    // Principals listed more than once are merged or rejected depending on the policy
+    let revoked_id = Id::from(revoked_id).to_string();
+    for policy in [DuplicateGrantee::Merge, DuplicateGrantee::Reject] {
+        let mut core = server.inner.shared_core.load_full().as_ref().clone();
+        core.jmap.acl_duplicate_grantee = policy;
+        server.inner.shared_core.store(core.into());
+
+        let response = jmap_json_request(
+            format!(
+                r#"[["Mailbox/set",{{"accountId":"{bill_id}","update":{{"{revoked_id}":{{"acl":{{"jdoe@example.com":["read"],"jdoe@example.com":["readItems"]}}}}}}}},"0"]]"#
+            ),
+            "bill@example.com",
+            "098765",
+        )
+        .await;
+        let acl = jmap_json_request(
+            format!(
+                r#"[["Mailbox/get",{{"accountId":"{bill_id}","ids":["{revoked_id}"],"properties":["acl"]}},"0"]]"#
+            ),
+            "bill@example.com",
+            "098765",
+        )
+        .await;
+        if policy == DuplicateGrantee::Merge {
+            assert!(
+                response["methodResponses"][0][1]["updated"]
+                    .as_object()
+                    .is_some_and(|updated| updated.contains_key(&revoked_id)),
+                "unexpected response: {response}"
+            );
+            assert_eq!(
+                acl["methodResponses"][0][1]["list"][0]["acl"],
+                serde_json::json!({"jdoe@example.com": ["read", "readItems"]}),
+                "unexpected response: {acl}"
+            );
+        } else {
+            assert_eq!(
+                response["methodResponses"][0][1]["notUpdated"][&revoked_id]["type"],
+                "invalidProperties",
+                "unexpected response: {response}"
+            );
+        }
+    }
+    let mut core = server.inner.shared_core.load_full().as_ref().clone();
+    core.jmap.acl_duplicate_grantee = DuplicateGrantee::Merge;
+    server.inner.shared_core.store(core.into());
+
+    // Grants to deleted principals are listed under a placeholder name, which can
+    // be used to remove them
+    let stale_id = server
+        .core
+        .storage
+        .data
+        .create_test_user(
+            "stale@example.com",
+            "secret",
+            "Stale",
+            &["stale@example.com"],
+        )
+        .await;
+    let response = jmap_json_request(
+        format!(
+            r#"[["Mailbox/set",{{"accountId":"{bill_id}","update":{{"{revoked_id}":{{"acl/stale@example.com":["read"]}}}}}},"0"]]"#
+        ),
+        "bill@example.com",
+        "098765",
+    )
+    .await;
+    assert!(
+        response["methodResponses"][0][1]["updated"]
+            .as_object()
+            .is_some_and(|updated| updated.contains_key(&revoked_id)),
+        "unexpected response: {response}"
+    );
+    server
+        .core
+        .storage
+        .data
+        .delete_principal(QueryBy::Id(stale_id))
+        .await
+        .unwrap();
+    assert!(server
+        .core
+        .storage
+        .data
+        .acl_query(AclQuery::GrantedTo {
+            grant_account_id: stale_id,
+        })
+        .await
+        .unwrap()
+        .is_empty());
+
+    // and are not recreated when the ACL index is repaired
+    assert_eq!(
+        server
+            .verify_acl_index(bill_id.document_id(), Collection::Mailbox, true)
+            .await
+            .unwrap(),
+        Vec::<u32>::new()
+    );
+    let stale_name = format!("deleted:{stale_id}");
+    let acl = jmap_json_request(
+        format!(
+            r#"[["Mailbox/get",{{"accountId":"{bill_id}","ids":["{revoked_id}"],"properties":["acl"]}},"0"]]"#
+        ),
+        "bill@example.com",
+        "098765",
+    )
+    .await;
+    assert_eq!(
+        acl["methodResponses"][0][1]["list"][0]["acl"][&stale_name],
+        serde_json::json!(["read"]),
+        "unexpected response: {acl}"
+    );
+    let response = jmap_json_request(
+        format!(
+            r#"[["Mailbox/set",{{"accountId":"{bill_id}","update":{{"{revoked_id}":{{"acl/{stale_name}":["read","readItems"]}}}}}},"0"]]"#
+        ),
+        "bill@example.com",
+        "098765",
+    )
+    .await;
+    assert_eq!(
+        response["methodResponses"][0][1]["notUpdated"][&revoked_id]["type"], "invalidProperties",
+        "unexpected response: {response}"
+    );
+
+    // Setting the whole ACL keeps the grant as it is, it cannot be changed
+    for (rights, is_updated) in [(r#"["read","readItems"]"#, false), (r#"["read"]"#, true)] {
+        let response = jmap_json_request(
+            format!(
+                r#"[["Mailbox/set",{{"accountId":"{bill_id}","update":{{"{revoked_id}":{{"acl":{{"jdoe@example.com":["read","readItems"],"{stale_name}":{rights}}}}}}}}},"0"]]"#
+            ),
+            "bill@example.com",
+            "098765",
+        )
+        .await;
+        assert_eq!(
+            response["methodResponses"][0][1]["updated"]
+                .as_object()
+                .is_some_and(|updated| updated.contains_key(&revoked_id)),
+            is_updated,
+            "unexpected response: {response}"
+        );
+    }
+    let acl = jmap_json_request(
+        format!(
+            r#"[["Mailbox/get",{{"accountId":"{bill_id}","ids":["{revoked_id}"],"properties":["acl"]}},"0"]]"#
+        ),
+        "bill@example.com",
+        "098765",
+    )
+    .await;
+    assert_eq!(
+        acl["methodResponses"][0][1]["list"][0]["acl"][&stale_name],
+        serde_json::json!(["read"]),
+        "unexpected response: {acl}"
+    );
+
+    let response = jmap_json_request(
+        format!(
+            r#"[["Mailbox/set",{{"accountId":"{bill_id}","update":{{"{revoked_id}":{{"acl/{stale_name}":null}}}}}},"0"]]"#
+        ),
+        "bill@example.com",
+        "098765",
+    )
+    .await;
+    assert!(
+        response["methodResponses"][0][1]["updated"]
+            .as_object()
+            .is_some_and(|updated| updated.contains_key(&revoked_id)),
+        "unexpected response: {response}"
+    );
+    let acl = jmap_json_request(
+        format!(
+            r#"[["Mailbox/get",{{"accountId":"{bill_id}","ids":["{revoked_id}"],"properties":["acl"]}},"0"]]"#
+        ),
+        "bill@example.com",
+        "098765",
+    )
+    .await;
+    assert!(
+        acl["methodResponses"][0][1]["list"][0]["acl"][&stale_name].is_null(),
+        "unexpected response: {acl}"
+    );
+
+    // Grants to a group apply to the members of its nested groups
+    let managers_id = server
+        .core
+        .storage
+        .data
+        .create_test_group(
+            "managers@example.com",
+            "Managers Group",
+            &["managers@example.com"],
+        )
+        .await;
+    server
+        .core
+        .storage
+        .data
+        .create_test_group("leads@example.com", "Leads Group", &["leads@example.com"])
+        .await;
+    for (name, group) in [
+        ("leads@example.com", "managers@example.com"),
+        ("bill@example.com", "leads@example.com"),
+    ] {
+        server
+            .increment_token_revision(server.core.storage.data.add_to_group(name, group).await)
+            .await;
+    }
+    for rights in [r#"["read","readItems"]"#, "null"] {
+        let response = jmap_json_request(
+            format!(
+                r#"[["Mailbox/set",{{"accountId":"{jane_id}","update":{{"{inbox_id}":{{"acl/managers@example.com":{rights}}}}}}},"0"]]"#
+            ),
+            "jane.smith@example.com",
+            "abcde",
+        )
+        .await;
+        assert!(
+            response["methodResponses"][0][1]["updated"]
+                .as_object()
+                .is_some_and(|updated| updated.contains_key(&inbox_id)),
+            "unexpected response: {response}"
+        );
+        let bill_token = server
+            .get_access_token(bill_id.document_id())
+            .await
+            .unwrap();
+        // Members of nested groups are not members of the parent group
+        assert!(bill_token.grantee_ids().any(|id| id == managers_id));
+        assert!(!bill_token.member_of.contains(&managers_id));
+        assert!(!bill_token.is_member(managers_id));
+        assert_eq!(
+            server
+                .has_access_to_document(
+                    &bill_token,
+                    jane_id.document_id(),
+                    Collection::Mailbox,
+                    INBOX_ID,
+                    Acl::ReadItems,
+                )
+                .await
+                .unwrap(),
+            rights != "null"
+        );
+    }
+
+    // Grants follow principals recreated under the name of a deleted principal
+    let previous_id = server
+        .core
+        .storage
+        .data
+        .create_test_user(
+            "moved@example.com",
+            "secret",
+            "moved@example.com",
+            &["moved@example.com"],
+        )
+        .await;
+    let response = jmap_json_request(
+        format!(
+            r#"[["Mailbox/set",{{"accountId":"{jane_id}","update":{{"{inbox_id}":{{"acl/moved@example.com":["read","readItems"]}}}}}},"0"]]"#
+        ),
+        "jane.smith@example.com",
+        "abcde",
+    )
+    .await;
+    assert!(
+        response["methodResponses"][0][1]["updated"]
+            .as_object()
+            .is_some_and(|updated| updated.contains_key(&inbox_id)),
+        "unexpected response: {response}"
+    );
+    server
+        .core
+        .storage
+        .data
+        .delete_principal(QueryBy::Id(previous_id))
+        .await
+        .unwrap();
+    let created = server
+        .core
+        .storage
+        .data
+        .create_principal(
+            Principal::new(0, Type::Individual)
+                .with_field(PrincipalField::Name, "moved@example.com".to_string()),
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+    let principal_id = created.id;
+    assert_ne!(previous_id, principal_id);
+    assert_eq!(
+        created.changed_principals.id_changes().collect::<Vec<_>>(),
+        vec![(previous_id, principal_id)]
+    );
+    server
+        .apply_principal_changes(created.changed_principals)
+        .await;
+    for (grant_account_id, expected) in [(previous_id, vec![]), (principal_id, vec![INBOX_ID])] {
+        assert_eq!(
+            server
+                .core
+                .storage
+                .data
+                .acl_query(AclQuery::GrantedTo { grant_account_id })
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|item| item.to_document_id)
+                .collect::<Vec<_>>(),
+            expected
+        );
+    }
+    let acl = jmap_json_request(
+        format!(
+            r#"[["Mailbox/get",{{"accountId":"{jane_id}","ids":["{inbox_id}"],"properties":["acl"]}},"0"]]"#
+        ),
+        "jane.smith@example.com",
+        "abcde",
+    )
+    .await;
+    assert_eq!(
+        acl["methodResponses"][0][1]["list"][0]["acl"]["moved@example.com"],
+        serde_json::json!(["read", "readItems"]),
+        "unexpected response: {acl}"
+    );
+    let moved_token = server.get_access_token(principal_id).await.unwrap();
+    assert!(server
+        .has_access_to_document(
+            &moved_token,
+            jane_id.document_id(),
+            Collection::Mailbox,
+            INBOX_ID,
+            Acl::ReadItems,
+        )
+        .await
+        .unwrap());
+    server
+        .core
+        .storage
+        .data
+        .delete_principal(QueryBy::Id(principal_id))
+        .await
+        .unwrap();
+
     // Destroy test account data
     for id in [john_id, bill_id, jane_id, sales_id] {
         params.client.set_default_account_id(id.to_string());
@@ -4,12 +4,27 @@
  * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
  */
 
+use std::sync::{Arc, Mutex};
+
 use ahash::AHashMap;
+use futures::TryStreamExt;
 use store::{
+    dispatch::{
+        blob::{BlobHint, EncodedBlob},
+        manifest::ManifestEntry,
+        pipeline::{BlobChecksum, BlobPipeline, BlobTransform},
+        stats::compression_stats,
+    },
     write::{blob::BlobQuota, now, BatchBuilder, BlobOp},
-    BlobClass, BlobStore, Serialize, Stores,
+    BlobClass, BlobQuotaMode, BlobStore, CompressionAlgo, Serialize, Stores,
+};
+use tokio::io::AsyncReadExt;
+use trc::{Collector, MetricType};
+use utils::{
+    codec::base32_custom::Base32Writer,
+    config::{utils::ParseValue, Config},
+    BlobHash,
 };
-use utils::{config::Config, BlobHash};
 
 use crate::store::{TempDir, CONFIG};
 
@@ -399,81 +414,2250 @@ pub async fn blob_tests() {
                     ^ ct
             );
         }
-    }
-    temp_dir.delete();
-}
-
-async fn test_store(store: BlobStore) {
-    // Test small blob
-    const DATA: &[u8] = b"Lorem ipsum dolor sit amet, consectetur adipiscing elit. Fusce erat nisl, dignissim a porttitor id, varius nec arcu. Sed mauris.";
-    let hash = BlobHash::from(DATA);
 
-    store.put_blob(hash.as_slice(), DATA).await.unwrap();
-    assert_eq!(
-        String::from_utf8(
+        // Quota usage of compressed blobs depends on the accounting mode
+        let compressed_store = blob_store
+            .clone()
+            .with_pipeline(BlobPipeline::new().with_compression(CompressionAlgo::Lz4));
+        let data = b"compressible ".repeat(1000);
+        let hash = BlobHash::from(data.as_slice());
+        // LZ4 output followed by the marker of the stage
+        let compressed_size = CompressionAlgo::Lz4.encode(&data).unwrap().len() + 1;
+        assert!(compressed_size < data.len());
+        let prepared = compressed_store
+            .prepare_blob(&data, BlobHint::Other)
+            .unwrap();
+        let sizes = [
+            (BlobQuotaMode::Logical, data.len()),
+            (BlobQuotaMode::Physical, compressed_size),
+        ]
+        .map(|(mode, expected_size)| (mode, expected_size, prepared.quota_size(mode)));
+        compressed_store
+            .put_prepared_blob(hash.as_ref(), prepared)
+            .await
+            .unwrap();
+        assert_eq!(
             store
-                .get_blob(hash.as_slice(), 0..usize::MAX)
+                .read_blob(hash.as_ref(), 0..usize::MAX)
                 .await
                 .unwrap()
                 .unwrap()
-        )
-        .unwrap(),
-        std::str::from_utf8(DATA).unwrap()
-    );
-    assert_eq!(
-        String::from_utf8(
+                .len(),
+            compressed_size
+        );
+        for (mode, expected_size, size) in sizes {
+            assert_eq!(size, expected_size, "{mode:?}");
+            let reserve = BlobOp::Reserve {
+                until: now() + 10,
+                hash: hash.clone(),
+            };
             store
-                .get_blob(hash.as_slice(), 11..57)
+                .write(
+                    BatchBuilder::new()
+                        .with_account_id(3)
+                        .set(reserve.clone(), (size as u32).serialize())
+                        .build_batch(),
+                )
                 .await
+                .unwrap();
+            assert_eq!(
+                store.blob_quota(3).await.unwrap(),
+                BlobQuota {
+                    bytes: expected_size,
+                    count: 1
+                },
+                "{mode:?}"
+            );
+            store
+                .write(
+                    BatchBuilder::new()
+                        .with_account_id(3)
+                        .clear(reserve)
+                        .build_batch(),
+                )
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            blob_store
+                .clone()
+                .with_pipeline(BlobPipeline::new())
+                .prepare_blob(&data, BlobHint::Other)
                 .unwrap()
-                .unwrap()
-        )
-        .unwrap(),
-        std::str::from_utf8(&DATA[11..57]).unwrap()
+                .quota_size(BlobQuotaMode::Physical),
+            data.len()
+        );
+        compressed_store.delete_blob(hash.as_ref()).await.unwrap();
+    }
+    temp_dir.delete();
+}
+
+#[tokio::test]
+pub async fn blob_concurrency_tests() {
+    let temp_dir = TempDir::new("blob_concurrency_tests", true);
+    let mut config = Config::new(
+        r#"
+[store."fs"]
+type = "fs"
+path = "{TMP}/fs"
+
+[store."sqlite"]
+type = "sqlite"
+path = "{TMP}/sqlite.db"
+
+[store."tiered"]
+type = "tiered-blob"
+fast = "sqlite"
+slow = "fs"
+
+[store."sharded"]
+type = "sharded-blob"
+stores = ["fs"]
+
+[storage.blob-concurrency]
+fs = 1
+store = 2
+"#
+        .replace("{TMP}", temp_dir.path.as_path().to_str().unwrap()),
+    )
+    .unwrap();
+    let stores = Stores::parse_all(&mut config, false).await;
+    let fs_store = stores.blob_stores.get("fs").unwrap().clone();
+    let db_store = stores.blob_stores.get("sqlite").unwrap().clone();
+    let tiered_store = stores.blob_stores.get("tiered").unwrap().clone();
+    let sharded_store = stores.blob_stores.get("sharded").unwrap().clone();
+    let fs_limit = fs_store.concurrency.clone().unwrap();
+    let db_limit = db_store.concurrency.clone().unwrap();
+    assert_eq!(fs_limit.available_permits(), 1);
+    assert_eq!(db_limit.available_permits(), 2);
+
+    // Exhaust the filesystem limit, store-backed blobs are not affected
+    let permit = fs_limit.acquire().await.unwrap();
+    let timeout = std::time::Duration::from_millis(200);
+    assert!(
+        tokio::time::timeout(timeout, fs_store.put_blob(b"fs-blob", b"data"))
+            .await
+            .is_err(),
+        "filesystem limit was not enforced"
     );
-    assert!(store.delete_blob(hash.as_slice()).await.unwrap());
+    tokio::time::timeout(timeout, db_store.put_blob(b"db-blob", b"data"))
+        .await
+        .expect("store-backed blob limit shared with the filesystem")
+        .unwrap();
+
+    // Composite stores are limited by the stores they are built on
+    assert!(tiered_store.concurrency.is_none());
+    assert!(sharded_store.concurrency.is_none());
+    assert!(
+        tokio::time::timeout(timeout, sharded_store.put_blob(b"sharded-blob", b"data"))
+            .await
+            .is_err(),
+        "filesystem limit was not enforced on the shard"
+    );
+    assert!(
+        tokio::time::timeout(timeout, tiered_store.put_blob(b"tiered-blob", b"data"))
+            .await
+            .is_err(),
+        "filesystem limit was not enforced on the slow tier"
+    );
+    assert!(
+        tokio::time::timeout(timeout, tiered_store.get_blob(b"missing", 0..usize::MAX))
+            .await
+            .is_err(),
+        "filesystem limit was not enforced on the slow tier"
+    );
+
+    // Exhaust the store-backed limit
+    let permits = db_limit.acquire_many(2).await.unwrap();
+    assert!(
+        tokio::time::timeout(timeout, db_store.get_blob(b"db-blob", 0..usize::MAX))
+            .await
+            .is_err()
+    );
+    drop(permit);
+    tokio::time::timeout(timeout, fs_store.put_blob(b"fs-blob", b"data"))
+        .await
+        .expect("filesystem limit shared with store-backed blobs")
+        .unwrap();
+    assert!(
+        tokio::time::timeout(timeout, tiered_store.put_blob(b"tiered-blob", b"data"))
+            .await
+            .is_err(),
+        "store-backed limit was not enforced on the fast tier"
+    );
+    drop(permits);
+    sharded_store
+        .put_blob(b"sharded-blob", b"data")
+        .await
+        .unwrap();
+    assert_eq!(
+        fs_store
+            .get_blob(b"sharded-blob", 0..usize::MAX)
+            .await
+            .unwrap()
+            .as_deref(),
+        Some(b"data".as_slice())
+    );
+    tiered_store
+        .put_blob(b"tiered-blob", b"data")
+        .await
+        .unwrap();
+    assert_eq!(
+        db_store
+            .get_blob(b"tiered-blob", 0..usize::MAX)
+            .await
+            .unwrap()
+            .as_deref(),
+        Some(b"data".as_slice())
+    );
+    assert_eq!(
+        db_store
+            .get_blob(b"db-blob", 0..usize::MAX)
+            .await
+            .unwrap()
+            .as_deref(),
+        Some(b"data".as_slice())
+    );
+    assert_eq!(fs_limit.available_permits(), 1);
+    assert_eq!(db_limit.available_permits(), 2);
+
+    temp_dir.delete();
+}
+
+#[tokio::test]
+pub async fn compression_advisor_tests() {
+    let temp_dir = TempDir::new("compression_advisor_tests", true);
+    let mut config = Config::new(
+        r#"
+[store."sqlite"]
+type = "sqlite"
+path = "{TMP}/sqlite.db"
+compression = "lz4"
+"#
+        .replace("{TMP}", temp_dir.path.as_path().to_str().unwrap()),
+    )
+    .unwrap();
+    let stores = Stores::parse_all(&mut config, false).await;
+    let store = stores.stores.get("sqlite").unwrap().clone();
+    let blob_store = stores.blob_stores.get("sqlite").unwrap().clone();
+
+    // Random bytes don't compress, text drawn from a small vocabulary favours
+    // entropy coding and repeated text compresses well with any algorithm
+    let mut seed = 0x2545_f491_4f6c_dd1du64;
+    let mut next_random = move || {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        seed
+    };
+    let words = [
+        "mail", "server", "blob", "store", "index", "query", "message", "folder", "account",
+        "quota", "filter", "thread", "header", "domain", "report", "queue",
+    ];
+    for document_id in 0..5u32 {
+        let random = (0..4096).map(|_| next_random() as u8).collect::<Vec<_>>();
+        let text = (0..4096)
+            .map(|_| words[next_random() as usize % words.len()])
+            .collect::<Vec<_>>()
+            .join(" ")
+            .into_bytes();
+        let repeated = format!("document {document_id} ").repeat(1000).into_bytes();
+
+        let mut batch = BatchBuilder::new();
+        batch.with_account_id(1);
+        for (collection, data) in [(0u8, random), (1, text), (2, repeated)] {
+            let hash = BlobHash::from(data.as_slice());
+            blob_store.put_blob(hash.as_slice(), &data).await.unwrap();
+            batch
+                .with_collection(collection)
+                .update_document(document_id)
+                .set(BlobOp::Link { hash }, Vec::new());
+        }
+        store.write(batch.build()).await.unwrap();
+    }
+
+    let reports = blob_store.compression_report(&store, 3).await.unwrap();
+    assert_eq!(
+        reports
+            .iter()
+            .map(|report| (report.collection, report.samples))
+            .collect::<Vec<_>>(),
+        vec![(0, 3), (1, 3), (2, 3)]
+    );
+    for (report, expected) in reports.iter().zip([
+        CompressionAlgo::None,
+        CompressionAlgo::zstd(),
+        CompressionAlgo::Lz4,
+    ]) {
+        assert_eq!(report.recommendation, expected, "{report:?}");
+
+        // The recommendation is never beaten by a significant margin
+        let best = report
+            .estimates
+            .iter()
+            .map(|(algorithm, _)| report.estimated_ratio(*algorithm).unwrap())
+            .fold(f64::MAX, f64::min);
+        assert!(
+            report.estimated_ratio(expected).unwrap() - best < 0.05,
+            "{report:?}"
+        );
+    }
+
+    // Achieved ratios reflect the configured algorithm, whose output is
+    // followed by a one byte marker
+    for report in &reports {
+        assert_eq!(
+            report.stored_size,
+            report.estimates[1].1 + report.samples,
+            "{report:?}"
+        );
+    }
+    assert!(reports[0].ratio() > 1.0);
+    assert!(reports[2].ratio() < 0.05);
+
+    temp_dir.delete();
+}
+
+#[tokio::test]
+pub async fn blob_pipeline_tests() {
+    let temp_dir = TempDir::new("blob_pipeline_tests", true);
+    let mut config = Config::new(
+        r#"
+[store."fs"]
+type = "fs"
+path = "{TMP}"
+"#
+        .replace("{TMP}", temp_dir.path.as_path().to_str().unwrap()),
+    )
+    .unwrap();
+    let raw_store = Stores::parse_all(&mut config, false)
+        .await
+        .blob_stores
+        .remove("fs")
+        .unwrap();
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let store = raw_store.clone().with_pipeline(
+        BlobPipeline::new()
+            .with_compression(CompressionAlgo::Lz4)
+            .with_stage(XorStage { log: log.clone() })
+            .with_stage(BlobChecksum),
+    );
+
+    // Round trip through compression, the test stage and the checksum
+    let data = b"pipeline test ".repeat(500);
+    let stats = compression_stats();
+    store.put_blob(b"pipeline", &data).await.unwrap();
+    assert_eq!(
+        store
+            .get_blob(b"pipeline", 0..usize::MAX)
+            .await
+            .unwrap()
+            .unwrap(),
+        data
+    );
+    assert_eq!(
+        store.get_blob(b"pipeline", 14..28).await.unwrap().unwrap(),
+        b"pipeline test "
+    );
+    assert_eq!(
+        log.lock().unwrap().drain(..).collect::<Vec<_>>(),
+        vec!["encode", "decode", "decode"]
+    );
+
+    // All ranges are served from a single decode
+    assert_eq!(
+        store
+            .get_blob_ranges(b"pipeline", &[0..8, 14..28, 6995..usize::MAX])
+            .await
+            .unwrap()
+            .unwrap(),
+        vec![
+            b"pipeline".to_vec(),
+            b"pipeline test ".to_vec(),
+            b"test ".to_vec()
+        ]
+    );
+    assert_eq!(
+        log.lock().unwrap().drain(..).collect::<Vec<_>>(),
+        vec!["decode"]
+    );
+
+    // The stored blob carries the markers of each stage, last stage outermost
+    let raw = raw_store
+        .read_blob(b"pipeline", 0..usize::MAX)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(raw.last(), Some(&(0xa0 | 0x03)));
+    let (inner, trailer) = raw.split_at(raw.len() - 10);
+    assert_eq!(trailer[0], XOR_MARKER);
+    let compressed = inner.iter().map(|b| b ^ XOR_MARKER).collect::<Vec<_>>();
+    assert_eq!(compressed.last(), Some(&CompressionAlgo::Lz4.marker()));
+    assert!(compressed.len() < data.len());
+
+    // Compression statistics count the blob under its algorithm, other tests
+    // write blobs concurrently so only lower bounds can be checked
+    let (before, after) = (
+        stats.algorithm(CompressionAlgo::Lz4),
+        compression_stats().algorithm(CompressionAlgo::Lz4),
+    );
+    assert!(after.blobs > before.blobs);
+    assert!(after.uncompressed_bytes - before.uncompressed_bytes >= data.len() as u64);
+    assert!(after.stored_bytes - before.stored_bytes >= raw.len() as u64);
+    assert!(after.savings() > 0.0);
+    assert!(Collector::read_metric(MetricType::BlobUncompressedSize) >= data.len() as f64);
+
+    // Tampering is detected by the checksum
+    let mut tampered = raw.clone();
+    tampered[0] ^= 0xff;
+    raw_store.delete_blob(b"pipeline").await.unwrap();
+    raw_store.put_blob(b"pipeline", &tampered).await.unwrap();
     assert!(store
-        .get_blob(hash.as_slice(), 0..usize::MAX)
+        .get_blob(b"pipeline", 0..usize::MAX)
+        .await
+        .unwrap_err()
+        .matches(trc::EventType::Store(trc::StoreEvent::BlobChecksumMismatch)));
+    assert!(log.lock().unwrap().is_empty());
+
+    // Blobs written before the pipeline was configured are returned as-is
+    raw_store
+        .put_blob(b"pipeline", b"legacy blob")
+        .await
+        .unwrap();
+    assert_eq!(
+        store
+            .get_blob(b"pipeline", 0..usize::MAX)
+            .await
+            .unwrap()
+            .unwrap(),
+        b"legacy blob"
+    );
+    assert!(store.delete_blob(b"pipeline").await.unwrap());
+
+    // Blobs compressed with a content coding can be forwarded without
+    // decompressing them
+    let zstd_store = store.clone().with_compression(CompressionAlgo::Zstd(3));
+    zstd_store.put_blob(b"encoded", &data).await.unwrap();
+    let (algorithm, compressed) = match zstd_store
+        .get_blob_encoded(b"encoded", |algorithm| {
+            algorithm.content_encoding() == Some("zstd")
+        })
         .await
         .unwrap()
-        .is_none());
+        .unwrap()
+    {
+        EncodedBlob::Compressed { algorithm, data } => (algorithm, data),
+        _ => panic!("expected Zstandard compressed blob"),
+    };
+    assert!(matches!(algorithm, CompressionAlgo::Zstd(_)));
+    assert!(compressed.len() < data.len());
+    assert_eq!(algorithm.decode(&compressed).unwrap(), data);
+    log.lock().unwrap().clear();
+    match zstd_store
+        .get_blob_encoded(b"encoded", |_| false)
+        .await
+        .unwrap()
+        .unwrap()
+    {
+        EncodedBlob::Decoded(decoded) => assert_eq!(decoded, data),
+        _ => panic!("expected decoded blob"),
+    }
+    assert_eq!(
+        log.lock().unwrap().drain(..).collect::<Vec<_>>(),
+        vec!["decode"]
+    );
 
-    // Test large blob
-    let mut data = Vec::with_capacity(50 * 1024 * 1024);
-    while data.len() < 50 * 1024 * 1024 {
-        data.extend_from_slice(DATA);
-        let marker = format!(" [{}] ", data.len());
-        data.extend_from_slice(marker.as_bytes());
+    // LZ4 blobs are always decoded, their format has no content coding
+    store.put_blob(b"encoded-lz4", &data).await.unwrap();
+    match store
+        .get_blob_encoded(b"encoded-lz4", |_| true)
+        .await
+        .unwrap()
+        .unwrap()
+    {
+        EncodedBlob::Decoded(decoded) => assert_eq!(decoded, data),
+        _ => panic!("expected decoded blob"),
     }
-    let hash = BlobHash::from(&data);
-    store.put_blob(hash.as_slice(), &data).await.unwrap();
+    raw_store
+        .put_blob(b"encoded-legacy", b"legacy blob")
+        .await
+        .unwrap();
+    assert!(matches!(
+        store
+            .get_blob_encoded(b"encoded-legacy", |_| true)
+            .await
+            .unwrap(),
+        Some(EncodedBlob::Decoded(data)) if data == b"legacy blob"
+    ));
+    assert!(store
+        .get_blob_encoded(b"missing", |_| true)
+        .await
+        .unwrap()
+        .is_none());
+
+    // Switching to Zstandard keeps LZ4 blobs readable
     assert_eq!(
-        String::from_utf8(
-            store
-                .get_blob(hash.as_slice(), 0..usize::MAX)
+        CompressionAlgo::parse_value("zstd").unwrap(),
+        CompressionAlgo::Zstd(3)
+    );
+    raw_store
+        .clone()
+        .with_compression(CompressionAlgo::Lz4)
+        .put_blob(b"lz4", &data)
+        .await
+        .unwrap();
+    let zstd_store = raw_store
+        .clone()
+        .with_compression(CompressionAlgo::Zstd(19));
+    zstd_store.put_blob(b"zstd", &data).await.unwrap();
+    let raw = raw_store
+        .read_blob(b"zstd", 0..usize::MAX)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(raw.last(), Some(&(0xa0 | 0x02)));
+    assert!(raw.len() < data.len());
+    for key in [&b"zstd"[..], b"lz4"] {
+        assert_eq!(
+            zstd_store
+                .get_blob(key, 0..usize::MAX)
                 .await
                 .unwrap()
-                .unwrap()
-        )
-        .unwrap(),
-        std::str::from_utf8(&data).unwrap()
+                .unwrap(),
+            data
+        );
+        assert_eq!(
+            zstd_store.uncompressed_size(key).await.unwrap(),
+            Some(data.len() as u64)
+        );
+    }
+    assert_eq!(
+        zstd_store.blob_size(b"zstd").await.unwrap(),
+        Some(raw.len() as u64)
+    );
+    assert_eq!(
+        raw_store
+            .clone()
+            .with_compression(CompressionAlgo::Lz4)
+            .uncompressed_size(b"lz4")
+            .await
+            .unwrap(),
+        Some(data.len() as u64)
     );
 
+    // Parts of uploads are passed through the pipeline as they are read
+    let streamed_store = raw_store.clone().with_pipeline(
+        BlobPipeline::new()
+            .with_compression(CompressionAlgo::Zstd(3))
+            .with_stage(BlobChecksum),
+    );
+    for (part_number, part) in data.chunks(1000).enumerate() {
+        streamed_store
+            .put_blob_part(b"streamed", part_number as u32, part)
+            .await
+            .unwrap();
+    }
+    streamed_store
+        .finalize_blob_upload(b"streamed", b"streamed")
+        .await
+        .unwrap();
+    let raw = raw_store
+        .read_blob(b"streamed", 0..usize::MAX)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(raw.last(), Some(&(0xa0 | 0x03)));
+    assert!(raw.len() < data.len());
     assert_eq!(
-        String::from_utf8(
-            store
-                .get_blob(hash.as_slice(), 3000111..4000999)
+        streamed_store
+            .get_blob(b"streamed", 0..usize::MAX)
+            .await
+            .unwrap()
+            .unwrap(),
+        data
+    );
+
+    // Framed LZ4 decodes ranges from the blocks overlapping them
+    let framed_store = raw_store
+        .clone()
+        .with_compression(CompressionAlgo::Lz4Framed);
+    let large = (0..200_000u32)
+        .flat_map(|n| format!("{n} ").into_bytes())
+        .collect::<Vec<_>>();
+    framed_store.put_blob(b"framed", &large).await.unwrap();
+    let raw = raw_store
+        .read_blob(b"framed", 0..usize::MAX)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(raw.last(), Some(&(0xa0 | 0x04)));
+    assert!(raw.len() < large.len());
+    assert_eq!(
+        framed_store.blob_size(b"framed").await.unwrap(),
+        Some(raw.len() as u64)
+    );
+    assert_eq!(
+        framed_store.uncompressed_size(b"framed").await.unwrap(),
+        Some(large.len() as u64)
+    );
+    assert_eq!(
+        framed_store.uncompressed_size(b"missing").await.unwrap(),
+        None
+    );
+    assert_eq!(
+        framed_store
+            .get_blob(b"framed", 0..usize::MAX)
+            .await
+            .unwrap()
+            .unwrap(),
+        large
+    );
+    for range in [
+        0..4096,
+        65530..65540,
+        100_000..300_000,
+        large.len() - 10..large.len(),
+        5..5,
+    ] {
+        assert_eq!(
+            framed_store
+                .get_blob(b"framed", range.clone())
                 .await
                 .unwrap()
-                .unwrap()
-        )
-        .unwrap(),
-        std::str::from_utf8(&data[3000111..4000999]).unwrap()
-    );
-    assert!(store.delete_blob(hash.as_slice()).await.unwrap());
-    assert!(store
-        .get_blob(hash.as_slice(), 0..usize::MAX)
+                .unwrap(),
+            &large[range.clone()],
+            "{range:?}"
+        );
+    }
+
+    // Streamed reads decode framed blobs a few blocks at a time and other
+    // pipelines in full
+    for range in [0..usize::MAX, 65530..700_000, large.len() - 10..usize::MAX] {
+        let mut streamed = Vec::new();
+        framed_store
+            .get_blob_stream(b"framed", range.clone())
+            .await
+            .unwrap()
+            .unwrap()
+            .read_to_end(&mut streamed)
+            .await
+            .unwrap();
+        assert_eq!(
+            streamed,
+            &large[range.start..range.end.min(large.len())],
+            "{range:?}"
+        );
+    }
+    let mut streamed = Vec::new();
+    store
+        .get_blob_stream(b"encoded", 14..28)
+        .await
+        .unwrap()
+        .unwrap()
+        .read_to_end(&mut streamed)
+        .await
+        .unwrap();
+    assert_eq!(streamed, b"pipeline test ");
+    assert!(framed_store
+        .get_blob_stream(b"missing", 0..usize::MAX)
         .await
         .unwrap()
         .is_none());
+
+    // Legacy LZ4 blobs and uncompressed blobs resembling a frame are read in full
+    let mut fake_frame = b"LZ4B".to_vec();
+    fake_frame.extend_from_slice(&(64 * 1024u32).to_be_bytes());
+    fake_frame.extend_from_slice(&10u64.to_be_bytes());
+    fake_frame.extend_from_slice(&1u32.to_be_bytes());
+    fake_frame.extend_from_slice(b"not a frame");
+    raw_store
+        .put_blob(b"fake-frame", &fake_frame)
+        .await
+        .unwrap();
+    for (key, expected) in [(&b"lz4"[..], &data), (&b"fake-frame"[..], &fake_frame)] {
+        assert_eq!(
+            framed_store.get_blob(key, 2..8).await.unwrap().unwrap(),
+            &expected[2..8]
+        );
+    }
+
+    // Stores without compression decode blobs compressed before it was
+    // disabled, including when they would be memory-mapped
+    raw_store
+        .clone()
+        .with_compression(CompressionAlgo::Lz4)
+        .put_blob(b"legacy-lz4", &large)
+        .await
+        .unwrap();
+    assert_eq!(
+        raw_store
+            .get_blob(b"legacy-lz4", 0..usize::MAX)
+            .await
+            .unwrap(),
+        Some(large.clone())
+    );
+    assert_eq!(
+        raw_store.get_blob(b"legacy-lz4", 10..20).await.unwrap(),
+        Some(large[10..20].to_vec())
+    );
+    assert_eq!(
+        raw_store
+            .get_blob_view(b"legacy-lz4", 0..usize::MAX)
+            .await
+            .unwrap()
+            .unwrap()
+            .as_ref(),
+        large.as_slice()
+    );
+
+    // Unless legacy compression is turned off, then blobs are returned as they
+    // are stored
+    let plain_store = raw_store
+        .clone()
+        .with_pipeline(BlobPipeline::new().with_legacy_compression(false));
+    assert_eq!(
+        plain_store
+            .get_blob(b"legacy-lz4", 0..usize::MAX)
+            .await
+            .unwrap(),
+        raw_store
+            .read_blob(b"legacy-lz4", 0..usize::MAX)
+            .await
+            .unwrap()
+    );
+    assert!(raw_store.delete_blob(b"legacy-lz4").await.unwrap());
+
+    // Blobs are decoded from their marker regardless of the configured algorithm,
+    // including after compression is disabled
+    let mut marker_like = b"stored as is ".repeat(10);
+    marker_like.push(CompressionAlgo::Lz4.marker());
+    raw_store
+        .put_blob(b"marker-like", &marker_like)
+        .await
+        .unwrap();
+    let mut keys = vec![(&b"marker-like"[..], &marker_like)];
+    for (key, algorithm) in [
+        (&b"mixed-none"[..], CompressionAlgo::None),
+        (b"mixed-lz4", CompressionAlgo::Lz4),
+        (b"mixed-framed", CompressionAlgo::Lz4Framed),
+        (b"mixed-zstd", CompressionAlgo::zstd()),
+    ] {
+        raw_store
+            .clone()
+            .with_compression(algorithm)
+            .put_blob(key, &large)
+            .await
+            .unwrap();
+        keys.push((key, &large));
+    }
+    for algorithm in [
+        CompressionAlgo::None,
+        CompressionAlgo::Lz4,
+        CompressionAlgo::Lz4Framed,
+        CompressionAlgo::zstd(),
+    ] {
+        let reader = raw_store
+            .clone()
+            .with_pipeline(BlobPipeline::new().with_legacy_compression(true))
+            .with_compression(algorithm);
+        for (key, expected) in &keys {
+            // Uncompressed blobs ending with a marker are only read as they are
+            // when no compression is configured
+            if *key == b"marker-like" && algorithm != CompressionAlgo::None {
+                continue;
+            }
+            let expected = expected.as_slice();
+            let middle = expected.len() / 2;
+            let ranges = [
+                0..usize::MAX,
+                2..8,
+                middle..middle + 10,
+                expected.len() - 5..usize::MAX,
+            ];
+            for range in &ranges {
+                let expected = &expected[range.start..range.end.min(expected.len())];
+                assert_eq!(
+                    reader.get_blob(key, range.clone()).await.unwrap().unwrap(),
+                    expected,
+                    "{algorithm:?} {range:?}"
+                );
+                let mut streamed = Vec::new();
+                reader
+                    .get_blob_stream(key, range.clone())
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .read_to_end(&mut streamed)
+                    .await
+                    .unwrap();
+                assert_eq!(streamed, expected, "{algorithm:?} {range:?}");
+            }
+            assert_eq!(
+                reader.get_blob_ranges(key, &ranges).await.unwrap().unwrap(),
+                ranges
+                    .iter()
+                    .map(|range| expected[range.start..range.end.min(expected.len())].to_vec())
+                    .collect::<Vec<_>>(),
+                "{algorithm:?}"
+            );
+            assert_eq!(
+                reader.uncompressed_size(key).await.unwrap(),
+                Some(expected.len() as u64),
+                "{algorithm:?}"
+            );
+        }
+    }
+
+    temp_dir.delete();
+}
+
+#[tokio::test]
+pub async fn blob_deduplication_tests() {
+    let temp_dir = TempDir::new("blob_deduplication_tests", true);
+    let mut config = Config::new(
+        r#"
+[store."fs"]
+type = "fs"
+path = "{TMP}"
+deduplicate = true
+"#
+        .replace("{TMP}", temp_dir.path.as_path().to_str().unwrap()),
+    )
+    .unwrap();
+    let store = Stores::parse_all(&mut config, false)
+        .await
+        .blob_stores
+        .remove("fs")
+        .unwrap();
+    assert!(store.deduplicate);
+
+    // Blobs already stored under the hash of their contents are not written again
+    let data = b"forwarded attachment".to_vec();
+    let hash = BlobHash::from(data.as_slice());
+    store.put_blob(hash.as_slice(), &data).await.unwrap();
+    store
+        .put_blob(hash.as_slice(), b"other data")
+        .await
+        .unwrap();
+    assert_eq!(
+        store
+            .get_blob(hash.as_slice(), 0..usize::MAX)
+            .await
+            .unwrap(),
+        Some(data.clone())
+    );
+    store
+        .clone()
+        .with_deduplication(false)
+        .put_blob(hash.as_slice(), b"other data")
+        .await
+        .unwrap();
+    assert_eq!(
+        store
+            .get_blob(hash.as_slice(), 0..usize::MAX)
+            .await
+            .unwrap(),
+        Some(b"other data".to_vec())
+    );
+
+    // Other keys are always overwritten
+    store.put_blob(b"settings", b"first").await.unwrap();
+    store.put_blob(b"settings", b"second").await.unwrap();
+    assert_eq!(
+        store.get_blob(b"settings", 0..usize::MAX).await.unwrap(),
+        Some(b"second".to_vec())
+    );
+
+    temp_dir.delete();
+}
+
+#[tokio::test]
+pub async fn blob_prefix_delete_tests() {
+    let temp_dir = TempDir::new("blob_prefix_delete_tests", true);
+    let mut config = Config::new(
+        r#"
+[store."fs"]
+type = "fs"
+path = "{TMP}"
+depth = 2
+"#
+        .replace("{TMP}", temp_dir.path.as_path().to_str().unwrap()),
+    )
+    .unwrap();
+    let store = Stores::parse_all(&mut config, false)
+        .await
+        .blob_stores
+        .remove("fs")
+        .unwrap();
+    let keys: [&[u8]; 4] = [b"\x01\x02a", b"\x01\x02b", b"\x01\x03a", b"\x02\x02a"];
+    for key in keys {
+        store.put_blob(key, b"data").await.unwrap();
+    }
+
+    // Prefixes within the directory depth remove a whole directory, longer
+    // prefixes delete the listed blobs
+    assert_eq!(store.delete_blob_prefix(b"\x01\x02").await.unwrap(), 2);
+    assert_eq!(store.delete_blob_prefix(b"\x01\x03a").await.unwrap(), 1);
+    assert_eq!(store.delete_blob_prefix(b"\x01\x04").await.unwrap(), 0);
+    for (key, exists) in keys.into_iter().zip([false, false, false, true]) {
+        assert_eq!(
+            store.get_blob(key, 0..usize::MAX).await.unwrap().is_some(),
+            exists,
+            "{key:?}"
+        );
+    }
+
+    // Prefixes covering held blobs are rejected
+    store.hold_blob(b"\x02\x02a", b"case").await.unwrap();
+    for prefix in [&b""[..], b"\xff", b"\xffhold.data:"] {
+        assert!(
+            store.delete_blob_prefix(prefix).await.is_err(),
+            "{prefix:?}"
+        );
+    }
+    assert!(store.get_held_blob(b"case").await.unwrap().is_some());
+
+    temp_dir.delete();
+}
+
+#[tokio::test]
+pub async fn blob_key_prefix_tests() {
+    let temp_dir = TempDir::new("blob_key_prefix_tests", true);
+    let mut config = Config::new(
+        r#"
+[store."fs"]
+type = "fs"
+path = "{TMP}"
+depth = 2
+
+[store."fs-tenant"]
+type = "fs"
+path = "{TMP}"
+depth = 2
+key-prefix = "tenant-c"
+"#
+        .replace("{TMP}", temp_dir.path.as_path().to_str().unwrap()),
+    )
+    .unwrap();
+    let mut blob_stores = Stores::parse_all(&mut config, false).await.blob_stores;
+    let store = blob_stores.remove("fs").unwrap();
+    let tenant_c = blob_stores.remove("fs-tenant").unwrap();
+    let tenant_a = store.clone().with_key_prefix(b"tenant-a/");
+    let tenant_b = store.clone().with_key_prefix(b"tenant-b/");
+    assert!(store.clone().with_key_prefix(b"").key_prefix.is_none());
+
+    // Blobs stored under the same key by different tenants do not collide
+    for (tenant, data) in [(&tenant_a, b"data a"), (&tenant_b, b"data b")] {
+        tenant.put_blob(b"message", data).await.unwrap();
+    }
+    for (tenant, data) in [(&tenant_a, b"data a"), (&tenant_b, b"data b")] {
+        assert_eq!(
+            tenant.get_blob(b"message", 0..usize::MAX).await.unwrap(),
+            Some(data.to_vec())
+        );
+        assert!(tenant.blob_exists(b"message").await.unwrap());
+    }
+    assert!(!store.blob_exists(b"message").await.unwrap());
+    assert!(!store.blob_exists(b"tenant-a/message").await.unwrap());
+
+    // Each tenant has its own directory, fanned out by the keys it was given
+    let mut tenant_dir = vec![b"tenant-a/".len() as u8];
+    tenant_dir.extend_from_slice(b"tenant-a/");
+    assert!(temp_dir
+        .path
+        .join(Base32Writer::from_bytes(&tenant_dir).finalize())
+        .join(format!("{:x}", b'm'))
+        .join(format!("{:x}", b'e'))
+        .is_dir());
+
+    // Tenants whose prefix starts with the prefix of another tenant are isolated
+    let tenant = store.clone().with_key_prefix(b"tenant");
+    tenant.put_blob(b"-a/message", b"data").await.unwrap();
+    assert_eq!(
+        tenant_a.get_blob(b"message", 0..usize::MAX).await.unwrap(),
+        Some(b"data a".to_vec())
+    );
+    assert_eq!(tenant.delete_blob_prefix(b"-a/").await.unwrap(), 1);
+    assert!(tenant_a.blob_exists(b"message").await.unwrap());
+
+    // Key prefixes can be configured
+    tenant_c.put_blob(b"message", b"data c").await.unwrap();
+    assert_eq!(
+        store
+            .clone()
+            .with_key_prefix(b"tenant-c")
+            .get_blob(b"message", 0..usize::MAX)
+            .await
+            .unwrap(),
+        Some(b"data c".to_vec())
+    );
+    assert!(tenant_c.delete_blob(b"message").await.unwrap());
+
+    // Listing and prefix deletions only see the blobs of the tenant
+    assert_eq!(
+        tenant_b
+            .generate_manifest(b"")
+            .map_ok(|entry| entry.key)
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap(),
+        vec![b"message".to_vec()]
+    );
+    assert_eq!(tenant_a.delete_blob_prefix(b"mess").await.unwrap(), 1);
+    assert!(!tenant_a.blob_exists(b"message").await.unwrap());
+    assert!(tenant_b.blob_exists(b"message").await.unwrap());
+    assert!(tenant_b.delete_blob(b"message").await.unwrap());
+    assert!(!tenant_b.blob_exists(b"message").await.unwrap());
+
+    // Uploads in parts are assembled under the prefix
+    for (part_number, part) in [(1, &b"first "[..]), (2, b"second")] {
+        tenant_a
+            .put_blob_part(b"upload", part_number, part)
+            .await
+            .unwrap();
+    }
+    tenant_a
+        .finalize_blob_upload(b"upload", b"assembled")
+        .await
+        .unwrap();
+    assert_eq!(
+        tenant_a
+            .get_blob(b"assembled", 0..usize::MAX)
+            .await
+            .unwrap(),
+        Some(b"first second".to_vec())
+    );
+    assert!(!tenant_b.blob_exists(b"assembled").await.unwrap());
+
+    temp_dir.delete();
+}
+
+#[cfg(feature = "rocks")]
+#[tokio::test]
+pub async fn blob_batch_tests() {
+    let temp_dir = TempDir::new("blob_batch_tests", true);
+    let config = r#"
+[store."rocksdb"]
+type = "rocksdb"
+path = "{TMP}/rocksdb"
+blob.batch.max-size = 1024
+blob.batch.max-delay = "1h"
+"#
+    .replace("{TMP}", temp_dir.path.as_path().to_str().unwrap());
+    let mut stores = Stores::parse_all(&mut Config::new(&config).unwrap(), false).await;
+    let store = stores.blob_stores.remove("rocksdb").unwrap();
+
+    // Buffered blobs are visible before being written
+    store
+        .put_blob_buffered(b"buffered", b"small blob")
+        .await
+        .unwrap();
+    assert_eq!(
+        store.get_blob(b"buffered", 0..5).await.unwrap(),
+        Some(b"small".to_vec())
+    );
+    assert!(store.blob_exists(b"buffered").await.unwrap());
+    assert!(!store
+        .put_blob_if_absent(b"buffered", b"other blob")
+        .await
+        .unwrap());
+
+    // Deleted blobs are removed from the buffer
+    store
+        .put_blob_buffered(b"deleted", b"small blob")
+        .await
+        .unwrap();
+    assert!(store.delete_blob(b"deleted").await.unwrap());
+    assert!(!store.blob_exists(b"deleted").await.unwrap());
+
+    // Blobs written with put_blob are not buffered
+    store
+        .put_blob_buffered(b"direct", b"buffered blob")
+        .await
+        .unwrap();
+    store.put_blob(b"direct", b"direct blob").await.unwrap();
+
+    // Batches are written when flushed, once they reach the maximum size and
+    // when the store is dropped
+    store.flush().await.unwrap();
+    store
+        .put_blob_buffered(b"large", &[b'a'; 2048])
+        .await
+        .unwrap();
+    store
+        .put_blob_buffered(b"pending", b"small blob")
+        .await
+        .unwrap();
+    drop(store);
+    drop(stores);
+
+    let mut stores = Stores::parse_all(&mut Config::new(&config).unwrap(), false).await;
+    let store = stores.blob_stores.remove("rocksdb").unwrap();
+    for (key, exists) in [
+        (&b"buffered"[..], true),
+        (b"deleted", false),
+        (b"large", true),
+        (b"pending", true),
+    ] {
+        assert_eq!(store.blob_exists(key).await.unwrap(), exists, "{key:?}");
+    }
+    assert_eq!(
+        store.get_blob(b"direct", 0..usize::MAX).await.unwrap(),
+        Some(b"direct blob".to_vec())
+    );
+    assert_eq!(
+        store.get_blob(b"buffered", 0..usize::MAX).await.unwrap(),
+        Some(b"small blob".to_vec())
+    );
+    drop(store);
+    drop(stores);
+
+    temp_dir.delete();
+}
+
+#[tokio::test]
+pub async fn blob_checksum_tests() {
+    let temp_dir = TempDir::new("blob_checksum_tests", true);
+    let mut config = Config::new(
+        r#"
+[store."fs"]
+type = "fs"
+path = "{TMP}"
+compression = "lz4"
+checksum = true
+
+[store."lz4"]
+type = "fs"
+path = "{TMP}"
+compression = "lz4"
+
+[store."raw"]
+type = "fs"
+path = "{TMP}"
+"#
+        .replace("{TMP}", temp_dir.path.as_path().to_str().unwrap()),
+    )
+    .unwrap();
+    let mut stores = Stores::parse_all(&mut config, false).await.blob_stores;
+    let store = stores.remove("fs").unwrap();
+    let lz4_store = stores.remove("lz4").unwrap();
+    let raw_store = stores.remove("raw").unwrap();
+
+    // Blobs are written with a checksum that is verified on read
+    let data = b"checksummed blob ".repeat(100);
+    store.put_blob(b"blob", &data).await.unwrap();
+    let raw = raw_store
+        .read_blob(b"blob", 0..usize::MAX)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(raw.last(), Some(&(0xa0 | 0x03)));
+    assert_eq!(
+        store.get_blob(b"blob", 0..usize::MAX).await.unwrap(),
+        Some(data.clone())
+    );
+
+    // Corrupted blobs are reported instead of being returned
+    let mut corrupted = raw.clone();
+    corrupted[4] ^= 0x01;
+    raw_store.put_blob(b"blob", &corrupted).await.unwrap();
+    assert!(store
+        .get_blob(b"blob", 0..usize::MAX)
+        .await
+        .unwrap_err()
+        .matches(trc::EventType::Store(trc::StoreEvent::BlobChecksumMismatch)));
+
+    // Removing the marker does not skip the verification, as the blob still
+    // ends with its checksum
+    raw_store
+        .put_blob(b"blob", &raw[..raw.len() - 1])
+        .await
+        .unwrap();
+    assert!(store
+        .get_blob(b"blob", 0..usize::MAX)
+        .await
+        .unwrap_err()
+        .matches(trc::EventType::Store(trc::StoreEvent::BlobChecksumMismatch)));
+
+    // Blobs stored before checksums were enabled are returned unverified
+    lz4_store.put_blob(b"legacy", &data).await.unwrap();
+    assert_ne!(
+        raw_store
+            .read_blob(b"legacy", 0..usize::MAX)
+            .await
+            .unwrap()
+            .unwrap()
+            .last(),
+        Some(&(0xa0 | 0x03))
+    );
+    assert_eq!(
+        store.get_blob(b"legacy", 0..usize::MAX).await.unwrap(),
+        Some(data.clone())
+    );
+    raw_store.put_blob(b"legacy", b"legacy blob").await.unwrap();
+    assert_eq!(
+        store.get_blob(b"legacy", 0..usize::MAX).await.unwrap(),
+        Some(b"legacy blob".to_vec())
+    );
+
+    temp_dir.delete();
+}
+
+#[tokio::test]
+pub async fn blob_compression_hint_tests() {
+    let temp_dir = TempDir::new("blob_compression_hint_tests", true);
+    let mut config = Config::new(
+        r#"
+[store."fs"]
+type = "fs"
+path = "{TMP}"
+"#
+        .replace("{TMP}", temp_dir.path.as_path().to_str().unwrap()),
+    )
+    .unwrap();
+    let raw_store = Stores::parse_all(&mut config, false)
+        .await
+        .blob_stores
+        .remove("fs")
+        .unwrap();
+    let store = raw_store.clone().with_compression(CompressionAlgo::zstd());
+
+    // Already compressed attachments are stored with the marker of no compression,
+    // even when their last byte looks like a compression marker
+    let mut jpeg = b"jpeg ".repeat(200);
+    jpeg.push(CompressionAlgo::Lz4.marker());
+    let data = b"message body ".repeat(200);
+    for (key, data, hint, algorithm) in [
+        (
+            &b"jpeg"[..],
+            &jpeg,
+            BlobHint::Attachment {
+                content_type: "image/jpeg; name=photo.jpg",
+            },
+            CompressionAlgo::None,
+        ),
+        (
+            &b"zip"[..],
+            &data,
+            BlobHint::Attachment {
+                content_type: "application/zip",
+            },
+            CompressionAlgo::None,
+        ),
+        (
+            &b"text"[..],
+            &data,
+            BlobHint::Attachment {
+                content_type: "text/plain",
+            },
+            CompressionAlgo::zstd(),
+        ),
+        (
+            &b"body"[..],
+            &data,
+            BlobHint::Message,
+            CompressionAlgo::zstd(),
+        ),
+    ] {
+        store.put_blob_with_hint(key, data, hint).await.unwrap();
+        let raw = raw_store
+            .read_blob(key, 0..usize::MAX)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(raw.last(), Some(&algorithm.marker()));
+        if algorithm == CompressionAlgo::None {
+            // Followed by a 32-bit checksum and the marker
+            assert_eq!(&raw[..raw.len() - 5], data.as_slice());
+        } else {
+            assert!(raw.len() < data.len());
+        }
+        assert_eq!(
+            store.get_blob(key, 0..usize::MAX).await.unwrap().as_ref(),
+            Some(data)
+        );
+        assert_eq!(
+            store.uncompressed_size(key).await.unwrap(),
+            Some(data.len() as u64)
+        );
+    }
+
+    // Uncompressed blobs are not reported as compressed when forwarded
+    assert!(matches!(
+        store.get_blob_encoded(b"jpeg", |_| true).await.unwrap(),
+        Some(EncodedBlob::Decoded(decoded)) if decoded == jpeg
+    ));
+
+    // Blobs written before compression was configured are not truncated when
+    // they end with the marker of no compression
+    let mut legacy = b"legacy blob".to_vec();
+    legacy.push(CompressionAlgo::None.marker());
+    raw_store.put_blob(b"legacy", &legacy).await.unwrap();
+    assert_eq!(
+        store.get_blob(b"legacy", 0..usize::MAX).await.unwrap(),
+        Some(legacy.clone())
+    );
+    assert!(matches!(
+        store.get_blob_encoded(b"legacy", |_| true).await.unwrap(),
+        Some(EncodedBlob::Decoded(decoded)) if decoded == legacy
+    ));
+    assert_eq!(
+        store.uncompressed_size(b"legacy").await.unwrap(),
+        Some(legacy.len() as u64)
+    );
+
+    temp_dir.delete();
+}
+
+#[tokio::test]
+pub async fn blob_tiered_tests() {
+    let temp_dir = TempDir::new("blob_tiered_tests", true);
+    let mut config = Config::new(
+        r#"
+[store."fast"]
+type = "fs"
+path = "{TMP}/fast"
+
+[store."slow"]
+type = "fs"
+path = "{TMP}/slow"
+
+[store."tiered"]
+type = "tiered-blob"
+fast = "fast"
+slow = "slow"
+compression = "lz4"
+"#
+        .replace("{TMP}", temp_dir.path.as_path().to_str().unwrap()),
+    )
+    .unwrap();
+    let mut stores = Stores::parse_all(&mut config, false).await.blob_stores;
+    let tiered = stores.remove("tiered").unwrap();
+    let fast = stores.remove("fast").unwrap();
+    let slow = stores.remove("slow").unwrap();
+
+    // Blobs are compressed once and written to both tiers
+    let data = "tiered blob ".repeat(1024).into_bytes();
+    tiered.put_blob(b"blob", &data).await.unwrap();
+    let encoded = slow
+        .get_blob(b"blob", 0..usize::MAX)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(encoded.len() < data.len());
+    assert_eq!(
+        fast.get_blob(b"blob", 0..usize::MAX).await.unwrap(),
+        Some(encoded.clone())
+    );
+    assert_eq!(
+        tiered.get_blob(b"blob", 0..usize::MAX).await.unwrap(),
+        Some(data.clone())
+    );
+
+    // Misses on the fast tier are served from the slow tier, which populates it
+    assert!(fast.delete_blob(b"blob").await.unwrap());
+    assert!(tiered.blob_exists(b"blob").await.unwrap());
+    assert_eq!(
+        tiered.get_blob(b"blob", 0..usize::MAX).await.unwrap(),
+        Some(data.clone())
+    );
+    assert_eq!(
+        fast.get_blob(b"blob", 0..usize::MAX).await.unwrap(),
+        Some(encoded)
+    );
+
+    // Deletions apply to both tiers
+    assert!(tiered.delete_blob(b"blob").await.unwrap());
+    assert_eq!(fast.get_blob(b"blob", 0..usize::MAX).await.unwrap(), None);
+    assert_eq!(slow.get_blob(b"blob", 0..usize::MAX).await.unwrap(), None);
+    assert!(!tiered.blob_exists(b"blob").await.unwrap());
+    assert_eq!(tiered.get_blob(b"blob", 0..usize::MAX).await.unwrap(), None);
+
+    temp_dir.delete();
+}
+
+#[cfg(feature = "s3")]
+#[tokio::test]
+pub async fn blob_key_sharding_tests() {
+    let mut config = Config::new(
+        r#"
+[store."s3"]
+type = "s3"
+access-key = "minioadmin"
+secret-key = "minioadmin"
+region = "eu-central-1"
+endpoint = "http://localhost:9000"
+bucket = "tmp"
+key-prefix = "sharded/"
+key-shards = 16
+
+[store."s3-unsharded"]
+type = "s3"
+access-key = "minioadmin"
+secret-key = "minioadmin"
+region = "eu-central-1"
+endpoint = "http://localhost:9000"
+bucket = "tmp"
+key-prefix = "sharded/"
+"#,
+    )
+    .unwrap();
+    let mut stores = Stores::parse_all(&mut config, false).await;
+    let store = stores.blob_stores.remove("s3").unwrap();
+    let unsharded_store = stores.blob_stores.remove("s3-unsharded").unwrap();
+    let s3 = match &store.backend {
+        store::BlobBackend::S3(s3) => s3.clone(),
+        _ => unreachable!(),
+    };
+
+    // Keys sharing the same account id prefix are spread across all shards
+    let mut shards = AHashMap::new();
+    for document_id in 0u32..512 {
+        let key = [&1u32.to_be_bytes()[..], &document_id.to_be_bytes()[..]].concat();
+        let path = s3.build_key(&key);
+        assert_eq!(path, s3.build_key(&key));
+        let (shard, name) = path
+            .strip_prefix("sharded/")
+            .unwrap()
+            .split_once('/')
+            .unwrap();
+        assert_eq!(shard.len(), 1);
+        assert!(!name.is_empty());
+        *shards.entry(shard.to_string()).or_insert(0) += 1;
+    }
+    assert_eq!(shards.len(), 16);
+    assert!(shards.values().all(|count| *count >= 8), "{shards:?}");
+
+    // Blobs round trip transparently
+    for document_id in 0u32..32 {
+        let key = [&1u32.to_be_bytes()[..], &document_id.to_be_bytes()[..]].concat();
+        let data = format!("sharded blob {document_id}").into_bytes();
+        store.put_blob(&key, &data).await.unwrap();
+        assert_eq!(
+            store.get_blob(&key, 0..usize::MAX).await.unwrap(),
+            Some(data)
+        );
+        assert!(store.delete_blob(&key).await.unwrap());
+        assert_eq!(store.get_blob(&key, 0..usize::MAX).await.unwrap(), None);
+    }
+
+    // Blobs written before sharding was enabled remain reachable
+    let key = [&2u32.to_be_bytes()[..], &0u32.to_be_bytes()[..]].concat();
+    let data = b"unsharded blob".to_vec();
+    assert_ne!(s3.build_key(&key), s3.unsharded_key(&key).unwrap());
+    unsharded_store.put_blob(&key, &data).await.unwrap();
+    assert_eq!(
+        store.get_blob(&key, 0..usize::MAX).await.unwrap(),
+        Some(data.clone())
+    );
+    assert!(store.blob_exists(&key).await.unwrap());
+    assert!(!store.put_blob_if_absent(&key, b"other blob").await.unwrap());
+    assert_eq!(
+        unsharded_store.get_blob(&key, 0..usize::MAX).await.unwrap(),
+        Some(data)
+    );
+    assert!(store.delete_blob(&key).await.unwrap());
+    assert!(!store.blob_exists(&key).await.unwrap());
+    assert!(!unsharded_store.blob_exists(&key).await.unwrap());
+}
+
+#[cfg(feature = "s3")]
+#[tokio::test]
+pub async fn blob_s3_encryption_tests() {
+    use hyper::{Method, StatusCode};
+    use jmap::api::http::ToHttpResponse;
+
+    use crate::http_server::{spawn_mock_http_server, HttpMessage};
+
+    const KMS_KEY_ID: &str = "arn:aws:kms:eu-central-1:111122223333:key/test-key";
+
+    // Mock S3 server that rejects uploads without server-side encryption
+    let _tx = spawn_mock_http_server(Arc::new(|req: HttpMessage| {
+        if req.method == Method::PUT
+            && (req
+                .headers
+                .get("x-amz-server-side-encryption")
+                .map(String::as_str)
+                != Some("aws:kms")
+                || req
+                    .headers
+                    .get("x-amz-server-side-encryption-aws-kms-key-id")
+                    .map(String::as_str)
+                    != Some(KMS_KEY_ID))
+        {
+            StatusCode::BAD_REQUEST.into_http_response()
+        } else {
+            StatusCode::OK.into_http_response()
+        }
+    }))
+    .await;
+
+    for (settings, expect_success) in [
+        ("", false),
+        ("sse.mode = \"aes256\"", false),
+        ("sse.mode = \"aws:kms\"", false),
+        (
+            &*format!("sse.mode = \"aws:kms\"\nsse.kms-key-id = \"{KMS_KEY_ID}\""),
+            true,
+        ),
+    ] {
+        let mut config = Config::new(format!(
+            r#"
+[store."s3"]
+type = "s3"
+access-key = "minioadmin"
+secret-key = "minioadmin"
+region = "eu-central-1"
+endpoint = "https://127.0.0.1:9090"
+bucket = "tmp"
+max-retries = 0
+{settings}
+"#
+        ))
+        .unwrap();
+        let store = Stores::parse_all(&mut config, false)
+            .await
+            .blob_stores
+            .remove("s3")
+            .unwrap();
+
+        assert_eq!(
+            store.put_blob(b"encrypted", b"secret data").await.is_ok(),
+            expect_success,
+            "{settings}"
+        );
+    }
+
+    // KMS key ids are only accepted with the aws:kms mode
+    for settings in [
+        "sse.mode = \"aes256\"\nsse.kms-key-id = \"key\"",
+        "sse.mode = \"aes128\"",
+    ] {
+        let mut config = Config::new(format!(
+            r#"
+[store."s3"]
+type = "s3"
+access-key = "minioadmin"
+secret-key = "minioadmin"
+region = "eu-central-1"
+endpoint = "https://127.0.0.1:9090"
+bucket = "tmp"
+{settings}
+"#
+        ))
+        .unwrap();
+        Stores::parse_all(&mut config, false).await;
+        assert!(!config.errors.is_empty(), "{settings}");
+    }
+}
+
+const XOR_MARKER: u8 = 0xb1;
+
+struct XorStage {
+    log: Arc<Mutex<Vec<&'static str>>>,
+}
+
+impl BlobTransform for XorStage {
+    fn marker(&self) -> u8 {
+        XOR_MARKER
+    }
+
+    fn encode(&self, data: &[u8]) -> trc::Result<Vec<u8>> {
+        self.log.lock().unwrap().push("encode");
+        Ok(data.iter().map(|b| b ^ XOR_MARKER).collect())
+    }
+
+    fn decode(&self, data: &[u8]) -> trc::Result<Vec<u8>> {
+        self.log.lock().unwrap().push("decode");
+        Ok(data.iter().map(|b| b ^ XOR_MARKER).collect())
+    }
+}
+
+async fn test_store(store: BlobStore) {
+    // Test small blob
+    const DATA: &[u8] = b"Lorem ipsum dolor sit amet, consectetur adipiscing elit. Fusce erat nisl, dignissim a porttitor id, varius nec arcu. Sed mauris.";
+    let hash = BlobHash::from(DATA);
+
+    assert!(!store.blob_exists(hash.as_slice()).await.unwrap());
+    assert_eq!(store.blob_size(hash.as_slice()).await.unwrap(), None);
+    store.put_blob(hash.as_slice(), DATA).await.unwrap();
+    assert!(store.blob_exists(hash.as_slice()).await.unwrap());
+    assert_eq!(
+        store.blob_size(hash.as_slice()).await.unwrap(),
+        Some(DATA.len() as u64)
+    );
+    assert_eq!(
+        String::from_utf8(
+            store
+                .get_blob(hash.as_slice(), 0..usize::MAX)
+                .await
+                .unwrap()
+                .unwrap()
+        )
+        .unwrap(),
+        std::str::from_utf8(DATA).unwrap()
+    );
+    assert_eq!(
+        String::from_utf8(
+            store
+                .get_blob(hash.as_slice(), 11..57)
+                .await
+                .unwrap()
+                .unwrap()
+        )
+        .unwrap(),
+        std::str::from_utf8(&DATA[11..57]).unwrap()
+    );
+    for range in [40..usize::MAX, DATA.len() - 3..DATA.len() + 10] {
+        assert_eq!(
+            store
+                .get_blob(hash.as_slice(), range.clone())
+                .await
+                .unwrap()
+                .unwrap(),
+            &DATA[range.start..DATA.len()],
+            "{range:?}"
+        );
+    }
+    assert!(store.delete_blob(hash.as_slice()).await.unwrap());
+    assert!(!store.blob_exists(hash.as_slice()).await.unwrap());
+    assert!(store
+        .get_blob(hash.as_slice(), 0..usize::MAX)
+        .await
+        .unwrap()
+        .is_none());
+
+    // Conditional writes only store blobs missing from the backend
+    assert!(store
+        .put_blob_if_absent(hash.as_slice(), DATA)
+        .await
+        .unwrap());
+    assert!(!store
+        .put_blob_if_absent(hash.as_slice(), b"other")
+        .await
+        .unwrap());
+    assert_eq!(
+        store
+            .get_blob(hash.as_slice(), 0..usize::MAX)
+            .await
+            .unwrap()
+            .as_deref(),
+        Some(DATA)
+    );
+    assert!(store.delete_blob(hash.as_slice()).await.unwrap());
+
+    // Only one of several concurrent writers of the same blob stores it
+    let written =
+        futures::future::join_all((0..8).map(|_| store.put_blob_if_absent(hash.as_slice(), DATA)))
+            .await;
+    assert_eq!(
+        written
+            .into_iter()
+            .filter(|written| *written.as_ref().unwrap())
+            .count(),
+        1
+    );
+    assert!(store.delete_blob(hash.as_slice()).await.unwrap());
+
+    // Blobs uploaded in parts, sent out of order and resent after a failure
+    let upload_id = format!("upload-{}", now()).into_bytes();
+    for (part_number, part) in [
+        (2, &DATA[40..]),
+        (0, &DATA[..20]),
+        (1, b"interrupted".as_slice()),
+        (1, &DATA[20..40]),
+    ] {
+        store
+            .put_blob_part(&upload_id, part_number, part)
+            .await
+            .unwrap();
+    }
+    store
+        .finalize_blob_upload(&upload_id, hash.as_slice())
+        .await
+        .unwrap();
+    assert_eq!(
+        store
+            .get_blob(hash.as_slice(), 0..usize::MAX)
+            .await
+            .unwrap()
+            .as_deref(),
+        Some(DATA)
+    );
+    assert!(store
+        .finalize_blob_upload(&upload_id, hash.as_slice())
+        .await
+        .is_err());
+    assert!(store.delete_blob(hash.as_slice()).await.unwrap());
+
+    // Aborted uploads leave no parts behind
+    store.put_blob_part(&upload_id, 0, DATA).await.unwrap();
+    store.abort_blob_upload(&upload_id).await.unwrap();
+    assert!(store
+        .finalize_blob_upload(&upload_id, hash.as_slice())
+        .await
+        .is_err());
+
+    // Test legal hold
+    let hold_key = format!("hold-{}", now()).into_bytes();
+    store.put_blob(hash.as_slice(), DATA).await.unwrap();
+    store.hold_blob(hash.as_slice(), &hold_key).await.unwrap();
+    assert!(store.delete_blob(hash.as_slice()).await.unwrap());
+    assert!(store
+        .get_blob(hash.as_slice(), 0..usize::MAX)
+        .await
+        .unwrap()
+        .is_none());
+    assert_eq!(
+        store.get_held_blob(&hold_key).await.unwrap().as_deref(),
+        Some(DATA)
+    );
+
+    // Held blobs are write-once
+    store.put_blob(hash.as_slice(), b"other").await.unwrap();
+    assert!(store.hold_blob(hash.as_slice(), &hold_key).await.is_err());
+    assert!(store.delete_blob(hash.as_slice()).await.unwrap());
+    assert!(store
+        .get_held_blob(b"missing-hold")
+        .await
+        .unwrap()
+        .is_none());
+    assert!(store
+        .hold_blob(hash.as_slice(), b"missing-hold")
+        .await
+        .is_err());
+    for key in [
+        [b"\xffhold.data:".as_slice(), &hold_key].concat(),
+        [b"\xffhold.marker:".as_slice(), &hold_key].concat(),
+    ] {
+        assert!(store.put_blob(&key, b"tampered").await.is_err());
+        assert!(store.delete_blob(&key).await.is_err());
+    }
+    assert_eq!(
+        store.get_held_blob(&hold_key).await.unwrap().as_deref(),
+        Some(DATA)
+    );
+
+    // Only one of concurrent holds under the same key succeeds
+    let hold_key = format!("hold-race-{}", now()).into_bytes();
+    let sources = [b"first held".as_slice(), b"second held"];
+    for source in sources {
+        store.put_blob(source, source).await.unwrap();
+    }
+    let (first, second) = futures::join!(
+        store.hold_blob(sources[0], &hold_key),
+        store.hold_blob(sources[1], &hold_key)
+    );
+    assert!(first.is_ok() ^ second.is_ok());
+    assert_eq!(
+        store.get_held_blob(&hold_key).await.unwrap().as_deref(),
+        Some(if first.is_ok() {
+            sources[0]
+        } else {
+            sources[1]
+        })
+    );
+    for source in sources {
+        assert!(store.delete_blob(source).await.unwrap());
+    }
+
+    // Test large blob
+    let mut data = Vec::with_capacity(50 * 1024 * 1024);
+    while data.len() < 50 * 1024 * 1024 {
+        data.extend_from_slice(DATA);
+        let marker = format!(" [{}] ", data.len());
+        data.extend_from_slice(marker.as_bytes());
+    }
+    let hash = BlobHash::from(&data);
+    store.put_blob(hash.as_slice(), &data).await.unwrap();
+    assert_eq!(
+        String::from_utf8(
+            store
+                .get_blob(hash.as_slice(), 0..usize::MAX)
+                .await
+                .unwrap()
+                .unwrap()
+        )
+        .unwrap(),
+        std::str::from_utf8(&data).unwrap()
+    );
+
+    assert_eq!(
+        String::from_utf8(
+            store
+                .get_blob(hash.as_slice(), 3000111..4000999)
+                .await
+                .unwrap()
+                .unwrap()
+        )
+        .unwrap(),
+        std::str::from_utf8(&data[3000111..4000999]).unwrap()
+    );
+
+    // Test ranged read using a blob view (memory-mapped on the filesystem backend)
+    let view = store
+        .get_blob_view(hash.as_slice(), 10000123..12000456)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(view.len(), 12000456 - 10000123);
+    assert_eq!(view.as_ref(), &data[10000123..12000456]);
+    drop(view);
+
+    // Test multiple ranges fetched with a single read
+    let ranges = [0..100, 3000111..3000999, 20000000..20000050];
+    let slices = store
+        .get_blob_ranges(hash.as_slice(), &ranges)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(slices.len(), ranges.len());
+    for (slice, range) in slices.iter().zip(ranges) {
+        assert_eq!(slice.as_slice(), &data[range]);
+    }
+
+    // Test streamed reads, which fetch the blob in windows on most backends
+    for range in [0..usize::MAX, 3000111..4000999, 45000000..usize::MAX] {
+        let mut stream = store
+            .get_blob_stream(hash.as_slice(), range.clone())
+            .await
+            .unwrap()
+            .unwrap();
+        let mut streamed = Vec::new();
+        stream.read_to_end(&mut streamed).await.unwrap();
+        assert_eq!(
+            streamed,
+            &data[range.start..range.end.min(data.len())],
+            "{range:?}"
+        );
+    }
+
+    assert!(store.delete_blob(hash.as_slice()).await.unwrap());
+    assert!(store
+        .get_blob(hash.as_slice(), 0..usize::MAX)
+        .await
+        .unwrap()
+        .is_none());
+    assert!(store
+        .get_blob_stream(hash.as_slice(), 0..usize::MAX)
+        .await
+        .unwrap()
+        .is_none());
+
+    // Test bulk deletion
+    let keys = (0u8..3)
+        .map(|id| format!("bulk-{}-{id}", now()).into_bytes())
+        .collect::<Vec<_>>();
+    for key in &keys {
+        store.put_blob(key, DATA).await.unwrap();
+    }
+    assert!(store
+        .delete_blobs(&[
+            keys[0].clone(),
+            [b"\xffhold.data:".as_slice(), &hold_key].concat()
+        ])
+        .await
+        .is_err());
+    assert_eq!(store.delete_blobs(&keys).await.unwrap(), keys.len());
+    for key in &keys {
+        assert!(store.get_blob(key, 0..usize::MAX).await.unwrap().is_none());
+    }
+    assert_eq!(store.delete_blobs(&[]).await.unwrap(), 0);
+
+    // Test manifest generation and verification
+    let prefix = format!("manifest-{}-", now()).into_bytes();
+    let keys = (0u8..3)
+        .map(|id| [prefix.as_slice(), &[id]].concat())
+        .collect::<Vec<_>>();
+    for (key, repeat) in keys.iter().zip([1, 100, 25_000]) {
+        store.put_blob(key, &DATA.repeat(repeat)).await.unwrap();
+    }
+    let manifest = store
+        .generate_manifest(&prefix)
+        .try_collect::<Vec<_>>()
+        .await
+        .unwrap();
+    // Entries follow the listing order of the backend
+    let mut manifest_keys = manifest
+        .iter()
+        .map(|entry| entry.key.clone())
+        .collect::<Vec<_>>();
+    manifest_keys.sort_unstable();
+    assert_eq!(manifest_keys, keys);
+    for entry in &manifest {
+        assert_eq!(entry.to_string().parse::<ManifestEntry>().unwrap(), *entry);
+    }
+    assert!(store
+        .verify_manifest(&prefix, manifest.clone())
+        .await
+        .unwrap()
+        .is_ok());
+
+    // Deliberately remove a blob
+    assert!(store.delete_blob(&keys[1]).await.unwrap());
+    let report = store
+        .verify_manifest(&prefix, manifest.clone())
+        .await
+        .unwrap();
+    assert_eq!(report.missing, vec![keys[1].clone()]);
+    assert!(report.extra.is_empty() && report.corrupt.is_empty());
+
+    // Tamper with a blob and add one that is not in the manifest
+    let extra_key = [prefix.as_slice(), &[9]].concat();
+    store.put_blob(&keys[0], b"tampered").await.unwrap();
+    store.put_blob(&extra_key, DATA).await.unwrap();
+    let report = store
+        .verify_manifest(&prefix, manifest.clone())
+        .await
+        .unwrap();
+    assert_eq!(report.missing, vec![keys[1].clone()]);
+    assert_eq!(report.corrupt, vec![keys[0].clone()]);
+    assert_eq!(report.extra, vec![extra_key.clone()]);
+
+    for key in [&keys[0], &keys[2], &extra_key] {
+        assert!(store.delete_blob(key).await.unwrap());
+    }
+
+    // Data stores are listed in pages, in key order
+    if matches!(store.backend, store::BlobBackend::Store(_)) {
+        let prefix = format!("manifest-pages-{}-", now()).into_bytes();
+        let keys = (0u16..2500)
+            .map(|id| [prefix.as_slice(), &id.to_be_bytes()].concat())
+            .collect::<Vec<_>>();
+        for key in &keys {
+            store.put_blob(key, DATA).await.unwrap();
+        }
+        assert_eq!(
+            store
+                .generate_manifest(&prefix)
+                .map_ok(|entry| entry.key)
+                .try_collect::<Vec<_>>()
+                .await
+                .unwrap(),
+            keys
+        );
+        assert_eq!(store.delete_blob_prefix(&prefix).await.unwrap(), keys.len());
+    }
+}
+
+#[tokio::test]
+pub async fn blob_orphan_tests() {
+    let temp_dir = TempDir::new("blob_orphan_tests", true);
+    let mut config = Config::new(
+        r#"
+[store."sqlite"]
+type = "sqlite"
+path = "{TMP}/sqlite.db"
+
+[store."fs"]
+type = "fs"
+path = "{TMP}/blobs"
+depth = 2
+"#
+        .replace("{TMP}", temp_dir.path.as_path().to_str().unwrap()),
+    )
+    .unwrap();
+    let stores = Stores::parse_all(&mut config, false).await;
+    let store = stores.stores.get("sqlite").unwrap().clone();
+    let blob_store = stores.blob_stores.get("fs").unwrap().clone();
+
+    // One blob linked, one reserved and one that nothing references
+    let linked = BlobHash::from(b"linked".as_slice());
+    let reserved = BlobHash::from(b"reserved".as_slice());
+    let orphan = BlobHash::from(b"orphan".as_slice());
+    for hash in [&linked, &reserved, &orphan] {
+        blob_store.put_blob(hash.as_slice(), b"data").await.unwrap();
+    }
+    store
+        .write(
+            BatchBuilder::new()
+                .with_account_id(1)
+                .set(
+                    BlobOp::Reserve {
+                        until: now() + 3600,
+                        hash: reserved.clone(),
+                    },
+                    4u32.serialize(),
+                )
+                .with_collection(0)
+                .update_document(1)
+                .set(
+                    BlobOp::Link {
+                        hash: linked.clone(),
+                    },
+                    Vec::new(),
+                )
+                .build_batch(),
+        )
+        .await
+        .unwrap();
+
+    // Upload parts and held blobs whose keys are as long as a blob hash are
+    // not mistaken for blobs
+    blob_store
+        .put_blob_part(&[b'u'; 19], 1, b"part")
+        .await
+        .unwrap();
+    blob_store
+        .hold_blob(linked.as_slice(), &[b'h'; 21])
+        .await
+        .unwrap();
+
+    // Unreferenced blobs are only marked the first time they are found, and
+    // kept while the grace period has not elapsed
+    let grace_period = std::time::Duration::from_secs(3600);
+    for _ in 0..2 {
+        assert_eq!(
+            store
+                .purge_orphaned_blobs(&blob_store, grace_period)
+                .await
+                .unwrap(),
+            0
+        );
+        assert!(blob_store.blob_exists(orphan.as_slice()).await.unwrap());
+    }
+    assert_eq!(
+        store
+            .purge_orphaned_blobs(&blob_store, std::time::Duration::ZERO)
+            .await
+            .unwrap(),
+        1
+    );
+    assert!(!blob_store.blob_exists(orphan.as_slice()).await.unwrap());
+    for hash in [&linked, &reserved] {
+        assert!(blob_store.blob_exists(hash.as_slice()).await.unwrap());
+    }
+    assert!(blob_store
+        .get_held_blob(&[b'h'; 21])
+        .await
+        .unwrap()
+        .is_some());
+    blob_store
+        .finalize_blob_upload(&[b'u'; 19], b"assembled")
+        .await
+        .unwrap();
+
+    // Blobs found unreferenced are not deleted if they are referenced by the
+    // time the grace period elapses
+    let late = BlobHash::from(b"late".as_slice());
+    blob_store.put_blob(late.as_slice(), b"data").await.unwrap();
+    assert_eq!(
+        store
+            .purge_orphaned_blobs(&blob_store, std::time::Duration::ZERO)
+            .await
+            .unwrap(),
+        0
+    );
+    store
+        .write(
+            BatchBuilder::new()
+                .with_account_id(1)
+                .with_collection(0)
+                .update_document(2)
+                .set(BlobOp::Link { hash: late.clone() }, Vec::new())
+                .build_batch(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        store
+            .purge_orphaned_blobs(&blob_store, std::time::Duration::ZERO)
+            .await
+            .unwrap(),
+        0
+    );
+    assert!(blob_store.blob_exists(late.as_slice()).await.unwrap());
+
+    // Deduplicated writes restart the grace period of a marked blob
+    let blob_store = blob_store.with_deduplication(true);
+    let rewritten = BlobHash::from(b"rewritten".as_slice());
+    blob_store
+        .put_blob(rewritten.as_slice(), b"data")
+        .await
+        .unwrap();
+    for _ in 0..2 {
+        assert_eq!(
+            store
+                .purge_orphaned_blobs(&blob_store, std::time::Duration::ZERO)
+                .await
+                .unwrap(),
+            0
+        );
+        blob_store
+            .put_blob(rewritten.as_slice(), b"data")
+            .await
+            .unwrap();
+    }
+    assert!(blob_store.blob_exists(rewritten.as_slice()).await.unwrap());
+
+    temp_dir.delete();
+}
+
+#[tokio::test]
+pub async fn blob_dictionary_tests() {
+    let temp_dir = TempDir::new("blob_dictionary_tests", true);
+    let mut config = Config::new(
+        r#"
+[store."fs"]
+type = "fs"
+path = "{TMP}"
+compression = "zstd"
+
+[store."node"]
+type = "fs"
+path = "{TMP}"
+compression = "zstd"
+
+[store."raw"]
+type = "fs"
+path = "{TMP}"
+"#
+        .replace("{TMP}", temp_dir.path.as_path().to_str().unwrap()),
+    )
+    .unwrap();
+    let mut stores = Stores::parse_all(&mut config, false).await.blob_stores;
+    let store = stores.remove("fs").unwrap();
+    let node_store = stores.remove("node").unwrap();
+    let raw_store = stores.remove("raw").unwrap();
+
+    // Automated mail differs between accounts but barely between messages
+    let report = |account_id: u32, n: u32| {
+        format!(
+            concat!(
+                "From: reports-{}@example.org\r\nSubject: Daily report #{}\r\n\r\n",
+                "Dear customer, this is your automated report for account {}. ",
+                "Jobs completed: {}, jobs failed: {}, disk usage: {} MB. ",
+                "Please do not reply to this message, it was sent by a robot.\r\n"
+            ),
+            account_id,
+            n,
+            account_id,
+            n * 7 % 13,
+            n % 3,
+            n * 31 % 1000
+        )
+        .into_bytes()
+    };
+    for account_id in [1, 2] {
+        assert!(store
+            .account_dictionary(account_id)
+            .await
+            .unwrap()
+            .is_none());
+        let samples = (0..500).map(|n| report(account_id, n)).collect::<Vec<_>>();
+        assert_eq!(
+            store
+                .train_account_dictionary(account_id, &samples, 4096)
+                .await
+                .unwrap(),
+            0
+        );
+    }
+
+    // Blobs of accounts with a dictionary are tagged with its ids and
+    // compress better than with plain Zstandard
+    let data_1 = report(1, 1000);
+    let data_2 = report(2, 1000);
+    store
+        .put_account_blob(1, b"report-1", &data_1, BlobHint::Message)
+        .await
+        .unwrap();
+    store
+        .put_account_blob(2, b"report-2", &data_2, BlobHint::Message)
+        .await
+        .unwrap();
+    store.put_blob(b"plain-1", &data_1).await.unwrap();
+    let raw = raw_store
+        .read_blob(b"report-1", 0..usize::MAX)
+        .await
+        .unwrap()
+        .unwrap();
+    let plain = raw_store
+        .read_blob(b"plain-1", 0..usize::MAX)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(raw.last(), Some(&(0xa0 | 0x06)));
+    assert_eq!(raw[raw.len() - 9..raw.len() - 1], [0, 0, 0, 1, 0, 0, 0, 0]);
+    assert!(raw.len() < plain.len());
+
+    // Accounts without a dictionary fall back to plain Zstandard
+    store
+        .put_account_blob(3, b"report-3", &data_1, BlobHint::Message)
+        .await
+        .unwrap();
+    let raw = raw_store
+        .read_blob(b"report-3", 0..usize::MAX)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(raw.last(), Some(&(0xa0 | 0x02)));
+
+    // Blobs are decoded with the dictionary named in their trailer, which
+    // other nodes read from the store
+    for blob_store in [&store, &node_store] {
+        for (key, data) in [
+            (&b"report-1"[..], &data_1),
+            (b"report-2", &data_2),
+            (b"report-3", &data_1),
+            (b"plain-1", &data_1),
+        ] {
+            assert_eq!(
+                blob_store.get_blob(key, 0..usize::MAX).await.unwrap(),
+                Some(data.clone())
+            );
+        }
+    }
+    assert_eq!(
+        node_store.get_blob(b"report-2", 10..20).await.unwrap(),
+        Some(data_2[10..20].to_vec())
+    );
+
+    // Their frames can't be decoded by clients without the dictionary
+    match node_store
+        .get_blob_encoded(b"report-1", |_| true)
+        .await
+        .unwrap()
+        .unwrap()
+    {
+        EncodedBlob::Decoded(decoded) => assert_eq!(decoded, data_1),
+        _ => panic!("expected decoded blob"),
+    }
+
+    // Retraining keeps the blobs compressed with the previous dictionary
+    // readable, other nodes pick the latest dictionary of an account
+    let samples = (0..500).map(|n| report(1, n * 2)).collect::<Vec<_>>();
+    assert_eq!(
+        store
+            .train_account_dictionary(1, &samples, 4096)
+            .await
+            .unwrap(),
+        1
+    );
+    store
+        .put_account_blob(1, b"report-1-new", &data_1, BlobHint::Message)
+        .await
+        .unwrap();
+    let raw = raw_store
+        .read_blob(b"report-1-new", 0..usize::MAX)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(raw[raw.len() - 9..raw.len() - 1], [0, 0, 0, 1, 0, 0, 0, 1]);
+    assert_eq!(
+        node_store
+            .account_dictionary(1)
+            .await
+            .unwrap()
+            .map(|dictionary| dictionary.dictionary_id),
+        Some(1)
+    );
+    for key in [&b"report-1"[..], b"report-1-new"] {
+        assert_eq!(
+            node_store.get_blob(key, 0..usize::MAX).await.unwrap(),
+            Some(data_1.clone())
+        );
+    }
+
+    temp_dir.delete();
 }
@@ -8,6 +8,7 @@ use ahash::AHashSet;
 use common::{manager::backup::BackupParams, Core};
 use jmap_proto::types::{collection::Collection, property::Property};
 use store::{
+    query::acl::AclQuery,
     rand,
     write::{
         AnyKey, BatchBuilder, BitmapClass, BitmapHash, BlobOp, DirectoryClass, InMemoryClass,
@@ -231,6 +232,81 @@ pub async fn test(db: Store) {
     let snapshot = Snapshot::new(&db).await;
     assert!(!snapshot.keys.is_empty(), "Store hash counts are empty",);
 
+    // Writes made after taking a store snapshot should not be visible through it
+    println!("Testing store snapshots...");
+    let store_snapshot = db.snapshot().await.unwrap();
+    let key = ValueKey {
+        account_id: u32::MAX - 1,
+        collection: Collection::Email.into(),
+        document_id: 0,
+        class: ValueClass::Property(Property::Value.into()),
+    };
+    let mut batch = BatchBuilder::new();
+    batch
+        .with_account_id(u32::MAX - 1)
+        .with_collection(Collection::Email)
+        .update_document(0)
+        .set(
+            ValueClass::Property(Property::Value.into()),
+            b"snapshot".to_vec(),
+        )
+        .set(ValueClass::Acl(u32::MAX - 2), 1u64.serialize());
+    db.write(batch.build()).await.unwrap();
+    assert_eq!(
+        db.get_value::<String>(key.clone())
+            .await
+            .unwrap()
+            .as_deref(),
+        Some("snapshot")
+    );
+    let acl_query = || AclQuery::GrantedTo {
+        grant_account_id: u32::MAX - 2,
+    };
+    assert_eq!(db.acl_query(acl_query()).await.unwrap().len(), 1);
+    let mut items = Vec::new();
+    db.acl_iterate(acl_query(), |item| {
+        items.push((item.to_account_id, item.to_document_id, item.permissions));
+        Ok(true)
+    })
+    .await
+    .unwrap();
+    assert_eq!(items, vec![(u32::MAX - 1, 0, 1)]);
+    if store_snapshot.is_consistent() {
+        assert_eq!(
+            store_snapshot
+                .get_value::<String>(key.clone())
+                .await
+                .unwrap(),
+            None
+        );
+        let mut found = false;
+        store_snapshot
+            .iterate(
+                IterateParams::new(key.clone(), key.clone()).no_values(),
+                |_, _| {
+                    found = true;
+                    Ok(true)
+                },
+            )
+            .await
+            .unwrap();
+        assert!(!found, "Snapshot returned a key written after it was taken");
+        assert!(store_snapshot
+            .acl_query(acl_query())
+            .await
+            .unwrap()
+            .is_empty());
+    }
+    drop(store_snapshot);
+    let mut batch = BatchBuilder::new();
+    batch
+        .with_account_id(u32::MAX - 1)
+        .with_collection(Collection::Email)
+        .update_document(0)
+        .clear(ValueClass::Property(Property::Value.into()))
+        .clear(ValueClass::Acl(u32::MAX - 2));
+    db.write(batch.build()).await.unwrap();
+
     // Export store
     println!("Exporting store...");
     let temp_dir = TempDir::new("art_vandelay_tests", true);
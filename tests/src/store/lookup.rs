@@ -86,6 +86,32 @@ pub async fn lookup_tests() {
             .unwrap();
         assert_eq!(0, store.counter_get(key.clone()).await.unwrap());
 
+        // Test counter watches
+        if store.supports_watch() {
+            let changed = store.counter_watch(key.clone(), 0).await.unwrap();
+            store
+                .counter_incr(KeyValue::new(key.clone(), 1), false)
+                .await
+                .unwrap();
+            tokio::time::timeout(tokio::time::Duration::from_secs(5), changed)
+                .await
+                .expect("watch did not fire")
+                .unwrap();
+
+            // Watches on an outdated value resolve right away
+            tokio::time::timeout(
+                tokio::time::Duration::from_secs(1),
+                store.counter_watch(key.clone(), 0).await.unwrap(),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+            store
+                .counter_incr(KeyValue::new(key.clone(), -1), false)
+                .await
+                .unwrap();
+        }
+
         // Test counter expiry
         let key = "fgh".as_bytes().to_vec();
         store
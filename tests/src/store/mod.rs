@@ -8,6 +8,7 @@ pub mod assign_id;
 pub mod blob;
 pub mod import_export;
 pub mod lookup;
+pub mod notify;
 pub mod ops;
 pub mod query;
 
@@ -38,9 +39,12 @@ path = "{TMP}"
 [store."rocksdb"]
 type = "rocksdb"
 path = "{TMP}/rocksdb"
+blob.compression = "lz4"
+blob.write-buffer-size = 67108864
 
 [store."foundationdb"]
 type = "foundationdb"
+key-prefix = "store-tests"
 
 [store."sqlite"]
 type = "sqlite"
@@ -53,6 +57,16 @@ port = 5432
 database = "stalwart"
 user = "postgres"
 password = "mysecretpassword"
+notify.enable = true
+
+[store."postgresql-peer"]
+type = "postgresql"
+host = "localhost"
+port = 5432
+database = "stalwart"
+user = "postgres"
+password = "mysecretpassword"
+notify.enable = true
 
 [store."mysql"]
 type = "mysql"
@@ -99,6 +113,9 @@ pub async fn store_tests() {
     import_export::test(store.clone()).await;
     assign_id::test(store.clone()).await;
     ops::test(store.clone()).await;
+    if let Some(peer) = stores.stores.get(&format!("{store_id}-peer")) {
+        notify::test(store.clone(), peer.clone()).await;
+    }
     query::test(store.clone(), FtsStore::Store(store.clone()), insert).await;
 
     if insert {
@@ -0,0 +1,70 @@
+/*
+ * SPDX-FileCopyrightText: 2020 Stalwart Labs Ltd <hello@stalw.art>
+ *
+ * SPDX-License-Identifier: AGPL-3.0-only OR LicenseRef-SEL
+ */
+
+use std::time::Duration;
+
+use jmap_proto::types::collection::Collection;
+use store::{
+    write::{BatchBuilder, MaybeDynamicValue, Operation},
+    LogKey, Store,
+};
+
+pub async fn test(db: Store, peer: Store) {
+    println!("Testing change notifications...");
+    let mut changes = peer
+        .listen_changes()
+        .expect("Change notifications are disabled");
+
+    // Wait for the listener to connect
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    // Changes committed by one node are notified to the others
+    write_change(&db, 10).await;
+    assert_eq!(
+        tokio::time::timeout(Duration::from_secs(5), changes.recv())
+            .await
+            .expect("Change was not notified"),
+        Some(LogKey {
+            account_id: 0,
+            collection: Collection::Email.into(),
+            change_id: 10,
+        })
+    );
+
+    // Changes committed by the listening node are not notified to it
+    write_change(&peer, 11).await;
+    assert!(
+        tokio::time::timeout(Duration::from_millis(500), changes.recv())
+            .await
+            .is_err(),
+        "Listener was notified of its own change"
+    );
+
+    db.delete_range(
+        LogKey {
+            account_id: 0,
+            collection: Collection::Email.into(),
+            change_id: 0,
+        },
+        LogKey {
+            account_id: 0,
+            collection: Collection::Email.into(),
+            change_id: u64::MAX,
+        },
+    )
+    .await
+    .unwrap();
+}
+
+async fn write_change(db: &Store, change_id: u64) {
+    let mut batch = BatchBuilder::new();
+    batch.with_account_id(0).with_collection(Collection::Email);
+    batch.ops.push(Operation::ChangeId { change_id });
+    batch.ops.push(Operation::Log {
+        set: MaybeDynamicValue::Static(vec![]),
+    });
+    db.write(batch.build()).await.unwrap();
+}
@@ -8,11 +8,28 @@ use std::collections::HashSet;
 
 use jmap_proto::types::{collection::Collection, property::Property};
 use store::{
+    dispatch::blob::Decompressed,
+    query::{
+        log::{Change, Query},
+        Filter,
+    },
+    roaring::RoaringBitmap,
     write::{
-        BatchBuilder, BitmapClass, DirectoryClass, MaybeDynamicId, TagValue, ValueClass, F_CLEAR,
+        account::AccountInit,
+        assert::HashedValue,
+        compress::{
+            compress_value, decompress_value, is_compressed_value, CompressionMigration,
+            ValueCompression,
+        },
+        outcome::OperationResult,
+        AnyKey, BatchBuilder, BitmapClass, BitmapHash, BlobOp, DirectoryClass, IntoOperations,
+        MaybeDynamicId, Operation, TagValue, ValueClass, CLEAR_PREFIX_SUBSPACES, F_BITMAP, F_CLEAR,
+        F_INDEX, F_VALUE, MAX_APPEND_SIZE,
     },
-    BitmapKey, Store, ValueKey,
+    BitmapKey, CompressionAlgo, Deserialize, IndexKeyPrefix, IterateParams, Serialize, Store,
+    ValueKey, SUBSPACE_SETTINGS,
 };
+use utils::BlobHash;
 
 // FDB max value
 const MAX_VALUE_SIZE: usize = 100000;
@@ -231,6 +248,1436 @@ pub async fn test(db: Store) {
         1000
     );
 
+    println!("Running multiple assertion tests...");
+    let mut batch = BatchBuilder::new();
+    batch
+        .with_account_id(0)
+        .with_collection(0)
+        .update_document(0);
+    for n in 0..3u64 {
+        batch.set(
+            ValueClass::Config(format!("assert{n}").into_bytes()),
+            n.serialize(),
+        );
+    }
+    db.write(batch.build_batch()).await.unwrap();
+
+    let mut batch = BatchBuilder::new();
+    batch
+        .with_account_id(0)
+        .with_collection(0)
+        .update_document(0)
+        .assert_value(ValueClass::Config(b"assert0".to_vec()), 0u64)
+        .assert_value(ValueClass::Config(b"assert1".to_vec()), 100u64)
+        .assert_value(ValueClass::Config(b"assert2".to_vec()), 2u64)
+        .set(ValueClass::Config(b"assert3".to_vec()), 3u64.serialize());
+    let batch = batch.build_batch();
+    let err = db.validate(&batch).await.unwrap_err();
+    assert!(
+        err.matches(trc::EventType::Store(trc::StoreEvent::AssertValueFailed)),
+        "unexpected error: {err:?}"
+    );
+    let err = db.write(batch).await.unwrap_err();
+    assert!(
+        err.matches(trc::EventType::Store(trc::StoreEvent::AssertValueFailed)),
+        "unexpected error: {err:?}"
+    );
+    assert_eq!(
+        db.get_value::<u64>(ValueKey {
+            account_id: 0,
+            collection: 0,
+            document_id: 0,
+            class: ValueClass::Config(b"assert3".to_vec()),
+        })
+        .await
+        .unwrap(),
+        None
+    );
+
+    let mut batch = BatchBuilder::new();
+    batch
+        .with_account_id(0)
+        .with_collection(0)
+        .update_document(0)
+        .assert_value(ValueClass::Config(b"assert0".to_vec()), 0u64)
+        .assert_value(ValueClass::Config(b"assert1".to_vec()), 1u64)
+        .assert_value(ValueClass::Config(b"assert2".to_vec()), 2u64)
+        .assert_value(ValueClass::Config(b"assert3".to_vec()), ())
+        .clear(ValueClass::Config(b"assert0".to_vec()))
+        .clear(ValueClass::Config(b"assert1".to_vec()))
+        .clear(ValueClass::Config(b"assert2".to_vec()));
+    let batch = batch.build_batch();
+
+    // Validating a batch does not apply it
+    db.validate(&batch).await.unwrap();
+    db.validate(&batch).await.unwrap();
+    assert_eq!(
+        db.get_value::<u64>(ValueKey {
+            account_id: 0,
+            collection: 0,
+            document_id: 0,
+            class: ValueClass::Config(b"assert0".to_vec()),
+        })
+        .await
+        .unwrap(),
+        Some(0)
+    );
+    db.write(batch).await.unwrap();
+
+    println!("Running atomic append tests...");
+    let append_key = ValueKey {
+        account_id: 0,
+        collection: 0,
+        document_id: 1,
+        class: ValueClass::Config(b"append".to_vec()),
+    };
+    let mut expected = String::new();
+    for chunk in ["part1", "", "part2", "part3"] {
+        db.write(
+            BatchBuilder::new()
+                .with_account_id(0)
+                .with_collection(0)
+                .update_document(1)
+                .append(ValueClass::Config(b"append".to_vec()), chunk.as_bytes())
+                .build_batch(),
+        )
+        .await
+        .unwrap();
+        expected.push_str(chunk);
+    }
+    let mut batch = BatchBuilder::new();
+    batch
+        .with_account_id(0)
+        .with_collection(0)
+        .update_document(1);
+    for chunk in ["part4", "part5"] {
+        batch.append(ValueClass::Config(b"append".to_vec()), chunk.as_bytes());
+        expected.push_str(chunk);
+    }
+    db.write(batch.build_batch()).await.unwrap();
+    assert_eq!(
+        db.get_value::<String>(append_key.clone()).await.unwrap(),
+        Some(expected.clone())
+    );
+
+    let err = db
+        .write(
+            BatchBuilder::new()
+                .with_account_id(0)
+                .with_collection(0)
+                .update_document(1)
+                .append(
+                    ValueClass::Config(b"append".to_vec()),
+                    vec![b'A'; MAX_APPEND_SIZE],
+                )
+                .build_batch(),
+        )
+        .await
+        .unwrap_err();
+    assert!(
+        err.matches(trc::EventType::Store(trc::StoreEvent::ValueTooLarge)),
+        "unexpected error: {err:?}"
+    );
+    assert_eq!(
+        db.get_value::<String>(append_key).await.unwrap(),
+        Some(expected)
+    );
+    db.write(
+        BatchBuilder::new()
+            .with_account_id(0)
+            .with_collection(0)
+            .update_document(1)
+            .clear(ValueClass::Config(b"append".to_vec()))
+            .build_batch(),
+    )
+    .await
+    .unwrap();
+
+    // Property values may be stored compressed and can't be appended to
+    let err = db
+        .write(
+            BatchBuilder::new()
+                .with_account_id(0)
+                .with_collection(0)
+                .update_document(1)
+                .append(ValueClass::Property(3), b"part1".to_vec())
+                .build_batch(),
+        )
+        .await
+        .unwrap_err();
+    assert!(
+        err.matches(trc::EventType::Store(trc::StoreEvent::NotSupported)),
+        "unexpected error: {err:?}"
+    );
+
+    println!("Running bitmap read-repair tests...");
+    let repair_id = 7;
+    let mut builder = BatchBuilder::new();
+    builder
+        .with_account_id(0)
+        .with_collection(Collection::Email)
+        .create_document_with_id(repair_id);
+    builder.ops.push(Operation::Index {
+        field: Property::Subject.into(),
+        key: b"read-repair".to_vec(),
+        set: true,
+    });
+    db.write(builder.build_batch()).await.unwrap();
+
+    // Remove the document id from the bitmap while leaving the index in place
+    let mut builder = BatchBuilder::new();
+    builder
+        .with_account_id(0)
+        .with_collection(Collection::Email)
+        .update_document(repair_id);
+    builder.ops.push(Operation::Bitmap {
+        class: BitmapClass::DocumentIds,
+        set: false,
+    });
+    db.write(builder.build_batch()).await.unwrap();
+    let document_ids = || async {
+        db.get_bitmap(BitmapKey::document_ids(0, Collection::Email))
+            .await
+            .unwrap()
+            .unwrap_or_default()
+    };
+    let query = || async {
+        db.filter(
+            0,
+            Collection::Email,
+            vec![Filter::eq(Property::Subject, "read-repair")],
+        )
+        .await
+        .unwrap()
+        .results
+    };
+
+    // Disabled by default
+    assert!(query().await.contains(repair_id));
+    assert!(!document_ids().await.contains(repair_id));
+
+    // The query only schedules the repair
+    db.settings().set_read_repair(true);
+    assert!(query().await.contains(repair_id));
+    db.settings().set_read_repair(false);
+    for _ in 0..50 {
+        if document_ids().await.contains(repair_id) {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    assert!(document_ids().await.contains(repair_id));
+    assert!(query().await.contains(repair_id));
+
+    let mut builder = BatchBuilder::new();
+    builder
+        .with_account_id(0)
+        .with_collection(Collection::Email)
+        .delete_document(repair_id);
+    builder.ops.push(Operation::Index {
+        field: Property::Subject.into(),
+        key: b"read-repair".to_vec(),
+        set: false,
+    });
+    db.write(builder.build_batch()).await.unwrap();
+
+    println!("Running indexed value tests...");
+    let index_fields: [(u8, fn(&String) -> Vec<u8>); 2] = [
+        (Property::From.into(), |value| {
+            value
+                .split_once(" <")
+                .map_or(value.as_str(), |(name, _)| name)
+                .to_lowercase()
+                .into_bytes()
+        }),
+        (Property::To.into(), |value| {
+            value
+                .rsplit_once('@')
+                .map_or("", |(_, domain)| domain.trim_end_matches('>'))
+                .as_bytes()
+                .to_vec()
+        }),
+    ];
+    let mut builder = BatchBuilder::new();
+    builder
+        .with_account_id(0)
+        .with_collection(Collection::Email)
+        .create_document()
+        .set_indexed_value(
+            Property::Sender,
+            "Jane Doe <jane@example.org>".to_string(),
+            &index_fields,
+        );
+    let document_id = db
+        .write(builder.build_batch())
+        .await
+        .unwrap()
+        .last_document_id()
+        .unwrap();
+    let matches = |property: Property, key: &'static str| {
+        let db = db.clone();
+        async move {
+            db.filter(0, Collection::Email, vec![Filter::eq(property, key)])
+                .await
+                .unwrap()
+                .results
+                .contains(document_id)
+        }
+    };
+    assert!(matches(Property::From, "jane doe").await);
+    assert!(matches(Property::To, "example.org").await);
+
+    // Replacing the value moves its index entries
+    let mut builder = BatchBuilder::new();
+    builder
+        .with_account_id(0)
+        .with_collection(Collection::Email)
+        .update_document(document_id)
+        .clear_indexed_value(
+            Property::Sender,
+            "Jane Doe <jane@example.org>".to_string(),
+            &index_fields,
+        )
+        .set_indexed_value(
+            Property::Sender,
+            "Jane Roe <jane@example.com>".to_string(),
+            &index_fields,
+        );
+    db.write(builder.build_batch()).await.unwrap();
+    assert!(!matches(Property::From, "jane doe").await);
+    assert!(!matches(Property::To, "example.org").await);
+    assert!(matches(Property::From, "jane roe").await);
+    assert!(matches(Property::To, "example.com").await);
+    assert_eq!(
+        db.get_value::<String>(ValueKey {
+            account_id: 0,
+            collection: Collection::Email.into(),
+            document_id,
+            class: ValueClass::Property(Property::Sender.into()),
+        })
+        .await
+        .unwrap()
+        .as_deref(),
+        Some("Jane Roe <jane@example.com>")
+    );
+
+    let mut builder = BatchBuilder::new();
+    builder
+        .with_account_id(0)
+        .with_collection(Collection::Email)
+        .delete_document(document_id)
+        .clear_indexed_value(
+            Property::Sender,
+            "Jane Roe <jane@example.com>".to_string(),
+            &index_fields,
+        );
+    db.write(builder.build_batch()).await.unwrap();
+    assert!(!matches(Property::From, "jane roe").await);
+    assert!(!matches(Property::To, "example.com").await);
+
+    println!("Running document transfer tests...");
+    let (from_account_id, to_account_id, source_id) = (0, 1, 3);
+    let blob_hash = BlobHash::from(b"transfer".as_slice());
+    let text_token = BitmapClass::Text {
+        field: Property::Subject.into(),
+        token: BitmapHash::new("transferred"),
+    };
+    let document_ops = |builder: &mut BatchBuilder, set: bool| {
+        let flags = if set { 0 } else { F_CLEAR };
+        builder.tag(Property::MailboxIds, 5u32, flags).tag(
+            Property::Keywords,
+            b"keyword".to_vec(),
+            flags,
+        );
+        builder.ops.push(Operation::Index {
+            field: Property::Subject.into(),
+            key: b"transferred".to_vec(),
+            set,
+        });
+        builder.ops.push(Operation::Bitmap {
+            class: text_token.clone(),
+            set,
+        });
+        if set {
+            builder
+                .set(Property::Subject, "transferred".to_string())
+                .set(ValueClass::Acl(5), vec![1u8])
+                .set(ValueClass::Acl(to_account_id), vec![2u8])
+                .set(
+                    ValueClass::Blob(BlobOp::Link {
+                        hash: blob_hash.clone(),
+                    }),
+                    vec![],
+                );
+        } else {
+            builder
+                .clear(Property::Subject)
+                .clear(ValueClass::Acl(5))
+                .clear(ValueClass::Blob(BlobOp::Link {
+                    hash: blob_hash.clone(),
+                }));
+        }
+    };
+    let mut builder = BatchBuilder::new();
+    builder
+        .with_account_id(from_account_id)
+        .with_collection(Collection::Email)
+        .create_document_with_id(source_id);
+    document_ops(&mut builder, true);
+    db.write(builder.build_batch()).await.unwrap();
+
+    let target_id = db
+        .transfer_document(from_account_id, to_account_id, Collection::Email, source_id)
+        .await
+        .unwrap();
+    let document_keys = |account_id: u32, document_id: u32| {
+        let db = db.clone();
+        let blob_hash = blob_hash.clone();
+        async move {
+            let value_key = |class: ValueClass<u32>| ValueKey {
+                account_id,
+                collection: Collection::Email.into(),
+                document_id,
+                class,
+            };
+            let bitmap_contains = |bitmap: Option<RoaringBitmap>| {
+                bitmap.is_some_and(|bitmap| bitmap.contains(document_id))
+            };
+            vec![
+                bitmap_contains(
+                    db.get_bitmap(BitmapKey::document_ids(account_id, Collection::Email))
+                        .await
+                        .unwrap(),
+                ),
+                db.get_value::<String>(value_key(ValueClass::Property(Property::Subject.into())))
+                    .await
+                    .unwrap()
+                    .is_some_and(|subject| subject == "transferred"),
+                db.filter(
+                    account_id,
+                    Collection::Email,
+                    vec![Filter::eq(Property::Subject, "transferred")],
+                )
+                .await
+                .unwrap()
+                .results
+                .contains(document_id),
+                bitmap_contains(
+                    db.get_bitmap(BitmapKey::tag(
+                        account_id,
+                        Collection::Email,
+                        Property::MailboxIds,
+                        5u32,
+                    ))
+                    .await
+                    .unwrap(),
+                ),
+                bitmap_contains(
+                    db.get_bitmap(BitmapKey::tag(
+                        account_id,
+                        Collection::Email,
+                        Property::Keywords,
+                        b"keyword".to_vec(),
+                    ))
+                    .await
+                    .unwrap(),
+                ),
+                bitmap_contains(
+                    db.get_bitmap(BitmapKey::text_token(
+                        account_id,
+                        Collection::Email,
+                        Property::Subject,
+                        "transferred",
+                    ))
+                    .await
+                    .unwrap(),
+                ),
+                db.get_value::<()>(value_key(ValueClass::Acl(5)))
+                    .await
+                    .unwrap()
+                    .is_some(),
+                db.get_value::<()>(value_key(ValueClass::Blob(BlobOp::Link {
+                    hash: blob_hash,
+                })))
+                .await
+                .unwrap()
+                .is_some(),
+            ]
+        }
+    };
+    assert_eq!(document_keys(to_account_id, target_id).await, vec![true; 8]);
+    assert_eq!(
+        document_keys(from_account_id, source_id).await,
+        vec![false; 8]
+    );
+
+    // Grants to the new owner are dropped
+    for (account_id, document_id) in [(from_account_id, source_id), (to_account_id, target_id)] {
+        assert_eq!(
+            db.get_value::<()>(ValueKey {
+                account_id,
+                collection: Collection::Email.into(),
+                document_id,
+                class: ValueClass::Acl(to_account_id),
+            })
+            .await
+            .unwrap(),
+            None
+        );
+    }
+    assert!(db
+        .transfer_document(from_account_id, to_account_id, Collection::Email, source_id)
+        .await
+        .is_err());
+
+    let mut builder = BatchBuilder::new();
+    builder
+        .with_account_id(to_account_id)
+        .with_collection(Collection::Email)
+        .delete_document(target_id);
+    document_ops(&mut builder, false);
+    db.write(builder.build_batch()).await.unwrap();
+
+    println!("Running account initialization tests...");
+    let account_id = 100;
+    let collection = 9u8;
+    let init = |fail: bool| {
+        AccountInit::new()
+            .with_document(
+                collection,
+                1,
+                TestDocument {
+                    value: b"first".to_vec(),
+                    fail: false,
+                },
+            )
+            .with_document(
+                collection,
+                2,
+                TestDocument {
+                    value: b"second".to_vec(),
+                    fail,
+                },
+            )
+            .with_value(ValueClass::Config(b"init".to_vec()), 1u64.serialize())
+            .with_change_id(10)
+    };
+
+    // A failure while writing the last document leaves the account untouched
+    let err = db
+        .initialize_account(account_id, init(true))
+        .await
+        .unwrap_err();
+    assert!(
+        err.matches(trc::EventType::Store(trc::StoreEvent::AssertValueFailed)),
+        "unexpected error: {err:?}"
+    );
+    assert_eq!(
+        db.get_bitmap(BitmapKey {
+            account_id,
+            collection,
+            class: BitmapClass::DocumentIds,
+            document_id: 0,
+        })
+        .await
+        .unwrap(),
+        None
+    );
+    for (document_id, class) in [
+        (1, ValueClass::Property(0)),
+        (0, ValueClass::Config(b"init".to_vec())),
+    ] {
+        assert_eq!(
+            db.get_value::<String>(ValueKey {
+                account_id,
+                collection: if document_id > 0 { collection } else { 0 },
+                document_id,
+                class,
+            })
+            .await
+            .unwrap(),
+            None
+        );
+    }
+    assert!(db
+        .changes(account_id, collection, Query::All)
+        .await
+        .unwrap()
+        .changes
+        .is_empty());
+
+    // All keys are written on success
+    db.initialize_account(account_id, init(false))
+        .await
+        .unwrap();
+    assert_eq!(
+        db.get_bitmap(BitmapKey {
+            account_id,
+            collection,
+            class: BitmapClass::DocumentIds,
+            document_id: 0,
+        })
+        .await
+        .unwrap()
+        .unwrap()
+        .into_iter()
+        .collect::<Vec<_>>(),
+        vec![1, 2]
+    );
+    for (document_id, value) in [(1, "first"), (2, "second")] {
+        assert_eq!(
+            db.get_value::<String>(ValueKey {
+                account_id,
+                collection,
+                document_id,
+                class: ValueClass::Property(0),
+            })
+            .await
+            .unwrap()
+            .as_deref(),
+            Some(value)
+        );
+    }
+    assert_eq!(
+        db.get_value::<u64>(ValueKey {
+            account_id,
+            collection: 0,
+            document_id: 0,
+            class: ValueClass::Config(b"init".to_vec()),
+        })
+        .await
+        .unwrap(),
+        Some(1)
+    );
+    let mut changes = db
+        .changes(account_id, collection, Query::All)
+        .await
+        .unwrap()
+        .changes;
+    changes.sort_unstable_by_key(|change| match change {
+        Change::Insert(id) => *id,
+        _ => u64::MAX,
+    });
+    assert_eq!(changes, vec![Change::Insert(1), Change::Insert(2)]);
+    db.purge_account(account_id).await.unwrap();
+    let mut batch = BatchBuilder::new();
+    batch.clear(ValueClass::Config(b"init".to_vec()));
+    db.write(batch.build_batch()).await.unwrap();
+
+    println!("Running account digest tests...");
+    let empty_digest = db.account_digest(101).await.unwrap();
+    for account_id in [101, 102] {
+        let mut batch = BatchBuilder::new();
+        batch
+            .with_account_id(account_id)
+            .with_change_id(5)
+            .with_collection(collection)
+            .create_document_with_id(1)
+            .value(0u8, "digest test", F_VALUE | F_INDEX | F_BITMAP)
+            .tag(1u8, TagValue::Id(MaybeDynamicId::Static(7)), 0)
+            .log(b"digest change".to_vec())
+            .with_collection(Collection::Mailbox)
+            .update_document(0)
+            .add(ValueClass::Property(84), 3)
+            .add(DirectoryClass::UsedQuota(account_id), 1024);
+        db.write(batch.build_batch()).await.unwrap();
+    }
+
+    // An exact copy under a different account id produces the same digest
+    let digest = db.account_digest(101).await.unwrap();
+    assert_ne!(digest, empty_digest);
+    assert_eq!(digest, db.account_digest(101).await.unwrap());
+    assert_eq!(digest, db.account_digest(102).await.unwrap());
+
+    // Change logs are not part of the digest
+    let mut batch = BatchBuilder::new();
+    batch
+        .with_account_id(102)
+        .with_change_id(6)
+        .with_collection(collection)
+        .log(b"migrated".to_vec());
+    db.write(batch.build_batch()).await.unwrap();
+    assert_eq!(digest, db.account_digest(102).await.unwrap());
+    assert!(db.account_digest(u32::MAX).await.is_err());
+
+    // Modified values and counters are detected
+    let mut batch = BatchBuilder::new();
+    batch
+        .with_account_id(102)
+        .with_collection(collection)
+        .update_document(1)
+        .set(ValueClass::Property(0), "digest tesT".serialize());
+    db.write(batch.build_batch()).await.unwrap();
+    assert_ne!(digest, db.account_digest(102).await.unwrap());
+    batch
+        .with_account_id(102)
+        .with_collection(collection)
+        .update_document(1)
+        .set(ValueClass::Property(0), "digest test".serialize());
+    db.write(batch.build_batch()).await.unwrap();
+    assert_eq!(digest, db.account_digest(102).await.unwrap());
+    batch
+        .with_account_id(102)
+        .with_collection(Collection::Mailbox)
+        .update_document(0)
+        .add(ValueClass::Property(84), 1);
+    db.write(batch.build_batch()).await.unwrap();
+    assert_ne!(digest, db.account_digest(102).await.unwrap());
+
+    for account_id in [101, 102] {
+        db.purge_account(account_id).await.unwrap();
+        batch
+            .with_account_id(account_id)
+            .with_collection(0u8)
+            .update_document(0)
+            .clear(DirectoryClass::UsedQuota(account_id));
+        db.write(batch.build_batch()).await.unwrap();
+        assert_eq!(db.account_digest(account_id).await.unwrap(), empty_digest);
+    }
+
+    println!("Running space reclamation tests...");
+    let account_id = 103;
+    for chunk in (0u32..2000).collect::<Vec<_>>().chunks(100) {
+        let mut batch = BatchBuilder::new();
+        batch
+            .with_account_id(account_id)
+            .with_collection(collection);
+        for &document_id in chunk {
+            batch.create_document_with_id(document_id).set(
+                ValueClass::Property(0),
+                (0..2048).map(|_| rand::random::<u8>()).collect::<Vec<_>>(),
+            );
+        }
+        db.write(batch.build_batch()).await.unwrap();
+    }
+    db.reclaim_space(&[account_id]).await.unwrap();
+    let size = db.storage_size().await.unwrap();
+    db.purge_account(account_id).await.unwrap();
+    db.reclaim_space(&[account_id]).await.unwrap();
+    if let Some(size) = size {
+        let reclaimed_size = db.storage_size().await.unwrap().unwrap();
+        assert!(
+            reclaimed_size < size,
+            "{reclaimed_size} bytes used after reclaiming, {size} before"
+        );
+    }
+
+    println!("Running index deduplication tests...");
+    let mut batch = BatchBuilder::new();
+    batch.with_account_id(0).with_collection(7);
+    for (document_id, value) in [(1u32, "a"), (1, "b"), (2, "c"), (3, "c"), (3, "c")] {
+        batch.update_document(document_id);
+        batch.ops.push(Operation::Index {
+            field: 200,
+            key: value.as_bytes().to_vec(),
+            set: true,
+        });
+    }
+    db.write(batch.build_batch()).await.unwrap();
+
+    // Several values indexed for the same document are not duplicates
+    assert_eq!(db.dedup_index(0, 7, 200).await.unwrap(), 0);
+    assert_eq!(db.dedup_index(0, 7, u8::MAX).await.unwrap(), 0);
+
+    let mut entries = Vec::new();
+    db.iterate(
+        IterateParams::new(
+            IndexKeyPrefix {
+                account_id: 0,
+                collection: 7,
+                field: 200,
+            },
+            IndexKeyPrefix {
+                account_id: 0,
+                collection: 7,
+                field: 201,
+            },
+        )
+        .no_values()
+        .ascending(),
+        |key, _| {
+            let (value, document_id) = key[IndexKeyPrefix::len()..].split_at(key.len() - 10);
+            entries.push((
+                String::from_utf8(value.to_vec()).unwrap(),
+                u32::from_be_bytes(document_id.try_into().unwrap()),
+            ));
+            Ok(true)
+        },
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        entries,
+        vec![
+            ("a".to_string(), 1),
+            ("b".to_string(), 1),
+            ("c".to_string(), 2),
+            ("c".to_string(), 3)
+        ]
+    );
+
+    let mut batch = BatchBuilder::new();
+    batch.with_account_id(0).with_collection(7);
+    for (value, document_id) in entries {
+        batch.update_document(document_id);
+        batch.ops.push(Operation::Index {
+            field: 200,
+            key: value.into_bytes(),
+            set: false,
+        });
+    }
+    db.write(batch.build_batch()).await.unwrap();
+
+    // The same number indexed both as a 32 and a 64-bit value is a duplicate
+    let mut batch = BatchBuilder::new();
+    batch.with_account_id(0).with_collection(7);
+    for (document_id, value) in [
+        (4u32, 5u32.to_be_bytes().to_vec()),
+        (4, 5u64.to_be_bytes().to_vec()),
+        (5, 5u64.to_be_bytes().to_vec()),
+        (6, 7u32.to_be_bytes().to_vec()),
+    ] {
+        batch.update_document(document_id);
+        batch.ops.push(Operation::Index {
+            field: 201,
+            key: value,
+            set: true,
+        });
+    }
+    db.write(batch.build_batch()).await.unwrap();
+    assert_eq!(db.dedup_index(0, 7, 201).await.unwrap(), 1);
+    assert_eq!(db.dedup_index(0, 7, 201).await.unwrap(), 0);
+
+    let mut entries = Vec::new();
+    db.iterate(
+        IterateParams::new(
+            IndexKeyPrefix {
+                account_id: 0,
+                collection: 7,
+                field: 201,
+            },
+            IndexKeyPrefix {
+                account_id: 0,
+                collection: 7,
+                field: 202,
+            },
+        )
+        .no_values()
+        .ascending(),
+        |key, _| {
+            let (value, document_id) = key[IndexKeyPrefix::len()..].split_at(key.len() - 10);
+            entries.push((
+                value.to_vec(),
+                u32::from_be_bytes(document_id.try_into().unwrap()),
+            ));
+            Ok(true)
+        },
+    )
+    .await
+    .unwrap();
+    entries.sort_unstable_by_key(|(_, document_id)| *document_id);
+    assert_eq!(
+        entries,
+        vec![
+            (5u64.to_be_bytes().to_vec(), 4),
+            (5u64.to_be_bytes().to_vec(), 5),
+            (7u32.to_be_bytes().to_vec(), 6)
+        ]
+    );
+
+    let mut batch = BatchBuilder::new();
+    batch.with_account_id(0).with_collection(7);
+    for (value, document_id) in entries {
+        batch.update_document(document_id);
+        batch.ops.push(Operation::Index {
+            field: 201,
+            key: value,
+            set: false,
+        });
+    }
+    db.write(batch.build_batch()).await.unwrap();
+
+    println!("Running per-operation write result tests...");
+    let mut batch = BatchBuilder::new();
+    batch
+        .with_account_id(0)
+        .with_collection(0)
+        .update_document(0)
+        .set(ValueClass::Config(b"result0".to_vec()), 1u64.serialize());
+    db.write(batch.build_batch()).await.unwrap();
+
+    let tag = BitmapClass::Tag {
+        field: 0,
+        value: TagValue::Text(b"result".to_vec()),
+    };
+    let mut batch = BatchBuilder::new();
+    batch
+        .with_account_id(0)
+        .with_collection(0)
+        .update_document(0)
+        .assert_value(ValueClass::Config(b"result0".to_vec()), 1u64)
+        .set(ValueClass::Config(b"result0".to_vec()), 1u64.serialize())
+        .set(ValueClass::Config(b"result1".to_vec()), 2u64.serialize())
+        .clear(ValueClass::Config(b"result2".to_vec()))
+        .add(DirectoryClass::UsedQuota(u32::MAX - 1), 1)
+        .clear(ValueClass::Config(b"result1".to_vec()));
+    batch.ops.push(Operation::Bitmap {
+        class: tag.clone(),
+        set: true,
+    });
+    batch.ops.push(Operation::Bitmap {
+        class: tag.clone(),
+        set: true,
+    });
+    let batch = batch.build_batch();
+    let expected = vec![
+        OperationResult::Context,
+        OperationResult::Context,
+        OperationResult::Context,
+        OperationResult::AssertPassed,
+        OperationResult::Unchanged,
+        OperationResult::Changed,
+        OperationResult::Unchanged,
+        OperationResult::Applied,
+        OperationResult::Changed,
+        OperationResult::Changed,
+        OperationResult::Unchanged,
+    ];
+
+    // Dry runs report the same outcomes without writing anything
+    assert_eq!(db.evaluate_batch(&batch).await.unwrap(), expected);
+    assert_eq!(
+        db.evaluate_batch(&batch).await.unwrap(),
+        expected,
+        "dry run modified the store"
+    );
+    assert_eq!(
+        db.write_with_results(batch).await.unwrap().results,
+        expected
+    );
+
+    // Failed assertions are reported by dry runs and abort the write
+    let mut batch = BatchBuilder::new();
+    batch
+        .with_account_id(0)
+        .with_collection(0)
+        .update_document(0)
+        .assert_value(ValueClass::Config(b"result0".to_vec()), 2u64)
+        .clear(ValueClass::Config(b"result0".to_vec()));
+    batch.ops.push(Operation::Bitmap {
+        class: tag.clone(),
+        set: false,
+    });
+    let batch = batch.build_batch();
+    assert_eq!(
+        db.evaluate_batch(&batch).await.unwrap(),
+        vec![
+            OperationResult::Context,
+            OperationResult::Context,
+            OperationResult::Context,
+            OperationResult::AssertFailed,
+            OperationResult::Changed,
+            OperationResult::Changed,
+        ]
+    );
+    let err = db.write_with_results(batch).await.unwrap_err();
+    assert!(
+        err.matches(trc::EventType::Store(trc::StoreEvent::AssertValueFailed)),
+        "unexpected error: {err:?}"
+    );
+
+    let mut batch = BatchBuilder::new();
+    batch
+        .with_account_id(0)
+        .with_collection(0)
+        .update_document(0)
+        .clear(ValueClass::Config(b"result0".to_vec()))
+        .clear(DirectoryClass::UsedQuota(u32::MAX - 1));
+    batch.ops.push(Operation::Bitmap {
+        class: tag,
+        set: false,
+    });
+    let result = db.write_with_results(batch.build_batch()).await.unwrap();
+    assert_eq!(
+        result.results[3..],
+        [
+            OperationResult::Changed,
+            OperationResult::Applied,
+            OperationResult::Changed,
+        ]
+    );
+
+    // Values of created documents are resolved once their ids are assigned
+    let mut batch = BatchBuilder::new();
+    batch
+        .with_account_id(0)
+        .with_collection(0)
+        .create_document()
+        .set(ValueClass::Property(0), 1u64.serialize())
+        .clear(ValueClass::Property(1));
+    let result = db.write_with_results(batch.build_batch()).await.unwrap();
+    assert_eq!(
+        result.results[4..],
+        [OperationResult::Changed, OperationResult::Unchanged]
+    );
+    let mut batch = BatchBuilder::new();
+    batch
+        .with_account_id(0)
+        .with_collection(0)
+        .delete_document(result.assigned_ids.last_document_id().unwrap())
+        .clear(ValueClass::Property(0));
+    db.write(batch.build_batch()).await.unwrap();
+
+    println!("Running value compression migration tests...");
+    let mut batch = BatchBuilder::new();
+    batch
+        .with_account_id(0)
+        .with_collection(0)
+        .update_document(0);
+    for n in 0..250 {
+        batch.set(
+            ValueClass::Config(format!("migrate{n:04}").into_bytes()),
+            format!("{}{n}", "compressible value ".repeat(20)).into_bytes(),
+        );
+    }
+    db.write(batch.build_batch()).await.unwrap();
+
+    let mut updates = Vec::new();
+    let progress = db
+        .migrate_value_compression(
+            CompressionMigration::new(SUBSPACE_SETTINGS, CompressionAlgo::Lz4).with_batch_size(100),
+            |progress| updates.push(progress.clone()),
+        )
+        .await
+        .unwrap();
+    assert_eq!(progress.scanned, 250);
+    assert_eq!(progress.migrated, 250);
+    assert_eq!(updates.len(), 3);
+    assert_eq!(
+        updates[0].last_key.as_deref(),
+        Some(b"migrate0099".as_slice())
+    );
+    assert_eq!(
+        progress.last_key.as_deref(),
+        Some(b"migrate0249".as_slice())
+    );
+
+    // Make sure values are stored compressed and can be read back
+    let mut raw_values = Vec::new();
+    db.iterate(
+        IterateParams::new(
+            AnyKey {
+                subspace: SUBSPACE_SETTINGS,
+                key: b"migrate".to_vec(),
+            },
+            AnyKey {
+                subspace: SUBSPACE_SETTINGS,
+                key: b"migrate\xFF".to_vec(),
+            },
+        ),
+        |_, value| {
+            raw_values.push(value.to_vec());
+            Ok(true)
+        },
+    )
+    .await
+    .unwrap();
+    assert_eq!(raw_values.len(), 250);
+    assert!(raw_values.iter().all(|value| is_compressed_value(value)));
+    for n in 0..250 {
+        assert_eq!(
+            db.get_value::<Decompressed<String>>(ValueKey {
+                account_id: 0,
+                collection: 0,
+                document_id: 0,
+                class: ValueClass::Config(format!("migrate{n:04}").into_bytes()),
+            })
+            .await
+            .unwrap()
+            .unwrap()
+            .0,
+            format!("{}{n}", "compressible value ".repeat(20))
+        );
+    }
+
+    // Resuming an already migrated range should not rewrite anything
+    let progress = db
+        .migrate_value_compression(
+            CompressionMigration::new(SUBSPACE_SETTINGS, CompressionAlgo::Lz4)
+                .resume_from(b"migrate0199".to_vec()),
+            |_| {},
+        )
+        .await
+        .unwrap();
+    assert_eq!(progress.scanned, 50);
+    assert_eq!(progress.migrated, 0);
+
+    // Migrate back to uncompressed values
+    let progress = db
+        .migrate_value_compression(
+            CompressionMigration::new(SUBSPACE_SETTINGS, CompressionAlgo::None),
+            |_| {},
+        )
+        .await
+        .unwrap();
+    assert_eq!(progress.migrated, 250);
+    for n in [0, 125, 249] {
+        assert_eq!(
+            db.get_value::<String>(ValueKey {
+                account_id: 0,
+                collection: 0,
+                document_id: 0,
+                class: ValueClass::Config(format!("migrate{n:04}").into_bytes()),
+            })
+            .await
+            .unwrap()
+            .unwrap(),
+            format!("{}{n}", "compressible value ".repeat(20))
+        );
+    }
+
+    // Values ending with a compression marker or a valid trailer are not
+    // mistaken for compressed ones
+    let plain_value = b"plain value\xa1".to_vec();
+    let lookalike_value = compress_value(
+        &ValueCompression {
+            algorithm: CompressionAlgo::Lz4,
+            threshold: 0,
+        },
+        "lookalike value ".repeat(20).as_bytes(),
+    )
+    .unwrap();
+    let raw_settings = || async {
+        let mut values = Vec::new();
+        db.iterate_raw(
+            IterateParams::new(
+                AnyKey {
+                    subspace: SUBSPACE_SETTINGS,
+                    key: b"migrate-".to_vec(),
+                },
+                AnyKey {
+                    subspace: SUBSPACE_SETTINGS,
+                    key: b"migrate-\xFF".to_vec(),
+                },
+            ),
+            |_, value| {
+                values.push(value.to_vec());
+                Ok(true)
+            },
+        )
+        .await
+        .unwrap();
+        values
+    };
+    let mut batch = BatchBuilder::new();
+    batch
+        .with_account_id(0)
+        .with_collection(0)
+        .update_document(0)
+        .set(
+            ValueClass::Config(b"migrate-plain".to_vec()),
+            plain_value.clone(),
+        )
+        .set(
+            ValueClass::Config(b"migrate-lookalike".to_vec()),
+            lookalike_value.clone(),
+        );
+    db.write(batch.build_batch()).await.unwrap();
+    for compression in [CompressionAlgo::Lz4, CompressionAlgo::None] {
+        db.migrate_value_compression(
+            CompressionMigration::new(SUBSPACE_SETTINGS, compression),
+            |_| {},
+        )
+        .await
+        .unwrap();
+        let values = raw_settings().await;
+        assert_eq!(
+            values
+                .iter()
+                .map(|value| decompress_value(value).unwrap().into_owned())
+                .collect::<Vec<_>>(),
+            [lookalike_value.clone(), plain_value.clone()]
+        );
+        if compression == CompressionAlgo::None {
+            assert_eq!(values[1], plain_value);
+        }
+    }
+
+    let mut batch = BatchBuilder::new();
+    batch
+        .with_account_id(0)
+        .with_collection(0)
+        .update_document(0)
+        .clear(ValueClass::Config(b"migrate-plain".to_vec()))
+        .clear(ValueClass::Config(b"migrate-lookalike".to_vec()));
+    for n in 0..250 {
+        batch.clear(ValueClass::Config(format!("migrate{n:04}").into_bytes()));
+    }
+    db.write(batch.build_batch()).await.unwrap();
+
+    println!("Running value compression tests...");
+    db.settings().set_value_compression(Some(ValueCompression {
+        algorithm: CompressionAlgo::Lz4,
+        threshold: 1024,
+    }));
+    let property_key = |field| ValueKey {
+        account_id: 0,
+        collection: 0,
+        document_id: 0,
+        class: ValueClass::Property(field),
+    };
+    let large_value = "compressible property ".repeat(200);
+    let small_value = "small property".to_string();
+    let mut batch = BatchBuilder::new();
+    batch
+        .with_account_id(0)
+        .with_collection(0)
+        .update_document(0)
+        .set(ValueClass::Property(200), large_value.clone().into_bytes())
+        .set(ValueClass::Property(201), small_value.clone().into_bytes());
+    db.write(batch.build_batch()).await.unwrap();
+
+    // Only values above the threshold are stored compressed
+    let mut raw_values = Vec::new();
+    db.iterate_raw(
+        IterateParams::new(property_key(200), property_key(201)),
+        |_, value| {
+            raw_values.push(value.to_vec());
+            Ok(true)
+        },
+    )
+    .await
+    .unwrap();
+    assert_eq!(raw_values.len(), 2);
+    assert!(is_compressed_value(&raw_values[0]));
+    assert!(raw_values[0].len() < large_value.len());
+    assert_eq!(raw_values[1], small_value.as_bytes());
+    let stored = db
+        .get_value::<HashedValue<String>>(property_key(200))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(stored.inner, large_value);
+
+    // Iterators receive the decompressed values
+    let mut values = Vec::new();
+    db.iterate(
+        IterateParams::new(property_key(200), property_key(201)),
+        |_, value| {
+            values.push(HashedValue::<String>::deserialize(value)?);
+            Ok(true)
+        },
+    )
+    .await
+    .unwrap();
+    assert_eq!(values.len(), 2);
+    assert_eq!(values[0].inner, large_value);
+    assert_eq!(values[0].hash, stored.hash);
+    assert_eq!(values[1].inner, small_value);
+    assert_eq!(
+        db.get_value::<String>(property_key(201))
+            .await
+            .unwrap()
+            .unwrap(),
+        small_value
+    );
+
+    // Assertions are evaluated against the decompressed value
+    let updated_value = format!("{large_value}updated");
+    let mut batch = BatchBuilder::new();
+    batch
+        .with_account_id(0)
+        .with_collection(0)
+        .update_document(0)
+        .assert_value(ValueClass::Property(200), &stored)
+        .set(
+            ValueClass::Property(200),
+            updated_value.clone().into_bytes(),
+        );
+    db.write(batch.build_batch()).await.unwrap();
+    let mut batch = BatchBuilder::new();
+    batch
+        .with_account_id(0)
+        .with_collection(0)
+        .update_document(0)
+        .assert_value(ValueClass::Property(200), &stored)
+        .clear(ValueClass::Property(200));
+    assert!(db
+        .write(batch.build_batch())
+        .await
+        .unwrap_err()
+        .matches(trc::EventType::Store(trc::StoreEvent::AssertValueFailed)));
+
+    // Compressed values remain readable once compression is disabled
+    db.settings().set_value_compression(None);
+    assert_eq!(
+        db.get_value::<String>(property_key(200))
+            .await
+            .unwrap()
+            .unwrap(),
+        updated_value
+    );
+
+    // Values that look compressed are read back as they were written
+    let mut batch = BatchBuilder::new();
+    batch
+        .with_account_id(0)
+        .with_collection(0)
+        .update_document(0)
+        .set(ValueClass::Property(201), lookalike_value.clone());
+    db.write(batch.build_batch()).await.unwrap();
+    let mut values = Vec::new();
+    db.iterate(
+        IterateParams::new(property_key(201), property_key(201)),
+        |_, value| {
+            values.push(value.to_vec());
+            Ok(true)
+        },
+    )
+    .await
+    .unwrap();
+    assert_eq!(values, [lookalike_value]);
+
+    let mut batch = BatchBuilder::new();
+    batch
+        .with_account_id(0)
+        .with_collection(0)
+        .update_document(0)
+        .clear(ValueClass::Property(200))
+        .clear(ValueClass::Property(201));
+    db.write(batch.build_batch()).await.unwrap();
+
+    println!("Running prefix clear tests...");
+    let account_id = 104;
+    for collection in [Collection::Email, Collection::Mailbox] {
+        let mut batch = BatchBuilder::new();
+        batch
+            .with_account_id(account_id)
+            .with_collection(collection)
+            .with_change_id(1);
+        for document_id in 0..10u32 {
+            batch
+                .create_document_with_id(document_id)
+                .value(
+                    Property::Subject,
+                    format!("prefix {document_id}"),
+                    F_VALUE | F_INDEX,
+                )
+                .tag(Property::Keywords, TagValue::Text(b"prefix".to_vec()), 0);
+        }
+        batch.log(vec![1u8]);
+        db.write(batch.build_batch()).await.unwrap();
+    }
+
+    // Delete the entire Email collection in a single batch
+    let mut batch = BatchBuilder::new();
+    for subspace in CLEAR_PREFIX_SUBSPACES {
+        batch.clear_prefix(subspace, account_id, Collection::Email);
+    }
+    db.write(batch.build_batch()).await.unwrap();
+    for (collection, expected_ids) in [
+        (Collection::Email, None),
+        (Collection::Mailbox, Some(RoaringBitmap::from_iter(0..10))),
+    ] {
+        assert_eq!(
+            db.get_bitmap(BitmapKey::document_ids(account_id, collection))
+                .await
+                .unwrap(),
+            expected_ids
+        );
+        assert_eq!(
+            db.get_bitmap(BitmapKey {
+                account_id,
+                collection: collection.into(),
+                class: BitmapClass::Tag {
+                    field: Property::Keywords.into(),
+                    value: TagValue::Text(b"prefix".to_vec()),
+                },
+                document_id: 0,
+            })
+            .await
+            .unwrap(),
+            expected_ids
+        );
+        assert_eq!(
+            db.get_value::<String>(ValueKey {
+                account_id,
+                collection: collection.into(),
+                document_id: 5,
+                class: ValueClass::Property(Property::Subject.into()),
+            })
+            .await
+            .unwrap()
+            .is_some(),
+            expected_ids.is_some()
+        );
+        assert_eq!(
+            db.get_last_change_id(account_id, collection)
+                .await
+                .unwrap()
+                .is_some(),
+            expected_ids.is_some()
+        );
+    }
+
+    // Prefix clears fail on subspaces not keyed by account and collection
+    let mut batch = BatchBuilder::new();
+    batch.clear_prefix(SUBSPACE_SETTINGS, account_id, Collection::Email);
+    assert!(db.write(batch.build_batch()).await.is_err());
+    db.purge_account(account_id).await.unwrap();
+
+    println!("Running per-collection latency metric tests...");
+    let histogram_count = |metric_type, collection: Collection| {
+        trc::Collector::collection_histogram(metric_type, collection.into())
+            .unwrap()
+            .count()
+    };
+    let metric_types = [
+        trc::MetricType::StoreCollectionReadTime,
+        trc::MetricType::StoreCollectionDocumentIdsTime,
+        trc::MetricType::StoreCollectionWriteTime,
+    ];
+    let counts_before = metric_types.map(|m| histogram_count(m, Collection::SieveScript));
+    trc::Collector::set_collection_metrics(true);
+    let mut batch = BatchBuilder::new();
+    batch
+        .with_account_id(0)
+        .with_collection(Collection::SieveScript)
+        .update_document(0)
+        .set(Property::Name, b"latency".to_vec());
+    db.write(batch.build_batch()).await.unwrap();
+    assert_eq!(
+        db.get_value::<String>(ValueKey {
+            account_id: 0,
+            collection: Collection::SieveScript.into(),
+            document_id: 0,
+            class: ValueClass::Property(Property::Name.into()),
+        })
+        .await
+        .unwrap()
+        .unwrap(),
+        "latency"
+    );
+    db.get_bitmap(BitmapKey::document_ids(0, Collection::SieveScript))
+        .await
+        .unwrap();
+    let mut batch = BatchBuilder::new();
+    batch
+        .with_account_id(0)
+        .with_collection(Collection::SieveScript)
+        .update_document(0)
+        .clear(Property::Name);
+    db.write(batch.build_batch()).await.unwrap();
+    trc::Collector::set_collection_metrics(false);
+    for ((metric_type, count_before), expected) in
+        metric_types.into_iter().zip(counts_before).zip([1, 1, 2])
+    {
+        assert!(
+            histogram_count(metric_type, Collection::SieveScript) >= count_before + expected,
+            "{metric_type:?} did not record any entries"
+        );
+    }
+
     println!("Running chunking tests...");
     for (test_num, value) in [
         vec![b'A'; 0],
@@ -344,3 +1791,17 @@ pub async fn test(db: Store) {
         db.assert_is_empty(db.clone().into()).await;
     }
 }
+
+struct TestDocument {
+    value: Vec<u8>,
+    fail: bool,
+}
+
+impl IntoOperations for TestDocument {
+    fn build(self, batch: &mut BatchBuilder) {
+        batch.set(ValueClass::Property(0), self.value);
+        if self.fail {
+            batch.assert_value(ValueClass::Config(b"init-missing".to_vec()), 1u64);
+        }
+    }
+}